@@ -0,0 +1,178 @@
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use iroh_docs::{
+    ranger::{
+        Fingerprint, MemoryStore, MessageBuilder, Range, RangeEntry, RangeKey, RangeValue, Store,
+        SyncConfig, SyncDirection, VecStore,
+    },
+    ContentStatus,
+};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord)]
+struct Key(u64);
+
+impl RangeKey for Key {
+    fn is_prefix_of(&self, other: &Self) -> bool {
+        self == other
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct Value(u64);
+
+impl RangeValue for Value {}
+
+#[derive(Debug, Clone)]
+struct Entry(Key, Value);
+
+impl RangeEntry for Entry {
+    type Key = Key;
+    type Value = Value;
+
+    fn key(&self) -> &Key {
+        &self.0
+    }
+
+    fn value(&self) -> &Value {
+        &self.1
+    }
+
+    fn as_fingerprint(&self) -> Fingerprint {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(&self.0 .0.to_le_bytes());
+        hasher.update(&self.1 .0.to_le_bytes());
+        Fingerprint(hasher.finalize().into())
+    }
+}
+
+/// Two sets of `n` entries, where every 1000th entry differs.
+fn sets(n: u64) -> (Vec<Entry>, Vec<Entry>) {
+    let alice = (0..n).map(|i| Entry(Key(i * 2), Value(0))).collect();
+    let bob = (0..n)
+        .map(|i| {
+            let key = if i % 1000 == 0 { i * 2 + 1 } else { i * 2 };
+            Entry(Key(key), Value(0))
+        })
+        .collect();
+    (alice, bob)
+}
+
+fn reconcile<S: Store<Entry>>(alice: &mut S, bob: &mut S) {
+    let config = Default::default();
+    let mut msg = Some(alice.initial_message().unwrap());
+    let mut turn = 0;
+    while let Some(m) = msg.take() {
+        let store = if turn % 2 == 0 {
+            &mut *bob
+        } else {
+            &mut *alice
+        };
+        msg = store
+            .process_message_reply(
+                &config,
+                m,
+                |_, _, _| true,
+                |_, _, _| (),
+                |_, _| ContentStatus::Complete,
+            )
+            .unwrap();
+        turn += 1;
+    }
+}
+
+pub fn reconcile_stores(c: &mut Criterion) {
+    let mut group = c.benchmark_group("reconcile");
+    group.sample_size(10);
+    for n in [10_000u64, 1_000_000] {
+        let (alice, bob) = sets(n);
+
+        let stores = (
+            alice.iter().cloned().collect::<MemoryStore<_>>(),
+            bob.iter().cloned().collect::<MemoryStore<_>>(),
+        );
+        group.bench_with_input(BenchmarkId::new("btree", n), &stores, |b, stores| {
+            b.iter_batched(
+                || stores.clone(),
+                |(mut alice, mut bob)| reconcile(&mut alice, &mut bob),
+                BatchSize::LargeInput,
+            )
+        });
+
+        let stores = (
+            VecStore::from_sorted_iter(alice),
+            VecStore::from_sorted_iter(bob),
+        );
+        group.bench_with_input(BenchmarkId::new("vec", n), &stores, |b, stores| {
+            b.iter_batched(
+                || stores.clone(),
+                |(mut alice, mut bob)| reconcile(&mut alice, &mut bob),
+                BatchSize::LargeInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+/// Answer a single item with all entries of the remote, which diffs them against all local
+/// entries. The entries are not stored, so that only the diff is measured.
+pub fn diff_items(c: &mut Criterion) {
+    let mut group = c.benchmark_group("diff_items");
+    group.sample_size(10);
+    let config = SyncConfig::default().with_direction(SyncDirection::SendOnly);
+    for n in [10_000u64, 50_000] {
+        let (alice, bob) = sets(n);
+        let message = MessageBuilder::new()
+            .add_items(
+                Range::new(Key(0), Key(0)),
+                alice
+                    .into_iter()
+                    .map(|entry| (entry, ContentStatus::Complete))
+                    .collect(),
+                false,
+            )
+            .build();
+        let bob = bob.into_iter().collect::<MemoryStore<_>>();
+        group.bench_with_input(BenchmarkId::new("btree", n), &bob, |b, bob| {
+            b.iter_batched(
+                || (bob.clone(), message.clone()),
+                |(mut bob, message)| {
+                    bob.process_message(
+                        &config,
+                        message,
+                        |_, _, _| true,
+                        |_, _, _| (),
+                        |_, _| ContentStatus::Complete,
+                    )
+                    .unwrap()
+                },
+                BatchSize::LargeInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+/// Compute the fingerprint of all entries of a large store, on one thread and, with the
+/// `parallel` feature, spread over all cores.
+pub fn fingerprint(c: &mut Criterion) {
+    let mut group = c.benchmark_group("fingerprint");
+    group.sample_size(10);
+    for n in [1_000_000u64, 4_000_000] {
+        let (alice, _) = sets(n);
+        let mut store = alice.into_iter().collect::<MemoryStore<_>>();
+        let all = Range::new(Key(0), Key(0));
+        group.bench_with_input(BenchmarkId::new("sequential", n), &all, |b, all| {
+            b.iter(|| store.get_fingerprint(all).unwrap())
+        });
+        #[cfg(feature = "parallel")]
+        {
+            let mut store = store.with_parallel_fingerprints();
+            group.bench_with_input(BenchmarkId::new("parallel", n), &all, |b, all| {
+                b.iter(|| store.get_fingerprint(all).unwrap())
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, reconcile_stores, diff_items, fingerprint);
+criterion_main!(benches);
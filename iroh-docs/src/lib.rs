@@ -44,12 +44,12 @@ mod ticket;
 pub mod engine;
 
 pub mod actor;
+pub mod ranger;
 pub mod store;
 pub mod sync;
 
 mod heads;
 mod keys;
-mod ranger;
 
 pub use self::heads::*;
 pub use self::keys::*;
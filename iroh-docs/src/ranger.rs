@@ -3,12 +3,102 @@
 //!
 
 use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet};
 use std::fmt::Debug;
+use std::io;
 
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
 use crate::ContentStatus;
 
+mod anti_entropy;
+mod bounded;
+mod boxed;
+mod delta;
+#[cfg(feature = "futures")]
+mod driver;
+mod envelope;
+mod epoch;
+mod expiry;
+mod filtered;
+#[cfg(feature = "futures")]
+mod framed;
+mod group;
+mod instrumented;
+mod key_filter;
+mod local;
+mod log_store;
+mod memory;
+mod mirrored;
+mod overlay;
+#[cfg(feature = "redb-store")]
+mod redb_store;
+#[cfg(feature = "seal")]
+mod sealed;
+mod session;
+mod set;
+mod sharded;
+mod signed;
+mod snapshot;
+#[cfg(feature = "sqlite-store")]
+mod sqlite_store;
+#[cfg(any(test, feature = "test-utils"))]
+pub mod test_utils;
+mod timestamped;
+mod tombstone;
+mod validate;
+pub mod validators;
+mod vec_store;
+mod wire;
+
+pub use self::anti_entropy::{AntiEntropyPlanner, SliceSize};
+pub use self::bounded::{BoundedStore, EvictionPolicy};
+pub use self::boxed::{BoxedIterator, BoxedStore};
+pub use self::delta::{AsKeyBytes, SplitKey};
+#[cfg(feature = "futures")]
+pub use self::driver::{run_sync, RunOptions, RunReport, RunSyncError, Termination};
+pub use self::envelope::{Envelope, EnvelopeError, PROTOCOL_VERSION};
+pub use self::epoch::EpochStore;
+pub use self::expiry::{ExpiringStore, ExpiryPolicy};
+pub use self::filtered::{EntryFilter, FilteredIterator, FilteredStore};
+#[cfg(feature = "futures")]
+pub use self::framed::{run_sync_io, FrameError};
+pub use self::group::{ReconcileGroup, RemoteId, RemoteStats};
+pub use self::instrumented::{
+    InstrumentedChunks, InstrumentedIterator, InstrumentedStore, StoreStats,
+};
+pub use self::key_filter::{KeyFilteredStore, SessionFilter};
+pub use self::local::{
+    diff_stores, sync_stores, StoreDiff, SyncOptions, SyncReport, SyncStoresError,
+};
+pub use self::log_store::{LogIterator, LogStore, LogStoreError};
+pub use self::memory::{BTreeMapRangeIterator, MemoryRangeIterator, MemoryStore};
+pub use self::mirrored::{MirroredIterator, MirroredStore, SecondaryErrorPolicy};
+pub use self::overlay::{ChunkEntries, OverlayIterator, OverlayStore};
+#[cfg(feature = "redb-store")]
+pub use self::redb_store::{RedbIterator, RedbStore, RedbStoreError};
+#[cfg(feature = "seal")]
+pub use self::sealed::{SealError, SealedMessage, SealingKey};
+pub use self::session::{
+    Budget, Continuation, DownloadDecision, EpochReport, EventReceiver, ExternalApplyReport,
+    InsertKind, Limit, ProtocolError, ProtocolLimits, RejectReason, Role, SessionLimits,
+    SessionSnapshot, StepProgress, SyncError, SyncEvent, SyncProgress, SyncSession, SyncStats,
+    TransformError, ValidationError, VerificationReport, DEFAULT_EVENT_CAPACITY,
+};
+pub use self::set::{MemorySetRangeIterator, MemorySetStore, SetEntry};
+pub use self::sharded::{ShardedIterator, ShardedStore};
+#[cfg(feature = "ed25519")]
+pub use self::signed::Ed25519Verifier;
+pub use self::signed::{SignedEntry, Verifier};
+pub use self::snapshot::{ExportError, ImportError, SNAPSHOT_VERSION};
+#[cfg(feature = "sqlite-store")]
+pub use self::sqlite_store::{SqliteIterator, SqliteStore, SqliteStoreError};
+pub use self::timestamped::{TimestampedEntry, TimestampedValue};
+pub use self::tombstone::{TombstoneKind, TombstoneStore, TombstoneValue, Tombstoned};
+pub use self::validate::{MessageLimits, MessageValidationError};
+pub use self::vec_store::{VecRangeIterator, VecStore};
+pub use self::wire::{DecodeError, MessagePartReader, PartsError};
+
 /// Store entries that can be fingerprinted and put into ranges.
 pub trait RangeEntry: Debug + Clone {
     /// The key type for this entry.
@@ -35,16 +125,31 @@ pub trait RangeEntry: Debug + Clone {
 
     /// Get the fingerprint for this entry.
     fn as_fingerprint(&self) -> Fingerprint;
+
+    /// Returns an estimate of the size of this entry in a message, see [`SendThreshold::Bytes`].
+    ///
+    /// The default is the size of the entry itself, which does not include any data on the heap,
+    /// so entries that own such data should override it.
+    fn encoded_size_hint(&self) -> u64 {
+        std::mem::size_of_val(self) as u64
+    }
+
+    /// Returns `true` if this entry replaces the older entries whose keys it is a prefix of, and
+    /// keeps older entries with such keys from being inserted, see [`Store::put`].
+    ///
+    /// This is the prefix deletion of the willow spec, which applies to all entries by default.
+    /// Entries that return `false` only replace the entries of their own key.
+    fn deletes_prefixed(&self) -> bool {
+        true
+    }
 }
 
 /// A trait constraining types that are valid entry keys.
 pub trait RangeKey: Sized + Debug + Ord + PartialEq + Clone + 'static {
     /// Returns `true` if `self` is a prefix of `other`.
-    #[cfg(test)]
     fn is_prefix_of(&self, other: &Self) -> bool;
 
     /// Returns true if `other` is a prefix of `self`.
-    #[cfg(test)]
     fn is_prefixed_by(&self, other: &Self) -> bool {
         other.is_prefix_of(self)
     }
@@ -53,6 +158,9 @@ pub trait RangeKey: Sized + Debug + Ord + PartialEq + Clone + 'static {
 /// A trait constraining types that are valid entry values.
 pub trait RangeValue: Sized + Debug + Ord + PartialEq + Clone + 'static {}
 
+impl RangeValue for () {}
+impl RangeValue for u64 {}
+
 /// Stores a range.
 ///
 /// There are three possibilities
@@ -67,18 +175,22 @@ pub struct Range<K> {
 }
 
 impl<K> Range<K> {
+    /// The start of the range (inclusive).
     pub fn x(&self) -> &K {
         &self.x
     }
 
+    /// The end of the range (exclusive).
     pub fn y(&self) -> &K {
         &self.y
     }
 
+    /// Create a new range from `x` to `y`.
     pub fn new(x: K, y: K) -> Self {
         Range { x, y }
     }
 
+    /// Map the bounds of this range to a new type.
     pub fn map<X>(self, f: impl FnOnce(K, K) -> (X, X)) -> Range<X> {
         let (x, y) = f(self.x, self.y);
         Range { x, y }
@@ -86,10 +198,12 @@ impl<K> Range<K> {
 }
 
 impl<K: Ord> Range<K> {
+    /// Returns `true` if this range covers the whole set.
     pub fn is_all(&self) -> bool {
         self.x() == self.y()
     }
 
+    /// Returns `true` if `t` is contained in this range.
     pub fn contains(&self, t: &K) -> bool {
         match self.x().cmp(self.y()) {
             Ordering::Equal => true,
@@ -97,6 +211,13 @@ impl<K: Ord> Range<K> {
             Ordering::Greater => self.x() <= t || t < self.y(),
         }
     }
+
+    /// Returns `true` if this range and `other` have a key in common.
+    pub fn overlaps(&self, other: &Range<K>) -> bool {
+        // ranges are contiguous on the wrap around, so if they overlap, one contains the start
+        // of the other
+        self.contains(other.x()) || other.contains(self.x())
+    }
 }
 
 impl<K> From<(K, K)> for Range<K> {
@@ -105,6 +226,9 @@ impl<K> From<(K, K)> for Range<K> {
     }
 }
 
+/// Fingerprint of a set of entries.
+///
+/// The fingerprint of a set is the XOR of the fingerprints of its entries.
 #[derive(Copy, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Fingerprint(pub [u8; 32]);
 
@@ -120,6 +244,7 @@ impl Fingerprint {
         Fingerprint(*blake3::hash(&[]).as_bytes())
     }
 
+    /// The fingerprint of a single entry.
     pub fn new<T: RangeEntry>(val: T) -> Self {
         val.as_fingerprint()
     }
@@ -133,8 +258,70 @@ impl std::ops::BitXorAssign for Fingerprint {
     }
 }
 
+/// The fingerprint and the number of entries of a whole store, see [`Store::full_fingerprint`].
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct FullFingerprint {
+    /// The fingerprint of all entries.
+    pub fingerprint: Fingerprint,
+    /// The number of entries.
+    pub count: u64,
+}
+
+/// A summary of a whole store, advertised at the start of a sync, see
+/// [`SyncSession::with_store_summary`].
+///
+/// The summary is advisory, e.g. to pick the parameters of a session or to show progress. It
+/// is not used to reconcile the stores, so a wrong summary cannot cause entries to be missed.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StoreSummary<K> {
+    /// The number of entries.
+    pub entry_count: u64,
+    /// The smallest key, or `None` if the store is empty.
+    pub first_key: Option<K>,
+    /// The largest key, or `None` if the store is empty.
+    pub last_key: Option<K>,
+    /// The fingerprint of all entries.
+    pub full_fingerprint: Fingerprint,
+}
+
+/// Identifies a sync session in its messages, see [`SyncSession::with_session_id`].
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct SessionId(pub [u8; 16]);
+
+impl Debug for SessionId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "SessionId({})", hex::encode(self.0))
+    }
+}
+
+impl SessionId {
+    /// Generate a random session id.
+    pub fn random() -> Self {
+        SessionId(rand::random())
+    }
+}
+
+/// Ties a message to the parts it responds to, see [`SyncSession::with_range_ids`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RangeIds {
+    /// The ids of the parts of the message that expect a reply, in the order of the parts.
+    ///
+    /// Ids are unique within the messages of a sender.
+    pub ids: Vec<u64>,
+    /// The ids of the parts of the message this message responds to, or empty if it does not
+    /// respond to a message.
+    pub responds_to: Vec<u64>,
+    /// The index of the message among the messages of the response, see
+    /// [`SyncSession::poll_pending_message`].
+    pub piece: u32,
+    /// Whether this is the last message of the response.
+    pub last: bool,
+}
+
+/// Transfers the fingerprint of a range to the other participant.
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct RangeFingerprint<K> {
+    /// The range for which the fingerprint was computed.
     #[serde(bound(
         serialize = "Range<K>: Serialize",
         deserialize = "Range<K>: Deserialize<'de>"
@@ -153,6 +340,7 @@ pub struct RangeItem<E: RangeEntry> {
         deserialize = "Range<E::Key>: Deserialize<'de>"
     ))]
     pub range: Range<E::Key>,
+    /// The entries in the range, together with the sender's content status for each entry.
     #[serde(bound(serialize = "E: Serialize", deserialize = "E: Deserialize<'de>"))]
     pub values: Vec<(E, ContentStatus)>,
     /// If false, requests to send local items in the range.
@@ -160,1277 +348,11104 @@ pub struct RangeItem<E: RangeEntry> {
     pub have_local: bool,
 }
 
+/// A [`RangeItem`] whose values are compressed, see [`SyncSession::with_compression`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CompressedRangeItem<K> {
+    /// The range out of which the elements are.
+    pub range: Range<K>,
+    /// The compressed postcard encoding of the values of the item.
+    pub compressed: Vec<u8>,
+    /// The codec the values are compressed with.
+    pub codec: Codec,
+    /// See [`RangeItem::have_local`].
+    pub have_local: bool,
+}
+
+/// A [`RangeItem`] with the keys and fingerprints of the entries instead of the entries, see
+/// [`SyncSession::with_lazy_values`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LazyRangeItem<K> {
+    /// The range out of which the elements are.
+    pub range: Range<K>,
+    /// The keys of the entries in the range, together with the fingerprint of each entry, see
+    /// [`RangeEntry::as_fingerprint`].
+    pub values: Vec<(K, Fingerprint)>,
+    /// See [`RangeItem::have_local`].
+    pub have_local: bool,
+}
+
+/// A [`RangeItem`] whose keys are delta encoded, see [`SyncSession::with_delta_keys`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeltaRangeItem<K> {
+    /// The range out of which the elements are.
+    pub range: Range<K>,
+    /// The values of the item, with each key encoded as the length of the prefix it shares with
+    /// the key of the previous value, followed by the remaining bytes, see [`Message::encode`].
+    pub values: Vec<u8>,
+    /// See [`RangeItem::have_local`].
+    pub have_local: bool,
+}
+
+/// A compression codec of [`CompressedRangeItem`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Codec {
+    /// [Zstandard](https://facebook.github.io/zstd/).
+    Zstd,
+}
+
+/// A single part of a [`Message`].
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum MessagePart<E: RangeEntry> {
+    /// The fingerprint of a range.
     #[serde(bound(
         serialize = "RangeFingerprint<E::Key>: Serialize",
         deserialize = "RangeFingerprint<E::Key>: Deserialize<'de>"
     ))]
     RangeFingerprint(RangeFingerprint<E::Key>),
+    /// The entries of a range.
     #[serde(bound(
         serialize = "RangeItem<E>: Serialize",
         deserialize = "RangeItem<E>: Deserialize<'de>"
     ))]
     RangeItem(RangeItem<E>),
+    /// Cancels the sync.
+    ///
+    /// The receiver discards the message and does not respond, see [`Message::cancel`].
+    Cancel,
+    /// The fingerprint of the sender's whole store, see [`Store::handshake_message`].
+    ///
+    /// The receiver responds with [`MessagePart::HandshakeMatch`] if its store matches, and
+    /// with the parts it would send in response to the initial message otherwise.
+    Handshake(FullFingerprint),
+    /// Confirms that both stores matched in the handshake, which ends the sync.
+    HandshakeMatch,
+    /// The id of the session the message belongs to, see [`SyncSession::with_session_id`].
+    ///
+    /// Only sent by sessions with an id, so messages of other sessions are encoded as before.
+    SessionId(SessionId),
+    /// The sequence number of the message in its session, see
+    /// [`SyncSession::with_sequence_numbers`].
+    Sequence(u64),
+    /// Marks the fingerprints of the message as re-advertised ranges, see
+    /// [`SyncSession::reopen_message`].
+    ///
+    /// The receiver reconciles these ranges again, even if they were settled before, and counts
+    /// their depth from zero, see [`ProtocolLimits::max_depth`].
+    Reopen,
+    /// A [`MessagePart::RangeItem`] with compressed values.
+    ///
+    /// Only sent in messages encoded with [`SyncSession::encode_message`], and replaced by the
+    /// uncompressed part in [`SyncSession::decode_message`].
+    #[serde(bound(
+        serialize = "CompressedRangeItem<E::Key>: Serialize",
+        deserialize = "CompressedRangeItem<E::Key>: Deserialize<'de>"
+    ))]
+    CompressedRangeItem(CompressedRangeItem<E::Key>),
+    /// Signals that the sender has nothing further to ask, see [`SyncSession::is_done`].
+    ///
+    /// The receiver responds with its final parts and `Done`, or not at all if it sent `Done`
+    /// before. Only sent by sessions that negotiated a version that supports it, see
+    /// [`SyncSession::decode_message`].
+    Done,
+    /// A [`MessagePart::RangeItem`] with the keys and fingerprints of the entries instead of the
+    /// entries, see [`SyncSession::with_lazy_values`].
+    ///
+    /// Handled by [`SyncSession`], and ignored by [`Store::process_message`].
+    #[serde(bound(
+        serialize = "LazyRangeItem<E::Key>: Serialize",
+        deserialize = "LazyRangeItem<E::Key>: Deserialize<'de>"
+    ))]
+    LazyRangeItem(LazyRangeItem<E::Key>),
+    /// Requests the entries with the given keys, see [`SyncSession::value_request_message`].
+    ///
+    /// The receiver responds with a [`MessagePart::ValueResponse`] with the entries it has.
+    /// Handled by [`SyncSession`], and ignored by [`Store::process_message`].
+    #[serde(bound(
+        serialize = "E::Key: Serialize",
+        deserialize = "E::Key: Deserialize<'de>"
+    ))]
+    ValueRequest(Vec<E::Key>),
+    /// The entries requested with a [`MessagePart::ValueRequest`], together with the sender's
+    /// content status for each entry.
+    ///
+    /// Handled by [`SyncSession`], and ignored by [`Store::process_message`].
+    #[serde(bound(serialize = "E: Serialize", deserialize = "E: Deserialize<'de>"))]
+    ValueResponse(Vec<(E, ContentStatus)>),
+    /// The wire form of [`Message::has_more`], appended as the last part of the encoded
+    /// message.
+    ///
+    /// Decoding removes it from the parts of the message and sets the flag instead, so it is
+    /// never returned from [`Message::parts`].
+    More,
+    /// Requests the entries with exactly these keys, outside of a reconciliation, see
+    /// [`SyncSession::request_keys`].
+    ///
+    /// The receiver responds with [`MessagePart::ValueResponse`] parts with the entries it has,
+    /// and a [`MessagePart::MissingKeys`] with the keys it does not have. Handled by
+    /// [`SyncSession`], and ignored by [`Store::process_message`].
+    #[serde(bound(
+        serialize = "E::Key: Serialize",
+        deserialize = "E::Key: Deserialize<'de>"
+    ))]
+    WantKeys {
+        /// The keys of the requested entries.
+        keys: Vec<E::Key>,
+    },
+    /// The keys of a [`MessagePart::WantKeys`] the sender has no entry for, see
+    /// [`SyncSession::missing_keys`].
+    ///
+    /// Handled by [`SyncSession`], and ignored by [`Store::process_message`].
+    #[serde(bound(
+        serialize = "E::Key: Serialize",
+        deserialize = "E::Key: Deserialize<'de>"
+    ))]
+    MissingKeys {
+        /// The keys without an entry.
+        keys: Vec<E::Key>,
+    },
+    /// The summary of the sender's store, see [`SyncSession::with_store_summary`].
+    ///
+    /// Handled by [`SyncSession`], and ignored by [`Store::process_message`].
+    #[serde(bound(
+        serialize = "StoreSummary<E::Key>: Serialize",
+        deserialize = "StoreSummary<E::Key>: Deserialize<'de>"
+    ))]
+    StoreSummary(StoreSummary<E::Key>),
+    /// A [`MessagePart::RangeItem`] with delta encoded keys.
+    ///
+    /// Only sent in messages encoded with [`SyncSession::encode_message`], and replaced by the
+    /// plain part in [`SyncSession::decode_message`].
+    #[serde(bound(
+        serialize = "DeltaRangeItem<E::Key>: Serialize",
+        deserialize = "DeltaRangeItem<E::Key>: Deserialize<'de>"
+    ))]
+    DeltaRangeItem(DeltaRangeItem<E::Key>),
+    /// Requests all entries of a range from the receiver, sent by a session whose store is
+    /// empty in response to the initial message, see [`SyncSession::with_max_message_bytes`].
+    ///
+    /// The receiver responds with [`MessagePart::RangeItem`] parts that cover the range, each
+    /// of at most `max_bytes` bytes, and splits its response into messages of at most that size.
+    /// Handled by [`SyncSession`], and ignored by [`Store::process_message`].
+    #[serde(bound(
+        serialize = "E::Key: Serialize",
+        deserialize = "E::Key: Deserialize<'de>"
+    ))]
+    SendAll {
+        /// The range whose entries are requested.
+        range: Range<E::Key>,
+        /// The maximum size of a message the sender accepts, when encoded with postcard.
+        max_bytes: u64,
+    },
+    /// Marks a range whose fingerprints do not match, and which the sender does not split
+    /// further, sent instead of the entries of the range with [`SyncMode::VerifyOnly`].
+    ///
+    /// The receiver splits the range if it has more entries in it than its anchor threshold, see
+    /// [`SyncConfig::with_thresholds`], and otherwise responds with the range marked as settled.
+    /// A settled range is not answered.
+    #[serde(bound(
+        serialize = "E::Key: Serialize",
+        deserialize = "E::Key: Deserialize<'de>"
+    ))]
+    Divergent {
+        /// The range whose entries differ.
+        range: Range<E::Key>,
+        /// Whether both sides have at most as many entries in the range as their anchor
+        /// threshold, so the range is reported as divergent, see [`ProcessOutcome::divergent`].
+        settled: bool,
+    },
+    /// A piece of a [`MessagePart::RangeItem`] or [`MessagePart::ValueResponse`] with a single
+    /// entry that exceeds the message budget on its own, see
+    /// [`SyncSession::with_max_message_bytes`].
+    ///
+    /// The bytes of all chunks of a key, in the order of their index, are the encoding of a
+    /// message with the item as its only part, see [`Message::encode`]. The receiver buffers the
+    /// chunks, and replaces the last one with the reassembled item in
+    /// [`SyncSession::decode_message`], so the item is validated and inserted like any other.
+    /// Handled by [`SyncSession`], and ignored by [`Store::process_message`].
+    #[serde(bound(
+        serialize = "E::Key: Serialize",
+        deserialize = "E::Key: Deserialize<'de>"
+    ))]
+    ValueChunk {
+        /// The key of the entry.
+        key: E::Key,
+        /// The index of the chunk, starting at zero.
+        index: u32,
+        /// The number of chunks of the entry.
+        total: u32,
+        /// The bytes of the chunk.
+        bytes: Vec<u8>,
+    },
+    /// The ids of the parts of the message that expect a reply, and of the parts of the remote
+    /// it responds to, see [`SyncSession::with_range_ids`].
+    ///
+    /// Handled by [`SyncSession`], and ignored by [`Store::process_message`].
+    RangeIds(RangeIds),
 }
 
 impl<E: RangeEntry> MessagePart<E> {
+    /// Returns `true` if this is a [`MessagePart::RangeFingerprint`].
     pub fn is_range_fingerprint(&self) -> bool {
         matches!(self, MessagePart::RangeFingerprint(_))
     }
 
+    /// Returns `true` if this is a [`MessagePart::RangeItem`].
     pub fn is_range_item(&self) -> bool {
         matches!(self, MessagePart::RangeItem(_))
     }
 
+    /// Returns `true` if this is a [`MessagePart::Cancel`].
+    pub fn is_cancel(&self) -> bool {
+        matches!(self, MessagePart::Cancel)
+    }
+
+    /// Returns `true` if this part asks the receiver for a reply, see [`Message::expects_reply`].
+    ///
+    /// These are fingerprints, handshakes, requests for entries, and items in any encoding
+    /// whose sender did not include all of its entries in the range, see
+    /// [`RangeItem::have_local`].
+    pub fn expects_reply(&self) -> bool {
+        match self {
+            MessagePart::RangeFingerprint(_)
+            | MessagePart::Handshake(_)
+            | MessagePart::ValueRequest(_)
+            | MessagePart::WantKeys { .. }
+            | MessagePart::SendAll { .. } => true,
+            MessagePart::Divergent { settled, .. } => !settled,
+            MessagePart::RangeItem(RangeItem { have_local, .. })
+            | MessagePart::CompressedRangeItem(CompressedRangeItem { have_local, .. })
+            | MessagePart::LazyRangeItem(LazyRangeItem { have_local, .. })
+            | MessagePart::DeltaRangeItem(DeltaRangeItem { have_local, .. }) => !have_local,
+            MessagePart::Cancel
+            | MessagePart::HandshakeMatch
+            | MessagePart::SessionId(_)
+            | MessagePart::Sequence(_)
+            | MessagePart::Reopen
+            | MessagePart::Done
+            | MessagePart::ValueResponse(_)
+            | MessagePart::More
+            | MessagePart::MissingKeys { .. }
+            | MessagePart::StoreSummary(_)
+            | MessagePart::ValueChunk { .. }
+            | MessagePart::RangeIds(_) => false,
+        }
+    }
+
+    /// Get the values of this part, if it is a [`MessagePart::RangeItem`] or a
+    /// [`MessagePart::ValueResponse`].
     pub fn values(&self) -> Option<&[(E, ContentStatus)]> {
         match self {
-            MessagePart::RangeFingerprint(_) => None,
             MessagePart::RangeItem(RangeItem { values, .. }) => Some(values),
+            MessagePart::ValueResponse(values) => Some(values),
+            MessagePart::RangeFingerprint(_)
+            | MessagePart::Cancel
+            | MessagePart::Handshake(_)
+            | MessagePart::HandshakeMatch
+            | MessagePart::SessionId(_)
+            | MessagePart::Sequence(_)
+            | MessagePart::Reopen
+            | MessagePart::CompressedRangeItem(_)
+            | MessagePart::Done
+            | MessagePart::LazyRangeItem(_)
+            | MessagePart::ValueRequest(_)
+            | MessagePart::More
+            | MessagePart::WantKeys { .. }
+            | MessagePart::MissingKeys { .. }
+            | MessagePart::StoreSummary(_)
+            | MessagePart::DeltaRangeItem(_)
+            | MessagePart::SendAll { .. }
+            | MessagePart::Divergent { .. }
+            | MessagePart::ValueChunk { .. }
+            | MessagePart::RangeIds(_) => None,
+        }
+    }
+
+    /// Take the values of this part, if it is a [`MessagePart::RangeItem`] or a
+    /// [`MessagePart::ValueResponse`].
+    pub fn into_values(self) -> Option<Vec<(E, ContentStatus)>> {
+        match self {
+            MessagePart::RangeItem(RangeItem { values, .. }) => Some(values),
+            MessagePart::ValueResponse(values) => Some(values),
+            _ => None,
         }
     }
+
+    /// Get the size of this part when encoded with postcard, without encoding it.
+    pub fn encoded_size(&self) -> usize
+    where
+        Self: Serialize,
+    {
+        encoded_size(self)
+    }
 }
 
-#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+/// A message of the set reconciliation protocol.
+///
+/// See [`Message::encode`] for the wire encoding.
+#[derive(Debug, Clone, PartialEq)]
 pub struct Message<E: RangeEntry> {
-    #[serde(bound(
-        serialize = "MessagePart<E>: Serialize",
-        deserialize = "MessagePart<E>: Deserialize<'de>"
-    ))]
     parts: Vec<MessagePart<E>>,
+    /// Whether more messages of the same round follow, see [`Message::has_more`].
+    more: bool,
 }
 
 impl<E: RangeEntry> Message<E> {
     /// Construct the initial message.
     fn init<S: Store<E>>(store: &mut S) -> Result<Self, S::Error> {
         let x = store.get_first()?;
-        let range = Range::new(x.clone(), x);
+        Self::init_for_range(store, Range::new(x.clone(), x))
+    }
+
+    /// Construct the initial message for reconciling only the entries in `range`.
+    fn init_for_range<S: Store<E>>(store: &mut S, range: Range<E::Key>) -> Result<Self, S::Error> {
         let fingerprint = store.get_fingerprint(&range)?;
         let part = MessagePart::RangeFingerprint(RangeFingerprint { range, fingerprint });
-        Ok(Message { parts: vec![part] })
+        Ok(Message {
+            parts: vec![part],
+            more: false,
+        })
     }
 
-    pub fn parts(&self) -> &[MessagePart<E>] {
-        &self.parts
+    /// Construct a handshake message, see [`Store::handshake_message`].
+    fn handshake<S: Store<E>>(store: &mut S) -> Result<Self, S::Error> {
+        let part = MessagePart::Handshake(store.full_fingerprint()?);
+        Ok(Message {
+            parts: vec![part],
+            more: false,
+        })
     }
 
-    pub fn values(&self) -> impl Iterator<Item = &(E, ContentStatus)> {
-        self.parts().iter().filter_map(|p| p.values()).flatten()
+    /// Construct a message that cancels the sync.
+    ///
+    /// Sending it tells the remote that no more messages follow, and that it can discard the
+    /// state of the sync.
+    pub fn cancel() -> Self {
+        Message {
+            parts: vec![MessagePart::Cancel],
+            more: false,
+        }
     }
 
-    pub fn value_count(&self) -> usize {
-        self.values().count()
+    /// Returns `true` if this message cancels the sync, see [`Message::cancel`].
+    pub fn is_cancel(&self) -> bool {
+        self.parts.iter().any(MessagePart::is_cancel)
     }
-}
-
-pub trait Store<E: RangeEntry>: Sized {
-    type Error: Debug + Send + Sync + Into<anyhow::Error> + 'static;
 
-    type RangeIterator<'a>: Iterator<Item = Result<E, Self::Error>>
-    where
-        Self: 'a,
-        E: 'a;
+    /// Get the id of the session this message belongs to, see [`MessagePart::SessionId`].
+    pub fn session_id(&self) -> Option<SessionId> {
+        self.parts.iter().find_map(|part| match part {
+            MessagePart::SessionId(id) => Some(*id),
+            _ => None,
+        })
+    }
 
-    type ParentIterator<'a>: Iterator<Item = Result<E, Self::Error>>
-    where
-        Self: 'a,
-        E: 'a;
+    /// Get the sequence number of this message, see [`MessagePart::Sequence`].
+    pub fn sequence(&self) -> Option<u64> {
+        self.parts.iter().find_map(|part| match part {
+            MessagePart::Sequence(seq) => Some(*seq),
+            _ => None,
+        })
+    }
 
-    /// Get a the first key (or the default if none is available).
-    fn get_first(&mut self) -> Result<E::Key, Self::Error>;
+    /// Get the range ids of this message, see [`MessagePart::RangeIds`].
+    pub fn range_ids(&self) -> Option<&RangeIds> {
+        self.parts.iter().find_map(|part| match part {
+            MessagePart::RangeIds(ids) => Some(ids),
+            _ => None,
+        })
+    }
 
-    /// Get a single entry.
-    fn get(&mut self, key: &E::Key) -> Result<Option<E>, Self::Error>;
+    /// Returns `true` if this message re-advertises settled ranges, see [`MessagePart::Reopen`].
+    pub fn is_reopen(&self) -> bool {
+        self.parts
+            .iter()
+            .any(|part| matches!(part, MessagePart::Reopen))
+    }
 
-    /// Get the number of entries in the store.
-    fn len(&mut self) -> Result<usize, Self::Error>;
+    /// Returns `true` if the sender has nothing further to ask, see [`MessagePart::Done`].
+    pub fn is_done(&self) -> bool {
+        self.parts
+            .iter()
+            .any(|part| matches!(part, MessagePart::Done))
+    }
 
-    /// Returns `true` if the vector contains no elements.
-    fn is_empty(&mut self) -> Result<bool, Self::Error>;
+    /// Returns `true` if more messages of the same round follow this one, see
+    /// [`Message::paginate`].
+    ///
+    /// The receiver collects the parts of these messages, and processes them together with the
+    /// next message without the flag, see [`SyncSession::process_message`].
+    pub fn has_more(&self) -> bool {
+        self.more
+    }
 
-    /// Calculate the fingerprint of the given range.
-    fn get_fingerprint(&mut self, range: &Range<E::Key>) -> Result<Fingerprint, Self::Error>;
+    /// Set whether more messages of the same round follow this one, see [`Message::has_more`].
+    pub fn with_more(mut self, more: bool) -> Self {
+        self.more = more;
+        self
+    }
 
-    /// Insert just the given key value pair.
+    /// Splits this message into messages of at most `max_parts` parts, of which all but the
+    /// last have [`Message::has_more`] set.
     ///
-    /// This will replace just the existing entry, but will not perform prefix
-    /// deletion.
-    fn entry_put(&mut self, entry: E) -> Result<(), Self::Error>;
+    /// The last message has the flag of this message. A `max_parts` of zero is treated as one.
+    pub fn paginate(self, max_parts: usize) -> Vec<Self> {
+        let max_parts = max_parts.max(1);
+        if self.parts.len() <= max_parts {
+            return vec![self];
+        }
+        let more = self.more;
+        let mut parts = self.parts.into_iter().peekable();
+        let mut pages = Vec::new();
+        while parts.peek().is_some() {
+            pages.push(Message {
+                parts: parts.by_ref().take(max_parts).collect(),
+                more: true,
+            });
+        }
+        if let Some(last) = pages.last_mut() {
+            last.more = more;
+        }
+        pages
+    }
 
-    /// Returns all entries in the given range.
-    fn get_range(&mut self, range: Range<E::Key>) -> Result<Self::RangeIterator<'_>, Self::Error>;
+    /// Merges `messages` into a single message with the parts of all messages, in order, and
+    /// removes redundant parts, see [`Message::normalize`].
+    ///
+    /// The merged message has the flag of the last message, see [`Message::has_more`].
+    pub fn merge(messages: Vec<Message<E>>) -> Message<E> {
+        let more = messages.last().is_some_and(Message::has_more);
+        let parts = messages
+            .into_iter()
+            .flat_map(|message| message.parts)
+            .collect();
+        let mut message = Message { parts, more };
+        message.normalize();
+        message
+    }
 
-    /// Returns the number of entries in the range.
+    /// Removes redundant parts, without changing how the message is processed.
     ///
-    /// Default impl is not optimized, but does avoid excessive memory usage.
-    fn get_range_len(&mut self, range: Range<E::Key>) -> Result<usize, Self::Error> {
-        let mut count = 0;
-        for el in self.get_range(range)? {
-            let _el = el?;
-            count += 1;
+    /// [`MessagePart::RangeItem`]s with the same range are merged into the first of them, which
+    /// expects a response if any of them does, see [`RangeItem::have_local`]. Values with the
+    /// same key are merged into the first of them, with the greatest value, just like the
+    /// receiver would store them. Fingerprint parts that repeat an earlier one are dropped. All
+    /// other parts are kept as they are.
+    pub fn normalize(&mut self) {
+        let mut fingerprints = BTreeSet::new();
+        let mut items = BTreeMap::new();
+        let mut parts: Vec<MessagePart<E>> = Vec::with_capacity(self.parts.len());
+        for part in std::mem::take(&mut self.parts) {
+            match part {
+                MessagePart::RangeFingerprint(RangeFingerprint { range, fingerprint }) => {
+                    let key = (range.x().clone(), range.y().clone(), fingerprint.0);
+                    if fingerprints.insert(key) {
+                        let part = RangeFingerprint { range, fingerprint };
+                        parts.push(MessagePart::RangeFingerprint(part));
+                    }
+                }
+                MessagePart::RangeItem(item) => {
+                    let key = (item.range.x().clone(), item.range.y().clone());
+                    let first = items.get(&key).and_then(|i| match &mut parts[*i] {
+                        MessagePart::RangeItem(first) => Some(first),
+                        _ => None,
+                    });
+                    match first {
+                        Some(first) => {
+                            first.values.extend(item.values);
+                            first.have_local &= item.have_local;
+                        }
+                        None => {
+                            items.insert(key, parts.len());
+                            parts.push(MessagePart::RangeItem(item));
+                        }
+                    }
+                }
+                part => parts.push(part),
+            }
         }
-        Ok(count)
+        for part in parts.iter_mut() {
+            if let MessagePart::RangeItem(item) = part {
+                dedup_values(&mut item.values);
+            }
+        }
+        self.parts = parts;
     }
 
-    /// Returns all entries whose key starts with the given `prefix`.
-    fn prefixed_by(&mut self, prefix: &E::Key) -> Result<Self::RangeIterator<'_>, Self::Error>;
+    /// Get the parts of this message.
+    pub fn parts(&self) -> &[MessagePart<E>] {
+        &self.parts
+    }
 
-    /// Returns all entries that share a prefix with `key`, including the entry for `key` itself.
-    fn prefixes_of(&mut self, key: &E::Key) -> Result<Self::ParentIterator<'_>, Self::Error>;
+    /// Take the parts of this message.
+    pub fn into_parts(self) -> Vec<MessagePart<E>> {
+        self.parts
+    }
 
-    /// Get all entries in the store
-    fn all(&mut self) -> Result<Self::RangeIterator<'_>, Self::Error>;
+    /// Iterate over all values contained in this message.
+    pub fn values(&self) -> impl Iterator<Item = &(E, ContentStatus)> {
+        self.parts().iter().filter_map(|p| p.values()).flatten()
+    }
 
-    /// Remove an entry from the store.
-    ///
-    /// This will remove just the entry with the given key, but will not perform prefix deletion.
-    fn entry_remove(&mut self, key: &E::Key) -> Result<Option<E>, Self::Error>;
+    /// Get the number of values contained in this message.
+    pub fn value_count(&self) -> usize {
+        self.values().count()
+    }
 
-    /// Remove all entries whose key start with a prefix and for which the `predicate` callback
-    /// returns true.
+    /// Returns `true` if the receiver of this message may reply to it, so the sender has to keep
+    /// waiting for the remote.
     ///
-    /// Returns the number of elements removed.
-    // TODO: We might want to return an iterator with the removed elements instead to emit as
-    // events to the application potentially.
-    fn remove_prefix_filtered(
-        &mut self,
-        prefix: &E::Key,
-        predicate: impl Fn(&E::Value) -> bool,
-    ) -> Result<usize, Self::Error>;
+    /// This is the case if any part asks for a reply, see [`MessagePart::expects_reply`], and
+    /// the message does not cancel the sync. A message that does not expect a reply is never
+    /// answered, while one that expects a reply may still be answered with nothing, e.g. if all
+    /// of its fingerprints match. Pages of a round are answered together, see
+    /// [`Message::has_more`].
+    pub fn expects_reply(&self) -> bool {
+        !self.is_cancel() && self.parts.iter().any(MessagePart::expects_reply)
+    }
 
-    /// Generates the initial message.
-    fn initial_message(&mut self) -> Result<Message<E>, Self::Error> {
-        Message::init(self)
+    /// Get a summary of the parts of this message, e.g. for logging.
+    pub fn summary(&self) -> MessageSummary {
+        let mut summary = MessageSummary {
+            expects_reply: self.expects_reply(),
+            ..Default::default()
+        };
+        for part in &self.parts {
+            match part {
+                MessagePart::RangeFingerprint(_) => summary.fingerprint_parts += 1,
+                MessagePart::RangeItem(_)
+                | MessagePart::CompressedRangeItem(_)
+                | MessagePart::LazyRangeItem(_)
+                | MessagePart::DeltaRangeItem(_) => summary.item_parts += 1,
+                _ => {}
+            }
+        }
+        summary.total_values = self.value_count();
+        summary
     }
 
-    /// Processes an incoming message and produces a response.
-    /// If terminated, returns `None`
-    ///
-    /// `validate_cb` is called for each incoming entry received from the remote.
-    /// It must return true if the entry is valid and should be stored, and false otherwise
-    /// (which means the entry will be dropped and not stored).
-    ///
-    /// `on_insert_cb` is called for each entry that was actually inserted into the store (so not
-    /// for entries which validated, but are not inserted because they are older than one of their
-    /// prefixes).
+    /// Get the size of this message when encoded with postcard, without encoding it.
     ///
-    /// `content_status_cb` is called for each outgoing entry about to be sent to the remote.
-    /// It must return a [`ContentStatus`], which will be sent to the remote with the entry.
-    fn process_message<F, F2, F3>(
-        &mut self,
-        config: &SyncConfig,
-        message: Message<E>,
-        validate_cb: F,
-        mut on_insert_cb: F2,
-        content_status_cb: F3,
-    ) -> Result<Option<Message<E>>, Self::Error>
+    /// This is the varint encoded number of parts, followed by the encoded size of each part,
+    /// see [`MessagePart::encoded_size`].
+    pub fn encoded_size(&self) -> usize
     where
-        F: Fn(&Self, &E, ContentStatus) -> bool,
-        F2: FnMut(&Self, E, ContentStatus),
-        F3: Fn(&Self, &E) -> ContentStatus,
+        Self: Serialize,
     {
-        let mut out = Vec::new();
+        encoded_size(self)
+    }
+}
 
-        // TODO: can these allocs be avoided?
-        let mut items = Vec::new();
-        let mut fingerprints = Vec::new();
-        for part in message.parts {
-            match part {
-                MessagePart::RangeItem(item) => {
+/// A summary of the parts of a [`Message`], see [`Message::summary`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MessageSummary {
+    /// Number of [`MessagePart::RangeFingerprint`]s.
+    pub fingerprint_parts: usize,
+    /// Number of items, in any encoding, such as [`MessagePart::RangeItem`] and
+    /// [`MessagePart::LazyRangeItem`].
+    pub item_parts: usize,
+    /// Number of values, see [`Message::value_count`].
+    pub total_values: usize,
+    /// Whether the message expects a reply, see [`Message::expects_reply`].
+    pub expects_reply: bool,
+}
+
+/// Builds a [`Message`] part by part, e.g. to construct messages outside of a [`Store`].
+#[derive(Debug, Clone)]
+pub struct MessageBuilder<E: RangeEntry> {
+    parts: Vec<MessagePart<E>>,
+}
+
+impl<E: RangeEntry> Default for MessageBuilder<E> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<E: RangeEntry> MessageBuilder<E> {
+    /// Create a builder without any parts.
+    pub fn new() -> Self {
+        Self { parts: Vec::new() }
+    }
+
+    /// Add a [`MessagePart::RangeFingerprint`].
+    pub fn add_fingerprint(&mut self, range: Range<E::Key>, fingerprint: Fingerprint) -> &mut Self {
+        self.add_part(MessagePart::RangeFingerprint(RangeFingerprint {
+            range,
+            fingerprint,
+        }))
+    }
+
+    /// Add a [`MessagePart::RangeItem`].
+    pub fn add_items(
+        &mut self,
+        range: Range<E::Key>,
+        values: Vec<(E, ContentStatus)>,
+        have_local: bool,
+    ) -> &mut Self {
+        self.add_part(MessagePart::RangeItem(RangeItem {
+            range,
+            values,
+            have_local,
+        }))
+    }
+
+    /// Add any part.
+    pub fn add_part(&mut self, part: MessagePart<E>) -> &mut Self {
+        self.parts.push(part);
+        self
+    }
+
+    /// Returns `true` if no part was added.
+    pub fn is_empty(&self) -> bool {
+        self.parts.is_empty()
+    }
+
+    /// Build the message from the parts added so far, which leaves the builder empty.
+    pub fn build(&mut self) -> Message<E> {
+        Message {
+            parts: std::mem::take(&mut self.parts),
+            more: false,
+        }
+    }
+}
+
+/// Merges the values with the same key into the first of them, with the greatest value, see
+/// [`Message::normalize`].
+fn dedup_values<E: RangeEntry>(values: &mut Vec<(E, ContentStatus)>) {
+    let mut first: BTreeMap<E::Key, usize> = BTreeMap::new();
+    let mut deduped: Vec<(E, ContentStatus)> = Vec::with_capacity(values.len());
+    for (entry, content_status) in values.drain(..) {
+        match first.get(entry.key()) {
+            Some(&i) => {
+                if entry.value() > deduped[i].0.value() {
+                    deduped[i] = (entry, content_status);
+                }
+            }
+            None => {
+                first.insert(entry.key().clone(), deduped.len());
+                deduped.push((entry, content_status));
+            }
+        }
+    }
+    *values = deduped;
+}
+
+/// The remote's values of a range item, sorted by key, to tell which local entries of the range
+/// the remote lacks or has an older value for with a merge.
+struct RemoteValues<'a, E: RangeEntry> {
+    /// The keys with the greatest value the remote sent for them, in ascending order.
+    values: Vec<(&'a E::Key, &'a E::Value)>,
+    /// The first of `values` whose key is not lower than the last local key.
+    cursor: usize,
+}
+
+impl<'a, E: RangeEntry> RemoteValues<'a, E> {
+    fn new(values: &'a [(E, ContentStatus)]) -> Self {
+        let mut values: Vec<_> = values
+            .iter()
+            .map(|(entry, _)| (entry.key(), entry.value()))
+            .collect();
+        // sort the greatest value of each key first, and keep only that one
+        values.sort_unstable_by(|a, b| a.0.cmp(b.0).then_with(|| b.1.cmp(a.1)));
+        values.dedup_by(|next, first| next.0 == first.0);
+        Self { values, cursor: 0 }
+    }
+
+    /// Returns `true` if the remote sent the key of `entry` with a value that is not lower.
+    ///
+    /// Local entries are expected in ascending key order, which makes the merge linear. A key
+    /// lower than the previous one, e.g. where a wrap-around range starts over, repositions
+    /// the merge.
+    fn covers(&mut self, entry: &E) -> bool {
+        self.value_of(entry.key())
+            .is_some_and(|their_value| their_value >= entry.value())
+    }
+
+    /// Returns `true` if the local `entry` is sent back to the remote with `policy`, after the
+    /// remote's entries were stored.
+    ///
+    /// Local entries are expected in ascending key order, see [`RemoteValues::covers`].
+    fn echoes(&mut self, entry: &E, policy: EchoPolicy) -> bool {
+        match (self.value_of(entry.key()), policy) {
+            (None, _) => true,
+            (Some(_), EchoPolicy::Never) => false,
+            (Some(their_value), EchoPolicy::IfNewer) => entry.value() > their_value,
+            (Some(their_value), EchoPolicy::IfDifferent) => entry.value() != their_value,
+        }
+    }
+
+    /// Returns the greatest value the remote sent for `key`.
+    fn value_of(&mut self, key: &E::Key) -> Option<&'a E::Value> {
+        if self.cursor > 0 && self.values[self.cursor - 1].0 >= key {
+            self.cursor = self
+                .values
+                .partition_point(|(their_key, _)| *their_key < key);
+        }
+        while self
+            .values
+            .get(self.cursor)
+            .is_some_and(|(their_key, _)| *their_key < key)
+        {
+            self.cursor += 1;
+        }
+        match self.values.get(self.cursor) {
+            Some((their_key, their_value)) if *their_key == key => Some(their_value),
+            _ => None,
+        }
+    }
+}
+
+/// The entries of a range within a [`SendThreshold`], or the fingerprint of the range if they
+/// exceed it, see [`SendThreshold::read_range`].
+enum RangeContent<E> {
+    Entries(Vec<E>),
+    Fingerprint(Fingerprint),
+}
+
+/// Removes the `entries` of `range` that are in one of the `sent` ranges, and adds `range` to
+/// them, so that ranges of a message that overlap send each entry only once.
+fn retain_unsent<E: RangeEntry>(
+    sent: &mut Vec<Range<E::Key>>,
+    range: &Range<E::Key>,
+    entries: &mut Vec<E>,
+) {
+    let overlapping: Vec<_> = sent.iter().filter(|sent| sent.overlaps(range)).collect();
+    if !overlapping.is_empty() {
+        entries.retain(|entry| !overlapping.iter().any(|sent| sent.contains(entry.key())));
+    }
+    sent.push(range.clone());
+}
+
+/// Removes the received `values` that lie outside of `range`, and all but the greatest value of
+/// each key, and records them in `outcome`.
+///
+/// A greater value replaces a lower one when it is stored, so keeping the greatest value makes
+/// the result independent of the order the remote sent the values in.
+fn retain_valid<E: RangeEntry>(
+    range: &Range<E::Key>,
+    values: &mut Vec<(E, ContentStatus)>,
+    outcome: &mut ProcessOutcome<E>,
+    list: bool,
+) {
+    values.retain(|(entry, _)| {
+        let contained = range.contains(entry.key());
+        if !contained {
+            outcome.out_of_range.add(entry, list);
+        }
+        contained
+    });
+    // sort the greatest value of each key first, and keep only that one
+    values.sort_by(|(a, _), (b, _)| a.key().cmp(b.key()).then_with(|| b.value().cmp(a.value())));
+    values.dedup_by(|(next, _), (first, _)| {
+        let duplicate = next.key() == first.key();
+        if duplicate {
+            outcome.duplicates.add(next, list);
+        }
+        duplicate
+    });
+}
+
+/// Returns `true` if `entry`, whose key is a prefix of `key`, keeps older entries of `key` from
+/// being inserted, see [`RangeEntry::deletes_prefixed`].
+fn shadows<E: RangeEntry>(entry: &E, key: &E::Key) -> bool {
+    entry.key() == key || entry.deletes_prefixed()
+}
+
+/// The position of a part in a reply of [`Store::process_message`]: parts without a range come
+/// first, and the others are ordered by the start of their range, fingerprints before items.
+fn reply_order<E: RangeEntry>(part: &MessagePart<E>) -> (Option<&E::Key>, bool) {
+    match part {
+        MessagePart::RangeFingerprint(fp) => (Some(fp.range.x()), false),
+        MessagePart::RangeItem(item) => (Some(item.range.x()), true),
+        MessagePart::Divergent { range, .. } => (Some(range.x()), true),
+        _ => (None, false),
+    }
+}
+
+/// Returns the size of `value` when encoded with postcard.
+fn encoded_size<T: Serialize + ?Sized>(value: &T) -> usize {
+    // serializing only fails for types that cannot be serialized at all, and those cannot be
+    // sent either
+    postcard::experimental::serialized_size(value).unwrap_or(0)
+}
+
+/// Storage for the entries of a set, and the set reconciliation protocol implemented on top of it.
+pub trait Store<E: RangeEntry>: Sized {
+    /// The error type returned from store operations.
+    type Error: Debug + Send + Sync + Into<anyhow::Error> + 'static;
+
+    /// Iterator over the entries of a range.
+    type RangeIterator<'a>: Iterator<Item = Result<E, Self::Error>>
+    where
+        Self: 'a,
+        E: 'a;
+
+    /// Iterator over the entries whose keys are a prefix of a key.
+    type ParentIterator<'a>: Iterator<Item = Result<E, Self::Error>>
+    where
+        Self: 'a,
+        E: 'a;
+
+    /// Get the epoch of the store, a counter that is incremented with each write to the store.
+    ///
+    /// A [`SyncSession`] compares the epoch between the calls that process messages, to detect
+    /// writes outside of the session to ranges it settled before, see
+    /// [`SyncSession::epoch_report`]. The default impl returns 0 for stores that do not count
+    /// their writes, so that such writes are not detected. [`EpochStore`] counts the writes of
+    /// any store.
+    fn epoch(&self) -> u64 {
+        0
+    }
+
+    /// Get a the first key (or the default if none is available).
+    fn get_first(&mut self) -> Result<E::Key, Self::Error>;
+
+    /// Get the smallest and the largest key in the store, or `None` if the store is empty.
+    ///
+    /// Default impl walks all entries, stores should override this if they can do better.
+    #[allow(clippy::type_complexity)]
+    fn first_and_last(&mut self) -> Result<Option<(E::Key, E::Key)>, Self::Error> {
+        let mut bounds: Option<(E::Key, E::Key)> = None;
+        for el in self.all()? {
+            let el = el?;
+            let key = el.key();
+            bounds = match bounds {
+                None => Some((key.clone(), key.clone())),
+                Some((first, last)) => {
+                    let first = if key < &first { key.clone() } else { first };
+                    let last = if key > &last { key.clone() } else { last };
+                    Some((first, last))
+                }
+            };
+        }
+        Ok(bounds)
+    }
+
+    /// Get a single entry.
+    fn get(&mut self, key: &E::Key) -> Result<Option<E>, Self::Error>;
+
+    /// Get the fingerprint of the entry for `key`, see [`RangeEntry::as_fingerprint`].
+    ///
+    /// This is used to skip writing received entries that are identical to the stored ones.
+    /// Stores that keep the fingerprints of their entries should override this method, so that
+    /// the entry does not have to be read.
+    fn entry_fingerprint(&mut self, key: &E::Key) -> Result<Option<Fingerprint>, Self::Error> {
+        Ok(self.get(key)?.map(|entry| entry.as_fingerprint()))
+    }
+
+    /// Get the number of entries in the store.
+    fn len(&mut self) -> Result<usize, Self::Error>;
+
+    /// Returns `true` if the vector contains no elements.
+    fn is_empty(&mut self) -> Result<bool, Self::Error>;
+
+    /// Calculate the fingerprint of the given range.
+    fn get_fingerprint(&mut self, range: &Range<E::Key>) -> Result<Fingerprint, Self::Error>;
+
+    /// Insert just the given key value pair.
+    ///
+    /// This will replace just the existing entry, but will not perform prefix
+    /// deletion.
+    fn entry_put(&mut self, entry: E) -> Result<(), Self::Error>;
+
+    /// Returns all entries in the given range.
+    fn get_range(&mut self, range: Range<E::Key>) -> Result<Self::RangeIterator<'_>, Self::Error>;
+
+    /// Returns all entries in the given range, split into chunks of at most `chunk_size`
+    /// entries.
+    ///
+    /// Each [`Chunk`] carries the sub-range it covers. The sub-ranges of consecutive chunks are
+    /// adjacent, and together they cover exactly `range`. For wrap-around ranges, the first chunk
+    /// starts at `range.x()`.
+    ///
+    /// Default impl reads the range with [`Store::get_range`] one chunk at a time, starting at
+    /// `range.x()`, and splits it with [`RangeChunks`]. It expects `get_range` to yield the
+    /// entries of each of the two parts of a wrap-around range in ascending key order. Stores
+    /// that can iterate a range in range order should override this, and use [`RangeChunks`]
+    /// over a single iterator.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `chunk_size` is 0.
+    fn get_range_chunked<'a>(
+        &'a mut self,
+        range: Range<E::Key>,
+        chunk_size: usize,
+    ) -> Result<ChunkIterator<'a, E, Self::Error>, Self::Error>
+    where
+        E: 'a,
+    {
+        let iter = RangePages::new(self, &range, chunk_size)?;
+        Ok(Box::new(RangeChunks::new(range, chunk_size, iter)))
+    }
+
+    /// Returns the number of entries in the range.
+    ///
+    /// Default impl is not optimized, but does avoid excessive memory usage.
+    fn get_range_len(&mut self, range: Range<E::Key>) -> Result<usize, Self::Error> {
+        let mut count = 0;
+        for el in self.get_range(range)? {
+            let _el = el?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Returns all entries whose key starts with the given `prefix`.
+    fn prefixed_by(&mut self, prefix: &E::Key) -> Result<Self::RangeIterator<'_>, Self::Error>;
+
+    /// Returns all entries that share a prefix with `key`, including the entry for `key` itself.
+    fn prefixes_of(&mut self, key: &E::Key) -> Result<Self::ParentIterator<'_>, Self::Error>;
+
+    /// Get all entries in the store
+    fn all(&mut self) -> Result<Self::RangeIterator<'_>, Self::Error>;
+
+    /// Remove an entry from the store.
+    ///
+    /// This will remove just the entry with the given key, but will not perform prefix deletion.
+    fn entry_remove(&mut self, key: &E::Key) -> Result<Option<E>, Self::Error>;
+
+    /// Remove all entries whose key start with a prefix and for which the `predicate` callback
+    /// returns true.
+    ///
+    /// Returns the number of elements removed.
+    // TODO: We might want to return an iterator with the removed elements instead to emit as
+    // events to the application potentially.
+    fn remove_prefix_filtered(
+        &mut self,
+        prefix: &E::Key,
+        predicate: impl Fn(&E::Value) -> bool,
+    ) -> Result<usize, Self::Error>;
+
+    /// Writes a snapshot of all entries in the store to `w`.
+    ///
+    /// The snapshot starts with a header containing the format version ([`SNAPSHOT_VERSION`])
+    /// and the fingerprint of the full range, followed by the postcard encoded entries.
+    fn export<W: io::Write>(&mut self, w: W) -> Result<(), ExportError>
+    where
+        E: Serialize,
+    {
+        snapshot::export(self, w)
+    }
+
+    /// Creates a new store from a snapshot written with [`Store::export`].
+    ///
+    /// The fingerprint of the imported entries is verified against the fingerprint in the
+    /// snapshot header. If the snapshot is corrupt or truncated, an error is returned and no
+    /// store is created.
+    fn import<R: io::Read>(r: R) -> Result<Self, ImportError>
+    where
+        Self: Default,
+        E: DeserializeOwned,
+    {
+        snapshot::import(r)
+    }
+
+    /// Generates the initial message.
+    fn initial_message(&mut self) -> Result<Message<E>, Self::Error> {
+        Message::init(self)
+    }
+
+    /// Get the fingerprint and the number of entries of the whole store.
+    fn full_fingerprint(&mut self) -> Result<FullFingerprint, Self::Error> {
+        let x = self.get_first()?;
+        let fingerprint = self.get_fingerprint(&Range::new(x.clone(), x))?;
+        let count = self.len()? as u64;
+        Ok(FullFingerprint { fingerprint, count })
+    }
+
+    /// Get the summary of the whole store, see [`StoreSummary`].
+    fn store_summary(&mut self) -> Result<StoreSummary<E::Key>, Self::Error> {
+        let FullFingerprint { fingerprint, count } = self.full_fingerprint()?;
+        let (first_key, last_key) = self.first_and_last()?.unzip();
+        Ok(StoreSummary {
+            entry_count: count,
+            first_key,
+            last_key,
+            full_fingerprint: fingerprint,
+        })
+    }
+
+    /// Generates a handshake message, which can be sent instead of the initial message.
+    ///
+    /// The message only contains the [`Store::full_fingerprint`]. If the remote store has the
+    /// same fingerprint and number of entries, the remote confirms the match and the sync ends
+    /// after one round trip. Otherwise the remote continues as if it had received the initial
+    /// message.
+    fn handshake_message(&mut self) -> Result<Message<E>, Self::Error> {
+        Message::handshake(self)
+    }
+
+    /// Generates the initial message for reconciling only the entries in `range`.
+    ///
+    /// Each side only ever splits the ranges it receives, so entries outside of `range` are not
+    /// exchanged in either direction. A range with equal bounds covers the whole set.
+    fn initial_message_for_range(
+        &mut self,
+        range: Range<E::Key>,
+    ) -> Result<Message<E>, Self::Error> {
+        Message::init_for_range(self, range)
+    }
+
+    /// Processes an incoming message and produces a response.
+    /// If terminated, the reply of the outcome is `None`.
+    ///
+    /// The outcome also reports which entries were stored or rejected, and which ranges are
+    /// settled, see [`ProcessOutcome`]. Use [`Store::process_message_reply`] if only the reply is
+    /// needed.
+    ///
+    /// `validate_cb` is called for each incoming entry received from the remote.
+    /// It must return true if the entry is valid and should be stored, and false otherwise
+    /// (which means the entry will be dropped and not stored).
+    ///
+    /// `on_insert_cb` is called for each entry that was actually inserted into the store (so not
+    /// for entries which validated, but are not inserted because they are older than one of their
+    /// prefixes).
+    ///
+    /// `content_status_cb` is called for each outgoing entry about to be sent to the remote.
+    /// It must return a [`ContentStatus`], which will be sent to the remote with the entry.
+    ///
+    /// A message that cancels the sync, see [`Message::cancel`], is not processed, and an
+    /// empty outcome is returned.
+    ///
+    /// Parts in a compact encoding, e.g. [`MessagePart::LazyRangeItem`] or
+    /// [`MessagePart::ValueChunk`], have to be expanded with [`SyncSession::decode_message`]
+    /// first, and are rejected with [`MessageValidationError::UnexpandedPart`] otherwise.
+    ///
+    /// The reply only depends on the message and the entries of the store. Ranges are split at
+    /// pivots chosen by their position among the entries of the range, and the parts of the
+    /// reply are ordered by the start of their range, with fingerprints before items of the same
+    /// start. Processing the same message with equal stores thus gives equal replies.
+    fn process_message<F, F2, F3>(
+        &mut self,
+        config: &SyncConfig,
+        message: Message<E>,
+        validate_cb: F,
+        on_insert_cb: F2,
+        content_status_cb: F3,
+    ) -> Result<ProcessOutcome<E>, ProcessError<Self::Error>>
+    where
+        F: Fn(&Self, &E, ContentStatus) -> bool,
+        F2: FnMut(&Self, E, ContentStatus),
+        F3: Fn(&Self, &E) -> ContentStatus,
+    {
+        self.process_message_with_options(
+            config,
+            message,
+            ProcessOptions::default(),
+            validate_cb,
+            on_insert_cb,
+            content_status_cb,
+        )
+    }
+
+    /// Processes an incoming message like [`Store::process_message`], and returns only the
+    /// response, or `None` if terminated.
+    fn process_message_reply<F, F2, F3>(
+        &mut self,
+        config: &SyncConfig,
+        message: Message<E>,
+        validate_cb: F,
+        on_insert_cb: F2,
+        content_status_cb: F3,
+    ) -> Result<Option<Message<E>>, ProcessError<Self::Error>>
+    where
+        F: Fn(&Self, &E, ContentStatus) -> bool,
+        F2: FnMut(&Self, E, ContentStatus),
+        F3: Fn(&Self, &E) -> ContentStatus,
+    {
+        self.process_message(
+            config,
+            message,
+            validate_cb,
+            on_insert_cb,
+            content_status_cb,
+        )
+        .map(|outcome| outcome.reply)
+    }
+
+    /// Processes the parts of an incoming message as they are read, e.g. with a
+    /// [`MessagePartReader`], and produces the same response as [`Store::process_message`].
+    ///
+    /// [`Store::process_message`] stores the entries of all items before it compares any
+    /// fingerprint, so the fingerprints of a message match if its items settle the difference.
+    /// To keep that order, each [`MessagePart::RangeItem`] is processed as soon as it is read,
+    /// while fingerprints and all other parts are kept until the last part was read, and are
+    /// processed together. Only those are held in memory, which are small compared to items.
+    ///
+    /// Unlike [`Store::process_message`], the entries of items that were read before a part that
+    /// cancels the sync, or before an error, were already stored. `None` is returned for a
+    /// message that cancels the sync, and no further parts are read.
+    fn process_parts<I, F, F2, F3>(
+        &mut self,
+        config: &SyncConfig,
+        parts: I,
+        validate_cb: F,
+        mut on_insert_cb: F2,
+        content_status_cb: F3,
+    ) -> Result<Option<Message<E>>, PartsError<Self::Error>>
+    where
+        I: IntoIterator<Item = Result<MessagePart<E>, DecodeError>>,
+        F: Fn(&Self, &E, ContentStatus) -> bool,
+        F2: FnMut(&Self, E, ContentStatus),
+        F3: Fn(&Self, &E) -> ContentStatus,
+    {
+        let mut items = Vec::new();
+        let mut rest = MessageBuilder::new();
+        for part in parts {
+            let part = part.map_err(PartsError::Decode)?;
+            if part.is_cancel() {
+                return Ok(None);
+            }
+            if !part.is_range_item() {
+                rest.add_part(part);
+                continue;
+            }
+            let message = Message {
+                parts: vec![part],
+                more: false,
+            };
+            let response = self.process_message_reply(
+                config,
+                message,
+                &validate_cb,
+                &mut on_insert_cb,
+                &content_status_cb,
+            )?;
+            items.extend(response.into_iter().flat_map(Message::into_parts));
+        }
+        let response = self.process_message_reply(
+            config,
+            rest.build(),
+            &validate_cb,
+            &mut on_insert_cb,
+            &content_status_cb,
+        )?;
+        // the parts are ordered like those of a reply of `process_message`
+        let mut parts: Vec<_> = items
+            .into_iter()
+            .chain(response.into_iter().flat_map(Message::into_parts))
+            .collect();
+        parts.sort_by(|a, b| reply_order(a).cmp(&reply_order(b)));
+        Ok((!parts.is_empty()).then_some(Message { parts, more: false }))
+    }
+
+    /// Processes an incoming message like [`Store::process_message`], with the [`ProcessOptions`]
+    /// that are not part of the [`SyncConfig`].
+    fn process_message_with_options<F, F2, F3>(
+        &mut self,
+        config: &SyncConfig,
+        message: Message<E>,
+        options: ProcessOptions<'_, E>,
+        validate_cb: F,
+        mut on_insert_cb: F2,
+        content_status_cb: F3,
+    ) -> Result<ProcessOutcome<E>, ProcessError<Self::Error>>
+    where
+        F: Fn(&Self, &E, ContentStatus) -> bool,
+        F2: FnMut(&Self, E, ContentStatus),
+        F3: Fn(&Self, &E) -> ContentStatus,
+    {
+        let transform_cb = |entry: E| match options.transform {
+            Some(transform) => transform(entry),
+            None => Some(entry),
+        };
+        let send_threshold_cb = |range: &Range<E::Key>| match options.send_threshold {
+            Some(send_threshold) => send_threshold(range),
+            None => config.send_threshold,
+        };
+        let mut outcome = ProcessOutcome::default();
+        if message.is_cancel() {
+            return Ok(outcome);
+        }
+        let list = config.outcome_entries;
+        let mut out = MessageBuilder::new();
+        // the ranges whose local entries are sent, see `retain_unsent`
+        let mut sent = Vec::new();
+
+        let verify = config.mode == SyncMode::VerifyOnly;
+
+        // TODO: can these allocs be avoided?
+        let mut items = Vec::new();
+        let mut fingerprints = Vec::new();
+        let mut divergent = Vec::new();
+        for (index, part) in message.parts.into_iter().enumerate() {
+            match part {
+                MessagePart::RangeItem(item) => {
                     items.push(item);
                 }
-                MessagePart::RangeFingerprint(fp) => {
-                    fingerprints.push(fp);
+                MessagePart::RangeFingerprint(fp) => {
+                    fingerprints.push(fp);
+                }
+                MessagePart::Divergent { range, settled } => {
+                    if settled {
+                        outcome.divergent.push(range);
+                    } else {
+                        divergent.push(range);
+                    }
+                }
+                MessagePart::Handshake(remote) => {
+                    if self.full_fingerprint()? == remote {
+                        out.add_part(MessagePart::HandshakeMatch);
+                        let x = self.get_first()?;
+                        outcome.settled.push(Range::new(x.clone(), x));
+                    } else {
+                        // continue with the whole set, like for the initial message
+                        let x = self.get_first()?;
+                        fingerprints.push(RangeFingerprint {
+                            range: Range::new(x.clone(), x),
+                            fingerprint: remote.fingerprint,
+                        });
+                    }
+                }
+                MessagePart::Cancel
+                | MessagePart::HandshakeMatch
+                | MessagePart::SessionId(_)
+                | MessagePart::Sequence(_)
+                | MessagePart::Reopen
+                | MessagePart::Done
+                | MessagePart::ValueRequest(_)
+                | MessagePart::ValueResponse(_)
+                | MessagePart::More
+                | MessagePart::WantKeys { .. }
+                | MessagePart::MissingKeys { .. }
+                | MessagePart::StoreSummary(_)
+                | MessagePart::SendAll { .. }
+                | MessagePart::RangeIds(_) => {}
+                MessagePart::CompressedRangeItem(_)
+                | MessagePart::DeltaRangeItem(_)
+                | MessagePart::LazyRangeItem(_)
+                | MessagePart::ValueChunk { .. } => {
+                    return Err(ProcessError::InvalidMessage(
+                        MessageValidationError::UnexpandedPart { part: index },
+                    ));
+                }
+            }
+        }
+
+        // Process item messages
+        for RangeItem {
+            range,
+            mut values,
+            have_local,
+        } in items
+        {
+            retain_valid(&range, &mut values, &mut outcome, list);
+            // only compare the items of a remote that does not verify, and store nothing
+            if verify {
+                let mut remote = Fingerprint::empty();
+                for (entry, _) in &values {
+                    remote ^= entry.as_fingerprint();
+                }
+                match self.get_fingerprint(&range)? == remote {
+                    true => outcome.settled.push(range),
+                    false => outcome.divergent.push(range),
+                }
+                continue;
+            }
+            // without sending local values, the remote's values are never answered
+            let have_local = have_local || config.direction == SyncDirection::ReceiveOnly;
+            // with `EchoPolicy::Never`, the local entries for the remote's keys are held back,
+            // and only sent back if the remote's entry for the key is rejected
+            let echo_rejected = !have_local && config.echo_policy == EchoPolicy::Never;
+            let mut held_back = Vec::new();
+            let mut diff = Vec::new();
+            if !have_local {
+                // we get the range of the item form our store. from this set, we remove all
+                // entries that the echo policy does not send back for the peer's entries.
+                let mut remote = RemoteValues::new(&values);
+                for our_entry in self.get_range(range.clone())? {
+                    let our_entry = our_entry?;
+                    if remote.echoes(&our_entry, config.echo_policy) {
+                        diff.push(our_entry);
+                    } else if echo_rejected {
+                        held_back.push(our_entry);
+                    }
+                }
+            }
+
+            // Store incoming values
+            // the keys of the stored entries, and the stored entries that removed entries with
+            // keys they are a prefix of
+            let mut stored = Vec::new();
+            let mut removed_prefixed = Vec::new();
+            // the keys of rejected entries, in ascending order, whose local entries are sent
+            // back with any policy
+            let mut rejected = Vec::new();
+            for (entry, content_status) in values {
+                if config.direction == SyncDirection::SendOnly {
+                    continue;
+                }
+                if validate_cb(self, &entry, content_status) {
+                    let Some(entry) = transform_cb(entry) else {
+                        continue;
+                    };
+                    // skip writing entries that are stored already
+                    let existing = self.entry_fingerprint(entry.key())?;
+                    if existing == Some(entry.as_fingerprint()) {
+                        outcome.already_present.add(&entry, list);
+                        continue;
+                    }
+                    let replaces = existing.is_some();
+                    // TODO: Get rid of the clone?
+                    if let InsertOutcome::Inserted { removed } = self.put(entry.clone())? {
+                        match replaces {
+                            true => outcome.replaced.add(&entry, list),
+                            false => outcome.inserted.add(&entry, list),
+                        }
+                        stored.push(entry.key().clone());
+                        if entry.deletes_prefixed() && removed > 0 {
+                            removed_prefixed.push(entry.clone());
+                        }
+                        on_insert_cb(self, entry, content_status);
+                    }
+                } else {
+                    if echo_rejected {
+                        rejected.push(entry.key().clone());
+                    }
+                    outcome.rejected.add(&entry, list);
+                }
+            }
+            if !rejected.is_empty() {
+                // the held back entries were read from the range like the others, so entries
+                // the store does not yield from a range are not sent back either
+                held_back.retain(|entry| rejected.binary_search(entry.key()).is_ok());
+                diff.append(&mut held_back);
+                diff.sort_by(|a, b| a.key().cmp(b.key()));
+            }
+            if !have_local {
+                retain_unsent(&mut sent, &range, &mut diff);
+            }
+
+            // The diff was read before the incoming values were stored, which may have replaced
+            // or removed some of its entries. Those are stale and not sent back. They are found
+            // from the stored entries, as `Store::put` replaces the entry of the key and removes
+            // the older entries whose keys the stored key is a prefix of.
+            if !stored.is_empty() {
+                stored.sort();
+                diff.retain(|ours| {
+                    stored.binary_search(ours.key()).is_err()
+                        && !removed_prefixed.iter().any(|theirs: &E| {
+                            theirs.key().is_prefix_of(ours.key()) && theirs.value() >= ours.value()
+                        })
+                });
+            }
+
+            if diff.is_empty() {
+                outcome.settled.push(range);
+            } else {
+                // add the content status in a second pass
+                let diff = diff
+                    .into_iter()
+                    .map(|entry| {
+                        let content_status = content_status_cb(self, &entry);
+                        (entry, content_status)
+                    })
+                    .collect();
+                out.add_items(range, diff, true);
+            }
+        }
+
+        // Process fingerprint messages, and the divergent ranges the remote did not settle,
+        // which have no remote fingerprint
+        let fingerprints = fingerprints
+            .into_iter()
+            .map(|RangeFingerprint { range, fingerprint }| (range, Some(fingerprint)))
+            .chain(divergent.into_iter().map(|range| (range, None)));
+        for (range, remote) in fingerprints {
+            if let Some(fingerprint) = remote {
+                let local_fingerprint = self.get_fingerprint(&range)?;
+                // Case1 Match, nothing to do
+                if local_fingerprint == fingerprint {
+                    outcome.settled.push(range);
+                    continue;
+                }
+                outcome.mismatched.push((
+                    RangeFingerprint {
+                        range: range.clone(),
+                        fingerprint,
+                    },
+                    local_fingerprint,
+                ));
+            }
+
+            let num_local_values = self.get_range_len(range.clone())?;
+            let receive_only = config.direction == SyncDirection::ReceiveOnly;
+            // When verifying, the recursion only ends at the anchor threshold, and the range is
+            // marked as divergent instead of being answered with its entries. It is settled once
+            // both sides are within their threshold.
+            if verify && num_local_values <= config.anchor_threshold {
+                let settled = remote.is_none();
+                if settled {
+                    outcome.divergent.push(range.clone());
+                }
+                out.add_part(MessagePart::Divergent { range, settled });
+                continue;
+            }
+            // With an item threshold, an empty range is answered with an empty fingerprint
+            // instead of an empty item, so that the remote splits its entries into ranges within
+            // the threshold instead of sending all of them at once. The whole set can not be
+            // answered with a fingerprint, as that would start a new sync.
+            if !verify
+                && num_local_values == 0
+                && config.item_threshold < usize::MAX
+                && !range.is_all()
+            {
+                out.add_fingerprint(range, Fingerprint::empty());
+                continue;
+            }
+
+            // Case2 Recursion Anchor
+            let anchor = !verify
+                && (num_local_values <= config.anchor_threshold
+                    || (remote == Some(Fingerprint::empty())
+                        && (receive_only || num_local_values <= config.item_threshold))
+                    || (!receive_only
+                        && !send_threshold_cb(&range).is_exceeded_by_range(
+                            self,
+                            &range,
+                            num_local_values,
+                        )?));
+            if !anchor {
+                // Case3 Recurse
+                let (num_parts, num_sent) = (out.parts.len(), sent.len());
+                // Create partition
+                // m0 = x < m1 < .. < mk = y, with k>= 2
+                // such that [ml, ml+1) is nonempty
+                let mut ranges = Vec::with_capacity(config.split_factor);
+
+                // select the pivot values. pivots repeat every split_factor, so pivot(i) == pivot(i + self.split_factor * x)
+                // it is guaranteed that pivot(0) != x if local_values.len() >= 2
+                //
+                // The pivots are the entries at these offsets from the start of the range:
+                // 1/2, 1 in case of split_factor == 2
+                // 1/3, 2/3, 1 in case of split_factor == 3
+                // etc., where an offset of 1 wraps around to the start of the range. Chunks are
+                // yielded in range order, starting at x, so all pivots are read in one pass. Each
+                // chunk holds a single entry, so only the pivots are kept in memory.
+                let offsets: Vec<_> = (0..config.split_factor)
+                    .map(|i| (num_local_values * (i + 1)) / config.split_factor % num_local_values)
+                    .collect();
+                let last_offset = offsets.iter().copied().max().unwrap_or_default();
+                let mut pivots = vec![None; config.split_factor];
+                let mut position = 0;
+                for chunk in self.get_range_chunked(range.clone(), 1)? {
+                    let entries = chunk?.entries;
+                    for (pivot, offset) in pivots.iter_mut().zip(&offsets) {
+                        let entry = offset.checked_sub(position).and_then(|i| entries.get(i));
+                        if let Some(entry) = entry {
+                            *pivot = Some(entry.key().clone());
+                        }
+                    }
+                    position += entries.len();
+                    if position > last_offset {
+                        break;
+                    }
+                }
+                // a store whose range has fewer entries than its length claims, e.g. because it
+                // changed between the reads, has no pivot at the offsets past its end. The range
+                // is then not split at all, and answered like a degenerate partition below.
+                let pivots: Vec<_> = pivots.into_iter().flatten().collect();
+                // ensure that pivots wrap around
+                let pivot = |i: usize| pivots[i % config.split_factor].clone();
+                if pivots.len() < config.split_factor {
+                    // leave the range unsplit, see above
+                } else if range.is_all() {
+                    // the range is the whole set, so range.x and range.y should not matter
+                    // just add all ranges as normal ranges. Exactly one of the ranges will
+                    // wrap around, so we cover the entire set.
+                    for i in 0..config.split_factor {
+                        let (x, y) = (pivot(i), pivot(i + 1));
+                        // don't push empty ranges
+                        if x != y {
+                            ranges.push(Range { x, y })
+                        }
+                    }
+                } else {
+                    // guaranteed to be non-empty because
+                    // - pivot(0) is guaranteed to be != x for local_values.len() >= 2
+                    // - local_values.len() < 2 gets handled by the recursion anchor, as the
+                    //   anchor threshold is at least 1
+                    // - x != y (regular range)
+                    ranges.push(Range {
+                        x: range.x().clone(),
+                        y: pivot(0),
+                    });
+                    // this will only be executed for split_factor > 2
+                    for i in 0..config.split_factor - 2 {
+                        // don't push empty ranges
+                        let (x, y) = (pivot(i), pivot(i + 1));
+                        if x != y {
+                            ranges.push(Range { x, y })
+                        }
+                    }
+                    // guaranteed to be non-empty because
+                    // - pivot is a value in the range
+                    // - y is the exclusive end of the range
+                    // - x != y (regular range)
+                    ranges.push(Range {
+                        x: pivot(config.split_factor - 2),
+                        y: range.y().clone(),
+                    });
+                }
+
+                let mut non_empty = 0;
+                for range in ranges {
+                    // Read the range once, for either the fingerprint or the item set. Without
+                    // sending local values, only empty item sets can be sent.
+                    let send_threshold = match receive_only || verify {
+                        true => SendThreshold::Entries(0),
+                        false => send_threshold_cb(&range),
+                    };
+                    match send_threshold.read_range(self, &range)? {
+                        // when verifying, empty ranges are compared like all others
+                        RangeContent::Entries(_) if verify => {
+                            out.add_fingerprint(range, Fingerprint::empty());
+                        }
+                        RangeContent::Entries(mut chunk) => {
+                            if !chunk.is_empty() {
+                                non_empty += 1;
+                            }
+                            retain_unsent(&mut sent, &range, &mut chunk);
+                            let values = chunk
+                                .into_iter()
+                                .map(|entry| {
+                                    let content_status = content_status_cb(self, &entry);
+                                    (entry, content_status)
+                                })
+                                .collect();
+                            let have_local = config.direction == SyncDirection::SendOnly;
+                            out.add_items(range, values, have_local);
+                        }
+                        // ranges that exceed the threshold are never empty
+                        RangeContent::Fingerprint(fingerprint) => {
+                            non_empty += 1;
+                            out.add_fingerprint(range, fingerprint);
+                        }
+                    }
+                }
+                if non_empty > 1 {
+                    continue;
+                }
+                // The remote would split a range that is not split into at least two non-empty
+                // ranges again, possibly forever, e.g. if the store changed while it was read.
+                // Answer it with all of its entries instead, like a recursion anchor, in items
+                // that are each within the send threshold.
+                out.parts.truncate(num_parts);
+                sent.truncate(num_sent);
+                outcome.degenerate.push(range.clone());
+                if verify {
+                    out.add_part(MessagePart::Divergent {
+                        range,
+                        settled: false,
+                    });
+                    continue;
+                }
+                if !receive_only {
+                    let threshold = send_threshold_cb(&range);
+                    let have_local = config.direction == SyncDirection::SendOnly;
+                    for Chunk { range, mut entries } in threshold.split_range(self, &range)? {
+                        retain_unsent(&mut sent, &range, &mut entries);
+                        let values = entries
+                            .into_iter()
+                            .map(|entry| {
+                                let content_status = content_status_cb(self, &entry);
+                                (entry, content_status)
+                            })
+                            .collect();
+                        out.add_items(range, values, have_local);
+                    }
+                    continue;
+                }
+            }
+            let mut values = match config.direction {
+                SyncDirection::ReceiveOnly => vec![],
+                _ => self
+                    .get_range(range.clone())?
+                    .collect::<Result<Vec<_>, _>>()?,
+            };
+            retain_unsent(&mut sent, &range, &mut values);
+            let values = values
+                .into_iter()
+                .map(|entry| {
+                    let content_status = content_status_cb(self, &entry);
+                    (entry, content_status)
+                })
+                .collect();
+            out.add_items(range, values, config.direction == SyncDirection::SendOnly);
+        }
+
+        // If we have any parts, return a message
+        if !out.is_empty() {
+            let mut reply = out.build();
+            reply
+                .parts
+                .sort_by(|a, b| reply_order(a).cmp(&reply_order(b)));
+            outcome.reply = Some(reply);
+        }
+        Ok(outcome)
+    }
+
+    /// Processes an incoming message like [`Store::process_message`], but records the
+    /// differences to the remote in `summary` instead of storing any entries.
+    ///
+    /// The response only ever contains fingerprints, regardless of the direction in `config`, so
+    /// no local entries are sent and the remote is not changed either. Every item the remote
+    /// sends in return contains all of its entries in the item's range, which is what allows to
+    /// compute the differences in both directions. This requires the remote to not be receive
+    /// only itself.
+    ///
+    /// Pass the same `summary` to each call of a session. It is complete once no more messages
+    /// are exchanged.
+    fn process_message_dry_run(
+        &mut self,
+        config: &SyncConfig,
+        message: Message<E>,
+        summary: &mut DiffSummary<E::Key>,
+    ) -> Result<Option<Message<E>>, ProcessError<Self::Error>> {
+        if message.is_cancel() {
+            return Ok(None);
+        }
+        message
+            .check_expanded()
+            .map_err(ProcessError::InvalidMessage)?;
+        for part in message.parts() {
+            let MessagePart::RangeItem(item) = part else {
+                continue;
+            };
+            // the remote's entries that would be inserted, see `put`
+            for (entry, _) in &item.values {
+                if !item.range.contains(entry.key()) {
+                    continue;
+                }
+                let mut newer = true;
+                for prefix_entry in self.prefixes_of(entry.key())? {
+                    let prefix_entry = prefix_entry?;
+                    if shadows(&prefix_entry, entry.key()) && entry.value() <= prefix_entry.value()
+                    {
+                        newer = false;
+                        break;
+                    }
+                }
+                if newer {
+                    summary.missing_locally.push(entry.key().clone());
+                }
+            }
+            // our entries that would be sent to the remote, see the diff in `process_message`
+            let mut remote = RemoteValues::new(&item.values);
+            for our_entry in self.get_range(item.range.clone())? {
+                let our_entry = our_entry?;
+                if !remote.covers(&our_entry) {
+                    summary.missing_remotely.push(our_entry.key().clone());
+                }
+            }
+        }
+        let config = config.with_direction(SyncDirection::ReceiveOnly);
+        let Some(response) = self.process_message_reply(
+            &config,
+            message,
+            |_, _, _| false,
+            |_, _, _| (),
+            |_, _| ContentStatus::Missing,
+        )?
+        else {
+            return Ok(None);
+        };
+        // Receive only responses contain empty items only. The remote does not respond to an
+        // empty item if it has no entries in its range either, so a fingerprint is sent instead,
+        // which the remote either matches or answers with its entries.
+        let mut parts = Vec::with_capacity(response.parts.len());
+        for part in response.parts {
+            let part = match part {
+                MessagePart::RangeItem(RangeItem { range, .. }) => {
+                    let fingerprint = self.get_fingerprint(&range)?;
+                    MessagePart::RangeFingerprint(RangeFingerprint { range, fingerprint })
+                }
+                part => part,
+            };
+            parts.push(part);
+        }
+        Ok(Some(Message { parts, more: false }))
+    }
+
+    /// Insert a key value pair.
+    ///
+    /// Entries are inserted if they compare strictly greater than all entries in the set of
+    /// entries which have the same key as `entry` or have a key which is a prefix of `entry`.
+    ///
+    /// Additionally, entries that have a key which is a prefix of the entry's key and whose
+    /// timestamp is not strictly greater than that of the new entry are deleted
+    ///
+    /// Both only apply to the entries with a prefix of the key if the entry with the prefix
+    /// deletes prefixed entries, see [`RangeEntry::deletes_prefixed`].
+    ///
+    /// Note: The deleted entries are simply dropped right now. We might want to make this return
+    /// an iterator, to potentially log or expose the deleted entries.
+    ///
+    /// Returns `true` if the entry was inserted.
+    /// Returns `false` if it was not inserted.
+    fn put(&mut self, entry: E) -> Result<InsertOutcome, Self::Error> {
+        let prefix_entry = self.prefixes_of(entry.key())?;
+        // First we check if our entry is strictly greater than all parent elements.
+        // From the willow spec:
+        // "Remove all entries whose timestamp is strictly less than the timestamp of any other entry [..]
+        // whose path is a prefix of p." and then "remove all but those whose record has the greatest hash component".
+        // This is the contract of the `Ord` impl for `E::Value`.
+        let mut replaces = false;
+        for prefix_entry in prefix_entry {
+            let prefix_entry = prefix_entry?;
+            replaces |= prefix_entry.key() == entry.key();
+            if !shadows(&prefix_entry, entry.key()) {
+                continue;
+            }
+            if entry.value() <= prefix_entry.value() {
+                return Ok(InsertOutcome::NotInserted);
+            }
+        }
+
+        // Now we remove all entries that have our key as a prefix and are older than our entry.
+        let removed = if entry.deletes_prefixed() {
+            self.remove_prefix_filtered(entry.key(), |value| entry.value() >= value)?
+        } else {
+            // replaced by `entry_put`
+            usize::from(replaces)
+        };
+
+        // Insert our new entry.
+        self.entry_put(entry)?;
+        Ok(InsertOutcome::Inserted { removed })
+    }
+
+    /// Insert many entries with [`Store::put`].
+    ///
+    /// See [`Store::put_many_with_progress`].
+    fn put_many(
+        &mut self,
+        entries: impl IntoIterator<Item = E>,
+    ) -> Result<ImportReport, Self::Error> {
+        self.put_many_with_progress(entries, 0, |_| ())
+    }
+
+    /// Insert many entries with [`Store::put`], and call `progress` with the report so far after
+    /// every `every` entries. If `every` is zero, `progress` is never called.
+    ///
+    /// Stores that can write many entries more efficiently at once, e.g. in a single
+    /// transaction, should override this method.
+    fn put_many_with_progress(
+        &mut self,
+        entries: impl IntoIterator<Item = E>,
+        every: usize,
+        mut progress: impl FnMut(&ImportReport),
+    ) -> Result<ImportReport, Self::Error> {
+        let mut report = ImportReport::default();
+        for entry in entries {
+            let replaces = self.get(entry.key())?.is_some();
+            match self.put(entry)? {
+                InsertOutcome::NotInserted => report.ignored += 1,
+                InsertOutcome::Inserted { removed } => {
+                    // the replaced entry is counted in `removed` by the store
+                    match replaces {
+                        true => {
+                            report.replaced += 1;
+                            report.removed += removed.saturating_sub(1);
+                        }
+                        false => {
+                            report.inserted += 1;
+                            report.removed += removed;
+                        }
+                    }
+                }
+            }
+            if every > 0 && report.processed() % every == 0 {
+                progress(&report);
+            }
+        }
+        Ok(report)
+    }
+}
+
+impl<E: RangeEntry, S: Store<E>> Store<E> for &mut S {
+    type Error = S::Error;
+
+    type RangeIterator<'a> = S::RangeIterator<'a> where Self: 'a, E: 'a;
+
+    type ParentIterator<'a> = S::ParentIterator<'a> where Self: 'a, E: 'a;
+
+    fn epoch(&self) -> u64 {
+        (**self).epoch()
+    }
+
+    fn get_first(&mut self) -> Result<<E as RangeEntry>::Key, Self::Error> {
+        (**self).get_first()
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn first_and_last(&mut self) -> Result<Option<(E::Key, E::Key)>, Self::Error> {
+        (**self).first_and_last()
+    }
+
+    fn get(&mut self, key: &<E as RangeEntry>::Key) -> Result<Option<E>, Self::Error> {
+        (**self).get(key)
+    }
+
+    fn entry_fingerprint(
+        &mut self,
+        key: &<E as RangeEntry>::Key,
+    ) -> Result<Option<Fingerprint>, Self::Error> {
+        (**self).entry_fingerprint(key)
+    }
+
+    fn len(&mut self) -> Result<usize, Self::Error> {
+        (**self).len()
+    }
+
+    fn is_empty(&mut self) -> Result<bool, Self::Error> {
+        (**self).is_empty()
+    }
+
+    fn get_fingerprint(
+        &mut self,
+        range: &Range<<E as RangeEntry>::Key>,
+    ) -> Result<Fingerprint, Self::Error> {
+        (**self).get_fingerprint(range)
+    }
+
+    fn entry_put(&mut self, entry: E) -> Result<(), Self::Error> {
+        (**self).entry_put(entry)
+    }
+
+    fn get_range(
+        &mut self,
+        range: Range<<E as RangeEntry>::Key>,
+    ) -> Result<Self::RangeIterator<'_>, Self::Error> {
+        (**self).get_range(range)
+    }
+
+    fn get_range_chunked<'a>(
+        &'a mut self,
+        range: Range<<E as RangeEntry>::Key>,
+        chunk_size: usize,
+    ) -> Result<ChunkIterator<'a, E, Self::Error>, Self::Error>
+    where
+        E: 'a,
+    {
+        (**self).get_range_chunked(range, chunk_size)
+    }
+
+    fn prefixed_by(
+        &mut self,
+        prefix: &<E as RangeEntry>::Key,
+    ) -> Result<Self::RangeIterator<'_>, Self::Error> {
+        (**self).prefixed_by(prefix)
+    }
+
+    fn prefixes_of(
+        &mut self,
+        key: &<E as RangeEntry>::Key,
+    ) -> Result<Self::ParentIterator<'_>, Self::Error> {
+        (**self).prefixes_of(key)
+    }
+
+    fn all(&mut self) -> Result<Self::RangeIterator<'_>, Self::Error> {
+        (**self).all()
+    }
+
+    fn entry_remove(&mut self, key: &<E as RangeEntry>::Key) -> Result<Option<E>, Self::Error> {
+        (**self).entry_remove(key)
+    }
+
+    fn remove_prefix_filtered(
+        &mut self,
+        prefix: &<E as RangeEntry>::Key,
+        predicate: impl Fn(&<E as RangeEntry>::Value) -> bool,
+    ) -> Result<usize, Self::Error> {
+        (**self).remove_prefix_filtered(prefix, predicate)
+    }
+}
+
+/// Iterator over the chunks of a range, see [`Store::get_range_chunked`].
+pub type ChunkIterator<'a, E, Err> = Box<dyn Iterator<Item = Result<Chunk<E>, Err>> + 'a>;
+
+/// A chunk of entries returned from [`Store::get_range_chunked`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Chunk<E: RangeEntry> {
+    /// The sub-range covered by this chunk.
+    ///
+    /// The end of the range is the first key of the next chunk, or the end of the requested range
+    /// for the last chunk.
+    pub range: Range<E::Key>,
+    /// The entries in `range`.
+    pub entries: Vec<E>,
+}
+
+/// Iterator adapter that splits the entries of a range into [`Chunk`]s.
+///
+/// The wrapped iterator must yield the entries of the range in range order, i.e. starting at
+/// `range.x()`, and for wrap-around ranges continuing at the smallest key after the largest one.
+#[derive(Debug)]
+pub struct RangeChunks<E: RangeEntry, I> {
+    iter: I,
+    range: Range<E::Key>,
+    chunk_size: usize,
+    /// Start of the next chunk, or `None` if iteration is finished.
+    next_start: Option<E::Key>,
+    /// The first entry of the next chunk, which we had to read to know where the current chunk
+    /// ends.
+    pending: Option<E>,
+}
+
+impl<E: RangeEntry, I> RangeChunks<E, I> {
+    /// Create a new chunk iterator over `range`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `chunk_size` is 0.
+    pub fn new(range: Range<E::Key>, chunk_size: usize, iter: I) -> Self {
+        assert!(chunk_size > 0, "chunk size must be non-zero");
+        RangeChunks {
+            iter,
+            next_start: Some(range.x().clone()),
+            range,
+            chunk_size,
+            pending: None,
+        }
+    }
+}
+
+impl<E, I, Err> Iterator for RangeChunks<E, I>
+where
+    E: RangeEntry,
+    I: Iterator<Item = Result<E, Err>>,
+{
+    type Item = Result<Chunk<E>, Err>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let x = self.next_start.take()?;
+        let mut entries = Vec::with_capacity(self.chunk_size);
+        entries.extend(self.pending.take());
+        let mut exhausted = false;
+        while entries.len() < self.chunk_size {
+            match self.iter.next() {
+                Some(Ok(entry)) => entries.push(entry),
+                Some(Err(err)) => return Some(Err(err)),
+                None => {
+                    exhausted = true;
+                    break;
+                }
+            }
+        }
+        // The chunk ends where the next one starts, so we need to look at the next entry.
+        let next = if exhausted { None } else { self.iter.next() };
+        let y = match next {
+            Some(Ok(entry)) => {
+                let y = entry.key().clone();
+                self.next_start = Some(y.clone());
+                self.pending = Some(entry);
+                y
+            }
+            Some(Err(err)) => return Some(Err(err)),
+            None => self.range.y().clone(),
+        };
+        Some(Ok(Chunk {
+            range: Range::new(x, y),
+            entries,
+        }))
+    }
+}
+
+/// Iterator over the entries of a range in range order, which reads them from a store in pages
+/// of entries, for the default impl of [`Store::get_range_chunked`].
+#[derive(Debug)]
+struct RangePages<'a, E: RangeEntry, S> {
+    store: &'a mut S,
+    page_size: usize,
+    /// The entries of the current page, in reverse order.
+    page: Vec<E>,
+    /// The part of the range that is still to be read from the store.
+    next: Option<Range<E::Key>>,
+    /// For wrap-around ranges, the part from the smallest key up to `range.y()`, which is read
+    /// after the part from `range.x()`.
+    rest: Option<Range<E::Key>>,
+}
+
+impl<'a, E: RangeEntry, S: Store<E>> RangePages<'a, E, S> {
+    fn new(store: &'a mut S, range: &Range<E::Key>, page_size: usize) -> Result<Self, S::Error> {
+        let (next, rest) = if range.x() < range.y() {
+            (range.clone(), None)
+        } else {
+            // the range wraps around. `Range::new(x, first)` covers only the keys from x to the
+            // end, as no key is smaller than the first one, and all keys if x is not greater.
+            let first = store.get_first()?;
+            let x = range.x().max(&first).clone();
+            let rest = if &first < range.y() {
+                Some(Range::new(first.clone(), range.y().clone()))
+            } else {
+                None
+            };
+            (Range::new(x, first), rest)
+        };
+        Ok(Self {
+            store,
+            page_size,
+            page: Vec::new(),
+            next: Some(next),
+            rest,
+        })
+    }
+
+    /// Reads the next page, skipping parts of the range without entries.
+    fn read_page(&mut self) -> Result<(), S::Error> {
+        while self.page.is_empty() {
+            let Some(part) = self.next.take() else {
+                return Ok(());
+            };
+            let y = part.y().clone();
+            // read one more entry, whose key is the start of the next page
+            for entry in self.store.get_range(part)?.take(self.page_size + 1) {
+                self.page.push(entry?);
+            }
+            self.next = if self.page.len() > self.page_size {
+                self.page
+                    .pop()
+                    .map(|entry| Range::new(entry.key().clone(), y))
+            } else {
+                self.rest.take()
+            };
+            self.page.reverse();
+        }
+        Ok(())
+    }
+}
+
+impl<'a, E: RangeEntry, S: Store<E>> Iterator for RangePages<'a, E, S> {
+    type Item = Result<E, S::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Err(err) = self.read_page() {
+            self.next = None;
+            self.rest = None;
+            return Some(Err(err));
+        }
+        self.page.pop().map(Ok)
+    }
+}
+
+/// Configuration of the set reconciliation protocol.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SyncConfig {
+    /// Up to which size to send the values of a range immediately, before sending only a
+    /// fingerprint.
+    send_threshold: SendThreshold,
+    /// `k` in the protocol, how many splits to generate. at least 2
+    split_factor: usize,
+    /// In which direction entries are exchanged.
+    direction: SyncDirection,
+    /// Whether a [`ProcessOutcome`] lists the entries, instead of only counting them.
+    #[serde(default)]
+    outcome_entries: bool,
+    /// Up to how many local entries a mismatching range is answered with its entries instead of
+    /// being split. At least 1.
+    #[serde(default = "default_anchor_threshold")]
+    anchor_threshold: usize,
+    /// Up to how many local entries a range whose remote fingerprint is empty is answered with
+    /// its entries instead of being split.
+    #[serde(default = "default_item_threshold")]
+    item_threshold: usize,
+    /// Which local entries are sent back for the keys of the entries received from the remote.
+    #[serde(default)]
+    echo_policy: EchoPolicy,
+    /// Whether entries are exchanged, or the stores are only compared.
+    #[serde(default)]
+    mode: SyncMode,
+}
+
+fn default_anchor_threshold() -> usize {
+    1
+}
+
+fn default_item_threshold() -> usize {
+    usize::MAX
+}
+
+impl Default for SyncConfig {
+    fn default() -> Self {
+        SyncConfig {
+            send_threshold: SendThreshold::Entries(1),
+            split_factor: 2,
+            direction: SyncDirection::Both,
+            outcome_entries: false,
+            anchor_threshold: default_anchor_threshold(),
+            item_threshold: default_item_threshold(),
+            echo_policy: EchoPolicy::default(),
+            mode: SyncMode::default(),
+        }
+    }
+}
+
+impl SyncConfig {
+    /// Set in which direction entries are exchanged.
+    pub fn with_direction(mut self, direction: SyncDirection) -> Self {
+        self.direction = direction;
+        self
+    }
+
+    /// Get in which direction entries are exchanged.
+    pub fn direction(&self) -> SyncDirection {
+        self.direction
+    }
+
+    /// Set up to which size the entries of a range are sent, instead of its fingerprint.
+    pub fn with_send_threshold(mut self, send_threshold: SendThreshold) -> Self {
+        self.send_threshold = send_threshold;
+        self
+    }
+
+    /// Get up to which size the entries of a range are sent, instead of its fingerprint.
+    pub fn send_threshold(&self) -> SendThreshold {
+        self.send_threshold
+    }
+
+    /// Set whether the [`ProcessOutcome`] of a message lists the entries that were stored or
+    /// rejected. Otherwise they are only counted, which avoids cloning them.
+    pub fn with_outcome_entries(mut self, list: bool) -> Self {
+        self.outcome_entries = list;
+        self
+    }
+
+    /// Get whether the [`ProcessOutcome`] of a message lists the entries that were stored or
+    /// rejected.
+    pub fn outcome_entries(&self) -> bool {
+        self.outcome_entries
+    }
+
+    /// Set up to how many local entries a range whose fingerprint does not match is answered
+    /// with its entries, instead of being split, and up to how many local entries a range whose
+    /// remote fingerprint is empty is answered with its entries.
+    ///
+    /// The recursion stops at ranges of at most `anchor_threshold` entries, which defaults to 1.
+    /// A higher threshold saves rounds, at the cost of sending entries the remote may have
+    /// already. An empty remote fingerprint usually means the remote has no entries in the range,
+    /// e.g. on the first sync, and by default such ranges are answered with all of their
+    /// entries at once. A lower `item_threshold` splits large ranges first, which bounds the size
+    /// of the parts at the cost of more rounds. This does not apply if the remote starts the sync
+    /// and the local store is empty, as the whole set is then answered with an empty item.
+    /// Ranges within the send threshold are always answered with their entries, see
+    /// [`SyncConfig::with_send_threshold`].
+    ///
+    /// An `anchor_threshold` of 0 is treated as 1, as a range with a single entry can not be
+    /// split. Returns a [`ThresholdError`] if `anchor_threshold` exceeds `item_threshold`.
+    pub fn with_thresholds(
+        mut self,
+        anchor_threshold: usize,
+        item_threshold: usize,
+    ) -> Result<Self, ThresholdError> {
+        let anchor_threshold = anchor_threshold.max(1);
+        if anchor_threshold > item_threshold {
+            return Err(ThresholdError {
+                anchor_threshold,
+                item_threshold,
+            });
+        }
+        self.anchor_threshold = anchor_threshold;
+        self.item_threshold = item_threshold;
+        Ok(self)
+    }
+
+    /// Get up to how many local entries a range whose fingerprint does not match is answered
+    /// with its entries, see [`SyncConfig::with_thresholds`].
+    pub fn anchor_threshold(&self) -> usize {
+        self.anchor_threshold
+    }
+
+    /// Get up to how many local entries a range whose remote fingerprint is empty is answered
+    /// with its entries, see [`SyncConfig::with_thresholds`].
+    pub fn item_threshold(&self) -> usize {
+        self.item_threshold
+    }
+
+    /// Set which local entries are sent back for the keys of the entries received from the
+    /// remote, see [`EchoPolicy`].
+    pub fn with_echo_policy(mut self, echo_policy: EchoPolicy) -> Self {
+        self.echo_policy = echo_policy;
+        self
+    }
+
+    /// Get which local entries are sent back for the keys of the entries received from the
+    /// remote.
+    pub fn echo_policy(&self) -> EchoPolicy {
+        self.echo_policy
+    }
+
+    /// Set whether entries are exchanged, or the stores are only compared, see [`SyncMode`].
+    pub fn with_mode(mut self, mode: SyncMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Get whether entries are exchanged, or the stores are only compared.
+    pub fn mode(&self) -> SyncMode {
+        self.mode
+    }
+}
+
+/// The thresholds passed to [`SyncConfig::with_thresholds`] are inconsistent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+#[error("anchor threshold {anchor_threshold} exceeds item threshold {item_threshold}")]
+pub struct ThresholdError {
+    /// The anchor threshold that was passed.
+    pub anchor_threshold: usize,
+    /// The item threshold that was passed.
+    pub item_threshold: usize,
+}
+
+/// Up to which size the entries of a range are sent, instead of the fingerprint of the range.
+///
+/// This applies to a range whose fingerprint does not match, which is answered with its entries
+/// if they are within the threshold, and otherwise to the ranges it is split into. A range with a
+/// single entry is always sent as items, even if the entry exceeds the threshold.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SendThreshold {
+    /// Send the entries of ranges with at most this many entries.
+    Entries(usize),
+    /// Send the entries of ranges whose entries take at most this many bytes, as estimated by
+    /// [`RangeEntry::encoded_size_hint`].
+    Bytes(u64),
+    /// Send the entries of ranges that are within both limits.
+    Both {
+        /// The maximum number of entries.
+        entries: usize,
+        /// The maximum number of bytes.
+        bytes: u64,
+    },
+}
+
+impl SendThreshold {
+    /// Get the maximum number of entries and the maximum number of bytes.
+    fn limits(&self) -> (usize, u64) {
+        match *self {
+            SendThreshold::Entries(max) => (max, u64::MAX),
+            SendThreshold::Bytes(max) => (usize::MAX, max),
+            SendThreshold::Both { entries, bytes } => (entries, bytes),
+        }
+    }
+
+    /// Reads the entries of `store` in `range` if they are within the threshold, or computes
+    /// the fingerprint of the range if they exceed it, in a single pass over the range.
+    ///
+    /// Once the entries exceed the threshold, they are folded into the fingerprint instead of
+    /// being kept, so at most one entry more than the threshold allows is held at once.
+    fn read_range<E: RangeEntry, S: Store<E>>(
+        &self,
+        store: &mut S,
+        range: &Range<E::Key>,
+    ) -> Result<RangeContent<E>, S::Error> {
+        let (max_entries, max_bytes) = self.limits();
+        let mut entries: Vec<E> = Vec::new();
+        let mut bytes = 0u64;
+        let mut fingerprint = None;
+        for entry in store.get_range(range.clone())? {
+            let entry = entry?;
+            if let Some(fingerprint) = &mut fingerprint {
+                *fingerprint ^= entry.as_fingerprint();
+                continue;
+            }
+            bytes = bytes.saturating_add(entry.encoded_size_hint());
+            if entries.len() >= max_entries || bytes > max_bytes {
+                let mut exceeded = Fingerprint::empty();
+                for entry in entries.drain(..).chain(Some(entry)) {
+                    exceeded ^= entry.as_fingerprint();
+                }
+                fingerprint = Some(exceeded);
+                continue;
+            }
+            entries.push(entry);
+        }
+        Ok(match fingerprint {
+            Some(fingerprint) => RangeContent::Fingerprint(fingerprint),
+            None => RangeContent::Entries(entries),
+        })
+    }
+
+    /// Splits the entries of `store` in `range` into consecutive chunks that cover `range`, each
+    /// with entries within the threshold, in range order.
+    ///
+    /// Each chunk holds at least one entry, unless `range` is empty, so an entry that exceeds the
+    /// threshold on its own gets a chunk of its own.
+    fn split_range<E: RangeEntry, S: Store<E>>(
+        &self,
+        store: &mut S,
+        range: &Range<E::Key>,
+    ) -> Result<Vec<Chunk<E>>, S::Error> {
+        let (max_entries, max_bytes) = self.limits();
+        let mut chunks = Vec::new();
+        let mut start = range.x().clone();
+        let mut entries: Vec<E> = Vec::new();
+        let mut bytes = 0u64;
+        // chunks are yielded in range order, starting at x, so the entries are as well
+        for chunk in store.get_range_chunked(range.clone(), 1)? {
+            for entry in chunk?.entries {
+                let size = entry.encoded_size_hint();
+                if !entries.is_empty()
+                    && (entries.len() >= max_entries || bytes.saturating_add(size) > max_bytes)
+                {
+                    let end = entry.key().clone();
+                    let start = std::mem::replace(&mut start, end.clone());
+                    chunks.push(Chunk {
+                        range: Range::new(start, end),
+                        entries: std::mem::take(&mut entries),
+                    });
+                    bytes = 0;
+                }
+                bytes = bytes.saturating_add(size);
+                entries.push(entry);
+            }
+        }
+        chunks.push(Chunk {
+            range: Range::new(start, range.y().clone()),
+            entries,
+        });
+        Ok(chunks)
+    }
+
+    /// Returns `true` if the `len` entries of `store` in `range` have to be sent as a fingerprint.
+    ///
+    /// The entries are only read if the threshold has a byte limit, and only until they exceed
+    /// it. None of them are kept.
+    fn is_exceeded_by_range<E: RangeEntry, S: Store<E>>(
+        &self,
+        store: &mut S,
+        range: &Range<E::Key>,
+        len: usize,
+    ) -> Result<bool, S::Error> {
+        let (max_entries, max_bytes) = self.limits();
+        if len > max_entries {
+            return Ok(true);
+        }
+        if max_bytes == u64::MAX {
+            return Ok(false);
+        }
+        let mut bytes = 0u64;
+        for entry in store.get_range(range.clone())? {
+            bytes = bytes.saturating_add(entry?.encoded_size_hint());
+            if bytes > max_bytes {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+}
+
+/// In which direction entries are exchanged during a sync, from the view of the local side.
+///
+/// The direction only applies to the side that processes messages with it. A remote with
+/// [`SyncDirection::Both`] syncs with a one-way side without any changes.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SyncDirection {
+    /// Send local entries to the remote, and store entries received from the remote.
+    #[default]
+    Both,
+    /// Store entries received from the remote, but never send local entries.
+    ///
+    /// Only fingerprints and empty item sets are sent, which make the remote send its entries.
+    ReceiveOnly,
+    /// Send local entries to the remote, but drop entries received from the remote.
+    ///
+    /// Entries are dropped before they are passed to the validate callback. Local entries are
+    /// sent with `have_local` set, so the remote does not send its entries in return.
+    SendOnly,
+}
+
+/// Whether a sync exchanges entries, or only compares the stores.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SyncMode {
+    /// Reconcile the stores, by exchanging the entries of the ranges that differ.
+    #[default]
+    Reconcile,
+    /// Only find the ranges in which the stores differ, e.g. to check that a replica matches its
+    /// source.
+    ///
+    /// Fingerprints are exchanged and ranges split as usual, but no entries are sent or stored.
+    /// A range whose fingerprints do not match, and which has at most as many entries as the
+    /// anchor threshold, see [`SyncConfig::with_thresholds`], is sent as a
+    /// [`MessagePart::Divergent`] instead of its entries. The remote splits it further if it has
+    /// more entries in it, so each reported range has at most that many entries on both sides,
+    /// see [`ProcessOutcome::divergent`] and [`SyncSession::verification_report`]. Ranges are
+    /// sent as fingerprints regardless of the send threshold. Both sides have to use this mode.
+    VerifyOnly,
+}
+
+/// Which local entries are sent back in response to the entries of the remote, for the keys
+/// the remote sent entries for.
+///
+/// Local entries with keys the remote did not send are always sent back. Entries are sent back
+/// as they are stored after the remote's entries were inserted: a local entry that was replaced
+/// or removed by an entry of the remote is never sent back, as it would be stale. With the
+/// newer-wins semantics of [`Store::put`], a local entry only survives if it is newer than the
+/// remote's entry, or if the remote's entry was rejected.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EchoPolicy {
+    /// Never send back a local entry for a key the remote sent.
+    ///
+    /// The remote keeps its entry, even if the local one is newer, until it receives the local
+    /// entry in another range or sync. Local entries for keys whose remote entries were rejected
+    /// by the validate callback are still sent back, so that a remote with an older entry, as
+    /// rejected by [`validators::newer_wins`], receives the newer one.
+    Never,
+    /// Send back a local entry for a key the remote sent if its value is greater than the value
+    /// of the remote's entry, i.e. if it replaces the remote's entry with [`Store::put`].
+    #[default]
+    IfNewer,
+    /// Send back a local entry for a key the remote sent if its value differs from the value of
+    /// the remote's entry.
+    ///
+    /// Unlike [`EchoPolicy::IfNewer`], this also sends back local entries that are older than
+    /// the remote's, if the remote's entries were rejected by the validate callback.
+    IfDifferent,
+}
+
+/// The differences to a remote, as recorded by [`Store::process_message_dry_run`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiffSummary<K> {
+    /// Keys of entries the remote has, which are missing or older locally.
+    pub missing_locally: Vec<K>,
+    /// Keys of local entries, which the remote is missing or has older versions of.
+    pub missing_remotely: Vec<K>,
+}
+
+impl<K> Default for DiffSummary<K> {
+    fn default() -> Self {
+        Self {
+            missing_locally: Default::default(),
+            missing_remotely: Default::default(),
+        }
+    }
+}
+
+impl<K> DiffSummary<K> {
+    /// Returns `true` if no differences were recorded.
+    pub fn is_empty(&self) -> bool {
+        self.missing_locally.is_empty() && self.missing_remotely.is_empty()
+    }
+}
+
+/// Error returned from [`Store::process_message`] and the methods built on it.
+#[derive(Debug, thiserror::Error)]
+pub enum ProcessError<S> {
+    /// The store returned an error.
+    #[error("store error: {0:?}")]
+    Store(S),
+    /// The message cannot be applied to a store, e.g. because it has parts that have to be
+    /// expanded first, see [`MessageValidationError::UnexpandedPart`].
+    #[error(transparent)]
+    InvalidMessage(MessageValidationError),
+}
+
+impl<S> From<S> for ProcessError<S> {
+    fn from(err: S) -> Self {
+        Self::Store(err)
+    }
+}
+
+/// Options of [`Store::process_message_with_options`].
+pub struct ProcessOptions<'a, E: RangeEntry> {
+    transform: Option<TransformRef<'a, E>>,
+    send_threshold: Option<SendThresholdRef<'a, E>>,
+}
+
+type TransformRef<'a, E> = &'a dyn Fn(E) -> Option<E>;
+type SendThresholdRef<'a, E> = &'a dyn Fn(&Range<<E as RangeEntry>::Key>) -> SendThreshold;
+
+impl<'a, E: RangeEntry> Default for ProcessOptions<'a, E> {
+    fn default() -> Self {
+        Self {
+            transform: None,
+            send_threshold: None,
+        }
+    }
+}
+
+impl<'a, E: RangeEntry> Debug for ProcessOptions<'a, E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ProcessOptions")
+            .field("transform", &self.transform.is_some())
+            .field("send_threshold", &self.send_threshold.is_some())
+            .finish()
+    }
+}
+
+impl<'a, E: RangeEntry> ProcessOptions<'a, E> {
+    /// Transform each incoming entry before it is stored.
+    ///
+    /// `transform` is called for each entry that was validated, and returns the entry to store
+    /// instead, or `None` to drop it. The entries sent back to the remote are computed from the
+    /// received entries, not the transformed ones. The transformed entry must have the same key,
+    /// or the fingerprints of both sides would never match.
+    pub fn with_transform(mut self, transform: &'a dyn Fn(E) -> Option<E>) -> Self {
+        self.transform = Some(transform);
+        self
+    }
+
+    /// Use a send threshold per range.
+    ///
+    /// `send_threshold` is called for each range the response is made of, and overrides the
+    /// [`SyncConfig::send_threshold`] for that range. A range whose local entries are within its
+    /// threshold is answered with its entries instead of being split further, and the ranges it
+    /// is split into are sent as entries if they are within their threshold. Both sides may use
+    /// different thresholds.
+    pub fn with_send_threshold(
+        mut self,
+        send_threshold: &'a dyn Fn(&Range<E::Key>) -> SendThreshold,
+    ) -> Self {
+        self.send_threshold = Some(send_threshold);
+        self
+    }
+}
+
+/// The result of [`Store::process_message`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProcessOutcome<E: RangeEntry> {
+    /// The response to send to the remote, or `None` if the sync is finished or was cancelled.
+    pub reply: Option<Message<E>>,
+    /// Entries inserted for keys that were not in the store before.
+    pub inserted: OutcomeEntries<E>,
+    /// Entries inserted that replaced an entry with the same key.
+    pub replaced: OutcomeEntries<E>,
+    /// Entries the validate callback rejected.
+    pub rejected: OutcomeEntries<E>,
+    /// Entries that were not written, because the store holds the identical entry already.
+    pub already_present: OutcomeEntries<E>,
+    /// Received entries that were dropped, because they lie outside of the range of their item.
+    ///
+    /// A [`SyncSession`] rejects such messages with [`ProtocolError::InvalidMessage`] instead.
+    pub out_of_range: OutcomeEntries<E>,
+    /// Received entries that were dropped, because their item has a greater value for the same
+    /// key.
+    ///
+    /// A [`SyncSession`] rejects such messages with [`ProtocolError::InvalidMessage`] instead.
+    pub duplicates: OutcomeEntries<E>,
+    /// Ranges that could not be split into at least two non-empty ranges, e.g. because the store
+    /// changed while they were read. They are answered with all of their entries instead, in
+    /// consecutive items that are each within the send threshold.
+    pub degenerate: Vec<Range<E::Key>>,
+    /// Received fingerprints that did not match the local fingerprint of their range, together
+    /// with the local fingerprint.
+    pub mismatched: Vec<(RangeFingerprint<E::Key>, Fingerprint)>,
+    /// Ranges that need no further messages, because the fingerprints match or the received
+    /// items are not answered.
+    pub settled: Vec<Range<E::Key>>,
+    /// Ranges in which the stores differ, found with [`SyncMode::VerifyOnly`].
+    ///
+    /// Each range has at most as many entries on either side as the anchor threshold of that
+    /// side, see [`SyncConfig::with_thresholds`].
+    pub divergent: Vec<Range<E::Key>>,
+}
+
+impl<E: RangeEntry> Default for ProcessOutcome<E> {
+    fn default() -> Self {
+        Self {
+            reply: None,
+            inserted: Default::default(),
+            replaced: Default::default(),
+            rejected: Default::default(),
+            already_present: Default::default(),
+            out_of_range: Default::default(),
+            duplicates: Default::default(),
+            degenerate: Default::default(),
+            mismatched: Default::default(),
+            settled: Default::default(),
+            divergent: Default::default(),
+        }
+    }
+}
+
+/// Entries of one kind in a [`ProcessOutcome`].
+///
+/// The entries are only listed if [`SyncConfig::with_outcome_entries`] is set, and otherwise
+/// only counted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutcomeEntries<E> {
+    /// Number of entries.
+    pub count: usize,
+    /// The entries, or empty if they are only counted.
+    pub entries: Vec<E>,
+}
+
+impl<E> Default for OutcomeEntries<E> {
+    fn default() -> Self {
+        Self {
+            count: 0,
+            entries: Vec::new(),
+        }
+    }
+}
+
+impl<E: Clone> OutcomeEntries<E> {
+    fn add(&mut self, entry: &E, list: bool) {
+        self.count += 1;
+        if list {
+            self.entries.push(entry.clone());
+        }
+    }
+}
+
+/// The result of [`Store::put_many`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ImportReport {
+    /// Number of entries inserted for keys that were not in the store before.
+    pub inserted: usize,
+    /// Number of entries inserted that replaced an entry with the same key.
+    pub replaced: usize,
+    /// Number of entries that were not inserted, because a newer entry for their key or a prefix
+    /// of their key exists.
+    pub ignored: usize,
+    /// Number of entries removed, because the key of an inserted entry is a prefix of their key.
+    pub removed: usize,
+}
+
+impl ImportReport {
+    /// Number of entries processed so far.
+    pub fn processed(&self) -> usize {
+        self.inserted + self.replaced + self.ignored
+    }
+}
+
+/// The outcome of a [`Store::put`] operation.
+#[derive(Debug)]
+pub enum InsertOutcome {
+    /// The entry was not inserted because a newer entry for its key or a
+    /// prefix of its key exists.
+    NotInserted,
+    /// The entry was inserted.
+    Inserted {
+        /// Number of entries that were removed as a consequence of this insert operation.
+        /// The removed entries had a key that starts with the new entry's key and a lower value.
+        removed: usize,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+    use std::{
+        cell::{Cell, RefCell},
+        collections::{BTreeMap, BTreeSet, VecDeque},
+        convert::Infallible,
+        fmt::Debug,
+        ops::ControlFlow,
+        rc::Rc,
+        sync::{Arc, Mutex},
+        time::Instant,
+    };
+    use test_strategy::proptest;
+
+    use super::{
+        test_utils::{self, sync_exchange_messages, TestEntry},
+        *,
+    };
+
+    impl<K, V> RangeEntry for (K, V)
+    where
+        K: RangeKey,
+        V: RangeValue,
+    {
+        type Key = K;
+        type Value = V;
+
+        fn key(&self) -> &Self::Key {
+            &self.0
+        }
+
+        fn value(&self) -> &Self::Value {
+            &self.1
+        }
+
+        fn as_fingerprint(&self) -> Fingerprint {
+            let mut hasher = blake3::Hasher::new();
+            hasher.update(format!("{:?}", self.0).as_bytes());
+            hasher.update(format!("{:?}", self.1).as_bytes());
+            Fingerprint(hasher.finalize().into())
+        }
+    }
+
+    impl<K, V> SplitKey for (K, V)
+    where
+        K: RangeKey,
+        V: RangeValue,
+    {
+        type Rest = V;
+
+        fn split_key(self) -> (K, V) {
+            self
+        }
+
+        fn join_key(key: K, value: V) -> Self {
+            (key, value)
+        }
+    }
+
+    impl RangeKey for &'static str {
+        fn is_prefix_of(&self, other: &Self) -> bool {
+            other.starts_with(self)
+        }
+    }
+    impl RangeKey for String {
+        fn is_prefix_of(&self, other: &Self) -> bool {
+            other.starts_with(self)
+        }
+    }
+    impl RangeKey for u32 {
+        fn is_prefix_of(&self, other: &Self) -> bool {
+            self == other
+        }
+    }
+    impl RangeKey for Vec<u8> {
+        fn is_prefix_of(&self, other: &Self) -> bool {
+            other.starts_with(self)
+        }
+    }
+
+    impl RangeValue for &'static [u8] {}
+    impl RangeValue for Vec<u8> {}
+    impl RangeValue for i32 {}
+    impl RangeValue for u8 {}
+
+    #[test]
+    fn test_paper_1() {
+        let alice_set = [("ape", 1), ("eel", 1), ("fox", 1), ("gnu", 1)];
+        let bob_set = [
+            ("bee", 1),
+            ("cat", 1),
+            ("doe", 1),
+            ("eel", 1),
+            ("fox", 1),
+            ("hog", 1),
+        ];
+
+        let res = sync(&alice_set, &bob_set);
+        res.print_messages();
+        assert_eq!(res.alice_to_bob.len(), 3, "A -> B message count");
+        assert_eq!(res.bob_to_alice.len(), 2, "B -> A message count");
+
+        // Initial message
+        assert_eq!(res.alice_to_bob[0].parts.len(), 1);
+        assert!(res.alice_to_bob[0].parts[0].is_range_fingerprint());
+
+        // Response from Bob - recurse once
+        assert_eq!(res.bob_to_alice[0].parts.len(), 2);
+        assert!(res.bob_to_alice[0].parts[0].is_range_fingerprint());
+        assert!(res.bob_to_alice[0].parts[1].is_range_fingerprint());
+        // Last response from Alice
+        assert_eq!(res.alice_to_bob[1].parts.len(), 3);
+        assert!(res.alice_to_bob[1].parts[0].is_range_item());
+        assert!(res.alice_to_bob[1].parts[1].is_range_fingerprint());
+        assert!(res.alice_to_bob[1].parts[2].is_range_fingerprint());
+
+        // Last response from Bob
+        assert_eq!(res.bob_to_alice[1].parts.len(), 2);
+        assert!(res.bob_to_alice[1].parts[0].is_range_item());
+        assert!(res.bob_to_alice[1].parts[1].is_range_item());
+    }
+
+    #[test]
+    fn test_paper_2() {
+        let alice_set = [
+            ("ape", 1),
+            ("bee", 1),
+            ("cat", 1),
+            ("doe", 1),
+            ("eel", 1),
+            ("fox", 1), // the only value being sent
+            ("gnu", 1),
+            ("hog", 1),
+        ];
+        let bob_set = [
+            ("ape", 1),
+            ("bee", 1),
+            ("cat", 1),
+            ("doe", 1),
+            ("eel", 1),
+            ("gnu", 1),
+            ("hog", 1),
+        ];
+
+        let res = sync(&alice_set, &bob_set);
+        assert_eq!(res.alice_to_bob.len(), 3, "A -> B message count");
+        assert_eq!(res.bob_to_alice.len(), 2, "B -> A message count");
+    }
+
+    #[test]
+    fn test_paper_3() {
+        let alice_set = [
+            ("ape", 1),
+            ("bee", 1),
+            ("cat", 1),
+            ("doe", 1),
+            ("eel", 1),
+            ("fox", 1),
+            ("gnu", 1),
+            ("hog", 1),
+        ];
+        let bob_set = [("ape", 1), ("cat", 1), ("eel", 1), ("gnu", 1)];
+
+        let res = sync(&alice_set, &bob_set);
+        assert_eq!(res.alice_to_bob.len(), 3, "A -> B message count");
+        assert_eq!(res.bob_to_alice.len(), 2, "B -> A message count");
+    }
+
+    #[test]
+    fn test_limits() {
+        let alice_set = [("ape", 1), ("bee", 1), ("cat", 1)];
+        let bob_set = [("ape", 1), ("cat", 1), ("doe", 1)];
+
+        let res = sync(&alice_set, &bob_set);
+        assert_eq!(res.alice_to_bob.len(), 2, "A -> B message count");
+        assert_eq!(res.bob_to_alice.len(), 2, "B -> A message count");
+    }
+
+    #[test]
+    fn test_prefixes_simple() {
+        let alice_set = [("/foo/bar", 1), ("/foo/baz", 1), ("/foo/cat", 1)];
+        let bob_set = [("/foo/bar", 1), ("/alice/bar", 1), ("/alice/baz", 1)];
+
+        let res = sync(&alice_set, &bob_set);
+        assert_eq!(res.alice_to_bob.len(), 2, "A -> B message count");
+        assert_eq!(res.bob_to_alice.len(), 2, "B -> A message count");
+    }
+
+    #[test]
+    fn test_prefixes_empty_alice() {
+        let alice_set = [];
+        let bob_set = [("/foo/bar", 1), ("/alice/bar", 1), ("/alice/baz", 1)];
+
+        let res = sync(&alice_set, &bob_set);
+        assert_eq!(res.alice_to_bob.len(), 1, "A -> B message count");
+        assert_eq!(res.bob_to_alice.len(), 1, "B -> A message count");
+    }
+
+    #[test]
+    fn test_prefixes_empty_bob() {
+        let alice_set = [("/foo/bar", 1), ("/foo/baz", 1), ("/foo/cat", 1)];
+        let bob_set = [];
+
+        let res = sync(&alice_set, &bob_set);
+        assert_eq!(res.alice_to_bob.len(), 2, "A -> B message count");
+        assert_eq!(res.bob_to_alice.len(), 1, "B -> A message count");
+    }
+
+    #[test]
+    fn test_equal_key_higher_value() {
+        let alice_set = [("foo", 2)];
+        let bob_set = [("foo", 1)];
+
+        let res = sync(&alice_set, &bob_set);
+        assert_eq!(res.alice_to_bob.len(), 2, "A -> B message count");
+        assert_eq!(res.bob_to_alice.len(), 1, "B -> A message count");
+    }
+
+    #[test]
+    fn test_multikey() {
+        /// Uses the blanket impl of [`RangeKey]` for `T: AsRef<[u8]>` in this module.
+        #[derive(Default, Clone, PartialEq, Eq, PartialOrd, Ord)]
+        struct Multikey {
+            author: [u8; 4],
+            key: Vec<u8>,
+        }
+
+        impl RangeKey for Multikey {
+            fn is_prefix_of(&self, other: &Self) -> bool {
+                self.author == other.author && self.key.starts_with(&other.key)
+            }
+        }
+
+        impl Debug for Multikey {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                let key = if let Ok(key) = std::str::from_utf8(&self.key) {
+                    key.to_string()
+                } else {
+                    hex::encode(&self.key)
+                };
+                f.debug_struct("Multikey")
+                    .field("author", &hex::encode(self.author))
+                    .field("key", &key)
+                    .finish()
+            }
+        }
+
+        impl Multikey {
+            fn new(author: [u8; 4], key: impl AsRef<[u8]>) -> Self {
+                Multikey {
+                    author,
+                    key: key.as_ref().to_vec(),
+                }
+            }
+        }
+        let author_a = [1u8; 4];
+        let author_b = [2u8; 4];
+        let alice_set = [
+            (Multikey::new(author_a, "ape"), 1),
+            (Multikey::new(author_a, "bee"), 1),
+            (Multikey::new(author_b, "bee"), 1),
+            (Multikey::new(author_a, "doe"), 1),
+        ];
+        let bob_set = [
+            (Multikey::new(author_a, "ape"), 1),
+            (Multikey::new(author_a, "bee"), 1),
+            (Multikey::new(author_a, "cat"), 1),
+            (Multikey::new(author_b, "cat"), 1),
+        ];
+
+        // No limit
+        let mut res = sync(&alice_set, &bob_set);
+        assert_eq!(res.alice_to_bob.len(), 2, "A -> B message count");
+        assert_eq!(res.bob_to_alice.len(), 2, "B -> A message count");
+        res.assert_alice_set(
+            "no limit",
+            &[
+                (Multikey::new(author_a, "ape"), 1),
+                (Multikey::new(author_a, "bee"), 1),
+                (Multikey::new(author_b, "bee"), 1),
+                (Multikey::new(author_a, "doe"), 1),
+                (Multikey::new(author_a, "cat"), 1),
+                (Multikey::new(author_b, "cat"), 1),
+            ],
+        );
+
+        res.assert_bob_set(
+            "no limit",
+            &[
+                (Multikey::new(author_a, "ape"), 1),
+                (Multikey::new(author_a, "bee"), 1),
+                (Multikey::new(author_b, "bee"), 1),
+                (Multikey::new(author_a, "doe"), 1),
+                (Multikey::new(author_a, "cat"), 1),
+                (Multikey::new(author_b, "cat"), 1),
+            ],
+        );
+    }
+
+    // This tests two things:
+    // 1) validate cb returning false leads to no changes on both sides after sync
+    // 2) validate cb receives expected entries
+    #[test]
+    fn test_validate_cb() {
+        let alice_set = [("alice1", 1), ("alice2", 2)];
+        let bob_set = [("bob1", 3), ("bob2", 4), ("bob3", 5)];
+        let mut alice: MemoryStore<_> = alice_set.into_iter().collect();
+        let mut bob: MemoryStore<_> = bob_set.into_iter().collect();
+
+        // run sync with a validate callback returning false, so no new entries are stored on either side
+        let config = SyncConfig::default().with_outcome_entries(true);
+        let mut alice_rejected = vec![];
+        let mut bob_rejected = vec![];
+        let mut next = Some(alice.initial_message().unwrap());
+        let mut rounds = 0;
+        while let Some(message) = next.take() {
+            assert!(rounds < 100, "too many rounds");
+            let (store, rejected) = match rounds % 2 {
+                0 => (&mut bob, &mut bob_rejected),
+                _ => (&mut alice, &mut alice_rejected),
+            };
+            let outcome = store
+                .process_message(
+                    &config,
+                    message,
+                    |_, _, _| false,
+                    |_, _, _| (),
+                    |_, _| ContentStatus::Complete,
+                )
+                .unwrap();
+            assert_eq!(outcome.inserted, OutcomeEntries::default());
+            assert_eq!(outcome.replaced, OutcomeEntries::default());
+            assert_eq!(outcome.rejected.count, outcome.rejected.entries.len());
+            rejected.extend(outcome.rejected.entries);
+            if outcome.reply.is_none() {
+                assert!(!outcome.settled.is_empty());
+            }
+            next = outcome.reply;
+            rounds += 1;
+        }
+        assert_eq!(collect(alice.all().unwrap()), alice_set);
+        assert_eq!(collect(bob.all().unwrap()), bob_set);
+
+        // assert that the outcomes contain all rejected entries
+        alice_rejected.sort();
+        bob_rejected.sort();
+        assert_eq!(alice_rejected, bob_set);
+        assert_eq!(bob_rejected, alice_set);
+    }
+
+    #[test]
+    fn test_process_outcome() {
+        let mut alice: MemoryStore<_> = [("ape", 1), ("bee", 2), ("cat", 1)].into_iter().collect();
+        let mut bob: MemoryStore<_> = [("bee", 1), ("doe", 1)].into_iter().collect();
+
+        let message = MessageBuilder::new()
+            .add_items(
+                Range::new("", ""),
+                collect(alice.all().unwrap())
+                    .into_iter()
+                    .map(|entry| (entry, ContentStatus::Complete))
+                    .collect(),
+                false,
+            )
+            .build();
+        // bob rejects ape, inserts cat and replaces bee, and answers with doe
+        let outcome = bob
+            .process_message(
+                &SyncConfig::default().with_outcome_entries(true),
+                message.clone(),
+                |_, e, _| e.0 != "ape",
+                |_, _, _| (),
+                |_, _| ContentStatus::Complete,
+            )
+            .unwrap();
+        assert_eq!(outcome.inserted.entries, [("cat", 1)]);
+        assert_eq!(outcome.replaced.entries, [("bee", 2)]);
+        assert_eq!(outcome.rejected.entries, [("ape", 1)]);
+        assert!(outcome.settled.is_empty());
+        let reply = outcome.reply.unwrap();
+        assert_eq!(
+            reply.values().collect::<Vec<_>>(),
+            [&(("doe", 1), ContentStatus::Complete)]
+        );
+
+        // alice stores doe, and the range is settled. Without listing entries, they are counted.
+        let outcome = alice
+            .process_message(
+                &SyncConfig::default(),
+                reply,
+                |_, _, _| true,
+                |_, _, _| (),
+                |_, _| ContentStatus::Complete,
+            )
+            .unwrap();
+        assert_eq!(outcome.inserted.count, 1);
+        assert!(outcome.inserted.entries.is_empty());
+        assert_eq!(outcome.settled, [Range::new("", "")]);
+        assert_eq!(outcome.reply, None);
+
+        // a cancelled message has an empty outcome
+        let outcome = bob
+            .process_message(
+                &SyncConfig::default(),
+                Message::cancel(),
+                |_, _, _| true,
+                |_, _, _| (),
+                |_, _| ContentStatus::Complete,
+            )
+            .unwrap();
+        assert_eq!(outcome, ProcessOutcome::default());
+    }
+
+    #[test]
+    fn test_values_out_of_range() {
+        let values = [("ape", 1), ("bee", 1), ("cat", 1), ("doe", 1)]
+            .map(|entry| (entry, ContentStatus::Complete))
+            .to_vec();
+        let message = MessageBuilder::new()
+            .add_items(Range::new("b", "d"), values, false)
+            .build();
+        let mut bob = MemoryStore::new();
+        let outcome = bob
+            .process_message(
+                &SyncConfig::default().with_outcome_entries(true),
+                message.clone(),
+                |_, _, _| true,
+                |_, _, _| (),
+                |_, _| ContentStatus::Complete,
+            )
+            .unwrap();
+        assert_eq!(outcome.out_of_range.entries, [("ape", 1), ("doe", 1)]);
+        assert_eq!(outcome.inserted.entries, [("bee", 1), ("cat", 1)]);
+        assert_eq!(collect(bob.all().unwrap()), [("bee", 1), ("cat", 1)]);
+
+        // a session rejects the message
+        let err = SyncSession::default()
+            .process(&mut MemoryStore::new(), message)
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            SyncError::Protocol(ProtocolError::InvalidMessage(
+                MessageValidationError::KeyOutOfRange { part: 0, .. }
+            ))
+        ));
+    }
+
+    #[test]
+    fn test_duplicate_values() {
+        let values = [("bee", 1), ("bee", 3), ("bee", 2), ("cat", 1)];
+        // the greatest value of a key is kept, regardless of the order of the values
+        for rotate in 0..values.len() {
+            let mut values = values.map(|entry| (entry, ContentStatus::Complete));
+            values.rotate_left(rotate);
+            let message = MessageBuilder::new()
+                .add_items(Range::new("", ""), values.to_vec(), false)
+                .build();
+            let mut bob = MemoryStore::new();
+            let mut inserted = Vec::new();
+            let outcome = bob
+                .process_message(
+                    &SyncConfig::default().with_outcome_entries(true),
+                    message.clone(),
+                    |_, _, _| true,
+                    |_, entry, _| inserted.push(entry),
+                    |_, _| ContentStatus::Complete,
+                )
+                .unwrap();
+            assert_eq!(inserted, [("bee", 3), ("cat", 1)]);
+            assert_eq!(outcome.duplicates.entries, [("bee", 2), ("bee", 1)]);
+            assert_eq!(outcome.inserted.count, 2);
+            assert_eq!(collect(bob.all().unwrap()), [("bee", 3), ("cat", 1)]);
+
+            // a session rejects the message
+            let err = SyncSession::default()
+                .process(&mut MemoryStore::new(), message)
+                .unwrap_err();
+            assert!(matches!(
+                err,
+                SyncError::Protocol(ProtocolError::InvalidMessage(
+                    MessageValidationError::DuplicateKey { part: 0, .. }
+                ))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_identical_entries_not_written() {
+        let entries: Vec<_> = (0..32u32).map(|i| (i, 1u8)).collect();
+        let mut bob = InstrumentedStore::new(entries.iter().copied().collect::<MemoryStore<_>>());
+
+        // alice resends all entries she has, which bob has already, in items of four entries
+        let mut message = MessageBuilder::new();
+        for chunk in entries.chunks(4) {
+            let range = Range::new(chunk[0].0, chunk[0].0 + 4);
+            let values = chunk
+                .iter()
+                .map(|entry| (*entry, ContentStatus::Complete))
+                .collect();
+            message.add_items(range, values, false);
+        }
+        let validated = Cell::new(0);
+        let outcome = bob
+            .process_message(
+                &SyncConfig::default(),
+                message.build(),
+                |_, _, _| {
+                    validated.set(validated.get() + 1);
+                    true
+                },
+                |_, _, _| panic!("identical entries must not be inserted"),
+                |_, _| ContentStatus::Complete,
+            )
+            .unwrap();
+        assert_eq!(outcome.already_present.count, entries.len());
+        assert_eq!(outcome.inserted.count + outcome.replaced.count, 0);
+        assert_eq!(outcome.reply, None);
+        // the validate callback still observes every entry
+        assert_eq!(validated.get(), entries.len());
+        let stats = bob.stats();
+        assert_eq!(stats.entry_fingerprint, entries.len() as u64);
+        assert_eq!(stats.prefixes_of, 0);
+        assert_eq!(stats.remove_prefix_filtered, 0);
+        assert_eq!(stats.entry_put, 0);
+    }
+
+    #[test]
+    fn test_stored_entries_not_read_back() {
+        let mut bob =
+            InstrumentedStore::new((0..64u32).map(|i| (i, 1u8)).collect::<MemoryStore<_>>());
+        // alice has newer values for the even keys below 32
+        let values = (0..32u32)
+            .step_by(2)
+            .map(|i| ((i, 2u8), ContentStatus::Complete))
+            .collect();
+        let message = MessageBuilder::new()
+            .add_items(Range::new(0, 64), values, false)
+            .build();
+        let outcome = bob
+            .process_message(
+                &SyncConfig::default(),
+                message,
+                |_, _, _| true,
+                |_, _, _| (),
+                |_, _| ContentStatus::Complete,
+            )
+            .unwrap();
+        assert_eq!(outcome.replaced.count, 16);
+        let sent: Vec<_> = outcome.reply.unwrap().values().map(|(e, _)| *e).collect();
+        let expected: Vec<_> = (0..64u32)
+            .filter(|i| i % 2 == 1 || *i >= 32)
+            .map(|i| (i, 1))
+            .collect();
+        assert_eq!(sent, expected);
+        // only the lookups that skip identical entries, the stored entries are not read back
+        let stats = bob.stats();
+        assert_eq!(stats.entry_fingerprint, 16);
+        assert_eq!(stats.get, 0);
+    }
+
+    #[test]
+    fn test_split_reads_ranges_once() {
+        let mut store =
+            InstrumentedStore::new((0..1_000u32).map(|i| (i, 1u8)).collect::<MemoryStore<_>>());
+        for send_threshold in [SendThreshold::Entries(1), SendThreshold::Entries(600)] {
+            let config = SyncConfig::default().with_send_threshold(send_threshold);
+            let message = MessageBuilder::new()
+                .add_fingerprint(Range::new(0, 0), Fingerprint([1; 32]))
+                .build();
+            store.reset();
+            let reply = store
+                .process_message_reply(
+                    &config,
+                    message,
+                    |_, _, _| true,
+                    |_, _, _| (),
+                    |_, _| ContentStatus::Complete,
+                )
+                .unwrap()
+                .unwrap();
+            let stats = store.stats();
+            // only the fingerprint of the received range is computed separately, the ranges it is
+            // split into are read once each
+            assert_eq!(stats.get_fingerprint, 1);
+            assert_eq!(reply.parts().len(), 2);
+            for part in reply.parts() {
+                match part {
+                    MessagePart::RangeFingerprint(RangeFingerprint { range, fingerprint }) => {
+                        assert_eq!(*fingerprint, store.get_fingerprint(range).unwrap());
+                    }
+                    MessagePart::RangeItem(RangeItem { range, values, .. }) => {
+                        let entries: Vec<_> = values.iter().map(|(entry, _)| *entry).collect();
+                        assert_eq!(entries, collect(store.get_range(*range).unwrap()));
+                    }
+                    part => panic!("unexpected part {part:?}"),
+                }
+            }
+        }
+    }
+
+    /// A store that returns the entries of the whole key space, but no entries for any other
+    /// range, as if it changed between the reads of a sync.
+    #[derive(Debug)]
+    struct FlakyStore<S>(S);
+
+    impl<E: RangeEntry, S: Store<E>> Store<E> for FlakyStore<S> {
+        type Error = S::Error;
+        type RangeIterator<'a> = std::iter::Take<S::RangeIterator<'a>> where S: 'a, E: 'a;
+        type ParentIterator<'a> = S::ParentIterator<'a> where S: 'a, E: 'a;
+
+        fn get_first(&mut self) -> Result<E::Key, Self::Error> {
+            self.0.get_first()
+        }
+
+        fn get(&mut self, key: &E::Key) -> Result<Option<E>, Self::Error> {
+            self.0.get(key)
+        }
+
+        fn len(&mut self) -> Result<usize, Self::Error> {
+            self.0.len()
+        }
+
+        fn is_empty(&mut self) -> Result<bool, Self::Error> {
+            self.0.is_empty()
+        }
+
+        fn get_fingerprint(&mut self, range: &Range<E::Key>) -> Result<Fingerprint, Self::Error> {
+            self.0.get_fingerprint(range)
+        }
+
+        fn entry_put(&mut self, entry: E) -> Result<(), Self::Error> {
+            self.0.entry_put(entry)
+        }
+
+        fn get_range(
+            &mut self,
+            range: Range<E::Key>,
+        ) -> Result<Self::RangeIterator<'_>, Self::Error> {
+            let n = match range.is_all() {
+                true => usize::MAX,
+                false => 0,
+            };
+            Ok(self.0.get_range(range)?.take(n))
+        }
+
+        fn get_range_chunked<'a>(
+            &'a mut self,
+            range: Range<E::Key>,
+            chunk_size: usize,
+        ) -> Result<ChunkIterator<'a, E, Self::Error>, Self::Error>
+        where
+            E: 'a,
+        {
+            let n = match range.is_all() {
+                true => usize::MAX,
+                false => 0,
+            };
+            Ok(Box::new(
+                self.0.get_range_chunked(range, chunk_size)?.take(n),
+            ))
+        }
+
+        fn get_range_len(&mut self, range: Range<E::Key>) -> Result<usize, Self::Error> {
+            self.0.get_range_len(range)
+        }
+
+        fn prefixed_by(&mut self, prefix: &E::Key) -> Result<Self::RangeIterator<'_>, Self::Error> {
+            Ok(self.0.prefixed_by(prefix)?.take(usize::MAX))
+        }
+
+        fn prefixes_of(&mut self, key: &E::Key) -> Result<Self::ParentIterator<'_>, Self::Error> {
+            self.0.prefixes_of(key)
+        }
+
+        fn all(&mut self) -> Result<Self::RangeIterator<'_>, Self::Error> {
+            Ok(self.0.all()?.take(usize::MAX))
+        }
+
+        fn entry_remove(&mut self, key: &E::Key) -> Result<Option<E>, Self::Error> {
+            self.0.entry_remove(key)
+        }
+
+        fn remove_prefix_filtered(
+            &mut self,
+            prefix: &E::Key,
+            predicate: impl Fn(&E::Value) -> bool,
+        ) -> Result<usize, Self::Error> {
+            self.0.remove_prefix_filtered(prefix, predicate)
+        }
+    }
+
+    #[test]
+    fn test_degenerate_partition() {
+        let entries: Vec<_> = (0..1_000u32).map(|i| (i, 1u8)).collect();
+        let mut store = FlakyStore(entries.iter().copied().collect::<MemoryStore<_>>());
+        let config = SyncConfig::default().with_send_threshold(SendThreshold::Entries(1));
+        let message = MessageBuilder::new()
+            .add_fingerprint(Range::new(0, 0), Fingerprint([1; 32]))
+            .build();
+
+        // the ranges the store splits the range into are all empty, so it answers with all
+        // entries of the range instead, in consecutive items within the send threshold
+        let outcome = store
+            .process_message(
+                &config,
+                message.clone(),
+                |_, _, _| true,
+                |_, _, _| (),
+                |_, _| ContentStatus::Complete,
+            )
+            .unwrap();
+        assert_eq!(outcome.degenerate, [Range::new(0, 0)]);
+        let reply = outcome.reply.unwrap();
+        assert_eq!(reply.parts().len(), entries.len());
+        let mut start = 0;
+        for part in reply.parts() {
+            let MessagePart::RangeItem(item) = part else {
+                panic!("unexpected part {part:?}");
+            };
+            assert_eq!(item.range.x(), &start);
+            assert_eq!(item.values.len(), 1);
+            start = *item.range.y();
+        }
+        assert_eq!(start, 0);
+        let values: Vec<_> = reply.values().map(|(entry, _)| *entry).collect();
+        assert_eq!(values, entries);
+
+        // a session answers the same way, and counts the range
+        let mut session = SyncSession::new(config);
+        let session_reply = session.process(&mut store, message).unwrap();
+        assert_eq!(session_reply, Some(reply));
+        assert_eq!(session.stats().degenerate_ranges, 1);
+    }
+
+    #[test]
+    fn test_degenerate_partition_sub_range() {
+        let entries: Vec<_> = (0..1_000u32).map(|i| (i, 1u8)).collect();
+        let mut store = FlakyStore(entries.iter().copied().collect::<MemoryStore<_>>());
+        let config = SyncConfig::default().with_send_threshold(SendThreshold::Entries(1));
+        let range = Range::new(100, 600);
+        let message = MessageBuilder::new()
+            .add_fingerprint(range, Fingerprint([1; 32]))
+            .build();
+
+        // the store claims 500 entries in the range but yields none of them, so there are no
+        // pivots to split it at, and it is answered with the entries it does yield
+        let outcome = store
+            .process_message(
+                &config,
+                message,
+                |_, _, _| true,
+                |_, _, _| (),
+                |_, _| ContentStatus::Complete,
+            )
+            .unwrap();
+        assert_eq!(outcome.degenerate, [range]);
+        let reply = outcome.reply.unwrap();
+        assert_eq!(reply.parts().len(), 1);
+        assert_eq!(reply.values().count(), 0);
+    }
+
+    #[test]
+    fn test_overlapping_ranges_send_once() {
+        let mut bob: MemoryStore<_> = [("a", 1), ("b", 1), ("c", 1), ("d", 1), ("e", 1)]
+            .into_iter()
+            .collect();
+        // the items ask for bob's entries in ranges that overlap each other, and the fingerprint
+        // of a range that overlaps both
+        for fingerprint_range in [Range::new("b", "f"), Range::new("d", "b")] {
+            for max_entries in [2, 8] {
+                let message = MessageBuilder::new()
+                    .add_items(Range::new("a", "d"), vec![], false)
+                    .add_items(Range::new("c", "e"), vec![], false)
+                    .add_fingerprint(fingerprint_range, Fingerprint([1; 32]))
+                    .build();
+                let config =
+                    SyncConfig::default().with_send_threshold(SendThreshold::Entries(max_entries));
+                let reply = bob
+                    .process_message_reply(
+                        &config,
+                        message,
+                        |_, _, _| true,
+                        |_, _, _| (),
+                        |_, _| ContentStatus::Complete,
+                    )
+                    .unwrap()
+                    .unwrap();
+                test_utils::assert_sent_once("bob", std::slice::from_ref(&reply));
+                let mut keys: Vec<_> = reply.values().map(|(entry, _)| entry.0).collect();
+                keys.sort();
+                assert_eq!(keys, ["a", "b", "c", "d", "e"], "{fingerprint_range:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_on_insert_hook() {
+        let alice_set = [("alice1", 1), ("both", 1), ("bob1", 1)];
+        let bob_set = [("bob1", 3), ("bob2", 4), ("bob3", 5), ("both", 1)];
+        let mut alice: MemoryStore<_> = alice_set.into_iter().collect();
+        let mut bob: MemoryStore<_> = bob_set.into_iter().collect();
+
+        let inserted = Arc::new(Mutex::new(vec![]));
+        let mut alice_session = SyncSession::default().with_on_insert({
+            let inserted = inserted.clone();
+            move |entry: &(&str, i32), kind| inserted.lock().unwrap().push((*entry, kind))
+        });
+        let mut bob_session = SyncSession::default();
+        let mut next = Some(alice_session.initial_message(&mut alice).unwrap());
+        while let Some(msg) = next.take() {
+            next = bob_session
+                .process(&mut bob, msg)
+                .unwrap()
+                .map(|msg| {
+                    // alice rejects bob3
+                    alice_session.process_message(
+                        &mut alice,
+                        msg,
+                        |_, e, _| e.0 != "bob3",
+                        |_, _, _| (),
+                        |_, _| ContentStatus::Complete,
+                    )
+                })
+                .transpose()
+                .unwrap()
+                .flatten();
+        }
+
+        // the hook fired once for each entry that was written, and never for rejected entries
+        // or entries alice already had
+        let mut inserted = inserted.lock().unwrap().clone();
+        inserted.sort_by_key(|(entry, _)| *entry);
+        assert_eq!(
+            inserted,
+            [
+                (("bob1", 3), InsertKind::Updated),
+                (("bob2", 4), InsertKind::New)
+            ]
+        );
+        assert!(alice.get(&"bob3").unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_async_validator() {
+        let bob_set = [("bee", 1), ("cat", 1), ("doe", 1), ("fox", 3), ("hog", 1)];
+        let new_alice = || -> MemoryStore<_> { [("ape", 1), ("fox", 2)].into_iter().collect() };
+        let part = MessagePart::RangeItem(RangeItem {
+            range: Range::new("", ""),
+            values: bob_set
+                .iter()
+                .map(|entry| (*entry, ContentStatus::Complete))
+                .collect(),
+            have_local: false,
+        });
+        let msg = Message {
+            parts: vec![part],
+            more: false,
+        };
+
+        let validated = Arc::new(Mutex::new(vec![]));
+        let validate = |_: &MemoryStore<_>, entry: &(&'static str, i32), _| {
+            let validated = validated.clone();
+            let entry = *entry;
+            async move {
+                tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+                validated.lock().unwrap().push(entry);
+                entry.0 != "doe"
+            }
+        };
+        let mut inserted = vec![];
+        let mut alice = new_alice();
+        let reply = SyncSession::default()
+            .process_message_with_async_validator(
+                &mut alice,
+                msg.clone(),
+                validate,
+                |_, entry, _| inserted.push(entry),
+                |_, _| ContentStatus::Complete,
+            )
+            .await
+            .unwrap();
+
+        // entries are validated and inserted in message order
+        assert_eq!(validated.lock().unwrap().as_slice(), bob_set);
+        let expected_inserted = [("bee", 1), ("cat", 1), ("fox", 3), ("hog", 1)];
+        assert_eq!(inserted, expected_inserted);
+
+        // the reply is computed before the entries are inserted, as with a sync callback
+        let expected_reply = SyncSession::default()
+            .process_message(
+                &mut new_alice(),
+                msg,
+                |_, entry, _| entry.0 != "doe",
+                |_, _, _| (),
+                |_, _| ContentStatus::Complete,
+            )
+            .unwrap();
+        assert_eq!(reply, expected_reply);
+        assert_eq!(reply.unwrap().value_count(), 1);
+    }
+
+    #[test]
+    fn test_fallible_validate_cb() {
+        let bob_set = [("bee", 1), ("cat", 1), ("doe", 1), ("fox", 3), ("hog", 1)];
+        let alice_set = [("ape", 1), ("fox", 2)];
+        let part = MessagePart::RangeItem(RangeItem {
+            range: Range::new("", ""),
+            values: bob_set
+                .iter()
+                .map(|entry| (*entry, ContentStatus::Complete))
+                .collect(),
+            have_local: false,
+        });
+        let msg = Message {
+            parts: vec![part],
+            more: false,
+        };
+        let mut alice: MemoryStore<_> = alice_set.into_iter().collect();
+
+        // a failing callback aborts before any entry is inserted
+        let mut session = SyncSession::default();
+        let res = session.try_process_message(
+            &mut alice,
+            msg.clone(),
+            |_, entry, _| match entry.0 {
+                "fox" => Err(anyhow::anyhow!("keystore unavailable")),
+                key => Ok(key != "doe"),
+            },
+            |_, _, _| panic!("inserted"),
+            |_, _| ContentStatus::Complete,
+        );
+        let Err(SyncError::Validation(err)) = res else {
+            panic!("expected a validation error: {res:?}");
+        };
+        assert_eq!(err.key, "\"fox\"");
+        assert_eq!(
+            err.to_string(),
+            "failed to validate entry \"fox\": keystore unavailable"
+        );
+        assert_eq!(collect(alice.all().unwrap()), alice_set);
+
+        // a successful callback behaves as the infallible one
+        let reply = session
+            .try_process_message(
+                &mut alice,
+                msg,
+                |_, entry, _| Ok(entry.0 != "doe"),
+                |_, _, _| (),
+                |_, _| ContentStatus::Complete,
+            )
+            .unwrap();
+        assert_eq!(
+            reply.unwrap().values().collect::<Vec<_>>(),
+            [&(("ape", 1), ContentStatus::Complete)]
+        );
+        assert_eq!(
+            collect(alice.all().unwrap()),
+            [("ape", 1), ("bee", 1), ("cat", 1), ("fox", 3), ("hog", 1)]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_validate_unsorted_values() {
+        // the values of the parts are unsorted, and "b" is sent twice
+        let item = |values: &[(&'static str, i32)]| {
+            MessagePart::RangeItem(RangeItem {
+                range: Range::new("", ""),
+                values: values
+                    .iter()
+                    .map(|entry| (*entry, ContentStatus::Complete))
+                    .collect(),
+                have_local: true,
+            })
+        };
+        let msg = Message {
+            parts: vec![item(&[("z", 1), ("b", 1)]), item(&[("y", 1), ("b", 1)])],
+            more: false,
+        };
+        let expected = [("a", 1), ("b", 1)];
+
+        // only "b" is valid
+        let mut alice: MemoryStore<_> = [("a", 1)].into_iter().collect();
+        SyncSession::default()
+            .try_process_message(
+                &mut alice,
+                msg.clone(),
+                |_, entry, _| Ok(entry.0 == "b"),
+                |_, _, _| (),
+                |_, _| ContentStatus::Complete,
+            )
+            .unwrap();
+        assert_eq!(collect(alice.all().unwrap()), expected);
+
+        let mut alice: MemoryStore<_> = [("a", 1)].into_iter().collect();
+        SyncSession::default()
+            .process_message_with_async_validator(
+                &mut alice,
+                msg,
+                |_, entry, _| std::future::ready(entry.0 == "b"),
+                |_, _, _| (),
+                |_, _| ContentStatus::Complete,
+            )
+            .await
+            .unwrap();
+        assert_eq!(collect(alice.all().unwrap()), expected);
+    }
+
+    type Sessions = (SyncSession<(u32, u8)>, SyncSession<(u32, u8)>);
+    type Stores = (MemoryStore<(u32, u8)>, MemoryStore<(u32, u8)>);
+
+    /// Delivers `message` and the following messages from alice to bob, and bob's responses
+    /// back, until the sync terminates or `max_messages` messages from alice were delivered.
+    ///
+    /// Returns the next message from alice that was not delivered, and the number of entries
+    /// delivered in both directions.
+    fn exchange_until(
+        (alice_session, bob_session): &mut Sessions,
+        (alice, bob): &mut Stores,
+        message: Message<(u32, u8)>,
+        max_messages: usize,
+    ) -> (Option<Message<(u32, u8)>>, usize) {
+        let mut entries = 0;
+        let mut next = Some(message);
+        for _ in 0..max_messages {
+            let Some(message) = next.take() else { break };
+            entries += message.value_count();
+            let validate = |_: &_, _: &_, _| true;
+            let Some(reply) = bob_session
+                .process_message(
+                    bob,
+                    message,
+                    validate,
+                    |_, _, _| (),
+                    |_, _| ContentStatus::Complete,
+                )
+                .unwrap()
+            else {
+                break;
+            };
+            entries += reply.value_count();
+            next = alice_session
+                .process_message(
+                    alice,
+                    reply,
+                    validate,
+                    |_, _, _| (),
+                    |_, _| ContentStatus::Complete,
+                )
+                .unwrap();
+        }
+        (next, entries)
+    }
+
+    #[test]
+    fn test_session_resume() {
+        let alice: MemoryStore<_> = (0..1024u32)
+            .filter(|i| i % 97 != 0)
+            .map(|i| (i, 1))
+            .collect();
+        let bob: MemoryStore<_> = (0..1024u32)
+            .filter(|i| i % 89 != 0)
+            .map(|i| (i, 1))
+            .collect();
+        let mut expected: MemoryStore<_> = (0..1024u32)
+            .filter(|i| i % 97 != 0 || i % 89 != 0)
+            .map(|i| (i, 1))
+            .collect();
+        let expected = collect(expected.all().unwrap());
+
+        // kill the connection after each message from alice, losing the next one
+        let mut transferred = Vec::new();
+        for kill_at in 1.. {
+            let mut stores = (alice.clone(), bob.clone());
+            let mut sessions = Sessions::default();
+            let initial = sessions.0.initial_message(&mut stores.0).unwrap();
+            let (lost, _) = exchange_until(&mut sessions, &mut stores, initial, kill_at);
+            if lost.is_none() {
+                break;
+            }
+
+            // restart from scratch
+            let mut restarted = stores.clone();
+            let mut restarted_sessions = Sessions::default();
+            let initial = restarted_sessions
+                .0
+                .initial_message(&mut restarted.0)
+                .unwrap();
+            let (_, restart_entries) =
+                exchange_until(&mut restarted_sessions, &mut restarted, initial, usize::MAX);
+
+            // resume alice's session, bob starts a new session
+            let snapshot = sessions.0.suspend();
+            let snapshot = postcard::from_bytes(&postcard::to_stdvec(&snapshot).unwrap()).unwrap();
+            let (alice_session, message) = SyncSession::resume(&mut stores.0, snapshot).unwrap();
+            let mut sessions = (alice_session, SyncSession::default());
+            let (_, resume_entries) =
+                exchange_until(&mut sessions, &mut stores, message.unwrap(), usize::MAX);
+            transferred.push((resume_entries, restart_entries));
+
+            assert_eq!(collect(stores.0.all().unwrap()), expected);
+            assert_eq!(collect(stores.1.all().unwrap()), expected);
+            assert_eq!(collect(restarted.0.all().unwrap()), expected);
+        }
+
+        // the settled ranges are not visited again, so the further the session got, the more
+        // transfers are saved
+        assert!(transferred.len() > 2, "{transferred:?}");
+        assert!(transferred
+            .iter()
+            .all(|(resume, restart)| resume <= restart));
+        let (resume, restart) = transferred.last().unwrap();
+        assert!(resume < restart, "{transferred:?}");
+    }
+
+    #[test]
+    fn test_interleaved_sessions() {
+        let alice_set = [("ape", 1), ("bee", 1), ("fox", 2)];
+        let bob_set = [("cat", 1), ("fox", 1), ("hog", 1)];
+        let carol_set = [("doe", 1), ("eel", 1), ("gnu", 1)];
+        let mut alice: MemoryStore<_> = alice_set.into_iter().collect();
+        let mut bob: MemoryStore<_> = bob_set.into_iter().collect();
+        let mut carol: MemoryStore<_> = carol_set.into_iter().collect();
+
+        // carol syncs with alice and bob at the same time, alternating between the sessions
+        let mut remotes = [
+            (&mut alice, SyncSession::default(), SyncSession::default()),
+            (&mut bob, SyncSession::default(), SyncSession::default()),
+        ];
+        let mut next: Vec<_> = remotes
+            .iter_mut()
+            .map(|(store, session, _)| session.initial_message(*store).ok())
+            .collect();
+        let mut rounds = 0;
+        while next.iter().any(Option::is_some) {
+            rounds += 1;
+            assert!(rounds < 100, "too many rounds");
+            for ((store, session, carol_session), next) in remotes.iter_mut().zip(&mut next) {
+                let Some(msg) = next.take() else {
+                    continue;
+                };
+                let validate = |_: &_, _: &_, _| true;
+                let status = |_: &_, _: &_| ContentStatus::Complete;
+                let Some(reply) = carol_session
+                    .process_message(&mut carol, msg, validate, |_, _, _| (), status)
+                    .unwrap()
+                else {
+                    continue;
+                };
+                *next = session
+                    .process_message(*store, reply, validate, |_, _, _| (), status)
+                    .unwrap();
+            }
+        }
+
+        let all = [
+            ("ape", 1),
+            ("bee", 1),
+            ("cat", 1),
+            ("doe", 1),
+            ("eel", 1),
+            ("fox", 2),
+            ("gnu", 1),
+            ("hog", 1),
+        ];
+        assert_eq!(collect(carol.all().unwrap()), all);
+        // alice's session settled before carol received bob's entries
+        assert_eq!(
+            collect(alice.all().unwrap()),
+            [
+                ("ape", 1),
+                ("bee", 1),
+                ("doe", 1),
+                ("eel", 1),
+                ("fox", 2),
+                ("gnu", 1)
+            ]
+        );
+        // bob's session got alice's newer entry, which carol received in the other session
+        assert_eq!(
+            collect(bob.all().unwrap()),
+            [
+                ("cat", 1),
+                ("doe", 1),
+                ("eel", 1),
+                ("fox", 2),
+                ("gnu", 1),
+                ("hog", 1)
+            ]
+        );
+
+        for remote in [&mut alice, &mut bob] {
+            let res =
+                sync_exchange_messages(remote, &mut carol, |_, _, _| true, |_, _, _| true, 100);
+            assert_eq!(res.bob_stats.entries_inserted, 0);
+            assert_eq!(collect(res.alice.all().unwrap()), all);
+        }
+    }
+
+    #[cfg(feature = "parallel")]
+    #[proptest]
+    fn parallel_fingerprint_matches_sequential(
+        #[strategy(proptest::collection::vec(any::<(u32, u8)>(), 0..256))] entries: Vec<(u32, u8)>,
+        #[strategy(0usize..64)] min_entries: usize,
+        #[strategy(1usize..16)] chunk_size: usize,
+        start: u32,
+    ) {
+        let entries: BTreeMap<u32, (u32, u8)> = entries.into_iter().map(|e| (e.0, e)).collect();
+        let mut sequential = Fingerprint([0u8; 32]);
+        for entry in entries.range(start..).map(|(_, entry)| entry) {
+            sequential ^= entry.as_fingerprint();
+        }
+        let bounds = (
+            std::ops::Bound::Included(&start),
+            std::ops::Bound::Unbounded,
+        );
+        let parallel = memory::parallel_fingerprint(&entries, bounds, min_entries, chunk_size);
+        prop_assert_eq!(parallel, sequential);
+
+        // a store that opted in computes the same fingerprints, also for wrap-around ranges
+        let mut store: MemoryStore<_> = entries.into_values().collect();
+        let mut parallel_store = store.clone().with_parallel_fingerprints();
+        let range = Range::new(start, start / 2);
+        prop_assert_eq!(
+            parallel_store.get_fingerprint(&range).unwrap(),
+            store.get_fingerprint(&range).unwrap()
+        );
+    }
+
+    #[proptest]
+    fn range_overlaps(
+        #[strategy(test_range())] a: Range<String>,
+        #[strategy(test_range())] b: Range<String>,
+        #[strategy(test_key())] key: String,
+    ) {
+        if a.contains(&key) && b.contains(&key) {
+            prop_assert!(a.overlaps(&b));
+        }
+        prop_assert_eq!(a.overlaps(&b), b.overlaps(&a));
+        // ranges that overlap have the start of one of them in common
+        if a.overlaps(&b) {
+            prop_assert!([a.x(), b.x()]
+                .into_iter()
+                .any(|key| a.contains(key) && b.contains(key)));
+        }
+    }
+
+    #[proptest]
+    fn remote_values_match_scan(
+        #[strategy(prop::collection::btree_map("[a-c]{0,2}", test_value_u8(), 0..10))]
+        local: TestSetStringU8,
+        #[strategy(prop::collection::vec(("[a-c]{0,2}", test_value_u8()), 0..10))] remote: Vec<(
+            String,
+            u8,
+        )>,
+        rotate: prop::sample::Index,
+    ) {
+        let remote = remote
+            .into_iter()
+            .map(|entry| (entry, ContentStatus::Complete))
+            .collect::<Vec<_>>();
+        // local entries in ascending order, and in two ascending runs like a wrap-around range
+        let mut rotated = local.into_iter().collect::<Vec<_>>();
+        let ascending = rotated.clone();
+        if !rotated.is_empty() {
+            let mid = rotate.index(rotated.len());
+            rotated.rotate_left(mid);
+        }
+        for local in [ascending, rotated] {
+            let mut values = RemoteValues::new(&remote);
+            for our_entry in &local {
+                let scan = remote.iter().any(|(their_entry, _)| {
+                    our_entry.key() == their_entry.key() && their_entry.value() >= our_entry.value()
+                });
+                prop_assert_eq!(values.covers(our_entry), scan, "{:?}", our_entry);
+            }
+        }
+    }
+
+    #[proptest]
+    fn messages_without_expected_reply_are_not_answered(
+        #[strategy(test_vec_string_u8())] alice: Vec<(String, u8)>,
+        #[strategy(test_vec_string_u8())] bob: Vec<(String, u8)>,
+        #[strategy(prop::sample::select(vec![
+            SyncDirection::Both,
+            SyncDirection::SendOnly,
+            SyncDirection::ReceiveOnly,
+        ]))]
+        alice_direction: SyncDirection,
+        #[strategy(prop::sample::select(vec![
+            SyncDirection::Both,
+            SyncDirection::SendOnly,
+            SyncDirection::ReceiveOnly,
+        ]))]
+        bob_direction: SyncDirection,
+        #[strategy(1usize..4)] threshold: usize,
+        lazy: bool,
+    ) {
+        let config = |direction| {
+            SyncConfig::default()
+                .with_direction(direction)
+                .with_send_threshold(SendThreshold::Entries(threshold))
+        };
+        let (alice_config, bob_config) = (config(alice_direction), config(bob_direction));
+
+        // stores
+        let mut alice_store: MemoryStore<_> = alice.iter().cloned().collect();
+        let mut bob_store: MemoryStore<_> = bob.iter().cloned().collect();
+        let mut next = Some(alice_store.initial_message().unwrap());
+        let mut sides = [
+            (&bob_config, &mut bob_store),
+            (&alice_config, &mut alice_store),
+        ];
+        let mut rounds = 0;
+        while let Some(message) = next.take() {
+            prop_assert!(rounds < 100, "too many rounds");
+            let (config, store) = &mut sides[rounds % 2];
+            let summary = message.summary();
+            prop_assert_eq!(summary.expects_reply, message.expects_reply());
+            prop_assert_eq!(summary.total_values, message.value_count());
+            next = store
+                .process_message_reply(
+                    config,
+                    message,
+                    |_, _, _| true,
+                    |_, _, _| (),
+                    |_, _| ContentStatus::Complete,
+                )
+                .unwrap();
+            prop_assert!(next.is_none() || summary.expects_reply);
+            rounds += 1;
+        }
+
+        // sessions
+        let mut alice_store: MemoryStore<_> = alice.into_iter().collect();
+        let mut bob_store: MemoryStore<_> = bob.into_iter().collect();
+        let session = |config| match lazy {
+            true => SyncSession::new(config).with_lazy_values(|_, _| ()),
+            false => SyncSession::new(config),
+        };
+        let mut sides = [
+            (session(bob_config), &mut bob_store),
+            (session(alice_config), &mut alice_store),
+        ];
+        let mut next = Some(sides[1].0.initial_message(sides[1].1).unwrap());
+        let mut rounds = 0;
+        while let Some(message) = next.take() {
+            prop_assert!(rounds < 100, "too many rounds");
+            let (session, store) = &mut sides[rounds % 2];
+            let expects_reply = message.expects_reply();
+            next = session.process(*store, message).unwrap();
+            prop_assert!(next.is_none() || expects_reply);
+            rounds += 1;
+        }
+    }
+
+    #[proptest]
+    fn session_progress_is_monotonic(
+        #[strategy(test_vec_string_u8())] alice: Vec<(String, u8)>,
+        #[strategy(test_vec_string_u8())] bob: Vec<(String, u8)>,
+    ) {
+        let mut alice: MemoryStore<_> = alice.into_iter().collect();
+        let mut bob: MemoryStore<_> = bob.into_iter().collect();
+        let mut alice_session = SyncSession::default();
+        let mut bob_session = SyncSession::default();
+        prop_assert_eq!(alice_session.progress(&mut alice).unwrap().estimate, None);
+
+        let mut next = Some(alice_session.initial_message(&mut alice).unwrap());
+        let mut estimates = [0.0f32; 2];
+        let mut rounds = 0;
+        let mut sides = [
+            (&mut bob_session, &mut bob),
+            (&mut alice_session, &mut alice),
+        ];
+        let mut receiver = 0;
+        while let Some(message) = next.take() {
+            rounds += 1;
+            prop_assert!(rounds < 100, "too many rounds");
+            let (session, store) = &mut sides[receiver];
+            next = session.process(*store, message).unwrap();
+            for (i, (session, store)) in sides.iter_mut().enumerate() {
+                if let Some(estimate) = session.progress(*store).unwrap().estimate {
+                    prop_assert!((0.0..=1.0).contains(&estimate));
+                    prop_assert!(estimate >= estimates[i]);
+                    estimates[i] = estimate;
+                }
+            }
+            if next.is_some() {
+                receiver = 1 - receiver;
+            }
+        }
+        // the side that received the last message observes the termination
+        let (session, store) = &mut sides[receiver];
+        let progress = session.progress(*store).unwrap();
+        prop_assert_eq!(progress.estimate, Some(1.0));
+        prop_assert_eq!(progress.outstanding_ranges, 0);
+    }
+
+    #[test]
+    fn test_session_cancel() {
+        let (alice_set, bob_set) = paper_sets()[0];
+        let alice: MemoryStore<_> = alice_set.iter().copied().collect();
+        let bob: MemoryStore<_> = bob_set.iter().copied().collect();
+        let res = test_utils::sync_cancel_after(alice.clone(), bob.clone(), 1);
+        assert!(res.alice_cancelled && res.bob_cancelled);
+        assert_eq!(res.alice_to_bob.len(), 2);
+        assert!(res.alice_to_bob[1].is_cancel());
+        assert_eq!(res.bob_to_alice.len(), 1);
+        assert_eq!(res.bob_stats.messages_received, 2);
+
+        // messages after the cancellation are rejected by both sides
+        let (mut alice, mut bob) = (alice.clone(), bob.clone());
+        let mut alice_session = SyncSession::default();
+        let mut bob_session = SyncSession::default();
+        let initial = alice_session.initial_message(&mut alice).unwrap();
+        let process = |session: &mut SyncSession<_>, store: &mut MemoryStore<_>, msg| {
+            session.process(store, msg)
+        };
+        let reply = process(&mut bob_session, &mut bob, initial.clone())
+            .unwrap()
+            .unwrap();
+        let cancel = alice_session.cancel_message();
+        assert!(alice_session.is_cancelled());
+        assert!(process(&mut bob_session, &mut bob, cancel)
+            .unwrap()
+            .is_none());
+        assert!(bob_session.is_cancelled());
+        for (session, store, msg) in [
+            (&mut alice_session, &mut alice, reply),
+            (&mut bob_session, &mut bob, initial),
+        ] {
+            let err = process(session, store, msg).unwrap_err();
+            assert!(matches!(err, SyncError::Protocol(ProtocolError::Cancelled)));
+        }
+        assert_eq!(collect(alice.all().unwrap()), alice_set);
+        assert_eq!(collect(bob.all().unwrap()), bob_set);
+
+        // the store ends the sync as well
+        let reply = bob
+            .process_message_reply(
+                &SyncConfig::default(),
+                Message::cancel(),
+                |_, _, _| true,
+                |_, _, _| (),
+                |_, _| ContentStatus::Complete,
+            )
+            .unwrap();
+        assert!(reply.is_none());
+    }
+
+    #[test]
+    fn test_handshake() {
+        let (alice_set, bob_set) = paper_sets()[0];
+        let mut alice: MemoryStore<_> = alice_set.iter().copied().collect();
+        let config = SyncConfig::default();
+        let validate = |_: &_, _: &_, _| true;
+        let status = |_: &_, _: &_| ContentStatus::Complete;
+
+        // identical stores end the sync after one round trip
+        let mut bob = alice.clone();
+        let handshake = alice.handshake_message().unwrap();
+        let full = alice.full_fingerprint().unwrap();
+        assert_eq!(full.count, 4);
+        assert_eq!(handshake.parts(), [MessagePart::Handshake(full)]);
+        let reply = bob
+            .process_message_reply(&config, handshake, validate, |_, _, _| (), status)
+            .unwrap()
+            .unwrap();
+        assert_eq!(reply.parts(), [MessagePart::HandshakeMatch]);
+        let reply = alice
+            .process_message_reply(&config, reply, validate, |_, _, _| (), status)
+            .unwrap();
+        assert!(reply.is_none());
+
+        // divergent stores continue as if the initial message was sent
+        let mut bob: MemoryStore<_> = bob_set.iter().copied().collect();
+        let handshake = alice.handshake_message().unwrap();
+        let initial = alice.initial_message().unwrap();
+        let expected = bob
+            .clone()
+            .process_message(&config, initial, validate, |_, _, _| (), status)
+            .unwrap();
+        let reply = bob
+            .process_message(&config, handshake, validate, |_, _, _| (), status)
+            .unwrap();
+        // the whole set is named by the first key of the sender
+        assert_eq!(reply.reply, expected.reply);
+
+        // and sync to the end
+        let mut alice_session = SyncSession::default();
+        let mut bob_session = SyncSession::default();
+        let mut next = Some(alice_session.handshake_message(&mut alice).unwrap());
+        let mut sides = [
+            (&mut bob_session, &mut bob),
+            (&mut alice_session, &mut alice),
+        ];
+        let mut rounds = 0;
+        while let Some(message) = next.take() {
+            assert!(message
+                .parts()
+                .iter()
+                .all(|part| *part != MessagePart::HandshakeMatch));
+            let (session, store) = &mut sides[rounds % 2];
+            next = session
+                .process_message(*store, message, validate, |_, _, _| (), status)
+                .unwrap();
+            rounds += 1;
+        }
+        assert!(rounds > 2);
+        let progress = alice_session.progress(&mut alice).unwrap();
+        assert_eq!(progress.outstanding_ranges, 0);
+        assert_eq!(alice, bob);
+    }
+
+    #[test]
+    fn test_session_sequence_numbers() {
+        type Entries = Vec<(&'static str, i32)>;
+
+        /// Runs a sync where each message is delivered `times` times, and returns the final
+        /// stores and the number of validated and inserted entries.
+        fn run(times: usize) -> (Entries, Entries, usize, usize) {
+            let (alice_set, bob_set) = paper_sets()[0];
+            let mut alice: MemoryStore<_> = alice_set.iter().copied().collect();
+            let mut bob: MemoryStore<_> = bob_set.iter().copied().collect();
+            let validated = Arc::new(Mutex::new(0));
+            let inserted = Arc::new(Mutex::new(0));
+            let session = || {
+                let (validated, inserted) = (validated.clone(), inserted.clone());
+                SyncSession::default()
+                    .with_sequence_numbers()
+                    .with_validator(move |_, _| {
+                        *validated.lock().unwrap() += 1;
+                        true
+                    })
+                    .with_on_insert(move |_, _| *inserted.lock().unwrap() += 1)
+            };
+            let (mut alice_session, mut bob_session) = (session(), session());
+            let mut next = Some(alice_session.initial_message(&mut alice).unwrap());
+            let mut sessions = [
+                (&mut bob_session, &mut bob),
+                (&mut alice_session, &mut alice),
+            ];
+            let mut rounds = 0;
+            while let Some(msg) = next.take() {
+                let (session, store) = &mut sessions[rounds % 2];
+                next = session.process(*store, msg.clone()).unwrap();
+                for _ in 1..times {
+                    assert!(session.process(*store, msg.clone()).unwrap().is_none());
+                }
+                assert_eq!(
+                    session.stats().messages_dropped,
+                    (rounds / 2 + 1) * (times - 1)
+                );
+                rounds += 1;
+            }
+            let validated = *validated.lock().unwrap();
+            let inserted = *inserted.lock().unwrap();
+            (
+                collect(alice.all().unwrap()),
+                collect(bob.all().unwrap()),
+                validated,
+                inserted,
+            )
+        }
+
+        let once = run(1);
+        assert!(once.2 > 0 && once.3 > 0);
+        assert_eq!(run(2), once);
+        assert_eq!(run(3), once);
+
+        // stale messages are dropped as well, and the last response can be sent again
+        let (alice_set, bob_set) = paper_sets()[0];
+        let mut alice: MemoryStore<_> = alice_set.iter().copied().collect();
+        let mut bob: MemoryStore<_> = bob_set.iter().copied().collect();
+        let mut alice_session = SyncSession::default().with_sequence_numbers();
+        let mut bob_session = SyncSession::default().with_sequence_numbers();
+        let init = alice_session.initial_message(&mut alice).unwrap();
+        assert_eq!(init.sequence(), Some(0));
+        let reply = bob_session
+            .process(&mut bob, init.clone())
+            .unwrap()
+            .unwrap();
+        assert_eq!(bob_session.last_response(), std::slice::from_ref(&reply));
+        let next = alice_session.process(&mut alice, reply).unwrap().unwrap();
+        assert_eq!(next.sequence(), Some(1));
+        bob_session.process(&mut bob, next).unwrap();
+        assert!(bob_session.process(&mut bob, init).unwrap().is_none());
+        let resent = alice_session.last_response().to_vec();
+        assert!(bob_session
+            .process(&mut bob, resent[0].clone())
+            .unwrap()
+            .is_none());
+        assert_eq!(bob_session.stats().messages_dropped, 2);
+        assert_eq!(bob_session.stats().messages_received, 2);
+    }
+
+    #[test]
+    fn test_send_threshold_fn() {
+        let data = ["/data/a", "/data/b", "/data/c", "/data/d"];
+        let meta = ["/meta/a", "/meta/b", "/meta/c", "/meta/d"];
+        let mut alice: MemoryStore<_> = data.iter().map(|key| (*key, 1)).collect();
+        let mut bob: MemoryStore<_> = data
+            .iter()
+            .map(|key| (*key, 2))
+            .chain(meta.iter().map(|key| (*key, 1)))
+            .collect();
+        // send everything under `/meta/` eagerly, and only single entries otherwise
+        let session = || {
+            SyncSession::default().with_send_threshold_fn(|range: &Range<&str>| {
+                match range.x().starts_with("/meta/") {
+                    true => SendThreshold::Entries(usize::MAX),
+                    false => SendThreshold::Entries(1),
+                }
+            })
+        };
+        let mut alice_session = session();
+        let mut bob_session = session();
+
+        // bob splits the whole set at `/meta/a`, and only recurses into the data
+        let msg = alice_session.initial_message(&mut alice).unwrap();
+        let msg = bob_session.process(&mut bob, msg).unwrap().unwrap();
+        let [MessagePart::RangeFingerprint(fp), MessagePart::RangeItem(item)] = msg.parts() else {
+            panic!("expected a fingerprint and an item, got {:?}", msg.parts());
+        };
+        assert_eq!(fp.range, Range::new("/data/a", "/meta/a"));
+        let keys: Vec<_> = item.values.iter().map(|(entry, _)| *entry.key()).collect();
+        assert_eq!(keys, meta);
+
+        let mut next = Some(msg);
+        let mut sessions = [
+            (&mut alice_session, &mut alice),
+            (&mut bob_session, &mut bob),
+        ];
+        let mut rounds = 0;
+        while let Some(msg) = next.take() {
+            let (session, store) = &mut sessions[rounds % 2];
+            next = session.process(*store, msg).unwrap();
+            rounds += 1;
+        }
+        assert_eq!(collect(alice.all().unwrap()), collect(bob.all().unwrap()));
+
+        // a fingerprint for a range under `/meta/` is answered with its entries right away
+        let mut carol = MemoryStore::default();
+        carol.put(("/meta/x", 1)).unwrap();
+        let msg = carol
+            .initial_message_for_range(Range::new("/meta/", "/meta0"))
+            .unwrap();
+        let msg = session().process(&mut bob, msg).unwrap().unwrap();
+        let [MessagePart::RangeItem(item)] = msg.parts() else {
+            panic!("expected a single item, got {:?}", msg.parts());
+        };
+        assert_eq!(item.values.len(), meta.len());
+    }
+
+    #[test]
+    fn test_process_message_with_options() {
+        let mut alice: MemoryStore<_> = [("ape", 1), ("bee", 1)].into_iter().collect();
+        let mut bob: MemoryStore<_> = [("cat", 1), ("doe", 1)].into_iter().collect();
+        let config = SyncConfig::default();
+        let process = |store: &mut MemoryStore<_>, msg, options| {
+            store
+                .process_message_with_options(
+                    &config,
+                    msg,
+                    options,
+                    |_, _, _| true,
+                    |_, _, _| (),
+                    |_, _| ContentStatus::Complete,
+                )
+                .unwrap()
+                .reply
+        };
+
+        // bob answers the fingerprint of the whole set with all of his entries
+        let send_threshold = |_: &Range<&str>| SendThreshold::Entries(usize::MAX);
+        let msg = alice.initial_message().unwrap();
+        let options = ProcessOptions::default().with_send_threshold(&send_threshold);
+        let msg = process(&mut bob, msg, options).unwrap();
+        let [MessagePart::RangeItem(item)] = msg.parts() else {
+            panic!("expected a single item, got {:?}", msg.parts());
+        };
+        assert_eq!(item.values.len(), 2);
+
+        // alice transforms bob's entries, and sends hers back
+        let transform = |(key, value): (&'static str, i32)| match key {
+            "cat" => None,
+            _ => Some((key, value + 100)),
+        };
+        let options = ProcessOptions::default().with_transform(&transform);
+        let msg = process(&mut alice, msg, options).unwrap();
+        assert_eq!(msg.value_count(), 2);
+        assert_eq!(
+            collect(alice.all().unwrap()),
+            [("ape", 1), ("bee", 1), ("doe", 101)]
+        );
+    }
+
+    #[test]
+    fn test_session_ids() {
+        let (alice_set, bob_set) = paper_sets()[0];
+        let mut alice: MemoryStore<_> = alice_set.iter().copied().collect();
+        let mut bob: MemoryStore<_> = bob_set.iter().copied().collect();
+        let process = |session: &mut SyncSession<_>, store: &mut MemoryStore<_>, msg| {
+            session.process(store, msg)
+        };
+        let is_mismatch = |err| {
+            matches!(
+                err,
+                SyncError::Protocol(ProtocolError::SessionMismatch { .. })
+            )
+        };
+
+        // two concurrent sessions between the same stores
+        let (id1, id2) = (SessionId::random(), SessionId::random());
+        assert_ne!(id1, id2);
+        let mut alice1 = SyncSession::default().with_session_id(id1);
+        let mut alice2 = SyncSession::default().with_session_id(id2);
+        let mut bob1 = SyncSession::default();
+        let mut bob2 = SyncSession::default();
+        let init1 = alice1.initial_message(&mut alice).unwrap();
+        let init2 = alice2.initial_message(&mut alice).unwrap();
+        assert_eq!(init1.session_id(), Some(id1));
+        let reply1 = process(&mut bob1, &mut bob, init1).unwrap().unwrap();
+        assert_eq!(bob1.session_id(), Some(id1));
+        assert_eq!(reply1.session_id(), Some(id1));
+        let reply2 = process(&mut bob2, &mut bob, init2.clone())
+            .unwrap()
+            .unwrap();
+
+        // messages fed into the other session are rejected without changing the stores
+        assert!(is_mismatch(
+            process(&mut alice2, &mut alice, reply1.clone()).unwrap_err()
+        ));
+        assert!(is_mismatch(
+            process(&mut bob1, &mut bob, init2).unwrap_err()
+        ));
+        assert_eq!(collect(alice.all().unwrap()), alice_set);
+
+        // both sessions still converge
+        for (session, reply) in [(&mut alice1, reply1), (&mut alice2, reply2)] {
+            let bob_session = match session.session_id() {
+                Some(id) if id == id1 => &mut bob1,
+                _ => &mut bob2,
+            };
+            let mut next = process(session, &mut alice, reply).unwrap();
+            while let Some(msg) = next {
+                next = process(bob_session, &mut bob, msg)
+                    .unwrap()
+                    .and_then(|msg| process(session, &mut alice, msg).unwrap());
+            }
+        }
+        assert_eq!(collect(alice.all().unwrap()), collect(bob.all().unwrap()));
+
+        // a session without an id rejects messages with an id after the first one, and the
+        // other way around
+        let mut alice_session = SyncSession::default();
+        let init = alice_session.initial_message(&mut alice).unwrap();
+        assert_eq!(init.session_id(), None);
+        let reply = Message {
+            parts: vec![MessagePart::SessionId(id1)],
+            more: false,
+        };
+        assert!(is_mismatch(
+            process(&mut alice_session, &mut alice, reply).unwrap_err()
+        ));
+        let mut bob_session = SyncSession::default().with_session_id(id1);
+        assert!(is_mismatch(
+            process(&mut bob_session, &mut bob, init).unwrap_err()
+        ));
+
+        // split responses carry the id in every message, within the budget
+        let mut alice: MemoryStore<_> = (0..100u32).map(|i| (i * 2, ())).collect();
+        let mut bob: MemoryStore<_> = (0..100u32).map(|i| (i * 3, ())).collect();
+        let mut alice_session = SyncSession::default().with_session_id(id1);
+        let mut bob_session = SyncSession::default().with_max_message_bytes(64);
+        let init = alice_session.initial_message(&mut alice).unwrap();
+        let mut messages = vec![bob_session.process(&mut bob, init).unwrap().unwrap()];
+        messages.extend(std::iter::from_fn(|| bob_session.poll_pending_message()));
+        assert!(messages.len() > 1);
+        for msg in messages {
+            assert_eq!(msg.session_id(), Some(id1));
+            assert!(postcard::to_stdvec(&msg).unwrap().len() <= 64);
+            alice_session.process(&mut alice, msg).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_session_apply_external() {
+        let alice: MemoryStore<_> = (0..1024u32)
+            .filter(|i| i % 97 != 0)
+            .map(|i| (i, 1))
+            .collect();
+        let bob: MemoryStore<_> = (0..1024u32)
+            .filter(|i| i % 89 != 0)
+            .map(|i| (i, 1))
+            .collect();
+
+        // inject entries into one side after each message, and once more after the sync ended
+        let mut dirty_injections = 0;
+        for inject_after in 1.. {
+            let mut stores = [alice.clone(), bob.clone()];
+            let mut sessions = [SyncSession::default(), SyncSession::default()];
+            let mut next = Some(sessions[0].initial_message(&mut stores[0]).unwrap());
+            let mut to = 1;
+            let mut messages = 0;
+            let mut external = Vec::new();
+            loop {
+                while let Some(message) = next.take() {
+                    next = sessions[to].process(&mut stores[to], message).unwrap();
+                    messages += 1;
+                    if messages == inject_after {
+                        // a new key, and a newer value for a key that both sides have
+                        let at = inject_after as u32;
+                        let entries = vec![(2048 + at, 1), (at * 7 % 1024 + 1, 2)];
+                        external.extend(entries.clone());
+                        let report = sessions[to]
+                            .apply_external(&mut stores[to], entries)
+                            .unwrap();
+                        assert_eq!(report.import.inserted + report.import.replaced, 2);
+                        if !report.dirty_ranges.is_empty() {
+                            dirty_injections += 1;
+                        }
+                    }
+                    to = 1 - to;
+                }
+                // once the exchange ended, the dirty ranges are reconciled again
+                let Some(from) = (0..2).find(|&i| sessions[i].has_dirty_ranges()) else {
+                    break;
+                };
+                next = sessions[from].reopen_message(&mut stores[from]).unwrap();
+                assert!(next.as_ref().unwrap().is_reopen());
+                to = 1 - from;
+            }
+            if external.is_empty() {
+                break;
+            }
+
+            let mut expected: MemoryStore<_> = alice.clone();
+            for entry in collect(bob.clone().all().unwrap())
+                .into_iter()
+                .chain(external)
+            {
+                expected.put(entry).unwrap();
+            }
+            let expected = collect(expected.all().unwrap());
+            assert_eq!(collect(stores[0].all().unwrap()), expected);
+            assert_eq!(collect(stores[1].all().unwrap()), expected);
+        }
+        assert!(dirty_injections > 2);
+
+        // entries rejected by the validator are not written, and make no range dirty
+        let (mut alice, mut bob) = (alice.clone(), bob.clone());
+        let mut alice_session =
+            SyncSession::default().with_validator(|entry: &(u32, u8), _| entry.1 < 5);
+        let mut bob_session = SyncSession::default();
+        let mut next = Some(alice_session.initial_message(&mut alice).unwrap());
+        while let Some(message) = next.take() {
+            next = bob_session.process(&mut bob, message).unwrap();
+            if let Some(message) = next.take() {
+                next = alice_session.process(&mut alice, message).unwrap();
+            }
+        }
+        let report = alice_session
+            .apply_external(&mut alice, vec![(3, 5), (3, 6)])
+            .unwrap();
+        assert_eq!(report.rejected, 2);
+        assert_eq!(report.import, ImportReport::default());
+        assert!(report.dirty_ranges.is_empty());
+        assert!(alice_session.reopen_message(&mut alice).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_session_epoch_report() {
+        let alice: MemoryStore<_> = (0..1024u32)
+            .filter(|i| i % 97 != 0)
+            .map(|i| (i, 1))
+            .collect();
+        let bob: MemoryStore<_> = (0..1024u32)
+            .filter(|i| i % 89 != 0)
+            .map(|i| (i, 1))
+            .collect();
+
+        // write to one store outside of the sessions after each message
+        let mut dirty_writes = 0;
+        for write_after in 1.. {
+            let mut stores = [EpochStore::new(alice.clone()), EpochStore::new(bob.clone())];
+            let mut sessions = [SyncSession::default(), SyncSession::default()];
+            let mut next = Some(sessions[0].initial_message(&mut stores[0]).unwrap());
+            let mut to = 1;
+            let mut messages = 0;
+            let mut external = Vec::new();
+            let mut dirty = false;
+            loop {
+                while let Some(message) = next.take() {
+                    next = sessions[to].process(&mut stores[to], message).unwrap();
+                    messages += 1;
+                    if messages == write_after {
+                        let at = write_after as u32;
+                        let entry = (2048 + at, 1);
+                        external.push(entry);
+                        stores[to].put(entry).unwrap();
+                    }
+                    to = 1 - to;
+                }
+                // the settled ranges of the side that was written to are dirty
+                let reports = [0, 1].map(|i| sessions[i].epoch_report(&stores[i]));
+                dirty |= reports.iter().any(|report| !report.dirty_ranges.is_empty());
+                let Some(from) = (0..2).find(|&i| sessions[i].has_dirty_ranges()) else {
+                    break;
+                };
+                next = sessions[from].reopen_message(&mut stores[from]).unwrap();
+                to = 1 - from;
+            }
+            if external.is_empty() {
+                // the writes of the sessions themselves make no range dirty
+                assert!(!dirty);
+                assert!(stores.iter().all(|store| store.epoch() > 0));
+                break;
+            }
+            dirty_writes += usize::from(dirty);
+
+            let mut expected: MemoryStore<_> = alice.clone();
+            for entry in collect(bob.clone().all().unwrap())
+                .into_iter()
+                .chain(external)
+            {
+                expected.put(entry).unwrap();
+            }
+            let expected = collect(expected.all().unwrap());
+            assert_eq!(collect(stores[0].all().unwrap()), expected);
+            assert_eq!(collect(stores[1].all().unwrap()), expected);
+        }
+        assert!(dirty_writes > 2);
+
+        // a session that only reads from its store reports no dirty ranges
+        let mut alice = EpochStore::new(alice);
+        let mut bob = EpochStore::new(alice.inner().clone());
+        let mut alice_session = SyncSession::default();
+        let mut bob_session = SyncSession::default();
+        let mut next = Some(alice_session.initial_message(&mut alice).unwrap());
+        while let Some(message) = next.take() {
+            next = bob_session.process(&mut bob, message).unwrap();
+            if let Some(message) = next.take() {
+                next = alice_session.process(&mut alice, message).unwrap();
+            }
+        }
+        for (session, store) in [(&mut alice_session, &alice), (&mut bob_session, &bob)] {
+            let report = session.epoch_report(store);
+            assert_eq!(report.epoch, 0);
+            assert!(report.dirty_ranges.is_empty());
+        }
+
+        // writes after the session ended make the whole set dirty
+        alice.put((4096, 1)).unwrap();
+        let report = alice_session.epoch_report(&alice);
+        assert_eq!(report.epoch, 1);
+        assert_eq!(report.dirty_ranges, [Range::new(1, 1)]);
+        assert!(alice_session.epoch_report(&alice).dirty_ranges.is_empty());
+    }
+
+    #[test]
+    fn test_session_epoch_report_wrapped() {
+        fn check<S: Store<(u32, i32)>>(mut alice: S) {
+            let mut bob: MemoryStore<_> = (0..256u32).map(|i| (i, 1)).collect();
+            let mut alice_session = SyncSession::default();
+            let mut bob_session = SyncSession::default();
+            let mut next = Some(alice_session.initial_message(&mut alice).unwrap());
+            while let Some(message) = next.take() {
+                next = bob_session.process(&mut bob, message).unwrap();
+                if let Some(message) = next.take() {
+                    next = alice_session.process(&mut alice, message).unwrap();
+                }
+            }
+            assert!(alice_session.epoch_report(&alice).dirty_ranges.is_empty());
+
+            // the wrapper reports the epoch of the epoch store, so the write is detected
+            alice.put((4096, 1)).unwrap();
+            let report = alice_session.epoch_report(&alice);
+            assert!(report.epoch > 0);
+            assert!(report
+                .dirty_ranges
+                .iter()
+                .any(|range| range.contains(&4096)));
+        }
+
+        let mut alice: MemoryStore<_> =
+            (0..256u32).filter(|i| i % 7 != 0).map(|i| (i, 1)).collect();
+        check(BoxedStore::new(EpochStore::new(alice.clone())));
+        let (low, high): (Vec<_>, Vec<_>) = collect(alice.all().unwrap())
+            .into_iter()
+            .partition(|(k, _)| *k < 128);
+        let shards = [low, high].map(|entries| EpochStore::new(MemoryStore::from_iter(entries)));
+        check(ShardedStore::new(vec![128], shards.into()));
+    }
+
+    #[test]
+    fn test_session_replace_store() {
+        fn sync(sessions: &mut Sessions, stores: &mut Stores) {
+            let initial = sessions.0.initial_message(&mut stores.0).unwrap();
+            let (next, _) = exchange_until(sessions, stores, initial, usize::MAX);
+            assert!(next.is_none());
+        }
+
+        let alice: MemoryStore<_> = (0..512u32).filter(|i| i % 3 != 0).map(|i| (i, 1)).collect();
+        let bob: MemoryStore<_> = (0..512u32).filter(|i| i % 5 != 0).map(|i| (i, 1)).collect();
+        let compacted: MemoryStore<_> = (256..768u32).map(|i| (i, 2)).collect();
+        let mut stores = (alice, bob);
+        let mut sessions: Sessions = (
+            SyncSession::default().with_limits(ProtocolLimits {
+                max_depth: 64,
+                ..Default::default()
+            }),
+            SyncSession::default(),
+        );
+
+        // the store cannot be replaced while a response is outstanding
+        let mut fresh = Sessions::default();
+        fresh.0.initial_message(&mut stores.0).unwrap();
+        let err = fresh
+            .0
+            .replace_store(&mut stores.0, compacted.clone(), false)
+            .unwrap_err();
+        assert_eq!(err, ProtocolError::InFlight);
+        assert_eq!(stores.0.get(&1).unwrap(), Some((1, 1)));
+
+        sync(&mut sessions, &mut stores);
+        let first = collect(stores.1.all().unwrap());
+        assert_eq!(collect(stores.0.all().unwrap()), first);
+
+        // the second sync reconciles the new contents, with the limits of the session
+        let old = sessions
+            .0
+            .replace_store(&mut stores.0, compacted.clone(), false)
+            .unwrap();
+        assert_eq!(collect(old.clone().all().unwrap()), first);
+        assert_eq!(sessions.0.role(), None);
+        assert_eq!(sessions.0.limits().max_depth, 64);
+        // bob starts a new session for the second sync
+        sessions.1 = SyncSession::default();
+        sync(&mut sessions, &mut stores);
+        let mut expected = MemoryStore::default();
+        for entry in first
+            .into_iter()
+            .chain(collect(compacted.clone().all().unwrap()))
+        {
+            expected.put(entry).unwrap();
+        }
+        let expected = collect(expected.all().unwrap());
+        assert_eq!(collect(stores.0.all().unwrap()), expected);
+        assert_eq!(collect(stores.1.all().unwrap()), expected);
+        assert_eq!(sessions.0.role(), Some(Role::Initiator));
+        assert_eq!(sessions.1.role(), Some(Role::Responder));
+    }
+
+    #[test]
+    fn test_session_replace_store_forced() {
+        let alice: MemoryStore<_> = (0..512u32).filter(|i| i % 3 != 0).map(|i| (i, 1)).collect();
+        let bob: MemoryStore<_> = (0..512u32).filter(|i| i % 5 != 0).map(|i| (i, 1)).collect();
+        let replacement: MemoryStore<_> = (256..768u32)
+            .filter(|i| i % 7 != 0)
+            .map(|i| (i, 2))
+            .collect();
+        let mut stores = (alice, bob);
+        let mut sessions = Sessions::default();
+
+        // replace alice's store in the middle of the sync, with a message to bob in flight
+        let initial = sessions.0.initial_message(&mut stores.0).unwrap();
+        let (next, _) = exchange_until(&mut sessions, &mut stores, initial, 2);
+        assert!(next.is_some());
+        let err = sessions
+            .0
+            .replace_store(&mut stores.0, replacement.clone(), false)
+            .unwrap_err();
+        assert_eq!(err, ProtocolError::InFlight);
+        sessions
+            .0
+            .replace_store(&mut stores.0, replacement.clone(), true)
+            .unwrap();
+        assert_eq!(stores.0, replacement);
+        assert_eq!(sessions.0.role(), None);
+        assert!(!sessions.0.is_done());
+        assert!(sessions.0.epoch_report(&stores.0).dirty_ranges.is_empty());
+
+        // nothing of the interrupted sync is left: the store can be replaced without `force`,
+        // and the session starts over with the same message as a new session
+        sessions
+            .0
+            .replace_store(&mut stores.0, replacement.clone(), false)
+            .unwrap();
+        let initial = sessions.0.initial_message(&mut stores.0).unwrap();
+        let fresh = SyncSession::default()
+            .initial_message(&mut replacement.clone())
+            .unwrap();
+        assert_eq!(initial, fresh);
+
+        // with a new session of bob, all of the new contents are reconciled, which fails if
+        // alice skips ranges settled with the old store, or compares fingerprints of it
+        let mut expected = stores.1.clone();
+        for entry in collect(replacement.clone().all().unwrap()) {
+            expected.put(entry).unwrap();
+        }
+        sessions.1 = SyncSession::default();
+        let (next, _) = exchange_until(&mut sessions, &mut stores, initial, usize::MAX);
+        assert!(next.is_none());
+        assert_eq!(stores.0, expected);
+        assert_eq!(stores.1, expected);
+    }
+
+    #[test]
+    fn test_session_replace_store_after_sync() {
+        // bob answers alice's fingerprint with an item, whose reply only carries alice's entries
+        let mut stores: Stores = (
+            [(1, 1)].into_iter().collect(),
+            [(2, 1)].into_iter().collect(),
+        );
+        let mut sessions = Sessions::default();
+        let initial = sessions.0.initial_message(&mut stores.0).unwrap();
+        let replacement: MemoryStore<_> = [(3, 1)].into_iter().collect();
+        let err = sessions
+            .0
+            .replace_store(&mut stores.0, replacement.clone(), false)
+            .unwrap_err();
+        assert!(matches!(err, ProtocolError::InFlight));
+        let reply = sessions.1.process(&mut stores.1, initial).unwrap().unwrap();
+        let reply = sessions.0.process(&mut stores.0, reply).unwrap().unwrap();
+        assert!(sessions.1.process(&mut stores.1, reply).unwrap().is_none());
+        assert_eq!(stores.0, stores.1);
+        let old = sessions
+            .1
+            .replace_store(&mut stores.1, replacement.clone(), false)
+            .unwrap();
+        assert_eq!(old, stores.0);
+
+        // bob has newer values for some of alice's entries, so she settles the items bob sends
+        // last without responding, and bob can replace his store once the sync finished
+        let alice: MemoryStore<_> = (0..512u32).map(|i| (i, 1)).collect();
+        let bob: MemoryStore<_> = (0..512u32).map(|i| (i, 1 + (i % 3 == 0) as u8)).collect();
+        let mut stores = (alice, bob);
+        let mut sessions = Sessions::default();
+        let initial = sessions.0.initial_message(&mut stores.0).unwrap();
+        let (next, _) = exchange_until(&mut sessions, &mut stores, initial, usize::MAX);
+        assert!(next.is_none());
+        assert_eq!(stores.0, stores.1);
+        assert!(!sessions.1.is_done());
+        let synced = stores.clone();
+        for (session, store) in [
+            (&mut sessions.0, &mut stores.0),
+            (&mut sessions.1, &mut stores.1),
+        ] {
+            session
+                .replace_store(store, replacement.clone(), false)
+                .unwrap();
+        }
+
+        // with `Done`, neither side needs `force` to replace its store once the sync finished
+        let (mut alice, mut bob) = synced;
+        alice.put((1024, 1)).unwrap();
+        let mut alice_session = SyncSession::default();
+        let mut bob_session = SyncSession::default();
+        let initial = alice_session.initial_message(&mut alice).unwrap();
+        let mut next = Some(alice_session.encode_message(&initial).unwrap());
+        let mut sessions = [
+            (&mut bob_session, &mut bob),
+            (&mut alice_session, &mut alice),
+        ];
+        let mut rounds = 0;
+        while let Some(bytes) = next.take() {
+            let (session, store) = &mut sessions[rounds % 2];
+            let message = session.decode_message(&bytes).unwrap();
+            next = session
+                .process(*store, message)
+                .unwrap()
+                .map(|reply| session.encode_message(&reply).unwrap());
+            rounds += 1;
+        }
+        for (session, store) in sessions {
+            assert!(session.is_done());
+            let mut old = session
+                .replace_store(store, replacement.clone(), false)
+                .unwrap();
+            assert_eq!(old.len().unwrap(), 513);
+            assert_eq!(session.role(), None);
+            assert!(!session.is_done());
+        }
+
+        // the stores can be synced again with the same sessions
+        bob.put((2048, 2)).unwrap();
+        let initial = alice_session.initial_message(&mut alice).unwrap();
+        let mut next = Some(initial);
+        let mut rounds = 0;
+        while let Some(message) = next.take() {
+            next = match rounds % 2 {
+                0 => bob_session.process(&mut bob, message).unwrap(),
+                _ => alice_session.process(&mut alice, message).unwrap(),
+            };
+            rounds += 1;
+        }
+        assert_eq!(alice, bob);
+        assert_eq!(alice.get(&2048).unwrap(), Some((2048, 2)));
+
+        // alice's fingerprint of equal stores is settled without a response, so without `Done`
+        // she cannot tell whether bob responds
+        let mut stores: Stores = (
+            [(1, 1)].into_iter().collect(),
+            [(1, 1)].into_iter().collect(),
+        );
+        let mut sessions = Sessions::default();
+        let initial = sessions.0.initial_message(&mut stores.0).unwrap();
+        assert!(sessions
+            .1
+            .process(&mut stores.1, initial)
+            .unwrap()
+            .is_none());
+        let err = sessions
+            .0
+            .replace_store(&mut stores.0, replacement.clone(), false)
+            .unwrap_err();
+        assert!(matches!(err, ProtocolError::InFlight));
+    }
+
+    #[test]
+    fn test_session_version() {
+        /// Runs a sync with messages sent as envelopes, and returns the negotiated versions.
+        fn run(
+            alice_session: &mut SyncSession<(u32, u8)>,
+            bob_session: &mut SyncSession<(u32, u8)>,
+        ) -> (Option<u32>, Option<u32>) {
+            let mut alice: MemoryStore<_> =
+                (0..256u32).filter(|i| i % 3 != 0).map(|i| (i, 1)).collect();
+            let mut bob: MemoryStore<_> =
+                (0..256u32).filter(|i| i % 7 != 0).map(|i| (i, 1)).collect();
+            let initial = alice_session.initial_message(&mut alice).unwrap();
+            let mut next = Some(alice_session.encode_message(&initial).unwrap());
+            let mut sessions = [(bob_session, &mut bob), (alice_session, &mut alice)];
+            let mut rounds = 0;
+            while let Some(bytes) = next.take() {
+                let (session, store) = &mut sessions[rounds % 2];
+                let message = session.decode_message(&bytes).unwrap();
+                next = session
+                    .process(*store, message)
+                    .unwrap()
+                    .map(|reply| session.encode_message(&reply).unwrap());
+                rounds += 1;
+            }
+            let [(bob_session, bob), (alice_session, alice)] = sessions;
+            assert_eq!(collect(alice.all().unwrap()), collect(bob.all().unwrap()));
+            (alice_session.version(), bob_session.version())
+        }
+
+        let v1 = || SyncSession::default().with_max_version(1);
+        let v2 = || SyncSession::default().with_max_version(2);
+        assert_eq!(run(&mut v2(), &mut v2()), (Some(2), Some(2)));
+        assert_eq!(run(&mut v1(), &mut v2()), (Some(1), Some(1)));
+        let (mut alice_session, mut bob_session) = (v2(), v1());
+        assert_eq!(
+            run(&mut alice_session, &mut bob_session),
+            (Some(1), Some(1))
+        );
+
+        // newer part kinds are not sent in a v1 session
+        let reopen = Message {
+            parts: vec![MessagePart::Reopen],
+            more: false,
+        };
+        let err = alice_session.encode_message(&reopen).unwrap_err();
+        assert!(matches!(
+            err,
+            EnvelopeError::Protocol(ProtocolError::UnsupportedVersion {
+                version: 2,
+                supported: 1
+            })
+        ));
+
+        // messages of other versions are rejected before they are decoded
+        let envelope = Envelope {
+            version: 3,
+            payload: vec![0xff; 8],
+        };
+        let bytes = postcard::to_stdvec(&envelope).unwrap();
+        let err = bob_session.decode_message(&bytes).unwrap_err();
+        assert!(matches!(
+            err,
+            EnvelopeError::Protocol(ProtocolError::UnsupportedVersion {
+                version: 3,
+                supported: 1
+            })
+        ));
+        let mut session: SyncSession<(u32, u8)> = v2();
+        session
+            .initial_message(&mut MemoryStore::default())
+            .unwrap();
+        let err = session.decode_message(&bytes).unwrap_err();
+        assert!(matches!(
+            err,
+            EnvelopeError::Protocol(ProtocolError::UnsupportedVersion { version: 3, .. })
+        ));
+        assert_eq!(session.version(), None);
+
+        // a payload that is not a message is an encoding error
+        let envelope = Envelope {
+            version: 2,
+            payload: vec![0x01, 0x7f],
+        };
+        let bytes = postcard::to_stdvec(&envelope).unwrap();
+        let err = v2().decode_message(&bytes).unwrap_err();
+        assert!(matches!(err, EnvelopeError::Encoding(_)));
+    }
+
+    #[test]
+    fn test_session_done() {
+        /// Runs a sync with messages sent as envelopes, and returns the number of `Done` parts
+        /// sent by alice and bob.
+        fn run(
+            alice_session: &mut SyncSession<(u32, u8)>,
+            bob_session: &mut SyncSession<(u32, u8)>,
+            bob_filter: fn(&u32) -> bool,
+        ) -> [usize; 2] {
+            let mut alice: MemoryStore<_> =
+                (0..256u32).filter(|i| i % 3 != 0).map(|i| (i, 1)).collect();
+            let mut bob: MemoryStore<_> = (0..256u32).filter(bob_filter).map(|i| (i, 1)).collect();
+            let initial = alice_session.initial_message(&mut alice).unwrap();
+            let mut next = Some(alice_session.encode_message(&initial).unwrap());
+            let mut sessions = [(bob_session, &mut bob), (alice_session, &mut alice)];
+            let mut done = [0, 0];
+            let mut rounds = 0;
+            while let Some(bytes) = next.take() {
+                let (session, store) = &mut sessions[rounds % 2];
+                let message = session.decode_message(&bytes).unwrap();
+                if message.is_done() {
+                    done[(rounds + 1) % 2] += 1;
+                }
+                // a session that is done does not send anything
+                assert!(!session.is_done());
+                next = session
+                    .process(*store, message)
+                    .unwrap()
+                    .map(|reply| session.encode_message(&reply).unwrap());
+                rounds += 1;
+            }
+            let [(_, bob), (_, alice)] = sessions;
+            assert_eq!(collect(alice.all().unwrap()), collect(bob.all().unwrap()));
+            // indexed by sender, alice first
+            [done[1], done[0]]
+        }
+
+        let (mut alice_session, mut bob_session) = (SyncSession::default(), SyncSession::default());
+        let done = run(&mut alice_session, &mut bob_session, |i| i % 7 != 0);
+        assert_eq!(done, [1, 1]);
+        assert!(alice_session.is_done());
+        assert!(bob_session.is_done());
+
+        // bob is done right away if the stores match
+        let (mut alice_session, mut bob_session) = (SyncSession::default(), SyncSession::default());
+        let done = run(&mut alice_session, &mut bob_session, |i| i % 3 != 0);
+        assert_eq!(done, [1, 1]);
+        assert!(alice_session.is_done());
+        assert!(bob_session.is_done());
+
+        // sessions that negotiate an older version end without `Done`
+        let (mut alice_session, mut bob_session) = (
+            SyncSession::default(),
+            SyncSession::default().with_max_version(2),
+        );
+        let done = run(&mut alice_session, &mut bob_session, |i| i % 7 != 0);
+        assert_eq!(done, [0, 0]);
+        assert!(!alice_session.is_done());
+        assert!(!bob_session.is_done());
+        assert_eq!(alice_session.version(), Some(2));
+
+        // `Done` is not sent without envelopes, as no version is negotiated
+        let mut alice: MemoryStore<_> = (0..64u32).map(|i| (i, 1)).collect();
+        let mut bob: MemoryStore<_> = (32..96u32).map(|i| (i, 1)).collect();
+        let mut alice_session = SyncSession::default();
+        let mut bob_session = SyncSession::default();
+        let mut next = Some(alice_session.initial_message(&mut alice).unwrap());
+        let mut rounds = 0;
+        while let Some(message) = next.take() {
+            assert!(!message.is_done());
+            next = match rounds % 2 {
+                0 => bob_session.process(&mut bob, message).unwrap(),
+                _ => alice_session.process(&mut alice, message).unwrap(),
+            };
+            rounds += 1;
+        }
+        assert!(!alice_session.is_done());
+        assert!(!bob_session.is_done());
+    }
+
+    #[test]
+    fn test_session_events() {
+        let mut alice: MemoryStore<_> =
+            (0..256u32).filter(|i| i % 3 != 0).map(|i| (i, 2)).collect();
+        let mut bob: MemoryStore<_> = (0..256u32).filter(|i| i % 5 != 0).map(|i| (i, 1)).collect();
+        // alice rejects the entries bob has for keys that are multiples of 7
+        let mut alice_session =
+            SyncSession::default().with_validator(|entry: &(u32, u8), _| entry.0 % 7 != 0);
+        let mut bob_session = SyncSession::default();
+        let mut alice_events = alice_session.subscribe();
+        let mut bob_events = bob_session.subscribe();
+
+        let initial = alice_session.initial_message(&mut alice).unwrap();
+        let mut next = Some(alice_session.encode_message(&initial).unwrap());
+        let mut sessions = [
+            (&mut bob_session, &mut bob),
+            (&mut alice_session, &mut alice),
+        ];
+        let mut rounds = 0;
+        while let Some(bytes) = next.take() {
+            let (session, store) = &mut sessions[rounds % 2];
+            let message = session.decode_message(&bytes).unwrap();
+            next = session
+                .process(*store, message)
+                .unwrap()
+                .map(|reply| session.encode_message(&reply).unwrap());
+            rounds += 1;
+        }
+        assert!(alice_session.is_done());
+        assert!(bob_session.is_done());
+
+        for (session, store, events) in [
+            (&mut alice_session, &mut alice, &mut alice_events),
+            (&mut bob_session, &mut bob, &mut bob_events),
+        ] {
+            let mut received = Vec::new();
+            while let Ok(event) = events.try_recv() {
+                received.push(event);
+            }
+            let stats = session.stats().clone();
+            assert_eq!(
+                received.last(),
+                Some(&SyncEvent::SessionFinished(stats.clone()))
+            );
+            let mut inserted = 0;
+            let mut rejected = 0;
+            let mut settled = 0;
+            for event in &received[..received.len() - 1] {
+                match event {
+                    SyncEvent::EntryInserted(entry) => {
+                        assert_eq!(store.get(&entry.0).unwrap().as_ref(), Some(entry));
+                        inserted += 1;
+                    }
+                    SyncEvent::EntryRejected { key, reason } => {
+                        assert_eq!(*reason, RejectReason::Validation);
+                        assert_eq!(key % 7, 0);
+                        rejected += 1;
+                    }
+                    SyncEvent::RangeSettled(_) => settled += 1,
+                    SyncEvent::SessionFinished(_) => panic!("finished twice"),
+                }
+            }
+            assert_eq!(inserted, stats.entries_inserted + stats.entries_overwritten);
+            assert_eq!(rejected, stats.entries_rejected);
+            assert_eq!(settled, session.progress(store).unwrap().settled_ranges);
+        }
+        assert!(alice_session.stats().entries_rejected > 0);
+        assert!(bob_session.stats().entries_inserted > 0);
+
+        // a subscriber that falls behind loses the oldest events
+        let mut alice: MemoryStore<_> = (0..64u32).map(|i| (i, 1)).collect();
+        let mut bob = MemoryStore::default();
+        let mut bob_session = SyncSession::default().with_event_capacity(4);
+        let mut events = bob_session.subscribe();
+        let mut alice_session = SyncSession::default();
+        let mut next = Some(alice_session.initial_message(&mut alice).unwrap());
+        let mut rounds = 0;
+        while let Some(message) = next.take() {
+            next = match rounds % 2 {
+                0 => bob_session.process(&mut bob, message).unwrap(),
+                _ => alice_session.process(&mut alice, message).unwrap(),
+            };
+            rounds += 1;
+        }
+        assert_eq!(bob.len().unwrap(), 64);
+        assert!(matches!(
+            events.try_recv(),
+            Err(tokio::sync::broadcast::error::TryRecvError::Lagged(_))
+        ));
+    }
+
+    #[test]
+    fn test_session_lazy_values() {
+        type Entry = (u32, Vec<u8>);
+        const SMALL: usize = 1024;
+        const LARGE: usize = 16 * 1024;
+
+        /// Syncs alice's entries to bob with messages sent as envelopes, and returns the bytes
+        /// sent and the keys whose values bob is missing.
+        fn run(
+            alice: &mut MemoryStore<Entry>,
+            bob: &mut MemoryStore<Entry>,
+            bob_session: &mut SyncSession<Entry>,
+            lazy: bool,
+        ) -> (usize, Vec<u32>) {
+            let missing_keys = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+            let mut alice_session = SyncSession::default();
+            if lazy {
+                let missing_keys = missing_keys.clone();
+                alice_session = alice_session.with_lazy_values(|_, _| panic!("nothing missing"));
+                *bob_session = SyncSession::default()
+                    .with_lazy_values(move |key, _| missing_keys.lock().unwrap().push(*key));
+            }
+            let initial = alice_session.initial_message(alice).unwrap();
+            let mut next = Some(alice_session.encode_message(&initial).unwrap());
+            let mut sessions = [(bob_session, bob), (&mut alice_session, alice)];
+            let mut rounds = 0;
+            let mut bytes_sent = 0;
+            while let Some(bytes) = next.take() {
+                bytes_sent += bytes.len();
+                let (session, store) = &mut sessions[rounds % 2];
+                let message = session.decode_message(&bytes).unwrap();
+                next = session
+                    .process(*store, message)
+                    .unwrap()
+                    .map(|reply| session.encode_message(&reply).unwrap());
+                rounds += 1;
+            }
+            let missing_keys = missing_keys.lock().unwrap().clone();
+            (bytes_sent, missing_keys)
+        }
+
+        // bob lacks `missing` of alice's 256 entries with values of `len` bytes, spread evenly
+        let stores = |len: usize, missing: u32| {
+            let alice: MemoryStore<Entry> = (0..256u32).map(|i| (i, vec![1; len])).collect();
+            let bob: MemoryStore<Entry> = (0..256u32)
+                .filter(|i| i % (256 / missing) != 0)
+                .map(|i| (i, vec![1; len]))
+                .collect();
+            (alice, bob)
+        };
+        let sync = |len, missing, lazy| {
+            let (mut alice, mut bob) = stores(len, missing);
+            let mut bob_session = SyncSession::default();
+            run(&mut alice, &mut bob, &mut bob_session, lazy)
+        };
+
+        // the bytes sent in lazy mode depend on the number of missing keys, not on the size of
+        // the values
+        let (full_small, _) = sync(SMALL, 8, false);
+        let (full_large, _) = sync(LARGE, 8, false);
+        let (lazy_small, mut missing) = sync(SMALL, 8, true);
+        let (lazy_large, _) = sync(LARGE, 8, true);
+        let (lazy_more, more_missing) = sync(LARGE, 32, true);
+        missing.sort();
+        assert_eq!(missing, (0..256).step_by(32).collect::<Vec<_>>());
+        assert_eq!(more_missing.len(), 32);
+        assert!(full_small > 8 * SMALL, "{full_small}");
+        assert!(full_large > 8 * LARGE, "{full_large}");
+        assert_eq!(lazy_small, lazy_large);
+        assert!(lazy_large * 10 < full_large, "{lazy_large} vs {full_large}");
+        assert!(lazy_more > lazy_large);
+        assert!(lazy_more < 4 * lazy_large, "{lazy_more} vs {lazy_large}");
+
+        // the values are pulled afterwards, within the same session
+        let (mut alice, mut bob) = stores(LARGE, 8);
+        let mut bob_session = SyncSession::default();
+        let (_, missing) = run(&mut alice, &mut bob, &mut bob_session, true);
+        assert_eq!(bob.all().unwrap().count(), 248);
+        let mut alice_session = SyncSession::default();
+        let request = bob_session.value_request_message(missing.clone());
+        let response = alice_session.process(&mut alice, request).unwrap().unwrap();
+        assert_eq!(response.values().count(), missing.len());
+        assert!(bob_session.process(&mut bob, response).unwrap().is_none());
+        assert_eq!(collect(alice.all().unwrap()), collect(bob.all().unwrap()));
+        assert_eq!(bob_session.stats().entries_received, missing.len());
+
+        // sessions without lazy values request the missing values right away
+        let (mut alice, mut bob) = stores(SMALL, 8);
+        let mut alice_session = SyncSession::default().with_lazy_values(|_, _| ());
+        let mut bob_session = SyncSession::default();
+        let mut next = Some(alice_session.initial_message(&mut alice).unwrap());
+        let mut rounds = 0;
+        let mut requests = 0;
+        while let Some(message) = next.take() {
+            requests += message
+                .parts()
+                .iter()
+                .filter(|part| matches!(part, MessagePart::ValueRequest(_)))
+                .count();
+            next = match rounds % 2 {
+                0 => bob_session.process(&mut bob, message).unwrap(),
+                _ => alice_session.process(&mut alice, message).unwrap(),
+            };
+            rounds += 1;
+        }
+        assert_eq!(requests, 1);
+        assert_eq!(collect(alice.all().unwrap()), collect(bob.all().unwrap()));
+    }
+
+    #[test]
+    fn test_download_policy() {
+        use std::sync::{Arc, Mutex};
+
+        type Entry = (u32, Vec<u8>);
+        type Keys = Arc<Mutex<Vec<u32>>>;
+
+        /// Inlines small values, defers medium ones, and skips large ones.
+        fn policy(entry: &Entry) -> DownloadDecision {
+            match entry.1.len() {
+                0..=4 => DownloadDecision::Inline,
+                5..=64 => DownloadDecision::Defer,
+                _ => DownloadDecision::Skip,
+            }
+        }
+
+        /// Syncs alice's entries to bob, and returns the keys passed to the defer callback.
+        fn run(
+            alice: &mut MemoryStore<Entry>,
+            bob: &mut MemoryStore<Entry>,
+            bob_session: SyncSession<Entry>,
+        ) -> (SyncSession<Entry>, Vec<u32>) {
+            let deferred = Keys::default();
+            let mut bob_session = bob_session.with_download_policy(policy, {
+                let deferred = deferred.clone();
+                move |key, _| deferred.lock().unwrap().push(*key)
+            });
+            let mut alice_session = SyncSession::default();
+            let mut next = Some(alice_session.initial_message(alice).unwrap());
+            let mut rounds = 0;
+            while let Some(message) = next.take() {
+                next = match rounds % 2 {
+                    0 => bob_session.process(bob, message).unwrap(),
+                    _ => alice_session.process(alice, message).unwrap(),
+                };
+                rounds += 1;
+            }
+            let deferred = deferred.lock().unwrap().clone();
+            (bob_session, deferred)
+        }
+
+        let len = |i: u32| [2, 32, 256][i as usize % 3];
+        let mut alice: MemoryStore<Entry> = (0..30u32).map(|i| (i, vec![1; len(i)])).collect();
+        let mut bob = MemoryStore::default();
+        let (bob_session, deferred) = run(&mut alice, &mut bob, SyncSession::default());
+
+        // only the inline entries are stored
+        let inline: Vec<u32> = (0..30).filter(|i| i % 3 == 0).collect();
+        let keys: Vec<u32> = collect(bob.all().unwrap()).iter().map(|e| e.0).collect();
+        assert_eq!(keys, inline);
+        // the deferred entries are passed to the callback and recorded
+        let medium: Vec<u32> = (0..30).filter(|i| i % 3 == 1).collect();
+        assert_eq!(deferred, medium);
+        let deferred_downloads = bob_session.deferred_downloads().clone();
+        assert_eq!(
+            deferred_downloads.keys().copied().collect::<Vec<_>>(),
+            medium
+        );
+        for (key, fingerprint) in &deferred_downloads {
+            let entry = alice.get(key).unwrap().unwrap();
+            assert_eq!(entry.as_fingerprint(), *fingerprint);
+        }
+        // the skipped entries are rejected
+        assert_eq!(bob_session.stats().entries_rejected, 10);
+        assert_eq!(bob_session.stats().entries_inserted, 10);
+
+        // entries deferred before are neither deferred again nor requested with lazy values
+        let missing = Keys::default();
+        let bob_session = SyncSession::default()
+            .with_deferred_downloads(deferred_downloads)
+            .with_lazy_values({
+                let missing = missing.clone();
+                move |key, _| missing.lock().unwrap().push(*key)
+            });
+        let (mut bob_session, deferred) = run(&mut alice, &mut bob, bob_session);
+        assert!(deferred.is_empty());
+        let large: Vec<u32> = (0..30).filter(|i| i % 3 == 2).collect();
+        let mut missing = missing.lock().unwrap().clone();
+        missing.sort();
+        assert_eq!(missing, large);
+        assert_eq!(bob_session.deferred_downloads().len(), 10);
+
+        // a deferred entry is no longer deferred once it is written
+        let reply = bob_session.value_request_message(vec![1]);
+        let response = SyncSession::default()
+            .process(&mut alice, reply)
+            .unwrap()
+            .unwrap();
+        let mut bob_session = SyncSession::default()
+            .with_deferred_downloads(bob_session.deferred_downloads().clone());
+        bob_session.process(&mut bob, response).unwrap();
+        assert_eq!(bob.get(&1).unwrap(), Some((1, vec![1; 32])));
+        assert_eq!(bob_session.deferred_downloads().len(), 9);
+    }
+
+    #[test]
+    fn test_session_want_keys() {
+        let mut alice: MemoryStore<(u32, u8)> = (0..50u32).map(|i| (i, 1)).collect();
+        let keys = vec![10, 20, 60, 30, 70];
+
+        // the entries alice has are sent, subject to bob's validator, the others are listed
+        let mut bob = MemoryStore::default();
+        let mut bob_session =
+            SyncSession::default().with_validator(|entry: &(u32, u8), _| entry.0 != 20);
+        let request = bob_session.request_keys(keys.clone());
+        let response = SyncSession::default()
+            .process(&mut alice, request)
+            .unwrap()
+            .unwrap();
+        assert_eq!(response.values().count(), 3);
+        assert!(bob_session.process(&mut bob, response).unwrap().is_none());
+        assert_eq!(collect(bob.all().unwrap()), vec![(10, 1), (30, 1)]);
+        assert_eq!(bob_session.missing_keys(), &[60, 70]);
+        assert_eq!(bob_session.stats().entries_rejected, 1);
+
+        // the entries are split to fit into messages of the byte budget
+        let mut alice_session = SyncSession::default().with_max_message_bytes(32);
+        let request = SyncSession::default().request_keys((0..50).collect());
+        let first = alice_session.process(&mut alice, request).unwrap();
+        let messages: Vec<_> = first
+            .into_iter()
+            .chain(std::iter::from_fn(|| alice_session.poll_pending_message()))
+            .collect();
+        assert!(messages.len() > 1);
+        assert!(messages.iter().all(|message| message.encoded_size() <= 32));
+        let mut bob = MemoryStore::default();
+        let mut bob_session = SyncSession::default();
+        for message in messages {
+            bob_session.process(&mut bob, message).unwrap();
+        }
+        assert_eq!(collect(bob.all().unwrap()), collect(alice.all().unwrap()));
+        assert!(bob_session.missing_keys().is_empty());
+
+        // a receive-only session has no entries to offer
+        let config = SyncConfig::default().with_direction(SyncDirection::ReceiveOnly);
+        let request = SyncSession::default().request_keys(keys.clone());
+        let response = SyncSession::new(config)
+            .process(&mut alice, request)
+            .unwrap()
+            .unwrap();
+        assert_eq!(response.values().count(), 0);
+        assert!(response
+            .parts()
+            .contains(&MessagePart::MissingKeys { keys: keys.clone() }));
+
+        // remotes that do not support the request reject it
+        let mut bob_session: SyncSession<(u32, u8)> = SyncSession::default();
+        let request = bob_session.request_keys(keys);
+        let bytes = bob_session.encode_message(&request).unwrap();
+        let mut alice_session: SyncSession<(u32, u8)> = SyncSession::default().with_max_version(5);
+        let err = alice_session.decode_message(&bytes).unwrap_err();
+        assert!(matches!(
+            err,
+            EnvelopeError::Protocol(ProtocolError::UnsupportedVersion {
+                version: 6,
+                supported: 5
+            })
+        ));
+    }
+
+    #[test]
+    fn test_session_store_summary() {
+        /// Syncs alice's and bob's stores with messages sent as envelopes, and returns the
+        /// sessions.
+        fn run(
+            mut alice_session: SyncSession<(u32, u8)>,
+            bob_session: SyncSession<(u32, u8)>,
+            alice: &mut MemoryStore<(u32, u8)>,
+            bob: &mut MemoryStore<(u32, u8)>,
+            initial: Option<Message<(u32, u8)>>,
+        ) -> [SyncSession<(u32, u8)>; 2] {
+            let initial = match initial {
+                Some(initial) => initial,
+                None => alice_session.initial_message(alice).unwrap(),
+            };
+            let mut next = Some(alice_session.encode_message(&initial).unwrap());
+            let mut sessions = [(bob_session, bob), (alice_session, alice)];
+            let mut rounds = 0;
+            while let Some(bytes) = next.take() {
+                let (session, store) = &mut sessions[rounds % 2];
+                let message = session.decode_message(&bytes).unwrap();
+                next = session
+                    .process(*store, message)
+                    .unwrap()
+                    .map(|reply| session.encode_message(&reply).unwrap());
+                rounds += 1;
+            }
+            let [(bob_session, _), (alice_session, _)] = sessions;
+            [alice_session, bob_session]
+        }
+
+        let stores = || {
+            let alice: MemoryStore<_> = (10..100u32).map(|i| (i, 1)).collect();
+            let bob: MemoryStore<_> = (50..200u32).map(|i| (i, 2)).collect();
+            (alice, bob)
+        };
+
+        // both sides see the summaries of the stores before the sync
+        let (mut alice, mut bob) = stores();
+        let alice_summary = alice.store_summary().unwrap();
+        let bob_summary = bob.store_summary().unwrap();
+        assert_eq!(alice_summary.entry_count, 90);
+        assert_eq!(
+            (alice_summary.first_key, alice_summary.last_key),
+            (Some(10), Some(99))
+        );
+        assert_eq!(bob_summary.entry_count, 150);
+        let [alice_session, bob_session] = run(
+            SyncSession::default().with_store_summary(),
+            SyncSession::default(),
+            &mut alice,
+            &mut bob,
+            None,
+        );
+        assert_eq!(alice_session.local_summary(), Some(&alice_summary));
+        assert_eq!(alice_session.remote_summary(), Some(&bob_summary));
+        assert_eq!(bob_session.local_summary(), Some(&bob_summary));
+        assert_eq!(bob_session.remote_summary(), Some(&alice_summary));
+        assert_eq!(collect(alice.all().unwrap()), collect(bob.all().unwrap()));
+
+        // without summaries, or with a remote that does not support them, the sync works as
+        // before
+        for (alice_session, bob_session) in [
+            (SyncSession::default(), SyncSession::default()),
+            (
+                SyncSession::default()
+                    .with_max_version(6)
+                    .with_store_summary(),
+                SyncSession::default()
+                    .with_max_version(6)
+                    .with_store_summary(),
+            ),
+        ] {
+            let (mut alice, mut bob) = stores();
+            let [alice_session, bob_session] =
+                run(alice_session, bob_session, &mut alice, &mut bob, None);
+            assert_eq!(alice_session.remote_summary(), None);
+            assert_eq!(bob_session.remote_summary(), None);
+            assert_eq!(collect(alice.all().unwrap()), collect(bob.all().unwrap()));
+        }
+
+        // a wrong summary does not affect the sync
+        let (mut alice, mut bob) = stores();
+        let mut alice_session = SyncSession::default();
+        let mut initial = alice_session.initial_message(&mut alice).unwrap();
+        let wrong = StoreSummary {
+            entry_count: 0,
+            first_key: None,
+            last_key: None,
+            full_fingerprint: Fingerprint::empty(),
+        };
+        initial.parts.push(MessagePart::StoreSummary(wrong.clone()));
+        let [_, bob_session] = run(
+            alice_session,
+            SyncSession::default(),
+            &mut alice,
+            &mut bob,
+            Some(initial),
+        );
+        assert_eq!(bob_session.remote_summary(), Some(&wrong));
+        assert_eq!(collect(alice.all().unwrap()), collect(bob.all().unwrap()));
+    }
+
+    #[cfg(feature = "ed25519")]
+    #[test]
+    fn test_signed_entries() {
+        use ed25519_dalek::SigningKey;
+
+        type Entry = SignedEntry<(Vec<u8>, u8), ed25519_dalek::Signature>;
+
+        let alice_key = SigningKey::from_bytes(&[1; 32]);
+        let bob_key = SigningKey::from_bytes(&[2; 32]);
+        let entry = |author: &SigningKey, name: &str, value: u8| {
+            let mut key = author.verifying_key().to_bytes().to_vec();
+            key.extend_from_slice(name.as_bytes());
+            (key, value)
+        };
+        let sign = |author: &SigningKey, name: &str, value: u8| {
+            Ed25519Verifier::sign(entry(author, name, value), author).unwrap()
+        };
+
+        // the fingerprint covers the signature
+        let signed = sign(&alice_key, "ape", 1);
+        let resigned = SignedEntry::new(signed.entry.clone(), sign(&alice_key, "bee", 1).signature);
+        assert_ne!(signed.as_fingerprint(), resigned.as_fingerprint());
+        assert!(signed.verify::<Ed25519Verifier>());
+        assert!(!resigned.verify::<Ed25519Verifier>());
+
+        // each store holds valid entries, an entry of the other author signed with the wrong
+        // key, and an entry that was changed after it was signed
+        let mut tampered = sign(&alice_key, "fox", 1);
+        tampered.entry.1 = 2;
+        let mut alice: MemoryStore<Entry> = [
+            sign(&alice_key, "ape", 1),
+            sign(&alice_key, "cat", 1),
+            Ed25519Verifier::sign(entry(&bob_key, "eel", 1), &alice_key).unwrap(),
+            tampered,
+        ]
+        .into_iter()
+        .collect();
+        let mut tampered = sign(&bob_key, "hog", 1);
+        tampered.entry.1 = 2;
+        let mut bob: MemoryStore<Entry> = [
+            sign(&bob_key, "bee", 1),
+            sign(&bob_key, "doe", 1),
+            Ed25519Verifier::sign(entry(&alice_key, "gnu", 1), &bob_key).unwrap(),
+            tampered,
+        ]
+        .into_iter()
+        .collect();
+        let valid = |store: &mut MemoryStore<Entry>| -> Vec<Entry> {
+            collect(store.all().unwrap())
+                .into_iter()
+                .filter(|entry| entry.verify::<Ed25519Verifier>())
+                .collect()
+        };
+        let mut expected = valid(&mut alice);
+        expected.extend(valid(&mut bob));
+        expected.sort_by(|a, b| a.key().cmp(b.key()));
+
+        let session = || {
+            SyncSession::default().with_validator(validators::verify_signed::<Ed25519Verifier, _>())
+        };
+        let mut sessions = [(session(), &mut bob), (session(), &mut alice)];
+        let mut next = Some(sessions[1].0.initial_message(sessions[1].1).unwrap());
+        let mut rounds = 0;
+        while let Some(message) = next.take() {
+            let (session, store) = &mut sessions[rounds % 2];
+            next = session.process(*store, message).unwrap();
+            rounds += 1;
+        }
+        for (session, store) in &mut sessions {
+            assert_eq!(session.stats().entries_rejected, 2);
+            assert_eq!(valid(store), expected);
+            assert_eq!(store.len().unwrap(), expected.len() + 2);
+        }
+    }
+
+    #[test]
+    fn test_tombstones() {
+        type Entry = Tombstoned<(&'static str, i32)>;
+
+        let store = |set: Set| -> MemoryStore<Entry> {
+            set.iter().copied().map(Tombstoned::entry).collect()
+        };
+        let sync = |alice: MemoryStore<Entry>, bob: MemoryStore<Entry>| {
+            let res = sync_exchange_messages(alice, bob, |_, _, _| true, |_, _, _| true, 100);
+            (res.alice, res.bob)
+        };
+        let live = |store: &mut MemoryStore<Entry>| -> Vec<_> {
+            collect(store.all().unwrap())
+                .into_iter()
+                .filter_map(Tombstoned::into_entry)
+                .collect()
+        };
+        let set: Set = &[("ape", 1), ("bee", 1), ("cat", 1)];
+        let (mut alice, bob) = sync(store(set), store(set));
+
+        // the deletion propagates, and the entry is not resurrected by either side
+        alice.delete("bee", 2).unwrap();
+        assert_eq!(alice.get_live(&"bee").unwrap(), None);
+        let (alice, mut bob) = sync(alice, bob);
+        assert_eq!(bob.get_live(&"bee").unwrap(), None);
+        let (bob, alice) = sync(bob, alice);
+        let (mut alice, mut bob) = sync(alice, bob);
+        let expected = vec![("ape", 1), ("cat", 1)];
+        assert_eq!(live(&mut alice), expected);
+        assert_eq!(live(&mut bob), expected);
+
+        // a remote that missed the deletion does not resurrect the entry either
+        let (mut carol, mut bob) = sync(store(set), bob);
+        assert_eq!(live(&mut carol), expected);
+        assert_eq!(live(&mut bob), expected);
+        assert!(bob.get(&"bee").unwrap().unwrap().is_tombstone());
+
+        // an older deletion does not replace a newer entry, and a newer entry resurrects the key
+        assert!(matches!(
+            bob.delete("ape", 0).unwrap(),
+            InsertOutcome::NotInserted
+        ));
+        bob.put(Tombstoned::entry(("bee", 3))).unwrap();
+        let (mut alice, mut bob) = sync(alice, bob);
+        let expected = vec![("ape", 1), ("bee", 3), ("cat", 1)];
+        assert_eq!(live(&mut alice), expected);
+        assert_eq!(live(&mut bob), expected);
+
+        // only older tombstones are purged
+        carol.delete("ape", 4).unwrap();
+        carol.delete("cat", 5).unwrap();
+        assert_eq!(carol.purge_tombstones(&5).unwrap(), 2);
+        assert_eq!(carol.get(&"ape").unwrap(), None);
+        assert_eq!(carol.get(&"bee").unwrap(), None);
+        assert!(carol.get(&"cat").unwrap().unwrap().is_tombstone());
+
+        // tombstones survive the wire
+        for entry in [
+            Tombstoned::tombstone("cat".to_string(), 5),
+            Tombstoned::prefix_tombstone("cat".to_string(), 5),
+            Tombstoned::entry(("cat".to_string(), 5)),
+        ] {
+            let bytes = postcard::to_stdvec(&entry).unwrap();
+            assert_eq!(
+                postcard::from_bytes::<Tombstoned<_>>(&bytes).unwrap(),
+                entry
+            );
+        }
+    }
+
+    #[test]
+    fn test_prefix_tombstones() {
+        type Entry = Tombstoned<(&'static str, i32)>;
+
+        let store = |set: Set| -> MemoryStore<Entry> {
+            set.iter().copied().map(Tombstoned::entry).collect()
+        };
+        let sync = |alice: MemoryStore<Entry>, bob: MemoryStore<Entry>| {
+            let res = sync_exchange_messages(alice, bob, |_, _, _| true, |_, _, _| true, 100);
+            (res.alice, res.bob)
+        };
+        let live = |store: &mut MemoryStore<Entry>| -> Vec<_> {
+            collect(store.all().unwrap())
+                .into_iter()
+                .filter_map(Tombstoned::into_entry)
+                .collect()
+        };
+        let set: Set = &[("/old/ape", 1), ("/old/bee", 1), ("/new/cat", 1)];
+        let (mut alice, mut bob) = sync(store(set), store(set));
+
+        // alice deletes the subtree, while bob writes an entry to it before and one after that
+        assert!(matches!(
+            alice.delete_prefix("/old/", 3).unwrap(),
+            InsertOutcome::Inserted { removed: 2 }
+        ));
+        bob.put(Tombstoned::entry(("/old/doe", 2))).unwrap();
+        bob.put(Tombstoned::entry(("/old/eel", 4))).unwrap();
+        let (mut alice, mut bob) = sync(alice, bob);
+        let expected = vec![("/new/cat", 1), ("/old/eel", 4)];
+        assert_eq!(live(&mut alice), expected);
+        assert_eq!(live(&mut bob), expected);
+        assert_eq!(collect(alice.all().unwrap()), collect(bob.all().unwrap()));
+        assert_eq!(
+            alice.get(&"/old/").unwrap().map(|entry| entry.kind()),
+            Some(TombstoneKind::PrefixTombstone)
+        );
+
+        // further syncs in both directions, and with a remote that missed the deletion, do not
+        // resurrect the deleted entries
+        let (bob, alice) = sync(bob, alice);
+        let (mut alice, mut carol) = sync(alice, store(set));
+        assert_eq!(live(&mut alice), expected);
+        assert_eq!(live(&mut carol), expected);
+        let (mut bob, mut alice) = sync(bob, alice);
+        assert_eq!(collect(alice.all().unwrap()), collect(bob.all().unwrap()));
+
+        // older entries below the prefix are not inserted, while a tombstone of a single key
+        // does not delete the keys it is a prefix of
+        assert!(matches!(
+            bob.put(Tombstoned::entry(("/old/fox", 3))).unwrap(),
+            InsertOutcome::NotInserted
+        ));
+        bob.delete("/new/", 5).unwrap();
+        assert_eq!(bob.get_live(&"/new/cat").unwrap(), Some(("/new/cat", 1)));
+        bob.delete_prefix("/new/", 5).unwrap();
+        assert_eq!(bob.get_live(&"/new/cat").unwrap(), None);
+        let (mut alice, mut bob) = sync(alice, bob);
+        let expected = vec![("/old/eel", 4)];
+        assert_eq!(live(&mut alice), expected);
+        assert_eq!(live(&mut bob), expected);
+        assert_eq!(collect(alice.all().unwrap()), collect(bob.all().unwrap()));
+    }
+
+    #[test]
+    fn test_newer_wins() {
+        type Entry = TimestampedEntry<(&'static str, i32)>;
+
+        let entry =
+            |key, value, timestamp| -> Entry { TimestampedEntry::new((key, value), timestamp) };
+        // alice wrote ape last, bob wrote bee last, and both wrote cat at the same time
+        let alice_set = [
+            entry("ape", 1, 5),
+            entry("bee", 1, 1),
+            entry("cat", 2, 3),
+            entry("doe", 1, 1),
+        ];
+        let bob_set = [
+            entry("ape", 2, 2),
+            entry("bee", 2, 4),
+            entry("cat", 1, 3),
+            entry("eel", 1, 1),
+        ];
+        let expected = vec![
+            entry("ape", 1, 5),
+            entry("bee", 2, 4),
+            entry("cat", 2, 3),
+            entry("doe", 1, 1),
+            entry("eel", 1, 1),
+        ];
+        for policy in [
+            EchoPolicy::IfNewer,
+            EchoPolicy::IfDifferent,
+            EchoPolicy::Never,
+        ] {
+            let config = SyncConfig::default().with_echo_policy(policy);
+            let mut alice: MemoryStore<Entry> = alice_set.iter().cloned().collect();
+            let mut bob: MemoryStore<Entry> = bob_set.iter().cloned().collect();
+            let mut next = Some(alice.initial_message().unwrap());
+            let stores = [&mut bob, &mut alice];
+            let mut rejected = 0;
+            let mut rounds = 0;
+            while let Some(msg) = next.take() {
+                rounds += 1;
+                assert!(rounds < 10, "{policy:?}: too many rounds");
+                let outcome = stores[(rounds + 1) % 2]
+                    .process_message(
+                        &config,
+                        msg,
+                        validators::newer_wins(),
+                        |_, _, _| (),
+                        |_, _| ContentStatus::Complete,
+                    )
+                    .unwrap();
+                rejected += outcome.rejected.count;
+                next = outcome.reply;
+            }
+            // the older entries were rejected, and both sides converge in the same session
+            assert!(rejected > 0, "{policy:?}");
+            assert_eq!(collect(alice.all().unwrap()), expected, "{policy:?}");
+            assert_eq!(collect(bob.all().unwrap()), expected, "{policy:?}");
+        }
+
+        // the timestamp is part of the fingerprint and the encoding
+        assert_ne!(
+            entry("ape", 1, 5).as_fingerprint(),
+            entry("ape", 1, 6).as_fingerprint()
+        );
+        let entry = TimestampedEntry::new(("ape".to_string(), 1), 5);
+        let bytes = postcard::to_stdvec(&entry).unwrap();
+        assert_eq!(
+            postcard::from_bytes::<TimestampedEntry<_>>(&bytes).unwrap(),
+            entry
+        );
+    }
+
+    #[test]
+    fn test_newer_wins_wrapped() {
+        type Entry = TimestampedEntry<(&'static str, i32)>;
+
+        /// Syncs the stores with [`validators::newer_wins`] and returns the entries of both.
+        fn run<A, B>(mut alice: A, mut bob: B) -> (Vec<Entry>, Vec<Entry>)
+        where
+            A: Store<Entry> + validators::EntryLookup<Entry>,
+            B: Store<Entry> + validators::EntryLookup<Entry>,
+        {
+            let config = SyncConfig::default();
+            let mut next = Some(alice.initial_message().unwrap());
+            let mut rounds = 0;
+            while let Some(msg) = next.take() {
+                rounds += 1;
+                assert!(rounds < 10, "too many rounds");
+                next = if rounds % 2 == 1 {
+                    bob.process_message(
+                        &config,
+                        msg,
+                        validators::newer_wins(),
+                        |_, _, _| (),
+                        |_, _| ContentStatus::Complete,
+                    )
+                    .unwrap()
+                    .reply
+                } else {
+                    alice
+                        .process_message(
+                            &config,
+                            msg,
+                            validators::newer_wins(),
+                            |_, _, _| (),
+                            |_, _| ContentStatus::Complete,
+                        )
+                        .unwrap()
+                        .reply
+                };
+            }
+            (collect(alice.all().unwrap()), collect(bob.all().unwrap()))
+        }
+
+        let entry =
+            |key, value, timestamp| -> Entry { TimestampedEntry::new((key, value), timestamp) };
+        let alice_set = [entry("ape", 1, 5), entry("bee", 1, 1), entry("cat", 1, 1)];
+        let bob_set = [entry("ape", 2, 2), entry("bee", 2, 4), entry("doe", 1, 1)];
+        let expected = vec![
+            entry("ape", 1, 5),
+            entry("bee", 2, 4),
+            entry("cat", 1, 1),
+            entry("doe", 1, 1),
+        ];
+        let memory = |set: &[Entry]| set.iter().cloned().collect::<MemoryStore<Entry>>();
+
+        let alice = InstrumentedStore::new(EpochStore::new(memory(&alice_set)));
+        let bob = FilteredStore::new(memory(&bob_set), |_: &Entry| true);
+        assert_eq!(run(alice, bob), (expected.clone(), expected.clone()));
+
+        let alice = OverlayStore::new(memory(&alice_set), MemoryStore::new());
+        let bob = ShardedStore::new(
+            vec!["c"],
+            vec![memory(&bob_set[..2]), memory(&bob_set[2..])],
+        );
+        assert_eq!(run(alice, bob), (expected.clone(), expected.clone()));
+
+        let mut alice = memory(&alice_set);
+        let bob = MirroredStore::new(
+            BoundedStore::new(memory(&bob_set)).unwrap(),
+            MemoryStore::new(),
+            SecondaryErrorPolicy::Propagate,
+        );
+        assert_eq!(run(&mut alice, bob), (expected.clone(), expected));
+    }
+
+    #[test]
+    fn test_session_pages() {
+        let alice_set: Vec<_> = (0..512u32).filter(|i| i % 5 != 0).map(|i| (i, 1)).collect();
+        let bob_set: Vec<_> = (0..512u32).filter(|i| i % 7 != 0).map(|i| (i, 1)).collect();
+
+        /// Runs a sync in which the first response with at least five parts is sent in three
+        /// pages if `paginate` is set, and returns the final stores and the number of rounds.
+        #[allow(clippy::type_complexity)]
+        fn run(
+            alice_set: &[(u32, u8)],
+            bob_set: &[(u32, u8)],
+            paginate: bool,
+        ) -> (Vec<(u32, u8)>, Vec<(u32, u8)>, usize) {
+            let mut alice: MemoryStore<_> = alice_set.iter().copied().collect();
+            let mut bob: MemoryStore<_> = bob_set.iter().copied().collect();
+            let mut alice_session = SyncSession::default();
+            let mut bob_session = SyncSession::default();
+            let mut next = Some(alice_session.initial_message(&mut alice).unwrap());
+            let mut rounds = 0;
+            let mut paginated = false;
+            while let Some(message) = next.take() {
+                let (session, store) = match rounds % 2 {
+                    0 => (&mut bob_session, &mut bob),
+                    _ => (&mut alice_session, &mut alice),
+                };
+                let len = message.parts().len();
+                if !paginate || paginated || len < 5 {
+                    next = session.process(store, message).unwrap();
+                } else {
+                    paginated = true;
+                    let pages = message.paginate(len.div_ceil(3));
+                    assert_eq!(pages.len(), 3);
+                    assert!(pages[..2].iter().all(Message::has_more));
+                    assert!(!pages[2].has_more());
+                    assert_eq!(
+                        pages.iter().map(|page| page.parts().len()).sum::<usize>(),
+                        len
+                    );
+                    for page in pages {
+                        assert!(next.is_none());
+                        next = session.process(store, page).unwrap();
+                    }
+                }
+                rounds += 1;
+            }
+            assert_eq!(paginated, paginate);
+            (
+                collect(alice.all().unwrap()),
+                collect(bob.all().unwrap()),
+                rounds,
+            )
+        }
+
+        let expected = run(&alice_set, &bob_set, false);
+        assert_eq!(expected.0, expected.1);
+        assert_eq!(run(&alice_set, &bob_set, true), expected);
+
+        // sessions paginate their responses, and the flag survives the wire encoding
+        let mut alice: MemoryStore<_> = alice_set.iter().copied().collect();
+        let mut bob: MemoryStore<_> = bob_set.iter().copied().collect();
+        let mut alice_session = SyncSession::default().with_page_size(2);
+        let mut bob_session = SyncSession::default().with_page_size(2);
+        let initial = alice_session.initial_message(&mut alice).unwrap();
+        let mut messages = VecDeque::from([alice_session.encode_message(&initial).unwrap()]);
+        let mut rounds = 0;
+        let mut pages = 0;
+        while !messages.is_empty() {
+            let (session, store) = match rounds % 2 {
+                0 => (&mut bob_session, &mut bob),
+                _ => (&mut alice_session, &mut alice),
+            };
+            let mut replies = VecDeque::new();
+            for bytes in messages {
+                let message = session.decode_message(&bytes).unwrap();
+                assert!(message.parts().len() <= 2);
+                pages += usize::from(message.has_more());
+                let mut reply = session.process(store, message).unwrap();
+                while let Some(message) = reply {
+                    replies.push_back(session.encode_message(&message).unwrap());
+                    reply = session.poll_pending_message();
+                }
+            }
+            messages = replies;
+            rounds += 1;
+        }
+        assert!(pages > 0);
+        // one response per round, plus the final `Done`, which needs a negotiated version
+        assert_eq!(rounds, expected.2 + 1);
+        assert_eq!(collect(alice.all().unwrap()), expected.0);
+        assert_eq!(collect(bob.all().unwrap()), expected.0);
+
+        let mut alice: MemoryStore<_> = alice_set.iter().copied().collect();
+        let mut bob: MemoryStore<_> = bob_set.iter().copied().collect();
+        let options = SyncOptions::default().with_page_size(3);
+        sync_stores(&mut alice, &mut bob, options).unwrap();
+        assert_eq!(collect(alice.all().unwrap()), expected.0);
+        assert_eq!(collect(bob.all().unwrap()), expected.0);
+
+        // the flag needs version 5
+        let session: SyncSession<(u32, u8)> = SyncSession::default().with_max_version(4);
+        let message = Message::cancel().with_more(true);
+        let err = session.encode_message(&message).unwrap_err();
+        assert!(matches!(
+            err,
+            EnvelopeError::Protocol(ProtocolError::UnsupportedVersion {
+                version: 5,
+                supported: 4
+            })
+        ));
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_session_compression() {
+        /// Runs a sync with messages sent as envelopes, and returns the bytes sent.
+        fn run(
+            mut alice_session: SyncSession<(String, u8)>,
+            mut bob_session: SyncSession<(String, u8)>,
+        ) -> usize {
+            let entry = |i: u32| {
+                (
+                    format!(
+                        "{{\"key\": \"{i:08}\", \"padding\": \"{}\"}}",
+                        "ab".repeat(64)
+                    ),
+                    1,
+                )
+            };
+            let mut alice: MemoryStore<_> = (0..256u32).map(entry).collect();
+            let mut bob: MemoryStore<_> = (256..300u32).map(entry).collect();
+            let initial = alice_session.initial_message(&mut alice).unwrap();
+            let mut next = Some(alice_session.encode_message(&initial).unwrap());
+            let mut sessions = [
+                (&mut bob_session, &mut bob),
+                (&mut alice_session, &mut alice),
+            ];
+            let mut rounds = 0;
+            let mut bytes_sent = 0;
+            while let Some(bytes) = next.take() {
+                bytes_sent += bytes.len();
+                let (session, store) = &mut sessions[rounds % 2];
+                let message = session.decode_message(&bytes).unwrap();
+                assert!(!message
+                    .parts()
+                    .iter()
+                    .any(|part| matches!(part, MessagePart::CompressedRangeItem(_))));
+                next = session
+                    .process(*store, message)
+                    .unwrap()
+                    .map(|reply| session.encode_message(&reply).unwrap());
+                rounds += 1;
+            }
+            let [(_, bob), (_, alice)] = sessions;
+            assert_eq!(alice.all().unwrap().count(), 300);
+            assert_eq!(collect(alice.all().unwrap()), collect(bob.all().unwrap()));
+            bytes_sent
+        }
+
+        let plain = run(SyncSession::default(), SyncSession::default());
+        let compressed = run(
+            SyncSession::default().with_compression(256),
+            SyncSession::default().with_compression(256),
+        );
+        assert!(compressed * 2 < plain, "{compressed} vs {plain}");
+        // compression is not used if the remote does not support it
+        let downgraded = run(
+            SyncSession::default().with_compression(256),
+            SyncSession::default().with_max_version(8),
+        );
+        assert_eq!(downgraded, plain);
+
+        // compressed parts need version 9
+        let mut session: SyncSession<(String, u8)> = SyncSession::default().with_max_version(8);
+        let part = MessagePart::CompressedRangeItem(CompressedRangeItem {
+            range: Range::new(String::new(), String::new()),
+            compressed: Vec::new(),
+            codec: Codec::Zstd,
+            have_local: true,
+        });
+        let message: Message<(String, u8)> = Message {
+            parts: vec![part],
+            more: false,
+        };
+        let envelope = Envelope {
+            version: 8,
+            payload: postcard::to_stdvec(&message).unwrap(),
+        };
+        let err = session
+            .decode_message(&postcard::to_stdvec(&envelope).unwrap())
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            EnvelopeError::Protocol(ProtocolError::UnsupportedVersion {
+                version: 9,
+                supported: 8
+            })
+        ));
+    }
+
+    #[test]
+    fn test_process_message_unexpanded_parts() {
+        let config = SyncConfig::default();
+        let mut store: MemoryStore<(u32, i32)> = MemoryStore::from_iter([(1, 1), (2, 2)]);
+        let lazy = MessagePart::LazyRangeItem(LazyRangeItem {
+            range: Range::new(0, 0),
+            values: vec![(1, (1, 1).as_fingerprint())],
+            have_local: false,
+        });
+        let chunk = MessagePart::ValueChunk {
+            key: 3,
+            index: 0,
+            total: 1,
+            bytes: vec![0],
+        };
+        for part in [lazy, chunk] {
+            let message = Message {
+                parts: vec![MessagePart::Done, part],
+                more: false,
+            };
+            let err = store
+                .process_message(
+                    &config,
+                    message.clone(),
+                    |_, _, _| true,
+                    |_, _, _| (),
+                    |_, _| ContentStatus::Complete,
+                )
+                .unwrap_err();
+            assert!(matches!(
+                err,
+                ProcessError::InvalidMessage(MessageValidationError::UnexpandedPart { part: 1 })
+            ));
+            let mut summary = DiffSummary::default();
+            let err = store
+                .process_message_dry_run(&config, message, &mut summary)
+                .unwrap_err();
+            assert!(matches!(
+                err,
+                ProcessError::InvalidMessage(MessageValidationError::UnexpandedPart { part: 1 })
+            ));
+        }
+        assert_eq!(collect(store.all().unwrap()), vec![(1, 1), (2, 2)]);
+    }
+
+    #[test]
+    fn test_session_delta_keys() {
+        type Entry = (String, u8);
+
+        let paths: Vec<Entry> = (0..200u32)
+            .map(|i| {
+                let dir = ["src/ranger", "src/store/fs", "tests/fixtures/wire"][i as usize % 3];
+                (
+                    format!("/home/alice/projects/iroh/iroh-docs/{dir}/file_{i:04}.rs"),
+                    1,
+                )
+            })
+            .collect();
+        let build = |values: &[Entry]| {
+            let values = values
+                .iter()
+                .map(|entry| (entry.clone(), ContentStatus::Complete))
+                .collect();
+            MessageBuilder::new()
+                .add_items(Range::new(String::new(), String::new()), values, true)
+                .build()
+        };
+        let delta = || SyncSession::<Entry>::default().with_delta_keys();
+
+        // keys with long common prefixes shrink, and are reassembled byte for byte
+        let mut sorted = paths.clone();
+        sorted.sort();
+        let message = build(&sorted);
+        let plain = SyncSession::default().encode_message(&message).unwrap();
+        let encoded = delta().encode_message(&message).unwrap();
+        assert!(
+            encoded.len() * 3 < plain.len(),
+            "{} vs {}",
+            encoded.len(),
+            plain.len()
+        );
+        assert_eq!(delta().decode_message(&encoded).unwrap(), message);
+        let envelope: Envelope = postcard::from_bytes(&encoded).unwrap();
+        let parts = Message::<Entry>::decode(&envelope.payload).unwrap().parts;
+        assert!(matches!(parts[..], [MessagePart::DeltaRangeItem(_)]));
+
+        // keys in any order are reassembled, and keys without common prefixes are sent as is
+        for values in [
+            &paths[..],
+            &[("ape".to_string(), 1), ("bee".to_string(), 2)],
+        ] {
+            let message = build(values);
+            let encoded = delta().encode_message(&message).unwrap();
+            assert_eq!(delta().decode_message(&encoded).unwrap(), message);
+        }
+        let message = build(&[("ape".to_string(), 1), ("bee".to_string(), 2)]);
+        assert_eq!(
+            delta().encode_message(&message).unwrap(),
+            SyncSession::default().encode_message(&message).unwrap()
+        );
+
+        // delta keys are not sent to remotes that do not support them, and not decoded by
+        // sessions that did not enable them
+        let message = build(&sorted);
+        let downgraded = delta()
+            .with_max_version(7)
+            .encode_message(&message)
+            .unwrap();
+        let plain = SyncSession::default()
+            .with_max_version(7)
+            .encode_message(&message)
+            .unwrap();
+        assert_eq!(downgraded, plain);
+        let err = SyncSession::<Entry>::default()
+            .decode_message(&encoded)
+            .unwrap_err();
+        assert!(matches!(err, EnvelopeError::DeltaKeysDisabled));
+
+        // a sync with delta keys
+        let mut alice: MemoryStore<Entry> = paths.iter().step_by(2).cloned().collect();
+        let mut bob: MemoryStore<Entry> = paths.iter().skip(1).step_by(2).cloned().collect();
+        let mut sessions = [(delta(), &mut bob), (delta(), &mut alice)];
+        let initial = sessions[1].0.initial_message(sessions[1].1).unwrap();
+        let mut next = Some(sessions[1].0.encode_message(&initial).unwrap());
+        let mut rounds = 0;
+        while let Some(bytes) = next.take() {
+            let (session, store) = &mut sessions[rounds % 2];
+            let message = session.decode_message(&bytes).unwrap();
+            next = session
+                .process(*store, message)
+                .unwrap()
+                .map(|reply| session.encode_message(&reply).unwrap());
+            rounds += 1;
+        }
+        assert_eq!(alice.len().unwrap(), paths.len());
+        assert_eq!(collect(alice.all().unwrap()), collect(bob.all().unwrap()));
+    }
+
+    #[test]
+    fn test_session_roles() {
+        let (alice_set, bob_set) = paper_sets()[0];
+        let mut alice: MemoryStore<_> = alice_set.iter().copied().collect();
+        let mut bob: MemoryStore<_> = bob_set.iter().copied().collect();
+        let process = |session: &mut SyncSession<_>, store: &mut MemoryStore<_>, msg| {
+            session.process(store, msg)
+        };
+
+        // both sides open the sync at the same time
+        let mut alice_session = SyncSession::default();
+        let mut bob_session = SyncSession::default();
+        assert_eq!(alice_session.role(), None);
+        let from_alice = alice_session.initial_message(&mut alice).unwrap();
+        let from_bob = bob_session.handshake_message(&mut bob).unwrap();
+        assert_eq!(alice_session.role(), Some(Role::Initiator));
+        let err = process(&mut alice_session, &mut alice, from_bob).unwrap_err();
+        assert!(matches!(
+            err,
+            SyncError::Protocol(ProtocolError::UnexpectedInitialMessage)
+        ));
+        let err = process(&mut bob_session, &mut bob, from_alice.clone()).unwrap_err();
+        assert!(matches!(
+            err,
+            SyncError::Protocol(ProtocolError::UnexpectedInitialMessage)
+        ));
+        assert_eq!(collect(alice.all().unwrap()), alice_set);
+        assert_eq!(collect(bob.all().unwrap()), bob_set);
+
+        // the responder cannot open the sync
+        let mut bob_session = SyncSession::default();
+        let reply = process(&mut bob_session, &mut bob, from_alice)
+            .unwrap()
+            .unwrap();
+        assert_eq!(bob_session.role(), Some(Role::Responder));
+        let err = bob_session.initial_message(&mut bob).unwrap_err();
+        assert!(matches!(
+            err,
+            SyncError::Protocol(ProtocolError::NotInitiator)
+        ));
+
+        // a response that is delivered twice is rejected
+        let mut alice_session = SyncSession::default();
+        alice_session.initial_message(&mut alice).unwrap();
+        let mut last_reply = reply;
+        loop {
+            let next = process(&mut alice_session, &mut alice, last_reply.clone())
+                .unwrap()
+                .and_then(|msg| process(&mut bob_session, &mut bob, msg).unwrap());
+            match next {
+                Some(reply) => last_reply = reply,
+                None => break,
+            }
+        }
+        let settled = alice_session.progress(&mut alice).unwrap().settled_ranges;
+        assert!(settled > 0);
+        let err = process(&mut alice_session, &mut alice, last_reply).unwrap_err();
+        assert!(matches!(
+            err,
+            SyncError::Protocol(ProtocolError::SettledRange)
+        ));
+    }
+
+    #[test]
+    fn test_sync_error_kinds() {
+        let (alice_set, bob_set) = paper_sets()[0];
+        let mut alice: MemoryStore<_> = alice_set.iter().copied().collect();
+        let bob: MemoryStore<_> = bob_set.iter().copied().collect();
+        let initial = SyncSession::default().initial_message(&mut alice).unwrap();
+        let protocol_error = |err: SyncError<Infallible>| {
+            assert!(err.is_protocol() && !err.is_store(), "{err:?}");
+            err.as_protocol().cloned().unwrap()
+        };
+        // an initiator that receives an initial message
+        let mut session = SyncSession::default();
+        session.initial_message(&mut bob.clone()).unwrap();
+        let err = session
+            .process(&mut bob.clone(), initial.clone())
+            .unwrap_err();
+        assert_eq!(protocol_error(err), ProtocolError::UnexpectedInitialMessage);
+
+        // a message of another session
+        let mut session = SyncSession::default().with_session_id(SessionId::random());
+        let err = session
+            .process(&mut bob.clone(), initial.clone())
+            .unwrap_err();
+        assert!(matches!(
+            protocol_error(err),
+            ProtocolError::SessionMismatch { received: None, .. }
+        ));
+
+        // a limit of the session
+        let limits = ProtocolLimits {
+            max_rounds: 0,
+            ..Default::default()
+        };
+        let mut session = SyncSession::default().with_limits(limits);
+        let err = session
+            .process(&mut bob.clone(), initial.clone())
+            .unwrap_err();
+        assert_eq!(protocol_error(err), Limit::Rounds(0).into());
+
+        // a malformed message
+        let range = Range::new("a", "z");
+        let duplicate = MessageBuilder::new()
+            .add_items(
+                range,
+                vec![
+                    (("c", 1), ContentStatus::Complete),
+                    (("c", 2), ContentStatus::Complete),
+                ],
+                false,
+            )
+            .build();
+        let mut session = SyncSession::default();
+        let err = session.process(&mut bob.clone(), duplicate).unwrap_err();
+        assert!(matches!(
+            protocol_error(err),
+            ProtocolError::InvalidMessage(MessageValidationError::DuplicateKey { part: 0, .. })
+        ));
+
+        // an error of the store is not a protocol error
+        let mut failing = FailingStore {
+            store: bob.clone(),
+            fail: Rc::new(std::cell::Cell::new(true)),
+        };
+        let items = MessageBuilder::new()
+            .add_items(range, vec![(("c", 1), ContentStatus::Complete)], true)
+            .build();
+        let err = SyncSession::default()
+            .process(&mut failing, items)
+            .unwrap_err();
+        assert!(err.is_store() && !err.is_protocol() && err.as_protocol().is_none());
+        let err = err.map_store(|err| err.to_string());
+        assert_eq!(err.into_store_err().unwrap(), "injected failure");
+        let err: SyncError<String> = Limit::Rounds(0).into();
+        assert!(err.into_store_err().unwrap_err().is_protocol());
+    }
+    #[test]
+    fn test_session_budget() {
+        type Session = SyncSession<(&'static str, i32)>;
+        type Store = MemoryStore<(&'static str, i32)>;
+        let process =
+            |session: &mut Session, store: &mut Store, msg| session.process(store, msg).unwrap();
+        // concatenates the responses to all parts of `msg`
+        let process_with_budget = |session: &mut Session, store: &mut Store, msg, budget| {
+            let validate = |_: &_, _: &_, _| true;
+            let status = |_: &_, _: &_| ContentStatus::Complete;
+            let (mut response, mut continuation) = session
+                .process_message_with_budget(store, msg, budget, validate, |_, _, _| (), status)
+                .unwrap();
+            let mut parts: Vec<_> = response.iter().flat_map(|m| m.parts().to_vec()).collect();
+            while let Some(next) = continuation.take() {
+                let remaining = next.remaining_parts();
+                (response, continuation) = session
+                    .continue_message(store, next, budget, validate, |_, _, _| (), status)
+                    .unwrap();
+                assert!(continuation.as_ref().map_or(0, |c| c.remaining_parts()) < remaining);
+                parts.extend(response.iter().flat_map(|m| m.parts().to_vec()));
+            }
+            (!parts.is_empty()).then_some(Message { parts, more: false })
+        };
+
+        for (alice_set, bob_set) in paper_sets() {
+            let mut alice: Store = alice_set.iter().copied().collect();
+            let mut bob: Store = bob_set.iter().copied().collect();
+            let mut expected = (alice.clone(), bob.clone());
+            let mut sessions = (Session::default(), Session::default());
+            let mut expected_sessions = (Session::default(), Session::default());
+
+            // alice stops after each part, bob once the deadline passed
+            let mut next = Some(sessions.0.initial_message(&mut alice).unwrap());
+            let mut expected_next = Some(
+                expected_sessions
+                    .0
+                    .initial_message(&mut expected.0)
+                    .unwrap(),
+            );
+            let mut rounds = 0;
+            while let Some(msg) = next.take() {
+                let expected_msg = expected_next.take().unwrap();
+                assert_eq!(msg, expected_msg);
+                let (session, store, expected_session, expected_store, budget) = match rounds % 2 {
+                    0 => (
+                        &mut sessions.1,
+                        &mut bob,
+                        &mut expected_sessions.1,
+                        &mut expected.1,
+                        Budget::Deadline(Instant::now()),
+                    ),
+                    _ => (
+                        &mut sessions.0,
+                        &mut alice,
+                        &mut expected_sessions.0,
+                        &mut expected.0,
+                        Budget::Parts(1),
+                    ),
+                };
+                next = process_with_budget(session, store, msg, budget);
+                expected_next = process(expected_session, expected_store, expected_msg);
+                rounds += 1;
+            }
+            assert!(expected_next.is_none());
+            assert!(rounds > 2);
+            assert_eq!((alice, bob), expected);
+        }
+    }
+
+    #[test]
+    fn test_session_step() {
+        type Session = SyncSession<(&'static str, i32)>;
+        type Store = MemoryStore<(&'static str, i32)>;
+        // steps through all queued messages, and returns the last response
+        let step = |session: &mut Session, store: &mut Store, msg, continued: &mut usize| {
+            session.enqueue(msg);
+            let mut processed = 0;
+            loop {
+                match session.step(store).unwrap() {
+                    ControlFlow::Continue(progress) => {
+                        processed += 1;
+                        assert_eq!(progress.processed, processed);
+                        assert!(progress.remaining > 0);
+                        *continued += 1;
+                    }
+                    ControlFlow::Break(response) => return response,
+                }
+            }
+        };
+
+        for (alice_set, bob_set) in paper_sets() {
+            let mut alice: Store = alice_set.iter().copied().collect();
+            let mut bob: Store = bob_set.iter().copied().collect();
+            let mut expected = (alice.clone(), bob.clone());
+            let mut sessions = (Session::default(), Session::default());
+            let mut expected_sessions = (Session::default(), Session::default());
+
+            let mut next = Some(sessions.0.initial_message(&mut alice).unwrap());
+            let mut expected_next = Some(
+                expected_sessions
+                    .0
+                    .initial_message(&mut expected.0)
+                    .unwrap(),
+            );
+            let mut rounds = 0;
+            let mut continued = 0;
+            while let Some(msg) = next.take() {
+                let expected_msg = expected_next.take().unwrap();
+                assert_eq!(msg, expected_msg);
+                let (session, store, expected_session, expected_store) = match rounds % 2 {
+                    0 => (
+                        &mut sessions.1,
+                        &mut bob,
+                        &mut expected_sessions.1,
+                        &mut expected.1,
+                    ),
+                    _ => (
+                        &mut sessions.0,
+                        &mut alice,
+                        &mut expected_sessions.0,
+                        &mut expected.0,
+                    ),
+                };
+                next = step(session, store, msg, &mut continued);
+                expected_next = expected_session
+                    .process(expected_store, expected_msg)
+                    .unwrap();
+                rounds += 1;
+            }
+            assert!(expected_next.is_none());
+            assert!(continued > 0);
+            // nothing is left to process
+            assert_eq!(
+                sessions.0.step(&mut alice).unwrap(),
+                ControlFlow::Break(None)
+            );
+            assert_eq!((alice, bob), expected);
+        }
+    }
+
+    #[test]
+    fn test_session_transform() {
+        let (alice_set, bob_set) = paper_sets()[0];
+        let sync = |alice_session: &mut SyncSession<_>, alice: &mut MemoryStore<_>| {
+            let mut bob: MemoryStore<_> = bob_set.iter().copied().collect();
+            let mut bob_session = SyncSession::default();
+            let mut from_alice = Vec::new();
+            let mut next = Some(alice_session.initial_message(alice)?);
+            while let Some(msg) = next.take() {
+                from_alice.push(msg.clone());
+                let validate = |_: &_, _: &_, _| true;
+                let status = |_: &_, _: &_| ContentStatus::Complete;
+                let reply = bob_session
+                    .process_message(&mut bob, msg, validate, |_, _, _| (), status)
+                    .unwrap();
+                if let Some(reply) = reply {
+                    next = alice_session.process_message(
+                        alice,
+                        reply,
+                        validate,
+                        |_, _, _| (),
+                        status,
+                    )?;
+                }
+            }
+            Ok::<_, SyncError<Infallible>>((bob, from_alice))
+        };
+        let mut expected: MemoryStore<_> = alice_set.iter().copied().collect();
+        let (_, expected_from_alice) = sync(&mut SyncSession::default(), &mut expected).unwrap();
+
+        // annotate the values of received entries, and drop one
+        let mut alice: MemoryStore<_> = alice_set.iter().copied().collect();
+        let mut session = SyncSession::default().with_transform(|(key, value)| match key {
+            "cat" => None,
+            _ => Some((key, value + 100)),
+        });
+        let (bob, from_alice) = sync(&mut session, &mut alice).unwrap();
+        assert_eq!(
+            collect(alice.all().unwrap()),
+            [
+                ("ape", 1),
+                ("bee", 101),
+                ("doe", 101),
+                ("eel", 1),
+                ("fox", 1),
+                ("gnu", 1),
+                ("hog", 101)
+            ]
+        );
+        // the entries sent to bob are computed from the received values
+        assert_eq!(from_alice, expected_from_alice);
+        assert_eq!(bob, expected);
+        assert_eq!(session.stats().entries_inserted, 3);
+
+        // changing the key is an error
+        let mut alice: MemoryStore<_> = alice_set.iter().copied().collect();
+        let mut session = SyncSession::default().with_transform(|(key, value)| match key {
+            "doe" => Some(("dog", value)),
+            _ => Some((key, value)),
+        });
+        let err = sync(&mut session, &mut alice).unwrap_err();
+        let SyncError::Transform(err) = err else {
+            panic!("unexpected error {err:?}");
+        };
+        assert_eq!(err.key, "\"doe\"");
+        assert_eq!(err.transformed_key, "\"dog\"");
+        assert!(alice.get(&"dog").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_session_validator() {
+        let (alice_set, bob_set) = paper_sets()[0];
+        let bob: MemoryStore<_> = bob_set.iter().copied().collect();
+        let initial_alice: MemoryStore<_> = alice_set.iter().copied().collect();
+
+        // alice's stored validator rejects "bee", an explicit validator rejects "cat" instead
+        for explicit in [false, true] {
+            let mut alice = initial_alice.clone();
+            let mut bob = bob.clone();
+            let mut alice_session =
+                SyncSession::default().with_validator(|(key, _), _| *key != "bee");
+            let mut bob_session = SyncSession::default();
+            let mut next = Some(alice_session.initial_message(&mut alice).unwrap());
+            while let Some(msg) = next.take() {
+                let Some(reply) = bob_session.process(&mut bob, msg).unwrap() else {
+                    break;
+                };
+                next = match explicit {
+                    false => alice_session.process(&mut alice, reply),
+                    true => alice_session.process_message(
+                        &mut alice,
+                        reply,
+                        |_, (key, _), _| *key != "cat",
+                        |_, _, _| (),
+                        |_, _| ContentStatus::Complete,
+                    ),
+                }
+                .unwrap();
+            }
+            let rejected = if explicit { "cat" } else { "bee" };
+            let expected: Vec<_> = alice_set
+                .iter()
+                .chain(bob_set)
+                .copied()
+                .filter(|(key, _)| *key != rejected)
+                .collect::<BTreeSet<_>>()
+                .into_iter()
+                .collect();
+            assert_eq!(collect(alice.all().unwrap()), expected);
+            assert_eq!(alice_session.stats().entries_rejected, 1);
+        }
+    }
+
+    #[test]
+    fn test_put_many() {
+        let entries = || (0..2_000u32).map(|i| (i, 1u8));
+        let mut imported = MemoryStore::default();
+        let mut progress = Vec::new();
+        let report = imported
+            .put_many_with_progress(entries(), 200, |report| progress.push(*report))
+            .unwrap();
+        assert_eq!(
+            report,
+            ImportReport {
+                inserted: 2_000,
+                ..Default::default()
+            }
+        );
+        assert_eq!(progress.len(), 10);
+        assert_eq!(progress[0].processed(), 200);
+        assert_eq!(progress[9], report);
+
+        // newer entries replace existing ones, older entries are ignored
+        let more = [(0, 2), (1, 0), (2_000, 1)];
+        let report = imported.put_many(more).unwrap();
+        assert_eq!(
+            report,
+            ImportReport {
+                inserted: 1,
+                replaced: 1,
+                ignored: 1,
+                removed: 0,
+            }
+        );
+
+        // the result is the same as with single puts, and so is a sync with a remote
+        let mut single = MemoryStore::default();
+        for entry in entries().chain(more) {
+            single.put(entry).unwrap();
+        }
+        assert_eq!(
+            collect(imported.all().unwrap()),
+            collect(single.all().unwrap())
+        );
+        let remote = || -> MemoryStore<_> { (1_000..3_000u32).map(|i| (i, 3u8)).collect() };
+        let (mut remote1, mut remote2) = (remote(), remote());
+        let report1 = sync_stores(&mut imported, &mut remote1, SyncOptions::default()).unwrap();
+        let report2 = sync_stores(&mut single, &mut remote2, SyncOptions::default()).unwrap();
+        assert_eq!(report1, report2);
+        assert_eq!(
+            collect(imported.all().unwrap()),
+            collect(single.all().unwrap())
+        );
+        assert_eq!(
+            collect(remote1.all().unwrap()),
+            collect(remote2.all().unwrap())
+        );
+    }
+
+    #[test]
+    fn test_sync_stores() {
+        for (alice_set, bob_set) in paper_sets() {
+            let mut alice: MemoryStore<_> = alice_set.iter().copied().collect();
+            let mut bob: MemoryStore<_> = bob_set.iter().copied().collect();
+            let expected: BTreeSet<_> = alice_set.iter().chain(bob_set).copied().collect();
+            let report = sync_stores(&mut alice, &mut bob, SyncOptions::default()).unwrap();
+            assert_eq!(collect(alice.all().unwrap()), collect(bob.all().unwrap()));
+            assert_eq!(report.a_inserted, expected.len() - alice_set.len());
+            assert_eq!(report.b_inserted, expected.len() - bob_set.len());
+            assert!(report.rounds > 1);
+        }
+
+        // each side validates the entries it receives
+        let (alice_set, bob_set) = paper_sets()[0];
+        let mut alice: MemoryStore<_> = alice_set.iter().copied().collect();
+        let mut bob: MemoryStore<_> = bob_set.iter().copied().collect();
+        let options = SyncOptions::default()
+            .with_validator_a(|(key, _), _| *key != "bee")
+            .with_validator_b(|(key, _), _| *key != "ape");
+        let report = sync_stores(&mut alice, &mut bob, options).unwrap();
+        assert_eq!(report.a_inserted, 3);
+        assert_eq!(report.b_inserted, 1);
+        assert!(alice.get(&"bee").unwrap().is_none());
+        assert!(bob.get(&"ape").unwrap().is_none());
+
+        // the side that fails is reported
+        let mut alice: MemoryStore<_> = alice_set.iter().copied().collect();
+        let mut bob: MemoryStore<_> = bob_set.iter().copied().collect();
+        let limits = ProtocolLimits {
+            max_rounds: 1,
+            ..Default::default()
+        };
+        let options = SyncOptions::default().with_limits(limits);
+        let err = sync_stores(&mut alice, &mut bob, options).unwrap_err();
+        assert!(matches!(
+            err,
+            SyncStoresError::B(SyncError::Protocol(ProtocolError::LimitExceeded(
+                Limit::Rounds(1)
+            )))
+        ));
+        let mut alice = FailingStore {
+            store: alice,
+            fail: Rc::new(std::cell::Cell::new(true)),
+        };
+        let err = sync_stores(&mut alice, &mut bob, SyncOptions::default()).unwrap_err();
+        assert!(matches!(err, SyncStoresError::A(SyncError::Store(_))));
+    }
+
+    #[proptest]
+    fn test_diff_stores(
+        #[strategy(prop::collection::btree_map("[a-d]{3}", 0u8..4, 0..40))] a_set: BTreeMap<
+            String,
+            u8,
+        >,
+        #[strategy(prop::collection::btree_map("[a-d]{3}", 0u8..4, 0..40))] b_set: BTreeMap<
+            String,
+            u8,
+        >,
+    ) {
+        // keys of equal length, so no entries are removed by prefix deletion
+        let mut a: MemoryStore<_> = a_set.clone().into_iter().collect();
+        let mut b: MemoryStore<_> = b_set.clone().into_iter().collect();
+        let diff = diff_stores(&mut a, &mut b).unwrap();
+
+        let mut expected = StoreDiff::default();
+        for (key, value) in &a_set {
+            match b_set.get(key) {
+                None => expected.only_a.push(key.clone()),
+                Some(other) if other != value => expected.different.push(key.clone()),
+                Some(_) => {}
+            }
+        }
+        expected.only_b = b_set
+            .keys()
+            .filter(|key| !a_set.contains_key(*key))
+            .cloned()
+            .collect();
+        prop_assert_eq!(&diff, &expected);
+        prop_assert_eq!(diff.is_empty(), a_set == b_set);
+
+        // neither store is changed, and the diff is symmetric
+        prop_assert_eq!(
+            collect(a.all().unwrap()),
+            a_set.clone().into_iter().collect::<Vec<_>>()
+        );
+        prop_assert_eq!(
+            collect(b.all().unwrap()),
+            b_set.clone().into_iter().collect::<Vec<_>>()
+        );
+        let reversed = diff_stores(&mut b, &mut a).unwrap();
+        prop_assert_eq!(reversed.only_a, expected.only_b);
+        prop_assert_eq!(reversed.only_b, expected.only_a);
+        prop_assert_eq!(reversed.different, expected.different);
+    }
+
+    #[test]
+    fn test_reconcile_group() {
+        type Entry = (u32, u64);
+        // three remotes with overlapping ranges of keys, and a local store with a few of them
+        let ranges = [0..48u32, 16..64, 32..80];
+        let mut remotes: Vec<(MemoryStore<Entry>, SyncSession<Entry>)> = ranges
+            .iter()
+            .map(|keys| {
+                let store = keys.clone().map(|i| (i, i as u64 * 7)).collect();
+                (store, SyncSession::default().with_lazy_values(|_, _| ()))
+            })
+            .collect();
+        let local: MemoryStore<_> = (0..80u32).step_by(10).map(|i| (i, i as u64 * 7)).collect();
+        let mut group = ReconcileGroup::new(local, SyncConfig::default());
+        let ids: Vec<_> = ranges.iter().map(|_| group.add_remote()).collect();
+
+        // the sessions run interleaved, one message per remote and turn
+        let mut to_remote: Vec<VecDeque<_>> = ids
+            .iter()
+            .map(|id| VecDeque::from([group.initial_message(*id).unwrap()]))
+            .collect();
+        let mut to_local: Vec<VecDeque<_>> = ids.iter().map(|_| VecDeque::new()).collect();
+        while to_remote
+            .iter()
+            .chain(&to_local)
+            .any(|queue| !queue.is_empty())
+        {
+            for (i, id) in ids.iter().enumerate() {
+                if let Some(message) = to_remote[i].pop_front() {
+                    let (store, session) = &mut remotes[i];
+                    to_local[i].extend(session.process(store, message).unwrap());
+                }
+                if let Some(message) = to_local[i].pop_front() {
+                    to_remote[i].extend(group.process(*id, message).unwrap());
+                }
+                while let Some(message) = group.poll_message(*id) {
+                    to_remote[i].push_back(message);
+                }
+            }
+        }
+
+        let expected: Vec<_> = (0..80u32).map(|i| (i, i as u64 * 7)).collect();
+        assert_eq!(collect(group.store_mut().all().unwrap()), expected);
+        assert_eq!(group.in_flight().count(), 0);
+        let stats: Vec<_> = ids.iter().map(|id| group.stats(*id).unwrap()).collect();
+        // each of the 72 missing entries was requested and received exactly once
+        let received: usize = stats.iter().map(|stats| stats.sync.entries_received).sum();
+        let requested: usize = stats.iter().map(|stats| stats.values_requested).sum();
+        assert_eq!(received, 72);
+        assert_eq!(requested, 72);
+        assert!(stats.iter().all(|stats| stats.values_requested > 0));
+        assert!(stats.iter().any(|stats| stats.values_deduplicated > 0));
+    }
+
+    #[cfg(feature = "futures")]
+    #[tokio::test]
+    async fn test_run_sync() {
+        let latency = std::time::Duration::from_millis(2);
+        for (alice_set, bob_set) in paper_sets() {
+            let mut alice: MemoryStore<_> = alice_set.iter().copied().collect();
+            let mut bob: MemoryStore<_> = bob_set.iter().copied().collect();
+            let (mut alice_session, mut bob_session) =
+                (SyncSession::default(), SyncSession::default());
+            let (alice_transport, bob_transport) = test_utils::duplex(latency);
+            let (alice_report, bob_report) = tokio::join!(
+                run_sync(
+                    &mut alice_session,
+                    &mut alice,
+                    alice_transport,
+                    RunOptions::initiator()
+                ),
+                run_sync(
+                    &mut bob_session,
+                    &mut bob,
+                    bob_transport,
+                    RunOptions::responder()
+                ),
+            );
+            let (alice_report, bob_report) = (alice_report.unwrap(), bob_report.unwrap());
+            assert_eq!(collect(alice.all().unwrap()), collect(bob.all().unwrap()));
+            assert_eq!(alice_report.role, Role::Initiator);
+            assert_eq!(bob_report.role, Role::Responder);
+            // one side processes the last message, the other sees the transport close
+            let mut terminations = [alice_report.termination, bob_report.termination];
+            terminations.sort_by_key(|t| *t == Termination::Closed);
+            assert_eq!(terminations, [Termination::Done, Termination::Closed]);
+            assert_eq!(
+                alice_report.stats.messages_sent,
+                bob_report.stats.messages_received
+            );
+            assert_eq!(
+                bob_report.stats.messages_sent,
+                alice_report.stats.messages_received
+            );
+        }
+
+        // responses split into pages are answered once
+        let (alice_set, bob_set) = paper_sets()[1];
+        let mut alice: MemoryStore<_> = alice_set.iter().copied().collect();
+        let mut bob: MemoryStore<_> = bob_set.iter().copied().collect();
+        let mut alice_session = SyncSession::default().with_page_size(1);
+        let mut bob_session = SyncSession::default().with_page_size(1);
+        let (alice_transport, bob_transport) = test_utils::duplex(latency);
+        let (alice_report, bob_report) = tokio::join!(
+            run_sync(
+                &mut alice_session,
+                &mut alice,
+                alice_transport,
+                RunOptions::initiator()
+            ),
+            run_sync(
+                &mut bob_session,
+                &mut bob,
+                bob_transport,
+                RunOptions::responder()
+            ),
+        );
+        let (alice_report, bob_report) = (alice_report.unwrap(), bob_report.unwrap());
+        assert_eq!(collect(alice.all().unwrap()), collect(bob.all().unwrap()));
+        let mut terminations = [alice_report.termination, bob_report.termination];
+        terminations.sort_by_key(|t| *t == Termination::Closed);
+        assert_eq!(terminations, [Termination::Done, Termination::Closed]);
+
+        // a handshake between equal stores ends after one round trip
+        let (alice_set, _) = paper_sets()[0];
+        let mut alice: MemoryStore<_> = alice_set.iter().copied().collect();
+        let mut bob: MemoryStore<_> = alice_set.iter().copied().collect();
+        let (mut alice_session, mut bob_session) = (SyncSession::default(), SyncSession::default());
+        let (alice_transport, bob_transport) = test_utils::duplex(latency);
+        let (alice_report, bob_report) = tokio::join!(
+            run_sync(
+                &mut alice_session,
+                &mut alice,
+                alice_transport,
+                RunOptions::initiator().with_handshake(true)
+            ),
+            run_sync(
+                &mut bob_session,
+                &mut bob,
+                bob_transport,
+                RunOptions::responder()
+            ),
+        );
+        let (alice_report, bob_report) = (alice_report.unwrap(), bob_report.unwrap());
+        assert_eq!(alice_report.termination, Termination::Done);
+        assert_eq!(bob_report.termination, Termination::Closed);
+        assert_eq!(alice_report.stats.messages_sent, 1);
+
+        // a session that exceeds its limits cancels the sync of the remote
+        let (alice_set, bob_set) = paper_sets()[0];
+        let mut alice: MemoryStore<_> = alice_set.iter().copied().collect();
+        let mut bob: MemoryStore<_> = bob_set.iter().copied().collect();
+        let limits = ProtocolLimits {
+            max_rounds: 1,
+            ..Default::default()
+        };
+        let mut alice_session = SyncSession::default();
+        let mut bob_session = SyncSession::default().with_limits(limits);
+        let (alice_transport, bob_transport) = test_utils::duplex(latency);
+        let (alice_report, bob_report) = tokio::join!(
+            run_sync(
+                &mut alice_session,
+                &mut alice,
+                alice_transport,
+                RunOptions::initiator()
+            ),
+            run_sync(
+                &mut bob_session,
+                &mut bob,
+                bob_transport,
+                RunOptions::responder()
+            ),
+        );
+        assert_eq!(alice_report.unwrap().termination, Termination::Cancelled);
+        assert!(matches!(
+            bob_report.unwrap_err(),
+            RunSyncError::Sync(SyncError::Protocol(ProtocolError::LimitExceeded(
+                Limit::Rounds(1)
+            )))
+        ));
+    }
+
+    #[cfg(feature = "futures")]
+    #[tokio::test]
+    async fn test_run_sync_io() {
+        use futures_util::{SinkExt, StreamExt};
+        use rand::{Rng, SeedableRng};
+        use tokio::io::AsyncWriteExt;
+
+        // received messages are owned, so the keys are as well
+        let store = |set: Set| -> MemoryStore<(String, i32)> {
+            set.iter().map(|(k, v)| (k.to_string(), *v)).collect()
+        };
+        // a small buffer splits the frames into many reads and writes
+        for (alice_set, bob_set) in paper_sets() {
+            let (mut alice, mut bob) = (store(alice_set), store(bob_set));
+            let (alice_io, bob_io) = tokio::io::duplex(7);
+            let (alice_reader, alice_writer) = tokio::io::split(alice_io);
+            let (bob_reader, bob_writer) = tokio::io::split(bob_io);
+            let (mut alice_session, mut bob_session) =
+                (SyncSession::default(), SyncSession::default());
+            let (alice_report, bob_report) = tokio::join!(
+                run_sync_io(
+                    &mut alice_session,
+                    &mut alice,
+                    alice_reader,
+                    alice_writer,
+                    RunOptions::initiator()
+                ),
+                run_sync_io(
+                    &mut bob_session,
+                    &mut bob,
+                    bob_reader,
+                    bob_writer,
+                    RunOptions::responder()
+                ),
+            );
+            alice_report.unwrap();
+            bob_report.unwrap();
+            assert_eq!(collect(alice.all().unwrap()), collect(bob.all().unwrap()));
+        }
+
+        // frames written in random chunks are read back unchanged
+        let mut rng = rand_chacha::ChaCha8Rng::seed_from_u64(42);
+        let messages: Vec<_> = paper_sets()
+            .into_iter()
+            .flat_map(|(alice_set, bob_set)| {
+                let (mut alice, mut bob) = (store(alice_set), store(bob_set));
+                let msg = alice.initial_message().unwrap();
+                let reply = bob.process_message_reply(
+                    &Default::default(),
+                    msg.clone(),
+                    |_, _, _| true,
+                    |_, _, _| (),
+                    |_, _| ContentStatus::Complete,
+                );
+                [msg, reply.unwrap().unwrap(), Message::cancel()]
+            })
+            .collect();
+        let mut bytes = Vec::new();
+        for message in &messages {
+            let frame = postcard::to_stdvec(message).unwrap();
+            bytes.extend_from_slice(&(frame.len() as u32).to_be_bytes());
+            bytes.extend_from_slice(&frame);
+        }
+        let (reader, mut writer) = tokio::io::duplex(1024);
+        let write = async {
+            let mut rest = &bytes[..];
+            while !rest.is_empty() {
+                let (chunk, next) = rest.split_at(rng.gen_range(1..=rest.len().min(9)));
+                writer.write_all(chunk).await.unwrap();
+                tokio::task::yield_now().await;
+                rest = next;
+            }
+            drop(writer);
+        };
+        let framed = framed::Framed::new(reader, tokio::io::sink(), 1024);
+        let read = framed.map(Result::unwrap).collect::<Vec<Message<_>>>();
+        let ((), received) = tokio::join!(write, read);
+        assert_eq!(received, messages);
+
+        // a stream that ends in the middle of a frame is truncated
+        let frame = postcard::to_stdvec(&messages[0]).unwrap();
+        let mut bytes = (frame.len() as u32).to_be_bytes().to_vec();
+        bytes.extend_from_slice(&frame[..frame.len() - 1]);
+        let mut framed = framed::Framed::new(&bytes[..], tokio::io::sink(), 1024);
+        let err: Option<Result<Message<(String, i32)>, _>> = framed.next().await;
+        assert!(matches!(
+            err,
+            Some(Err(FrameError::Truncated { received, expected }))
+                if received == bytes.len() && expected == bytes.len() + 1
+        ));
+        let mut framed = framed::Framed::new(&bytes[..2], tokio::io::sink(), 1024);
+        let err: Option<Result<Message<(String, i32)>, _>> = framed.next().await;
+        assert!(matches!(
+            err,
+            Some(Err(FrameError::Truncated {
+                received: 2,
+                expected: 4
+            }))
+        ));
+
+        // frames above the limit are neither read nor sent
+        let mut framed = framed::Framed::new(&bytes[..], tokio::io::sink(), 4);
+        let err: Option<Result<Message<(String, i32)>, _>> = framed.next().await;
+        assert!(matches!(
+            err,
+            Some(Err(FrameError::TooLarge { len, max: 4 })) if len == frame.len()
+        ));
+        let err = framed.send(messages[0].clone()).await.unwrap_err();
+        assert!(matches!(err, FrameError::TooLarge { max: 4, .. }));
+
+        // the remote closing mid-session fails the next write
+        let (alice_set, _) = paper_sets()[0];
+        let mut alice = store(alice_set);
+        let (alice_io, bob_io) = tokio::io::duplex(1024);
+        drop(bob_io);
+        let (reader, writer) = tokio::io::split(alice_io);
+        let err = run_sync_io(
+            &mut SyncSession::default(),
+            &mut alice,
+            reader,
+            writer,
+            RunOptions::initiator(),
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(err, RunSyncError::Transport(FrameError::Closed)));
+    }
+
+    #[cfg(feature = "seal")]
+    #[test]
+    fn test_session_sealed() {
+        type Entry = (String, i32);
+
+        let key = SealingKey::new([7; 32]);
+        let store = |set: Set| -> MemoryStore<Entry> {
+            set.iter().map(|(k, v)| (k.to_string(), *v)).collect()
+        };
+        for (alice_set, bob_set) in paper_sets() {
+            let (mut alice, mut bob) = (store(alice_set), store(bob_set));
+            let mut sessions = [
+                (SyncSession::<Entry>::default(), &mut bob),
+                (SyncSession::default(), &mut alice),
+            ];
+            let initial = sessions[1].0.initial_message(sessions[1].1).unwrap();
+            let mut next = Some(sessions[1].0.seal_message(&initial, &key).unwrap());
+            let mut rounds = 0;
+            while let Some(sealed) = next.take() {
+                let (session, store) = &mut sessions[rounds % 2];
+                let message = session.open_message(&sealed, &key).unwrap();
+                next = session
+                    .process(*store, message)
+                    .unwrap()
+                    .map(|reply| session.seal_message(&reply, &key).unwrap());
+                rounds += 1;
+            }
+            assert_eq!(collect(alice.all().unwrap()), collect(bob.all().unwrap()));
+        }
+
+        // the same message is sealed under a fresh nonce each time
+        let session = SyncSession::<Entry>::default();
+        let message = MessageBuilder::new()
+            .add_items(
+                Range::new(String::new(), String::new()),
+                vec![(("ape".to_string(), 1), ContentStatus::Complete)],
+                false,
+            )
+            .build();
+        let sealed = session.seal_message(&message, &key).unwrap();
+        assert_ne!(session.seal_message(&message, &key).unwrap(), sealed);
+
+        // any modified byte, and a different key, fail to open, and leave the session unchanged
+        let mut receiver = SyncSession::<Entry>::default();
+        for i in 0..sealed.ciphertext.len() {
+            let mut tampered = sealed.clone();
+            tampered.ciphertext[i] ^= 1;
+            let err = receiver.open_message(&tampered, &key).unwrap_err();
+            assert!(matches!(err, EnvelopeError::Seal(SealError)));
+        }
+        let mut tampered = sealed.clone();
+        tampered.nonce[0] ^= 1;
+        assert!(receiver.open_message(&tampered, &key).is_err());
+        let err = receiver
+            .open_message(&sealed, &SealingKey::new([8; 32]))
+            .unwrap_err();
+        assert!(matches!(err, EnvelopeError::Seal(SealError)));
+        assert_eq!(receiver.version(), None);
+        assert_eq!(receiver.open_message(&sealed, &key).unwrap(), message);
+    }
+
+    #[cfg(all(feature = "futures", feature = "seal"))]
+    #[tokio::test]
+    async fn test_run_sync_io_sealed() {
+        use futures_util::{SinkExt, StreamExt};
+
+        let key = SealingKey::new([7; 32]);
+        let store = |set: Set| -> MemoryStore<(String, i32)> {
+            set.iter().map(|(k, v)| (k.to_string(), *v)).collect()
+        };
+        let run = |alice_key, bob_key| async move {
+            let (alice_set, bob_set) = paper_sets()[0];
+            let (mut alice, mut bob) = (store(alice_set), store(bob_set));
+            let (alice_io, bob_io) = tokio::io::duplex(64);
+            let (alice_reader, alice_writer) = tokio::io::split(alice_io);
+            let (bob_reader, bob_writer) = tokio::io::split(bob_io);
+            let (mut alice_session, mut bob_session) =
+                (SyncSession::default(), SyncSession::default());
+            let (alice_report, bob_report) = tokio::join!(
+                run_sync_io(
+                    &mut alice_session,
+                    &mut alice,
+                    alice_reader,
+                    alice_writer,
+                    RunOptions::initiator().with_sealing_key(alice_key)
+                ),
+                run_sync_io(
+                    &mut bob_session,
+                    &mut bob,
+                    bob_reader,
+                    bob_writer,
+                    RunOptions::responder().with_sealing_key(bob_key)
+                ),
+            );
+            (alice_report, bob_report, alice, bob)
+        };
+
+        let (alice_report, bob_report, mut alice, mut bob) = run(key, key).await;
+        alice_report.unwrap();
+        bob_report.unwrap();
+        assert_eq!(collect(alice.all().unwrap()), collect(bob.all().unwrap()));
+
+        // the responder cannot open the initial message of an initiator with another key
+        let (_, bob_report, _, _) = run(key, SealingKey::new([8; 32])).await;
+        assert!(matches!(
+            bob_report.unwrap_err(),
+            RunSyncError::Transport(FrameError::Seal(SealError))
+        ));
+
+        // a frame modified on the way is not opened
+        let message = Message::<(String, i32)>::cancel();
+        let mut bytes = Vec::new();
+        let mut sender =
+            framed::Framed::new(tokio::io::empty(), &mut bytes, 1024).with_sealing_key(Some(key));
+        sender.send(message).await.unwrap();
+        drop(sender);
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        let mut receiver =
+            framed::Framed::new(&bytes[..], tokio::io::sink(), 1024).with_sealing_key(Some(key));
+        let err: Option<Result<Message<(String, i32)>, _>> = receiver.next().await;
+        assert!(matches!(err, Some(Err(FrameError::Seal(SealError)))));
+    }
+
+    #[test]
+    fn test_get_remove_mid_session() {
+        let mut alice: MemoryStore<_> = [("ape", 1), ("eel", 1), ("fox", 1), ("gnu", 1)]
+            .into_iter()
+            .collect();
+        let mut bob: MemoryStore<_> = [("bee", 1), ("cat", 1), ("eel", 1), ("hog", 1)]
+            .into_iter()
+            .collect();
+        let process = |store: &mut MemoryStore<_>, msg| {
+            store
+                .process_message_reply(
+                    &Default::default(),
+                    msg,
+                    |_, _, _| true,
+                    |_, _, _| (),
+                    |_, _| ContentStatus::Complete,
+                )
+                .unwrap()
+        };
+
+        // the stores are owned by the caller, so they can be read and changed between messages
+        let msg = alice.initial_message().unwrap();
+        let msg = process(&mut bob, msg).unwrap();
+        assert_eq!(bob.get(&"cat").unwrap(), Some(("cat", 1)));
+        assert_eq!(bob.entry_remove(&"cat").unwrap(), Some(("cat", 1)));
+        assert_eq!(alice.entry_remove(&"gnu").unwrap(), Some(("gnu", 1)));
+        let mut next = process(&mut alice, msg);
+        while let Some(msg) = next.take() {
+            next = process(&mut bob, msg).and_then(|msg| process(&mut alice, msg));
+        }
+
+        // a new session converges with the removals applied
+        message_counts(&mut alice, &mut bob);
+        let expected = [("ape", 1), ("bee", 1), ("eel", 1), ("fox", 1), ("hog", 1)];
+        assert_eq!(collect(alice.all().unwrap()), expected);
+        assert_eq!(collect(bob.all().unwrap()), expected);
+    }
+
+    #[test]
+    fn test_partial_sync() {
+        let mut alice: MemoryStore<_> =
+            [("ape", 1), ("bee", 2), ("cat", 1), ("doe", 1), ("fox", 1)]
+                .into_iter()
+                .collect();
+        let mut bob: MemoryStore<_> = [("ant", 1), ("bee", 1), ("cow", 1), ("dog", 1), ("gnu", 1)]
+            .into_iter()
+            .collect();
+        let range = Range::new("b", "e");
+        let received = RefCell::new(vec![]);
+        let process = |store: &mut MemoryStore<_>, msg| {
+            store
+                .process_message_reply(
+                    &Default::default(),
+                    msg,
+                    |_, entry: &(&str, i32), _| {
+                        received.borrow_mut().push(entry.0);
+                        true
+                    },
+                    |_, _, _| (),
+                    |_, _| ContentStatus::Complete,
+                )
+                .unwrap()
+        };
+
+        let mut next = Some(alice.initial_message_for_range(range).unwrap());
+        while let Some(msg) = next.take() {
+            next = process(&mut bob, msg).and_then(|msg| process(&mut alice, msg));
+        }
+
+        let received = received.take();
+        assert!(!received.is_empty());
+        assert!(
+            received.iter().all(|key| range.contains(key)),
+            "{received:?}"
+        );
+        assert_eq!(
+            collect(alice.all().unwrap()),
+            [
+                ("ape", 1),
+                ("bee", 2),
+                ("cat", 1),
+                ("cow", 1),
+                ("doe", 1),
+                ("dog", 1),
+                ("fox", 1),
+            ]
+        );
+        assert_eq!(
+            collect(bob.all().unwrap()),
+            [
+                ("ant", 1),
+                ("bee", 2),
+                ("cat", 1),
+                ("cow", 1),
+                ("doe", 1),
+                ("dog", 1),
+                ("gnu", 1),
+            ]
+        );
+
+        // values outside of the range of an item are ignored
+        let msg = Message {
+            parts: vec![MessagePart::RangeItem(RangeItem {
+                range,
+                values: vec![
+                    (("bat", 1), ContentStatus::Complete),
+                    (("yak", 1), ContentStatus::Complete),
+                ],
+                have_local: true,
+            })],
+            more: false,
+        };
+        process(&mut alice, msg);
+        assert!(alice.get(&"bat").unwrap().is_some());
+        assert!(alice.get(&"yak").unwrap().is_none());
+    }
+
+    type Entries = Vec<(&'static str, i32)>;
+
+    /// Syncs two stores, each processing messages with its own config, and returns their entries.
+    fn sync_with_configs(
+        alice_set: Set,
+        bob_set: Set,
+        alice_config: SyncConfig,
+        bob_config: SyncConfig,
+    ) -> (Entries, Entries) {
+        let mut alice: MemoryStore<_> = alice_set.iter().copied().collect();
+        let mut bob: MemoryStore<_> = bob_set.iter().copied().collect();
+        let process = |store: &mut MemoryStore<_>, config, msg| {
+            store
+                .process_message_reply(
+                    config,
+                    msg,
+                    |_, _, _| true,
+                    |_, _, _| (),
+                    |_, _| ContentStatus::Complete,
+                )
+                .unwrap()
+        };
+        let mut next = Some(alice.initial_message().unwrap());
+        let mut rounds = 0;
+        while let Some(msg) = next.take() {
+            rounds += 1;
+            assert!(rounds < 100, "too many rounds");
+            next = process(&mut bob, &bob_config, msg)
+                .and_then(|msg| process(&mut alice, &alice_config, msg));
+        }
+        (collect(alice.all().unwrap()), collect(bob.all().unwrap()))
+    }
+
+    #[test]
+    fn test_echo_policy() {
+        let sets: [(Set, Set); 2] = [
+            (
+                &[("ape", 1), ("bee", 2), ("cat", 1), ("doe", 3)],
+                &[("ape", 2), ("bee", 1), ("cat", 1), ("doe", 1)],
+            ),
+            // the remote's newer prefix removes the local entries it is a prefix of
+            (
+                &[("/foo", 2), ("/foo/baz", 3), ("/qux", 1)],
+                &[("/foo", 1), ("/foo/bar", 1), ("/qux", 2)],
+            ),
+        ];
+        for policy in [
+            EchoPolicy::IfNewer,
+            EchoPolicy::IfDifferent,
+            EchoPolicy::Never,
+        ] {
+            let config = SyncConfig::default().with_echo_policy(policy);
+            for (alice_set, bob_set) in sets {
+                let mut alice: MemoryStore<_> = alice_set.iter().copied().collect();
+                let mut bob: MemoryStore<_> = bob_set.iter().copied().collect();
+                let mut next = Some(alice.initial_message().unwrap());
+                let stores = [&mut bob, &mut alice];
+                let mut rounds = 0;
+                while let Some(msg) = next.take() {
+                    rounds += 1;
+                    assert!(rounds < 100, "too many rounds");
+                    let store = &mut *stores[(rounds + 1) % 2];
+                    let received: Vec<_> = msg
+                        .parts()
+                        .iter()
+                        .filter_map(MessagePart::values)
+                        .flatten()
+                        .map(|(entry, _)| *entry)
+                        .collect();
+                    next = store
+                        .process_message_reply(
+                            &config,
+                            msg,
+                            |_, _, _| true,
+                            |_, _, _| (),
+                            |_, _| ContentStatus::Complete,
+                        )
+                        .unwrap();
+                    let Some(reply) = &next else {
+                        continue;
+                    };
+                    for (entry, _) in reply
+                        .parts()
+                        .iter()
+                        .filter_map(MessagePart::values)
+                        .flatten()
+                    {
+                        // every entry that is sent is still stored
+                        assert_eq!(store.get(entry.key()).unwrap(), Some(*entry), "{policy:?}");
+                        if policy == EchoPolicy::Never {
+                            assert!(
+                                received.iter().all(|their| their.key() != entry.key()),
+                                "{entry:?} echoed"
+                            );
+                        }
+                    }
+                }
+                if policy != EchoPolicy::Never {
+                    let mut expected = MemoryStore::default();
+                    for entry in alice_set.iter().chain(bob_set) {
+                        expected.put(*entry).unwrap();
+                    }
+                    let expected = collect(expected.all().unwrap());
+                    assert_eq!(collect(alice.all().unwrap()), expected);
+                    assert_eq!(collect(bob.all().unwrap()), expected);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_echo_rejected_withheld() {
+        type Filter = fn(&(&str, i32)) -> bool;
+        let public: Filter = |e| e.0 != "secret";
+        let store: MemoryStore<_> = [("public", 1), ("secret", 5)].into_iter().collect();
+        let mut store = FilteredStore::new(store, public);
+        let config = SyncConfig::default().with_echo_policy(EchoPolicy::Never);
+        // the second item overlaps the first, so the rejected entry is sent back only once
+        let msg = MessageBuilder::new()
+            .add_items(
+                Range::new("a", "z"),
+                vec![
+                    (("public", 2), ContentStatus::Complete),
+                    (("secret", 1), ContentStatus::Complete),
+                ],
+                false,
+            )
+            .add_items(
+                Range::new("p", "q"),
+                vec![(("public", 2), ContentStatus::Complete)],
+                false,
+            )
+            .build();
+        let reply = store
+            .process_message_reply(
+                &config,
+                msg,
+                |_, _, _| false,
+                |_, _, _| (),
+                |_, _| ContentStatus::Complete,
+            )
+            .unwrap()
+            .unwrap();
+        let sent: Vec<_> = reply.values().map(|(entry, _)| *entry).collect();
+        assert_eq!(sent, [("public", 1)]);
+    }
+
+    #[test]
+    fn test_sync_direction() {
+        let both = SyncConfig::default();
+        let receive_only = both.with_direction(SyncDirection::ReceiveOnly);
+        let send_only = both.with_direction(SyncDirection::SendOnly);
+        let prefix_sets: [(Set, Set); 2] = [
+            (
+                &[("/foo/bar", 1), ("/foo/baz", 1), ("/foo/cat", 1)],
+                &[("/alice/bar", 1), ("/alice/baz", 1), ("/foo/bar", 1)],
+            ),
+            (
+                &[("/foo", 2), ("/qux", 1)],
+                &[("/foo/bar", 1), ("/foo/baz", 1)],
+            ),
+        ];
+        for (alice_set, bob_set) in paper_sets().into_iter().chain(prefix_sets) {
+            let (expected, _) = sync_with_configs(alice_set, bob_set, both, both);
+
+            // the receiving side pulls everything, but the other side does not learn anything
+            let (alice, bob) = sync_with_configs(alice_set, bob_set, both, receive_only);
+            assert_eq!(
+                (&alice[..], &bob),
+                (alice_set, &expected),
+                "bob receive only"
+            );
+            let (alice, bob) = sync_with_configs(alice_set, bob_set, receive_only, both);
+            assert_eq!(
+                (&alice, &bob[..]),
+                (&expected, bob_set),
+                "alice receive only"
+            );
+
+            // the sending side publishes everything, but does not store anything
+            let (alice, bob) = sync_with_configs(alice_set, bob_set, both, send_only);
+            assert_eq!((&alice, &bob[..]), (&expected, bob_set), "bob send only");
+            let (alice, bob) = sync_with_configs(alice_set, bob_set, send_only, both);
+            assert_eq!(
+                (&alice[..], &bob),
+                (alice_set, &expected),
+                "alice send only"
+            );
+        }
+    }
+
+    #[test]
+    fn test_send_threshold_bytes() {
+        /// An entry whose size is dominated by its value.
+        #[derive(Debug, Clone, PartialEq)]
+        struct Blob(u32, &'static [u8]);
+
+        impl RangeEntry for Blob {
+            type Key = u32;
+            type Value = &'static [u8];
+
+            fn key(&self) -> &u32 {
+                &self.0
+            }
+
+            fn value(&self) -> &&'static [u8] {
+                &self.1
+            }
+
+            fn as_fingerprint(&self) -> Fingerprint {
+                let mut hasher = blake3::Hasher::new();
+                hasher.update(&self.0.to_le_bytes());
+                hasher.update(self.1);
+                Fingerprint(hasher.finalize().into())
+            }
+
+            fn encoded_size_hint(&self) -> u64 {
+                4 + self.1.len() as u64
+            }
+        }
+
+        // every 8th entry is large, and bob has older versions of every 5th entry
+        let blob = |i: u32, version: u8| match i % 8 {
+            0 => Blob(i, if version == 1 { &[1; 100] } else { &[2; 100] }),
+            _ => Blob(i, if version == 1 { &[1; 4] } else { &[2; 4] }),
+        };
+        let alice_set: Vec<_> = (0..256).map(|i| blob(i, 2)).collect();
+        let bob_set: Vec<_> = (0..256)
+            .map(|i| blob(i, if i % 5 == 0 { 1 } else { 2 }))
+            .collect();
+        let mut alice: MemoryStore<_> = alice_set.iter().cloned().collect();
+        let mut bob: MemoryStore<_> = bob_set.into_iter().collect();
+
+        let config = SyncConfig::default().with_send_threshold(SendThreshold::Bytes(256));
+        let mut alice_session = SyncSession::new(config);
+        let mut bob_session = SyncSession::new(config);
+        let mut max_items = 0;
+        let mut check = |msg: &Message<Blob>| {
+            for values in msg.parts().iter().filter_map(MessagePart::values) {
+                let bytes: u64 = values.iter().map(|(e, _)| e.encoded_size_hint()).sum();
+                assert!(bytes <= 256 || values.len() == 1, "{bytes} bytes");
+                max_items = max_items.max(values.len());
+            }
+        };
+        let mut next = Some(alice_session.initial_message(&mut alice).unwrap());
+        while let Some(msg) = next.take() {
+            let validate = |_: &_, _: &_, _| true;
+            let status = |_: &_, _: &_| ContentStatus::Complete;
+            let Some(reply) = bob_session
+                .process_message(&mut bob, msg, validate, |_, _, _| (), status)
+                .unwrap()
+            else {
+                break;
+            };
+            check(&reply);
+            next = alice_session
+                .process_message(&mut alice, reply, validate, |_, _, _| (), status)
+                .unwrap();
+            next.iter().for_each(&mut check);
+        }
+        assert_eq!(collect(bob.all().unwrap()), alice_set);
+        // ranges of one large and seven small entries are sent as items
+        assert_eq!(max_items, 8);
+    }
+
+    #[test]
+    fn test_sync_thresholds() {
+        type Store = MemoryStore<(u32, u8)>;
+        // syncs with bob starting, and returns the stats of both sessions and the size of the
+        // largest item
+        let sync = |alice: &mut Store, bob: &mut Store, config: SyncConfig| {
+            let mut alice_session = SyncSession::new(config);
+            let mut bob_session = SyncSession::new(config);
+            let mut max_items = 0;
+            let mut check = |msg: &Message<(u32, u8)>| {
+                for values in msg.parts().iter().filter_map(MessagePart::values) {
+                    max_items = max_items.max(values.len());
+                }
+            };
+            let mut next = Some(bob_session.initial_message(bob).unwrap());
+            while let Some(msg) = next.take() {
+                let Some(reply) = alice_session.process(alice, msg).unwrap() else {
+                    break;
+                };
+                check(&reply);
+                next = bob_session.process(bob, reply).unwrap();
+                next.iter().for_each(&mut check);
+            }
+            assert_eq!(collect(alice.all().unwrap()), collect(bob.all().unwrap()));
+            (
+                alice_session.take_stats(),
+                bob_session.take_stats(),
+                max_items,
+            )
+        };
+        let messages = |(alice, bob, _): &(SyncStats, SyncStats, usize)| {
+            alice.messages_sent + bob.messages_sent
+        };
+        let entries =
+            |(alice, bob, _): &(SyncStats, SyncStats, usize)| alice.entries_sent + bob.entries_sent;
+        let alice_set = || (0..512u32).map(|i| (i, 1u8)).collect::<Store>();
+        let default = SyncConfig::default();
+        assert_eq!(default.with_thresholds(1, usize::MAX).unwrap(), default);
+        assert_eq!(
+            default.with_thresholds(9, 8),
+            Err(ThresholdError {
+                anchor_threshold: 9,
+                item_threshold: 8
+            })
+        );
+        assert_eq!(default.with_thresholds(0, 8).unwrap().anchor_threshold(), 1);
+
+        // on a cold start, all entries are sent at once by default, and the item threshold
+        // splits them into ranges within the threshold, at the cost of more rounds
+        let cold = sync(&mut alice_set(), &mut Store::default(), default);
+        assert_eq!(messages(&cold), 2);
+        assert_eq!(cold.2, 512);
+        let config = default.with_thresholds(1, 64).unwrap();
+        let split = sync(&mut alice_set(), &mut Store::default(), config);
+        assert!(split.2 <= 64, "{} items", split.2);
+        assert!(messages(&split) > messages(&cold));
+        assert_eq!(entries(&split), entries(&cold));
+
+        // a higher anchor threshold saves rounds, but sends entries the remote has already
+        let bob_set = || {
+            (0..512u32)
+                .map(|i| (i, if i % 100 == 0 { 2u8 } else { 1 }))
+                .collect::<Store>()
+        };
+        let deep = sync(&mut alice_set(), &mut bob_set(), default);
+        let config = default.with_thresholds(8, 64).unwrap();
+        let shallow = sync(&mut alice_set(), &mut bob_set(), config);
+        assert!(messages(&shallow) < messages(&deep));
+        assert!(entries(&shallow) > entries(&deep));
+    }
+
+    #[test]
+    fn test_filtered_store() {
+        type Filter = fn(&(&str, i32)) -> bool;
+        let not_draft: Filter = |e| !e.0.starts_with("/drafts/");
+        let alice_set = [
+            ("/drafts/ape", 1),
+            ("/drafts/bee", 2),
+            ("/notes/ape", 1),
+            ("/notes/cat", 1),
+        ];
+        let bob_set = [("/drafts/bee", 1), ("/drafts/doe", 1), ("/notes/eel", 1)];
+        let alice =
+            FilteredStore::new(alice_set.into_iter().collect::<MemoryStore<_>>(), not_draft);
+        let bob = FilteredStore::new(bob_set.into_iter().collect::<MemoryStore<_>>(), not_draft);
+
+        let res = sync_exchange_messages(alice, bob, |_, _, _| true, |_, _, _| true, 100);
+        for (side, messages) in [("alice", &res.alice_to_bob), ("bob", &res.bob_to_alice)] {
+            for (e, _) in messages.iter().flat_map(Message::values) {
+                assert!(not_draft(e), "{side} sent withheld entry {e:?}");
+            }
+        }
+
+        let alice: Entries = collect(res.alice.into_inner().all().unwrap());
+        let bob: Entries = collect(res.bob.into_inner().all().unwrap());
+        assert_eq!(
+            alice,
+            [
+                ("/drafts/ape", 1),
+                ("/drafts/bee", 2),
+                ("/notes/ape", 1),
+                ("/notes/cat", 1),
+                ("/notes/eel", 1),
+            ]
+        );
+        assert_eq!(
+            bob,
+            [
+                ("/drafts/bee", 1),
+                ("/drafts/doe", 1),
+                ("/notes/ape", 1),
+                ("/notes/cat", 1),
+                ("/notes/eel", 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_expiry() {
+        use std::{
+            sync::{
+                atomic::{AtomicU64, Ordering},
+                Arc,
+            },
+            time::{Duration, SystemTime},
+        };
+
+        // the value of an entry is the second it expires at
+        type Entry = (&'static str, u64);
+        let clock = Arc::new(AtomicU64::new(10));
+        let policy = ExpiryPolicy::new(|entry: &Entry, now| {
+            SystemTime::UNIX_EPOCH + Duration::from_secs(entry.1) <= now
+        })
+        .with_clock({
+            let clock = clock.clone();
+            move || SystemTime::UNIX_EPOCH + Duration::from_secs(clock.load(Ordering::SeqCst))
+        });
+
+        /// Runs a session between alice and bob, and returns the number of entries sent by each.
+        fn run<S: Store<Entry>>(
+            policy: &ExpiryPolicy<Entry>,
+            alice: &mut S,
+            bob: &mut S,
+        ) -> [usize; 2] {
+            let mut alice_session = SyncSession::default().with_expiry_policy(policy.clone());
+            let mut bob_session = SyncSession::default().with_expiry_policy(policy.clone());
+            let mut next = Some(alice_session.initial_message(alice).unwrap());
+            let mut rounds = 0;
+            while let Some(message) = next.take() {
+                assert!(rounds < 10, "too many rounds");
+                next = match rounds % 2 {
+                    0 => bob_session.process(bob, message).unwrap(),
+                    _ => alice_session.process(alice, message).unwrap(),
+                };
+                rounds += 1;
+            }
+            [alice_session.stats(), bob_session.stats()].map(|stats| stats.entries_sent)
+        }
+
+        // a store with only expired entries is in sync with an empty store
+        let mut alice = policy.store(
+            [("ape", 5), ("bee", 10)]
+                .into_iter()
+                .collect::<MemoryStore<_>>(),
+        );
+        let mut bob = policy.store(MemoryStore::default());
+        assert_eq!(run(&policy, &mut alice, &mut bob), [0, 0]);
+        assert!(bob.clone().into_inner().is_empty().unwrap());
+        assert_eq!(alice.clone().into_inner().len().unwrap(), 2);
+
+        // live entries are synced, and stop being sent once they expired
+        alice.put(("cat", 20)).unwrap();
+        assert_eq!(run(&policy, &mut alice, &mut bob), [1, 0]);
+        assert_eq!(collect(bob.all().unwrap()), vec![("cat", 20)]);
+        clock.store(20, Ordering::SeqCst);
+        alice.put(("doe", 30)).unwrap();
+        assert_eq!(run(&policy, &mut alice, &mut bob), [1, 0]);
+        assert_eq!(collect(bob.all().unwrap()), vec![("doe", 30)]);
+
+        // expired entries sent by a remote that does not withhold them are rejected
+        let mut carol: MemoryStore<_> = [("ape", 5), ("eel", 40)].into_iter().collect();
+        let mut dave = MemoryStore::default();
+        let mut dave_session = SyncSession::default().with_expiry_policy(policy.clone());
+        let message = SyncSession::default().initial_message(&mut carol).unwrap();
+        let message = dave_session.process(&mut dave, message).unwrap().unwrap();
+        let message = SyncSession::default()
+            .process(&mut carol, message)
+            .unwrap()
+            .unwrap();
+        dave_session.process(&mut dave, message).unwrap();
+        assert_eq!(collect(dave.all().unwrap()), vec![("eel", 40)]);
+        assert_eq!(dave_session.stats().entries_rejected, 1);
+
+        // expired entries are only removed from the store by the garbage collection
+        assert_eq!(alice.clone().into_inner().len().unwrap(), 4);
+        assert_eq!(alice.gc_expired().unwrap(), 3);
+        assert_eq!(
+            collect(alice.clone().into_inner().all().unwrap()),
+            vec![("doe", 30)]
+        );
+        assert_eq!(bob.gc_expired().unwrap(), 1);
+    }
+
+    #[proptest]
+    fn test_dry_run(
+        #[strategy(prop::collection::btree_map("[a-z]{3}", test_value_u8(), 0..10))]
+        alice_set: BTreeMap<String, u8>,
+        #[strategy(prop::collection::btree_map("[a-z]{3}", test_value_u8(), 0..10))]
+        bob_set: BTreeMap<String, u8>,
+        alice_starts: bool,
+    ) {
+        // keys of equal length, so no entries are removed by prefix deletion
+        let mut alice: MemoryStore<_> = alice_set.into_iter().collect();
+        let mut bob: MemoryStore<_> = bob_set.into_iter().collect();
+        let alice_before = collect(alice.all().unwrap());
+        let bob_before = collect(bob.all().unwrap());
+        let config = SyncConfig::default();
+
+        let mut summary = DiffSummary::default();
+        let mut next = match alice_starts {
+            true => alice.initial_message().unwrap(),
+            false => bob.initial_message().unwrap(),
+        };
+        let mut alice_turn = !alice_starts;
+        let mut rounds = 0;
+        loop {
+            rounds += 1;
+            prop_assert!(rounds < 100, "too many rounds");
+            let res = if alice_turn {
+                alice.process_message_dry_run(&config, next, &mut summary)
+            } else {
+                bob.process_message_reply(
+                    &config,
+                    next,
+                    |_, _, _| true,
+                    |_, _, _| (),
+                    |_, _| ContentStatus::Complete,
+                )
+            };
+            let Some(msg) = res.unwrap() else {
+                break;
+            };
+            next = msg;
+            alice_turn = !alice_turn;
+        }
+        prop_assert_eq!(collect(alice.all().unwrap()), alice_before);
+        prop_assert_eq!(collect(bob.all().unwrap()), bob_before);
+
+        // the real sync inserts exactly the keys in the summary
+        let mut alice_inserted = Vec::new();
+        let mut bob_inserted = Vec::new();
+        let mut next = Some(alice.initial_message().unwrap());
+        while let Some(msg) = next.take() {
+            next = bob
+                .process_message_reply(
+                    &config,
+                    msg,
+                    |_, _, _| true,
+                    |_, entry, _| bob_inserted.push(entry.0),
+                    |_, _| ContentStatus::Complete,
+                )
+                .unwrap()
+                .map(|msg| {
+                    alice.process_message_reply(
+                        &config,
+                        msg,
+                        |_, _, _| true,
+                        |_, entry, _| alice_inserted.push(entry.0),
+                        |_, _| ContentStatus::Complete,
+                    )
+                })
+                .transpose()
+                .unwrap()
+                .flatten();
+        }
+        alice_inserted.sort();
+        bob_inserted.sort();
+        summary.missing_locally.sort();
+        summary.missing_remotely.sort();
+        prop_assert_eq!(summary.missing_locally, alice_inserted);
+        prop_assert_eq!(summary.missing_remotely, bob_inserted);
+    }
+
+    /// Runs a session for bob against a remote that answers each response with `respond`, and
+    /// returns the error that ended the session.
+    fn adversarial_session(
+        limits: ProtocolLimits,
+        mut respond: impl FnMut(Message<(u32, ())>) -> Message<(u32, ())>,
+    ) -> (SyncSession<(u32, ())>, ProtocolError) {
+        let mut bob: MemoryStore<_> = (0..1000u32).map(|i| (i, ())).collect();
+        let mut session = SyncSession::default().with_limits(limits);
+        let part = MessagePart::RangeFingerprint(RangeFingerprint {
+            range: Range::new(0, 0),
+            fingerprint: Fingerprint(*blake3::hash(b"initial").as_bytes()),
+        });
+        let mut msg = Message {
+            parts: vec![part],
+            more: false,
+        };
+        loop {
+            let res = session.process(&mut bob, msg);
+            match res {
+                Ok(Some(response)) => msg = respond(response),
+                Ok(None) => panic!("session terminated"),
+                Err(SyncError::Protocol(err)) => return (session, err),
+                Err(err) => panic!("unexpected error: {err}"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_session_limits() {
+        let limits = ProtocolLimits::default();
+        let fingerprint = |i: u32| Fingerprint(*blake3::hash(&i.to_be_bytes()).as_bytes());
+
+        // a fresh fingerprint for the whole set in every round
+        let mut i = 0;
+        let fresh = |_| {
+            i += 1;
+            let part = MessagePart::RangeFingerprint(RangeFingerprint {
+                range: Range::new(0, 0),
+                fingerprint: fingerprint(i),
+            });
+            Message {
+                parts: vec![part],
+                more: false,
+            }
+        };
+        let limits_rounds = ProtocolLimits {
+            max_rounds: 10,
+            ..limits
+        };
+        let (session, err) = adversarial_session(limits_rounds, fresh);
+        assert_eq!(err, ProtocolError::LimitExceeded(Limit::Rounds(10)));
+        assert_eq!((session.rounds(), session.depth()), (10, 0));
+
+        // many fingerprints in every round
+        let many = |_| {
+            let parts = (0..10)
+                .map(|i| {
+                    MessagePart::RangeFingerprint(RangeFingerprint {
+                        range: Range::new(i * 100, i * 100 + 100),
+                        fingerprint: fingerprint(i),
+                    })
+                })
+                .collect();
+            Message { parts, more: false }
+        };
+        // the same parts in every round make no progress, see `test_no_progress`
+        let limits_parts = ProtocolLimits {
+            max_fingerprint_parts: 25,
+            max_responded_fingerprints: 0,
+            ..limits
+        };
+        let (session, err) = adversarial_session(limits_parts, many);
+        assert_eq!(
+            err,
+            ProtocolError::LimitExceeded(Limit::FingerprintParts(25))
+        );
+        assert_eq!((session.rounds(), session.fingerprint_parts()), (3, 21));
+
+        // the same parts count toward the ranges, up to and including the limit
+        let limits_ranges = ProtocolLimits {
+            max_ranges: 21,
+            max_responded_fingerprints: 0,
+            ..limits
+        };
+        let (session, err) = adversarial_session(limits_ranges, many);
+        assert_eq!(err, ProtocolError::LimitExceeded(Limit::Ranges(21)));
+        assert_eq!((session.rounds(), session.ranges()), (3, 21));
+
+        // a mismatching fingerprint for every range we sent, so the ranges get smaller
+        let echo = |msg: Message<(u32, ())>| {
+            let parts = msg
+                .parts
+                .into_iter()
+                .filter(|part| part.is_range_fingerprint())
+                .enumerate()
+                .map(|(i, part)| match part {
+                    MessagePart::RangeFingerprint(fp) => {
+                        MessagePart::RangeFingerprint(RangeFingerprint {
+                            range: fp.range,
+                            fingerprint: fingerprint(i as u32),
+                        })
+                    }
+                    _ => unreachable!(),
+                })
+                .collect();
+            Message { parts, more: false }
+        };
+        let limits_depth = ProtocolLimits {
+            max_depth: 3,
+            ..limits
+        };
+        let (session, err) = adversarial_session(limits_depth, echo);
+        assert_eq!(err, ProtocolError::LimitExceeded(Limit::Depth(3)));
+        assert_eq!((session.rounds(), session.depth()), (2, 2));
+
+        // the default limits allow a full sync
+        let alice: MemoryStore<_> = (0..1000u32).map(|i| (i * 2, ())).collect();
+        let bob: MemoryStore<_> = (0..1000u32).map(|i| (i * 3, ())).collect();
+        let mut res = sync_exchange_messages(alice, bob, |_, _, _| true, |_, _, _| true, 100);
+        assert_eq!(collect(res.alice.all().unwrap()).len(), 1666);
+        assert_eq!(
+            collect(res.alice.all().unwrap()),
+            collect(res.bob.all().unwrap())
+        );
+    }
+
+    #[test]
+    fn test_no_progress() {
+        // alice's store is inconsistent with bob's, and splits the first range bob sends into
+        // the range itself, so she sends the same fingerprint in every round
+        let mut alice: MemoryStore<_> = (0..1000u32).map(|i| (i * 2, ())).collect();
+        let mut bounce = |range: Range<u32>| Message {
+            parts: vec![MessagePart::RangeFingerprint(RangeFingerprint {
+                fingerprint: alice.get_fingerprint(&range).unwrap(),
+                range,
+            })],
+            more: false,
+        };
+        // bob splits the whole set into these ranges
+        let (first, second) = (bounce(Range::new(0, 500)), bounce(Range::new(500, 0)));
+        let (session, err) = adversarial_session(ProtocolLimits::default(), |_| first.clone());
+        assert_eq!(
+            err,
+            ProtocolError::NoProgress {
+                range: format!("{:?}", Range::new(0u32, 500))
+            }
+        );
+        // the first time the fingerprint is received again
+        assert_eq!(session.rounds(), 3);
+
+        // without the check, the sync goes on until the round limit
+        let limits = ProtocolLimits {
+            max_responded_fingerprints: 0,
+            ..Default::default()
+        };
+        let (_, err) = adversarial_session(limits, |_| first.clone());
+        assert_eq!(err, ProtocolError::LimitExceeded(Limit::Rounds(1024)));
+
+        // a fingerprint that was forgotten before it is received again is not detected
+        let limits = ProtocolLimits {
+            max_responded_fingerprints: 1,
+            ..Default::default()
+        };
+        let mut i = 0;
+        let alternate = |_| {
+            i += 1;
+            match i % 2 {
+                0 => first.clone(),
+                _ => second.clone(),
+            }
+        };
+        let (_, err) = adversarial_session(limits, alternate);
+        assert_eq!(err, ProtocolError::LimitExceeded(Limit::Rounds(1024)));
+
+        // nor is a fingerprint for a range that changed locally since
+        let mut bob: MemoryStore<_> = (0..1000u32).map(|i| (i, ())).collect();
+        let mut session = SyncSession::default();
+        let initial = MessageBuilder::new()
+            .add_fingerprint(Range::new(0, 0), Fingerprint([1; 32]))
+            .build();
+        session.process(&mut bob, initial).unwrap().unwrap();
+        session.process(&mut bob, first.clone()).unwrap().unwrap();
+        bob.entry_remove(&1).unwrap();
+        session.process(&mut bob, first.clone()).unwrap().unwrap();
+        let err = session.process(&mut bob, first).unwrap_err();
+        assert!(matches!(
+            err,
+            SyncError::Protocol(ProtocolError::NoProgress { .. })
+        ));
+    }
+
+    #[test]
+    fn test_session_stats() {
+        let alice_set = [("ape", 1), ("eel", 1), ("fox", 2), ("gnu", 1)];
+        let bob_set = [("bee", 1), ("cat", 1), ("doe", 1), ("fox", 3), ("hog", 1)];
+        let validate_alice: ValidateCb<_, _> = Box::new(|_, e, _| e.0 != "doe");
+        let validate_bob: ValidateCb<_, _> = Box::new(|_, _, _| true);
+        let alice: MemoryStore<_> = alice_set.into_iter().collect();
+        let bob: MemoryStore<_> = bob_set.into_iter().collect();
+        let res = sync_exchange_messages(alice, bob, &validate_alice, &validate_bob, 100);
+
+        // the stats match the messages that were exchanged
+        let count_parts = |messages: &[Message<_>], fingerprints: bool| {
+            messages
+                .iter()
+                .flat_map(|msg| msg.parts())
+                .filter(|part| part.is_range_fingerprint() == fingerprints)
+                .count()
+        };
+        let count_values = |messages: &[Message<_>]| -> usize {
+            messages.iter().map(|msg| msg.value_count()).sum()
+        };
+        for (stats, sent, received) in [
+            (&res.alice_stats, &res.alice_to_bob, &res.bob_to_alice),
+            (&res.bob_stats, &res.bob_to_alice, &res.alice_to_bob),
+        ] {
+            assert_eq!(stats.messages_sent, sent.len());
+            assert_eq!(stats.messages_received, received.len());
+            assert_eq!(stats.fingerprint_parts_sent, count_parts(sent, true));
+            assert_eq!(
+                stats.fingerprint_parts_received,
+                count_parts(received, true)
+            );
+            assert_eq!(stats.item_parts_sent, count_parts(sent, false));
+            assert_eq!(stats.item_parts_received, count_parts(received, false));
+            assert_eq!(stats.entries_sent, count_values(sent));
+            assert_eq!(stats.entries_received, count_values(received));
+        }
+
+        // alice rejects "doe" and overwrites "fox", bob overwrites nothing
+        let alice = &res.alice_stats;
+        assert_eq!(
+            (
+                alice.entries_rejected,
+                alice.entries_inserted,
+                alice.entries_overwritten
+            ),
+            (1, 3, 1)
+        );
+        let bob = &res.bob_stats;
+        assert_eq!(
+            (
+                bob.entries_rejected,
+                bob.entries_inserted,
+                bob.entries_overwritten
+            ),
+            (0, 3, 0)
+        );
+    }
+
+    #[test]
+    fn test_session_quota() {
+        // syncs an empty alice with a large bob, and returns alice's keys and session
+        let sync_with_quota = |limits: SessionLimits, validate: fn(&u32) -> bool| {
+            let mut alice = MemoryStore::new();
+            let mut bob: MemoryStore<_> = (0..1000u32).map(|i| (i, ())).collect();
+            let mut alice_session = SyncSession::default().with_session_limits(limits, |_| 10);
+            let mut bob_session = SyncSession::default();
+            let mut next = Some(bob_session.initial_message(&mut bob).unwrap());
+            while let Some(msg) = next.take() {
+                next = alice_session
+                    .process_message(
+                        &mut alice,
+                        msg,
+                        |_, (key, _), _| validate(key),
+                        |_, _, _| (),
+                        |_, _| ContentStatus::Complete,
+                    )
+                    .unwrap()
+                    .map(|msg| bob_session.process(&mut bob, msg))
+                    .transpose()
+                    .unwrap()
+                    .flatten();
+            }
+            let keys: Vec<_> = collect(alice.all().unwrap())
+                .into_iter()
+                .map(|(key, ())| key)
+                .collect();
+            (keys, alice_session)
+        };
+
+        let limits = SessionLimits {
+            max_entries_received: Some(100),
+            ..Default::default()
+        };
+        let (keys, session) = sync_with_quota(limits, |_| true);
+        assert_eq!(keys.len(), 100);
+        assert_eq!(session.truncated(), Some(Limit::EntriesReceived(100)));
+        assert_eq!(session.entries_received(), 100);
+
+        let limits = SessionLimits {
+            max_bytes_received: Some(255),
+            ..Default::default()
+        };
+        let (keys, session) = sync_with_quota(limits, |_| true);
+        assert_eq!(keys.len(), 25);
+        assert_eq!(session.truncated(), Some(Limit::BytesReceived(255)));
+        assert_eq!(session.bytes_received(), 250);
+
+        // rejected entries do not count toward the quota
+        let (keys, session) = sync_with_quota(
+            SessionLimits {
+                max_entries_received: Some(100),
+                ..Default::default()
+            },
+            |key| key % 2 == 1,
+        );
+        assert_eq!(keys.len(), 100);
+        assert!(keys.iter().all(|key| key % 2 == 1));
+        assert_eq!(session.truncated(), Some(Limit::EntriesReceived(100)));
+
+        // without exceeding the quota, the sync is complete
+        let (keys, session) = sync_with_quota(
+            SessionLimits {
+                max_entries_received: Some(500),
+                ..Default::default()
+            },
+            |key| key % 2 == 1,
+        );
+        assert_eq!(keys.len(), 500);
+        assert_eq!(session.truncated(), None);
+    }
+
+    #[test]
+    fn test_max_pending_value_bytes() {
+        let mut alice: MemoryStore<_> = (0..1000u32)
+            .filter(|i| i % 3 != 0)
+            .map(|i| (i, 1u8))
+            .collect();
+        let mut bob: MemoryStore<_> = (0..1000u32)
+            .filter(|i| i % 5 != 0)
+            .map(|i| (i, 2u8))
+            .collect();
+        let limits = SessionLimits {
+            max_pending_value_bytes: Some(100),
+            ..Default::default()
+        };
+        let mut alice_session = SyncSession::default().with_session_limits(limits, |_| 10);
+        let mut bob_session = SyncSession::default();
+        let mut next = Some(alice_session.initial_message(&mut alice).unwrap());
+        let mut rounds = 0;
+        while let Some(msg) = next.take() {
+            rounds += 1;
+            let Some(reply) = bob_session.process(&mut bob, msg).unwrap() else {
+                break;
+            };
+            // alice applies at most ten of the values of each message
+            let inserted = Cell::new(0);
+            next = alice_session
+                .process_message(
+                    &mut alice,
+                    reply,
+                    |_, _, _| true,
+                    |_, _, _| inserted.set(inserted.get() + 1),
+                    |_, _| ContentStatus::Complete,
+                )
+                .unwrap();
+            assert!(inserted.get() <= 10);
+        }
+        // the deferred values are sent again, until both stores are equal
+        assert!(alice_session.stats().entries_deferred > 0);
+        assert_eq!(alice_session.truncated(), None);
+        let expected: Vec<_> = (0..1000u32)
+            .filter(|i| i % 15 != 0)
+            .map(|i| (i, if i % 5 == 0 { 1 } else { 2 }))
+            .collect();
+        assert_eq!(collect(alice.all().unwrap()), expected);
+        assert_eq!(collect(bob.all().unwrap()), expected);
+        assert!(rounds > 10, "{rounds}");
+    }
+
+    #[test]
+    fn test_message_builder() {
+        let mut bob: MemoryStore<_> = (0..8u32).map(|i| (i, 1u8)).collect();
+        // alice has newer values for 1 and 3, and nothing from 4 on
+        let message = MessageBuilder::new()
+            .add_items(
+                Range::new(0, 4),
+                vec![
+                    ((1, 2), ContentStatus::Complete),
+                    ((3, 2), ContentStatus::Complete),
+                ],
+                false,
+            )
+            .add_fingerprint(Range::new(4, 0), Fingerprint::empty())
+            .build();
+        assert_eq!(message.value_count(), 2);
+
+        let validate = |_: &_, _: &_, _| true;
+        let response = bob
+            .process_message_reply(
+                &Default::default(),
+                message,
+                validate,
+                |_, _, _| (),
+                |_, _| ContentStatus::Complete,
+            )
+            .unwrap()
+            .unwrap();
+        let keys = |values: Vec<((u32, u8), ContentStatus)>| {
+            values
+                .into_iter()
+                .map(|(entry, _)| entry)
+                .collect::<Vec<_>>()
+        };
+        let parts = response.into_parts();
+        assert_eq!(parts.len(), 2);
+        assert!(matches!(&parts[0], MessagePart::RangeItem(item) if item.have_local));
+        assert!(matches!(&parts[1], MessagePart::RangeItem(item) if !item.have_local));
+        let values: Vec<_> = parts
+            .into_iter()
+            .map(|part| keys(part.into_values().unwrap()))
+            .collect();
+        assert_eq!(values[0], [(0, 1), (2, 1)]);
+        assert_eq!(values[1], (4..8).map(|i| (i, 1)).collect::<Vec<_>>());
+        assert_eq!(bob.get(&1).unwrap(), Some((1, 2)));
+        assert_eq!(bob.get(&3).unwrap(), Some((3, 2)));
+
+        // building takes the parts, so the builder can be reused
+        let mut builder = MessageBuilder::<(u32, u8)>::default();
+        builder.add_part(MessagePart::Cancel);
+        assert!(builder.build().is_cancel());
+        assert!(builder.is_empty());
+        assert_eq!(MessagePart::<(u32, u8)>::Cancel.into_values(), None);
+    }
+
+    #[test]
+    fn test_message_merge() {
+        let status = ContentStatus::Complete;
+        let first = MessageBuilder::new()
+            .add_fingerprint(Range::new(0, 4), Fingerprint([1; 32]))
+            .add_items(
+                Range::new(4, 8),
+                vec![((4, 1), status), ((5, 2), status)],
+                true,
+            )
+            .build();
+        let second = MessageBuilder::new()
+            .add_fingerprint(Range::new(0, 4), Fingerprint([1; 32]))
+            .add_fingerprint(Range::new(0, 4), Fingerprint([2; 32]))
+            .add_items(
+                Range::new(4, 8),
+                vec![((5, 3), status), ((6, 1), status)],
+                false,
+            )
+            .add_items(Range::new(8, 0), vec![((8, 1), status)], true)
+            .build()
+            .with_more(true);
+        let merged: Message<(u32, u8)> = Message::merge(vec![first, second]);
+        let expected = MessageBuilder::new()
+            .add_fingerprint(Range::new(0, 4), Fingerprint([1; 32]))
+            .add_items(
+                Range::new(4, 8),
+                vec![((4, 1), status), ((5, 3), status), ((6, 1), status)],
+                false,
+            )
+            .add_fingerprint(Range::new(0, 4), Fingerprint([2; 32]))
+            .add_items(Range::new(8, 0), vec![((8, 1), status)], true)
+            .build()
+            .with_more(true);
+        assert_eq!(merged, expected);
+
+        // forced normalization of every message leaves the sync unchanged
+        for (alice_set, bob_set) in paper_sets() {
+            let run = |normalize: bool| {
+                let mut alice: MemoryStore<_> = alice_set.iter().copied().collect();
+                let mut bob: MemoryStore<_> = bob_set.iter().copied().collect();
+                let mut alice_session = SyncSession::default();
+                let mut bob_session = SyncSession::default();
+                let mut next = Some(alice_session.initial_message(&mut alice).unwrap());
+                let mut rounds = 0;
+                while let Some(message) = next.take() {
+                    let message = match normalize {
+                        true => {
+                            let pages = message.clone().paginate(1);
+                            assert_eq!(Message::merge(pages), message);
+                            let merged = Message::merge(vec![message.clone(), message.clone()]);
+                            assert_eq!(merged, message);
+                            merged
+                        }
+                        false => message,
+                    };
+                    next = match rounds % 2 {
+                        0 => bob_session.process(&mut bob, message).unwrap(),
+                        _ => alice_session.process(&mut alice, message).unwrap(),
+                    };
+                    rounds += 1;
+                }
+                (
+                    collect(alice.all().unwrap()),
+                    collect(bob.all().unwrap()),
+                    rounds,
+                )
+            };
+            let expected = run(false);
+            assert_eq!(expected.0, expected.1);
+            assert_eq!(run(true), expected);
+        }
+    }
+
+    #[test]
+    fn test_session_invalid_message() {
+        let store: MemoryStore<_> = (0..8u32).map(|i| (i, 1u8)).collect();
+        let items = |range: (u32, u32), values: &[(u32, u8)]| {
+            let values = values
+                .iter()
+                .map(|entry| (*entry, ContentStatus::Complete))
+                .collect();
+            MessageBuilder::new()
+                .add_items(Range::new(range.0, range.1), values, false)
+                .build()
+        };
+        let limits = MessageLimits {
+            max_parts: 2,
+            max_values: 3,
+        };
+        let mut too_many_parts = MessageBuilder::new();
+        for i in 0..3 {
+            too_many_parts.add_fingerprint(Range::new(i, i + 1), Fingerprint::empty());
+        }
+        let mut too_many_values = items((0, 2), &[(0, 2), (1, 2)]);
+        too_many_values
+            .parts
+            .extend(items((2, 4), &[(2, 2), (3, 2)]).parts);
+        let cases = [
+            (
+                items((0, 4), &[(1, 2), (5, 2)]),
+                MessageValidationError::KeyOutOfRange {
+                    part: 0,
+                    key: "5".to_string(),
+                    range: "Range { x: 0, y: 4 }".to_string(),
+                },
+            ),
+            (
+                // the range wraps around, so it does not contain 3
+                items((6, 2), &[(7, 2), (0, 2), (3, 2)]),
+                MessageValidationError::KeyOutOfRange {
+                    part: 0,
+                    key: "3".to_string(),
+                    range: "Range { x: 6, y: 2 }".to_string(),
+                },
+            ),
+            (
+                items((0, 4), &[(1, 2), (1, 3)]),
+                MessageValidationError::DuplicateKey {
+                    part: 0,
+                    key: "1".to_string(),
+                },
+            ),
+            (
+                too_many_parts.build(),
+                MessageValidationError::TooManyParts { parts: 3, max: 2 },
+            ),
+            (
+                too_many_values,
+                MessageValidationError::TooManyValues { max: 3 },
+            ),
+        ];
+        for (message, expected) in cases {
+            assert_eq!(message.validate(&limits), Err(expected.clone()));
+            let mut bob = store.clone();
+            let mut session = SyncSession::default().with_message_limits(limits);
+            let err = session.process(&mut bob, message).unwrap_err();
+            assert!(
+                matches!(err, SyncError::Protocol(ProtocolError::InvalidMessage(ref err)) if *err == expected),
+                "{err:?}"
+            );
+            // the store was not touched
+            assert_eq!(bob, store);
+        }
+
+        // messages at the limits are valid
+        let mut at_limit = MessageBuilder::<(u32, u8)>::new();
+        for i in 0..2 {
+            at_limit.add_fingerprint(Range::new(i, i + 1), Fingerprint::empty());
+        }
+        at_limit.build().validate(&limits).unwrap();
+        items((0, 4), &[(0, 2), (1, 2), (2, 2)])
+            .validate(&limits)
+            .unwrap();
+
+        // values in a range that wraps around are valid
+        let message = items((6, 2), &[(7, 2), (0, 2), (1, 2)]);
+        message.validate(&limits).unwrap();
+        let mut bob = store.clone();
+        SyncSession::default().process(&mut bob, message).unwrap();
+        assert_eq!(bob.get(&7).unwrap(), Some((7, 2)));
+    }
+
+    #[test]
+    fn test_framed() {
+        let alice: MemoryStore<_> = (0..100u32).map(|i| (i, i as u8)).collect();
+        let bob: MemoryStore<_> = (50..150u32).map(|i| (i, 1u8)).collect();
+        let validate = |_: &_, _: &_, _| true;
+        let res = sync_exchange_messages(alice, bob, validate, validate, 100);
+        let messages: Vec<_> = res
+            .alice_to_bob
+            .into_iter()
+            .chain(res.bob_to_alice)
+            .collect();
+        let mut buf = Vec::new();
+        for message in &messages {
+            message.encode_framed(&mut buf).unwrap();
+        }
+
+        // frames are decoded one after the other
+        let mut rest = &buf[..];
+        for message in &messages {
+            let (decoded, len) = Message::<(u32, u8)>::decode_framed(rest, usize::MAX).unwrap();
+            assert_eq!(&decoded, message);
+            assert_eq!(len, message.encoded_size() + 4);
+            // a truncated frame needs more bytes
+            for i in 0..len {
+                let err = Message::<(u32, u8)>::decode_framed(&rest[..i], usize::MAX).unwrap_err();
+                let expected = if i < 4 { 4 } else { len };
+                assert!(matches!(err, DecodeError::Incomplete { needed } if needed == expected));
+            }
+            // the size limit applies to the body
+            let body = len - 4;
+            Message::<(u32, u8)>::decode_framed(rest, body).unwrap();
+            let err = Message::<(u32, u8)>::decode_framed(rest, body - 1).unwrap_err();
+            assert!(
+                matches!(err, DecodeError::TooLarge { len, max } if len == body && max == body - 1)
+            );
+            rest = &rest[len..];
+        }
+        assert!(rest.is_empty());
+
+        // an oversized frame is rejected from its prefix alone
+        let err =
+            Message::<(u32, u8)>::decode_framed(&u32::MAX.to_le_bytes(), 1 << 24).unwrap_err();
+        assert!(matches!(err, DecodeError::TooLarge { len, .. } if len == u32::MAX as usize));
+
+        // a body that is not a message
+        let mut garbage = 2u32.to_le_bytes().to_vec();
+        garbage.extend([0x01, 0x7f]);
+        let err = Message::<(u32, u8)>::decode_framed(&garbage, 1 << 24).unwrap_err();
+        assert!(matches!(err, DecodeError::Encoding(_)));
+    }
+
+    #[test]
+    fn test_message_parts() {
+        type Parts<'a> = MessagePartReader<&'a [u8], (u32, u8)>;
+
+        let alice: MemoryStore<_> = (0..100u32).map(|i| (i, i as u8)).collect();
+        let bob: MemoryStore<_> = (50..150u32).map(|i| (i, 1u8)).collect();
+        let validate = |_: &_, _: &_, _| true;
+        let res = sync_exchange_messages(alice.clone(), bob.clone(), validate, validate, 100);
+        let mut messages: Vec<_> = res
+            .alice_to_bob
+            .into_iter()
+            .chain(res.bob_to_alice)
+            .collect();
+        messages.push(messages[0].clone().with_more(true));
+        let mut buf = Vec::new();
+        for message in &messages {
+            message.encode_parts(&mut buf).unwrap();
+        }
+
+        // parts are read one message after the other, with the flag as a trailing part
+        let mut rest = &buf[..];
+        for message in &messages {
+            let mut parts = Parts::new(rest, usize::MAX);
+            let decoded: Vec<_> = parts.by_ref().collect::<Result<_, _>>().unwrap();
+            assert!(parts.is_done());
+            let mut expected = message.parts().to_vec();
+            if message.has_more() {
+                expected.push(MessagePart::More);
+            }
+            assert_eq!(decoded, expected);
+            rest = parts.into_inner();
+        }
+        assert!(rest.is_empty());
+
+        // a stream that ends before the end of the message, an oversized part and a part that
+        // cannot be decoded end the parts with an error
+        let mut buf = Vec::new();
+        messages[0].encode_parts(&mut buf).unwrap();
+        let mut parts = Parts::new(&buf[..buf.len() - 1], usize::MAX);
+        assert!(parts.next().unwrap().is_ok());
+        let err = parts.next().unwrap().unwrap_err();
+        assert!(
+            matches!(err, DecodeError::Io(ref err) if err.kind() == io::ErrorKind::UnexpectedEof)
+        );
+        assert!(parts.next().is_none());
+        let mut parts = Parts::new(&buf, 1);
+        let err = parts.next().unwrap().unwrap_err();
+        assert!(matches!(err, DecodeError::TooLarge { max: 1, .. }));
+        assert!(parts.next().is_none());
+        let garbage = [2, 0, 0, 0, 0x7f, 0x7f];
+        let err = Parts::new(&garbage, usize::MAX)
+            .next()
+            .unwrap()
+            .unwrap_err();
+        assert!(matches!(err, DecodeError::Encoding(_)));
+
+        // processing the parts of each message as they are read is the same as processing the
+        // messages
+        let run = |parts: bool| {
+            let (mut alice, mut bob) = (alice.clone(), bob.clone());
+            let mut sent = Vec::new();
+            let mut next = Some(alice.initial_message().unwrap());
+            while let Some(message) = next.take() {
+                let store = match sent.len() % 2 {
+                    0 => &mut bob,
+                    _ => &mut alice,
+                };
+                let config = SyncConfig::default();
+                let content_status = |_: &_, _: &_| ContentStatus::Complete;
+                next = match parts {
+                    true => {
+                        let mut buf = Vec::new();
+                        message.encode_parts(&mut buf).unwrap();
+                        let parts = Parts::new(&buf, usize::MAX);
+                        store
+                            .process_parts(&config, parts, validate, |_, _, _| (), content_status)
+                            .unwrap()
+                    }
+                    false => store
+                        .process_message_reply(
+                            &config,
+                            message.clone(),
+                            validate,
+                            |_, _, _| (),
+                            content_status,
+                        )
+                        .unwrap(),
+                };
+                sent.push(message);
+            }
+            (sent, alice, bob)
+        };
+        let (sent, alice, bob) = run(true);
+        assert_eq!((sent.clone(), alice.clone(), bob.clone()), run(false));
+        assert!(sent.len() > 4);
+        assert_eq!(alice, bob);
+    }
+
+    /// Syncs the stores, and returns the encoded messages of both sides, in the order they were
+    /// sent by each side.
+    fn sync_transcript(alice: MemoryStore<(u32, u8)>, bob: MemoryStore<(u32, u8)>) -> Vec<Vec<u8>> {
+        let res = sync_exchange_messages(alice, bob, |_, _, _| true, |_, _, _| true, 100);
+        for message in res.alice_to_bob.iter().chain(&res.bob_to_alice) {
+            let order: Vec<_> = message.parts().iter().map(reply_order).collect();
+            assert!(order.windows(2).all(|w| w[0] <= w[1]), "{message:?}");
+        }
+        res.alice_to_bob
+            .iter()
+            .chain(&res.bob_to_alice)
+            .map(|message| message.encode().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_deterministic_transcript() {
+        let alice: Vec<_> = (0..200u32)
+            .filter(|i| i % 7 != 0)
+            .map(|i| (i, 1u8))
+            .collect();
+        let bob: Vec<_> = (0..200u32)
+            .filter(|i| i % 11 != 0)
+            .map(|i| (i, 2u8))
+            .collect();
+        let stores = |alice: &[(u32, u8)], bob: &[(u32, u8)]| {
+            (
+                alice.iter().copied().collect::<MemoryStore<_>>(),
+                bob.iter().copied().collect::<MemoryStore<_>>(),
+            )
+        };
+        let (alice_store, bob_store) = stores(&alice, &bob);
+        let transcript = sync_transcript(alice_store.clone(), bob_store.clone());
+        assert!(transcript.len() > 4);
+        assert_eq!(sync_transcript(alice_store, bob_store), transcript);
+
+        // the order the entries were inserted in does not matter
+        let rev = |entries: &[(u32, u8)]| entries.iter().rev().copied().collect::<Vec<_>>();
+        let (alice_store, bob_store) = stores(&rev(&alice), &rev(&bob));
+        assert_eq!(sync_transcript(alice_store, bob_store), transcript);
+    }
+
+    #[proptest]
+    fn deterministic_transcript(
+        #[strategy(proptest::collection::vec(any::<(u32, u8)>(), 0..64))] alice: Vec<(u32, u8)>,
+        #[strategy(proptest::collection::vec(any::<(u32, u8)>(), 0..64))] bob: Vec<(u32, u8)>,
+    ) {
+        let alice: MemoryStore<_> = alice.into_iter().collect();
+        let bob: MemoryStore<_> = bob.into_iter().collect();
+        let transcript = sync_transcript(alice.clone(), bob.clone());
+        prop_assert_eq!(sync_transcript(alice, bob), transcript);
+    }
+
+    #[test]
+    fn test_message_parts_order() {
+        let mut bob: MemoryStore<_> = [(1u32, 1u8), (2, 1)].into_iter().collect();
+        let mut full = bob.clone();
+        full.put((3, 1)).unwrap();
+        let all = Range::new(0, 0);
+        let process = |store: &mut MemoryStore<_>, message: &Message<_>, parts: bool| {
+            let config = SyncConfig::default();
+            let (validate, content_status) = (
+                |_: &_, _: &_, _| true,
+                |_: &_, _: &_| ContentStatus::Complete,
+            );
+            match parts {
+                true => store
+                    .process_parts(
+                        &config,
+                        message.parts().iter().cloned().map(Ok),
+                        validate,
+                        |_, _, _| (),
+                        content_status,
+                    )
+                    .unwrap(),
+                false => store
+                    .process_message_reply(
+                        &config,
+                        message.clone(),
+                        validate,
+                        |_, _, _| (),
+                        content_status,
+                    )
+                    .unwrap(),
+            }
+        };
+
+        // a fingerprint that only matches once the item after it was stored
+        let message = MessageBuilder::new()
+            .add_fingerprint(all, full.get_fingerprint(&all).unwrap())
+            .add_items(
+                Range::new(3, 4),
+                vec![((3, 1), ContentStatus::Complete)],
+                true,
+            )
+            .build();
+        for parts in [false, true] {
+            let mut bob = bob.clone();
+            assert_eq!(process(&mut bob, &message, parts), None);
+            assert_eq!(bob, full);
+        }
+        // processed in the order of the parts, the fingerprint would not match
+        let fingerprint = Message {
+            parts: message.parts()[..1].to_vec(),
+            more: false,
+        };
+        assert!(process(&mut bob.clone(), &fingerprint, true).is_some());
+
+        // the match of a handshake comes before the answers to items, and those come before the
+        // answers to fingerprints, regardless of the order of the parts
+        let message = MessageBuilder::new()
+            .add_fingerprint(Range::new(2, 1), Fingerprint::empty())
+            .add_items(Range::new(1, 2), vec![], false)
+            .add_part(MessagePart::Handshake(bob.full_fingerprint().unwrap()))
+            .build();
+        let expected = process(&mut bob.clone(), &message, false).unwrap();
+        assert!(matches!(
+            expected.parts(),
+            [
+                MessagePart::HandshakeMatch,
+                MessagePart::RangeItem(_),
+                MessagePart::RangeItem(_)
+            ]
+        ));
+        assert_eq!(process(&mut bob, &message, true), Some(expected));
+
+        // a cancelled message is not answered
+        let cancel = Message::cancel();
+        assert_eq!(process(&mut full.clone(), &cancel, true), None);
+    }
+
+    #[proptest]
+    fn test_framed_garbage(
+        #[strategy(0u32..64)] prefix: u32,
+        #[strategy(proptest::collection::vec(any::<u8>(), 0..64))] body: Vec<u8>,
+        #[strategy(0usize..72)] cut: usize,
+        #[strategy(0usize..64)] max_size: usize,
+    ) {
+        let mut bytes = prefix.to_le_bytes().to_vec();
+        bytes.extend(body);
+        bytes.truncate(cut);
+        match Message::<(String, u8)>::decode_framed(&bytes, max_size) {
+            Ok((_, len)) => prop_assert!(len == prefix as usize + 4 && len <= bytes.len()),
+            Err(DecodeError::Incomplete { needed }) => prop_assert!(needed > bytes.len()),
+            Err(DecodeError::TooLarge { len, max }) => {
+                prop_assert!(len == prefix as usize && len > max && max == max_size)
+            }
+            Err(DecodeError::Encoding(_)) => prop_assert!(bytes.len() >= prefix as usize + 4),
+            Err(DecodeError::Io(err)) => prop_assert!(false, "unexpected io error: {err}"),
+        }
+    }
+
+    #[test]
+    fn test_encoded_size() {
+        let mut alice: MemoryStore<_> = (0..100u32).map(|i| (i, i as u8)).collect();
+        let mut bob: MemoryStore<_> = (50..150u32).map(|i| (i, 1u8)).collect();
+        let mut messages = vec![
+            Message {
+                parts: Vec::new(),
+                more: false,
+            },
+            Message::cancel(),
+            alice.initial_message().unwrap(),
+            alice.handshake_message().unwrap(),
+            Message {
+                parts: vec![
+                    MessagePart::SessionId(SessionId::random()),
+                    MessagePart::Sequence(u64::MAX),
+                    MessagePart::Reopen,
+                    MessagePart::HandshakeMatch,
+                ],
+                more: false,
+            },
+        ];
+        let validate = |_: &_, _: &_, _| true;
+        let res = sync_exchange_messages(alice.clone(), bob.clone(), validate, validate, 100);
+        messages.extend(res.alice_to_bob);
+        messages.extend(res.bob_to_alice);
+        // many parts, so the number of parts takes more than one byte
+        let parts = collect(bob.all().unwrap())
+            .into_iter()
+            .map(|entry| {
+                MessagePart::RangeItem(RangeItem {
+                    range: Range::new(entry.0, entry.0 + 1),
+                    values: vec![(entry, ContentStatus::Complete)],
+                    have_local: false,
+                })
+            })
+            .collect();
+        messages.push(Message { parts, more: false });
+
+        for msg in &messages {
+            assert_eq!(
+                msg.encoded_size(),
+                postcard::to_allocvec(msg).unwrap().len()
+            );
+            for part in msg.parts() {
+                assert_eq!(
+                    part.encoded_size(),
+                    postcard::to_allocvec(part).unwrap().len()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_session_message_budget() {
+        let new_bob = || -> MemoryStore<_> { (0..500u32).map(|i| (i, ())).collect() };
+        // a mismatching fingerprint for many ranges, so the response has many parts
+        let parts = (0..50)
+            .map(|i| {
+                MessagePart::RangeFingerprint(RangeFingerprint {
+                    range: Range::new(i * 10, i * 10 + 10),
+                    fingerprint: Fingerprint::empty(),
+                })
+            })
+            .collect();
+        let msg = Message { parts, more: false };
+        let process = |session: &mut SyncSession<_>, store: &mut MemoryStore<_>, msg| {
+            session.process(store, msg).unwrap()
+        };
+
+        let expected = process(&mut SyncSession::default(), &mut new_bob(), msg.clone()).unwrap();
+        let mut session = SyncSession::default().with_max_message_bytes(64);
+        let mut messages = vec![process(&mut session, &mut new_bob(), msg).unwrap()];
+        while let Some(msg) = session.poll_pending_message() {
+            messages.push(msg);
+        }
+        assert!(messages.len() > 10);
+        for msg in &messages {
+            assert!(postcard::to_stdvec(msg).unwrap().len() <= 64);
+        }
+        let parts: Vec<_> = messages.into_iter().flat_map(|msg| msg.parts).collect();
+        assert_eq!(parts, expected.parts);
+
+        // a sync with split responses converges
+        let mut alice: MemoryStore<_> = (0..500u32).map(|i| (i * 2, ())).collect();
+        let mut bob: MemoryStore<_> = (0..500u32).map(|i| (i * 3, ())).collect();
+        let mut alice_session = SyncSession::default().with_max_message_bytes(64);
+        let mut bob_session = SyncSession::default().with_max_message_bytes(64);
+        let mut to_bob = VecDeque::from([alice_session.initial_message(&mut alice).unwrap()]);
+        let mut to_alice = VecDeque::new();
+        while !to_bob.is_empty() || !to_alice.is_empty() {
+            if let Some(msg) = to_bob.pop_front() {
+                to_alice.extend(process(&mut bob_session, &mut bob, msg));
+                to_alice.extend(std::iter::from_fn(|| bob_session.poll_pending_message()));
+            }
+            if let Some(msg) = to_alice.pop_front() {
+                to_bob.extend(process(&mut alice_session, &mut alice, msg));
+                to_bob.extend(std::iter::from_fn(|| alice_session.poll_pending_message()));
+            }
+        }
+        assert_eq!(collect(alice.all().unwrap()).len(), 833);
+        assert_eq!(collect(alice.all().unwrap()), collect(bob.all().unwrap()));
+    }
+
+    #[test]
+    fn test_session_priority_ranges() {
+        // every other block of ten keys has priority, given in reverse order
+        let priority: Vec<_> = (0..10u32)
+            .rev()
+            .map(|i| Range::new(i * 20 + 10, i * 20 + 20))
+            .collect();
+        let is_priority = |key: &u32| priority.iter().any(|range| range.contains(key));
+        let mut alice: MemoryStore<_> = (0..200u32).map(|i| (i, ())).collect();
+        let mut bob: MemoryStore<_> = (0..200u32).step_by(7).map(|i| (i, ())).collect();
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let config = SyncConfig::default().with_send_threshold(SendThreshold::Entries(16));
+        let session = || {
+            SyncSession::new(config)
+                .with_max_message_bytes(64)
+                .with_priority_ranges(priority.clone())
+        };
+        let mut alice_session = session();
+        let mut bob_session = session().with_on_insert({
+            let received = received.clone();
+            move |entry: &(u32, ()), _| received.lock().unwrap().push(entry.0)
+        });
+
+        // the initial message has the priority ranges first, followed by the ranges between
+        // them, which together cover the whole set
+        let initial = alice_session.initial_message(&mut alice).unwrap();
+        let ranges: Vec<_> = initial
+            .parts()
+            .iter()
+            .map(|part| match part {
+                MessagePart::RangeFingerprint(fp) => fp.range,
+                part => panic!("expected a fingerprint, got {part:?}"),
+            })
+            .collect();
+        let mut expected: Vec<_> = priority.iter().rev().copied().collect();
+        expected.extend((1..10u32).map(|i| Range::new(i * 20, i * 20 + 10)));
+        expected.push(Range::new(200, 10));
+        assert_eq!(ranges, expected);
+
+        let mut to_bob = VecDeque::from([initial]);
+        let mut to_alice = VecDeque::new();
+        while !to_bob.is_empty() || !to_alice.is_empty() {
+            if let Some(msg) = to_bob.pop_front() {
+                to_alice.extend(bob_session.process(&mut bob, msg).unwrap());
+                to_alice.extend(std::iter::from_fn(|| bob_session.poll_pending_message()));
+            }
+            if let Some(msg) = to_alice.pop_front() {
+                to_bob.extend(alice_session.process(&mut alice, msg).unwrap());
+                to_bob.extend(std::iter::from_fn(|| alice_session.poll_pending_message()));
+            }
+        }
+        assert_eq!(collect(alice.all().unwrap()), collect(bob.all().unwrap()));
+
+        // bob received all entries in the priority ranges before any other entry
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 200 - 29);
+        let first_other = received.iter().position(|key| !is_priority(key)).unwrap();
+        assert!(first_other > 0);
+        assert!(received[first_other..].iter().all(|key| !is_priority(key)));
+    }
+
+    #[test]
+    fn test_session_live_updates() {
+        type Session = SyncSession<(u32, u8)>;
+        /// Exchanges messages starting with `message` from alice, until neither side responds.
+        fn exchange(
+            alice: (&mut Session, &mut MemoryStore<(u32, u8)>),
+            bob: (&mut Session, &mut MemoryStore<(u32, u8)>),
+            message: Message<(u32, u8)>,
+        ) {
+            let mut sides = [bob, alice];
+            let mut next = Some(message);
+            let mut rounds = 0;
+            while let Some(message) = next.take() {
+                let (session, store) = &mut sides[rounds % 2];
+                next = session.process(*store, message).unwrap();
+                rounds += 1;
+            }
+        }
+
+        let mut alice: MemoryStore<_> = (0..100u32).map(|i| (i, 1)).collect();
+        let mut bob: MemoryStore<_> = (50..150u32).map(|i| (i, 1)).collect();
+        let mut alice_session = SyncSession::default()
+            .with_live_updates()
+            .with_sequence_numbers();
+        let mut bob_session = SyncSession::default()
+            .with_live_updates()
+            .with_validator(|entry: &(u32, u8), _| entry.1 != 0);
+        let initial = alice_session.initial_message(&mut alice).unwrap();
+        exchange(
+            (&mut alice_session, &mut alice),
+            (&mut bob_session, &mut bob),
+            initial,
+        );
+        assert_eq!(collect(alice.all().unwrap()), collect(bob.all().unwrap()));
+        let rounds = (alice_session.rounds(), bob_session.rounds());
+        let fingerprints_sent = (
+            alice_session.stats().fingerprint_parts_sent,
+            bob_session.stats().fingerprint_parts_sent,
+        );
+
+        // both sides write after the sync, and push their writes to each other
+        for i in 0..20u32 {
+            let (session, store, remote_session, remote_store) = match i % 2 {
+                0 => (&mut alice_session, &mut alice, &mut bob_session, &mut bob),
+                _ => (&mut bob_session, &mut bob, &mut alice_session, &mut alice),
+            };
+            for entry in [(200 + i, 1), (i, 2)] {
+                store.put(entry).unwrap();
+                session.local_put(entry);
+            }
+            let update = session.take_update_message().unwrap();
+            assert!(session.take_update_message().is_none());
+            assert!(remote_session
+                .process(remote_store, update)
+                .unwrap()
+                .is_none());
+            assert_eq!(collect(alice.all().unwrap()), collect(bob.all().unwrap()));
+        }
+        assert_eq!(alice.len().unwrap(), 170);
+        // the updates were applied without another reconciliation
+        assert_eq!((alice_session.rounds(), bob_session.rounds()), rounds);
+        assert_eq!(
+            (
+                alice_session.stats().fingerprint_parts_sent,
+                bob_session.stats().fingerprint_parts_sent,
+            ),
+            fingerprints_sent
+        );
+        assert!(!alice_session.has_drifted() && !bob_session.has_drifted());
+
+        // a lost update, and a rejected entry, let the stores drift apart
+        for entry in [(300, 1), (301, 0)] {
+            alice.put(entry).unwrap();
+            alice_session.local_put(entry);
+            let update = alice_session.take_update_message().unwrap();
+            if entry.0 == 301 {
+                assert!(bob_session.process(&mut bob, update).unwrap().is_none());
+            }
+        }
+        assert!(bob_session.has_drifted());
+        assert_eq!(bob.get(&300).unwrap(), None);
+
+        // which a new reconciliation of the whole set repairs, as far as bob accepts
+        let resync = bob_session.resync_message(&mut bob).unwrap().unwrap();
+        assert!(!bob_session.has_drifted());
+        exchange(
+            (&mut bob_session, &mut bob),
+            (&mut alice_session, &mut alice),
+            resync,
+        );
+        assert_eq!(bob.get(&300).unwrap(), Some((300, 1)));
+        assert_eq!(bob.get(&301).unwrap(), None);
+        assert_eq!(bob.len().unwrap(), alice.len().unwrap() - 1);
+    }
+
+    #[test]
+    fn test_session_filter() {
+        type Entry = (String, u8);
+        // two collections share each store, and only the first is synced
+        let stores = |n: usize, offset: usize, value: u8| {
+            let entries = ["a", "b"].into_iter().flat_map(move |collection| {
+                (0..n).map(move |i| (format!("{collection}/{:03}", i * 2 + offset), value))
+            });
+            entries.collect::<MemoryStore<Entry>>()
+        };
+        let filter = SessionFilter::new(|key: &String| key.starts_with("a/")).strict();
+        let mut alice = filter.store(stores(40, 0, 1));
+        let mut bob = filter.store(stores(40, 1, 2));
+        let mut alice_session = SyncSession::default().with_filter(filter.clone());
+        let mut bob_session = SyncSession::default().with_filter(filter.clone());
+
+        let mut transcript = Vec::new();
+        let mut next = Some(alice_session.initial_message(&mut alice).unwrap());
+        let mut rounds = 0;
+        while let Some(message) = next.take() {
+            transcript.push(message.clone());
+            next = match rounds % 2 {
+                0 => bob_session.process(&mut bob, message).unwrap(),
+                _ => alice_session.process(&mut alice, message).unwrap(),
+            };
+            rounds += 1;
+        }
+        assert!(rounds > 2);
+        for message in &transcript {
+            assert!(message.values().all(|(entry, _)| entry.0.starts_with("a/")));
+        }
+        let synced =
+            |store: &mut KeyFilteredStore<MemoryStore<Entry>, Entry>| collect(store.all().unwrap());
+        assert_eq!(synced(&mut alice), synced(&mut bob));
+        assert_eq!(synced(&mut alice).len(), 80);
+        // the other collection is untouched on both sides
+        let others = |store: KeyFilteredStore<MemoryStore<Entry>, Entry>| {
+            let entries = collect(store.into_inner().all().unwrap());
+            entries
+                .into_iter()
+                .filter(|(key, _)| !key.starts_with("a/"))
+                .collect::<Vec<_>>()
+        };
+        let b_only = |offset, value| {
+            (0..40)
+                .map(|i| (format!("b/{:03}", i * 2 + offset), value))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(others(alice), b_only(0, 1));
+        assert_eq!(others(bob), b_only(1, 2));
+
+        // a remote that sends entries of the other collection violates the protocol in strict
+        // mode, and the store is not changed
+        let message = |key: &str| {
+            MessageBuilder::new()
+                .add_items(
+                    Range::new(key.to_string(), key.to_string()),
+                    vec![((key.to_string(), 3), ContentStatus::Complete)],
+                    true,
+                )
+                .build()
+        };
+        let mut store = filter.store(MemoryStore::<Entry>::default());
+        let mut session = SyncSession::default().with_filter(filter.clone());
+        let err = session.process(&mut store, message("b/x")).unwrap_err();
+        assert!(matches!(
+            err.as_protocol(),
+            Some(ProtocolError::KeyOutsideFilter { .. })
+        ));
+        assert_eq!(store.get(&"b/x".to_string()).unwrap(), None);
+
+        // otherwise such entries are rejected
+        let lenient = SessionFilter::new(|key: &String| key.starts_with("a/"));
+        let mut session = SyncSession::default().with_filter(lenient);
+        let mut events = session.subscribe();
+        assert!(session
+            .process(&mut store, message("b/x"))
+            .unwrap()
+            .is_none());
+        assert_eq!(store.get(&"b/x".to_string()).unwrap(), None);
+        assert_eq!(session.stats().entries_rejected, 1);
+        assert_eq!(
+            events.try_recv().unwrap(),
+            SyncEvent::EntryRejected {
+                key: "b/x".to_string(),
+                reason: RejectReason::Filtered
+            }
+        );
+    }
+
+    #[test]
+    fn test_anti_entropy_planner() {
+        type Entry = (u32, u64);
+        let store = |keys: std::ops::Range<u32>, value: u64| {
+            keys.map(|key| (key, value)).collect::<MemoryStore<Entry>>()
+        };
+        let ranges = |planner: &mut AntiEntropyPlanner<Entry>, store: &mut MemoryStore<Entry>| {
+            (0..20)
+                .map(|_| planner.next_range(store).unwrap())
+                .collect::<Vec<_>>()
+        };
+
+        // the same seed gives the same ranges, other seeds start elsewhere
+        let mut alice = store(0..100, 1);
+        let seeded = |seed| AntiEntropyPlanner::new(SliceSize::Entries(7), seed);
+        let planned = ranges(&mut seeded(3), &mut alice);
+        let replanned = ranges(&mut seeded(3), &mut alice);
+        assert_eq!(planned, replanned);
+        assert!((4..10).any(|seed| seeded(seed).next_range(&mut alice).unwrap() != planned[0]));
+
+        // successive ranges are adjacent, and cover the whole store within 15 ticks
+        for (slice, ticks) in [(SliceSize::Entries(7), 15), (SliceSize::Fraction(0.1), 10)] {
+            let mut planner = AntiEntropyPlanner::new(slice, 5);
+            let planned: Vec<_> = (0..ticks)
+                .map(|_| planner.next_range(&mut alice).unwrap())
+                .collect();
+            assert_eq!(planner.ticks(), ticks as u64);
+            for pair in planned.windows(2) {
+                assert_eq!(pair[0].y(), pair[1].x());
+                assert!(!pair[0].is_all());
+            }
+            for key in 0..100 {
+                assert!(planned.iter().any(|range| range.contains(&key)), "{key}");
+            }
+        }
+
+        // entries written or removed between ticks do not break the cycle
+        let mut planner = AntiEntropyPlanner::new(SliceSize::Entries(10), 7);
+        let first = planner.next_range(&mut alice).unwrap();
+        let mut planned = vec![first];
+        for key in 100..150 {
+            alice.put((key, 1)).unwrap();
+        }
+        for key in (0..100).step_by(3) {
+            alice.entry_remove(&key).unwrap();
+        }
+        // 116 entries remain, so 12 more ticks reach the start of the first range
+        for _ in 0..12 {
+            planned.push(planner.next_range(&mut alice).unwrap());
+        }
+        for pair in planned.windows(2) {
+            assert_eq!(pair[0].y(), pair[1].x());
+        }
+        for (key, _) in collect(alice.all().unwrap()) {
+            assert!(planned.iter().any(|range| range.contains(&key)), "{key}");
+        }
+
+        // small and empty stores are reconciled as a whole
+        let mut small = store(0..5, 1);
+        assert!(planner.next_range(&mut small).unwrap().is_all());
+        let mut empty = MemoryStore::<Entry>::default();
+        assert!(planner.next_range(&mut empty).unwrap().is_all());
+
+        // each tick syncs a slice, until both stores are equal
+        let mut alice = store(0..60, 1);
+        let mut bob = store(40..100, 2);
+        let mut planner = AntiEntropyPlanner::new(SliceSize::Fraction(0.25), 11);
+        for _ in 0..4 {
+            let mut alice_session = SyncSession::default();
+            let mut bob_session = SyncSession::default();
+            let message = planner.initial_message(&mut alice_session, &mut alice);
+            let mut next = Some(message.unwrap());
+            let part = next.as_ref().unwrap().parts[0].clone();
+            let MessagePart::RangeFingerprint(RangeFingerprint { range, .. }) = part else {
+                panic!("expected a fingerprint");
+            };
+            let mut rounds = 0;
+            while let Some(message) = next.take() {
+                for (entry, _) in message.values() {
+                    assert!(range.contains(&entry.0));
                 }
+                next = match rounds % 2 {
+                    0 => bob_session.process(&mut bob, message).unwrap(),
+                    _ => alice_session.process(&mut alice, message).unwrap(),
+                };
+                rounds += 1;
+            }
+        }
+        assert_eq!(collect(alice.all().unwrap()), collect(bob.all().unwrap()));
+        assert_eq!(planner.ticks(), 4);
+    }
+
+    #[test]
+    fn test_session_verify_only() {
+        type Entry = (u32, u64);
+        let config = SyncConfig::default()
+            .with_mode(SyncMode::VerifyOnly)
+            .with_thresholds(4, usize::MAX)
+            .unwrap();
+        let verify = |alice: &mut MemoryStore<Entry>, bob: &mut MemoryStore<Entry>| {
+            let mut alice_session = SyncSession::new(config);
+            let mut bob_session = SyncSession::new(config);
+            let mut next = Some(alice_session.initial_message(alice).unwrap());
+            let mut rounds = 0;
+            while let Some(message) = next.take() {
+                assert_eq!(message.value_count(), 0);
+                next = match rounds % 2 {
+                    0 => bob_session.process(bob, message).unwrap(),
+                    _ => alice_session.process(alice, message).unwrap(),
+                };
+                rounds += 1;
+            }
+            let report = alice_session.verification_report().unwrap();
+            let remote = bob_session.verification_report().unwrap();
+            assert_eq!(remote.divergent_ranges, report.divergent_ranges);
+            report
+        };
+
+        // identical stores
+        let mut alice: MemoryStore<Entry> = (0..500).map(|key| (key, 1)).collect();
+        let mut bob: MemoryStore<Entry> = (0..500).map(|key| (key, 1)).collect();
+        let report = verify(&mut alice, &mut bob);
+        assert!(report.identical);
+        assert!(report.divergent_ranges.is_empty());
+
+        // plant divergent keys: changed, missing on either side, and only on bob
+        bob.put((17, 2)).unwrap();
+        bob.put((250, 2)).unwrap();
+        bob.entry_remove(&101).unwrap();
+        alice.entry_remove(&333).unwrap();
+        bob.put((600, 1)).unwrap();
+        let planted = [17, 101, 250, 333, 600];
+        let before = (collect(alice.all().unwrap()), collect(bob.all().unwrap()));
+        let report = verify(&mut alice, &mut bob);
+        assert!(!report.identical);
+        assert!(report.depth_reached > 0);
+        let divergent = &report.divergent_ranges;
+        for key in planted {
+            assert!(divergent.iter().any(|range| range.contains(&key)), "{key}");
+        }
+        assert!(divergent.len() <= planted.len());
+        for range in divergent {
+            assert!(alice.get_range_len(*range).unwrap() <= 4);
+            assert!(bob.get_range_len(*range).unwrap() <= 4);
+        }
+        // nothing was stored on either side
+        assert_eq!(
+            (collect(alice.all().unwrap()), collect(bob.all().unwrap())),
+            before
+        );
+
+        // other sessions do not report
+        assert!(SyncSession::<Entry>::default()
+            .verification_report()
+            .is_none());
+    }
+
+    #[test]
+    fn test_session_value_chunks() {
+        use rand::{seq::SliceRandom, SeedableRng};
+        type Entry = (u32, Vec<u8>);
+        const BUDGET: usize = 256;
+
+        /// Decodes and processes the encoded `messages`, and returns the encoded responses,
+        /// together with the number of value chunks in them.
+        fn deliver(
+            session: &mut SyncSession<Entry>,
+            store: &mut MemoryStore<Entry>,
+            messages: Vec<Vec<u8>>,
+        ) -> (Vec<Vec<u8>>, usize) {
+            let mut responses = Vec::new();
+            for bytes in messages {
+                let message = session.decode_message(&bytes).unwrap();
+                responses.extend(session.process(store, message).unwrap());
+                responses.extend(std::iter::from_fn(|| session.poll_pending_message()));
             }
+            let chunks = responses
+                .iter()
+                .flat_map(|message| message.parts())
+                .filter(|part| matches!(part, MessagePart::ValueChunk { .. }))
+                .count();
+            let responses = responses
+                .iter()
+                .map(|message| {
+                    assert!(message.encoded_size() <= BUDGET);
+                    session.encode_message(message).unwrap()
+                })
+                .collect();
+            (responses, chunks)
+        }
+
+        let large = |seed: u8| (0..4096u32).map(|i| (i as u8) ^ seed).collect::<Vec<_>>();
+        let session = || SyncSession::default().with_max_message_bytes(BUDGET);
+
+        // entries larger than the budget are synced in both directions
+        let mut alice: MemoryStore<Entry> = (0..20).map(|key| (key, vec![key as u8])).collect();
+        alice.put((100, large(1))).unwrap();
+        let mut bob: MemoryStore<Entry> = (10..30).map(|key| (key, vec![key as u8])).collect();
+        bob.put((200, large(2))).unwrap();
+        let mut alice_session = session();
+        let mut bob_session = session();
+        let initial = alice_session.initial_message(&mut alice).unwrap();
+        let mut next = vec![alice_session.encode_message(&initial).unwrap()];
+        let mut chunks = 0;
+        let mut rounds = 0;
+        while !next.is_empty() {
+            let (responses, sent) = match rounds % 2 {
+                0 => deliver(&mut bob_session, &mut bob, next),
+                _ => deliver(&mut alice_session, &mut alice, next),
+            };
+            next = responses;
+            chunks += sent;
+            rounds += 1;
         }
-
-        // Process item messages
-        for RangeItem {
-            range,
-            values,
-            have_local,
-        } in items
-        {
-            let diff: Option<Vec<_>> = if have_local {
-                None
-            } else {
-                Some({
-                    // we get the range of the item form our store. from this set, we remove all
-                    // entries that whose key is contained in the peer's set and where our value is
-                    // lower than the peer entry's value.
-                    let items = self
-                        .get_range(range.clone())?
-                        .filter_map(|our_entry| match our_entry {
-                            Ok(our_entry) => {
-                                if !values.iter().any(|(their_entry, _)| {
-                                    our_entry.key() == their_entry.key()
-                                        && their_entry.value() >= our_entry.value()
-                                }) {
-                                    Some(Ok(our_entry))
-                                } else {
-                                    None
-                                }
-                            }
-                            Err(err) => Some(Err(err)),
-                        })
-                        .collect::<Result<Vec<_>, _>>()?;
-                    // add the content status in a second pass
-                    items
-                        .into_iter()
-                        .map(|entry| {
-                            let content_status = content_status_cb(self, &entry);
-                            (entry, content_status)
-                        })
-                        .collect()
+        assert!(chunks >= 2 * 4096 / BUDGET, "{chunks} chunks");
+        assert_eq!(collect(alice.all().unwrap()), collect(bob.all().unwrap()));
+        for key in [100, 200] {
+            let alice_entry = alice.get(&key).unwrap().unwrap();
+            let bob_entry = bob.get(&key).unwrap().unwrap();
+            assert_eq!(alice_entry.as_fingerprint(), bob_entry.as_fingerprint());
+        }
+        assert_eq!(alice_session.stats().entries_abandoned, 0);
+        assert_eq!(bob_session.stats().entries_abandoned, 0);
+
+        // the chunks of an entry whose last chunk is lost are dropped when the session ends
+        let mut alice: MemoryStore<Entry> = [(7, large(1))].into_iter().collect();
+        let mut bob = MemoryStore::default();
+        let mut alice_session = session();
+        let mut bob_session = session();
+        let initial = alice_session.initial_message(&mut alice).unwrap();
+        let initial = vec![alice_session.encode_message(&initial).unwrap()];
+        let (request, _) = deliver(&mut bob_session, &mut bob, initial);
+        let (mut response, chunks) = deliver(&mut alice_session, &mut alice, request.clone());
+        assert!(chunks > 1);
+        response.pop();
+        for bytes in &response {
+            let message = bob_session.decode_message(bytes).unwrap();
+            assert!(message.has_more());
+            assert!(bob_session.process(&mut bob, message).unwrap().is_none());
+        }
+        assert_eq!(bob_session.partial_values().collect::<Vec<_>>(), [&7]);
+        assert!(bob_session.cancel_message().is_cancel());
+        assert_eq!(bob_session.partial_values().count(), 0);
+        assert_eq!(bob_session.stats().entries_abandoned, 1);
+        assert!(bob.is_empty().unwrap());
+
+        // the buffered chunks are limited
+        let limits = ProtocolLimits {
+            max_reassembly_bytes: 1024,
+            ..Default::default()
+        };
+        let mut alice_session = session();
+        let mut bob_session = session().with_limits(limits);
+        let initial = alice_session.initial_message(&mut alice).unwrap();
+        let initial = vec![alice_session.encode_message(&initial).unwrap()];
+        let (request, _) = deliver(&mut bob_session, &mut bob, initial);
+        let (response, _) = deliver(&mut alice_session, &mut alice, request);
+        let err = response
+            .iter()
+            .find_map(|bytes| bob_session.decode_message(bytes).err())
+            .unwrap();
+        assert!(matches!(
+            err,
+            EnvelopeError::Protocol(ProtocolError::LimitExceeded(Limit::ReassemblyBytes(1024)))
+        ));
+        assert_eq!(bob_session.partial_values().count(), 0);
+
+        // so are the chunks themselves: empty chunks are invalid, and an entry of more chunks
+        // than fit into the limit is rejected with its first chunk
+        let chunk = |key: u32, total: u32, bytes: Vec<u8>| {
+            let message = MessageBuilder::new()
+                .add_part(MessagePart::ValueChunk {
+                    key,
+                    index: 0,
+                    total,
+                    bytes,
                 })
+                .build();
+            session().encode_message(&message).unwrap()
+        };
+        let mut bob_session = session().with_limits(limits);
+        let err = bob_session
+            .decode_message(&chunk(1, 2, vec![]))
+            .unwrap_err();
+        assert!(matches!(err, EnvelopeError::InvalidValueChunk));
+        let err = bob_session
+            .decode_message(&chunk(1, u32::MAX, vec![1]))
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            EnvelopeError::Protocol(ProtocolError::LimitExceeded(Limit::ReassemblyBytes(1024)))
+        ));
+        // each chunk, and each entry, counts against the limit regardless of its size
+        let err = (0..)
+            .find_map(|key| bob_session.decode_message(&chunk(key, 2, vec![1])).err())
+            .unwrap();
+        assert!(matches!(
+            err,
+            EnvelopeError::Protocol(ProtocolError::LimitExceeded(Limit::ReassemblyBytes(1024)))
+        ));
+        assert_eq!(bob_session.stats().entries_abandoned, 1024 / 129);
+
+        // chunks that arrive twice, in any order, are reassembled once
+        let mut bob = MemoryStore::default();
+        let mut alice_session = session().with_range_ids();
+        let mut bob_session = session().with_range_ids();
+        let initial = alice_session.initial_message(&mut alice).unwrap();
+        let initial = vec![alice_session.encode_message(&initial).unwrap()];
+        let (request, _) = deliver(&mut bob_session, &mut bob, initial);
+        let (response, chunks) = deliver(&mut alice_session, &mut alice, request);
+        assert!(chunks > 1);
+        let mut response: Vec<_> = response.iter().chain(&response).cloned().collect();
+        response.shuffle(&mut rand_chacha::ChaCha8Rng::seed_from_u64(0));
+        let (mut next, _) = deliver(&mut bob_session, &mut bob, response);
+        assert_eq!(bob_session.partial_values().count(), 0);
+        let mut rounds = 0;
+        while !next.is_empty() {
+            next = match rounds % 2 {
+                0 => deliver(&mut alice_session, &mut alice, next).0,
+                _ => deliver(&mut bob_session, &mut bob, next).0,
             };
+            rounds += 1;
+        }
+        assert_eq!(bob_session.partial_values().count(), 0);
+        assert_eq!(alice_session.outstanding_range_ids().count(), 0);
+        assert_eq!(bob_session.outstanding_range_ids().count(), 0);
+        assert_eq!(collect(alice.all().unwrap()), collect(bob.all().unwrap()));
+        assert_eq!(bob_session.stats().entries_abandoned, 0);
+    }
 
-            // Store incoming values
-            for (entry, content_status) in values {
-                if validate_cb(self, &entry, content_status) {
-                    // TODO: Get rid of the clone?
-                    let outcome = self.put(entry.clone())?;
-                    if let InsertOutcome::Inserted { .. } = outcome {
-                        on_insert_cb(self, entry, content_status);
-                    }
+    #[test]
+    fn test_session_range_ids() {
+        use rand::{Rng, SeedableRng};
+        type Entry = (u32, i32);
+        const WINDOW: usize = 4;
+
+        let mut dropped = 0;
+        for seed in 0..20 {
+            let mut rng = rand_chacha::ChaCha8Rng::seed_from_u64(seed);
+            let mut alice: MemoryStore<Entry> = (0..300)
+                .filter_map(|key| {
+                    // seven in ten keys, with one of three values
+                    let x = rng.gen_range(0..10);
+                    (x < 7).then_some((key, x % 3))
+                })
+                .collect();
+            let mut bob: MemoryStore<Entry> = (0..300)
+                .filter_map(|key| {
+                    // seven in ten keys, with one of three values
+                    let x = rng.gen_range(0..10);
+                    (x < 7).then_some((key, x % 3))
+                })
+                .collect();
+            let session = || {
+                SyncSession::default()
+                    .with_range_ids()
+                    .with_max_message_bytes(512)
+            };
+            let (mut alice_session, mut bob_session) = (session(), session());
+            let initial = alice_session.initial_message(&mut alice).unwrap();
+            assert!(initial.range_ids().is_some());
+            // messages in flight to bob and to alice, delivered in any order within a window,
+            // and some of them twice
+            let mut in_flight = [vec![initial], Vec::new()];
+            let mut steps = 0;
+            while let Some(to) = (0..2)
+                .filter(|to| !in_flight[*to].is_empty())
+                .nth(rng.gen_range(0..2))
+                .or_else(|| (0..2).find(|to| !in_flight[*to].is_empty()))
+            {
+                steps += 1;
+                assert!(steps < 10_000, "sync does not terminate");
+                let window = in_flight[to].len().min(WINDOW);
+                let message = in_flight[to].remove(rng.gen_range(0..window));
+                if rng.gen_bool(0.2) {
+                    let at = rng.gen_range(0..=in_flight[to].len());
+                    in_flight[to].insert(at, message.clone());
                 }
+                let (session, store) = match to {
+                    0 => (&mut bob_session, &mut bob),
+                    _ => (&mut alice_session, &mut alice),
+                };
+                let mut responses: Vec<_> = session
+                    .process(store, message)
+                    .unwrap()
+                    .into_iter()
+                    .collect();
+                responses.extend(std::iter::from_fn(|| session.poll_pending_message()));
+                in_flight[1 - to].extend(responses);
             }
+            assert_eq!(
+                collect(alice.all().unwrap()),
+                collect(bob.all().unwrap()),
+                "seed {seed}"
+            );
+            assert_eq!(alice_session.outstanding_range_ids().count(), 0);
+            assert_eq!(bob_session.outstanding_range_ids().count(), 0);
+            dropped += alice_session.stats().messages_dropped;
+            dropped += bob_session.stats().messages_dropped;
+        }
+        assert!(dropped > 0);
+
+        // a duplicate is dropped, and so is a late response whose parts were answered
+        let (alice_set, bob_set) = paper_sets()[0];
+        let mut alice: MemoryStore<_> = alice_set.iter().copied().collect();
+        let mut bob: MemoryStore<_> = bob_set.iter().copied().collect();
+        let mut alice_session = SyncSession::default().with_range_ids();
+        let mut bob_session = SyncSession::default();
+        let init = alice_session.initial_message(&mut alice).unwrap();
+        let reply = bob_session
+            .process(&mut bob, init.clone())
+            .unwrap()
+            .unwrap();
+        assert!(reply.range_ids().is_some());
+        assert!(bob_session.process(&mut bob, init).unwrap().is_none());
+        assert_eq!(bob_session.stats().messages_dropped, 1);
+        let outstanding = alice_session.outstanding_range_ids().count();
+        assert!(outstanding > 0);
+        alice_session.process(&mut alice, reply.clone()).unwrap();
+        assert!(alice_session.process(&mut alice, reply).unwrap().is_none());
+        assert_eq!(alice_session.stats().messages_dropped, 1);
+    }
 
-            if let Some(diff) = diff {
-                if !diff.is_empty() {
-                    out.push(MessagePart::RangeItem(RangeItem {
-                        range,
-                        values: diff,
-                        have_local: true,
-                    }));
-                }
-            }
+    /// A store whose keys are never prefixes of each other, so that writes take the same time
+    /// regardless of the size of the store. It keeps the default impl of
+    /// [`Store::get_range_chunked`].
+    #[derive(Debug, Default)]
+    struct FlatStore<S>(S);
+
+    impl<E: RangeEntry + 'static, S: Store<E>> Store<E> for FlatStore<S> {
+        type Error = S::Error;
+        type RangeIterator<'a> = S::RangeIterator<'a> where S: 'a, E: 'a;
+        type ParentIterator<'a> = std::iter::Empty<Result<E, S::Error>> where S: 'a, E: 'a;
+
+        fn get_first(&mut self) -> Result<E::Key, Self::Error> {
+            self.0.get_first()
         }
 
-        // Process fingerprint messages
-        for RangeFingerprint { range, fingerprint } in fingerprints {
-            let local_fingerprint = self.get_fingerprint(&range)?;
-            // Case1 Match, nothing to do
-            if local_fingerprint == fingerprint {
-                continue;
+        fn get(&mut self, key: &E::Key) -> Result<Option<E>, Self::Error> {
+            self.0.get(key)
+        }
+
+        fn len(&mut self) -> Result<usize, Self::Error> {
+            self.0.len()
+        }
+
+        fn is_empty(&mut self) -> Result<bool, Self::Error> {
+            self.0.is_empty()
+        }
+
+        fn get_fingerprint(&mut self, range: &Range<E::Key>) -> Result<Fingerprint, Self::Error> {
+            self.0.get_fingerprint(range)
+        }
+
+        fn entry_put(&mut self, entry: E) -> Result<(), Self::Error> {
+            self.0.entry_put(entry)
+        }
+
+        fn get_range(
+            &mut self,
+            range: Range<E::Key>,
+        ) -> Result<Self::RangeIterator<'_>, Self::Error> {
+            self.0.get_range(range)
+        }
+
+        fn get_range_len(&mut self, range: Range<E::Key>) -> Result<usize, Self::Error> {
+            self.0.get_range_len(range)
+        }
+
+        fn prefixed_by(&mut self, prefix: &E::Key) -> Result<Self::RangeIterator<'_>, Self::Error> {
+            self.0.prefixed_by(prefix)
+        }
+
+        fn prefixes_of(&mut self, _key: &E::Key) -> Result<Self::ParentIterator<'_>, Self::Error> {
+            Ok(std::iter::empty())
+        }
+
+        fn all(&mut self) -> Result<Self::RangeIterator<'_>, Self::Error> {
+            self.0.all()
+        }
+
+        fn entry_remove(&mut self, key: &E::Key) -> Result<Option<E>, Self::Error> {
+            self.0.entry_remove(key)
+        }
+
+        fn remove_prefix_filtered(
+            &mut self,
+            _prefix: &E::Key,
+            _predicate: impl Fn(&E::Value) -> bool,
+        ) -> Result<usize, Self::Error> {
+            Ok(0)
+        }
+    }
+
+    #[test]
+    fn test_session_send_all() {
+        const BUDGET: usize = 16 * 1024;
+        let mut alice = FlatStore(
+            (0..100_000u32)
+                .map(|i| (i, 1u8))
+                .collect::<MemoryStore<_>>(),
+        );
+        let mut bob = FlatStore(MemoryStore::default());
+        // alice splits her messages into larger ones than bob accepts
+        let mut alice_session = SyncSession::default().with_max_message_bytes(1024 * 1024);
+        let mut bob_session = SyncSession::default().with_max_message_bytes(BUDGET);
+        let process = |session: &mut SyncSession<_>, store: &mut FlatStore<MemoryStore<_>>, msg| {
+            session.process(store, msg).unwrap()
+        };
+        let mut to_bob = VecDeque::from([alice_session.initial_message(&mut alice).unwrap()]);
+        let mut to_alice = VecDeque::new();
+        let mut send_all = 0;
+        let mut messages = 0;
+        while !to_bob.is_empty() || !to_alice.is_empty() {
+            if let Some(msg) = to_bob.pop_front() {
+                assert!(msg.encode().unwrap().len() <= BUDGET);
+                messages += 1;
+                to_alice.extend(process(&mut bob_session, &mut bob, msg));
+                to_alice.extend(std::iter::from_fn(|| bob_session.poll_pending_message()));
+            }
+            if let Some(msg) = to_alice.pop_front() {
+                send_all += msg
+                    .parts()
+                    .iter()
+                    .filter(|part| matches!(part, MessagePart::SendAll { .. }))
+                    .count();
+                to_bob.extend(process(&mut alice_session, &mut alice, msg));
+                to_bob.extend(std::iter::from_fn(|| alice_session.poll_pending_message()));
             }
+        }
+        assert_eq!(send_all, 1);
+        assert!(messages > 30, "{messages} messages");
+        assert_eq!(bob.len().unwrap(), 100_000);
+        assert_eq!(collect(alice.all().unwrap()), collect(bob.all().unwrap()));
+    }
 
-            // Case2 Recursion Anchor
-            let num_local_values = self.get_range_len(range.clone())?;
-            if num_local_values <= 1 || fingerprint == Fingerprint::empty() {
-                let values = self
-                    .get_range(range.clone())?
-                    .collect::<Result<Vec<_>, _>>()?;
-                let values = values
-                    .into_iter()
-                    .map(|entry| {
-                        let content_status = content_status_cb(self, &entry);
-                        (entry, content_status)
-                    })
-                    .collect();
-                out.push(MessagePart::RangeItem(RangeItem {
-                    range,
-                    values,
-                    have_local: false,
-                }));
-            } else {
-                // Case3 Recurse
-                // Create partition
-                // m0 = x < m1 < .. < mk = y, with k>= 2
-                // such that [ml, ml+1) is nonempty
-                let mut ranges = Vec::with_capacity(config.split_factor);
+    type SyncResult<K, V> = test_utils::SyncResult<MemoryStore<(K, V)>, (K, V)>;
 
-                // Select the first index, for which the key is larger or equal than the x of the range.
-                let mut start_index = 0;
-                for el in self.get_range(range.clone())? {
-                    let el = el?;
-                    if el.key() >= range.x() {
-                        break;
-                    }
-                    start_index += 1;
-                }
+    type ValidateCb<K, V> = Box<dyn Fn(&MemoryStore<(K, V)>, &(K, V), ContentStatus) -> bool>;
 
-                // select a pivot value. pivots repeat every split_factor, so pivot(i) == pivot(i + self.split_factor * x)
-                // it is guaranteed that pivot(0) != x if local_values.len() >= 2
-                let mut pivot = |i: usize| {
-                    // ensure that pivots wrap around
-                    let i = i % config.split_factor;
-                    // choose an offset. this will be
-                    // 1/2, 1 in case of split_factor == 2
-                    // 1/3, 2/3, 1 in case of split_factor == 3
-                    // etc.
-                    let offset = (num_local_values * (i + 1)) / config.split_factor;
-                    let offset = (start_index + offset) % num_local_values;
-                    self.get_range(range.clone())
-                        .map(|mut i| i.nth(offset))
-                        .and_then(|e| e.expect("missing entry"))
-                        .map(|e| e.key().clone())
-                };
-                if range.is_all() {
-                    // the range is the whole set, so range.x and range.y should not matter
-                    // just add all ranges as normal ranges. Exactly one of the ranges will
-                    // wrap around, so we cover the entire set.
-                    for i in 0..config.split_factor {
-                        let (x, y) = (pivot(i)?, pivot(i + 1)?);
-                        // don't push empty ranges
-                        if x != y {
-                            ranges.push(Range { x, y })
-                        }
-                    }
+    fn sync<K, V>(alice_set: &[(K, V)], bob_set: &[(K, V)]) -> SyncResult<K, V>
+    where
+        K: RangeKey + Default,
+        V: RangeValue,
+    {
+        let alice_validate_cb: ValidateCb<K, V> = Box::new(|_, _, _| true);
+        let bob_validate_cb: ValidateCb<K, V> = Box::new(|_, _, _| true);
+        sync_with_validate_cb_and_assert(alice_set, bob_set, &alice_validate_cb, &bob_validate_cb)
+    }
+
+    fn insert_if_larger<K: RangeKey, V: RangeValue>(map: &mut BTreeMap<K, V>, key: K, value: V) {
+        let mut insert = true;
+        for (k, v) in map.iter() {
+            if k.is_prefix_of(&key) && v >= &value {
+                insert = false;
+            }
+        }
+        if insert {
+            #[allow(clippy::needless_bool)]
+            map.retain(|k, v| {
+                if key.is_prefix_of(k) && value >= *v {
+                    false
                 } else {
-                    // guaranteed to be non-empty because
-                    // - pivot(0) is guaranteed to be != x for local_values.len() >= 2
-                    // - local_values.len() < 2 gets handled by the recursion anchor
-                    // - x != y (regular range)
-                    ranges.push(Range {
-                        x: range.x().clone(),
-                        y: pivot(0)?,
-                    });
-                    // this will only be executed for split_factor > 2
-                    for i in 0..config.split_factor - 2 {
-                        // don't push empty ranges
-                        let (x, y) = (pivot(i)?, pivot(i + 1)?);
-                        if x != y {
-                            ranges.push(Range { x, y })
-                        }
-                    }
-                    // guaranteed to be non-empty because
-                    // - pivot is a value in the range
-                    // - y is the exclusive end of the range
-                    // - x != y (regular range)
-                    ranges.push(Range {
-                        x: pivot(config.split_factor - 2)?,
-                        y: range.y().clone(),
-                    });
+                    true
                 }
+            });
+            map.insert(key, value);
+        }
+    }
 
-                let mut non_empty = 0;
-                for range in ranges {
-                    let chunk: Vec<_> = self.get_range(range.clone())?.collect();
-                    if !chunk.is_empty() {
-                        non_empty += 1;
-                    }
-                    // Add either the fingerprint or the item set
-                    let fingerprint = self.get_fingerprint(&range)?;
-                    if chunk.len() > config.max_set_size {
-                        out.push(MessagePart::RangeFingerprint(RangeFingerprint {
-                            range: range.clone(),
-                            fingerprint,
-                        }));
-                    } else {
-                        let values = chunk
-                            .into_iter()
-                            .map(|entry| {
-                                entry.map(|entry| {
-                                    let content_status = content_status_cb(self, &entry);
-                                    (entry, content_status)
-                                })
-                            })
-                            .collect::<Result<_, _>>()?;
-                        out.push(MessagePart::RangeItem(RangeItem {
-                            range,
-                            values,
-                            have_local: false,
-                        }));
-                    }
-                }
-                debug_assert!(non_empty > 1);
+    fn sync_with_validate_cb_and_assert<K, V, F1, F2>(
+        alice_set: &[(K, V)],
+        bob_set: &[(K, V)],
+        alice_validate_cb: F1,
+        bob_validate_cb: F2,
+    ) -> SyncResult<K, V>
+    where
+        K: RangeKey + Default,
+        V: RangeValue,
+        F1: Fn(&MemoryStore<(K, V)>, &(K, V), ContentStatus) -> bool,
+        F2: Fn(&MemoryStore<(K, V)>, &(K, V), ContentStatus) -> bool,
+    {
+        let mut alice = MemoryStore::<(K, V)>::default();
+        let mut bob = MemoryStore::<(K, V)>::default();
+
+        let expected_set = {
+            let mut expected_set = BTreeMap::new();
+            let mut alice_expected = BTreeMap::new();
+            for e in alice_set {
+                alice.put(e.clone()).unwrap();
+                insert_if_larger(&mut expected_set, e.0.clone(), e.1.clone());
+                insert_if_larger(&mut alice_expected, e.0.clone(), e.1.clone());
+            }
+            let alice_expected = alice_expected.into_iter().collect::<Vec<_>>();
+            let alice_now: Vec<_> = alice.all().unwrap().collect::<Result<_, _>>().unwrap();
+            assert_eq!(
+                alice_expected, alice_now,
+                "alice initial set does not match"
+            );
+
+            let mut bob_expected = BTreeMap::new();
+            for e in bob_set {
+                bob.put(e.clone()).unwrap();
+                insert_if_larger(&mut expected_set, e.0.clone(), e.1.clone());
+                insert_if_larger(&mut bob_expected, e.0.clone(), e.1.clone());
             }
+            let bob_expected = bob_expected.into_iter().collect::<Vec<_>>();
+            let bob_now: Vec<_> = bob.all().unwrap().collect::<Result<_, _>>().unwrap();
+            assert_eq!(bob_expected, bob_now, "bob initial set does not match");
+
+            expected_set.into_iter().collect::<Vec<_>>()
+        };
+
+        let mut res = sync_exchange_messages(alice, bob, alice_validate_cb, bob_validate_cb, 100);
+
+        let alice_now: Vec<_> = res.alice.all().unwrap().collect::<Result<_, _>>().unwrap();
+        if alice_now != expected_set {
+            res.print_messages();
+            println!("alice_init: {alice_set:?}");
+            println!("bob_init:   {bob_set:?}");
+            println!("expected:   {expected_set:?}");
+            println!("alice_now:  {alice_now:?}");
+            panic!("alice_now does not match expected");
         }
 
-        // If we have any parts, return a message
-        if !out.is_empty() {
-            Ok(Some(Message { parts: out }))
-        } else {
-            Ok(None)
+        let bob_now: Vec<_> = res.bob.all().unwrap().collect::<Result<_, _>>().unwrap();
+        if bob_now != expected_set {
+            res.print_messages();
+            println!("alice_init: {alice_set:?}");
+            println!("bob_init:   {bob_set:?}");
+            println!("expected:   {expected_set:?}");
+            println!("bob_now:    {bob_now:?}");
+            panic!("bob_now does not match expected");
+        }
+
+        res.assert_sent_once();
+        res
+    }
+
+    /// Runs a sync between alice and bob, and returns the number of messages sent by each side.
+    fn message_counts<E: RangeEntry, A: Store<E>, B: Store<E>>(
+        alice: &mut A,
+        bob: &mut B,
+    ) -> (usize, usize) {
+        let (mut alice_to_bob, mut bob_to_alice) = (0, 0);
+        let mut next_to_bob = Some(alice.initial_message().unwrap());
+        while let Some(msg) = next_to_bob.take() {
+            alice_to_bob += 1;
+            let reply = bob
+                .process_message_reply(
+                    &Default::default(),
+                    msg,
+                    |_, _, _| true,
+                    |_, _, _| (),
+                    |_, _| ContentStatus::Complete,
+                )
+                .unwrap();
+            if let Some(msg) = reply {
+                bob_to_alice += 1;
+                next_to_bob = alice
+                    .process_message_reply(
+                        &Default::default(),
+                        msg,
+                        |_, _, _| true,
+                        |_, _, _| (),
+                        |_, _| ContentStatus::Complete,
+                    )
+                    .unwrap();
+            }
         }
+        (alice_to_bob, bob_to_alice)
     }
 
-    /// Insert a key value pair.
-    ///
-    /// Entries are inserted if they compare strictly greater than all entries in the set of
-    /// entries which have the same key as `entry` or have a key which is a prefix of `entry`.
-    ///
-    /// Additionally, entries that have a key which is a prefix of the entry's key and whose
-    /// timestamp is not strictly greater than that of the new entry are deleted
-    ///
-    /// Note: The deleted entries are simply dropped right now. We might want to make this return
-    /// an iterator, to potentially log or expose the deleted entries.
-    ///
-    /// Returns `true` if the entry was inserted.
-    /// Returns `false` if it was not inserted.
-    fn put(&mut self, entry: E) -> Result<InsertOutcome, Self::Error> {
-        let prefix_entry = self.prefixes_of(entry.key())?;
-        // First we check if our entry is strictly greater than all parent elements.
-        // From the willow spec:
-        // "Remove all entries whose timestamp is strictly less than the timestamp of any other entry [..]
-        // whose path is a prefix of p." and then "remove all but those whose record has the greatest hash component".
-        // This is the contract of the `Ord` impl for `E::Value`.
-        for prefix_entry in prefix_entry {
-            let prefix_entry = prefix_entry?;
-            if entry.value() <= prefix_entry.value() {
-                return Ok(InsertOutcome::NotInserted);
-            }
-        }
+    /// The sets of the examples in the paper.
+    type Set = &'static [(&'static str, i32)];
+
+    fn paper_sets() -> [(Set, Set); 3] {
+        [
+            (
+                &[("ape", 1), ("eel", 1), ("fox", 1), ("gnu", 1)],
+                &[
+                    ("bee", 1),
+                    ("cat", 1),
+                    ("doe", 1),
+                    ("eel", 1),
+                    ("fox", 1),
+                    ("hog", 1),
+                ],
+            ),
+            (
+                &[
+                    ("ape", 1),
+                    ("bee", 1),
+                    ("cat", 1),
+                    ("doe", 1),
+                    ("eel", 1),
+                    ("fox", 1),
+                    ("gnu", 1),
+                    ("hog", 1),
+                ],
+                &[
+                    ("ape", 1),
+                    ("bee", 1),
+                    ("cat", 1),
+                    ("doe", 1),
+                    ("eel", 1),
+                    ("gnu", 1),
+                    ("hog", 1),
+                ],
+            ),
+            (
+                &[
+                    ("ape", 1),
+                    ("bee", 1),
+                    ("cat", 1),
+                    ("doe", 1),
+                    ("eel", 1),
+                    ("fox", 1),
+                    ("gnu", 1),
+                    ("hog", 1),
+                ],
+                &[("ape", 1), ("cat", 1), ("eel", 1), ("gnu", 1)],
+            ),
+        ]
+    }
 
-        // Now we remove all entries that have our key as a prefix and are older than our entry.
-        let removed = self.remove_prefix_filtered(entry.key(), |value| entry.value() >= value)?;
+    fn collect<E, Err: Debug>(iter: impl Iterator<Item = Result<E, Err>>) -> Vec<E> {
+        iter.collect::<Result<_, _>>().unwrap()
+    }
 
-        // Insert our new entry.
-        self.entry_put(entry)?;
-        Ok(InsertOutcome::Inserted { removed })
+    /// Asserts that a store behaves like a [`MemoryStore`] with the same entries.
+    fn assert_same_range<E, S>(expected: &mut MemoryStore<E>, actual: &mut S, range: Range<E::Key>)
+    where
+        E: RangeEntry + PartialEq,
+        E::Key: Default,
+        S: Store<E>,
+    {
+        assert_eq!(
+            collect(actual.get_range(range.clone()).unwrap()),
+            collect(expected.get_range(range.clone()).unwrap()),
+            "get_range {range:?}"
+        );
+        assert_eq!(
+            actual.get_fingerprint(&range).unwrap(),
+            expected.get_fingerprint(&range).unwrap(),
+            "get_fingerprint {range:?}"
+        );
+        assert_eq!(
+            actual.get_range_len(range.clone()).unwrap(),
+            expected.get_range_len(range.clone()).unwrap(),
+            "get_range_len {range:?}"
+        );
+        assert_eq!(
+            collect(actual.get_range_chunked(range.clone(), 2).unwrap()),
+            collect(expected.get_range_chunked(range.clone(), 2).unwrap()),
+            "get_range_chunked {range:?}"
+        );
     }
-}
 
-impl<E: RangeEntry, S: Store<E>> Store<E> for &mut S {
-    type Error = S::Error;
+    /// Conformance test for store implementations.
+    ///
+    /// Fills two stores created with `new_store` and two [`MemoryStore`]s with the given sets, and
+    /// asserts that they agree on all ranges between the keys of the sets, and that syncing them
+    /// takes the same number of messages and leads to the same result.
+    fn assert_store_conformance<E, S>(
+        mut new_store: impl FnMut() -> S,
+        alice_set: &[E],
+        bob_set: &[E],
+    ) where
+        E: RangeEntry + PartialEq,
+        E::Key: Default,
+        S: Store<E>,
+    {
+        let (mut alice_expected, mut bob_expected) =
+            (MemoryStore::default(), MemoryStore::default());
+        let (mut alice, mut bob) = (new_store(), new_store());
+        for e in alice_set {
+            alice_expected.put(e.clone()).unwrap();
+            alice.put(e.clone()).unwrap();
+        }
+        for e in bob_set {
+            bob_expected.put(e.clone()).unwrap();
+            bob.put(e.clone()).unwrap();
+        }
+        assert_eq!(alice.len().unwrap(), alice_expected.len().unwrap());
+        assert_eq!(bob.len().unwrap(), bob_expected.len().unwrap());
+        assert_eq!(
+            alice.get_first().unwrap(),
+            alice_expected.get_first().unwrap()
+        );
+        assert_eq!(
+            alice.first_and_last().unwrap(),
+            alice_expected.first_and_last().unwrap()
+        );
 
-    type RangeIterator<'a> = S::RangeIterator<'a> where Self: 'a, E: 'a;
+        // all ranges between the keys, including wrap-around and full ranges
+        let mut keys: Vec<E::Key> = alice_set
+            .iter()
+            .chain(bob_set)
+            .map(|e| e.key().clone())
+            .collect();
+        keys.push(Default::default());
+        for x in &keys {
+            for y in &keys {
+                let range = Range::new(x.clone(), y.clone());
+                assert_same_range(&mut alice_expected, &mut alice, range.clone());
+                assert_same_range(&mut bob_expected, &mut bob, range);
+            }
+        }
 
-    type ParentIterator<'a> = S::ParentIterator<'a> where Self: 'a, E: 'a;
+        let expected_counts = message_counts(&mut alice_expected, &mut bob_expected);
+        let actual_counts = message_counts(&mut alice, &mut bob);
+        assert_eq!(actual_counts, expected_counts, "message counts");
+        assert_eq!(
+            collect(alice.all().unwrap()),
+            collect(alice_expected.all().unwrap())
+        );
+        assert_eq!(
+            collect(bob.all().unwrap()),
+            collect(bob_expected.all().unwrap())
+        );
+    }
 
-    fn get_first(&mut self) -> Result<<E as RangeEntry>::Key, Self::Error> {
-        (**self).get_first()
+    #[test]
+    fn store_conformance() {
+        test_utils::conformance::<MemoryStore<TestEntry>, _>();
+        test_utils::conformance::<VecStore<TestEntry>, _>();
+        test_utils::conformance_with(|| BoxedStore::new(MemoryStore::<TestEntry>::new()));
+        test_utils::conformance_with(|| {
+            sharded_memory_store::<TestEntry>(&[
+                test_utils::TestKey("cat".to_string()),
+                test_utils::TestKey("fox".to_string()),
+            ])
+        });
+        test_utils::conformance_with(|| {
+            OverlayStore::new(MemoryStore::<TestEntry>::new(), MemoryStore::new())
+        });
+        test_utils::conformance_with(|| InstrumentedStore::new(MemoryStore::<TestEntry>::new()));
+        test_utils::conformance_with(|| {
+            FilteredStore::new(MemoryStore::<TestEntry>::new(), |_: &TestEntry| true)
+        });
+        test_utils::conformance_with(|| {
+            BoundedStore::new(MemoryStore::<TestEntry>::new()).unwrap()
+        });
+        test_utils::conformance_with(|| {
+            MirroredStore::new(
+                MemoryStore::<TestEntry>::new(),
+                VecStore::new(),
+                SecondaryErrorPolicy::Propagate,
+            )
+        });
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut i = 0;
+        test_utils::conformance_with(|| {
+            i += 1;
+            LogStore::<TestEntry>::open(dir.path().join(format!("{i}.log"))).unwrap()
+        });
     }
 
-    fn get(&mut self, key: &<E as RangeEntry>::Key) -> Result<Option<E>, Self::Error> {
-        (**self).get(key)
+    #[test]
+    #[cfg(feature = "redb-store")]
+    fn redb_store_conformance() {
+        test_utils::conformance_with(|| RedbStore::<TestEntry>::memory().unwrap());
     }
 
-    fn len(&mut self) -> Result<usize, Self::Error> {
-        (**self).len()
+    #[test]
+    #[cfg(feature = "sqlite-store")]
+    fn sqlite_store_conformance() {
+        test_utils::conformance_with(|| SqliteStore::<TestEntry>::memory().unwrap());
     }
 
-    fn is_empty(&mut self) -> Result<bool, Self::Error> {
-        (**self).is_empty()
+    #[test]
+    fn boxed_store_paper() {
+        for (alice_set, bob_set) in paper_sets() {
+            assert_store_conformance(
+                || BoxedStore::new(MemoryStore::default()),
+                alice_set,
+                bob_set,
+            );
+        }
     }
 
-    fn get_fingerprint(
-        &mut self,
-        range: &Range<<E as RangeEntry>::Key>,
-    ) -> Result<Fingerprint, Self::Error> {
-        (**self).get_fingerprint(range)
+    #[test]
+    fn btree_map_store_paper() {
+        for (alice_set, bob_set) in paper_sets() {
+            assert_store_conformance(BTreeMap::new, alice_set, bob_set);
+        }
     }
 
-    fn entry_put(&mut self, entry: E) -> Result<(), Self::Error> {
-        (**self).entry_put(entry)
+    #[proptest]
+    fn btree_map_store_sync(
+        #[strategy(test_vec_string_u8())] alice: Vec<(String, u8)>,
+        #[strategy(test_vec_string_u8())] bob: Vec<(String, u8)>,
+    ) {
+        assert_store_conformance(BTreeMap::new, &alice, &bob);
     }
 
-    fn get_range(
-        &mut self,
-        range: Range<<E as RangeEntry>::Key>,
-    ) -> Result<Self::RangeIterator<'_>, Self::Error> {
-        (**self).get_range(range)
+    thread_local! {
+        static KEY_COMPARISONS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
     }
 
-    fn prefixed_by(
-        &mut self,
-        prefix: &<E as RangeEntry>::Key,
-    ) -> Result<Self::RangeIterator<'_>, Self::Error> {
-        (**self).prefixed_by(prefix)
+    /// A key that counts how often it is compared.
+    #[derive(Debug, Clone, Default, PartialEq, Eq)]
+    struct CountingKey(u32);
+
+    impl Ord for CountingKey {
+        fn cmp(&self, other: &Self) -> Ordering {
+            KEY_COMPARISONS.with(|c| c.set(c.get() + 1));
+            self.0.cmp(&other.0)
+        }
     }
 
-    fn prefixes_of(
-        &mut self,
-        key: &<E as RangeEntry>::Key,
-    ) -> Result<Self::ParentIterator<'_>, Self::Error> {
-        (**self).prefixes_of(key)
+    impl PartialOrd for CountingKey {
+        fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+            Some(self.cmp(other))
+        }
     }
 
-    fn all(&mut self) -> Result<Self::RangeIterator<'_>, Self::Error> {
-        (**self).all()
+    impl RangeKey for CountingKey {
+        fn is_prefix_of(&self, other: &Self) -> bool {
+            self == other
+        }
     }
 
-    fn entry_remove(&mut self, key: &<E as RangeEntry>::Key) -> Result<Option<E>, Self::Error> {
-        (**self).entry_remove(key)
+    #[proptest]
+    fn btree_map_store_small_range(
+        #[strategy(1000u32..4000)] len: u32,
+        #[strategy(0u32..4000)] start: u32,
+        #[strategy(1u32..8)] width: u32,
+    ) {
+        let mut store: BTreeMap<_, _> = (0..len).map(|i| (CountingKey(i), ())).collect();
+        let range = Range::new(CountingKey(start), CountingKey(start + width));
+        KEY_COMPARISONS.with(|c| c.set(0));
+        let keys: Vec<_> = collect(store.get_range(range).unwrap())
+            .into_iter()
+            .map(|(k, _)| k.0)
+            .collect();
+        let comparisons = KEY_COMPARISONS.with(|c| c.get());
+        let expected: Vec<_> = (start..(start + width).min(len)).collect();
+        prop_assert_eq!(keys, expected);
+        // A scan would compare every key, a tree search only a few keys per level.
+        prop_assert!(comparisons < 200, "{} comparisons", comparisons);
     }
 
-    fn remove_prefix_filtered(
-        &mut self,
-        prefix: &<E as RangeEntry>::Key,
-        predicate: impl Fn(&<E as RangeEntry>::Value) -> bool,
-    ) -> Result<usize, Self::Error> {
-        (**self).remove_prefix_filtered(prefix, predicate)
+    thread_local! {
+        static LIVE_ENTRIES: std::cell::Cell<(usize, usize)> = const { std::cell::Cell::new((0, 0)) };
     }
-}
 
-#[derive(Debug, Clone, Copy)]
-pub struct SyncConfig {
-    /// Up to how many values to send immediately, before sending only a fingerprint.
-    max_set_size: usize,
-    /// `k` in the protocol, how many splits to generate. at least 2
-    split_factor: usize,
-}
+    /// An entry that counts how many entries are alive, and the peak of that count.
+    #[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+    struct LiveEntry(u32, u8);
 
-impl Default for SyncConfig {
-    fn default() -> Self {
-        SyncConfig {
-            max_set_size: 1,
-            split_factor: 2,
+    impl LiveEntry {
+        fn new(key: u32, value: u8) -> Self {
+            LIVE_ENTRIES.with(|c| {
+                let (live, peak) = c.get();
+                c.set((live + 1, peak.max(live + 1)));
+            });
+            Self(key, value)
         }
-    }
-}
-
-/// The outcome of a [`Store::put`] operation.
-#[derive(Debug)]
-pub enum InsertOutcome {
-    /// The entry was not inserted because a newer entry for its key or a
-    /// prefix of its key exists.
-    NotInserted,
-    /// The entry was inserted.
-    Inserted {
-        /// Number of entries that were removed as a consequence of this insert operation.
-        /// The removed entries had a key that starts with the new entry's key and a lower value.
-        removed: usize,
-    },
-}
 
-#[cfg(test)]
-mod tests {
-    use proptest::prelude::*;
-    use std::{cell::RefCell, collections::BTreeMap, convert::Infallible, fmt::Debug, rc::Rc};
-    use test_strategy::proptest;
+        /// Resets the peak to the number of entries alive, and returns that number.
+        fn reset_peak() -> usize {
+            LIVE_ENTRIES.with(|c| {
+                let (live, _) = c.get();
+                c.set((live, live));
+                live
+            })
+        }
 
-    use super::*;
+        fn peak() -> usize {
+            LIVE_ENTRIES.with(|c| c.get().1)
+        }
+    }
 
-    #[derive(Debug)]
-    struct SimpleStore<K, V> {
-        data: BTreeMap<K, V>,
+    impl Clone for LiveEntry {
+        fn clone(&self) -> Self {
+            Self::new(self.0, self.1)
+        }
     }
 
-    impl<K, V> Default for SimpleStore<K, V> {
-        fn default() -> Self {
-            SimpleStore {
-                data: BTreeMap::default(),
-            }
+    impl Drop for LiveEntry {
+        fn drop(&mut self) {
+            LIVE_ENTRIES.with(|c| {
+                let (live, peak) = c.get();
+                c.set((live.saturating_sub(1), peak));
+            });
         }
     }
 
-    impl<K, V> RangeEntry for (K, V)
-    where
-        K: RangeKey,
-        V: RangeValue,
-    {
-        type Key = K;
-        type Value = V;
+    impl RangeEntry for LiveEntry {
+        type Key = u32;
+        type Value = u8;
 
-        fn key(&self) -> &Self::Key {
+        fn key(&self) -> &u32 {
             &self.0
         }
 
-        fn value(&self) -> &Self::Value {
+        fn value(&self) -> &u8 {
             &self.1
         }
 
         fn as_fingerprint(&self) -> Fingerprint {
-            let mut hasher = blake3::Hasher::new();
-            hasher.update(format!("{:?}", self.0).as_bytes());
-            hasher.update(format!("{:?}", self.1).as_bytes());
-            Fingerprint(hasher.finalize().into())
+            (self.0, self.1).as_fingerprint()
         }
     }
 
-    impl RangeKey for &'static str {
-        fn is_prefix_of(&self, other: &Self) -> bool {
-            other.starts_with(self)
+    #[test]
+    fn test_split_reads_bounded_entries() {
+        let thresholds = [
+            SendThreshold::Entries(1),
+            SendThreshold::Entries(16),
+            SendThreshold::Both {
+                entries: 8,
+                bytes: 1024,
+            },
+        ];
+        for threshold in thresholds {
+            let store = |divergent| -> MemoryStore<LiveEntry> {
+                (0..10_000)
+                    .map(|i| LiveEntry::new(i, if i == 5_000 { divergent } else { 1 }))
+                    .collect()
+            };
+            let mut alice = store(1);
+            let mut bob = store(2);
+            let config = SyncConfig::default().with_send_threshold(threshold);
+            let (max_entries, _) = threshold.limits();
+
+            let mut next = Some(alice.initial_message().unwrap());
+            let mut rounds = 0;
+            let mut peak = 0;
+            while let Some(message) = next.take() {
+                let store = match rounds % 2 {
+                    0 => &mut bob,
+                    _ => &mut alice,
+                };
+                let live = LiveEntry::reset_peak();
+                next = store
+                    .process_message_reply(
+                        &config,
+                        message,
+                        |_, _, _| true,
+                        |_, _, _| (),
+                        |_, _| ContentStatus::Complete,
+                    )
+                    .unwrap();
+                peak = peak.max(LiveEntry::peak() - live);
+                rounds += 1;
+            }
+            assert_eq!(alice.get(&5_000).unwrap(), Some(LiveEntry::new(5_000, 2)));
+            // the entries of each range the response is split into, and the entry that exceeds
+            // the threshold
+            let bound = config.split_factor * max_entries + 1;
+            assert!(
+                peak <= bound,
+                "{threshold:?}: {peak} entries alive at once, bound {bound}"
+            );
         }
     }
-    impl RangeKey for String {
-        fn is_prefix_of(&self, other: &Self) -> bool {
-            other.starts_with(self)
+
+    #[test]
+    fn vec_store_paper() {
+        for (alice_set, bob_set) in paper_sets() {
+            assert_store_conformance(VecStore::new, alice_set, bob_set);
         }
     }
 
-    impl RangeValue for &'static [u8] {}
-    impl RangeValue for i32 {}
-    impl RangeValue for u8 {}
-    impl RangeValue for () {}
+    #[proptest]
+    fn vec_store_sync(
+        #[strategy(test_vec_string_u8())] alice: Vec<(String, u8)>,
+        #[strategy(test_vec_string_u8())] bob: Vec<(String, u8)>,
+    ) {
+        assert_store_conformance(VecStore::new, &alice, &bob);
+    }
 
-    impl<K, V> Store<(K, V)> for SimpleStore<K, V>
-    where
-        K: RangeKey + Default,
-        V: RangeValue,
-    {
-        type Error = Infallible;
-        type ParentIterator<'a> = std::vec::IntoIter<Result<(K, V), Infallible>>;
+    #[proptest]
+    fn vec_store_bulk_load(#[strategy(test_vec_string_u8())] entries: Vec<(String, u8)>) {
+        let expected: MemoryStore<_> = entries.iter().cloned().collect();
+        let mut sorted = entries.clone();
+        sorted.sort();
+        let from_sorted = VecStore::from_sorted_iter(sorted);
+        let collected: VecStore<_> = entries.into_iter().collect();
+        prop_assert_eq!(&from_sorted, &collected);
+        prop_assert_eq!(
+            from_sorted.into_entries(),
+            expected.into_entries().collect::<Vec<_>>()
+        );
+    }
 
-        fn get_first(&mut self) -> Result<K, Self::Error> {
-            if let Some((k, _)) = self.data.first_key_value() {
-                Ok(k.clone())
-            } else {
-                Ok(Default::default())
-            }
-        }
+    #[test]
+    fn vec_store_from_iter_replaces() {
+        let store: VecStore<_> = [("b", 1), ("a", 1), ("b", 2), ("a", 3)]
+            .into_iter()
+            .collect();
+        assert_eq!(store.as_slice(), &[("a", 3), ("b", 2)]);
+    }
 
-        fn get(&mut self, key: &K) -> Result<Option<(K, V)>, Self::Error> {
-            Ok(self.data.get(key).cloned().map(|v| (key.clone(), v)))
-        }
+    #[test]
+    #[should_panic(expected = "sorted")]
+    fn vec_store_from_unsorted_iter() {
+        VecStore::from_sorted_iter([("b", 1), ("a", 1)]);
+    }
 
-        fn len(&mut self) -> Result<usize, Self::Error> {
-            Ok(self.data.len())
-        }
+    fn sharded_memory_store<E: RangeEntry>(
+        boundaries: &[E::Key],
+    ) -> ShardedStore<E, MemoryStore<E>> {
+        let shards = (0..=boundaries.len()).map(|_| MemoryStore::new()).collect();
+        ShardedStore::new(boundaries.to_vec(), shards)
+    }
 
-        fn is_empty(&mut self) -> Result<bool, Self::Error> {
-            Ok(self.data.is_empty())
+    #[test]
+    fn sharded_store_paper() {
+        for boundaries in [&[][..], &["cat"], &["bee", "eel", "fox"], &["a", "zzz"]] {
+            for (alice_set, bob_set) in paper_sets() {
+                let new_store = || sharded_memory_store(boundaries);
+                assert_store_conformance(new_store, alice_set, bob_set);
+            }
         }
+    }
 
-        /// Calculate the fingerprint of the given range.
-        fn get_fingerprint(&mut self, range: &Range<K>) -> Result<Fingerprint, Self::Error> {
-            let elements = self.get_range(range.clone())?;
-            let mut fp = Fingerprint::empty();
-            for el in elements {
-                let el = el?;
-                fp ^= el.as_fingerprint();
-            }
+    #[proptest]
+    fn sharded_store_sync(
+        #[strategy(test_vec_string_u8())] alice: Vec<(String, u8)>,
+        #[strategy(test_vec_string_u8())] bob: Vec<(String, u8)>,
+        #[strategy(prop::collection::btree_set(test_key(), 0..4))] boundaries: BTreeSet<String>,
+    ) {
+        let boundaries: Vec<_> = boundaries.into_iter().collect();
+        assert_store_conformance(|| sharded_memory_store(&boundaries), &alice, &bob);
+    }
 
-            Ok(fp)
+    #[test]
+    fn sharded_store_shard_boundaries() {
+        let boundaries = ["c", "e", "g"];
+        let mut store = sharded_memory_store(&boundaries);
+        let mut expected = MemoryStore::new();
+        for key in ["a", "b", "c", "d", "e", "f", "g", "h"] {
+            store.put((key, 1)).unwrap();
+            expected.put((key, 1)).unwrap();
         }
+        // every shard holds exactly the keys between its boundaries
+        let shards: Vec<Vec<_>> = store
+            .into_shards()
+            .into_iter()
+            .map(|shard| shard.into_entries().map(|(k, _)| k).collect())
+            .collect();
+        assert_eq!(shards, [["a", "b"], ["c", "d"], ["e", "f"], ["g", "h"]]);
 
-        /// Insert the given key value pair.
-        fn entry_put(&mut self, e: (K, V)) -> Result<(), Self::Error> {
-            self.data.insert(e.0, e.1);
-            Ok(())
+        let mut store = sharded_memory_store(&boundaries);
+        for e in expected.all().unwrap() {
+            store.put(e.unwrap()).unwrap();
         }
-
-        type RangeIterator<'a> = SimpleRangeIterator<'a, K, V>
-        where K: 'a, V: 'a;
-        /// Returns all items in the given range
-        fn get_range(&mut self, range: Range<K>) -> Result<Self::RangeIterator<'_>, Self::Error> {
-            // TODO: this is not very efficient, optimize depending on data structure
-            let iter = self.data.iter();
-
-            Ok(SimpleRangeIterator {
-                iter,
-                filter: SimpleFilter::Range(range),
-            })
+        // ranges starting and ending exactly on the shard boundaries, including wrap-around and
+        // full ranges
+        for x in ["", "c", "e", "g"] {
+            for y in ["", "c", "e", "g"] {
+                assert_same_range(&mut expected, &mut store, Range::new(x, y));
+            }
         }
+        let range = Range::new("c", "e");
+        assert_eq!(
+            collect(store.get_range(range).unwrap()),
+            [("c", 1), ("d", 1)]
+        );
+        let range = Range::new("g", "c");
+        assert_eq!(
+            collect(store.get_range_chunked(range, 3).unwrap())
+                .into_iter()
+                .flat_map(|chunk| chunk.entries)
+                .collect::<Vec<_>>(),
+            [("g", 1), ("h", 1), ("a", 1), ("b", 1)]
+        );
+    }
 
-        fn entry_remove(&mut self, key: &K) -> Result<Option<(K, V)>, Self::Error> {
-            let res = self.data.remove(key).map(|v| (key.clone(), v));
-            Ok(res)
-        }
+    /// A store that fails all writes while `fail` is set.
+    #[derive(Debug, Default)]
+    struct FailingStore<S> {
+        store: S,
+        fail: Rc<std::cell::Cell<bool>>,
+    }
 
-        fn all(&mut self) -> Result<Self::RangeIterator<'_>, Self::Error> {
-            let iter = self.data.iter();
-            Ok(SimpleRangeIterator {
-                iter,
-                filter: SimpleFilter::None,
-            })
-        }
+    type FailingIterator<I, E, Err> = std::iter::Map<I, fn(Result<E, Err>) -> anyhow::Result<E>>;
 
-        // TODO: Not horrible.
-        fn prefixes_of(&mut self, key: &K) -> Result<Self::ParentIterator<'_>, Self::Error> {
-            let mut res = vec![];
-            for (k, v) in self.data.iter() {
-                if k.is_prefix_of(key) {
-                    res.push(Ok((k.clone(), v.clone())));
-                }
-            }
-            Ok(res.into_iter())
+    impl<S> FailingStore<S> {
+        fn check(&self) -> anyhow::Result<()> {
+            anyhow::ensure!(!self.fail.get(), "injected failure");
+            Ok(())
         }
+    }
 
-        fn prefixed_by(&mut self, prefix: &K) -> Result<Self::RangeIterator<'_>, Self::Error> {
-            let iter = self.data.iter();
-            Ok(SimpleRangeIterator {
-                iter,
-                filter: SimpleFilter::Prefix(prefix.clone()),
-            })
-        }
+    impl<E: RangeEntry, S: Store<E>> Store<E> for FailingStore<S> {
+        type Error = anyhow::Error;
+        type RangeIterator<'a> = FailingIterator<S::RangeIterator<'a>, E, S::Error>
+        where S: 'a, E: 'a;
+        type ParentIterator<'a> = FailingIterator<S::ParentIterator<'a>, E, S::Error>
+        where S: 'a, E: 'a;
 
-        fn remove_prefix_filtered(
-            &mut self,
-            prefix: &K,
-            predicate: impl Fn(&V) -> bool,
-        ) -> Result<usize, Self::Error> {
-            let old_len = self.data.len();
-            self.data.retain(|k, v| {
-                let remove = prefix.is_prefix_of(k) && predicate(v);
-                !remove
-            });
-            Ok(old_len - self.data.len())
+        fn get_first(&mut self) -> anyhow::Result<E::Key> {
+            self.store.get_first().map_err(Into::into)
         }
-    }
 
-    #[derive(Debug)]
-    pub struct SimpleRangeIterator<'a, K, V> {
-        iter: std::collections::btree_map::Iter<'a, K, V>,
-        filter: SimpleFilter<K>,
-    }
+        fn get(&mut self, key: &E::Key) -> anyhow::Result<Option<E>> {
+            self.store.get(key).map_err(Into::into)
+        }
 
-    #[derive(Debug)]
-    enum SimpleFilter<K> {
-        None,
-        Range(Range<K>),
-        Prefix(K),
-    }
+        fn len(&mut self) -> anyhow::Result<usize> {
+            self.store.len().map_err(Into::into)
+        }
 
-    impl<'a, K, V> Iterator for SimpleRangeIterator<'a, K, V>
-    where
-        K: RangeKey + Default,
-        V: Clone,
-    {
-        type Item = Result<(K, V), Infallible>;
+        fn is_empty(&mut self) -> anyhow::Result<bool> {
+            self.store.is_empty().map_err(Into::into)
+        }
 
-        fn next(&mut self) -> Option<Self::Item> {
-            let mut next = self.iter.next()?;
+        fn get_fingerprint(&mut self, range: &Range<E::Key>) -> anyhow::Result<Fingerprint> {
+            self.store.get_fingerprint(range).map_err(Into::into)
+        }
 
-            let filter = |x: &K| match &self.filter {
-                SimpleFilter::None => true,
-                SimpleFilter::Range(range) => range.contains(x),
-                SimpleFilter::Prefix(prefix) => prefix.is_prefix_of(x),
-            };
+        fn entry_put(&mut self, entry: E) -> anyhow::Result<()> {
+            self.check()?;
+            self.store.entry_put(entry).map_err(Into::into)
+        }
 
-            loop {
-                if filter(next.0) {
-                    return Some(Ok((next.0.clone(), next.1.clone())));
-                }
+        fn get_range(&mut self, range: Range<E::Key>) -> anyhow::Result<Self::RangeIterator<'_>> {
+            let iter = self.store.get_range(range).map_err(Into::into)?;
+            Ok(iter.map(|res| res.map_err(Into::into)))
+        }
 
-                next = self.iter.next()?;
-            }
+        fn get_range_chunked<'a>(
+            &'a mut self,
+            range: Range<E::Key>,
+            chunk_size: usize,
+        ) -> anyhow::Result<ChunkIterator<'a, E, anyhow::Error>>
+        where
+            E: 'a,
+        {
+            let iter = self
+                .store
+                .get_range_chunked(range, chunk_size)
+                .map_err(Into::into)?;
+            Ok(Box::new(iter.map(|res| res.map_err(Into::into))))
         }
-    }
 
-    #[test]
-    fn test_paper_1() {
-        let alice_set = [("ape", 1), ("eel", 1), ("fox", 1), ("gnu", 1)];
-        let bob_set = [
-            ("bee", 1),
-            ("cat", 1),
-            ("doe", 1),
-            ("eel", 1),
-            ("fox", 1),
-            ("hog", 1),
-        ];
+        fn prefixed_by(&mut self, prefix: &E::Key) -> anyhow::Result<Self::RangeIterator<'_>> {
+            let iter = self.store.prefixed_by(prefix).map_err(Into::into)?;
+            Ok(iter.map(|res| res.map_err(Into::into)))
+        }
 
-        let res = sync(&alice_set, &bob_set);
-        res.print_messages();
-        assert_eq!(res.alice_to_bob.len(), 3, "A -> B message count");
-        assert_eq!(res.bob_to_alice.len(), 2, "B -> A message count");
+        fn prefixes_of(&mut self, key: &E::Key) -> anyhow::Result<Self::ParentIterator<'_>> {
+            let iter = self.store.prefixes_of(key).map_err(Into::into)?;
+            Ok(iter.map(|res| res.map_err(Into::into)))
+        }
 
-        // Initial message
-        assert_eq!(res.alice_to_bob[0].parts.len(), 1);
-        assert!(res.alice_to_bob[0].parts[0].is_range_fingerprint());
+        fn all(&mut self) -> anyhow::Result<Self::RangeIterator<'_>> {
+            let iter = self.store.all().map_err(Into::into)?;
+            Ok(iter.map(|res| res.map_err(Into::into)))
+        }
 
-        // Response from Bob - recurse once
-        assert_eq!(res.bob_to_alice[0].parts.len(), 2);
-        assert!(res.bob_to_alice[0].parts[0].is_range_fingerprint());
-        assert!(res.bob_to_alice[0].parts[1].is_range_fingerprint());
-        // Last response from Alice
-        assert_eq!(res.alice_to_bob[1].parts.len(), 3);
-        assert!(res.alice_to_bob[1].parts[0].is_range_fingerprint());
-        assert!(res.alice_to_bob[1].parts[1].is_range_fingerprint());
-        assert!(res.alice_to_bob[1].parts[2].is_range_item());
+        fn entry_remove(&mut self, key: &E::Key) -> anyhow::Result<Option<E>> {
+            self.check()?;
+            self.store.entry_remove(key).map_err(Into::into)
+        }
 
-        // Last response from Bob
-        assert_eq!(res.bob_to_alice[1].parts.len(), 2);
-        assert!(res.bob_to_alice[1].parts[0].is_range_item());
-        assert!(res.bob_to_alice[1].parts[1].is_range_item());
+        fn remove_prefix_filtered(
+            &mut self,
+            prefix: &E::Key,
+            predicate: impl Fn(&E::Value) -> bool,
+        ) -> anyhow::Result<usize> {
+            self.check()?;
+            self.store
+                .remove_prefix_filtered(prefix, predicate)
+                .map_err(Into::into)
+        }
     }
 
+    type TestMirroredStore = MirroredStore<
+        MemoryStore<(&'static str, i32)>,
+        FailingStore<MemoryStore<(&'static str, i32)>>,
+    >;
+
     #[test]
-    fn test_paper_2() {
-        let alice_set = [
-            ("ape", 1),
-            ("bee", 1),
-            ("cat", 1),
-            ("doe", 1),
-            ("eel", 1),
-            ("fox", 1), // the only value being sent
-            ("gnu", 1),
-            ("hog", 1),
-        ];
-        let bob_set = [
-            ("ape", 1),
-            ("bee", 1),
-            ("cat", 1),
-            ("doe", 1),
-            ("eel", 1),
-            ("gnu", 1),
-            ("hog", 1),
-        ];
+    fn mirrored_store_paper() {
+        for (alice_set, bob_set) in paper_sets() {
+            let new_store = || {
+                let secondary = FailingStore::<MemoryStore<_>>::default();
+                MirroredStore::new(MemoryStore::new(), secondary, Default::default())
+            };
+            assert_store_conformance(new_store, alice_set, bob_set);
+        }
 
-        let res = sync(&alice_set, &bob_set);
-        assert_eq!(res.alice_to_bob.len(), 3, "A -> B message count");
-        assert_eq!(res.bob_to_alice.len(), 2, "B -> A message count");
+        // syncing applies all changes to the secondary stores as well
+        let (alice_set, bob_set) = paper_sets()[0];
+        let new_store = |set: Set| -> TestMirroredStore {
+            let mut store = MirroredStore::new(
+                MemoryStore::new(),
+                FailingStore::default(),
+                Default::default(),
+            );
+            for e in set {
+                store.put(*e).unwrap();
+            }
+            store
+        };
+        let (mut alice, mut bob) = (new_store(alice_set), new_store(bob_set));
+        message_counts(&mut alice, &mut bob);
+        assert!(alice.check_consistency().unwrap());
+        assert!(bob.check_consistency().unwrap());
+        let (alice_primary, alice_secondary) = alice.into_inner();
+        assert_eq!(alice_primary, alice_secondary.store);
+        assert_eq!(bob.secondary().store, alice_primary);
     }
 
     #[test]
-    fn test_paper_3() {
-        let alice_set = [
-            ("ape", 1),
-            ("bee", 1),
-            ("cat", 1),
-            ("doe", 1),
-            ("eel", 1),
-            ("fox", 1),
-            ("gnu", 1),
-            ("hog", 1),
-        ];
-        let bob_set = [("ape", 1), ("cat", 1), ("eel", 1), ("gnu", 1)];
-
-        let res = sync(&alice_set, &bob_set);
-        assert_eq!(res.alice_to_bob.len(), 3, "A -> B message count");
-        assert_eq!(res.bob_to_alice.len(), 2, "B -> A message count");
+    fn mirrored_store_propagate_secondary_errors() {
+        let secondary = FailingStore::default();
+        let fail = secondary.fail.clone();
+        let mut store: TestMirroredStore = MirroredStore::new(
+            MemoryStore::new(),
+            secondary,
+            SecondaryErrorPolicy::Propagate,
+        );
+        store.entry_put(("ape", 1)).unwrap();
+        store.entry_put(("bee", 1)).unwrap();
+        assert!(store.check_consistency().unwrap());
+
+        fail.set(true);
+        assert!(store.entry_put(("cat", 1)).is_err());
+        assert!(store.entry_remove(&"ape").is_err());
+        assert!(store.remove_prefix_filtered(&"b", |_| true).is_err());
+        assert!(store.take_secondary_errors().is_empty());
+        // the primary store has been written to anyway
+        assert_eq!(collect(store.all().unwrap()), [("cat", 1)]);
+        assert!(!store.check_consistency().unwrap());
+
+        fail.set(false);
+        store.entry_put(("ape", 1)).unwrap();
+        store.entry_remove(&"ape").unwrap();
+        store.entry_put(("bee", 1)).unwrap();
+        store.entry_remove(&"bee").unwrap();
+        store.entry_put(("cat", 1)).unwrap();
+        assert!(store.check_consistency().unwrap());
     }
 
     #[test]
-    fn test_limits() {
-        let alice_set = [("ape", 1), ("bee", 1), ("cat", 1)];
-        let bob_set = [("ape", 1), ("cat", 1), ("doe", 1)];
-
-        let res = sync(&alice_set, &bob_set);
-        assert_eq!(res.alice_to_bob.len(), 2, "A -> B message count");
-        assert_eq!(res.bob_to_alice.len(), 2, "B -> A message count");
+    fn mirrored_store_record_secondary_errors() {
+        let secondary = FailingStore::default();
+        let fail = secondary.fail.clone();
+        let mut store: TestMirroredStore =
+            MirroredStore::new(MemoryStore::new(), secondary, SecondaryErrorPolicy::Record);
+        store.entry_put(("ape", 1)).unwrap();
+        store.entry_put(("bee", 1)).unwrap();
+
+        fail.set(true);
+        store.entry_put(("cat", 1)).unwrap();
+        assert_eq!(store.entry_remove(&"ape").unwrap(), Some(("ape", 1)));
+        assert_eq!(store.remove_prefix_filtered(&"b", |_| true).unwrap(), 1);
+        let errors = store.take_secondary_errors();
+        assert_eq!(errors.len(), 3);
+        assert_eq!(errors[0].to_string(), "injected failure");
+        assert!(store.take_secondary_errors().is_empty());
+
+        assert_eq!(collect(store.all().unwrap()), [("cat", 1)]);
+        let secondary = store.secondary().store.clone();
+        assert_eq!(
+            secondary.into_entries().collect::<Vec<_>>(),
+            [("ape", 1), ("bee", 1)]
+        );
+        assert!(!store.check_consistency().unwrap());
     }
 
     #[test]
-    fn test_prefixes_simple() {
-        let alice_set = [("/foo/bar", 1), ("/foo/baz", 1), ("/foo/cat", 1)];
-        let bob_set = [("/foo/bar", 1), ("/alice/bar", 1), ("/alice/baz", 1)];
-
-        let res = sync(&alice_set, &bob_set);
-        assert_eq!(res.alice_to_bob.len(), 2, "A -> B message count");
-        assert_eq!(res.bob_to_alice.len(), 2, "B -> A message count");
+    fn bounded_store_evicts() {
+        let evicted = Arc::new(Mutex::new(Vec::new()));
+        let mut store = BoundedStore::new(MemoryStore::new())
+            .unwrap()
+            .with_max_entries(3)
+            .with_max_bytes(10, |(key, _): &(&str, i32)| key.len())
+            .with_on_evict({
+                let evicted = evicted.clone();
+                move |entry| evicted.lock().unwrap().push(*entry)
+            });
+        for key in ["eel", "ape", "fox"] {
+            store.put((key, 1)).unwrap();
+        }
+        assert!(evicted.lock().unwrap().is_empty());
+        // replacing an entry makes it the most recently inserted one
+        store.put(("eel", 2)).unwrap();
+        store.put(("bee", 1)).unwrap();
+        assert_eq!(*evicted.lock().unwrap(), [("ape", 1)]);
+        // too large for the byte budget together with the other entries
+        store.put(("gnu-gnu", 1)).unwrap();
+        assert_eq!(
+            *evicted.lock().unwrap(),
+            [("ape", 1), ("fox", 1), ("eel", 2)]
+        );
+        assert_eq!(store.bytes(), 10);
+        store.entry_remove(&"bee").unwrap();
+        assert_eq!(store.bytes(), 7);
+
+        // the fingerprints match a store with the remaining entries
+        let mut expected = MemoryStore::new();
+        expected.put(("gnu-gnu", 1)).unwrap();
+        for (x, y) in [("", ""), ("a", "h"), ("h", "a")] {
+            assert_same_range(&mut expected, &mut store, Range::new(x, y));
+        }
     }
 
     #[test]
-    fn test_prefixes_empty_alice() {
-        let alice_set = [];
-        let bob_set = [("/foo/bar", 1), ("/alice/bar", 1), ("/alice/baz", 1)];
+    fn bounded_store_sync_newest() {
+        let keys: Vec<String> = (0..20).map(|i| format!("{i:02}")).collect();
+        let evicted = Arc::new(Mutex::new(Vec::new()));
+        let mut alice = BoundedStore::new(MemoryStore::new())
+            .unwrap()
+            .with_max_entries(5)
+            .with_policy(EvictionPolicy::KeyOrder)
+            .with_on_evict({
+                let evicted = evicted.clone();
+                move |entry: &(String, u8)| evicted.lock().unwrap().push(entry.0.clone())
+            });
+        for key in &keys[..3] {
+            alice.put((key.clone(), 1)).unwrap();
+        }
+        let mut bob = MemoryStore::new();
+        for key in &keys {
+            bob.put((key.clone(), 1)).unwrap();
+        }
 
-        let res = sync(&alice_set, &bob_set);
-        assert_eq!(res.alice_to_bob.len(), 1, "A -> B message count");
-        assert_eq!(res.bob_to_alice.len(), 1, "B -> A message count");
+        for _ in 0..3 {
+            let mut next_to_bob = Some(alice.initial_message().unwrap());
+            while let Some(msg) = next_to_bob.take() {
+                let reply = bob
+                    .process_message_reply(
+                        &Default::default(),
+                        msg,
+                        |_, _, _| true,
+                        |_, _, _| (),
+                        |_, _| ContentStatus::Complete,
+                    )
+                    .unwrap();
+                if let Some(msg) = reply {
+                    next_to_bob = alice
+                        .process_message_reply(
+                            &Default::default(),
+                            msg,
+                            |store, entry, _| store.admits(entry),
+                            |_, _, _| (),
+                            |_, _| ContentStatus::Complete,
+                        )
+                        .unwrap();
+                }
+            }
+            let alice_keys: Vec<_> = collect(alice.all().unwrap())
+                .into_iter()
+                .map(|(key, _)| key)
+                .collect();
+            assert_eq!(alice_keys, keys[15..]);
+        }
+        // only entries that were once newest are evicted, and none are inserted again after
+        // the first sync
+        let evicted = evicted.lock().unwrap();
+        assert!(evicted.len() < 15, "{evicted:?}");
+        assert!(evicted.iter().all(|key| key < &keys[15]));
+        let mut deduped = evicted.clone();
+        deduped.dedup();
+        assert_eq!(*evicted, deduped);
     }
 
-    #[test]
-    fn test_prefixes_empty_bob() {
-        let alice_set = [("/foo/bar", 1), ("/foo/baz", 1), ("/foo/cat", 1)];
-        let bob_set = [];
-
-        let res = sync(&alice_set, &bob_set);
-        assert_eq!(res.alice_to_bob.len(), 2, "A -> B message count");
-        assert_eq!(res.bob_to_alice.len(), 1, "B -> A message count");
+    fn to_owned_set(set: Set) -> Vec<(String, i32)> {
+        set.iter().map(|(k, v)| (k.to_string(), *v)).collect()
     }
 
     #[test]
-    fn test_equal_key_higher_value() {
-        let alice_set = [("foo", 2)];
-        let bob_set = [("foo", 1)];
+    #[cfg(feature = "redb-store")]
+    fn redb_store_paper() {
+        for (alice_set, bob_set) in paper_sets() {
+            assert_store_conformance(
+                || RedbStore::memory().unwrap(),
+                &to_owned_set(alice_set),
+                &to_owned_set(bob_set),
+            );
+        }
+    }
 
-        let res = sync(&alice_set, &bob_set);
-        assert_eq!(res.alice_to_bob.len(), 2, "A -> B message count");
-        assert_eq!(res.bob_to_alice.len(), 1, "B -> A message count");
+    #[cfg(feature = "redb-store")]
+    #[proptest(ProptestConfig { cases: 32, ..Default::default() })]
+    fn redb_store_sync(
+        #[strategy(test_vec_string_u8())] alice: Vec<(String, u8)>,
+        #[strategy(test_vec_string_u8())] bob: Vec<(String, u8)>,
+    ) {
+        assert_store_conformance(|| RedbStore::memory().unwrap(), &alice, &bob);
     }
 
     #[test]
-    fn test_multikey() {
-        /// Uses the blanket impl of [`RangeKey]` for `T: AsRef<[u8]>` in this module.
-        #[derive(Default, Clone, PartialEq, Eq, PartialOrd, Ord)]
-        struct Multikey {
-            author: [u8; 4],
-            key: Vec<u8>,
-        }
-
-        impl RangeKey for Multikey {
-            fn is_prefix_of(&self, other: &Self) -> bool {
-                self.author == other.author && self.key.starts_with(&other.key)
+    #[cfg(feature = "redb-store")]
+    fn redb_store_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ranger.db");
+        let mut expected = MemoryStore::default();
+        {
+            let mut store = RedbStore::persistent(&path).unwrap();
+            for (alice_set, bob_set) in paper_sets() {
+                for e in to_owned_set(alice_set)
+                    .into_iter()
+                    .chain(to_owned_set(bob_set))
+                {
+                    store.put(e.clone()).unwrap();
+                    expected.put(e).unwrap();
+                }
             }
+            store.put(("e".to_string(), 2)).unwrap();
+            expected.put(("e".to_string(), 2)).unwrap();
+            store.entry_remove(&"cat".to_string()).unwrap();
+            expected.entry_remove(&"cat".to_string()).unwrap();
         }
 
-        impl Debug for Multikey {
-            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-                let key = if let Ok(key) = std::str::from_utf8(&self.key) {
-                    key.to_string()
-                } else {
-                    hex::encode(&self.key)
-                };
-                f.debug_struct("Multikey")
-                    .field("author", &hex::encode(self.author))
-                    .field("key", &key)
-                    .finish()
-            }
-        }
+        let mut store = RedbStore::<(String, i32)>::persistent(&path).unwrap();
+        let entries = collect(store.all().unwrap());
+        assert_eq!(entries, collect(expected.all().unwrap()));
+        assert!(!entries.iter().any(|(k, _)| k.starts_with('e') && k != "e"));
 
-        impl Multikey {
-            fn new(author: [u8; 4], key: impl AsRef<[u8]>) -> Self {
-                Multikey {
-                    author,
-                    key: key.as_ref().to_vec(),
-                }
-            }
+        // the fingerprints maintained on disk match the entries
+        let mut fingerprint = Fingerprint::empty();
+        for e in &entries {
+            fingerprint ^= e.as_fingerprint();
         }
-        let author_a = [1u8; 4];
-        let author_b = [2u8; 4];
-        let alice_set = [
-            (Multikey::new(author_a, "ape"), 1),
-            (Multikey::new(author_a, "bee"), 1),
-            (Multikey::new(author_b, "bee"), 1),
-            (Multikey::new(author_a, "doe"), 1),
-        ];
-        let bob_set = [
-            (Multikey::new(author_a, "ape"), 1),
-            (Multikey::new(author_a, "bee"), 1),
-            (Multikey::new(author_a, "cat"), 1),
-            (Multikey::new(author_b, "cat"), 1),
-        ];
+        let all = Range::new(String::new(), String::new());
+        assert_eq!(store.get_fingerprint(&all).unwrap(), fingerprint);
+        assert_eq!(store.len().unwrap(), entries.len());
+        for (x, y) in [("a", "f"), ("f", "a"), ("cat", "doe")] {
+            let range = Range::new(x.to_string(), y.to_string());
+            assert_same_range(&mut expected, &mut store, range);
+        }
+    }
 
-        // No limit
-        let mut res = sync(&alice_set, &bob_set);
-        assert_eq!(res.alice_to_bob.len(), 2, "A -> B message count");
-        assert_eq!(res.bob_to_alice.len(), 2, "B -> A message count");
-        res.assert_alice_set(
-            "no limit",
-            &[
-                (Multikey::new(author_a, "ape"), 1),
-                (Multikey::new(author_a, "bee"), 1),
-                (Multikey::new(author_b, "bee"), 1),
-                (Multikey::new(author_a, "doe"), 1),
-                (Multikey::new(author_a, "cat"), 1),
-                (Multikey::new(author_b, "cat"), 1),
-            ],
-        );
+    #[test]
+    #[cfg(feature = "sqlite-store")]
+    fn sqlite_store_paper() {
+        for (alice_set, bob_set) in paper_sets() {
+            assert_store_conformance(
+                || SqliteStore::memory().unwrap(),
+                &to_owned_set(alice_set),
+                &to_owned_set(bob_set),
+            );
+        }
+    }
 
-        res.assert_bob_set(
-            "no limit",
-            &[
-                (Multikey::new(author_a, "ape"), 1),
-                (Multikey::new(author_a, "bee"), 1),
-                (Multikey::new(author_b, "bee"), 1),
-                (Multikey::new(author_a, "doe"), 1),
-                (Multikey::new(author_a, "cat"), 1),
-                (Multikey::new(author_b, "cat"), 1),
-            ],
-        );
+    #[cfg(feature = "sqlite-store")]
+    #[proptest(ProptestConfig { cases: 32, ..Default::default() })]
+    fn sqlite_store_sync(
+        #[strategy(test_vec_string_u8())] alice: Vec<(String, u8)>,
+        #[strategy(test_vec_string_u8())] bob: Vec<(String, u8)>,
+    ) {
+        assert_store_conformance(|| SqliteStore::memory().unwrap(), &alice, &bob);
     }
 
-    // This tests two things:
-    // 1) validate cb returning false leads to no changes on both sides after sync
-    // 2) validate cb receives expected entries
     #[test]
-    fn test_validate_cb() {
-        let alice_set = [("alice1", 1), ("alice2", 2)];
-        let bob_set = [("bob1", 3), ("bob2", 4), ("bob3", 5)];
-        let alice_validate_set = Rc::new(RefCell::new(vec![]));
-        let bob_validate_set = Rc::new(RefCell::new(vec![]));
-
-        let validate_alice: ValidateCb<&str, i32> = Box::new({
-            let alice_validate_set = alice_validate_set.clone();
-            move |_, e, _| {
-                alice_validate_set.borrow_mut().push(*e);
-                false
+    #[cfg(feature = "sqlite-store")]
+    fn sqlite_store_large() {
+        const N: usize = 50_000;
+        let (mut alice, mut bob) = (
+            SqliteStore::memory().unwrap(),
+            SqliteStore::memory().unwrap(),
+        );
+        for i in 0..N {
+            let key = format!("{i:08}");
+            // each side is missing a few entries the other side has
+            if i % 5000 != 0 {
+                alice.entry_put((key.clone(), 1)).unwrap();
             }
-        });
-        let validate_bob: ValidateCb<&str, i32> = Box::new({
-            let bob_validate_set = bob_validate_set.clone();
-            move |_, e, _| {
-                bob_validate_set.borrow_mut().push(*e);
-                false
+            if i % 5000 != 2500 {
+                bob.entry_put((key, 1)).unwrap();
             }
-        });
-
-        let mut alice = SimpleStore::default();
-        for (k, v) in alice_set {
-            alice.put((k, v)).unwrap();
-        }
-
-        let mut bob = SimpleStore::default();
-        for (k, v) in bob_set {
-            bob.put((k, v)).unwrap();
         }
-
-        // run sync with a validate callback returning false, so no new entries are stored on either side
-        let mut res = sync_exchange_messages(alice, bob, &validate_alice, &validate_bob, 100);
-        res.assert_alice_set("unchanged", &alice_set);
-        res.assert_bob_set("unchanged", &bob_set);
-
-        // assert that the validate callbacks received all expected entries
-        assert_eq!(alice_validate_set.take(), bob_set);
-        assert_eq!(bob_validate_set.take(), alice_set);
+        assert_eq!(alice.len().unwrap(), N - 10);
+
+        let (alice_to_bob, bob_to_alice) = message_counts(&mut alice, &mut bob);
+        // ranges are split in half in every round, so the number of rounds is logarithmic
+        assert!(alice_to_bob <= 20, "{alice_to_bob} rounds");
+        assert!(bob_to_alice <= alice_to_bob);
+
+        assert_eq!(alice.len().unwrap(), N);
+        assert_eq!(bob.len().unwrap(), N);
+        let all = Range::new(String::new(), String::new());
+        assert_eq!(
+            alice.get_fingerprint(&all).unwrap(),
+            bob.get_fingerprint(&all).unwrap()
+        );
     }
 
-    struct SyncResult<K, V>
-    where
-        K: RangeKey + Default,
-        V: RangeValue,
-    {
-        alice: SimpleStore<K, V>,
-        bob: SimpleStore<K, V>,
-        alice_to_bob: Vec<Message<(K, V)>>,
-        bob_to_alice: Vec<Message<(K, V)>>,
+    #[test]
+    fn log_store_paper() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut i = 0;
+        let mut new_store = || {
+            i += 1;
+            LogStore::open(dir.path().join(format!("{i}.log"))).unwrap()
+        };
+        for (alice_set, bob_set) in paper_sets() {
+            assert_store_conformance(
+                &mut new_store,
+                &to_owned_set(alice_set),
+                &to_owned_set(bob_set),
+            );
+        }
     }
 
-    impl<K, V> SyncResult<K, V>
-    where
-        K: RangeKey + Default,
-        V: RangeValue,
-    {
-        fn print_messages(&self) {
-            let len = std::cmp::max(self.alice_to_bob.len(), self.bob_to_alice.len());
-            for i in 0..len {
-                if let Some(msg) = self.alice_to_bob.get(i) {
-                    println!("A -> B:");
-                    print_message(msg);
-                }
-                if let Some(msg) = self.bob_to_alice.get(i) {
-                    println!("B -> A:");
-                    print_message(msg);
+    #[test]
+    fn log_store_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ranger.log");
+        let mut expected = MemoryStore::default();
+        {
+            let mut store = LogStore::open(&path).unwrap();
+            for (alice_set, bob_set) in paper_sets() {
+                for e in to_owned_set(alice_set)
+                    .into_iter()
+                    .chain(to_owned_set(bob_set))
+                {
+                    store.put(e.clone()).unwrap();
+                    expected.put(e).unwrap();
                 }
             }
+            store.put(("e".to_string(), 2)).unwrap();
+            expected.put(("e".to_string(), 2)).unwrap();
+            store.entry_remove(&"cat".to_string()).unwrap();
+            expected.entry_remove(&"cat".to_string()).unwrap();
+            store.put(("bee".to_string(), 3)).unwrap();
+            expected.put(("bee".to_string(), 3)).unwrap();
         }
 
-        fn assert_alice_set(&mut self, ctx: &str, expected: &[(K, V)]) {
-            dbg!(self.alice.all().unwrap().collect::<Vec<_>>());
-            for e in expected {
-                assert_eq!(
-                    self.alice.get(e.key()).unwrap().as_ref(),
-                    Some(e),
-                    "{}: (alice) missing key {:?}",
-                    ctx,
-                    e.key()
-                );
-            }
-            assert_eq!(
-                expected.len(),
-                self.alice.len().unwrap(),
-                "{}: (alice)",
-                ctx
-            );
+        let all = Range::new(String::new(), String::new());
+        let mut store = LogStore::<(String, i32)>::open(&path).unwrap();
+        assert_eq!(
+            store.get_fingerprint(&all).unwrap(),
+            expected.get_fingerprint(&all).unwrap()
+        );
+        assert_eq!(
+            collect(store.all().unwrap()),
+            collect(expected.all().unwrap())
+        );
+        for (x, y) in [("a", "f"), ("f", "a"), ("cat", "doe")] {
+            let range = Range::new(x.to_string(), y.to_string());
+            assert_same_range(&mut expected, &mut store, range);
         }
 
-        fn assert_bob_set(&mut self, ctx: &str, expected: &[(K, V)]) {
-            dbg!(self.bob.all().unwrap().collect::<Vec<_>>());
+        // compaction drops the overwritten and removed records
+        let log_len = store.log_len();
+        store.compact().unwrap();
+        assert!(store.log_len() < log_len);
+        assert_eq!(
+            store.get_fingerprint(&all).unwrap(),
+            expected.get_fingerprint(&all).unwrap()
+        );
+        drop(store);
+        let mut store = LogStore::<(String, i32)>::open(&path).unwrap();
+        assert_eq!(
+            store.get_fingerprint(&all).unwrap(),
+            expected.get_fingerprint(&all).unwrap()
+        );
+        assert_eq!(
+            collect(store.all().unwrap()),
+            collect(expected.all().unwrap())
+        );
 
-            for e in expected {
-                assert_eq!(
-                    self.bob.get(e.key()).unwrap().as_ref(),
-                    Some(e),
-                    "{}: (bob) missing key {:?}",
-                    ctx,
-                    e
-                );
-            }
-            assert_eq!(expected.len(), self.bob.len().unwrap(), "{}: (bob)", ctx);
+        // sync the reopened store with a memory store
+        let mut bob = MemoryStore::default();
+        for e in [("ape", 2), ("cat", 1), ("zebra", 1)] {
+            bob.put((e.0.to_string(), e.1)).unwrap();
+            expected.put((e.0.to_string(), e.1)).unwrap();
         }
+        message_counts(&mut store, &mut bob);
+        assert_eq!(
+            collect(store.all().unwrap()),
+            collect(expected.all().unwrap())
+        );
+        assert_eq!(
+            collect(bob.all().unwrap()),
+            collect(expected.all().unwrap())
+        );
+        drop(store);
+        let mut store = LogStore::<(String, i32)>::open(&path).unwrap();
+        assert_eq!(
+            store.get_fingerprint(&all).unwrap(),
+            bob.get_fingerprint(&all).unwrap()
+        );
     }
 
-    fn print_message<E: RangeEntry>(msg: &Message<E>) {
-        for part in &msg.parts {
-            match part {
-                MessagePart::RangeFingerprint(RangeFingerprint { range, fingerprint }) => {
-                    println!(
-                        "  RangeFingerprint({:?}, {:?}, {:?})",
-                        range.x(),
-                        range.y(),
-                        fingerprint
-                    );
-                }
-                MessagePart::RangeItem(RangeItem {
-                    range,
-                    values,
-                    have_local,
-                }) => {
-                    println!(
-                        "  RangeItem({:?} | {:?}) (local?: {})\n  {:?}",
-                        range.x(),
-                        range.y(),
-                        have_local,
-                        values,
-                    );
-                }
-            }
+    #[test]
+    fn log_store_truncated() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ranger.log");
+        let mut store = LogStore::open(&path).unwrap();
+        store.put(("ape".to_string(), 1)).unwrap();
+        let len = store.log_len();
+        store.put(("bee".to_string(), 1)).unwrap();
+        drop(store);
+
+        // a partly written final record is removed when the log is opened
+        let file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+        let torn_len = file.metadata().unwrap().len() - 1;
+        for torn in [torn_len, len + 3] {
+            file.set_len(torn).unwrap();
+            let mut store = LogStore::<(String, i32)>::open(&path).unwrap();
+            assert_eq!(store.log_len(), len);
+            assert_eq!(collect(store.all().unwrap()), [("ape".to_string(), 1)]);
         }
+        assert_eq!(file.metadata().unwrap().len(), len);
+        // records are appended after the last complete one
+        let mut store = LogStore::<(String, i32)>::open(&path).unwrap();
+        store.put(("cat".to_string(), 1)).unwrap();
+        drop(store);
+        let mut store = LogStore::<(String, i32)>::open(&path).unwrap();
+        assert_eq!(
+            collect(store.all().unwrap()),
+            [("ape".to_string(), 1), ("cat".to_string(), 1)]
+        );
+        drop(store);
+
+        // an invalid record before the end of the log is an error
+        let mut log = std::fs::read(&path).unwrap();
+        log[1] ^= 1;
+        std::fs::write(&path, log).unwrap();
+        let res = LogStore::<(String, i32)>::open(&path);
+        assert!(matches!(res, Err(LogStoreError::Corrupt(0))));
     }
 
-    type ValidateCb<K, V> = Box<dyn Fn(&SimpleStore<K, V>, &(K, V), ContentStatus) -> bool>;
+    #[test]
+    fn memory_set_store_paper() {
+        for (alice_set, bob_set) in paper_sets() {
+            let keys =
+                |set: Set| -> Vec<_> { set.iter().map(|(k, _)| SetEntry(k.to_string())).collect() };
+            assert_store_conformance(MemorySetStore::new, &keys(alice_set), &keys(bob_set));
+        }
+    }
 
-    fn sync<K, V>(alice_set: &[(K, V)], bob_set: &[(K, V)]) -> SyncResult<K, V>
+    /// Runs a sync between alice and bob, and returns the total size of the encoded messages.
+    fn message_bytes<E, S>(alice: &mut S, bob: &mut S) -> usize
     where
-        K: RangeKey + Default,
-        V: RangeValue,
+        E: RangeEntry + Serialize,
+        E::Key: Serialize,
+        S: Store<E>,
     {
-        let alice_validate_cb: ValidateCb<K, V> = Box::new(|_, _, _| true);
-        let bob_validate_cb: ValidateCb<K, V> = Box::new(|_, _, _| true);
-        sync_with_validate_cb_and_assert(alice_set, bob_set, &alice_validate_cb, &bob_validate_cb)
+        let mut bytes = 0;
+        let mut msg = Some(alice.initial_message().unwrap());
+        let mut turn = 0;
+        while let Some(m) = msg.take() {
+            bytes += postcard::to_stdvec(&m).unwrap().len();
+            let store = if turn % 2 == 0 {
+                &mut *bob
+            } else {
+                &mut *alice
+            };
+            msg = store
+                .process_message_reply(
+                    &Default::default(),
+                    m,
+                    |_, _, _| true,
+                    |_, _, _| (),
+                    |_, _| ContentStatus::Complete,
+                )
+                .unwrap();
+            turn += 1;
+        }
+        bytes
     }
 
-    fn insert_if_larger<K: RangeKey, V: RangeValue>(map: &mut BTreeMap<K, V>, key: K, value: V) {
-        let mut insert = true;
-        for (k, v) in map.iter() {
-            if k.is_prefix_of(&key) && v >= &value {
-                insert = false;
-            }
+    #[test]
+    fn memory_set_store_sync() {
+        let alice_set: BTreeSet<String> = ["ape", "eel", "fox", "gnu"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        let bob_set: BTreeSet<String> = ["bee", "cat", "doe", "eel", "fox", "hog"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        let union: BTreeSet<String> = alice_set.union(&bob_set).cloned().collect();
+
+        let mut alice = MemorySetStore::from(alice_set.clone());
+        let mut bob = MemorySetStore::from(bob_set.clone());
+        let set_bytes = message_bytes(&mut alice, &mut bob);
+        assert_eq!(alice.as_set(), &union);
+        assert_eq!(bob.into_set(), union);
+
+        // the same sync with `(K, ())` entries
+        let pairs = |set: &BTreeSet<String>| -> MemoryStore<(String, ())> {
+            set.iter().map(|k| (k.clone(), ())).collect()
+        };
+        let (mut alice, mut bob) = (pairs(&alice_set), pairs(&bob_set));
+        let pair_bytes = message_bytes(&mut alice, &mut bob);
+        assert!(set_bytes <= pair_bytes, "{set_bytes} > {pair_bytes}");
+
+        // entries are encoded as the bare key
+        let key = "ape".to_string();
+        assert_eq!(
+            postcard::to_stdvec(&SetEntry(key.clone())).unwrap(),
+            postcard::to_stdvec(&key).unwrap()
+        );
+    }
+
+    #[test]
+    fn instrumented_store_paper() {
+        let alice_set = [("ape", 1), ("eel", 1), ("fox", 1), ("gnu", 1)];
+        let bob_set = [
+            ("bee", 1),
+            ("cat", 1),
+            ("doe", 1),
+            ("eel", 1),
+            ("fox", 1),
+            ("hog", 1),
+        ];
+        let mut alice = InstrumentedStore::new(MemoryStore::default());
+        let mut bob = InstrumentedStore::new(MemoryStore::default());
+        for e in alice_set {
+            alice.put(e).unwrap();
         }
-        if insert {
-            #[allow(clippy::needless_bool)]
-            map.retain(|k, v| {
-                if key.is_prefix_of(k) && value >= *v {
-                    false
-                } else {
-                    true
-                }
-            });
-            map.insert(key, value);
+        for e in bob_set {
+            bob.put(e).unwrap();
         }
+        assert_eq!(alice.stats().entries_written, 4);
+        alice.reset();
+        bob.reset();
+        assert_eq!(alice.stats(), StoreStats::default());
+
+        assert_eq!(message_counts(&mut alice, &mut bob), (3, 2));
+
+        // Each side writes exactly the entries it was missing, so no value was sent twice.
+        let alice_stats = alice.stats();
+        let bob_stats = bob.stats();
+        assert_eq!(alice_stats.entries_written, 4, "bee, cat, doe, hog");
+        assert_eq!(bob_stats.entries_written, 2, "ape, gnu");
+        assert_eq!(alice_stats.entry_put, 4);
+        assert_eq!(bob_stats.entry_put, 2);
+
+        assert_eq!(alice_stats.prefixes_of, 4);
+        assert_eq!(bob_stats.prefixes_of, 2);
+
+        assert_eq!(alice_stats.get_first, 1);
+        assert_eq!(bob_stats.get_first, 0);
+        // the ranges a range is split into are read once, along with their fingerprint
+        assert_eq!(alice_stats.get_fingerprint, 3);
+        assert_eq!(bob_stats.get_fingerprint, 3);
+        assert_eq!(alice_stats.get_range_len, 2);
+        assert_eq!(bob_stats.get_range_len, 2);
+        assert_eq!(alice_stats.get_range, 4);
+        assert_eq!(bob_stats.get_range, 4);
+        // the pivots of a range are read in a single pass
+        assert_eq!(alice_stats.get_range_chunked, 1);
+        assert_eq!(bob_stats.get_range_chunked, 1);
+
+        assert_eq!(alice.inner(), bob.inner());
     }
 
-    fn sync_with_validate_cb_and_assert<K, V, F1, F2>(
-        alice_set: &[(K, V)],
-        bob_set: &[(K, V)],
-        alice_validate_cb: F1,
-        bob_validate_cb: F2,
-    ) -> SyncResult<K, V>
-    where
-        K: RangeKey + Default,
-        V: RangeValue,
-        F1: Fn(&SimpleStore<K, V>, &(K, V), ContentStatus) -> bool,
-        F2: Fn(&SimpleStore<K, V>, &(K, V), ContentStatus) -> bool,
-    {
-        let mut alice = SimpleStore::<K, V>::default();
-        let mut bob = SimpleStore::<K, V>::default();
-
-        let expected_set = {
-            let mut expected_set = BTreeMap::new();
-            let mut alice_expected = BTreeMap::new();
-            for e in alice_set {
-                alice.put(e.clone()).unwrap();
-                insert_if_larger(&mut expected_set, e.0.clone(), e.1.clone());
-                insert_if_larger(&mut alice_expected, e.0.clone(), e.1.clone());
-            }
-            let alice_expected = alice_expected.into_iter().collect::<Vec<_>>();
-            let alice_now: Vec<_> = alice.all().unwrap().collect::<Result<_, _>>().unwrap();
-            assert_eq!(
-                alice_expected, alice_now,
-                "alice initial set does not match"
-            );
-
-            let mut bob_expected = BTreeMap::new();
-            for e in bob_set {
-                bob.put(e.clone()).unwrap();
-                insert_if_larger(&mut expected_set, e.0.clone(), e.1.clone());
-                insert_if_larger(&mut bob_expected, e.0.clone(), e.1.clone());
+    #[test]
+    fn overlay_store_sync() {
+        // ("/foo", 3) from bob replaces ("/foo/bar", 1) on alice's side
+        let alice_set = [
+            ("/foo/bar", 1),
+            ("/foo/baz", 4),
+            ("ape", 1),
+            ("eel", 1),
+            ("fox", 1),
+        ];
+        let bob_set = [("/foo", 3), ("bee", 1), ("cat", 1), ("eel", 2), ("hog", 1)];
+        let mk_store = |set: &[(&'static str, i32)]| {
+            let mut store = MemoryStore::default();
+            for e in set {
+                store.put(*e).unwrap();
             }
-            let bob_expected = bob_expected.into_iter().collect::<Vec<_>>();
-            let bob_now: Vec<_> = bob.all().unwrap().collect::<Result<_, _>>().unwrap();
-            assert_eq!(bob_expected, bob_now, "bob initial set does not match");
-
-            expected_set.into_iter().collect::<Vec<_>>()
+            store
         };
 
-        let mut res = sync_exchange_messages(alice, bob, alice_validate_cb, bob_validate_cb, 100);
-
-        let alice_now: Vec<_> = res.alice.all().unwrap().collect::<Result<_, _>>().unwrap();
-        if alice_now != expected_set {
-            res.print_messages();
-            println!("alice_init: {alice_set:?}");
-            println!("bob_init:   {bob_set:?}");
-            println!("expected:   {expected_set:?}");
-            println!("alice_now:  {alice_now:?}");
-            panic!("alice_now does not match expected");
-        }
+        // sync directly, to compare against
+        let mut expected = mk_store(&alice_set);
+        message_counts(&mut expected, &mut mk_store(&bob_set));
+
+        let mut overlay = OverlayStore::new(mk_store(&alice_set), MemoryStore::default());
+        message_counts(&mut overlay, &mut mk_store(&bob_set));
+        let actual: Vec<_> = overlay.all().unwrap().collect::<Result<_, _>>().unwrap();
+        let expected_entries: Vec<_> = expected.all().unwrap().collect::<Result<_, _>>().unwrap();
+        assert_eq!(actual, expected_entries);
+        assert!(!actual.contains(&("/foo/bar", 1)));
+
+        // discarding leaves the base untouched
+        let base = overlay.discard();
+        assert_eq!(base, mk_store(&alice_set));
+
+        // committing is equivalent to syncing directly
+        let mut overlay = OverlayStore::new(base, MemoryStore::default());
+        message_counts(&mut overlay, &mut mk_store(&bob_set));
+        let base = overlay.commit().unwrap();
+        assert_eq!(base, expected);
+    }
 
-        let bob_now: Vec<_> = res.bob.all().unwrap().collect::<Result<_, _>>().unwrap();
-        if bob_now != expected_set {
-            res.print_messages();
-            println!("alice_init: {alice_set:?}");
-            println!("bob_init:   {bob_set:?}");
-            println!("expected:   {expected_set:?}");
-            println!("bob_now:    {bob_now:?}");
-            panic!("bob_now does not match expected");
+    #[proptest]
+    fn overlay_store_matches_simple_store(
+        #[strategy(test_vec_string_u8())] base: Vec<(String, u8)>,
+        #[strategy(test_vec_string_u8())] puts: Vec<(String, u8)>,
+        #[strategy(test_updates())] updates: Vec<(prop::sample::Index, Option<u8>)>,
+        #[strategy(test_range())] range: Range<String>,
+        #[strategy(1usize..4)] chunk_size: usize,
+    ) {
+        let mut expected = MemoryStore::<(String, u8)>::default();
+        let mut base_store = MemoryStore::<(String, u8)>::default();
+        for e in &base {
+            expected.entry_put(e.clone()).unwrap();
+            base_store.entry_put(e.clone()).unwrap();
         }
-
-        // Check that values were never sent twice
-        let mut alice_sent = BTreeMap::new();
-        for msg in &res.alice_to_bob {
-            for part in &msg.parts {
-                if let Some(values) = part.values() {
-                    for (e, _) in values {
-                        assert!(
-                            alice_sent.insert(e.key(), e).is_none(),
-                            "alice: duplicate {:?}",
-                            e
-                        );
+        let mut overlay = OverlayStore::new(base_store, MemoryStore::default());
+
+        // update and remove entries from the base, and add new entries
+        if !base.is_empty() {
+            for (index, value) in updates {
+                let key = base[index.index(base.len())].0.clone();
+                match value {
+                    Some(value) => {
+                        expected.entry_put((key.clone(), value)).unwrap();
+                        overlay.entry_put((key, value)).unwrap();
                     }
-                }
-            }
-        }
-
-        let mut bob_sent = BTreeMap::new();
-        for msg in &res.bob_to_alice {
-            for part in &msg.parts {
-                if let Some(values) = part.values() {
-                    for (e, _) in values {
-                        assert!(
-                            bob_sent.insert(e.key(), e).is_none(),
-                            "bob: duplicate {:?}",
-                            e
-                        );
+                    None => {
+                        let res = expected.entry_remove(&key).unwrap();
+                        prop_assert_eq!(overlay.entry_remove(&key).unwrap(), res);
                     }
                 }
             }
         }
+        for e in puts {
+            expected.entry_put(e.clone()).unwrap();
+            overlay.entry_put(e).unwrap();
+        }
 
-        res
+        prop_assert_eq!(
+            collect(overlay.all().unwrap()),
+            collect(expected.all().unwrap())
+        );
+        prop_assert_eq!(
+            collect(overlay.get_range(range.clone()).unwrap()),
+            collect(expected.get_range(range.clone()).unwrap())
+        );
+        prop_assert_eq!(
+            collect(
+                overlay
+                    .get_range_chunked(range.clone(), chunk_size)
+                    .unwrap()
+            ),
+            collect(
+                expected
+                    .get_range_chunked(range.clone(), chunk_size)
+                    .unwrap()
+            )
+        );
+        prop_assert_eq!(
+            overlay.get_fingerprint(&range).unwrap(),
+            expected.get_fingerprint(&range).unwrap()
+        );
+        prop_assert_eq!(overlay.len().unwrap(), expected.len().unwrap());
+        prop_assert_eq!(overlay.get_first().unwrap(), expected.get_first().unwrap());
+
+        let committed = overlay.commit().unwrap();
+        prop_assert_eq!(committed, expected);
     }
 
-    fn sync_exchange_messages<K, V, F1, F2>(
-        mut alice: SimpleStore<K, V>,
-        mut bob: SimpleStore<K, V>,
-        alice_validate_cb: F1,
-        bob_validate_cb: F2,
-        max_rounds: usize,
-    ) -> SyncResult<K, V>
-    where
-        K: RangeKey + Default,
-        V: RangeValue,
-        F1: Fn(&SimpleStore<K, V>, &(K, V), ContentStatus) -> bool,
-        F2: Fn(&SimpleStore<K, V>, &(K, V), ContentStatus) -> bool,
-    {
-        let mut alice_to_bob = Vec::new();
-        let mut bob_to_alice = Vec::new();
-        let initial_message = alice.initial_message().unwrap();
+    #[test]
+    fn store_first_and_last() {
+        let mut store = MemoryStore::<(&'static str, i32)>::default();
+        assert_eq!(store.first_and_last().unwrap(), None);
 
-        let mut next_to_bob = Some(initial_message);
-        let mut rounds = 0;
-        while let Some(msg) = next_to_bob.take() {
-            assert!(rounds < max_rounds, "too many rounds");
-            rounds += 1;
-            alice_to_bob.push(msg.clone());
+        store.entry_put(("doe", 1)).unwrap();
+        assert_eq!(store.first_and_last().unwrap(), Some(("doe", "doe")));
 
-            if let Some(msg) = bob
-                .process_message(
-                    &Default::default(),
-                    msg,
-                    &bob_validate_cb,
-                    |_, _, _| (),
-                    |_, _| ContentStatus::Complete,
-                )
-                .unwrap()
-            {
-                bob_to_alice.push(msg.clone());
-                next_to_bob = alice
-                    .process_message(
-                        &Default::default(),
-                        msg,
-                        &alice_validate_cb,
-                        |_, _, _| (),
-                        |_, _| ContentStatus::Complete,
-                    )
-                    .unwrap();
-            }
+        for (k, v) in [("fox", 1), ("bee", 1), ("cat", 1)] {
+            store.entry_put((k, v)).unwrap();
         }
-        SyncResult {
-            alice,
-            bob,
-            alice_to_bob,
-            bob_to_alice,
+        assert_eq!(store.first_and_last().unwrap(), Some(("bee", "fox")));
+
+        store.entry_remove(&"bee").unwrap();
+        store.entry_remove(&"fox").unwrap();
+        assert_eq!(store.first_and_last().unwrap(), Some(("cat", "doe")));
+    }
+
+    #[proptest]
+    fn simple_store_first_and_last(
+        #[strategy(test_set_string_unit())] contents: BTreeMap<String, ()>,
+    ) {
+        let expected = contents
+            .keys()
+            .next()
+            .cloned()
+            .zip(contents.keys().next_back().cloned());
+        let mut store = MemoryStore::<(String, ())>::default();
+        for (k, v) in contents {
+            store.entry_put((k, v)).unwrap();
         }
+        prop_assert_eq!(store.first_and_last().unwrap(), expected);
     }
 
     #[test]
-    fn store_get_range() {
-        let mut store = SimpleStore::<&'static str, i32>::default();
+    fn store_get_range_chunked() {
+        let mut store = MemoryStore::<(&'static str, i32)>::default();
         let set = [
             ("bee", 1),
             ("cat", 1),
@@ -1443,57 +11458,249 @@ mod tests {
             store.entry_put((*k, *v)).unwrap();
         }
 
-        let all: Vec<_> = store
-            .get_range(Range::new("", ""))
+        let regular: Vec<_> = store
+            .get_range_chunked(("cat", "hog").into(), 2)
             .unwrap()
             .collect::<Result<_, Infallible>>()
             .unwrap();
-        assert_eq!(&all, &set[..]);
+        assert_eq!(
+            regular,
+            vec![
+                Chunk {
+                    range: ("cat", "eel").into(),
+                    entries: vec![("cat", 1), ("doe", 1)]
+                },
+                Chunk {
+                    range: ("eel", "hog").into(),
+                    entries: vec![("eel", 1), ("fox", 1)]
+                },
+            ]
+        );
 
-        let regular: Vec<_> = store
-            .get_range(("bee", "eel").into())
+        let wrapped: Vec<_> = store
+            .get_range_chunked(("fox", "cat").into(), 2)
             .unwrap()
             .collect::<Result<_, Infallible>>()
             .unwrap();
-        assert_eq!(&regular, &set[..3]);
+        assert_eq!(
+            wrapped,
+            vec![
+                Chunk {
+                    range: ("fox", "bee").into(),
+                    entries: vec![("fox", 1), ("hog", 1)]
+                },
+                Chunk {
+                    range: ("bee", "cat").into(),
+                    entries: vec![("bee", 1)]
+                },
+            ]
+        );
 
-        // empty start
-        let regular: Vec<_> = store
-            .get_range(("", "eel").into())
+        // the full range starting at a key which is not in the store
+        let all: Vec<_> = store
+            .get_range_chunked(("dog", "dog").into(), 4)
             .unwrap()
             .collect::<Result<_, Infallible>>()
             .unwrap();
-        assert_eq!(&regular, &set[..3]);
+        assert_eq!(
+            all,
+            vec![
+                Chunk {
+                    range: ("dog", "cat").into(),
+                    entries: vec![("eel", 1), ("fox", 1), ("hog", 1), ("bee", 1)]
+                },
+                Chunk {
+                    range: ("cat", "dog").into(),
+                    entries: vec![("cat", 1), ("doe", 1)]
+                },
+            ]
+        );
 
-        let regular: Vec<_> = store
-            .get_range(("cat", "hog").into())
+        // an empty range still yields a single chunk covering it
+        let empty: Vec<_> = store
+            .get_range_chunked(("ape", "bee").into(), 2)
             .unwrap()
             .collect::<Result<_, Infallible>>()
             .unwrap();
+        assert_eq!(
+            empty,
+            vec![Chunk {
+                range: ("ape", "bee").into(),
+                entries: vec![]
+            }]
+        );
+    }
 
-        assert_eq!(&regular, &set[1..5]);
-
-        let excluded: Vec<_> = store
-            .get_range(("fox", "bee").into())
+    #[proptest]
+    fn simple_store_get_range_chunked(
+        #[strategy(test_set_string_unit())] contents: BTreeMap<String, ()>,
+        #[strategy(test_range())] range: Range<String>,
+        #[strategy(1usize..4)] chunk_size: usize,
+    ) {
+        let mut store = MemoryStore::<(String, ())>::default();
+        for (k, v) in contents {
+            store.entry_put((k, v)).unwrap();
+        }
+        let chunks = store
+            .get_range_chunked(range.clone(), chunk_size)
             .unwrap()
-            .collect::<Result<_, Infallible>>()
+            .collect::<Result<Vec<_>, _>>()
             .unwrap();
 
-        assert_eq!(excluded[0].0, "fox");
-        assert_eq!(excluded[1].0, "hog");
-        assert_eq!(excluded.len(), 2);
+        // the sub-ranges tile the requested range
+        prop_assert_eq!(chunks.first().unwrap().range.x(), range.x());
+        prop_assert_eq!(chunks.last().unwrap().range.y(), range.y());
+        for pair in chunks.windows(2) {
+            prop_assert_eq!(pair[0].range.y(), pair[1].range.x());
+        }
+
+        // each chunk contains exactly the entries of its sub-range
+        let mut all_entries = vec![];
+        for (i, chunk) in chunks.iter().enumerate() {
+            if i < chunks.len() - 1 {
+                prop_assert_eq!(chunk.entries.len(), chunk_size);
+            } else {
+                prop_assert!(chunk.entries.len() <= chunk_size);
+            }
+            let mut actual = chunk.entries.clone();
+            actual.sort();
+            let expected: Vec<_> = store
+                .get_range(chunk.range.clone())
+                .unwrap()
+                .collect::<Result<_, _>>()
+                .unwrap();
+            prop_assert_eq!(actual, expected);
+            all_entries.extend(chunk.entries.iter().cloned());
+        }
 
-        let excluded: Vec<_> = store
-            .get_range(("fox", "doe").into())
+        // no gaps and no overlaps
+        all_entries.sort();
+        let expected: Vec<_> = store
+            .get_range(range)
             .unwrap()
-            .collect::<Result<_, Infallible>>()
+            .collect::<Result<_, _>>()
             .unwrap();
+        prop_assert_eq!(all_entries, expected);
+    }
+
+    #[proptest]
+    fn default_get_range_chunked(
+        #[strategy(test_set_string_unit())] contents: BTreeMap<String, ()>,
+        #[strategy(test_range())] range: Range<String>,
+        #[strategy(1usize..4)] chunk_size: usize,
+    ) {
+        let mut expected = MemoryStore::<(String, ())>::default();
+        for (k, v) in contents {
+            expected.entry_put((k, v)).unwrap();
+        }
+        // `FlatStore` reads the chunks from `get_range` of the inner store
+        let mut store = FlatStore(expected.clone());
+        prop_assert_eq!(
+            collect(store.get_range_chunked(range.clone(), chunk_size).unwrap()),
+            collect(expected.get_range_chunked(range, chunk_size).unwrap())
+        );
+    }
 
-        assert_eq!(excluded.len(), 4);
-        assert_eq!(excluded[0].0, "bee");
-        assert_eq!(excluded[1].0, "cat");
-        assert_eq!(excluded[2].0, "fox");
-        assert_eq!(excluded[3].0, "hog");
+    #[test]
+    fn store_export_import() {
+        use rand::{distributions::Alphanumeric, Rng, SeedableRng};
+
+        let mut rng = rand_chacha::ChaCha8Rng::seed_from_u64(42);
+        let mut store = MemoryStore::<(String, u8)>::default();
+        for _ in 0..3000 {
+            let len = rng.gen_range(1..20);
+            let key: String = (&mut rng)
+                .sample_iter(&Alphanumeric)
+                .take(len)
+                .map(char::from)
+                .collect();
+            store.entry_put((key, rng.gen())).unwrap();
+        }
+
+        let mut snapshot = Vec::new();
+        store.export(&mut snapshot).unwrap();
+        let mut imported = MemoryStore::<(String, u8)>::import(&snapshot[..]).unwrap();
+        assert_eq!(imported, store);
+        let all = Range::new(String::new(), String::new());
+        assert_eq!(
+            imported.get_fingerprint(&all).unwrap(),
+            store.get_fingerprint(&all).unwrap()
+        );
+
+        // empty store
+        let mut empty = MemoryStore::<(String, u8)>::default();
+        let mut snapshot = Vec::new();
+        empty.export(&mut snapshot).unwrap();
+        let mut imported = MemoryStore::<(String, u8)>::import(&snapshot[..]).unwrap();
+        assert!(imported.is_empty().unwrap());
+    }
+
+    #[test]
+    fn store_import_corrupt() {
+        let mut store = MemoryStore::<(String, u8)>::default();
+        for (k, v) in [("bee", 1), ("cat", 2), ("doe", 3)] {
+            store.entry_put((k.to_string(), v)).unwrap();
+        }
+        let mut snapshot = Vec::new();
+        store.export(&mut snapshot).unwrap();
+
+        // fingerprint in the header does not match the entries
+        let mut corrupt = snapshot.clone();
+        corrupt[5] ^= 1;
+        let res = MemoryStore::<(String, u8)>::import(&corrupt[..]);
+        assert!(matches!(res, Err(ImportError::FingerprintMismatch { .. })));
+
+        // an entry value was changed
+        let mut corrupt = snapshot.clone();
+        *corrupt.last_mut().unwrap() ^= 1;
+        let res = MemoryStore::<(String, u8)>::import(&corrupt[..]);
+        assert!(matches!(res, Err(ImportError::FingerprintMismatch { .. })));
+
+        // data after the last entry
+        let mut corrupt = snapshot.clone();
+        corrupt.push(0);
+        let res = MemoryStore::<(String, u8)>::import(&corrupt[..]);
+        assert!(matches!(res, Err(ImportError::TrailingData)));
+
+        // the header counts fewer entries than the snapshot has
+        let mut corrupt = snapshot.clone();
+        corrupt[37] -= 1;
+        let res = MemoryStore::<(String, u8)>::import(&corrupt[..]);
+        assert!(matches!(res, Err(ImportError::TrailingData)));
+
+        // two more copies of the last entry, which cancel out in the fingerprint
+        let mut corrupt = snapshot.clone();
+        corrupt[37] += 2;
+        let last = snapshot.len() - (4 + postcard::to_allocvec(&("doe", 3u8)).unwrap().len());
+        for _ in 0..2 {
+            corrupt.extend_from_slice(&snapshot[last..]);
+        }
+        let res = MemoryStore::<(String, u8)>::import(&corrupt[..]);
+        assert!(matches!(res, Err(ImportError::UnorderedKeys)));
+
+        // the same entries out of order
+        let mut corrupt = snapshot[..last].to_vec();
+        let first = 45;
+        let second = first + 4 + postcard::to_allocvec(&("bee", 1u8)).unwrap().len();
+        corrupt.extend_from_slice(&snapshot[last..]);
+        corrupt.extend_from_slice(&snapshot[first..second]);
+        corrupt.drain(first..second);
+        let res = MemoryStore::<(String, u8)>::import(&corrupt[..]);
+        assert!(matches!(res, Err(ImportError::UnorderedKeys)));
+
+        // truncated at every possible position
+        for len in 0..snapshot.len() {
+            let res = MemoryStore::<(String, u8)>::import(&snapshot[..len]);
+            assert!(matches!(res, Err(ImportError::Truncated)), "len {len}");
+        }
+
+        let mut corrupt = snapshot.clone();
+        corrupt[4] = SNAPSHOT_VERSION + 1;
+        let res = MemoryStore::<(String, u8)>::import(&corrupt[..]);
+        assert!(matches!(res, Err(ImportError::UnsupportedVersion(_))));
+
+        let res = MemoryStore::<(String, u8)>::import(&b"garbage"[..]);
+        assert!(matches!(res, Err(ImportError::InvalidMagic)));
     }
 
     type TestSetStringUnit = BTreeMap<String, ()>;
@@ -1522,6 +11729,15 @@ mod tests {
         test_set_string_u8().prop_map(|m| m.into_iter().collect::<Vec<_>>())
     }
 
+    /// Updates to existing entries, `None` means the entry is removed.
+    fn test_updates() -> impl Strategy<Value = Vec<(prop::sample::Index, Option<u8>)>> {
+        let update = (
+            any::<prop::sample::Index>(),
+            prop::option::of(test_value_u8()),
+        );
+        prop::collection::vec(update, 0..10)
+    }
+
     fn test_range() -> impl Strategy<Value = Range<String>> {
         // ranges with x > y are explicitly allowed - they wrap around
         (test_key(), test_key()).prop_map(|(x, y)| Range::new(x, y))
@@ -1618,7 +11834,7 @@ mod tests {
         #[strategy(test_set_string_unit())] contents: BTreeMap<String, ()>,
         #[strategy(test_range())] range: Range<String>,
     ) {
-        let (expected, actual) = store_get_ranges_test::<SimpleStore<_, _>, _>(contents, range);
+        let (expected, actual) = store_get_ranges_test::<MemoryStore<(_, _)>, _>(contents, range);
         prop_assert_eq!(expected, actual);
     }
 }
@@ -0,0 +1,158 @@
+//! Periodic reconciliation of slices of a store, see [`AntiEntropyPlanner`].
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use super::{Message, Range, RangeEntry, Store, SyncError, SyncSession};
+
+/// The size of the slices produced by an [`AntiEntropyPlanner`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SliceSize {
+    /// Slices with this many entries of the local store.
+    Entries(usize),
+    /// Slices with this fraction of the entries of the local store, rounded up.
+    ///
+    /// Keys are not necessarily numbers, so the fraction is of the entries, not of the key
+    /// space between the first and the last key.
+    Fraction(f64),
+}
+
+impl SliceSize {
+    /// Returns the number of entries of a slice of a store with `len` entries, at least one.
+    fn entries(&self, len: usize) -> usize {
+        let entries = match self {
+            SliceSize::Entries(entries) => *entries,
+            SliceSize::Fraction(fraction) => (len as f64 * fraction).ceil() as usize,
+        };
+        entries.max(1)
+    }
+}
+
+/// Plans anti-entropy syncs, which reconcile a slice of the store per tick instead of the whole
+/// store, to amortize the cost of fingerprinting over several ticks.
+///
+/// Each call of [`AntiEntropyPlanner::next_range`] returns the range that starts where the
+/// previous one ended, and contains the number of entries given by the [`SliceSize`]. The ranges
+/// wrap around at the largest key, so successive ticks cycle through the whole key space, and a
+/// store with `len` entries is covered within `len / entries` ticks, rounded up. The first range
+/// starts at a random entry, chosen with the seed of the planner, so that peers with the same
+/// store do not all reconcile the same slices.
+///
+/// The store may change between ticks. Ranges are bounded by keys, not by positions, so the
+/// ranges of successive ticks stay adjacent: entries written ahead of the current position are
+/// reconciled in the current cycle, and entries written behind it in the next.
+#[derive(Debug, Clone)]
+pub struct AntiEntropyPlanner<E: RangeEntry> {
+    slice: SliceSize,
+    rng: StdRng,
+    /// The start of the next range, or `None` before the first tick.
+    cursor: Option<E::Key>,
+    ticks: u64,
+}
+
+impl<E: RangeEntry> AntiEntropyPlanner<E> {
+    /// Create a planner that produces slices of size `slice`, and chooses the start of the first
+    /// slice with `seed`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `slice` is zero entries, or not a fraction in `(0, 1]`.
+    pub fn new(slice: SliceSize, seed: u64) -> Self {
+        match slice {
+            SliceSize::Entries(entries) => assert!(entries > 0, "slice must not be empty"),
+            SliceSize::Fraction(fraction) => assert!(
+                fraction > 0.0 && fraction <= 1.0,
+                "slice fraction must be in (0, 1]"
+            ),
+        }
+        Self {
+            slice,
+            rng: StdRng::seed_from_u64(seed),
+            cursor: None,
+            ticks: 0,
+        }
+    }
+
+    /// Get the size of the slices.
+    pub fn slice_size(&self) -> SliceSize {
+        self.slice
+    }
+
+    /// Get the number of ranges produced so far.
+    pub fn ticks(&self) -> u64 {
+        self.ticks
+    }
+
+    /// Get the start of the next range, or `None` before the first tick.
+    pub fn cursor(&self) -> Option<&E::Key> {
+        self.cursor.as_ref()
+    }
+
+    /// Returns the range to reconcile in this tick.
+    ///
+    /// If the store has no more entries than a slice, the range covers the whole set.
+    pub fn next_range<S: Store<E>>(&mut self, store: &mut S) -> Result<Range<E::Key>, S::Error> {
+        let (range, cursor) = self.plan(store)?;
+        self.cursor = cursor;
+        self.ticks += 1;
+        Ok(range)
+    }
+
+    /// Generates the initial message of `session` for the range of this tick, see
+    /// [`SyncSession::initial_message_for_range`].
+    ///
+    /// The planner only moves on to the next range if the message was generated.
+    pub fn initial_message<S: Store<E>>(
+        &mut self,
+        session: &mut SyncSession<E>,
+        store: &mut S,
+    ) -> Result<Message<E>, SyncError<S::Error>> {
+        let (range, cursor) = self.plan(store).map_err(SyncError::Store)?;
+        let message = session.initial_message_for_range(store, range)?;
+        self.cursor = cursor;
+        self.ticks += 1;
+        Ok(message)
+    }
+
+    /// Returns the range of this tick, and the start of the next one.
+    #[allow(clippy::type_complexity)]
+    fn plan<S: Store<E>>(
+        &mut self,
+        store: &mut S,
+    ) -> Result<(Range<E::Key>, Option<E::Key>), S::Error> {
+        let Some((first, _last)) = store.first_and_last()? else {
+            let x = store.get_first()?;
+            return Ok((Range::new(x.clone(), x), self.cursor.clone()));
+        };
+        let len = store.len()?;
+        let entries = self.slice.entries(len);
+        if entries >= len {
+            return Ok((Range::new(first.clone(), first), self.cursor.clone()));
+        }
+        let start = match &self.cursor {
+            Some(cursor) => cursor.clone(),
+            None => match self.rng.gen_range(0..len) {
+                0 => first,
+                // the first chunk ends at the key of the entry at `offset`
+                offset => match store
+                    .get_range_chunked(Range::new(first.clone(), first.clone()), offset)?
+                    .next()
+                {
+                    Some(chunk) => chunk?.range.y().clone(),
+                    None => first,
+                },
+            },
+        };
+        let range = match store
+            .get_range_chunked(Range::new(start.clone(), start.clone()), entries)?
+            .next()
+        {
+            Some(chunk) => chunk?.range,
+            None => Range::new(start.clone(), start),
+        };
+        let cursor = match range.is_all() {
+            true => self.cursor.clone(),
+            false => Some(range.y().clone()),
+        };
+        Ok((range, cursor))
+    }
+}
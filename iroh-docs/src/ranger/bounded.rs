@@ -0,0 +1,314 @@
+//! A store wrapper that evicts entries when the store grows beyond a configured bound.
+
+use std::collections::BTreeMap;
+
+use super::{validators::EntryLookup, ChunkIterator, Fingerprint, Range, RangeEntry, Store};
+
+/// Which entries a [`BoundedStore`] evicts first.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum EvictionPolicy {
+    /// Evict the entry that was inserted or replaced least recently.
+    #[default]
+    Insertion,
+    /// Evict the entry with the smallest key.
+    KeyOrder,
+}
+
+type SizeFn<E> = Box<dyn Fn(&E) -> usize + Send + Sync>;
+type EvictFn<E> = Box<dyn FnMut(&E) + Send>;
+
+/// A [`Store`] that keeps the number of entries, and optionally their total size, within a
+/// bound, evicting entries according to an [`EvictionPolicy`].
+///
+/// Evicted entries are removed with [`Store::entry_remove`] on the inner store, so caching
+/// layers below this wrapper see every eviction and keep their bookkeeping consistent. An
+/// `on_evict` callback is invoked with each evicted entry, e.g. to record tombstones.
+///
+/// When syncing a bounded store against a larger peer, the validate callback passed to
+/// [`Store::process_message`] should reject entries for which [`BoundedStore::admits`] returns
+/// false. Otherwise entries that are evicted right away are inserted again on every sync.
+pub struct BoundedStore<E: RangeEntry, S> {
+    store: S,
+    max_entries: Option<usize>,
+    max_bytes: Option<usize>,
+    size_of: SizeFn<E>,
+    policy: EvictionPolicy,
+    on_evict: Option<EvictFn<E>>,
+    /// Insertion sequence number and size of each entry.
+    index: BTreeMap<E::Key, (u64, usize)>,
+    /// Keys by insertion sequence number.
+    insertion_order: BTreeMap<u64, E::Key>,
+    next_seq: u64,
+    bytes: usize,
+}
+
+impl<E: RangeEntry, S> std::fmt::Debug for BoundedStore<E, S> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BoundedStore")
+            .field("max_entries", &self.max_entries)
+            .field("max_bytes", &self.max_bytes)
+            .field("policy", &self.policy)
+            .field("len", &self.index.len())
+            .field("bytes", &self.bytes)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<E: RangeEntry, S: Store<E>> BoundedStore<E, S> {
+    /// Wrap a store, without any bounds.
+    ///
+    /// The entries already in the store are considered inserted in key order.
+    pub fn new(mut store: S) -> Result<Self, S::Error> {
+        let keys = store
+            .all()?
+            .map(|entry| entry.map(|entry| entry.key().clone()))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut this = Self {
+            store,
+            max_entries: None,
+            max_bytes: None,
+            size_of: Box::new(|_| 0),
+            policy: EvictionPolicy::default(),
+            on_evict: None,
+            index: Default::default(),
+            insertion_order: Default::default(),
+            next_seq: 0,
+            bytes: 0,
+        };
+        for key in keys {
+            this.track(key, 0);
+        }
+        Ok(this)
+    }
+
+    /// Set the maximum number of entries.
+    ///
+    /// The bound is enforced on the next write, or with [`BoundedStore::evict_to_bounds`].
+    pub fn with_max_entries(mut self, max_entries: usize) -> Self {
+        self.max_entries = Some(max_entries);
+        self
+    }
+
+    /// Set the maximum total size of the entries, where `size_of` returns the size of an entry.
+    ///
+    /// Entries that are already in the store when this is called count as size 0. The bound is
+    /// enforced on the next write, or with [`BoundedStore::evict_to_bounds`].
+    pub fn with_max_bytes(
+        mut self,
+        max_bytes: usize,
+        size_of: impl Fn(&E) -> usize + Send + Sync + 'static,
+    ) -> Self {
+        self.max_bytes = Some(max_bytes);
+        self.size_of = Box::new(size_of);
+        self
+    }
+
+    /// Set the eviction policy.
+    pub fn with_policy(mut self, policy: EvictionPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Set a callback that is invoked with each evicted entry.
+    pub fn with_on_evict(mut self, on_evict: impl FnMut(&E) + Send + 'static) -> Self {
+        self.on_evict = Some(Box::new(on_evict));
+        self
+    }
+
+    /// Get the maximum number of entries.
+    pub fn max_entries(&self) -> Option<usize> {
+        self.max_entries
+    }
+
+    /// Get the maximum total size of the entries.
+    pub fn max_bytes(&self) -> Option<usize> {
+        self.max_bytes
+    }
+
+    /// Get the total size of the entries.
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Get a reference to the inner store.
+    pub fn inner(&self) -> &S {
+        &self.store
+    }
+
+    /// Unwrap the inner store.
+    pub fn into_inner(self) -> S {
+        self.store
+    }
+
+    /// Returns whether `entry` would be kept if it was inserted now.
+    ///
+    /// With [`EvictionPolicy::KeyOrder`], an entry that is smaller than all entries of a full
+    /// store would be evicted right away. With [`EvictionPolicy::Insertion`], new entries are
+    /// always kept.
+    pub fn admits(&self, entry: &E) -> bool {
+        if self.policy == EvictionPolicy::Insertion || self.index.contains_key(entry.key()) {
+            return true;
+        }
+        let full = self.max_entries.is_some_and(|max| self.index.len() >= max)
+            || self
+                .max_bytes
+                .is_some_and(|max| self.bytes + (self.size_of)(entry) > max);
+        let smallest = self.index.first_key_value().map(|(key, _)| key);
+        !full || smallest.is_some_and(|smallest| smallest < entry.key())
+    }
+
+    /// Evict entries until the store is within its bounds, and return the number of evicted
+    /// entries.
+    pub fn evict_to_bounds(&mut self) -> Result<usize, S::Error> {
+        let mut evicted = 0;
+        while self.is_over_bounds() {
+            let key = match self.policy {
+                EvictionPolicy::Insertion => self.insertion_order.first_key_value().map(|(_, k)| k),
+                EvictionPolicy::KeyOrder => self.index.first_key_value().map(|(k, _)| k),
+            };
+            let Some(key) = key.cloned() else {
+                break;
+            };
+            let removed = self.store.entry_remove(&key)?;
+            self.untrack(&key);
+            if let (Some(entry), Some(on_evict)) = (removed, &mut self.on_evict) {
+                on_evict(&entry);
+            }
+            evicted += 1;
+        }
+        Ok(evicted)
+    }
+
+    fn is_over_bounds(&self) -> bool {
+        self.max_entries.is_some_and(|max| self.index.len() > max)
+            || self.max_bytes.is_some_and(|max| self.bytes > max)
+    }
+
+    fn track(&mut self, key: E::Key, size: usize) {
+        self.untrack(&key);
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.insertion_order.insert(seq, key.clone());
+        self.index.insert(key, (seq, size));
+        self.bytes += size;
+    }
+
+    fn untrack(&mut self, key: &E::Key) {
+        if let Some((seq, size)) = self.index.remove(key) {
+            self.insertion_order.remove(&seq);
+            self.bytes -= size;
+        }
+    }
+}
+
+impl<E: RangeEntry, S: EntryLookup<E>> EntryLookup<E> for BoundedStore<E, S> {
+    fn lookup(&self, key: &E::Key) -> Option<&E> {
+        self.store.lookup(key)
+    }
+}
+
+impl<E: RangeEntry, S: Store<E>> Store<E> for BoundedStore<E, S> {
+    type Error = S::Error;
+    type RangeIterator<'a> = S::RangeIterator<'a>
+    where S: 'a, E: 'a;
+    type ParentIterator<'a> = S::ParentIterator<'a>
+    where S: 'a, E: 'a;
+
+    fn epoch(&self) -> u64 {
+        self.store.epoch()
+    }
+
+    fn get_first(&mut self) -> Result<E::Key, Self::Error> {
+        self.store.get_first()
+    }
+
+    fn first_and_last(&mut self) -> Result<Option<(E::Key, E::Key)>, Self::Error> {
+        self.store.first_and_last()
+    }
+
+    fn get(&mut self, key: &E::Key) -> Result<Option<E>, Self::Error> {
+        self.store.get(key)
+    }
+
+    fn entry_fingerprint(&mut self, key: &E::Key) -> Result<Option<Fingerprint>, Self::Error> {
+        self.store.entry_fingerprint(key)
+    }
+
+    fn len(&mut self) -> Result<usize, Self::Error> {
+        self.store.len()
+    }
+
+    fn is_empty(&mut self) -> Result<bool, Self::Error> {
+        self.store.is_empty()
+    }
+
+    fn get_fingerprint(&mut self, range: &Range<E::Key>) -> Result<Fingerprint, Self::Error> {
+        self.store.get_fingerprint(range)
+    }
+
+    fn entry_put(&mut self, entry: E) -> Result<(), Self::Error> {
+        let key = entry.key().clone();
+        let size = (self.size_of)(&entry);
+        self.store.entry_put(entry)?;
+        self.track(key, size);
+        self.evict_to_bounds()?;
+        Ok(())
+    }
+
+    fn get_range(&mut self, range: Range<E::Key>) -> Result<Self::RangeIterator<'_>, Self::Error> {
+        self.store.get_range(range)
+    }
+
+    fn get_range_chunked<'a>(
+        &'a mut self,
+        range: Range<E::Key>,
+        chunk_size: usize,
+    ) -> Result<ChunkIterator<'a, E, Self::Error>, Self::Error>
+    where
+        E: 'a,
+    {
+        self.store.get_range_chunked(range, chunk_size)
+    }
+
+    fn get_range_len(&mut self, range: Range<E::Key>) -> Result<usize, Self::Error> {
+        self.store.get_range_len(range)
+    }
+
+    fn prefixed_by(&mut self, prefix: &E::Key) -> Result<Self::RangeIterator<'_>, Self::Error> {
+        self.store.prefixed_by(prefix)
+    }
+
+    fn prefixes_of(&mut self, key: &E::Key) -> Result<Self::ParentIterator<'_>, Self::Error> {
+        self.store.prefixes_of(key)
+    }
+
+    fn all(&mut self) -> Result<Self::RangeIterator<'_>, Self::Error> {
+        self.store.all()
+    }
+
+    fn entry_remove(&mut self, key: &E::Key) -> Result<Option<E>, Self::Error> {
+        let removed = self.store.entry_remove(key)?;
+        self.untrack(key);
+        Ok(removed)
+    }
+
+    fn remove_prefix_filtered(
+        &mut self,
+        prefix: &E::Key,
+        predicate: impl Fn(&E::Value) -> bool,
+    ) -> Result<usize, Self::Error> {
+        // Remove the entries one by one, to keep the index up to date.
+        let keys = self
+            .store
+            .prefixed_by(prefix)?
+            .filter_map(|entry| match entry {
+                Ok(entry) => predicate(entry.value()).then(|| Ok(entry.key().clone())),
+                Err(err) => Some(Err(err)),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        for key in &keys {
+            self.entry_remove(key)?;
+        }
+        Ok(keys.len())
+    }
+}
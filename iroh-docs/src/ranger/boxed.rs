@@ -0,0 +1,259 @@
+//! Object-safe stores, to select the storage backend at runtime.
+
+use super::{Chunk, ChunkIterator, Fingerprint, Range, RangeEntry, Store};
+
+/// Boxed iterator returned from a [`BoxedStore`].
+pub type BoxedIterator<'a, T> = Box<dyn Iterator<Item = anyhow::Result<T>> + 'a>;
+
+/// Object-safe version of [`Store`].
+///
+/// This is implemented for all types implementing [`Store`]. Iterators are boxed and errors are
+/// converted into [`anyhow::Error`].
+trait DynStore<E: RangeEntry + 'static> {
+    /// See [`Store::epoch`].
+    fn epoch(&self) -> u64;
+
+    /// See [`Store::get_first`].
+    fn get_first(&mut self) -> anyhow::Result<E::Key>;
+
+    /// See [`Store::first_and_last`].
+    #[allow(clippy::type_complexity)]
+    fn first_and_last(&mut self) -> anyhow::Result<Option<(E::Key, E::Key)>>;
+
+    /// See [`Store::get`].
+    fn get(&mut self, key: &E::Key) -> anyhow::Result<Option<E>>;
+
+    /// See [`Store::entry_fingerprint`].
+    fn entry_fingerprint(&mut self, key: &E::Key) -> anyhow::Result<Option<Fingerprint>>;
+
+    /// See [`Store::len`].
+    fn len(&mut self) -> anyhow::Result<usize>;
+
+    /// See [`Store::is_empty`].
+    fn is_empty(&mut self) -> anyhow::Result<bool>;
+
+    /// See [`Store::get_fingerprint`].
+    fn get_fingerprint(&mut self, range: &Range<E::Key>) -> anyhow::Result<Fingerprint>;
+
+    /// See [`Store::entry_put`].
+    fn entry_put(&mut self, entry: E) -> anyhow::Result<()>;
+
+    /// See [`Store::get_range`].
+    fn get_range(&mut self, range: Range<E::Key>) -> anyhow::Result<BoxedIterator<'_, E>>;
+
+    /// See [`Store::get_range_chunked`].
+    fn get_range_chunked(
+        &mut self,
+        range: Range<E::Key>,
+        chunk_size: usize,
+    ) -> anyhow::Result<BoxedIterator<'_, Chunk<E>>>;
+
+    /// See [`Store::get_range_len`].
+    fn get_range_len(&mut self, range: Range<E::Key>) -> anyhow::Result<usize>;
+
+    /// See [`Store::prefixed_by`].
+    fn prefixed_by(&mut self, prefix: &E::Key) -> anyhow::Result<BoxedIterator<'_, E>>;
+
+    /// See [`Store::prefixes_of`].
+    fn prefixes_of(&mut self, key: &E::Key) -> anyhow::Result<BoxedIterator<'_, E>>;
+
+    /// See [`Store::all`].
+    fn all(&mut self) -> anyhow::Result<BoxedIterator<'_, E>>;
+
+    /// See [`Store::entry_remove`].
+    fn entry_remove(&mut self, key: &E::Key) -> anyhow::Result<Option<E>>;
+
+    /// See [`Store::remove_prefix_filtered`].
+    fn remove_prefix_filtered(
+        &mut self,
+        prefix: &E::Key,
+        predicate: &dyn Fn(&E::Value) -> bool,
+    ) -> anyhow::Result<usize>;
+}
+
+impl<E: RangeEntry + 'static, S: Store<E>> DynStore<E> for S {
+    fn epoch(&self) -> u64 {
+        Store::epoch(self)
+    }
+
+    fn get_first(&mut self) -> anyhow::Result<E::Key> {
+        Store::get_first(self).map_err(Into::into)
+    }
+
+    fn first_and_last(&mut self) -> anyhow::Result<Option<(E::Key, E::Key)>> {
+        Store::first_and_last(self).map_err(Into::into)
+    }
+
+    fn get(&mut self, key: &E::Key) -> anyhow::Result<Option<E>> {
+        Store::get(self, key).map_err(Into::into)
+    }
+
+    fn entry_fingerprint(&mut self, key: &E::Key) -> anyhow::Result<Option<Fingerprint>> {
+        Store::entry_fingerprint(self, key).map_err(Into::into)
+    }
+
+    fn len(&mut self) -> anyhow::Result<usize> {
+        Store::len(self).map_err(Into::into)
+    }
+
+    fn is_empty(&mut self) -> anyhow::Result<bool> {
+        Store::is_empty(self).map_err(Into::into)
+    }
+
+    fn get_fingerprint(&mut self, range: &Range<E::Key>) -> anyhow::Result<Fingerprint> {
+        Store::get_fingerprint(self, range).map_err(Into::into)
+    }
+
+    fn entry_put(&mut self, entry: E) -> anyhow::Result<()> {
+        Store::entry_put(self, entry).map_err(Into::into)
+    }
+
+    fn get_range(&mut self, range: Range<E::Key>) -> anyhow::Result<BoxedIterator<'_, E>> {
+        let iter = Store::get_range(self, range).map_err(Into::into)?;
+        Ok(Box::new(iter.map(|e| e.map_err(Into::into))))
+    }
+
+    fn get_range_chunked(
+        &mut self,
+        range: Range<E::Key>,
+        chunk_size: usize,
+    ) -> anyhow::Result<BoxedIterator<'_, Chunk<E>>> {
+        let iter = Store::get_range_chunked(self, range, chunk_size).map_err(Into::into)?;
+        Ok(Box::new(iter.map(|e| e.map_err(Into::into))))
+    }
+
+    fn get_range_len(&mut self, range: Range<E::Key>) -> anyhow::Result<usize> {
+        Store::get_range_len(self, range).map_err(Into::into)
+    }
+
+    fn prefixed_by(&mut self, prefix: &E::Key) -> anyhow::Result<BoxedIterator<'_, E>> {
+        let iter = Store::prefixed_by(self, prefix).map_err(Into::into)?;
+        Ok(Box::new(iter.map(|e| e.map_err(Into::into))))
+    }
+
+    fn prefixes_of(&mut self, key: &E::Key) -> anyhow::Result<BoxedIterator<'_, E>> {
+        let iter = Store::prefixes_of(self, key).map_err(Into::into)?;
+        Ok(Box::new(iter.map(|e| e.map_err(Into::into))))
+    }
+
+    fn all(&mut self) -> anyhow::Result<BoxedIterator<'_, E>> {
+        let iter = Store::all(self).map_err(Into::into)?;
+        Ok(Box::new(iter.map(|e| e.map_err(Into::into))))
+    }
+
+    fn entry_remove(&mut self, key: &E::Key) -> anyhow::Result<Option<E>> {
+        Store::entry_remove(self, key).map_err(Into::into)
+    }
+
+    fn remove_prefix_filtered(
+        &mut self,
+        prefix: &E::Key,
+        predicate: &dyn Fn(&E::Value) -> bool,
+    ) -> anyhow::Result<usize> {
+        Store::remove_prefix_filtered(self, prefix, predicate).map_err(Into::into)
+    }
+}
+
+/// A type-erased [`Store`].
+///
+/// This allows to select the storage backend at runtime, at the cost of boxing the iterators
+/// returned from the store.
+pub struct BoxedStore<E: RangeEntry + 'static>(Box<dyn DynStore<E>>);
+
+impl<E: RangeEntry + 'static> std::fmt::Debug for BoxedStore<E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BoxedStore").finish_non_exhaustive()
+    }
+}
+
+impl<E: RangeEntry + 'static> BoxedStore<E> {
+    /// Create a new boxed store.
+    pub fn new<S: Store<E> + 'static>(store: S) -> Self {
+        BoxedStore(Box::new(store))
+    }
+}
+
+impl<E: RangeEntry + 'static> Store<E> for BoxedStore<E> {
+    type Error = anyhow::Error;
+    type RangeIterator<'a> = BoxedIterator<'a, E> where E: 'a;
+    type ParentIterator<'a> = BoxedIterator<'a, E> where E: 'a;
+
+    fn epoch(&self) -> u64 {
+        self.0.epoch()
+    }
+
+    fn get_first(&mut self) -> anyhow::Result<E::Key> {
+        self.0.get_first()
+    }
+
+    fn first_and_last(&mut self) -> anyhow::Result<Option<(E::Key, E::Key)>> {
+        self.0.first_and_last()
+    }
+
+    fn get(&mut self, key: &E::Key) -> anyhow::Result<Option<E>> {
+        self.0.get(key)
+    }
+
+    fn entry_fingerprint(&mut self, key: &E::Key) -> anyhow::Result<Option<Fingerprint>> {
+        self.0.entry_fingerprint(key)
+    }
+
+    fn len(&mut self) -> anyhow::Result<usize> {
+        self.0.len()
+    }
+
+    fn is_empty(&mut self) -> anyhow::Result<bool> {
+        self.0.is_empty()
+    }
+
+    fn get_fingerprint(&mut self, range: &Range<E::Key>) -> anyhow::Result<Fingerprint> {
+        self.0.get_fingerprint(range)
+    }
+
+    fn entry_put(&mut self, entry: E) -> anyhow::Result<()> {
+        self.0.entry_put(entry)
+    }
+
+    fn get_range(&mut self, range: Range<E::Key>) -> anyhow::Result<Self::RangeIterator<'_>> {
+        self.0.get_range(range)
+    }
+
+    fn get_range_chunked<'a>(
+        &'a mut self,
+        range: Range<E::Key>,
+        chunk_size: usize,
+    ) -> anyhow::Result<ChunkIterator<'a, E, anyhow::Error>>
+    where
+        E: 'a,
+    {
+        self.0.get_range_chunked(range, chunk_size)
+    }
+
+    fn get_range_len(&mut self, range: Range<E::Key>) -> anyhow::Result<usize> {
+        self.0.get_range_len(range)
+    }
+
+    fn prefixed_by(&mut self, prefix: &E::Key) -> anyhow::Result<Self::RangeIterator<'_>> {
+        self.0.prefixed_by(prefix)
+    }
+
+    fn prefixes_of(&mut self, key: &E::Key) -> anyhow::Result<Self::ParentIterator<'_>> {
+        self.0.prefixes_of(key)
+    }
+
+    fn all(&mut self) -> anyhow::Result<Self::RangeIterator<'_>> {
+        self.0.all()
+    }
+
+    fn entry_remove(&mut self, key: &E::Key) -> anyhow::Result<Option<E>> {
+        self.0.entry_remove(key)
+    }
+
+    fn remove_prefix_filtered(
+        &mut self,
+        prefix: &E::Key,
+        predicate: impl Fn(&E::Value) -> bool,
+    ) -> anyhow::Result<usize> {
+        self.0.remove_prefix_filtered(prefix, &predicate)
+    }
+}
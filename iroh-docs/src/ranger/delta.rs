@@ -0,0 +1,151 @@
+//! Delta encoding of the keys of range items, see [`SyncSession::with_delta_keys`].
+//!
+//! [`SyncSession::with_delta_keys`]: super::SyncSession::with_delta_keys
+
+use std::fmt::Debug;
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::ContentStatus;
+
+use super::{
+    encoded_size, DeltaRangeItem, EnvelopeError, RangeEntry, RangeItem, RangeKey, SetEntry,
+    SignedEntry,
+};
+
+/// Keys that are a sequence of bytes, so that consecutive keys can be delta encoded, see
+/// [`SyncSession::with_delta_keys`].
+///
+/// [`SyncSession::with_delta_keys`]: super::SyncSession::with_delta_keys
+pub trait AsKeyBytes: Sized {
+    /// Get the bytes of the key.
+    fn as_key_bytes(&self) -> &[u8];
+
+    /// Reassembles a key from its bytes, or returns `None` if they are not a valid key.
+    fn from_key_bytes(bytes: Vec<u8>) -> Option<Self>;
+}
+
+impl AsKeyBytes for Vec<u8> {
+    fn as_key_bytes(&self) -> &[u8] {
+        self
+    }
+
+    fn from_key_bytes(bytes: Vec<u8>) -> Option<Self> {
+        Some(bytes)
+    }
+}
+
+impl AsKeyBytes for String {
+    fn as_key_bytes(&self) -> &[u8] {
+        self.as_bytes()
+    }
+
+    fn from_key_bytes(bytes: Vec<u8>) -> Option<Self> {
+        String::from_utf8(bytes).ok()
+    }
+}
+
+/// Entries that can be split into their key and the rest of the entry, and reassembled, so
+/// that their keys can be delta encoded, see [`SyncSession::with_delta_keys`].
+///
+/// [`SyncSession::with_delta_keys`]: super::SyncSession::with_delta_keys
+pub trait SplitKey: RangeEntry {
+    /// The entry without its key.
+    type Rest;
+
+    /// Splits the entry into its key and the rest.
+    fn split_key(self) -> (Self::Key, Self::Rest);
+
+    /// Reassembles an entry from its key and the rest.
+    fn join_key(key: Self::Key, rest: Self::Rest) -> Self;
+}
+
+impl<K: RangeKey + AsRef<[u8]>> SplitKey for SetEntry<K> {
+    type Rest = ();
+
+    fn split_key(self) -> (K, ()) {
+        (self.0, ())
+    }
+
+    fn join_key(key: K, _: ()) -> Self {
+        SetEntry(key)
+    }
+}
+
+impl<E, Sig> SplitKey for SignedEntry<E, Sig>
+where
+    E: SplitKey,
+    Sig: Debug + Clone + Serialize,
+{
+    type Rest = (E::Rest, Sig);
+
+    fn split_key(self) -> (E::Key, Self::Rest) {
+        let (key, rest) = self.entry.split_key();
+        (key, (rest, self.signature))
+    }
+
+    fn join_key(key: E::Key, (rest, signature): Self::Rest) -> Self {
+        SignedEntry::new(E::join_key(key, rest), signature)
+    }
+}
+
+/// Delta encodes the keys of `item`.
+///
+/// Returns `None` if the encoding does not get smaller, e.g. because the keys share no
+/// prefixes.
+pub(super) fn encode<E>(
+    item: &RangeItem<E>,
+) -> Result<Option<DeltaRangeItem<E::Key>>, EnvelopeError>
+where
+    E: SplitKey + Serialize,
+    E::Key: AsKeyBytes,
+    E::Rest: Serialize,
+{
+    let mut values = Vec::with_capacity(item.values.len());
+    let mut previous = Vec::new();
+    for (entry, content_status) in &item.values {
+        let (key, rest) = entry.clone().split_key();
+        let key = key.as_key_bytes();
+        let shared = previous.iter().zip(key).take_while(|(a, b)| a == b).count();
+        previous.truncate(shared);
+        previous.extend_from_slice(&key[shared..]);
+        values.push((shared, key[shared..].to_vec(), rest, *content_status));
+    }
+    let encoded = postcard::to_stdvec(&values)?;
+    if encoded.len() >= encoded_size(&item.values) {
+        return Ok(None);
+    }
+    Ok(Some(DeltaRangeItem {
+        range: item.range.clone(),
+        values: encoded,
+        have_local: item.have_local,
+    }))
+}
+
+/// Reassembles the keys of `item`.
+pub(super) fn decode<E>(item: &DeltaRangeItem<E::Key>) -> Result<RangeItem<E>, EnvelopeError>
+where
+    E: SplitKey,
+    E::Key: AsKeyBytes,
+    E::Rest: DeserializeOwned,
+{
+    let encoded: Vec<(usize, Vec<u8>, E::Rest, ContentStatus)> =
+        postcard::from_bytes(&item.values)?;
+    let mut values = Vec::with_capacity(encoded.len());
+    let mut previous = Vec::new();
+    for (shared, suffix, rest, content_status) in encoded {
+        if shared > previous.len() {
+            return Err(EnvelopeError::InvalidDeltaKeys);
+        }
+        previous.truncate(shared);
+        previous.extend_from_slice(&suffix);
+        let key =
+            E::Key::from_key_bytes(previous.clone()).ok_or(EnvelopeError::InvalidDeltaKeys)?;
+        values.push((E::join_key(key, rest), content_status));
+    }
+    Ok(RangeItem {
+        range: item.range.clone(),
+        values,
+        have_local: item.have_local,
+    })
+}
@@ -0,0 +1,211 @@
+//! Running a sync session over an async transport.
+
+use std::pin::pin;
+
+use futures_util::{Sink, SinkExt, Stream, StreamExt};
+
+#[cfg(feature = "seal")]
+use super::SealingKey;
+use super::{Message, RangeEntry, Role, Store, SyncError, SyncSession, SyncStats};
+
+const DEFAULT_MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+
+/// Options for [`run_sync`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RunOptions {
+    role: Role,
+    handshake: bool,
+    max_frame_size: usize,
+    #[cfg(feature = "seal")]
+    sealing_key: Option<SealingKey>,
+}
+
+impl RunOptions {
+    /// Run the session as the initiator, which sends the initial message.
+    pub fn initiator() -> Self {
+        Self {
+            role: Role::Initiator,
+            handshake: false,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            #[cfg(feature = "seal")]
+            sealing_key: None,
+        }
+    }
+
+    /// Run the session as the responder, which waits for the initial message of the remote.
+    pub fn responder() -> Self {
+        Self {
+            role: Role::Responder,
+            handshake: false,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            #[cfg(feature = "seal")]
+            sealing_key: None,
+        }
+    }
+
+    /// Start with a handshake instead of the initial message, see
+    /// [`SyncSession::handshake_message`]. Only used by the initiator.
+    pub fn with_handshake(mut self, handshake: bool) -> Self {
+        self.handshake = handshake;
+        self
+    }
+
+    /// Set the maximum size of a frame in bytes, excluding the length prefix, that is sent or
+    /// received by [`run_sync_io`](super::run_sync_io). Defaults to 16 MiB.
+    pub fn with_max_frame_size(mut self, max_frame_size: usize) -> Self {
+        self.max_frame_size = max_frame_size;
+        self
+    }
+
+    /// Seal each frame sent and open each frame received by [`run_sync_io`](super::run_sync_io)
+    /// with `key`, see [`SealedMessage`](super::SealedMessage).
+    ///
+    /// Both sides need the same key. A frame that was modified on the way, or sealed with a
+    /// different key, fails the run with [`FrameError::Seal`](super::FrameError::Seal).
+    #[cfg(feature = "seal")]
+    pub fn with_sealing_key(mut self, key: SealingKey) -> Self {
+        self.sealing_key = Some(key);
+        self
+    }
+
+    /// Get the role the session is run with.
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    /// Get the maximum size of a frame, see [`RunOptions::with_max_frame_size`].
+    pub fn max_frame_size(&self) -> usize {
+        self.max_frame_size
+    }
+
+    /// Get the key frames are sealed with, see [`RunOptions::with_sealing_key`].
+    #[cfg(feature = "seal")]
+    pub fn sealing_key(&self) -> Option<&SealingKey> {
+        self.sealing_key.as_ref()
+    }
+}
+
+/// How a sync run with [`run_sync`] ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Termination {
+    /// We processed the last message of the sync, which needed no response, and closed the
+    /// transport.
+    Done,
+    /// The remote closed the transport after the last message we sent.
+    ///
+    /// The remote closes the transport once it processed the last message of the sync. As a
+    /// message that needs no response looks the same as one the remote could not respond to,
+    /// this is also returned if the remote went away mid-session.
+    Closed,
+    /// The remote cancelled the sync, see [`SyncSession::cancel_message`].
+    Cancelled,
+}
+
+/// The result of [`run_sync`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RunReport {
+    /// The role the session was run with.
+    pub role: Role,
+    /// How the sync ended.
+    pub termination: Termination,
+    /// The statistics of the session after the run.
+    pub stats: SyncStats,
+}
+
+/// Error returned from [`run_sync`].
+#[derive(Debug, thiserror::Error)]
+pub enum RunSyncError<S, T> {
+    /// Processing a message failed.
+    ///
+    /// The remote was sent a message that cancels the sync.
+    #[error(transparent)]
+    Sync(SyncError<S>),
+    /// Sending or receiving a message failed.
+    #[error("transport error: {0:?}")]
+    Transport(T),
+}
+
+/// Runs a sync session over a transport until the sync terminates.
+///
+/// The initiator sends the initial message, or a handshake, and both sides then respond to each
+/// message they receive until a message needs no response. The side that processes that message
+/// closes the transport, which ends the run for the remote, see [`Termination`].
+///
+/// Neither side may split its responses with [`SyncSession::with_max_message_bytes`]: a message
+/// of a split response that needs no response looks the same as the last message of the sync,
+/// so the receiver would end the run before it received the rest of the response. Responses
+/// split into pages with [`SyncSession::with_page_size`] are fine, as all pages but the last are
+/// flagged, see [`Message::has_more`].
+///
+/// The limits of the session are enforced while processing, see [`SyncSession::process`], and
+/// the stored callbacks of the session are used. If processing fails, the remote is sent a message
+/// that cancels its session before the error is returned.
+pub async fn run_sync<E, S, T, Err>(
+    session: &mut SyncSession<E>,
+    store: &mut S,
+    transport: T,
+    options: RunOptions,
+) -> Result<RunReport, RunSyncError<S::Error, Err>>
+where
+    E: RangeEntry,
+    S: Store<E>,
+    T: Sink<Message<E>, Error = Err> + Stream<Item = Result<Message<E>, Err>>,
+{
+    let mut transport = pin!(transport);
+
+    if options.role == Role::Initiator {
+        let message = match options.handshake {
+            true => session.handshake_message(store),
+            false => session.initial_message(store),
+        }
+        .map_err(RunSyncError::Sync)?;
+        transport
+            .send(message)
+            .await
+            .map_err(RunSyncError::Transport)?;
+    }
+
+    let termination = loop {
+        let message = match transport.next().await {
+            Some(message) => message.map_err(RunSyncError::Transport)?,
+            None => break Termination::Closed,
+        };
+        let more = message.has_more();
+        let reply = match session.process(store, message) {
+            Ok(reply) => reply,
+            Err(err) => {
+                // best effort, the error of the session is more useful than a send error
+                let _ = transport.send(session.cancel_message()).await;
+                return Err(RunSyncError::Sync(err));
+            }
+        };
+        let Some(reply) = reply else {
+            // the response follows the last page of the round
+            if more {
+                continue;
+            }
+            transport.close().await.map_err(RunSyncError::Transport)?;
+            match session.is_cancelled() {
+                true => break Termination::Cancelled,
+                false => break Termination::Done,
+            }
+        };
+        transport
+            .feed(reply)
+            .await
+            .map_err(RunSyncError::Transport)?;
+        while let Some(message) = session.poll_pending_message() {
+            transport
+                .feed(message)
+                .await
+                .map_err(RunSyncError::Transport)?;
+        }
+        transport.flush().await.map_err(RunSyncError::Transport)?;
+    };
+
+    Ok(RunReport {
+        role: options.role,
+        termination,
+        stats: session.stats().clone(),
+    })
+}
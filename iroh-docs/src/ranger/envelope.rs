@@ -0,0 +1,227 @@
+//! Versioned encoding of messages, see [`SyncSession::encode_message`].
+//!
+//! [`SyncSession::encode_message`]: super::SyncSession::encode_message
+
+use std::io;
+
+#[cfg(feature = "zstd")]
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "zstd")]
+use super::{Codec, CompressedRangeItem, RangeItem};
+use super::{Message, MessagePart, ProtocolError, RangeEntry};
+
+/// The highest version of the protocol this implementation supports.
+///
+/// * Version 1 is the initial protocol.
+/// * Version 2 adds [`MessagePart::Reopen`].
+/// * Version 3 adds [`MessagePart::Done`].
+/// * Version 4 adds [`MessagePart::LazyRangeItem`], [`MessagePart::ValueRequest`] and
+///   [`MessagePart::ValueResponse`].
+/// * Version 5 adds [`Message::has_more`].
+/// * Version 6 adds [`MessagePart::WantKeys`] and [`MessagePart::MissingKeys`].
+/// * Version 7 adds [`MessagePart::StoreSummary`].
+/// * Version 8 adds [`MessagePart::DeltaRangeItem`].
+/// * Version 9 adds [`MessagePart::CompressedRangeItem`], and is only supported with the `zstd`
+///   feature, as are all later versions.
+/// * Version 10 adds [`MessagePart::SendAll`].
+/// * Version 11 adds [`MessagePart::Divergent`].
+/// * Version 12 adds [`MessagePart::ValueChunk`].
+/// * Version 13 adds [`MessagePart::RangeIds`].
+#[cfg(feature = "zstd")]
+pub const PROTOCOL_VERSION: u32 = RANGE_IDS_VERSION;
+/// The highest version of the protocol this implementation supports.
+///
+/// * Version 1 is the initial protocol.
+/// * Version 2 adds [`MessagePart::Reopen`].
+/// * Version 3 adds [`MessagePart::Done`].
+/// * Version 4 adds [`MessagePart::LazyRangeItem`], [`MessagePart::ValueRequest`] and
+///   [`MessagePart::ValueResponse`].
+/// * Version 5 adds [`Message::has_more`].
+/// * Version 6 adds [`MessagePart::WantKeys`] and [`MessagePart::MissingKeys`].
+/// * Version 7 adds [`MessagePart::StoreSummary`].
+/// * Version 8 adds [`MessagePart::DeltaRangeItem`].
+/// * Version 9 adds [`MessagePart::CompressedRangeItem`], and is only supported with the `zstd`
+///   feature, as are all later versions.
+/// * Version 10 adds [`MessagePart::SendAll`].
+/// * Version 11 adds [`MessagePart::Divergent`].
+/// * Version 12 adds [`MessagePart::ValueChunk`].
+/// * Version 13 adds [`MessagePart::RangeIds`].
+#[cfg(not(feature = "zstd"))]
+pub const PROTOCOL_VERSION: u32 = COMPRESSION_VERSION - 1;
+
+/// The version of the protocol that adds [`MessagePart::StoreSummary`].
+pub(super) const SUMMARY_VERSION: u32 = 7;
+
+/// The version of the protocol that adds [`MessagePart::DeltaRangeItem`].
+pub(super) const DELTA_VERSION: u32 = 8;
+
+/// The version of the protocol that adds [`MessagePart::CompressedRangeItem`].
+pub(super) const COMPRESSION_VERSION: u32 = 9;
+
+/// The version of the protocol that adds [`MessagePart::SendAll`].
+pub(super) const SEND_ALL_VERSION: u32 = 10;
+
+/// The version of the protocol that adds [`MessagePart::Divergent`].
+pub(super) const DIVERGENT_VERSION: u32 = 11;
+
+/// The version of the protocol that adds [`MessagePart::ValueChunk`].
+pub(super) const CHUNK_VERSION: u32 = 12;
+
+/// The version of the protocol that adds [`MessagePart::RangeIds`].
+pub(super) const RANGE_IDS_VERSION: u32 = 13;
+
+/// The zstd compression level of [`MessagePart::CompressedRangeItem`].
+#[cfg(feature = "zstd")]
+const ZSTD_LEVEL: i32 = 3;
+
+/// Maximum size of the decompressed values of a [`MessagePart::CompressedRangeItem`].
+#[cfg(feature = "zstd")]
+const MAX_DECOMPRESSED_SIZE: usize = 16 * 1024 * 1024;
+
+/// A message as sent on the wire, together with the version of the protocol it is encoded with.
+///
+/// The envelope is encoded the same in all versions of the protocol, so the version of a
+/// message can be read before the message itself is decoded, which may fail for message parts
+/// the receiver does not know.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Envelope {
+    /// The highest version supported by the sender for the first message of a session, and the
+    /// negotiated version for all other messages.
+    pub version: u32,
+    /// The postcard encoding of the message.
+    pub payload: Vec<u8>,
+}
+
+/// Error returned from [`SyncSession::encode_message`] and [`SyncSession::decode_message`].
+///
+/// [`SyncSession::encode_message`]: super::SyncSession::encode_message
+/// [`SyncSession::decode_message`]: super::SyncSession::decode_message
+#[derive(Debug, thiserror::Error)]
+pub enum EnvelopeError {
+    /// The version of the message is not supported, or the message needs a newer version than
+    /// the negotiated one.
+    #[error(transparent)]
+    Protocol(#[from] ProtocolError),
+    /// The envelope or the message could not be encoded or decoded.
+    #[error("failed to encode or decode message: {0}")]
+    Encoding(#[from] postcard::Error),
+    /// The values of a [`MessagePart::CompressedRangeItem`] could not be compressed or
+    /// decompressed.
+    #[error("failed to compress or decompress values: {0}")]
+    Compression(#[source] io::Error),
+    /// A [`MessagePart::DeltaRangeItem`] was received, but delta keys are not enabled, see
+    /// [`SyncSession::with_delta_keys`].
+    ///
+    /// [`SyncSession::with_delta_keys`]: super::SyncSession::with_delta_keys
+    #[error("received delta encoded keys, which are not enabled")]
+    DeltaKeysDisabled,
+    /// The keys of a [`MessagePart::DeltaRangeItem`] could not be reassembled.
+    #[error("invalid delta encoded keys")]
+    InvalidDeltaKeys,
+    /// A [`MessagePart::ValueChunk`] does not fit the chunks received before for its key, or
+    /// the reassembled chunks are not a single entry with that key.
+    #[error("invalid value chunk")]
+    InvalidValueChunk,
+    /// A message could not be sealed or opened, see [`SyncSession::open_message`].
+    ///
+    /// [`SyncSession::open_message`]: super::SyncSession::open_message
+    #[cfg(feature = "seal")]
+    #[error(transparent)]
+    Seal(#[from] super::SealError),
+}
+
+impl<E: RangeEntry> MessagePart<E> {
+    /// Get the version of the protocol that introduced this kind of part.
+    pub fn min_version(&self) -> u32 {
+        match self {
+            MessagePart::Reopen => 2,
+            MessagePart::Done => 3,
+            MessagePart::LazyRangeItem(_)
+            | MessagePart::ValueRequest(_)
+            | MessagePart::ValueResponse(_) => 4,
+            MessagePart::More => 5,
+            MessagePart::WantKeys { .. } | MessagePart::MissingKeys { .. } => 6,
+            MessagePart::StoreSummary(_) => SUMMARY_VERSION,
+            MessagePart::DeltaRangeItem(_) => DELTA_VERSION,
+            MessagePart::CompressedRangeItem(_) => COMPRESSION_VERSION,
+            MessagePart::SendAll { .. } => SEND_ALL_VERSION,
+            MessagePart::Divergent { .. } => DIVERGENT_VERSION,
+            MessagePart::ValueChunk { .. } => CHUNK_VERSION,
+            MessagePart::RangeIds(_) => RANGE_IDS_VERSION,
+            MessagePart::RangeFingerprint(_)
+            | MessagePart::RangeItem(_)
+            | MessagePart::Cancel
+            | MessagePart::Handshake(_)
+            | MessagePart::HandshakeMatch
+            | MessagePart::SessionId(_)
+            | MessagePart::Sequence(_) => 1,
+        }
+    }
+}
+
+impl<E: RangeEntry> Message<E> {
+    /// Get the version of the protocol needed to send this message, see
+    /// [`MessagePart::min_version`].
+    pub fn min_version(&self) -> u32 {
+        let more = match self.has_more() {
+            true => MessagePart::<E>::More.min_version(),
+            false => 1,
+        };
+        self.parts()
+            .iter()
+            .map(MessagePart::min_version)
+            .fold(more, u32::max)
+    }
+}
+
+/// Compresses the values of `item` if their encoding exceeds `threshold` bytes, see
+/// [`SyncSession::with_compression`].
+///
+/// Returns `None` if the values are below the threshold or do not get smaller.
+///
+/// [`SyncSession::with_compression`]: super::SyncSession::with_compression
+#[cfg(feature = "zstd")]
+pub(super) fn compress<E>(
+    item: &RangeItem<E>,
+    threshold: usize,
+) -> Result<Option<CompressedRangeItem<E::Key>>, EnvelopeError>
+where
+    E: RangeEntry + Serialize,
+{
+    let values = postcard::to_stdvec(&item.values)?;
+    if values.len() <= threshold {
+        return Ok(None);
+    }
+    let compressed =
+        zstd::bulk::compress(&values, ZSTD_LEVEL).map_err(EnvelopeError::Compression)?;
+    if compressed.len() >= values.len() {
+        return Ok(None);
+    }
+    Ok(Some(CompressedRangeItem {
+        range: item.range.clone(),
+        compressed,
+        codec: Codec::Zstd,
+        have_local: item.have_local,
+    }))
+}
+
+/// Decompresses the values of `item`.
+#[cfg(feature = "zstd")]
+pub(super) fn decompress<E>(
+    item: &CompressedRangeItem<E::Key>,
+) -> Result<RangeItem<E>, EnvelopeError>
+where
+    E: RangeEntry + DeserializeOwned,
+{
+    let values = match item.codec {
+        Codec::Zstd => zstd::bulk::decompress(&item.compressed, MAX_DECOMPRESSED_SIZE)
+            .map_err(EnvelopeError::Compression)?,
+    };
+    Ok(RangeItem {
+        range: item.range.clone(),
+        values: postcard::from_bytes(&values)?,
+        have_local: item.have_local,
+    })
+}
@@ -0,0 +1,141 @@
+//! A store wrapper that counts the writes to the inner store, see [`EpochStore`].
+
+use super::{validators::EntryLookup, ChunkIterator, Fingerprint, Range, RangeEntry, Store};
+
+/// A [`Store`] that delegates to an inner store, and increments its epoch, see
+/// [`Store::epoch`], with each write to it.
+///
+/// Each call to [`Store::entry_put`] is a write, as are the calls to [`Store::entry_remove`] and
+/// [`Store::remove_prefix_filtered`] that remove an entry. Writes that fail still increment the
+/// epoch, as they may have changed the inner store before failing.
+#[derive(Debug, Default)]
+pub struct EpochStore<S> {
+    store: S,
+    epoch: u64,
+}
+
+impl<S> EpochStore<S> {
+    /// Wrap a store, starting at epoch 0.
+    pub fn new(store: S) -> Self {
+        Self { store, epoch: 0 }
+    }
+
+    /// Get a reference to the inner store.
+    pub fn inner(&self) -> &S {
+        &self.store
+    }
+
+    /// Unwrap the inner store.
+    pub fn into_inner(self) -> S {
+        self.store
+    }
+}
+
+impl<E: RangeEntry, S: EntryLookup<E>> EntryLookup<E> for EpochStore<S> {
+    fn lookup(&self, key: &E::Key) -> Option<&E> {
+        self.store.lookup(key)
+    }
+}
+
+impl<E: RangeEntry, S: Store<E>> Store<E> for EpochStore<S> {
+    type Error = S::Error;
+    type RangeIterator<'a>
+        = S::RangeIterator<'a>
+    where
+        S: 'a,
+        E: 'a;
+    type ParentIterator<'a>
+        = S::ParentIterator<'a>
+    where
+        S: 'a,
+        E: 'a;
+
+    fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    fn get_first(&mut self) -> Result<E::Key, Self::Error> {
+        self.store.get_first()
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn first_and_last(&mut self) -> Result<Option<(E::Key, E::Key)>, Self::Error> {
+        self.store.first_and_last()
+    }
+
+    fn get(&mut self, key: &E::Key) -> Result<Option<E>, Self::Error> {
+        self.store.get(key)
+    }
+
+    fn entry_fingerprint(&mut self, key: &E::Key) -> Result<Option<Fingerprint>, Self::Error> {
+        self.store.entry_fingerprint(key)
+    }
+
+    fn len(&mut self) -> Result<usize, Self::Error> {
+        self.store.len()
+    }
+
+    fn is_empty(&mut self) -> Result<bool, Self::Error> {
+        self.store.is_empty()
+    }
+
+    fn get_fingerprint(&mut self, range: &Range<E::Key>) -> Result<Fingerprint, Self::Error> {
+        self.store.get_fingerprint(range)
+    }
+
+    fn entry_put(&mut self, entry: E) -> Result<(), Self::Error> {
+        self.epoch += 1;
+        self.store.entry_put(entry)
+    }
+
+    fn get_range(&mut self, range: Range<E::Key>) -> Result<Self::RangeIterator<'_>, Self::Error> {
+        self.store.get_range(range)
+    }
+
+    fn get_range_chunked<'a>(
+        &'a mut self,
+        range: Range<E::Key>,
+        chunk_size: usize,
+    ) -> Result<ChunkIterator<'a, E, Self::Error>, Self::Error>
+    where
+        E: 'a,
+    {
+        self.store.get_range_chunked(range, chunk_size)
+    }
+
+    fn get_range_len(&mut self, range: Range<E::Key>) -> Result<usize, Self::Error> {
+        self.store.get_range_len(range)
+    }
+
+    fn prefixed_by(&mut self, prefix: &E::Key) -> Result<Self::RangeIterator<'_>, Self::Error> {
+        self.store.prefixed_by(prefix)
+    }
+
+    fn prefixes_of(&mut self, key: &E::Key) -> Result<Self::ParentIterator<'_>, Self::Error> {
+        self.store.prefixes_of(key)
+    }
+
+    fn all(&mut self) -> Result<Self::RangeIterator<'_>, Self::Error> {
+        self.store.all()
+    }
+
+    fn entry_remove(&mut self, key: &E::Key) -> Result<Option<E>, Self::Error> {
+        let removed = self.store.entry_remove(key);
+        if !matches!(removed, Ok(None)) {
+            self.epoch += 1;
+        }
+        removed
+    }
+
+    fn remove_prefix_filtered(
+        &mut self,
+        prefix: &E::Key,
+        predicate: impl Fn(&E::Value) -> bool,
+    ) -> Result<usize, Self::Error> {
+        let removed = self.store.remove_prefix_filtered(prefix, predicate);
+        if !matches!(removed, Ok(0)) {
+            self.epoch += 1;
+        }
+        removed
+    }
+}
@@ -0,0 +1,101 @@
+//! Entries that expire, see [`ExpiryPolicy`].
+
+use std::{sync::Arc, time::SystemTime};
+
+use super::{EntryFilter, FilteredStore, RangeEntry, Store};
+
+type ExpiredFn<E> = Arc<dyn Fn(&E, SystemTime) -> bool + Send + Sync>;
+type ClockFn = Arc<dyn Fn() -> SystemTime + Send + Sync>;
+
+/// Decides which entries are expired, and treats them as absent.
+///
+/// Expired entries are neither sent to nor accepted from the remote:
+/// - a store wrapped with [`ExpiryPolicy::store`] withholds its expired entries from the sync,
+///   including the fingerprints, so two stores that only differ in expired entries are in sync;
+/// - a session with [`SyncSession::with_expiry_policy`] rejects received entries that are
+///   expired, so a remote that does not withhold them cannot resurrect them.
+///
+/// Expired entries stay in the store until they are removed with
+/// [`ExpiringStore::gc_expired`]. Point lookups still see them, so that an older entry received
+/// for an expired key does not replace it.
+///
+/// The current time is read from a clock, which defaults to [`SystemTime::now`], and can be
+/// replaced with [`ExpiryPolicy::with_clock`], e.g. in tests.
+///
+/// [`SyncSession::with_expiry_policy`]: super::SyncSession::with_expiry_policy
+pub struct ExpiryPolicy<E> {
+    is_expired: ExpiredFn<E>,
+    clock: ClockFn,
+}
+
+/// A store whose expired entries are withheld from the sync, see [`ExpiryPolicy::store`].
+pub type ExpiringStore<S, E> = FilteredStore<S, ExpiryPolicy<E>>;
+
+impl<E> Clone for ExpiryPolicy<E> {
+    fn clone(&self) -> Self {
+        Self {
+            is_expired: self.is_expired.clone(),
+            clock: self.clock.clone(),
+        }
+    }
+}
+
+impl<E> std::fmt::Debug for ExpiryPolicy<E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ExpiryPolicy").finish_non_exhaustive()
+    }
+}
+
+impl<E> ExpiryPolicy<E> {
+    /// Create a policy where `is_expired` returns `true` for the entries that are expired at
+    /// the given time.
+    pub fn new(is_expired: impl Fn(&E, SystemTime) -> bool + Send + Sync + 'static) -> Self {
+        Self {
+            is_expired: Arc::new(is_expired),
+            clock: Arc::new(SystemTime::now),
+        }
+    }
+
+    /// Read the current time from `clock` instead of [`SystemTime::now`].
+    pub fn with_clock(mut self, clock: impl Fn() -> SystemTime + Send + Sync + 'static) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Get the current time, according to the clock of the policy.
+    pub fn now(&self) -> SystemTime {
+        (self.clock)()
+    }
+
+    /// Returns `true` if `entry` is expired at `now`.
+    pub fn is_expired_at(&self, entry: &E, now: SystemTime) -> bool {
+        (self.is_expired)(entry, now)
+    }
+
+    /// Returns `true` if `entry` is expired now.
+    pub fn is_expired(&self, entry: &E) -> bool {
+        self.is_expired_at(entry, self.now())
+    }
+
+    /// Wrap `store`, so that its expired entries are withheld from the sync.
+    pub fn store<S>(&self, store: S) -> ExpiringStore<S, E> {
+        FilteredStore::new(store, self.clone())
+    }
+}
+
+impl<E> EntryFilter<E> for ExpiryPolicy<E> {
+    fn keep(&self, entry: &E) -> bool {
+        !self.is_expired(entry)
+    }
+}
+
+impl<S, E> ExpiringStore<S, E>
+where
+    E: RangeEntry,
+    S: Store<E>,
+{
+    /// Remove the expired entries from the inner store, and return how many were removed.
+    pub fn gc_expired(&mut self) -> Result<usize, S::Error> {
+        self.remove_withheld()
+    }
+}
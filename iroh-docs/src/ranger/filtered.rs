@@ -0,0 +1,220 @@
+//! A store wrapper that withholds entries from sync.
+
+use super::{
+    validators::EntryLookup, ChunkEntries, ChunkIterator, Fingerprint, Range, RangeChunks,
+    RangeEntry, Store,
+};
+
+/// Decides which entries a [`FilteredStore`] yields.
+///
+/// This is implemented for all functions `Fn(&E) -> bool`, and for [`ExpiryPolicy`], which keeps
+/// the entries that are not expired.
+///
+/// [`ExpiryPolicy`]: super::ExpiryPolicy
+pub trait EntryFilter<E> {
+    /// Returns `true` if `entry` is yielded, and `false` if it is withheld.
+    fn keep(&self, entry: &E) -> bool;
+}
+
+impl<E, F: Fn(&E) -> bool> EntryFilter<E> for F {
+    fn keep(&self, entry: &E) -> bool {
+        self(entry)
+    }
+}
+
+/// A [`Store`] that hides the entries rejected by a filter from everything the sync protocol
+/// sends to the remote.
+///
+/// The filter is called with each local entry that would be read from a range, and only entries
+/// for which it returns `true` are yielded. This affects [`Store::get_range`],
+/// [`Store::get_range_chunked`], [`Store::prefixed_by`], [`Store::all`], and everything derived
+/// from them, in particular the fingerprints. Withheld entries are therefore neither sent nor
+/// advertised, and a remote that has the same withheld entries sees a fingerprint mismatch and
+/// sends them to us, where they are ignored like any other entry we already have.
+///
+/// Point lookups with [`Store::get`] and [`Store::prefixes_of`] still see withheld entries, so
+/// that received entries do not overwrite newer withheld entries. Writes are passed to the inner
+/// store unchanged.
+#[derive(Debug, Clone)]
+pub struct FilteredStore<S, F> {
+    store: S,
+    filter: F,
+}
+
+impl<S, F> FilteredStore<S, F> {
+    /// Wrap a store, withholding all entries for which `filter` returns `false`.
+    pub fn new(store: S, filter: F) -> Self {
+        Self { store, filter }
+    }
+
+    /// Get a reference to the inner store.
+    pub fn inner(&self) -> &S {
+        &self.store
+    }
+
+    /// Unwrap the inner store.
+    pub fn into_inner(self) -> S {
+        self.store
+    }
+
+    /// Remove the withheld entries from the inner store, and return how many were removed.
+    pub fn remove_withheld<E>(&mut self) -> Result<usize, S::Error>
+    where
+        E: RangeEntry,
+        S: Store<E>,
+        F: EntryFilter<E>,
+    {
+        let mut withheld = Vec::new();
+        for entry in self.store.all()? {
+            let entry = entry?;
+            if !self.filter.keep(&entry) {
+                withheld.push(entry.key().clone());
+            }
+        }
+        for key in &withheld {
+            self.store.entry_remove(key)?;
+        }
+        Ok(withheld.len())
+    }
+}
+
+impl<E: RangeEntry, S: EntryLookup<E>, F> EntryLookup<E> for FilteredStore<S, F> {
+    /// Like [`Store::get`], this sees withheld entries.
+    fn lookup(&self, key: &E::Key) -> Option<&E> {
+        self.store.lookup(key)
+    }
+}
+
+/// Iterator returned from a [`FilteredStore`], skipping withheld entries.
+#[derive(Debug)]
+pub struct FilteredIterator<'a, I, F> {
+    iter: I,
+    filter: &'a F,
+}
+
+impl<'a, E, Err, I, F> Iterator for FilteredIterator<'a, I, F>
+where
+    I: Iterator<Item = Result<E, Err>>,
+    F: EntryFilter<E>,
+{
+    type Item = Result<E, Err>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let filter = self.filter;
+        self.iter.find(|entry| match entry {
+            Ok(entry) => filter.keep(entry),
+            Err(_) => true,
+        })
+    }
+}
+
+impl<E, S, F> Store<E> for FilteredStore<S, F>
+where
+    E: RangeEntry,
+    S: Store<E>,
+    F: EntryFilter<E>,
+{
+    type Error = S::Error;
+    type RangeIterator<'a> = FilteredIterator<'a, S::RangeIterator<'a>, F>
+    where S: 'a, F: 'a, E: 'a;
+    type ParentIterator<'a> = S::ParentIterator<'a> where S: 'a, F: 'a, E: 'a;
+
+    fn epoch(&self) -> u64 {
+        self.store.epoch()
+    }
+
+    fn get_first(&mut self) -> Result<E::Key, Self::Error> {
+        let first = self.all()?.next().transpose()?;
+        match first {
+            Some(entry) => Ok(entry.key().clone()),
+            // if all entries are withheld, any key describes the full range
+            None => self.store.get_first(),
+        }
+    }
+
+    fn get(&mut self, key: &E::Key) -> Result<Option<E>, Self::Error> {
+        self.store.get(key)
+    }
+
+    fn len(&mut self) -> Result<usize, Self::Error> {
+        let mut len = 0;
+        for entry in self.all()? {
+            entry?;
+            len += 1;
+        }
+        Ok(len)
+    }
+
+    fn is_empty(&mut self) -> Result<bool, Self::Error> {
+        Ok(self.all()?.next().transpose()?.is_none())
+    }
+
+    fn get_fingerprint(&mut self, range: &Range<E::Key>) -> Result<Fingerprint, Self::Error> {
+        let mut fp = Fingerprint::empty();
+        for entry in self.get_range(range.clone())? {
+            fp ^= entry?.as_fingerprint();
+        }
+        Ok(fp)
+    }
+
+    fn entry_put(&mut self, entry: E) -> Result<(), Self::Error> {
+        self.store.entry_put(entry)
+    }
+
+    fn get_range(&mut self, range: Range<E::Key>) -> Result<Self::RangeIterator<'_>, Self::Error> {
+        let iter = self.store.get_range(range)?;
+        Ok(FilteredIterator {
+            iter,
+            filter: &self.filter,
+        })
+    }
+
+    fn get_range_chunked<'a>(
+        &'a mut self,
+        range: Range<E::Key>,
+        chunk_size: usize,
+    ) -> Result<ChunkIterator<'a, E, Self::Error>, Self::Error>
+    where
+        E: 'a,
+    {
+        // Re-chunk the remaining entries, so that chunks stay full if entries are withheld.
+        let chunks = self.store.get_range_chunked(range.clone(), chunk_size)?;
+        let iter = FilteredIterator {
+            iter: ChunkEntries::new(chunks),
+            filter: &self.filter,
+        };
+        Ok(Box::new(RangeChunks::new(range, chunk_size, iter)))
+    }
+
+    fn prefixed_by(&mut self, prefix: &E::Key) -> Result<Self::RangeIterator<'_>, Self::Error> {
+        let iter = self.store.prefixed_by(prefix)?;
+        Ok(FilteredIterator {
+            iter,
+            filter: &self.filter,
+        })
+    }
+
+    fn prefixes_of(&mut self, key: &E::Key) -> Result<Self::ParentIterator<'_>, Self::Error> {
+        self.store.prefixes_of(key)
+    }
+
+    fn all(&mut self) -> Result<Self::RangeIterator<'_>, Self::Error> {
+        let iter = self.store.all()?;
+        Ok(FilteredIterator {
+            iter,
+            filter: &self.filter,
+        })
+    }
+
+    fn entry_remove(&mut self, key: &E::Key) -> Result<Option<E>, Self::Error> {
+        self.store.entry_remove(key)
+    }
+
+    fn remove_prefix_filtered(
+        &mut self,
+        prefix: &E::Key,
+        predicate: impl Fn(&E::Value) -> bool,
+    ) -> Result<usize, Self::Error> {
+        self.store.remove_prefix_filtered(prefix, predicate)
+    }
+}
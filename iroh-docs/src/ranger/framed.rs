@@ -0,0 +1,263 @@
+//! Running a sync session over a byte stream with length-prefixed frames.
+
+use std::{
+    io,
+    marker::PhantomData,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use futures_util::{Sink, Stream};
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use super::{
+    run_sync, Message, RangeEntry, RunOptions, RunReport, RunSyncError, Store, SyncSession,
+};
+#[cfg(feature = "seal")]
+use super::{SealError, SealedMessage, SealingKey};
+
+/// Size of the length prefix of a frame.
+const PREFIX_LEN: usize = 4;
+
+/// Error of the transport of [`run_sync_io`].
+#[derive(Debug, thiserror::Error)]
+pub enum FrameError {
+    /// Reading from or writing to the byte stream failed.
+    #[error("io error: {0}")]
+    Io(#[from] io::Error),
+    /// A frame exceeded the maximum frame size, see [`RunOptions::with_max_frame_size`].
+    #[error("frame of {len} bytes exceeds the maximum of {max} bytes")]
+    TooLarge {
+        /// The size of the frame.
+        len: usize,
+        /// The maximum frame size.
+        max: usize,
+    },
+    /// The byte stream ended in the middle of a frame.
+    #[error("stream ended after {received} of {expected} bytes of a frame")]
+    Truncated {
+        /// Number of bytes of the frame, including the length prefix, that were received.
+        received: usize,
+        /// Number of bytes of the frame, as far as they are known, including the length prefix.
+        expected: usize,
+    },
+    /// The remote closed the byte stream while we were sending.
+    #[error("remote closed the stream")]
+    Closed,
+    /// A frame could not be decoded into a message, or a message could not be encoded.
+    #[error("failed to encode or decode message: {0}")]
+    Encoding(#[from] postcard::Error),
+    /// A frame could not be sealed or opened, see [`RunOptions::with_sealing_key`].
+    #[cfg(feature = "seal")]
+    #[error(transparent)]
+    Seal(#[from] SealError),
+}
+
+/// Runs a sync session over a byte stream until the sync terminates, see [`run_sync`].
+///
+/// Each message is sent as a frame of its postcard encoding, prefixed with its length as a
+/// big-endian `u32`. Frames larger than [`RunOptions::max_frame_size`] are neither sent nor
+/// read. The writer is shut down when the sync ends on our side.
+///
+/// With [`RunOptions::with_sealing_key`], each frame is the postcard encoding of a
+/// [`SealedMessage`] instead, which seals the postcard encoding of the message.
+pub async fn run_sync_io<E, S, R, W>(
+    session: &mut SyncSession<E>,
+    store: &mut S,
+    reader: R,
+    writer: W,
+    options: RunOptions,
+) -> Result<RunReport, RunSyncError<S::Error, FrameError>>
+where
+    E: RangeEntry,
+    S: Store<E>,
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+    Message<E>: Serialize + DeserializeOwned,
+{
+    let transport = Framed::new(reader, writer, options.max_frame_size());
+    #[cfg(feature = "seal")]
+    let transport = transport.with_sealing_key(options.sealing_key().copied());
+    run_sync(session, store, transport, options).await
+}
+
+/// A [`Sink`] and [`Stream`] of messages over a byte stream, see [`run_sync_io`].
+#[derive(Debug)]
+pub(super) struct Framed<E, R, W> {
+    reader: R,
+    writer: W,
+    max_frame_size: usize,
+    /// The bytes of the frame that is being read, of which the first `read_len` are filled.
+    read_buf: Vec<u8>,
+    read_len: usize,
+    /// The frames that are being written, of which the first `written` bytes were written.
+    write_buf: Vec<u8>,
+    written: usize,
+    #[cfg(feature = "seal")]
+    sealing_key: Option<SealingKey>,
+    _entry: PhantomData<fn() -> E>,
+}
+
+impl<E, R, W> Framed<E, R, W> {
+    pub(super) fn new(reader: R, writer: W, max_frame_size: usize) -> Self {
+        Self {
+            reader,
+            writer,
+            max_frame_size,
+            read_buf: Vec::new(),
+            read_len: 0,
+            write_buf: Vec::new(),
+            written: 0,
+            #[cfg(feature = "seal")]
+            sealing_key: None,
+            _entry: PhantomData,
+        }
+    }
+
+    /// Seal and open the frames with `key`, see [`RunOptions::with_sealing_key`].
+    #[cfg(feature = "seal")]
+    pub(super) fn with_sealing_key(mut self, key: Option<SealingKey>) -> Self {
+        self.sealing_key = key;
+        self
+    }
+
+    /// Decodes the message of a received frame.
+    fn decode_frame(&self, frame: &[u8]) -> Result<Message<E>, FrameError>
+    where
+        E: RangeEntry,
+        Message<E>: DeserializeOwned,
+    {
+        #[cfg(feature = "seal")]
+        if let Some(key) = &self.sealing_key {
+            let sealed: SealedMessage = postcard::from_bytes(frame)?;
+            return Ok(postcard::from_bytes(&sealed.open(key)?)?);
+        }
+        Ok(postcard::from_bytes(frame)?)
+    }
+
+    /// Encodes a message into a frame, without the length prefix.
+    fn encode_frame(&self, message: &Message<E>) -> Result<Vec<u8>, FrameError>
+    where
+        E: RangeEntry,
+        Message<E>: Serialize,
+    {
+        let frame = postcard::to_stdvec(message)?;
+        #[cfg(feature = "seal")]
+        if let Some(key) = &self.sealing_key {
+            let sealed = SealedMessage::seal(key, &frame)?;
+            return Ok(postcard::to_stdvec(&sealed)?);
+        }
+        Ok(frame)
+    }
+}
+
+impl<E, R, W> Stream for Framed<E, R, W>
+where
+    E: RangeEntry,
+    R: AsyncRead + Unpin,
+    W: Unpin,
+    Message<E>: DeserializeOwned,
+{
+    type Item = Result<Message<E>, FrameError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            let mut expected = PREFIX_LEN;
+            if this.read_len >= PREFIX_LEN {
+                let prefix: [u8; PREFIX_LEN] = this.read_buf[..PREFIX_LEN].try_into().unwrap();
+                let len = u32::from_be_bytes(prefix) as usize;
+                if len > this.max_frame_size {
+                    let max = this.max_frame_size;
+                    return Poll::Ready(Some(Err(FrameError::TooLarge { len, max })));
+                }
+                expected += len;
+                if this.read_len == expected {
+                    this.read_len = 0;
+                    let message = this.decode_frame(&this.read_buf[PREFIX_LEN..expected]);
+                    return Poll::Ready(Some(message));
+                }
+            }
+            if this.read_buf.len() < expected {
+                this.read_buf.resize(expected, 0);
+            }
+            let mut buf = ReadBuf::new(&mut this.read_buf[this.read_len..expected]);
+            ready!(Pin::new(&mut this.reader).poll_read(cx, &mut buf))?;
+            match buf.filled().len() {
+                0 if this.read_len == 0 => return Poll::Ready(None),
+                0 => {
+                    let received = this.read_len;
+                    return Poll::Ready(Some(Err(FrameError::Truncated { received, expected })));
+                }
+                n => this.read_len += n,
+            }
+        }
+    }
+}
+
+impl<E, R, W> Sink<Message<E>> for Framed<E, R, W>
+where
+    E: RangeEntry,
+    R: Unpin,
+    W: AsyncWrite + Unpin,
+    Message<E>: Serialize,
+{
+    type Error = FrameError;
+
+    fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Message<E>) -> Result<(), Self::Error> {
+        let this = self.get_mut();
+        let frame = this.encode_frame(&item)?;
+        if frame.len() > this.max_frame_size {
+            let max = this.max_frame_size;
+            return Err(FrameError::TooLarge {
+                len: frame.len(),
+                max,
+            });
+        }
+        let len = u32::try_from(frame.len()).map_err(|_| FrameError::TooLarge {
+            len: frame.len(),
+            max: u32::MAX as usize,
+        })?;
+        this.write_buf.extend_from_slice(&len.to_be_bytes());
+        this.write_buf.extend_from_slice(&frame);
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let this = self.get_mut();
+        while this.written < this.write_buf.len() {
+            let buf = &this.write_buf[this.written..];
+            let n = ready!(Pin::new(&mut this.writer).poll_write(cx, buf)).map_err(write_error)?;
+            if n == 0 {
+                return Poll::Ready(Err(FrameError::Closed));
+            }
+            this.written += n;
+        }
+        this.write_buf.clear();
+        this.written = 0;
+        let res = ready!(Pin::new(&mut this.writer).poll_flush(cx));
+        Poll::Ready(res.map_err(write_error))
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        ready!(self.as_mut().poll_flush(cx))?;
+        let res = ready!(Pin::new(&mut self.get_mut().writer).poll_shutdown(cx));
+        Poll::Ready(res.map_err(write_error))
+    }
+}
+
+/// Maps a write error that means that the remote closed the stream to [`FrameError::Closed`].
+fn write_error(err: io::Error) -> FrameError {
+    match err.kind() {
+        io::ErrorKind::BrokenPipe
+        | io::ErrorKind::ConnectionReset
+        | io::ErrorKind::ConnectionAborted
+        | io::ErrorKind::WriteZero => FrameError::Closed,
+        _ => FrameError::Io(err),
+    }
+}
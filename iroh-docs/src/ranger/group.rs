@@ -0,0 +1,307 @@
+//! Reconciling one store with several remotes at the same time.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+use super::{
+    EpochReport, Fingerprint, Message, RangeEntry, Store, SyncConfig, SyncError, SyncSession,
+    SyncStats,
+};
+
+/// Identifies a remote of a [`ReconcileGroup`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct RemoteId(usize);
+
+/// Statistics of a remote of a [`ReconcileGroup`].
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct RemoteStats {
+    /// The statistics of the session with the remote.
+    pub sync: SyncStats,
+    /// Number of values requested from the remote.
+    pub values_requested: usize,
+    /// Number of values the remote offered that were not requested from it, because they were
+    /// requested from another remote before, or were stored meanwhile.
+    pub values_deduplicated: usize,
+}
+
+/// The keys and fingerprints a session reported missing, see [`SyncSession::with_lazy_values`].
+type Missing<K> = Arc<Mutex<Vec<(K, Fingerprint)>>>;
+
+struct Remote<E: RangeEntry> {
+    session: SyncSession<E>,
+    missing: Missing<E::Key>,
+    /// Value requests for this remote, made while processing messages of other remotes.
+    outbox: VecDeque<Message<E>>,
+    values_requested: usize,
+    values_deduplicated: usize,
+}
+
+/// Reconciles one store with several remotes at the same time, and fetches each missing value
+/// from only one of them.
+///
+/// The group owns the store, and a session for each remote, which transfers values lazily, see
+/// [`SyncSession::with_lazy_values`]. The values a remote offers are only requested if the store
+/// does not have them already, and they were not requested from another remote before. A value
+/// that is requested from one remote is requested from another remote that offered it, once the
+/// first remote is removed with [`ReconcileGroup::remove_remote`] without having delivered it.
+///
+/// Values are only deduplicated if the remotes transfer values lazily as well. Remotes without
+/// lazy values send the entries of range items right away.
+pub struct ReconcileGroup<E: RangeEntry, S> {
+    store: S,
+    config: SyncConfig,
+    remotes: BTreeMap<RemoteId, Remote<E>>,
+    next_id: usize,
+    /// The keys whose values were requested and not received yet, with the fingerprint and the
+    /// remote they were requested from.
+    in_flight: BTreeMap<E::Key, (Fingerprint, RemoteId)>,
+    /// The remotes that offered values for keys in flight, in the order they offered them.
+    waiting: BTreeMap<E::Key, Vec<(RemoteId, Fingerprint)>>,
+}
+
+impl<E: RangeEntry, S: std::fmt::Debug> std::fmt::Debug for ReconcileGroup<E, S> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ReconcileGroup")
+            .field("store", &self.store)
+            .field("config", &self.config)
+            .field("remotes", &self.remotes.len())
+            .field("in_flight", &self.in_flight.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl<E: RangeEntry, S: Store<E>> ReconcileGroup<E, S> {
+    /// Create a group for `store`, whose sessions use `config`.
+    pub fn new(store: S, config: SyncConfig) -> Self {
+        Self {
+            store,
+            config,
+            remotes: BTreeMap::new(),
+            next_id: 0,
+            in_flight: BTreeMap::new(),
+            waiting: BTreeMap::new(),
+        }
+    }
+
+    /// Get the store.
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    /// Get the store mutably.
+    pub fn store_mut(&mut self) -> &mut S {
+        &mut self.store
+    }
+
+    /// Returns the store.
+    pub fn into_store(self) -> S {
+        self.store
+    }
+
+    /// Add a remote, with a new session with the configuration of the group.
+    pub fn add_remote(&mut self) -> RemoteId
+    where
+        E::Key: Send,
+    {
+        self.add_remote_with(SyncSession::new(self.config))
+    }
+
+    /// Add a remote, with `session`, e.g. to set a validator or limits for the remote.
+    ///
+    /// The callback set with [`SyncSession::with_lazy_values`] is replaced by the group.
+    pub fn add_remote_with(&mut self, session: SyncSession<E>) -> RemoteId
+    where
+        E::Key: Send,
+    {
+        let missing: Missing<E::Key> = Default::default();
+        let session = session.with_lazy_values({
+            let missing = missing.clone();
+            move |key, fingerprint| missing.lock().unwrap().push((key.clone(), fingerprint))
+        });
+        let id = RemoteId(self.next_id);
+        self.next_id += 1;
+        self.remotes.insert(
+            id,
+            Remote {
+                session,
+                missing,
+                outbox: VecDeque::new(),
+                values_requested: 0,
+                values_deduplicated: 0,
+            },
+        );
+        id
+    }
+
+    /// Remove a remote, and return its statistics, or `None` if there is no such remote.
+    ///
+    /// The values that were requested from the remote and not received are requested from the
+    /// next remote that offered them, see [`ReconcileGroup::poll_message`].
+    pub fn remove_remote(
+        &mut self,
+        remote: RemoteId,
+    ) -> Result<Option<RemoteStats>, SyncError<S::Error>> {
+        let Some(removed) = self.remotes.remove(&remote) else {
+            return Ok(None);
+        };
+        for offers in self.waiting.values_mut() {
+            offers.retain(|(id, _)| *id != remote);
+        }
+        let keys: Vec<_> = self
+            .in_flight
+            .iter()
+            .filter(|(_, (_, id))| *id == remote)
+            .map(|(key, _)| key.clone())
+            .collect();
+        self.release(keys)?;
+        Ok(Some(RemoteStats {
+            sync: removed.session.stats().clone(),
+            values_requested: removed.values_requested,
+            values_deduplicated: removed.values_deduplicated,
+        }))
+    }
+
+    /// Get the session of a remote.
+    pub fn session(&self, remote: RemoteId) -> Option<&SyncSession<E>> {
+        self.remotes.get(&remote).map(|remote| &remote.session)
+    }
+
+    /// Get the statistics of a remote.
+    pub fn stats(&self, remote: RemoteId) -> Option<RemoteStats> {
+        let remote = self.remotes.get(&remote)?;
+        Some(RemoteStats {
+            sync: remote.session.stats().clone(),
+            values_requested: remote.values_requested,
+            values_deduplicated: remote.values_deduplicated,
+        })
+    }
+
+    /// Checks whether the store was written to after ranges were settled with a remote, see
+    /// [`SyncSession::epoch_report`], or returns `None` if there is no such remote.
+    ///
+    /// The writes of the sessions with the other remotes of the group count as writes outside of
+    /// the session with this remote.
+    pub fn epoch_report(&mut self, remote: RemoteId) -> Option<EpochReport<E::Key>> {
+        let remote = self.remotes.get_mut(&remote)?;
+        Some(remote.session.epoch_report(&self.store))
+    }
+
+    /// Get the keys whose values were requested and not received yet, with the remote they
+    /// were requested from.
+    pub fn in_flight(&self) -> impl Iterator<Item = (&E::Key, RemoteId)> {
+        self.in_flight
+            .iter()
+            .map(|(key, (_, remote))| (key, *remote))
+    }
+
+    /// Returns the initial message for a remote, see [`SyncSession::initial_message`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if there is no such remote.
+    pub fn initial_message(&mut self, remote: RemoteId) -> Result<Message<E>, SyncError<S::Error>> {
+        let state = self.remotes.get_mut(&remote).expect("unknown remote");
+        state.session.initial_message(&mut self.store)
+    }
+
+    /// Processes a message from a remote, and returns the messages to send to it in response.
+    ///
+    /// The response is followed by a request for the values the remote offered that are
+    /// neither in the store, nor requested from another remote. Requests for other remotes are
+    /// queued, see [`ReconcileGroup::poll_message`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if there is no such remote.
+    pub fn process(
+        &mut self,
+        remote: RemoteId,
+        message: Message<E>,
+    ) -> Result<Vec<Message<E>>, SyncError<S::Error>> {
+        let received: Vec<_> = message
+            .values()
+            .map(|(entry, _)| entry.key().clone())
+            .filter(|key| matches!(self.in_flight.get(key), Some((_, id)) if *id == remote))
+            .collect();
+        let state = self.remotes.get_mut(&remote).expect("unknown remote");
+        let mut messages = Vec::new();
+        messages.extend(state.session.process(&mut self.store, message)?);
+        while let Some(message) = state.session.poll_pending_message() {
+            messages.push(message);
+        }
+        let missing = std::mem::take(&mut *state.missing.lock().unwrap());
+        self.release(received)?;
+
+        let mut requested = Vec::new();
+        let mut deduplicated = 0;
+        for (key, fingerprint) in missing {
+            if self
+                .has_value(&key, fingerprint)
+                .map_err(SyncError::Store)?
+            {
+                deduplicated += 1;
+            } else if self.in_flight.contains_key(&key) {
+                self.waiting
+                    .entry(key)
+                    .or_default()
+                    .push((remote, fingerprint));
+                deduplicated += 1;
+            } else {
+                self.in_flight.insert(key.clone(), (fingerprint, remote));
+                requested.push(key);
+            }
+        }
+        let state = self.remotes.get_mut(&remote).expect("checked above");
+        state.values_deduplicated += deduplicated;
+        state.values_requested += requested.len();
+        if !requested.is_empty() {
+            messages.push(state.session.value_request_message(requested));
+        }
+        messages.extend(state.outbox.drain(..));
+        Ok(messages)
+    }
+
+    /// Takes the next queued message for a remote, see [`ReconcileGroup::process`].
+    pub fn poll_message(&mut self, remote: RemoteId) -> Option<Message<E>> {
+        self.remotes.get_mut(&remote)?.outbox.pop_front()
+    }
+
+    /// Returns `true` if the store has the entry for `key` with `fingerprint`.
+    fn has_value(&mut self, key: &E::Key, fingerprint: Fingerprint) -> Result<bool, S::Error> {
+        let local = self.store.get(key)?;
+        Ok(local.is_some_and(|entry| entry.as_fingerprint() == fingerprint))
+    }
+
+    /// Requests the values for `keys`, which are no longer in flight, from the next remote
+    /// that offered a value the store does not have.
+    fn release(&mut self, keys: Vec<E::Key>) -> Result<(), SyncError<S::Error>> {
+        for key in keys {
+            self.in_flight.remove(&key);
+            let Some(mut offers) = self.waiting.remove(&key) else {
+                continue;
+            };
+            while !offers.is_empty() {
+                let (remote, fingerprint) = offers.remove(0);
+                if self
+                    .has_value(&key, fingerprint)
+                    .map_err(SyncError::Store)?
+                {
+                    continue;
+                }
+                let Some(state) = self.remotes.get_mut(&remote) else {
+                    continue;
+                };
+                let request = state.session.value_request_message(vec![key.clone()]);
+                state.outbox.push_back(request);
+                state.values_requested += 1;
+                state.values_deduplicated -= 1;
+                self.in_flight.insert(key.clone(), (fingerprint, remote));
+                if !offers.is_empty() {
+                    self.waiting.insert(key, offers);
+                }
+                break;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,335 @@
+//! A store wrapper that counts the operations performed on the inner store.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use super::{validators::EntryLookup, Chunk, ChunkIterator, Fingerprint, Range, RangeEntry, Store};
+
+/// Snapshot of the counters of an [`InstrumentedStore`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct StoreStats {
+    /// Number of calls to [`Store::get_first`].
+    pub get_first: u64,
+    /// Number of calls to [`Store::first_and_last`].
+    pub first_and_last: u64,
+    /// Number of calls to [`Store::get`].
+    pub get: u64,
+    /// Number of calls to [`Store::entry_fingerprint`].
+    pub entry_fingerprint: u64,
+    /// Number of calls to [`Store::len`].
+    pub len: u64,
+    /// Number of calls to [`Store::is_empty`].
+    pub is_empty: u64,
+    /// Number of calls to [`Store::get_fingerprint`].
+    pub get_fingerprint: u64,
+    /// Number of calls to [`Store::entry_put`].
+    pub entry_put: u64,
+    /// Number of calls to [`Store::get_range`].
+    pub get_range: u64,
+    /// Number of calls to [`Store::get_range_chunked`].
+    pub get_range_chunked: u64,
+    /// Number of calls to [`Store::get_range_len`].
+    pub get_range_len: u64,
+    /// Number of calls to [`Store::prefixed_by`].
+    pub prefixed_by: u64,
+    /// Number of calls to [`Store::prefixes_of`].
+    pub prefixes_of: u64,
+    /// Number of calls to [`Store::all`].
+    pub all: u64,
+    /// Number of calls to [`Store::entry_remove`].
+    pub entry_remove: u64,
+    /// Number of calls to [`Store::remove_prefix_filtered`].
+    pub remove_prefix_filtered: u64,
+    /// Number of entries yielded from the iterators returned by the store.
+    pub entries_iterated: u64,
+    /// Number of entries successfully written with [`Store::entry_put`].
+    pub entries_written: u64,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    get_first: AtomicU64,
+    first_and_last: AtomicU64,
+    get: AtomicU64,
+    entry_fingerprint: AtomicU64,
+    len: AtomicU64,
+    is_empty: AtomicU64,
+    get_fingerprint: AtomicU64,
+    entry_put: AtomicU64,
+    get_range: AtomicU64,
+    get_range_chunked: AtomicU64,
+    get_range_len: AtomicU64,
+    prefixed_by: AtomicU64,
+    prefixes_of: AtomicU64,
+    all: AtomicU64,
+    entry_remove: AtomicU64,
+    remove_prefix_filtered: AtomicU64,
+    entries_iterated: AtomicU64,
+    entries_written: AtomicU64,
+}
+
+impl Counters {
+    fn counters(&self) -> [&AtomicU64; 18] {
+        [
+            &self.get_first,
+            &self.first_and_last,
+            &self.get,
+            &self.entry_fingerprint,
+            &self.len,
+            &self.is_empty,
+            &self.get_fingerprint,
+            &self.entry_put,
+            &self.get_range,
+            &self.get_range_chunked,
+            &self.get_range_len,
+            &self.prefixed_by,
+            &self.prefixes_of,
+            &self.all,
+            &self.entry_remove,
+            &self.remove_prefix_filtered,
+            &self.entries_iterated,
+            &self.entries_written,
+        ]
+    }
+
+    fn stats(&self) -> StoreStats {
+        let load = |c: &AtomicU64| c.load(Ordering::Relaxed);
+        StoreStats {
+            get_first: load(&self.get_first),
+            first_and_last: load(&self.first_and_last),
+            get: load(&self.get),
+            entry_fingerprint: load(&self.entry_fingerprint),
+            len: load(&self.len),
+            is_empty: load(&self.is_empty),
+            get_fingerprint: load(&self.get_fingerprint),
+            entry_put: load(&self.entry_put),
+            get_range: load(&self.get_range),
+            get_range_chunked: load(&self.get_range_chunked),
+            get_range_len: load(&self.get_range_len),
+            prefixed_by: load(&self.prefixed_by),
+            prefixes_of: load(&self.prefixes_of),
+            all: load(&self.all),
+            entry_remove: load(&self.entry_remove),
+            remove_prefix_filtered: load(&self.remove_prefix_filtered),
+            entries_iterated: load(&self.entries_iterated),
+            entries_written: load(&self.entries_written),
+        }
+    }
+}
+
+fn inc(counter: &AtomicU64) {
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+/// A [`Store`] that delegates to an inner store and counts the calls made to it.
+///
+/// This is useful to profile store backends, and to assert on the efficiency of the sync
+/// protocol in tests. The counters can be read with [`InstrumentedStore::stats`].
+#[derive(Debug, Default)]
+pub struct InstrumentedStore<S> {
+    store: S,
+    counters: Counters,
+}
+
+impl<S> InstrumentedStore<S> {
+    /// Wrap a store.
+    pub fn new(store: S) -> Self {
+        Self {
+            store,
+            counters: Counters::default(),
+        }
+    }
+
+    /// Get the current values of the counters.
+    pub fn stats(&self) -> StoreStats {
+        self.counters.stats()
+    }
+
+    /// Reset all counters to zero.
+    pub fn reset(&self) {
+        for counter in self.counters.counters() {
+            counter.store(0, Ordering::Relaxed);
+        }
+    }
+
+    /// Get a reference to the inner store.
+    pub fn inner(&self) -> &S {
+        &self.store
+    }
+
+    /// Unwrap the inner store.
+    pub fn into_inner(self) -> S {
+        self.store
+    }
+}
+
+/// Iterator returned from an [`InstrumentedStore`], counting the entries it yields.
+#[derive(Debug)]
+pub struct InstrumentedIterator<'a, I> {
+    iter: I,
+    entries_iterated: &'a AtomicU64,
+}
+
+impl<'a, T, Err, I: Iterator<Item = Result<T, Err>>> Iterator for InstrumentedIterator<'a, I> {
+    type Item = Result<T, Err>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let next = self.iter.next();
+        if let Some(Ok(_)) = next {
+            inc(self.entries_iterated);
+        }
+        next
+    }
+}
+
+/// Chunk iterator returned from an [`InstrumentedStore`], counting the entries it yields.
+#[derive(Debug)]
+pub struct InstrumentedChunks<'a, I> {
+    iter: I,
+    entries_iterated: &'a AtomicU64,
+}
+
+impl<'a, E: RangeEntry, Err, I: Iterator<Item = Result<Chunk<E>, Err>>> Iterator
+    for InstrumentedChunks<'a, I>
+{
+    type Item = Result<Chunk<E>, Err>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let next = self.iter.next();
+        if let Some(Ok(chunk)) = &next {
+            self.entries_iterated
+                .fetch_add(chunk.entries.len() as u64, Ordering::Relaxed);
+        }
+        next
+    }
+}
+
+impl<E: RangeEntry, S: EntryLookup<E>> EntryLookup<E> for InstrumentedStore<S> {
+    /// Counted as a call to [`Store::get`].
+    fn lookup(&self, key: &E::Key) -> Option<&E> {
+        inc(&self.counters.get);
+        self.store.lookup(key)
+    }
+}
+
+impl<E: RangeEntry, S: Store<E>> Store<E> for InstrumentedStore<S> {
+    type Error = S::Error;
+    type RangeIterator<'a> = InstrumentedIterator<'a, S::RangeIterator<'a>> where S: 'a, E: 'a;
+    type ParentIterator<'a> = InstrumentedIterator<'a, S::ParentIterator<'a>> where S: 'a, E: 'a;
+
+    fn epoch(&self) -> u64 {
+        self.store.epoch()
+    }
+
+    fn get_first(&mut self) -> Result<E::Key, Self::Error> {
+        inc(&self.counters.get_first);
+        self.store.get_first()
+    }
+
+    fn first_and_last(&mut self) -> Result<Option<(E::Key, E::Key)>, Self::Error> {
+        inc(&self.counters.first_and_last);
+        self.store.first_and_last()
+    }
+
+    fn get(&mut self, key: &E::Key) -> Result<Option<E>, Self::Error> {
+        inc(&self.counters.get);
+        self.store.get(key)
+    }
+
+    fn entry_fingerprint(&mut self, key: &E::Key) -> Result<Option<Fingerprint>, Self::Error> {
+        inc(&self.counters.entry_fingerprint);
+        self.store.entry_fingerprint(key)
+    }
+
+    fn len(&mut self) -> Result<usize, Self::Error> {
+        inc(&self.counters.len);
+        self.store.len()
+    }
+
+    fn is_empty(&mut self) -> Result<bool, Self::Error> {
+        inc(&self.counters.is_empty);
+        self.store.is_empty()
+    }
+
+    fn get_fingerprint(&mut self, range: &Range<E::Key>) -> Result<Fingerprint, Self::Error> {
+        inc(&self.counters.get_fingerprint);
+        self.store.get_fingerprint(range)
+    }
+
+    fn entry_put(&mut self, entry: E) -> Result<(), Self::Error> {
+        inc(&self.counters.entry_put);
+        self.store.entry_put(entry)?;
+        inc(&self.counters.entries_written);
+        Ok(())
+    }
+
+    fn get_range(&mut self, range: Range<E::Key>) -> Result<Self::RangeIterator<'_>, Self::Error> {
+        inc(&self.counters.get_range);
+        let iter = self.store.get_range(range)?;
+        Ok(InstrumentedIterator {
+            iter,
+            entries_iterated: &self.counters.entries_iterated,
+        })
+    }
+
+    fn get_range_chunked<'a>(
+        &'a mut self,
+        range: Range<E::Key>,
+        chunk_size: usize,
+    ) -> Result<ChunkIterator<'a, E, Self::Error>, Self::Error>
+    where
+        E: 'a,
+    {
+        inc(&self.counters.get_range_chunked);
+        let iter = self.store.get_range_chunked(range, chunk_size)?;
+        Ok(Box::new(InstrumentedChunks {
+            iter,
+            entries_iterated: &self.counters.entries_iterated,
+        }))
+    }
+
+    fn get_range_len(&mut self, range: Range<E::Key>) -> Result<usize, Self::Error> {
+        inc(&self.counters.get_range_len);
+        self.store.get_range_len(range)
+    }
+
+    fn prefixed_by(&mut self, prefix: &E::Key) -> Result<Self::RangeIterator<'_>, Self::Error> {
+        inc(&self.counters.prefixed_by);
+        let iter = self.store.prefixed_by(prefix)?;
+        Ok(InstrumentedIterator {
+            iter,
+            entries_iterated: &self.counters.entries_iterated,
+        })
+    }
+
+    fn prefixes_of(&mut self, key: &E::Key) -> Result<Self::ParentIterator<'_>, Self::Error> {
+        inc(&self.counters.prefixes_of);
+        let iter = self.store.prefixes_of(key)?;
+        Ok(InstrumentedIterator {
+            iter,
+            entries_iterated: &self.counters.entries_iterated,
+        })
+    }
+
+    fn all(&mut self) -> Result<Self::RangeIterator<'_>, Self::Error> {
+        inc(&self.counters.all);
+        let iter = self.store.all()?;
+        Ok(InstrumentedIterator {
+            iter,
+            entries_iterated: &self.counters.entries_iterated,
+        })
+    }
+
+    fn entry_remove(&mut self, key: &E::Key) -> Result<Option<E>, Self::Error> {
+        inc(&self.counters.entry_remove);
+        self.store.entry_remove(key)
+    }
+
+    fn remove_prefix_filtered(
+        &mut self,
+        prefix: &E::Key,
+        predicate: impl Fn(&E::Value) -> bool,
+    ) -> Result<usize, Self::Error> {
+        inc(&self.counters.remove_prefix_filtered);
+        self.store.remove_prefix_filtered(prefix, predicate)
+    }
+}
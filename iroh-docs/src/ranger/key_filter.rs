@@ -0,0 +1,90 @@
+//! Sessions that only sync a selection of keys, see [`SessionFilter`].
+
+use std::sync::Arc;
+
+use super::{EntryFilter, FilteredStore, RangeEntry};
+
+type MatchesFn<E> = Arc<dyn Fn(&<E as RangeEntry>::Key) -> bool + Send + Sync>;
+
+/// Decides which keys a session syncs, e.g. to sync one of several collections that share a
+/// store.
+///
+/// Entries whose keys do not match are neither sent to nor accepted from the remote:
+/// - a store wrapped with [`SessionFilter::store`] withholds them from the sync, including the
+///   fingerprints, so two stores that only differ in such entries are in sync;
+/// - a session with [`SyncSession::with_filter`] rejects received entries whose keys do not
+///   match, and does not answer requests for them.
+///
+/// Unlike a range, see [`Store::initial_message_for_range`], the selected keys do not have to
+/// be contiguous. Both sides should use the same filter, so a remote that sends entries whose
+/// keys do not match does not follow the protocol. In strict mode, see
+/// [`SessionFilter::strict`], such messages are rejected with
+/// [`ProtocolError::KeyOutsideFilter`].
+///
+/// [`SyncSession::with_filter`]: super::SyncSession::with_filter
+/// [`Store::initial_message_for_range`]: super::Store::initial_message_for_range
+/// [`ProtocolError::KeyOutsideFilter`]: super::ProtocolError::KeyOutsideFilter
+pub struct SessionFilter<E: RangeEntry> {
+    matches: MatchesFn<E>,
+    strict: bool,
+}
+
+/// A store whose entries with keys that do not match a filter are withheld from the sync, see
+/// [`SessionFilter::store`].
+pub type KeyFilteredStore<S, E> = FilteredStore<S, SessionFilter<E>>;
+
+impl<E: RangeEntry> Clone for SessionFilter<E> {
+    fn clone(&self) -> Self {
+        Self {
+            matches: self.matches.clone(),
+            strict: self.strict,
+        }
+    }
+}
+
+impl<E: RangeEntry> std::fmt::Debug for SessionFilter<E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SessionFilter")
+            .field("strict", &self.strict)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<E: RangeEntry> SessionFilter<E> {
+    /// Create a filter that selects the keys for which `matches` returns `true`.
+    pub fn new(matches: impl Fn(&E::Key) -> bool + Send + Sync + 'static) -> Self {
+        Self {
+            matches: Arc::new(matches),
+            strict: false,
+        }
+    }
+
+    /// Treat entries and requests of the remote for keys that do not match as a violation of
+    /// the protocol, instead of ignoring them.
+    pub fn strict(mut self) -> Self {
+        self.strict = true;
+        self
+    }
+
+    /// Returns `true` if the filter is strict, see [`SessionFilter::strict`].
+    pub fn is_strict(&self) -> bool {
+        self.strict
+    }
+
+    /// Returns `true` if `key` is selected.
+    pub fn matches(&self, key: &E::Key) -> bool {
+        (self.matches)(key)
+    }
+
+    /// Wrap `store`, so that its entries with keys that do not match are withheld from the
+    /// sync.
+    pub fn store<S>(&self, store: S) -> KeyFilteredStore<S, E> {
+        FilteredStore::new(store, self.clone())
+    }
+}
+
+impl<E: RangeEntry> EntryFilter<E> for SessionFilter<E> {
+    fn keep(&self, entry: &E) -> bool {
+        self.matches(entry.key())
+    }
+}
@@ -0,0 +1,268 @@
+//! Reconciling two stores in the same process.
+
+use std::collections::{BTreeMap, VecDeque};
+
+use crate::ContentStatus;
+
+use super::{
+    session::ValidateFn, DiffSummary, Message, MessagePart, ProtocolLimits, RangeEntry, Store,
+    SyncConfig, SyncError, SyncSession,
+};
+
+/// Options for [`sync_stores`].
+pub struct SyncOptions<E: RangeEntry> {
+    config: SyncConfig,
+    limits: ProtocolLimits,
+    page_size: Option<usize>,
+    validate_a: Option<ValidateFn<E>>,
+    validate_b: Option<ValidateFn<E>>,
+}
+
+impl<E: RangeEntry> std::fmt::Debug for SyncOptions<E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SyncOptions")
+            .field("config", &self.config)
+            .field("limits", &self.limits)
+            .field("page_size", &self.page_size)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<E: RangeEntry> Default for SyncOptions<E> {
+    fn default() -> Self {
+        Self {
+            config: SyncConfig::default(),
+            limits: ProtocolLimits::default(),
+            page_size: None,
+            validate_a: None,
+            validate_b: None,
+        }
+    }
+}
+
+impl<E: RangeEntry> SyncOptions<E> {
+    /// Set the configuration of both sessions.
+    pub fn with_config(mut self, config: SyncConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Set the limits of both sessions.
+    ///
+    /// The sync fails once either side processed [`ProtocolLimits::max_rounds`] messages.
+    pub fn with_limits(mut self, limits: ProtocolLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Split the responses of both sessions into messages of at most `page_size` parts, see
+    /// [`SyncSession::with_page_size`].
+    pub fn with_page_size(mut self, page_size: usize) -> Self {
+        self.page_size = Some(page_size);
+        self
+    }
+
+    /// Set the validator for the entries store `a` receives, see
+    /// [`SyncSession::with_validator`].
+    pub fn with_validator_a(
+        mut self,
+        validator: impl Fn(&E, ContentStatus) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.validate_a = Some(Box::new(validator));
+        self
+    }
+
+    /// Set the validator for the entries store `b` receives, see
+    /// [`SyncSession::with_validator`].
+    pub fn with_validator_b(
+        mut self,
+        validator: impl Fn(&E, ContentStatus) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.validate_b = Some(Box::new(validator));
+        self
+    }
+}
+
+/// The result of [`sync_stores`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SyncReport {
+    /// Number of entries written to store `a`, including entries that replaced an older entry.
+    pub a_inserted: usize,
+    /// Number of entries written to store `b`, including entries that replaced an older entry.
+    pub b_inserted: usize,
+    /// Number of messages sent from `a` to `b`.
+    pub rounds: usize,
+}
+
+/// Error returned from [`sync_stores`] and [`diff_stores`], for the side that failed.
+#[derive(Debug, thiserror::Error)]
+pub enum SyncStoresError<A, B> {
+    /// Processing a message with store `a` failed.
+    #[error("store a: {0}")]
+    A(SyncError<A>),
+    /// Processing a message with store `b` failed.
+    #[error("store b: {0}")]
+    B(SyncError<B>),
+}
+
+/// Reconciles two local stores, e.g. to merge a snapshot into a live store.
+///
+/// Runs the messages of a sync between a session for `a`, which sends the initial message, and
+/// a session for `b` until the sync terminates. Both stores end up with the union of their
+/// entries, except for entries that are rejected by the validators in `options`.
+pub fn sync_stores<E, A, B>(
+    a: &mut A,
+    b: &mut B,
+    options: SyncOptions<E>,
+) -> Result<SyncReport, SyncStoresError<A::Error, B::Error>>
+where
+    E: RangeEntry,
+    A: Store<E>,
+    B: Store<E>,
+{
+    let session = || {
+        let session = SyncSession::new(options.config).with_limits(options.limits);
+        match options.page_size {
+            Some(page_size) => session.with_page_size(page_size),
+            None => session,
+        }
+    };
+    let mut a_session = session();
+    let mut b_session = session();
+    a_session.set_validator(options.validate_a);
+    b_session.set_validator(options.validate_b);
+
+    let mut to_b = VecDeque::from([a_session.initial_message(a).map_err(SyncStoresError::A)?]);
+    let mut rounds = 0;
+    while !to_b.is_empty() {
+        rounds += to_b.len();
+        let to_a = deliver(&mut b_session, b, to_b).map_err(SyncStoresError::B)?;
+        to_b = deliver(&mut a_session, a, to_a).map_err(SyncStoresError::A)?;
+    }
+    let (a_stats, b_stats) = (a_session.stats(), b_session.stats());
+    Ok(SyncReport {
+        a_inserted: a_stats.entries_inserted + a_stats.entries_overwritten,
+        b_inserted: b_stats.entries_inserted + b_stats.entries_overwritten,
+        rounds,
+    })
+}
+
+/// Processes `messages` in order, and returns the messages sent in response, including the
+/// pending messages of split responses.
+///
+/// Messages with more messages following, see [`Message::has_more`], are answered together
+/// with the last message of their round.
+fn deliver<E: RangeEntry, S: Store<E>>(
+    session: &mut SyncSession<E>,
+    store: &mut S,
+    messages: VecDeque<Message<E>>,
+) -> Result<VecDeque<Message<E>>, SyncError<S::Error>> {
+    let mut replies = VecDeque::new();
+    for message in messages {
+        replies.extend(session.process(store, message)?);
+        while let Some(message) = session.poll_pending_message() {
+            replies.push_back(message);
+        }
+    }
+    Ok(replies)
+}
+
+/// The result of [`diff_stores`], with the keys in each list sorted and unique.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoreDiff<K> {
+    /// Keys of entries only store `a` has.
+    pub only_a: Vec<K>,
+    /// Keys of entries only store `b` has.
+    pub only_b: Vec<K>,
+    /// Keys of entries both stores have, with different fingerprints.
+    pub different: Vec<K>,
+}
+
+impl<K> Default for StoreDiff<K> {
+    fn default() -> Self {
+        Self {
+            only_a: Default::default(),
+            only_b: Default::default(),
+            different: Default::default(),
+        }
+    }
+}
+
+impl<K> StoreDiff<K> {
+    /// Returns `true` if the stores have the same entries.
+    pub fn is_empty(&self) -> bool {
+        self.only_a.is_empty() && self.only_b.is_empty() && self.different.is_empty()
+    }
+}
+
+/// Computes the differences between two local stores, e.g. between a live store and a
+/// snapshot, without changing either of them.
+///
+/// Runs the messages of a sync between `a`, which processes them with
+/// [`Store::process_message_dry_run`], and `b`, which rejects all entries, so only the ranges
+/// whose fingerprints differ are compared entry by entry.
+#[allow(clippy::type_complexity)]
+pub fn diff_stores<E, A, B>(
+    a: &mut A,
+    b: &mut B,
+) -> Result<StoreDiff<E::Key>, SyncStoresError<A::Error, B::Error>>
+where
+    E: RangeEntry,
+    A: Store<E>,
+    B: Store<E>,
+{
+    let config = SyncConfig::default();
+    let store_a = |err| SyncStoresError::A(SyncError::Store(err));
+    let mut summary = DiffSummary::default();
+    let mut diff = StoreDiff::default();
+    let mut next = a.initial_message().map_err(store_a)?;
+    loop {
+        let reply = b
+            .process_message_reply(
+                &config,
+                next,
+                |_, _, _| false,
+                |_, _, _| (),
+                |_, _| ContentStatus::Complete,
+            )
+            .map_err(|err| SyncStoresError::B(err.into()))?;
+        let Some(reply) = reply else {
+            break;
+        };
+        // the items of `b` hold all its entries in their ranges, see the dry run
+        for part in reply.parts() {
+            let MessagePart::RangeItem(item) = part else {
+                continue;
+            };
+            let mut b_entries = BTreeMap::new();
+            for (entry, _) in &item.values {
+                if item.range.contains(entry.key()) {
+                    b_entries.insert(entry.key().clone(), entry.as_fingerprint());
+                }
+            }
+            for entry in a.get_range(item.range.clone()).map_err(store_a)? {
+                let entry = entry.map_err(store_a)?;
+                match b_entries.remove(entry.key()) {
+                    None => diff.only_a.push(entry.key().clone()),
+                    Some(fingerprint) if fingerprint != entry.as_fingerprint() => {
+                        diff.different.push(entry.key().clone())
+                    }
+                    Some(_) => {}
+                }
+            }
+            diff.only_b.extend(b_entries.into_keys());
+        }
+        match a
+            .process_message_dry_run(&config, reply, &mut summary)
+            .map_err(|err| SyncStoresError::A(err.into()))?
+        {
+            Some(message) => next = message,
+            None => break,
+        }
+    }
+    for keys in [&mut diff.only_a, &mut diff.only_b, &mut diff.different] {
+        keys.sort();
+        keys.dedup();
+    }
+    Ok(diff)
+}
@@ -0,0 +1,456 @@
+//! Persistent store that appends all changes to a log file.
+
+use std::{
+    collections::{btree_map, BTreeMap},
+    fs::{File, OpenOptions},
+    io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    iter::{Chain, Flatten},
+    marker::PhantomData,
+    ops::Bound,
+    path::{Path, PathBuf},
+};
+
+use serde::{de::DeserializeOwned, Serialize};
+use tracing::warn;
+
+use super::{ChunkIterator, Fingerprint, Range, RangeChunks, RangeEntry, RangeKey, Store};
+
+/// Record kind of a postcard encoded entry.
+const RECORD_PUT: u8 = 0;
+/// Record kind of a postcard encoded key, whose entry was removed.
+const RECORD_REMOVE: u8 = 1;
+/// Size of the record header: kind, offset of the record as `u64` little-endian, and length of
+/// the payload as `u32` little-endian.
+const HEADER_LEN: usize = 1 + 8 + 4;
+
+/// Error returned from a [`LogStore`].
+#[derive(Debug, thiserror::Error)]
+pub enum LogStoreError {
+    /// Failed to read or write the log file.
+    #[error("io error: {0}")]
+    Io(#[from] io::Error),
+    /// Failed to encode or decode a record.
+    #[error("encoding error: {0}")]
+    Encoding(#[from] postcard::Error),
+    /// The log file contains an invalid record, or a truncated one before its end.
+    #[error("corrupt record at offset {0}")]
+    Corrupt(u64),
+}
+
+/// Result of reading a record from the log.
+enum ReadRecord {
+    /// A complete record, with its kind and payload.
+    Complete(u8, Vec<u8>),
+    /// The end of the log.
+    End,
+    /// A record that ends past the end of the log, because it was only partly written.
+    Torn,
+}
+
+/// Position and fingerprint of the latest record of an entry.
+#[derive(Debug, Clone, Copy)]
+struct IndexEntry {
+    offset: u64,
+    fingerprint: Fingerprint,
+}
+
+/// A persistent [`Store`] that appends every change to a log file.
+///
+/// Each [`Store::entry_put`] appends a record with the encoded entry, and each removal appends a
+/// tombstone record with the removed key, so the log is a complete history of all changes. An
+/// in-memory index maps each key to the offset of its latest record, together with the
+/// fingerprint of the entry. The index is rebuilt from the log when the store is opened, after
+/// which fingerprints are computed from the index alone, while entries are read lazily from the
+/// log.
+///
+/// The log grows with every change. [`LogStore::compact`] rewrites it to contain only the
+/// current entries.
+#[derive(Debug)]
+pub struct LogStore<E: RangeEntry> {
+    path: PathBuf,
+    file: File,
+    index: BTreeMap<E::Key, IndexEntry>,
+    /// Length of the log file, which is the offset of the next record.
+    end: u64,
+    /// Number of records appended since the store was opened, see [`Store::epoch`].
+    appended: u64,
+    _entry: PhantomData<fn() -> E>,
+}
+
+impl<E> LogStore<E>
+where
+    E: RangeEntry + Serialize + DeserializeOwned,
+    E::Key: Serialize + DeserializeOwned,
+{
+    /// Open the log file at `path`, creating it if it does not exist, and rebuild the index.
+    ///
+    /// A final record that was only partly written, e.g. because the process crashed while
+    /// appending it, is removed from the log. Any other invalid record is an error.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, LogStoreError> {
+        let path = path.as_ref().to_path_buf();
+        let file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&path)?;
+        let mut index = BTreeMap::new();
+        let mut reader = BufReader::new(&file);
+        let mut end = 0;
+        loop {
+            let (kind, payload) = match read_record(&mut reader, end)? {
+                ReadRecord::Complete(kind, payload) => (kind, payload),
+                ReadRecord::End => break,
+                ReadRecord::Torn => {
+                    warn!(offset = end, "truncating partly written record of log");
+                    file.set_len(end)?;
+                    file.sync_all()?;
+                    break;
+                }
+            };
+            match kind {
+                RECORD_PUT => {
+                    let entry: E = postcard::from_bytes(&payload)?;
+                    let fingerprint = entry.as_fingerprint();
+                    let index_entry = IndexEntry {
+                        offset: end,
+                        fingerprint,
+                    };
+                    index.insert(entry.key().clone(), index_entry);
+                }
+                RECORD_REMOVE => {
+                    let key: E::Key = postcard::from_bytes(&payload)?;
+                    index.remove(&key);
+                }
+                _ => return Err(LogStoreError::Corrupt(end)),
+            }
+            end += (HEADER_LEN + payload.len()) as u64;
+        }
+        Ok(Self {
+            path,
+            file,
+            index,
+            end,
+            appended: 0,
+            _entry: PhantomData,
+        })
+    }
+
+    /// Get the size of the log file in bytes.
+    pub fn log_len(&self) -> u64 {
+        self.end
+    }
+
+    /// Rewrite the log file to contain only the current entries, dropping all overwritten and
+    /// removed records.
+    pub fn compact(&mut self) -> Result<(), LogStoreError> {
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".compact");
+        let tmp_path = PathBuf::from(tmp_path);
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        let mut index = BTreeMap::new();
+        let mut end = 0;
+        for (key, index_entry) in &self.index {
+            let mut file = &self.file;
+            file.seek(SeekFrom::Start(index_entry.offset))?;
+            let ReadRecord::Complete(_, payload) = read_record(&mut file, index_entry.offset)?
+            else {
+                return Err(LogStoreError::Corrupt(index_entry.offset));
+            };
+            let offset = end;
+            end += write_record(&mut writer, RECORD_PUT, offset, &payload)?;
+            let index_entry = IndexEntry {
+                offset,
+                fingerprint: index_entry.fingerprint,
+            };
+            index.insert(key.clone(), index_entry);
+        }
+        writer
+            .into_inner()
+            .map_err(|err| err.into_error())?
+            .sync_all()?;
+        std::fs::rename(&tmp_path, &self.path)?;
+        self.file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .open(&self.path)?;
+        self.index = index;
+        self.end = end;
+        Ok(())
+    }
+
+    fn append(&mut self, kind: u8, payload: &[u8]) -> Result<u64, LogStoreError> {
+        let offset = self.end;
+        match write_record(&mut self.file, kind, offset, payload) {
+            Ok(len) => {
+                self.end += len;
+                self.appended += 1;
+                Ok(offset)
+            }
+            Err(err) => {
+                // a partly written record would shift all records appended after it, so the
+                // log is truncated back to where the record started
+                self.file.set_len(offset)?;
+                Err(err)
+            }
+        }
+    }
+
+    fn read_entry(&self, offset: u64) -> Result<E, LogStoreError> {
+        read_entry(&self.file, offset)
+    }
+}
+
+/// Write a record, and return its length.
+fn write_record(
+    mut writer: impl Write,
+    kind: u8,
+    offset: u64,
+    payload: &[u8],
+) -> Result<u64, LogStoreError> {
+    let len = u32::try_from(payload.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "record too large"))?;
+    let mut record = Vec::with_capacity(HEADER_LEN + payload.len());
+    record.push(kind);
+    record.extend_from_slice(&offset.to_le_bytes());
+    record.extend_from_slice(&len.to_le_bytes());
+    record.extend_from_slice(payload);
+    writer.write_all(&record)?;
+    Ok(record.len() as u64)
+}
+
+/// Read the record at `offset`.
+fn read_record(mut reader: impl Read, offset: u64) -> Result<ReadRecord, LogStoreError> {
+    let mut header = [0u8; HEADER_LEN];
+    let mut read = 0;
+    while read < HEADER_LEN {
+        match reader.read(&mut header[read..])? {
+            0 if read == 0 => return Ok(ReadRecord::End),
+            0 => return Ok(ReadRecord::Torn),
+            n => read += n,
+        }
+    }
+    let kind = header[0];
+    let record_offset = u64::from_le_bytes(header[1..9].try_into().expect("slice of length 8"));
+    let len = u32::from_le_bytes(header[9..].try_into().expect("slice of length 4"));
+    if record_offset != offset {
+        return Err(LogStoreError::Corrupt(offset));
+    }
+    let mut payload = vec![0u8; len as usize];
+    match reader.read_exact(&mut payload) {
+        Ok(()) => Ok(ReadRecord::Complete(kind, payload)),
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => Ok(ReadRecord::Torn),
+        Err(err) => Err(err.into()),
+    }
+}
+
+fn read_entry<E: DeserializeOwned>(mut file: &File, offset: u64) -> Result<E, LogStoreError> {
+    file.seek(SeekFrom::Start(offset))?;
+    match read_record(file, offset)? {
+        ReadRecord::Complete(RECORD_PUT, payload) => Ok(postcard::from_bytes(&payload)?),
+        _ => Err(LogStoreError::Corrupt(offset)),
+    }
+}
+
+impl<E> Store<E> for LogStore<E>
+where
+    E: RangeEntry + Serialize + DeserializeOwned,
+    E::Key: Serialize + DeserializeOwned + Default,
+{
+    type Error = LogStoreError;
+    type RangeIterator<'a> = LogIterator<'a, E>
+    where E: 'a;
+    type ParentIterator<'a> = std::vec::IntoIter<Result<E, LogStoreError>>
+    where E: 'a;
+
+    fn epoch(&self) -> u64 {
+        self.appended
+    }
+
+    fn get_first(&mut self) -> Result<E::Key, Self::Error> {
+        match self.index.first_key_value() {
+            Some((key, _)) => Ok(key.clone()),
+            None => Ok(Default::default()),
+        }
+    }
+
+    fn first_and_last(&mut self) -> Result<Option<(E::Key, E::Key)>, Self::Error> {
+        let first = self.index.first_key_value().map(|(k, _)| k.clone());
+        let last = self.index.last_key_value().map(|(k, _)| k.clone());
+        Ok(first.zip(last))
+    }
+
+    fn get(&mut self, key: &E::Key) -> Result<Option<E>, Self::Error> {
+        self.index
+            .get(key)
+            .map(|index_entry| self.read_entry(index_entry.offset))
+            .transpose()
+    }
+
+    fn len(&mut self) -> Result<usize, Self::Error> {
+        Ok(self.index.len())
+    }
+
+    fn is_empty(&mut self) -> Result<bool, Self::Error> {
+        Ok(self.index.is_empty())
+    }
+
+    fn get_fingerprint(&mut self, range: &Range<E::Key>) -> Result<Fingerprint, Self::Error> {
+        let (first, second) = index_ranges(&self.index, range, false);
+        let mut fp = Fingerprint::empty();
+        for (_, index_entry) in first.chain(second.into_iter().flatten()) {
+            fp ^= index_entry.fingerprint;
+        }
+        Ok(fp)
+    }
+
+    fn entry_put(&mut self, entry: E) -> Result<(), Self::Error> {
+        let payload = postcard::to_stdvec(&entry)?;
+        let offset = self.append(RECORD_PUT, &payload)?;
+        let index_entry = IndexEntry {
+            offset,
+            fingerprint: entry.as_fingerprint(),
+        };
+        self.index.insert(entry.key().clone(), index_entry);
+        Ok(())
+    }
+
+    fn get_range(&mut self, range: Range<E::Key>) -> Result<Self::RangeIterator<'_>, Self::Error> {
+        let (first, second) = index_ranges(&self.index, &range, false);
+        Ok(LogIterator::new(&self.file, first, second, None))
+    }
+
+    fn get_range_chunked<'a>(
+        &'a mut self,
+        range: Range<E::Key>,
+        chunk_size: usize,
+    ) -> Result<ChunkIterator<'a, E, Self::Error>, Self::Error>
+    where
+        E: 'a,
+    {
+        // chunks are yielded in range order, so for wrap-around ranges we start at x.
+        let (first, second) = index_ranges(&self.index, &range, true);
+        let iter = LogIterator::new(&self.file, first, second, None);
+        Ok(Box::new(RangeChunks::new(range, chunk_size, iter)))
+    }
+
+    fn get_range_len(&mut self, range: Range<E::Key>) -> Result<usize, Self::Error> {
+        let (first, second) = index_ranges(&self.index, &range, false);
+        Ok(first.count() + second.map_or(0, |second| second.count()))
+    }
+
+    fn prefixed_by(&mut self, prefix: &E::Key) -> Result<Self::RangeIterator<'_>, Self::Error> {
+        let iter = self.index.range::<E::Key, _>(..);
+        let prefix = Some(prefix.clone());
+        Ok(LogIterator::new(&self.file, iter, None, prefix))
+    }
+
+    fn prefixes_of(&mut self, key: &E::Key) -> Result<Self::ParentIterator<'_>, Self::Error> {
+        let res: Vec<_> = self
+            .index
+            .iter()
+            .filter(|(k, _)| k.is_prefix_of(key))
+            .map(|(_, index_entry)| self.read_entry(index_entry.offset))
+            .collect();
+        Ok(res.into_iter())
+    }
+
+    fn all(&mut self) -> Result<Self::RangeIterator<'_>, Self::Error> {
+        let iter = self.index.range::<E::Key, _>(..);
+        Ok(LogIterator::new(&self.file, iter, None, None))
+    }
+
+    fn entry_remove(&mut self, key: &E::Key) -> Result<Option<E>, Self::Error> {
+        let Some(index_entry) = self.index.get(key) else {
+            return Ok(None);
+        };
+        let entry = self.read_entry(index_entry.offset)?;
+        let payload = postcard::to_stdvec(key)?;
+        self.append(RECORD_REMOVE, &payload)?;
+        self.index.remove(key);
+        Ok(Some(entry))
+    }
+
+    fn remove_prefix_filtered(
+        &mut self,
+        prefix: &E::Key,
+        predicate: impl Fn(&E::Value) -> bool,
+    ) -> Result<usize, Self::Error> {
+        let keys = self
+            .prefixed_by(prefix)?
+            .filter_map(|entry| match entry {
+                Ok(entry) => predicate(entry.value()).then(|| Ok(entry.key().clone())),
+                Err(err) => Some(Err(err)),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        for key in &keys {
+            self.entry_remove(key)?;
+        }
+        Ok(keys.len())
+    }
+}
+
+type IndexRange<'a, K> = btree_map::Range<'a, K, IndexEntry>;
+type IndexRanges<'a, K> =
+    Chain<IndexRange<'a, K>, Flatten<std::option::IntoIter<IndexRange<'a, K>>>>;
+
+/// The index entries in `range`, as two segments in key order, or in range order if
+/// `range_order` is set.
+fn index_ranges<'a, K: RangeKey>(
+    index: &'a BTreeMap<K, IndexEntry>,
+    range: &Range<K>,
+    range_order: bool,
+) -> (IndexRange<'a, K>, Option<IndexRange<'a, K>>) {
+    let (x, y) = (Bound::Included(range.x()), Bound::Excluded(range.y()));
+    if range.x() < range.y() {
+        return (index.range::<K, _>((x, y)), None);
+    }
+    // the range wraps around (or is the full range), so we need two segments: from the start to
+    // y, and from x to the end.
+    let (start, end) = (
+        index.range::<K, _>((Bound::Unbounded, y)),
+        index.range::<K, _>((x, Bound::Unbounded)),
+    );
+    if range_order {
+        (end, Some(start))
+    } else {
+        (start, Some(end))
+    }
+}
+
+/// Iterator over the entries of a [`LogStore`], reading the entries from the log file.
+#[derive(Debug)]
+pub struct LogIterator<'a, E: RangeEntry> {
+    file: &'a File,
+    keys: IndexRanges<'a, E::Key>,
+    /// Only yield entries whose key starts with this prefix.
+    prefix: Option<E::Key>,
+    _entry: PhantomData<fn() -> E>,
+}
+
+impl<'a, E: RangeEntry> LogIterator<'a, E> {
+    fn new(
+        file: &'a File,
+        first: IndexRange<'a, E::Key>,
+        second: Option<IndexRange<'a, E::Key>>,
+        prefix: Option<E::Key>,
+    ) -> Self {
+        Self {
+            file,
+            keys: first.chain(second.into_iter().flatten()),
+            prefix,
+            _entry: PhantomData,
+        }
+    }
+}
+
+impl<'a, E: RangeEntry + DeserializeOwned> Iterator for LogIterator<'a, E> {
+    type Item = Result<E, LogStoreError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (_, index_entry) = match &self.prefix {
+            None => self.keys.next()?,
+            Some(prefix) => self.keys.find(|(key, _)| prefix.is_prefix_of(key))?,
+        };
+        Some(read_entry(self.file, index_entry.offset))
+    }
+}
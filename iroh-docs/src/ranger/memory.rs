@@ -0,0 +1,587 @@
+//! In-memory stores backed by a [`BTreeMap`].
+
+use std::{
+    collections::{btree_map, BTreeMap},
+    convert::Infallible,
+    iter::{Chain, Flatten},
+    ops::Bound,
+};
+
+use super::{
+    validators::EntryLookup, ChunkIterator, Fingerprint, Range, RangeChunks, RangeEntry, RangeKey,
+    RangeValue, Store,
+};
+
+/// A [`Store`] that keeps all entries in memory, ordered by key.
+///
+/// # Example
+///
+/// A complete sync between two peers:
+///
+/// ```
+/// use iroh_docs::{
+///     ranger::{Fingerprint, MemoryStore, RangeEntry, RangeKey, RangeValue, Store},
+///     ContentStatus,
+/// };
+///
+/// #[derive(Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord)]
+/// struct Key(String);
+///
+/// impl RangeKey for Key {
+///     fn is_prefix_of(&self, other: &Self) -> bool {
+///         other.0.starts_with(&self.0)
+///     }
+/// }
+///
+/// #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+/// struct Value(u64);
+///
+/// impl RangeValue for Value {}
+///
+/// #[derive(Debug, Clone)]
+/// struct KeyValue(Key, Value);
+///
+/// impl RangeEntry for KeyValue {
+///     type Key = Key;
+///     type Value = Value;
+///
+///     fn key(&self) -> &Key {
+///         &self.0
+///     }
+///
+///     fn value(&self) -> &Value {
+///         &self.1
+///     }
+///
+///     fn as_fingerprint(&self) -> Fingerprint {
+///         let mut hasher = blake3::Hasher::new();
+///         hasher.update(self.0 .0.as_bytes());
+///         hasher.update(&self.1 .0.to_le_bytes());
+///         Fingerprint(hasher.finalize().into())
+///     }
+/// }
+///
+/// let store = |keys: &[&str]| -> MemoryStore<KeyValue> {
+///     keys.iter()
+///         .map(|k| KeyValue(Key(k.to_string()), Value(1)))
+///         .collect()
+/// };
+/// let mut alice = store(&["ape", "eel", "fox"]);
+/// let mut bob = store(&["bee", "eel", "hog"]);
+///
+/// let config = Default::default();
+/// let mut msg = Some(alice.initial_message()?);
+/// let mut turn = 0;
+/// while let Some(m) = msg.take() {
+///     let store = if turn % 2 == 0 { &mut bob } else { &mut alice };
+///     msg = store.process_message(
+///         &config,
+///         m,
+///         |_, _, _| true,
+///         |_, _, _| (),
+///         |_, _| ContentStatus::Complete,
+///     )?
+///     .reply;
+///     turn += 1;
+/// }
+///
+/// let keys = |store: MemoryStore<KeyValue>| {
+///     store.into_entries().map(|e| e.0 .0).collect::<Vec<_>>()
+/// };
+/// assert_eq!(keys(alice), ["ape", "bee", "eel", "fox", "hog"]);
+/// assert_eq!(keys(bob), ["ape", "bee", "eel", "fox", "hog"]);
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+#[derive(Clone)]
+pub struct MemoryStore<E: RangeEntry> {
+    entries: BTreeMap<E::Key, E>,
+    /// Computes the fingerprints of ranges, see [`MemoryStore::with_parallel_fingerprints`].
+    fingerprint: FingerprintFn<E>,
+}
+
+impl<E: RangeEntry> Default for MemoryStore<E> {
+    fn default() -> Self {
+        Self {
+            entries: Default::default(),
+            fingerprint: sequential_fingerprint,
+        }
+    }
+}
+
+impl<E: RangeEntry> std::fmt::Debug for MemoryStore<E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MemoryStore")
+            .field("entries", &self.entries)
+            .finish()
+    }
+}
+
+/// Stores are equal if they hold the same entries.
+impl<E: RangeEntry + PartialEq> PartialEq for MemoryStore<E> {
+    fn eq(&self, other: &Self) -> bool {
+        self.entries == other.entries
+    }
+}
+
+impl<E: RangeEntry> MemoryStore<E> {
+    /// Create a new, empty store.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Consume the store and return its entries, ordered by key.
+    pub fn into_entries(self) -> impl Iterator<Item = E> {
+        self.entries.into_values()
+    }
+}
+
+impl<E: RangeEntry> EntryLookup<E> for MemoryStore<E> {
+    fn lookup(&self, key: &E::Key) -> Option<&E> {
+        self.entries.get(key)
+    }
+}
+
+impl<E: RangeEntry> FromIterator<E> for MemoryStore<E> {
+    /// Create a store from entries.
+    ///
+    /// Entries are inserted with [`Store::entry_put`] semantics, so later entries replace
+    /// earlier entries with the same key.
+    fn from_iter<T: IntoIterator<Item = E>>(iter: T) -> Self {
+        let entries = iter
+            .into_iter()
+            .map(|entry| (entry.key().clone(), entry))
+            .collect();
+        Self {
+            entries,
+            ..Default::default()
+        }
+    }
+}
+
+/// The bounds of a segment of a [`Range`] in a [`BTreeMap`].
+type Bounds<'a, K> = (Bound<&'a K>, Bound<&'a K>);
+
+/// Returns the XOR of the fingerprints of the entries of a map within a pair of bounds.
+type FingerprintFn<E> =
+    fn(&BTreeMap<<E as RangeEntry>::Key, E>, Bounds<'_, <E as RangeEntry>::Key>) -> Fingerprint;
+
+/// Returns the XOR of the fingerprints of `entries`.
+fn xor_fingerprints<'a, E: RangeEntry + 'a>(entries: impl Iterator<Item = &'a E>) -> Fingerprint {
+    entries.fold(Fingerprint([0u8; 32]), |mut fp, entry| {
+        fp ^= entry.as_fingerprint();
+        fp
+    })
+}
+
+/// Returns the XOR of the fingerprints of the entries within `bounds`, on the calling thread.
+fn sequential_fingerprint<E: RangeEntry>(
+    entries: &BTreeMap<E::Key, E>,
+    bounds: Bounds<'_, E::Key>,
+) -> Fingerprint {
+    xor_fingerprints(entries.range::<E::Key, _>(bounds).map(|(_, entry)| entry))
+}
+
+/// Number of entries of a range whose fingerprints are computed on the calling thread, before
+/// the rest is computed in parallel.
+#[cfg(feature = "parallel")]
+const PARALLEL_MIN_ENTRIES: usize = 1 << 14;
+
+/// Number of entries whose fingerprints are computed in one task of the thread pool.
+#[cfg(feature = "parallel")]
+const PARALLEL_CHUNK_SIZE: usize = 1 << 12;
+
+/// Returns the XOR of the fingerprints of the entries within `bounds`, where all but the first
+/// `min_entries` are split into chunks of `chunk_size` entries, whose fingerprints are computed
+/// in parallel.
+///
+/// XOR is associative and commutative, so the result does not depend on how the entries are
+/// split.
+#[cfg(feature = "parallel")]
+pub(super) fn parallel_fingerprint<E>(
+    entries: &BTreeMap<E::Key, E>,
+    bounds: Bounds<'_, E::Key>,
+    min_entries: usize,
+    chunk_size: usize,
+) -> Fingerprint
+where
+    E: RangeEntry + Sync,
+    E::Key: Sync,
+{
+    use rayon::prelude::*;
+
+    let mut iter = entries.range::<E::Key, _>(bounds);
+    let mut fp = xor_fingerprints(iter.by_ref().take(min_entries).map(|(_, entry)| entry));
+    // split the rest at every `chunk_size`th key. walking the tree is cheap compared to hashing,
+    // and each task then reads its chunk with its own `BTreeMap::range`.
+    let starts: Vec<&E::Key> = iter
+        .step_by(chunk_size.max(1))
+        .map(|(key, _)| key)
+        .collect();
+    fp ^= starts
+        .par_iter()
+        .enumerate()
+        .map(|(i, start)| {
+            let end = match starts.get(i + 1) {
+                Some(next) => Bound::Excluded(*next),
+                None => bounds.1,
+            };
+            sequential_fingerprint(entries, (Bound::Included(*start), end))
+        })
+        .reduce(
+            || Fingerprint([0u8; 32]),
+            |mut a, b| {
+                a ^= b;
+                a
+            },
+        );
+    fp
+}
+
+#[cfg(feature = "parallel")]
+impl<E: RangeEntry + Sync> MemoryStore<E>
+where
+    E::Key: Sync,
+{
+    /// Compute the fingerprints of large ranges on the [rayon] thread pool.
+    ///
+    /// The first 16k entries of a range are folded on the calling thread, so small ranges pay no
+    /// scheduling overhead. The rest is split into chunks of 4k entries. The fingerprints are the
+    /// same as those computed on a single thread.
+    ///
+    /// [rayon]: https://docs.rs/rayon
+    pub fn with_parallel_fingerprints(mut self) -> Self {
+        self.fingerprint = |entries, bounds| {
+            parallel_fingerprint(entries, bounds, PARALLEL_MIN_ENTRIES, PARALLEL_CHUNK_SIZE)
+        };
+        self
+    }
+}
+
+impl<E: RangeEntry> Store<E> for MemoryStore<E>
+where
+    E::Key: Default,
+{
+    type Error = Infallible;
+    type RangeIterator<'a> = MemoryRangeIterator<'a, E>
+    where E: 'a;
+    type ParentIterator<'a> = std::vec::IntoIter<Result<E, Infallible>>
+    where E: 'a;
+
+    fn get_first(&mut self) -> Result<E::Key, Self::Error> {
+        match self.entries.first_key_value() {
+            Some((key, _)) => Ok(key.clone()),
+            None => Ok(Default::default()),
+        }
+    }
+
+    fn first_and_last(&mut self) -> Result<Option<(E::Key, E::Key)>, Self::Error> {
+        let first = self.entries.first_key_value().map(|(k, _)| k.clone());
+        let last = self.entries.last_key_value().map(|(k, _)| k.clone());
+        Ok(first.zip(last))
+    }
+
+    fn get(&mut self, key: &E::Key) -> Result<Option<E>, Self::Error> {
+        Ok(self.entries.get(key).cloned())
+    }
+
+    fn len(&mut self) -> Result<usize, Self::Error> {
+        Ok(self.entries.len())
+    }
+
+    fn is_empty(&mut self) -> Result<bool, Self::Error> {
+        Ok(self.entries.is_empty())
+    }
+
+    fn get_fingerprint(&mut self, range: &Range<E::Key>) -> Result<Fingerprint, Self::Error> {
+        let (x, y) = (Bound::Included(range.x()), Bound::Excluded(range.y()));
+        let mut fp = Fingerprint::empty();
+        match range.x().cmp(range.y()) {
+            std::cmp::Ordering::Less => fp ^= (self.fingerprint)(&self.entries, (x, y)),
+            // the range wraps around (or is the full range), see `get_range`.
+            _ => {
+                fp ^= (self.fingerprint)(&self.entries, (Bound::Unbounded, y));
+                fp ^= (self.fingerprint)(&self.entries, (x, Bound::Unbounded));
+            }
+        }
+        Ok(fp)
+    }
+
+    fn entry_put(&mut self, entry: E) -> Result<(), Self::Error> {
+        self.entries.insert(entry.key().clone(), entry);
+        Ok(())
+    }
+
+    fn get_range(&mut self, range: Range<E::Key>) -> Result<Self::RangeIterator<'_>, Self::Error> {
+        let (x, y) = (Bound::Included(range.x()), Bound::Excluded(range.y()));
+        let (first, second) = match range.x().cmp(range.y()) {
+            std::cmp::Ordering::Less => (self.entries.range::<E::Key, _>((x, y)), None),
+            // the range wraps around (or is the full range), so we need two segments: from the
+            // start to y, and from x to the end.
+            _ => (
+                self.entries.range::<E::Key, _>((Bound::Unbounded, y)),
+                Some(self.entries.range::<E::Key, _>((x, Bound::Unbounded))),
+            ),
+        };
+        Ok(MemoryRangeIterator::new(first, second, None))
+    }
+
+    fn get_range_chunked<'a>(
+        &'a mut self,
+        range: Range<E::Key>,
+        chunk_size: usize,
+    ) -> Result<ChunkIterator<'a, E, Self::Error>, Self::Error>
+    where
+        E: 'a,
+    {
+        let (x, y) = (Bound::Included(range.x()), Bound::Excluded(range.y()));
+        // chunks are yielded in range order, so for wrap-around ranges we start at x.
+        let (first, second) = match range.x().cmp(range.y()) {
+            std::cmp::Ordering::Less => (self.entries.range::<E::Key, _>((x, y)), None),
+            _ => (
+                self.entries.range::<E::Key, _>((x, Bound::Unbounded)),
+                Some(self.entries.range::<E::Key, _>((Bound::Unbounded, y))),
+            ),
+        };
+        let iter = MemoryRangeIterator::new(first, second, None);
+        Ok(Box::new(RangeChunks::new(range, chunk_size, iter)))
+    }
+
+    fn prefixed_by(&mut self, prefix: &E::Key) -> Result<Self::RangeIterator<'_>, Self::Error> {
+        let iter = self.entries.range::<E::Key, _>(..);
+        Ok(MemoryRangeIterator::new(iter, None, Some(prefix.clone())))
+    }
+
+    fn prefixes_of(&mut self, key: &E::Key) -> Result<Self::ParentIterator<'_>, Self::Error> {
+        let res: Vec<_> = self
+            .entries
+            .iter()
+            .filter(|(k, _)| k.is_prefix_of(key))
+            .map(|(_, entry)| Ok(entry.clone()))
+            .collect();
+        Ok(res.into_iter())
+    }
+
+    fn all(&mut self) -> Result<Self::RangeIterator<'_>, Self::Error> {
+        let iter = self.entries.range::<E::Key, _>(..);
+        Ok(MemoryRangeIterator::new(iter, None, None))
+    }
+
+    fn entry_remove(&mut self, key: &E::Key) -> Result<Option<E>, Self::Error> {
+        Ok(self.entries.remove(key))
+    }
+
+    fn remove_prefix_filtered(
+        &mut self,
+        prefix: &E::Key,
+        predicate: impl Fn(&E::Value) -> bool,
+    ) -> Result<usize, Self::Error> {
+        let old_len = self.entries.len();
+        self.entries.retain(|key, entry| {
+            let remove = prefix.is_prefix_of(key) && predicate(entry.value());
+            !remove
+        });
+        Ok(old_len - self.entries.len())
+    }
+}
+
+/// Iterator over the entries of a [`MemoryStore`].
+#[derive(Debug)]
+pub struct MemoryRangeIterator<'a, E: RangeEntry> {
+    #[allow(clippy::type_complexity)]
+    iter: Chain<
+        btree_map::Range<'a, E::Key, E>,
+        Flatten<std::option::IntoIter<btree_map::Range<'a, E::Key, E>>>,
+    >,
+    /// Only yield entries whose key starts with this prefix.
+    prefix: Option<E::Key>,
+}
+
+impl<'a, E: RangeEntry> MemoryRangeIterator<'a, E> {
+    fn new(
+        first: btree_map::Range<'a, E::Key, E>,
+        second: Option<btree_map::Range<'a, E::Key, E>>,
+        prefix: Option<E::Key>,
+    ) -> Self {
+        Self {
+            iter: first.chain(second.into_iter().flatten()),
+            prefix,
+        }
+    }
+}
+
+impl<'a, E: RangeEntry> Iterator for MemoryRangeIterator<'a, E> {
+    type Item = Result<E, Infallible>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match &self.prefix {
+            None => self.iter.next().map(|(_, entry)| Ok(entry.clone())),
+            Some(prefix) => self
+                .iter
+                .find(|(key, _)| prefix.is_prefix_of(key))
+                .map(|(_, entry)| Ok(entry.clone())),
+        }
+    }
+}
+
+/// A plain [`BTreeMap`] can be used as a store for `(K, V)` entries.
+///
+/// Regular ranges are answered with [`BTreeMap::range`], so they only visit the entries in the
+/// range.
+impl<K, V> Store<(K, V)> for BTreeMap<K, V>
+where
+    K: RangeKey + Default,
+    V: RangeValue,
+    (K, V): RangeEntry<Key = K, Value = V>,
+{
+    type Error = Infallible;
+    type RangeIterator<'a> = BTreeMapRangeIterator<'a, K, V>
+    where K: 'a, V: 'a;
+    type ParentIterator<'a> = std::vec::IntoIter<Result<(K, V), Infallible>>
+    where K: 'a, V: 'a;
+
+    fn get_first(&mut self) -> Result<K, Self::Error> {
+        match self.first_key_value() {
+            Some((key, _)) => Ok(key.clone()),
+            None => Ok(Default::default()),
+        }
+    }
+
+    fn first_and_last(&mut self) -> Result<Option<(K, K)>, Self::Error> {
+        let first = self.first_key_value().map(|(k, _)| k.clone());
+        let last = self.last_key_value().map(|(k, _)| k.clone());
+        Ok(first.zip(last))
+    }
+
+    fn get(&mut self, key: &K) -> Result<Option<(K, V)>, Self::Error> {
+        Ok(BTreeMap::get(self, key).map(|value| (key.clone(), value.clone())))
+    }
+
+    fn len(&mut self) -> Result<usize, Self::Error> {
+        Ok(BTreeMap::len(self))
+    }
+
+    fn is_empty(&mut self) -> Result<bool, Self::Error> {
+        Ok(BTreeMap::is_empty(self))
+    }
+
+    fn get_fingerprint(&mut self, range: &Range<K>) -> Result<Fingerprint, Self::Error> {
+        let mut fp = Fingerprint::empty();
+        for entry in self.get_range(range.clone())? {
+            fp ^= entry?.as_fingerprint();
+        }
+        Ok(fp)
+    }
+
+    fn entry_put(&mut self, (key, value): (K, V)) -> Result<(), Self::Error> {
+        self.insert(key, value);
+        Ok(())
+    }
+
+    fn get_range(&mut self, range: Range<K>) -> Result<Self::RangeIterator<'_>, Self::Error> {
+        let (x, y) = (Bound::Included(range.x()), Bound::Excluded(range.y()));
+        let (first, second) = match range.x().cmp(range.y()) {
+            std::cmp::Ordering::Less => (self.range::<K, _>((x, y)), None),
+            // the range wraps around (or is the full range), so we need two segments: from the
+            // start to y, and from x to the end.
+            _ => (
+                self.range::<K, _>((Bound::Unbounded, y)),
+                Some(self.range::<K, _>((x, Bound::Unbounded))),
+            ),
+        };
+        Ok(BTreeMapRangeIterator::new(first, second, None))
+    }
+
+    fn get_range_chunked<'a>(
+        &'a mut self,
+        range: Range<K>,
+        chunk_size: usize,
+    ) -> Result<ChunkIterator<'a, (K, V), Self::Error>, Self::Error>
+    where
+        (K, V): 'a,
+    {
+        let (x, y) = (Bound::Included(range.x()), Bound::Excluded(range.y()));
+        // chunks are yielded in range order, so for wrap-around ranges we start at x.
+        let (first, second) = match range.x().cmp(range.y()) {
+            std::cmp::Ordering::Less => (self.range::<K, _>((x, y)), None),
+            _ => (
+                self.range::<K, _>((x, Bound::Unbounded)),
+                Some(self.range::<K, _>((Bound::Unbounded, y))),
+            ),
+        };
+        let iter = BTreeMapRangeIterator::new(first, second, None);
+        Ok(Box::new(RangeChunks::new(range, chunk_size, iter)))
+    }
+
+    fn prefixed_by(&mut self, prefix: &K) -> Result<Self::RangeIterator<'_>, Self::Error> {
+        let iter = self.range::<K, _>(..);
+        Ok(BTreeMapRangeIterator::new(iter, None, Some(prefix.clone())))
+    }
+
+    fn prefixes_of(&mut self, key: &K) -> Result<Self::ParentIterator<'_>, Self::Error> {
+        let res: Vec<_> = self
+            .iter()
+            .filter(|(k, _)| k.is_prefix_of(key))
+            .map(|(k, v)| Ok((k.clone(), v.clone())))
+            .collect();
+        Ok(res.into_iter())
+    }
+
+    fn all(&mut self) -> Result<Self::RangeIterator<'_>, Self::Error> {
+        let iter = self.range::<K, _>(..);
+        Ok(BTreeMapRangeIterator::new(iter, None, None))
+    }
+
+    fn entry_remove(&mut self, key: &K) -> Result<Option<(K, V)>, Self::Error> {
+        Ok(self.remove_entry(key))
+    }
+
+    fn remove_prefix_filtered(
+        &mut self,
+        prefix: &K,
+        predicate: impl Fn(&V) -> bool,
+    ) -> Result<usize, Self::Error> {
+        let old_len = BTreeMap::len(self);
+        self.retain(|key, value| {
+            let remove = prefix.is_prefix_of(key) && predicate(value);
+            !remove
+        });
+        Ok(old_len - BTreeMap::len(self))
+    }
+}
+
+/// Iterator over the entries of a [`BTreeMap`] used as a [`Store`].
+#[derive(Debug)]
+pub struct BTreeMapRangeIterator<'a, K, V> {
+    #[allow(clippy::type_complexity)]
+    iter: Chain<
+        btree_map::Range<'a, K, V>,
+        Flatten<std::option::IntoIter<btree_map::Range<'a, K, V>>>,
+    >,
+    /// Only yield entries whose key starts with this prefix.
+    prefix: Option<K>,
+}
+
+impl<'a, K, V> BTreeMapRangeIterator<'a, K, V> {
+    fn new(
+        first: btree_map::Range<'a, K, V>,
+        second: Option<btree_map::Range<'a, K, V>>,
+        prefix: Option<K>,
+    ) -> Self {
+        Self {
+            iter: first.chain(second.into_iter().flatten()),
+            prefix,
+        }
+    }
+}
+
+impl<'a, K: RangeKey, V: Clone> Iterator for BTreeMapRangeIterator<'a, K, V> {
+    type Item = Result<(K, V), Infallible>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (key, value) = match &self.prefix {
+            None => self.iter.next()?,
+            Some(prefix) => self.iter.find(|(key, _)| prefix.is_prefix_of(key))?,
+        };
+        Some(Ok((key.clone(), value.clone())))
+    }
+}
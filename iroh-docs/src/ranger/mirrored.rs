@@ -0,0 +1,227 @@
+//! A store that applies all writes to a secondary store as well.
+
+use super::{validators::EntryLookup, ChunkIterator, Fingerprint, Range, RangeEntry, Store};
+
+/// What a [`MirroredStore`] does when a write to the secondary store fails.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum SecondaryErrorPolicy {
+    /// Return the error from the write.
+    ///
+    /// The write has already been applied to the primary store at this point, so the stores
+    /// diverge until the write is retried.
+    #[default]
+    Propagate,
+    /// Record the error, and report the write as successful.
+    ///
+    /// Recorded errors can be retrieved with [`MirroredStore::take_secondary_errors`].
+    Record,
+}
+
+/// A [`Store`] that reads from a primary store, and applies all writes to both the primary and a
+/// secondary store.
+///
+/// This allows to run a new storage backend in shadow mode, e.g. to migrate from one backend to
+/// another: all sync traffic is applied to the secondary store, while the primary store stays
+/// authoritative. Whether the two stores still agree can be checked with
+/// [`MirroredStore::check_consistency`].
+///
+/// Writes are applied to the primary store first. If that fails, the secondary store is not
+/// touched. If the write to the secondary store fails, the [`SecondaryErrorPolicy`] decides
+/// whether the error is returned or recorded.
+#[derive(Debug)]
+pub struct MirroredStore<P, S> {
+    primary: P,
+    secondary: S,
+    policy: SecondaryErrorPolicy,
+    secondary_errors: Vec<anyhow::Error>,
+}
+
+impl<P, S> MirroredStore<P, S> {
+    /// Create a new mirrored store.
+    pub fn new(primary: P, secondary: S, policy: SecondaryErrorPolicy) -> Self {
+        Self {
+            primary,
+            secondary,
+            policy,
+            secondary_errors: Vec::new(),
+        }
+    }
+
+    /// Get a reference to the primary store.
+    pub fn primary(&self) -> &P {
+        &self.primary
+    }
+
+    /// Get a reference to the secondary store.
+    pub fn secondary(&self) -> &S {
+        &self.secondary
+    }
+
+    /// Unwrap the primary and the secondary store.
+    pub fn into_inner(self) -> (P, S) {
+        (self.primary, self.secondary)
+    }
+
+    /// Take the errors of failed writes to the secondary store that were recorded so far.
+    ///
+    /// Errors are only recorded with [`SecondaryErrorPolicy::Record`].
+    pub fn take_secondary_errors(&mut self) -> Vec<anyhow::Error> {
+        std::mem::take(&mut self.secondary_errors)
+    }
+
+    /// Handle the result of a write to the secondary store according to the policy.
+    fn secondary_result<T>(
+        &mut self,
+        res: Result<T, impl Into<anyhow::Error>>,
+    ) -> anyhow::Result<()> {
+        match (res, self.policy) {
+            (Ok(_), _) => Ok(()),
+            (Err(err), SecondaryErrorPolicy::Propagate) => Err(err.into()),
+            (Err(err), SecondaryErrorPolicy::Record) => {
+                self.secondary_errors.push(err.into());
+                Ok(())
+            }
+        }
+    }
+
+    /// Check whether the primary and the secondary store contain the same entries, by comparing
+    /// the fingerprints of the full range.
+    pub fn check_consistency<E>(&mut self) -> anyhow::Result<bool>
+    where
+        E: RangeEntry,
+        P: Store<E>,
+        S: Store<E>,
+    {
+        let first = self.primary.get_first().map_err(Into::into)?;
+        let range = Range::new(first.clone(), first);
+        let primary = self.primary.get_fingerprint(&range).map_err(Into::into)?;
+        let secondary = self.secondary.get_fingerprint(&range).map_err(Into::into)?;
+        Ok(primary == secondary)
+    }
+}
+
+impl<E: RangeEntry, P: EntryLookup<E>, S> EntryLookup<E> for MirroredStore<P, S> {
+    fn lookup(&self, key: &E::Key) -> Option<&E> {
+        self.primary.lookup(key)
+    }
+}
+
+impl<E: RangeEntry, P: Store<E>, S: Store<E>> Store<E> for MirroredStore<P, S> {
+    type Error = anyhow::Error;
+    type RangeIterator<'a> = MirroredIterator<P::RangeIterator<'a>>
+    where P: 'a, S: 'a, E: 'a;
+    type ParentIterator<'a> = MirroredIterator<P::ParentIterator<'a>>
+    where P: 'a, S: 'a, E: 'a;
+
+    fn epoch(&self) -> u64 {
+        self.primary.epoch()
+    }
+
+    fn get_first(&mut self) -> anyhow::Result<E::Key> {
+        self.primary.get_first().map_err(Into::into)
+    }
+
+    fn first_and_last(&mut self) -> anyhow::Result<Option<(E::Key, E::Key)>> {
+        self.primary.first_and_last().map_err(Into::into)
+    }
+
+    fn get(&mut self, key: &E::Key) -> anyhow::Result<Option<E>> {
+        self.primary.get(key).map_err(Into::into)
+    }
+
+    fn entry_fingerprint(&mut self, key: &E::Key) -> anyhow::Result<Option<Fingerprint>> {
+        self.primary.entry_fingerprint(key).map_err(Into::into)
+    }
+
+    fn len(&mut self) -> anyhow::Result<usize> {
+        self.primary.len().map_err(Into::into)
+    }
+
+    fn is_empty(&mut self) -> anyhow::Result<bool> {
+        self.primary.is_empty().map_err(Into::into)
+    }
+
+    fn get_fingerprint(&mut self, range: &Range<E::Key>) -> anyhow::Result<Fingerprint> {
+        self.primary.get_fingerprint(range).map_err(Into::into)
+    }
+
+    fn entry_put(&mut self, entry: E) -> anyhow::Result<()> {
+        self.primary.entry_put(entry.clone()).map_err(Into::into)?;
+        let res = self.secondary.entry_put(entry);
+        self.secondary_result(res)
+    }
+
+    fn get_range(&mut self, range: Range<E::Key>) -> anyhow::Result<Self::RangeIterator<'_>> {
+        let iter = self.primary.get_range(range).map_err(Into::into)?;
+        Ok(MirroredIterator(iter))
+    }
+
+    fn get_range_chunked<'a>(
+        &'a mut self,
+        range: Range<E::Key>,
+        chunk_size: usize,
+    ) -> anyhow::Result<ChunkIterator<'a, E, anyhow::Error>>
+    where
+        E: 'a,
+    {
+        let iter = self
+            .primary
+            .get_range_chunked(range, chunk_size)
+            .map_err(Into::into)?;
+        Ok(Box::new(MirroredIterator(iter)))
+    }
+
+    fn get_range_len(&mut self, range: Range<E::Key>) -> anyhow::Result<usize> {
+        self.primary.get_range_len(range).map_err(Into::into)
+    }
+
+    fn prefixed_by(&mut self, prefix: &E::Key) -> anyhow::Result<Self::RangeIterator<'_>> {
+        let iter = self.primary.prefixed_by(prefix).map_err(Into::into)?;
+        Ok(MirroredIterator(iter))
+    }
+
+    fn prefixes_of(&mut self, key: &E::Key) -> anyhow::Result<Self::ParentIterator<'_>> {
+        let iter = self.primary.prefixes_of(key).map_err(Into::into)?;
+        Ok(MirroredIterator(iter))
+    }
+
+    fn all(&mut self) -> anyhow::Result<Self::RangeIterator<'_>> {
+        let iter = self.primary.all().map_err(Into::into)?;
+        Ok(MirroredIterator(iter))
+    }
+
+    fn entry_remove(&mut self, key: &E::Key) -> anyhow::Result<Option<E>> {
+        let removed = self.primary.entry_remove(key).map_err(Into::into)?;
+        let res = self.secondary.entry_remove(key);
+        self.secondary_result(res)?;
+        Ok(removed)
+    }
+
+    fn remove_prefix_filtered(
+        &mut self,
+        prefix: &E::Key,
+        predicate: impl Fn(&E::Value) -> bool,
+    ) -> anyhow::Result<usize> {
+        let removed = self
+            .primary
+            .remove_prefix_filtered(prefix, &predicate)
+            .map_err(Into::into)?;
+        let res = self.secondary.remove_prefix_filtered(prefix, predicate);
+        self.secondary_result(res)?;
+        Ok(removed)
+    }
+}
+
+/// Iterator returned from a [`MirroredStore`], yielding the items of the primary store.
+#[derive(Debug)]
+pub struct MirroredIterator<I>(I);
+
+impl<T, Err: Into<anyhow::Error>, I: Iterator<Item = Result<T, Err>>> Iterator
+    for MirroredIterator<I>
+{
+    type Item = anyhow::Result<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next().map(|res| res.map_err(Into::into))
+    }
+}
@@ -0,0 +1,347 @@
+//! A layered store, that collects writes in a delta store on top of a read-only base store.
+
+use std::{cmp::Ordering, collections::BTreeSet, iter::Peekable};
+
+use super::{
+    validators::EntryLookup, Chunk, ChunkIterator, Fingerprint, Range, RangeChunks, RangeEntry,
+    Store,
+};
+
+/// A [`Store`] that layers a writable delta store on top of a base store.
+///
+/// Reads consult the delta first, and fall back to the base. Writes and removals only ever touch
+/// the delta, removals of entries in the base are recorded as masks. The overlay can then either
+/// be committed into the base with [`OverlayStore::commit`], or thrown away with
+/// [`OverlayStore::discard`], leaving the base untouched.
+///
+/// This allows speculative syncs without copying the base store.
+///
+/// Both stores must yield the entries of [`Store::get_range`], [`Store::prefixed_by`],
+/// [`Store::prefixes_of`] and [`Store::all`] ordered by key, which holds for all stores in this
+/// crate.
+#[derive(Debug)]
+pub struct OverlayStore<E: RangeEntry, B, D> {
+    base: B,
+    delta: D,
+    /// Keys removed from the base. A masked key is never present in the delta.
+    masked: BTreeSet<E::Key>,
+    /// Number of keys masked so far, which are writes that neither store counts in its epoch.
+    masks: u64,
+}
+
+impl<E: RangeEntry, B: Store<E>, D: Store<E>> OverlayStore<E, B, D> {
+    /// Create a new overlay over `base`, collecting writes in `delta`.
+    ///
+    /// `delta` should be empty.
+    pub fn new(base: B, delta: D) -> Self {
+        Self {
+            base,
+            delta,
+            masked: Default::default(),
+            masks: 0,
+        }
+    }
+
+    /// Get a reference to the base store.
+    pub fn base(&self) -> &B {
+        &self.base
+    }
+
+    /// Apply all changes from the delta to the base store and return it.
+    pub fn commit(mut self) -> anyhow::Result<B> {
+        for key in &self.masked {
+            self.base.entry_remove(key).map_err(Into::into)?;
+        }
+        for entry in self.delta.all().map_err(Into::into)? {
+            let entry = entry.map_err(Into::into)?;
+            self.base.entry_put(entry).map_err(Into::into)?;
+        }
+        Ok(self.base)
+    }
+
+    /// Drop all changes and return the unchanged base store.
+    pub fn discard(self) -> B {
+        self.base
+    }
+
+    /// Get the entry for `key` from the base, unless it is masked.
+    fn base_get(&mut self, key: &E::Key) -> anyhow::Result<Option<E>> {
+        if self.masked.contains(key) {
+            Ok(None)
+        } else {
+            self.base.get(key).map_err(Into::into)
+        }
+    }
+}
+
+impl<E, B, D> EntryLookup<E> for OverlayStore<E, B, D>
+where
+    E: RangeEntry,
+    B: EntryLookup<E>,
+    D: EntryLookup<E>,
+{
+    fn lookup(&self, key: &E::Key) -> Option<&E> {
+        match self.delta.lookup(key) {
+            Some(entry) => Some(entry),
+            None if self.masked.contains(key) => None,
+            None => self.base.lookup(key),
+        }
+    }
+}
+
+impl<E: RangeEntry, B: Store<E>, D: Store<E>> Store<E> for OverlayStore<E, B, D> {
+    type Error = anyhow::Error;
+    type RangeIterator<'a> = OverlayIterator<'a, E, B::RangeIterator<'a>, D::RangeIterator<'a>>
+    where B: 'a, D: 'a, E: 'a;
+    type ParentIterator<'a> = OverlayIterator<'a, E, B::ParentIterator<'a>, D::ParentIterator<'a>>
+    where B: 'a, D: 'a, E: 'a;
+
+    fn epoch(&self) -> u64 {
+        self.base
+            .epoch()
+            .wrapping_add(self.delta.epoch())
+            .wrapping_add(self.masks)
+    }
+
+    fn get_first(&mut self) -> anyhow::Result<E::Key> {
+        let first = self.all()?.next().transpose()?;
+        match first {
+            Some(entry) => Ok(entry.key().clone()),
+            // all entries in the delta are visible, so the delta is empty too
+            None => self.delta.get_first().map_err(Into::into),
+        }
+    }
+
+    fn get(&mut self, key: &E::Key) -> anyhow::Result<Option<E>> {
+        match self.delta.get(key).map_err(Into::into)? {
+            Some(entry) => Ok(Some(entry)),
+            None => self.base_get(key),
+        }
+    }
+
+    fn len(&mut self) -> anyhow::Result<usize> {
+        let mut count = 0;
+        for entry in self.all()? {
+            entry?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    fn is_empty(&mut self) -> anyhow::Result<bool> {
+        Ok(self.all()?.next().transpose()?.is_none())
+    }
+
+    fn get_fingerprint(&mut self, range: &Range<E::Key>) -> anyhow::Result<Fingerprint> {
+        let mut fingerprint = self.base.get_fingerprint(range).map_err(Into::into)?;
+        // Entries in the delta replace the base entry with the same key.
+        for entry in self.delta.get_range(range.clone()).map_err(Into::into)? {
+            let entry = entry.map_err(Into::into)?;
+            fingerprint ^= entry.as_fingerprint();
+            if let Some(base_entry) = self.base.get(entry.key()).map_err(Into::into)? {
+                fingerprint ^= base_entry.as_fingerprint();
+            }
+        }
+        // Masked entries are removed from the base fingerprint.
+        for key in self.masked.iter().filter(|key| range.contains(key)) {
+            if let Some(base_entry) = self.base.get(key).map_err(Into::into)? {
+                fingerprint ^= base_entry.as_fingerprint();
+            }
+        }
+        Ok(fingerprint)
+    }
+
+    fn entry_put(&mut self, entry: E) -> anyhow::Result<()> {
+        self.masked.remove(entry.key());
+        self.delta.entry_put(entry).map_err(Into::into)
+    }
+
+    fn get_range(&mut self, range: Range<E::Key>) -> anyhow::Result<Self::RangeIterator<'_>> {
+        let base = self.base.get_range(range.clone()).map_err(Into::into)?;
+        let delta = self.delta.get_range(range).map_err(Into::into)?;
+        Ok(OverlayIterator::new(base, delta, &self.masked, None))
+    }
+
+    fn get_range_chunked<'a>(
+        &'a mut self,
+        range: Range<E::Key>,
+        chunk_size: usize,
+    ) -> anyhow::Result<ChunkIterator<'a, E, anyhow::Error>>
+    where
+        E: 'a,
+    {
+        let base = self
+            .base
+            .get_range_chunked(range.clone(), chunk_size)
+            .map_err(Into::into)?;
+        let delta = self
+            .delta
+            .get_range_chunked(range.clone(), chunk_size)
+            .map_err(Into::into)?;
+        // The chunk iterators yield in range order, so merge accordingly.
+        let wrap_at = Some(range.x().clone());
+        let iter = OverlayIterator::new(
+            ChunkEntries::new(base),
+            ChunkEntries::new(delta),
+            &self.masked,
+            wrap_at,
+        );
+        Ok(Box::new(RangeChunks::new(range, chunk_size, iter)))
+    }
+
+    fn prefixed_by(&mut self, prefix: &E::Key) -> anyhow::Result<Self::RangeIterator<'_>> {
+        let base = self.base.prefixed_by(prefix).map_err(Into::into)?;
+        let delta = self.delta.prefixed_by(prefix).map_err(Into::into)?;
+        Ok(OverlayIterator::new(base, delta, &self.masked, None))
+    }
+
+    fn prefixes_of(&mut self, key: &E::Key) -> anyhow::Result<Self::ParentIterator<'_>> {
+        let base = self.base.prefixes_of(key).map_err(Into::into)?;
+        let delta = self.delta.prefixes_of(key).map_err(Into::into)?;
+        Ok(OverlayIterator::new(base, delta, &self.masked, None))
+    }
+
+    fn all(&mut self) -> anyhow::Result<Self::RangeIterator<'_>> {
+        let base = self.base.all().map_err(Into::into)?;
+        let delta = self.delta.all().map_err(Into::into)?;
+        Ok(OverlayIterator::new(base, delta, &self.masked, None))
+    }
+
+    fn entry_remove(&mut self, key: &E::Key) -> anyhow::Result<Option<E>> {
+        let removed = self.delta.entry_remove(key).map_err(Into::into)?;
+        let base_entry = self.base_get(key)?;
+        if base_entry.is_some() && self.masked.insert(key.clone()) {
+            self.masks += 1;
+        }
+        Ok(removed.or(base_entry))
+    }
+
+    fn remove_prefix_filtered(
+        &mut self,
+        prefix: &E::Key,
+        predicate: impl Fn(&E::Value) -> bool,
+    ) -> anyhow::Result<usize> {
+        let keys = self
+            .prefixed_by(prefix)?
+            .filter_map(|entry| match entry {
+                Ok(entry) => predicate(entry.value()).then(|| Ok(entry.key().clone())),
+                Err(err) => Some(Err(err)),
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        for key in &keys {
+            self.entry_remove(key)?;
+        }
+        Ok(keys.len())
+    }
+}
+
+/// Iterator over the entries of an [`OverlayStore`].
+///
+/// Merges the sorted entries of the base and the delta store. Entries from the delta replace
+/// entries from the base with the same key, and masked entries from the base are skipped.
+pub struct OverlayIterator<'a, E: RangeEntry, B: Iterator, D: Iterator> {
+    base: Peekable<B>,
+    delta: Peekable<D>,
+    masked: &'a BTreeSet<E::Key>,
+    /// If set, keys smaller than this key sort after all other keys, to merge in range order.
+    wrap_at: Option<E::Key>,
+}
+
+impl<'a, E: RangeEntry, B: Iterator, D: Iterator> std::fmt::Debug for OverlayIterator<'a, E, B, D> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("OverlayIterator")
+            .field("masked", &self.masked)
+            .field("wrap_at", &self.wrap_at)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<'a, E: RangeEntry, B: Iterator, D: Iterator> OverlayIterator<'a, E, B, D> {
+    fn new(base: B, delta: D, masked: &'a BTreeSet<E::Key>, wrap_at: Option<E::Key>) -> Self {
+        Self {
+            base: base.peekable(),
+            delta: delta.peekable(),
+            masked,
+            wrap_at,
+        }
+    }
+}
+
+impl<'a, E, B, D, BErr, DErr> Iterator for OverlayIterator<'a, E, B, D>
+where
+    E: RangeEntry,
+    B: Iterator<Item = Result<E, BErr>>,
+    D: Iterator<Item = Result<E, DErr>>,
+    BErr: Into<anyhow::Error>,
+    DErr: Into<anyhow::Error>,
+{
+    type Item = anyhow::Result<E>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            // Errors are yielded as soon as they are seen.
+            let ord = match (self.base.peek(), self.delta.peek()) {
+                (None, None) => return None,
+                (Some(Err(_)), _) | (Some(Ok(_)), None) => Ordering::Less,
+                (_, Some(Err(_))) | (None, Some(Ok(_))) => Ordering::Greater,
+                (Some(Ok(base)), Some(Ok(delta))) => {
+                    cmp_keys(self.wrap_at.as_ref(), base.key(), delta.key())
+                }
+            };
+            match ord {
+                Ordering::Less => match self.base.next()? {
+                    Ok(entry) if self.masked.contains(entry.key()) => continue,
+                    res => return Some(res.map_err(Into::into)),
+                },
+                Ordering::Greater => return self.delta.next().map(|res| res.map_err(Into::into)),
+                Ordering::Equal => {
+                    // the entry in the delta replaces the entry in the base
+                    self.base.next();
+                    return self.delta.next().map(|res| res.map_err(Into::into));
+                }
+            }
+        }
+    }
+}
+
+/// Compare two keys, with keys smaller than `wrap_at` sorting after all other keys.
+pub(super) fn cmp_keys<K: Ord>(wrap_at: Option<&K>, a: &K, b: &K) -> Ordering {
+    match wrap_at {
+        None => a.cmp(b),
+        Some(x) => (a < x).cmp(&(b < x)).then_with(|| a.cmp(b)),
+    }
+}
+
+/// Iterator over the entries of the chunks from [`Store::get_range_chunked`].
+#[derive(Debug)]
+pub struct ChunkEntries<E, I> {
+    iter: I,
+    current: std::vec::IntoIter<E>,
+}
+
+impl<E, I> ChunkEntries<E, I> {
+    pub(super) fn new(iter: I) -> Self {
+        Self {
+            iter,
+            current: Vec::new().into_iter(),
+        }
+    }
+}
+
+impl<E: RangeEntry, I: Iterator<Item = Result<Chunk<E>, Err>>, Err> Iterator
+    for ChunkEntries<E, I>
+{
+    type Item = Result<E, Err>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(entry) = self.current.next() {
+                return Some(Ok(entry));
+            }
+            match self.iter.next()? {
+                Ok(chunk) => self.current = chunk.entries.into_iter(),
+                Err(err) => return Some(Err(err)),
+            }
+        }
+    }
+}
@@ -0,0 +1,485 @@
+//! Persistent store backed by [`redb`].
+
+use std::{
+    iter::{Chain, Flatten},
+    marker::PhantomData,
+    ops::Bound,
+    path::Path,
+};
+
+use redb::{Database, ReadableTable, Table, TableDefinition};
+use serde::{de::DeserializeOwned, Serialize};
+
+use super::{ChunkIterator, Fingerprint, Range, RangeChunks, RangeEntry, RangeKey, Store};
+
+/// Table: Entries
+/// Key:   `&[u8]` # The key of the entry
+/// Value: `&[u8]` # Postcard encoded entry
+const ENTRIES_TABLE: TableDefinition<&[u8], &[u8]> = TableDefinition::new("ranger-entries-1");
+
+/// Table: Fingerprints
+/// Key:   `&[u8]`     # The key of the entry
+/// Value: `[u8; 32]`  # The fingerprint of the entry
+const FINGERPRINTS_TABLE: TableDefinition<&[u8], &[u8; 32]> =
+    TableDefinition::new("ranger-fingerprints-1");
+
+/// Table: Metadata
+/// Key:   `&str`
+/// Value: `&[u8]`
+///
+/// Contains the fingerprint of all entries at [`META_FINGERPRINT`], and the number of entries as
+/// `u64` little-endian at [`META_LEN`]. Both are updated in the same transaction as the entries.
+const META_TABLE: TableDefinition<&str, &[u8]> = TableDefinition::new("ranger-meta-1");
+const META_FINGERPRINT: &str = "fingerprint";
+const META_LEN: &str = "len";
+
+/// Error returned from a [`RedbStore`].
+#[derive(Debug, thiserror::Error)]
+pub enum RedbStoreError {
+    /// Failed to access the database.
+    #[error("database error: {0}")]
+    Redb(Box<redb::Error>),
+    /// Failed to encode or decode an entry.
+    #[error("encoding error: {0}")]
+    Encoding(#[from] postcard::Error),
+    /// The metadata table is corrupt.
+    #[error("invalid metadata")]
+    InvalidMetadata,
+}
+
+impl From<redb::DatabaseError> for RedbStoreError {
+    fn from(err: redb::DatabaseError) -> Self {
+        Self::Redb(Box::new(err.into()))
+    }
+}
+
+impl From<redb::TransactionError> for RedbStoreError {
+    fn from(err: redb::TransactionError) -> Self {
+        Self::Redb(Box::new(err.into()))
+    }
+}
+
+impl From<redb::TableError> for RedbStoreError {
+    fn from(err: redb::TableError) -> Self {
+        Self::Redb(Box::new(err.into()))
+    }
+}
+
+impl From<redb::StorageError> for RedbStoreError {
+    fn from(err: redb::StorageError) -> Self {
+        Self::Redb(Box::new(err.into()))
+    }
+}
+
+impl From<redb::CommitError> for RedbStoreError {
+    fn from(err: redb::CommitError) -> Self {
+        Self::Redb(Box::new(err.into()))
+    }
+}
+
+/// A persistent [`Store`] backed by a [`redb`] database.
+///
+/// Entries are stored postcard encoded, ordered by the bytes of their key, so the byte order of
+/// [`RangeEntry::Key`] must match its [`Ord`] implementation. The fingerprint of each entry is
+/// kept in a separate table, together with the fingerprint and number of all entries, which are
+/// maintained incrementally on every write. Each write is committed in its own transaction.
+pub struct RedbStore<E> {
+    db: Database,
+    _entry: PhantomData<fn() -> E>,
+}
+
+impl<E> std::fmt::Debug for RedbStore<E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RedbStore").finish_non_exhaustive()
+    }
+}
+
+impl<E> RedbStore<E> {
+    /// Create or open a store at the given path.
+    pub fn persistent(path: impl AsRef<Path>) -> Result<Self, RedbStoreError> {
+        let db = Database::create(path)?;
+        Self::new(db)
+    }
+
+    /// Create a new in-memory store.
+    pub fn memory() -> Result<Self, RedbStoreError> {
+        let db = Database::builder().create_with_backend(redb::backends::InMemoryBackend::new())?;
+        Self::new(db)
+    }
+
+    fn new(db: Database) -> Result<Self, RedbStoreError> {
+        let tx = db.begin_write()?;
+        {
+            tx.open_table(ENTRIES_TABLE)?;
+            tx.open_table(FINGERPRINTS_TABLE)?;
+            let mut meta = tx.open_table(META_TABLE)?;
+            if meta.get(META_FINGERPRINT)?.is_none() {
+                meta.insert(META_FINGERPRINT, &Fingerprint::empty().0[..])?;
+                meta.insert(META_LEN, &0u64.to_le_bytes()[..])?;
+            }
+        }
+        tx.commit()?;
+        Ok(Self {
+            db,
+            _entry: PhantomData,
+        })
+    }
+
+    fn read_meta(&self) -> Result<Meta, RedbStoreError> {
+        let tx = self.db.begin_read()?;
+        let meta = tx.open_table(META_TABLE)?;
+        Meta::read(&meta)
+    }
+}
+
+/// The fingerprint and number of all entries.
+struct Meta {
+    fingerprint: Fingerprint,
+    len: u64,
+}
+
+impl Meta {
+    fn read(
+        meta: &impl ReadableTable<&'static str, &'static [u8]>,
+    ) -> Result<Self, RedbStoreError> {
+        let fingerprint = meta
+            .get(META_FINGERPRINT)?
+            .ok_or(RedbStoreError::InvalidMetadata)?;
+        let fingerprint: [u8; 32] = fingerprint
+            .value()
+            .try_into()
+            .map_err(|_| RedbStoreError::InvalidMetadata)?;
+        let len = meta.get(META_LEN)?.ok_or(RedbStoreError::InvalidMetadata)?;
+        let len: [u8; 8] = len
+            .value()
+            .try_into()
+            .map_err(|_| RedbStoreError::InvalidMetadata)?;
+        Ok(Self {
+            fingerprint: Fingerprint(fingerprint),
+            len: u64::from_le_bytes(len),
+        })
+    }
+}
+
+/// The tables of a write transaction.
+struct WriteTables<'tx> {
+    entries: Table<'tx, &'static [u8], &'static [u8]>,
+    fingerprints: Table<'tx, &'static [u8], &'static [u8; 32]>,
+    meta: Table<'tx, &'static str, &'static [u8]>,
+    state: Meta,
+}
+
+impl<'tx> WriteTables<'tx> {
+    fn new(tx: &'tx redb::WriteTransaction) -> Result<Self, RedbStoreError> {
+        let entries = tx.open_table(ENTRIES_TABLE)?;
+        let fingerprints = tx.open_table(FINGERPRINTS_TABLE)?;
+        let meta = tx.open_table(META_TABLE)?;
+        let state = Meta::read(&meta)?;
+        Ok(Self {
+            entries,
+            fingerprints,
+            meta,
+            state,
+        })
+    }
+
+    fn put(
+        &mut self,
+        key: &[u8],
+        value: &[u8],
+        fingerprint: Fingerprint,
+    ) -> Result<(), RedbStoreError> {
+        let old = self
+            .fingerprints
+            .insert(key, &fingerprint.0)?
+            .map(|old| Fingerprint(*old.value()));
+        match old {
+            Some(old) => self.state.fingerprint ^= old,
+            None => self.state.len += 1,
+        }
+        self.state.fingerprint ^= fingerprint;
+        self.entries.insert(key, value)?;
+        Ok(())
+    }
+
+    fn remove(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>, RedbStoreError> {
+        let old = self
+            .fingerprints
+            .remove(key)?
+            .map(|old| Fingerprint(*old.value()));
+        if let Some(old) = old {
+            self.state.fingerprint ^= old;
+            self.state.len -= 1;
+        }
+        let value = self
+            .entries
+            .remove(key)?
+            .map(|value| value.value().to_vec());
+        Ok(value)
+    }
+
+    fn finish(mut self) -> Result<(), RedbStoreError> {
+        self.meta
+            .insert(META_FINGERPRINT, &self.state.fingerprint.0[..])?;
+        self.meta
+            .insert(META_LEN, &self.state.len.to_le_bytes()[..])?;
+        Ok(())
+    }
+}
+
+/// Bounds of a scan over the tables.
+type KeyBounds<'a> = (Bound<&'a [u8]>, Bound<&'a [u8]>);
+
+/// Translate a range into at most two scans, in key order.
+fn key_ranges<K: AsRef<[u8]> + Ord>(range: &Range<K>) -> [Option<KeyBounds<'_>>; 2] {
+    let (x, y) = (range.x().as_ref(), range.y().as_ref());
+    if range.x() < range.y() {
+        [Some((Bound::Included(x), Bound::Excluded(y))), None]
+    } else {
+        // the range wraps around (or is the full range), so we need two scans: from the start
+        // to y, and from x to the end.
+        [
+            Some((Bound::Unbounded, Bound::Excluded(y))),
+            Some((Bound::Included(x), Bound::Unbounded)),
+        ]
+    }
+}
+
+impl<E> Store<E> for RedbStore<E>
+where
+    E: RangeEntry + Serialize + DeserializeOwned,
+    E::Key: AsRef<[u8]> + Default,
+{
+    type Error = RedbStoreError;
+    type RangeIterator<'a> = RedbIterator<E>
+    where E: 'a;
+    type ParentIterator<'a> = std::vec::IntoIter<Result<E, RedbStoreError>>
+    where E: 'a;
+
+    fn get_first(&mut self) -> Result<E::Key, Self::Error> {
+        let tx = self.db.begin_read()?;
+        let entries = tx.open_table(ENTRIES_TABLE)?;
+        let first = entries.first()?;
+        match first {
+            Some((_, value)) => Ok(decode::<E>(value.value())?.key().clone()),
+            None => Ok(Default::default()),
+        }
+    }
+
+    fn first_and_last(&mut self) -> Result<Option<(E::Key, E::Key)>, Self::Error> {
+        let tx = self.db.begin_read()?;
+        let entries = tx.open_table(ENTRIES_TABLE)?;
+        let (Some((_, first)), Some((_, last))) = (entries.first()?, entries.last()?) else {
+            return Ok(None);
+        };
+        let first = decode::<E>(first.value())?.key().clone();
+        let last = decode::<E>(last.value())?.key().clone();
+        Ok(Some((first, last)))
+    }
+
+    fn get(&mut self, key: &E::Key) -> Result<Option<E>, Self::Error> {
+        let tx = self.db.begin_read()?;
+        let entries = tx.open_table(ENTRIES_TABLE)?;
+        match entries.get(key.as_ref())? {
+            Some(value) => Ok(Some(decode(value.value())?)),
+            None => Ok(None),
+        }
+    }
+
+    fn len(&mut self) -> Result<usize, Self::Error> {
+        Ok(self.read_meta()?.len as usize)
+    }
+
+    fn is_empty(&mut self) -> Result<bool, Self::Error> {
+        Ok(self.len()? == 0)
+    }
+
+    fn get_fingerprint(&mut self, range: &Range<E::Key>) -> Result<Fingerprint, Self::Error> {
+        if range.is_all() {
+            return Ok(self.read_meta()?.fingerprint);
+        }
+        let tx = self.db.begin_read()?;
+        let fingerprints = tx.open_table(FINGERPRINTS_TABLE)?;
+        let mut fingerprint = Fingerprint::empty();
+        for bounds in key_ranges(range).into_iter().flatten() {
+            for item in fingerprints.range::<&[u8]>(bounds)? {
+                let (_, value) = item?;
+                fingerprint ^= Fingerprint(*value.value());
+            }
+        }
+        Ok(fingerprint)
+    }
+
+    fn entry_put(&mut self, entry: E) -> Result<(), Self::Error> {
+        let value = postcard::to_stdvec(&entry)?;
+        let tx = self.db.begin_write()?;
+        {
+            let mut tables = WriteTables::new(&tx)?;
+            tables.put(entry.key().as_ref(), &value, entry.as_fingerprint())?;
+            tables.finish()?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn get_range(&mut self, range: Range<E::Key>) -> Result<Self::RangeIterator<'_>, Self::Error> {
+        let tx = self.db.begin_read()?;
+        let entries = tx.open_table(ENTRIES_TABLE)?;
+        let [first, second] = key_ranges(&range);
+        let first = entries.range::<&[u8]>(first.expect("first scan is always set"))?;
+        let second = second
+            .map(|bounds| entries.range::<&[u8]>(bounds))
+            .transpose()?;
+        Ok(RedbIterator::new(first, second, None))
+    }
+
+    fn get_range_chunked<'a>(
+        &'a mut self,
+        range: Range<E::Key>,
+        chunk_size: usize,
+    ) -> Result<ChunkIterator<'a, E, Self::Error>, Self::Error>
+    where
+        E: 'a,
+    {
+        let tx = self.db.begin_read()?;
+        let entries = tx.open_table(ENTRIES_TABLE)?;
+        // chunks are yielded in range order, so for wrap-around ranges we start at x.
+        let [first, second] = match key_ranges(&range) {
+            [first, None] => [first, None],
+            [first, second] => [second, first],
+        };
+        let first = entries.range::<&[u8]>(first.expect("first scan is always set"))?;
+        let second = second
+            .map(|bounds| entries.range::<&[u8]>(bounds))
+            .transpose()?;
+        let iter = RedbIterator::new(first, second, None);
+        Ok(Box::new(RangeChunks::new(range, chunk_size, iter)))
+    }
+
+    fn get_range_len(&mut self, range: Range<E::Key>) -> Result<usize, Self::Error> {
+        if range.is_all() {
+            return self.len();
+        }
+        let tx = self.db.begin_read()?;
+        let fingerprints = tx.open_table(FINGERPRINTS_TABLE)?;
+        let mut len = 0;
+        for bounds in key_ranges(&range).into_iter().flatten() {
+            for item in fingerprints.range::<&[u8]>(bounds)? {
+                item?;
+                len += 1;
+            }
+        }
+        Ok(len)
+    }
+
+    fn prefixed_by(&mut self, prefix: &E::Key) -> Result<Self::RangeIterator<'_>, Self::Error> {
+        let tx = self.db.begin_read()?;
+        let entries = tx.open_table(ENTRIES_TABLE)?;
+        let iter = entries.range::<&[u8]>(..)?;
+        Ok(RedbIterator::new(iter, None, Some(prefix.clone())))
+    }
+
+    fn prefixes_of(&mut self, key: &E::Key) -> Result<Self::ParentIterator<'_>, Self::Error> {
+        let res: Vec<_> = self
+            .all()?
+            .filter(|entry| match entry {
+                Ok(entry) => entry.key().is_prefix_of(key),
+                Err(_) => true,
+            })
+            .collect();
+        Ok(res.into_iter())
+    }
+
+    fn all(&mut self) -> Result<Self::RangeIterator<'_>, Self::Error> {
+        let tx = self.db.begin_read()?;
+        let entries = tx.open_table(ENTRIES_TABLE)?;
+        let iter = entries.range::<&[u8]>(..)?;
+        Ok(RedbIterator::new(iter, None, None))
+    }
+
+    fn entry_remove(&mut self, key: &E::Key) -> Result<Option<E>, Self::Error> {
+        let tx = self.db.begin_write()?;
+        let removed = {
+            let mut tables = WriteTables::new(&tx)?;
+            let removed = tables.remove(key.as_ref())?;
+            tables.finish()?;
+            removed
+        };
+        tx.commit()?;
+        removed.map(|value| decode(&value)).transpose()
+    }
+
+    fn remove_prefix_filtered(
+        &mut self,
+        prefix: &E::Key,
+        predicate: impl Fn(&E::Value) -> bool,
+    ) -> Result<usize, Self::Error> {
+        let tx = self.db.begin_write()?;
+        let count = {
+            let mut tables = WriteTables::new(&tx)?;
+            let mut keys = vec![];
+            for item in tables.entries.iter()? {
+                let (key, value) = item?;
+                let entry: E = decode(value.value())?;
+                if prefix.is_prefix_of(entry.key()) && predicate(entry.value()) {
+                    keys.push(key.value().to_vec());
+                }
+            }
+            for key in &keys {
+                tables.remove(key)?;
+            }
+            tables.finish()?;
+            keys.len()
+        };
+        tx.commit()?;
+        Ok(count)
+    }
+}
+
+fn decode<E: DeserializeOwned>(bytes: &[u8]) -> Result<E, RedbStoreError> {
+    Ok(postcard::from_bytes(bytes)?)
+}
+
+type EntriesRange = redb::Range<'static, &'static [u8], &'static [u8]>;
+
+/// Iterator over the entries of a [`RedbStore`].
+pub struct RedbIterator<E: RangeEntry> {
+    iter: Chain<EntriesRange, Flatten<std::option::IntoIter<EntriesRange>>>,
+    /// Only yield entries whose key starts with this prefix.
+    prefix: Option<E::Key>,
+}
+
+impl<E: RangeEntry> std::fmt::Debug for RedbIterator<E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RedbIterator")
+            .field("prefix", &self.prefix)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<E: RangeEntry> RedbIterator<E> {
+    fn new(first: EntriesRange, second: Option<EntriesRange>, prefix: Option<E::Key>) -> Self {
+        Self {
+            iter: first.chain(second.into_iter().flatten()),
+            prefix,
+        }
+    }
+}
+
+impl<E: RangeEntry + DeserializeOwned> Iterator for RedbIterator<E> {
+    type Item = Result<E, RedbStoreError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let entry = self
+                .iter
+                .next()?
+                .map_err(RedbStoreError::from)
+                .and_then(|(_, value)| decode::<E>(value.value()));
+            match (entry, &self.prefix) {
+                (Ok(entry), Some(prefix)) if !prefix.is_prefix_of(entry.key()) => continue,
+                (entry, _) => return Some(entry),
+            }
+        }
+    }
+}
@@ -0,0 +1,81 @@
+//! Authenticated encryption of messages with a key shared by both sides, see [`SealedMessage`].
+
+use std::fmt;
+
+use crypto_secretbox::{aead::Aead, KeyInit, XChaCha20Poly1305};
+use serde::{Deserialize, Serialize};
+
+/// Size of the nonce of a [`SealedMessage`].
+const NONCE_LEN: usize = 24;
+
+/// A key that seals and opens the messages of a session, see [`SealedMessage`].
+///
+/// Both sides of a session need the same key. How they agree on it is up to the caller, e.g.
+/// it can be derived from the keys of an authenticated transport.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct SealingKey([u8; 32]);
+
+impl SealingKey {
+    /// Create a key from its bytes.
+    pub fn new(bytes: [u8; 32]) -> Self {
+        Self(bytes)
+    }
+
+    /// Get the bytes of the key.
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+}
+
+impl From<[u8; 32]> for SealingKey {
+    fn from(bytes: [u8; 32]) -> Self {
+        Self(bytes)
+    }
+}
+
+impl fmt::Debug for SealingKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // never print the key itself
+        f.write_str("SealingKey(..)")
+    }
+}
+
+/// An encoded message, encrypted and authenticated with a [`SealingKey`].
+///
+/// The ciphertext is sealed with XChaCha20-Poly1305 under a random nonce, so a message that was
+/// modified on the way, or sealed with a different key, cannot be opened. See
+/// [`SyncSession::seal_message`] and [`RunOptions::with_sealing_key`].
+///
+/// [`SyncSession::seal_message`]: super::SyncSession::seal_message
+/// [`RunOptions::with_sealing_key`]: super::RunOptions::with_sealing_key
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SealedMessage {
+    /// The nonce the message was sealed with.
+    pub nonce: [u8; NONCE_LEN],
+    /// The encrypted message, followed by the authentication tag.
+    pub ciphertext: Vec<u8>,
+}
+
+/// The message could not be sealed, or could not be opened because it was modified or sealed
+/// with a different key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+#[error("failed to seal or open message")]
+pub struct SealError;
+
+impl SealedMessage {
+    /// Seals `bytes` with `key` under a random nonce.
+    pub(super) fn seal(key: &SealingKey, bytes: &[u8]) -> Result<Self, SealError> {
+        let nonce: [u8; NONCE_LEN] = rand::random();
+        let ciphertext = XChaCha20Poly1305::new(key.as_bytes().into())
+            .encrypt(&nonce.into(), bytes)
+            .map_err(|_| SealError)?;
+        Ok(Self { nonce, ciphertext })
+    }
+
+    /// Opens the message with `key`, returning the sealed bytes.
+    pub(super) fn open(&self, key: &SealingKey) -> Result<Vec<u8>, SealError> {
+        XChaCha20Poly1305::new(key.as_bytes().into())
+            .decrypt(&self.nonce.into(), &self.ciphertext[..])
+            .map_err(|_| SealError)
+    }
+}
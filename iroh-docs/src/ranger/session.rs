@@ -0,0 +1,4774 @@
+//! Per-session state of the set reconciliation protocol.
+
+use std::{
+    cell::{Cell, RefCell},
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    future::Future,
+    ops::ControlFlow,
+    time::{Duration, Instant},
+};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::sync::broadcast;
+use tracing::warn;
+
+use crate::ContentStatus;
+
+use super::{
+    envelope::{
+        CHUNK_VERSION, DELTA_VERSION, RANGE_IDS_VERSION, SEND_ALL_VERSION, SUMMARY_VERSION,
+    },
+    reply_order, AsKeyBytes, Chunk, DeltaRangeItem, Envelope, EnvelopeError, ExpiryPolicy,
+    Fingerprint, ImportReport, LazyRangeItem, Message, MessageLimits, MessagePart,
+    MessageValidationError, ProcessError, ProcessOptions, Range, RangeEntry, RangeFingerprint,
+    RangeIds, RangeItem, SendThreshold, SessionFilter, SessionId, SplitKey, Store, StoreSummary,
+    SyncConfig, SyncDirection, SyncMode, PROTOCOL_VERSION,
+};
+#[cfg(feature = "seal")]
+use super::{SealedMessage, SealingKey};
+
+/// Limits on the work a remote can cause in a single [`SyncSession`].
+///
+/// A remote that keeps answering with fresh fingerprints could otherwise keep a session alive
+/// forever. The defaults are far beyond what a sync of two well-behaved stores needs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProtocolLimits {
+    /// Maximum number of messages processed.
+    pub max_rounds: usize,
+    /// Maximum number of fingerprint parts processed, summed over all messages.
+    pub max_fingerprint_parts: usize,
+    /// Maximum recursion depth.
+    ///
+    /// The range of the initial message has depth 0. Each range that is contained in a range
+    /// sent by the other side has the depth of that range plus one.
+    pub max_depth: usize,
+    /// Maximum number of ranges of fingerprint and item parts processed, summed over all
+    /// messages.
+    ///
+    /// Each range costs a fingerprint query of the store, so this bounds the work of a remote
+    /// that sends many small parts.
+    pub max_ranges: usize,
+    /// Maximum number of received fingerprints that are remembered, to detect a remote that
+    /// sends the same fingerprint again, see [`ProtocolError::NoProgress`].
+    ///
+    /// The least recently received fingerprints are forgotten first. Zero disables the check.
+    #[serde(default = "default_max_responded_fingerprints")]
+    pub max_responded_fingerprints: usize,
+    /// Maximum total size of the [`MessagePart::ValueChunk`]s that are buffered until all
+    /// chunks of their entry arrived.
+    ///
+    /// Each buffered chunk, and each entry with buffered chunks, counts 64 bytes on top of the
+    /// bytes of the chunks, so that many small chunks exceed the limit as well. The chunks of an
+    /// entry that has more chunks than fit into the limit are rejected with the first of them.
+    #[serde(default = "default_max_reassembly_bytes")]
+    pub max_reassembly_bytes: usize,
+    /// Maximum number of reassembled entries that are remembered, to ignore their
+    /// [`MessagePart::ValueChunk`]s if they arrive again.
+    ///
+    /// The least recently reassembled entries are forgotten first. Chunks of a remembered entry
+    /// are ignored even if they carry a different value.
+    #[serde(default = "default_max_completed_values")]
+    pub max_completed_values: usize,
+}
+
+fn default_max_responded_fingerprints() -> usize {
+    4096
+}
+
+fn default_max_reassembly_bytes() -> usize {
+    16 * 1024 * 1024
+}
+
+fn default_max_completed_values() -> usize {
+    1024
+}
+
+/// The size counted for each buffered [`MessagePart::ValueChunk`], and for each entry with
+/// buffered chunks, in addition to the bytes of the chunks, see
+/// [`ProtocolLimits::max_reassembly_bytes`].
+const VALUE_CHUNK_OVERHEAD: usize = 64;
+
+impl Default for ProtocolLimits {
+    fn default() -> Self {
+        // a recursion tree with `max_parts` leaves has fewer than twice as many ranges
+        let max_ranges = 2 * MessageLimits::default().max_parts;
+        Self {
+            max_rounds: 1024,
+            max_fingerprint_parts: 1 << 24,
+            max_depth: 128,
+            max_ranges,
+            max_responded_fingerprints: default_max_responded_fingerprints(),
+            max_reassembly_bytes: default_max_reassembly_bytes(),
+            max_completed_values: default_max_completed_values(),
+        }
+    }
+}
+
+/// Quotas on the entries a remote can push into the store in a single [`SyncSession`].
+///
+/// Entries count toward the quotas once they pass validation, even if they are then not
+/// inserted because a newer entry exists. Entries rejected by validation do not count.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionLimits {
+    /// Maximum number of entries received.
+    pub max_entries_received: Option<usize>,
+    /// Maximum total size of the entries received.
+    pub max_bytes_received: Option<usize>,
+    /// Maximum total size of the values of a single message that are applied to the store.
+    ///
+    /// The values beyond are not applied, and the response carries the fingerprints of their
+    /// ranges instead, so that the remote sends them again in a later round, see
+    /// [`SyncStats::entries_deferred`]. The first value of a message is always applied, so the
+    /// sync makes progress with any limit.
+    #[serde(default)]
+    pub max_pending_value_bytes: Option<usize>,
+}
+
+/// A limit of [`ProtocolLimits`] or [`SessionLimits`], together with its configured value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Limit {
+    /// [`ProtocolLimits::max_rounds`]
+    Rounds(usize),
+    /// [`ProtocolLimits::max_fingerprint_parts`]
+    FingerprintParts(usize),
+    /// [`ProtocolLimits::max_depth`]
+    Depth(usize),
+    /// [`ProtocolLimits::max_ranges`]
+    Ranges(usize),
+    /// [`SessionLimits::max_entries_received`]
+    EntriesReceived(usize),
+    /// [`SessionLimits::max_bytes_received`]
+    BytesReceived(usize),
+    /// [`SessionLimits::max_pending_value_bytes`], if the remote does not support
+    /// [`MessagePart::Reopen`] to receive the fingerprints of the deferred values.
+    PendingValueBytes(usize),
+    /// [`ProtocolLimits::max_reassembly_bytes`]
+    ReassemblyBytes(usize),
+}
+
+impl std::fmt::Display for Limit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Limit::Rounds(max) => write!(f, "more than {max} rounds"),
+            Limit::FingerprintParts(max) => write!(f, "more than {max} fingerprint parts"),
+            Limit::Depth(max) => write!(f, "recursion deeper than {max}"),
+            Limit::Ranges(max) => write!(f, "more than {max} ranges"),
+            Limit::EntriesReceived(max) => write!(f, "more than {max} entries received"),
+            Limit::BytesReceived(max) => write!(f, "more than {max} bytes received"),
+            Limit::PendingValueBytes(max) => {
+                write!(f, "more than {max} bytes of values in a message")
+            }
+            Limit::ReassemblyBytes(max) => {
+                write!(f, "more than {max} bytes of value chunks buffered")
+            }
+        }
+    }
+}
+
+/// A violation of the protocol by the remote.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum ProtocolError {
+    /// The remote exceeded a limit of the session.
+    #[error("limit exceeded: {0}")]
+    LimitExceeded(Limit),
+    /// A message was received after the session was cancelled.
+    #[error("session was cancelled")]
+    Cancelled,
+    /// The initiator received a message that starts a sync, e.g. because both sides sent an
+    /// initial message.
+    #[error("initiator received an initial message")]
+    UnexpectedInitialMessage,
+    /// The responder was asked to send an initial message.
+    #[error("responder cannot send an initial message")]
+    NotInitiator,
+    /// A message contained a part for a range that was settled before.
+    #[error("received a part for a settled range")]
+    SettledRange,
+    /// A message belongs to a different session, see [`SyncSession::with_session_id`].
+    #[error("received a message of session {received:?} in session {expected:?}")]
+    SessionMismatch {
+        /// The id of the session.
+        expected: Option<SessionId>,
+        /// The id carried by the message.
+        received: Option<SessionId>,
+    },
+    /// The store was replaced while a response of the remote was outstanding, see
+    /// [`SyncSession::replace_store`].
+    #[error("cannot replace the store while a sync is in flight")]
+    InFlight,
+    /// A message uses a version of the protocol that is not supported, see
+    /// [`SyncSession::with_max_version`].
+    #[error("protocol version {version} is not supported, the supported version is {supported}")]
+    UnsupportedVersion {
+        /// The version of the message, or the version needed to send it.
+        version: u32,
+        /// The highest supported version, or the negotiated version.
+        supported: u32,
+    },
+    /// A message is malformed, see [`Message::validate`].
+    #[error("invalid message: {0}")]
+    InvalidMessage(#[from] MessageValidationError),
+    /// The remote sent an entry, or asked for a key, that does not match the strict filter of
+    /// the session, see [`SessionFilter::strict`].
+    #[error("key {key} does not match the filter of the session")]
+    KeyOutsideFilter {
+        /// The key, formatted with `Debug`.
+        key: String,
+    },
+    /// The remote sent a fingerprint for a range that it sent before, although the local
+    /// fingerprint of the range did not change since, so the response would be the same as
+    /// before. This happens if the stores order their keys differently, or if one of them is
+    /// broken, and would otherwise go on until a [`Limit`] is hit.
+    #[error("no progress for range {range}")]
+    NoProgress {
+        /// The range, formatted with `Debug`.
+        range: String,
+    },
+}
+
+impl From<Limit> for ProtocolError {
+    fn from(limit: Limit) -> Self {
+        ProtocolError::LimitExceeded(limit)
+    }
+}
+
+/// The role of a [`SyncSession`], see [`SyncSession::role`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    /// The session sent the initial message.
+    Initiator,
+    /// The session received the initial message.
+    Responder,
+}
+
+/// Error returned from [`SyncSession::process_message`], [`SyncSession::initial_message`] and
+/// the other methods of a session that use the store.
+///
+/// Errors of the store are kept apart from violations of the protocol by the remote, see
+/// [`SyncError::is_protocol`] and [`SyncError::into_store_err`].
+#[derive(Debug, thiserror::Error)]
+pub enum SyncError<E> {
+    /// The store returned an error.
+    #[error("store error: {0:?}")]
+    Store(E),
+    /// The remote violated the protocol.
+    #[error("protocol error: {0}")]
+    Protocol(#[from] ProtocolError),
+    /// The validate callback failed.
+    #[error(transparent)]
+    Validation(#[from] ValidationError),
+    /// The transform hook changed the key of an entry.
+    #[error(transparent)]
+    Transform(#[from] TransformError),
+}
+
+impl<E> SyncError<E> {
+    /// Returns `true` if the remote violated the protocol, see [`SyncError::Protocol`].
+    pub fn is_protocol(&self) -> bool {
+        matches!(self, SyncError::Protocol(_))
+    }
+
+    /// Returns `true` if the store returned an error, see [`SyncError::Store`].
+    pub fn is_store(&self) -> bool {
+        matches!(self, SyncError::Store(_))
+    }
+
+    /// Get the violation of the protocol, if the remote violated the protocol.
+    pub fn as_protocol(&self) -> Option<&ProtocolError> {
+        match self {
+            SyncError::Protocol(err) => Some(err),
+            _ => None,
+        }
+    }
+
+    /// Take the error of the store, or return this error if it is not an error of the store.
+    pub fn into_store_err(self) -> Result<E, Self> {
+        match self {
+            SyncError::Store(err) => Ok(err),
+            err => Err(err),
+        }
+    }
+
+    /// Maps the error of the store with `f`, and keeps all other errors.
+    pub fn map_store<F>(self, f: impl FnOnce(E) -> F) -> SyncError<F> {
+        match self {
+            SyncError::Store(err) => SyncError::Store(f(err)),
+            SyncError::Protocol(err) => SyncError::Protocol(err),
+            SyncError::Validation(err) => SyncError::Validation(err),
+            SyncError::Transform(err) => SyncError::Transform(err),
+        }
+    }
+}
+
+impl<E> From<MessageValidationError> for SyncError<E> {
+    fn from(err: MessageValidationError) -> Self {
+        SyncError::Protocol(err.into())
+    }
+}
+
+impl<E> From<ProcessError<E>> for SyncError<E> {
+    fn from(err: ProcessError<E>) -> Self {
+        match err {
+            ProcessError::Store(err) => SyncError::Store(err),
+            ProcessError::InvalidMessage(err) => err.into(),
+        }
+    }
+}
+
+impl<E> From<Limit> for SyncError<E> {
+    fn from(limit: Limit) -> Self {
+        SyncError::Protocol(limit.into())
+    }
+}
+
+/// A validate callback failed to validate an entry, see [`SyncSession::try_process_message`].
+#[derive(Debug, thiserror::Error)]
+#[error("failed to validate entry {key}: {source}")]
+pub struct ValidationError {
+    /// The debug representation of the key of the entry.
+    pub key: String,
+    /// The error returned from the callback.
+    #[source]
+    pub source: anyhow::Error,
+}
+
+/// The transform hook changed the key of an entry, see [`SyncSession::with_transform`].
+#[derive(Debug, thiserror::Error)]
+#[error("transform changed the key of entry {key} to {transformed_key}")]
+pub struct TransformError {
+    /// The debug representation of the key of the received entry.
+    pub key: String,
+    /// The debug representation of the key of the transformed entry.
+    pub transformed_key: String,
+}
+
+/// The result of comparing two stores with [`SyncMode::VerifyOnly`], see
+/// [`SyncSession::verification_report`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct VerificationReport<K> {
+    /// Whether no range was found in which the stores differ.
+    pub identical: bool,
+    /// The ranges in which the stores differ, ordered by their start. Each range has at most as
+    /// many entries on either side as the anchor threshold of that side.
+    pub divergent_ranges: Vec<Range<K>>,
+    /// The deepest recursion depth reached, see [`SyncSession::depth`].
+    pub depth_reached: usize,
+}
+
+/// Statistics of a [`SyncSession`].
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct SyncStats {
+    /// Number of messages sent, including the initial message.
+    pub messages_sent: usize,
+    /// Number of messages received.
+    pub messages_received: usize,
+    /// Number of messages received that were dropped because a message with the same or a
+    /// higher sequence number was processed before, see [`SyncSession::with_sequence_numbers`],
+    /// or because they were late or duplicate, see [`SyncSession::with_range_ids`].
+    pub messages_dropped: usize,
+    /// Number of fingerprint parts sent.
+    pub fingerprint_parts_sent: usize,
+    /// Number of fingerprint parts received.
+    pub fingerprint_parts_received: usize,
+    /// Number of item parts sent.
+    pub item_parts_sent: usize,
+    /// Number of item parts received.
+    pub item_parts_received: usize,
+    /// Number of entries sent.
+    pub entries_sent: usize,
+    /// Number of entries received, whether they were stored or not.
+    pub entries_received: usize,
+    /// Number of entries received that were rejected by the validate callback, or because they
+    /// were expired, see [`SyncSession::with_expiry_policy`].
+    pub entries_rejected: usize,
+    /// Number of entries inserted into the store, for keys that were not in the store before.
+    pub entries_inserted: usize,
+    /// Number of entries inserted into the store, which replaced an entry with the same key.
+    pub entries_overwritten: usize,
+    /// Number of entries received that were not applied, because they exceeded
+    /// [`SessionLimits::max_pending_value_bytes`].
+    pub entries_deferred: usize,
+    /// Number of entries received in [`MessagePart::ValueChunk`]s that were dropped before all
+    /// of their chunks arrived, see [`SyncSession::abandon_partial_values`].
+    pub entries_abandoned: usize,
+    /// Number of received ranges that could not be split into at least two non-empty ranges, and
+    /// were answered with all of their entries instead, see
+    /// [`ProcessOutcome::degenerate`](super::ProcessOutcome::degenerate).
+    pub degenerate_ranges: usize,
+    /// Time from the first message generated or processed in the session to the end of the
+    /// last one.
+    pub duration: Duration,
+}
+
+impl SyncStats {
+    fn record_sent<E: RangeEntry>(&mut self, message: &Message<E>) {
+        self.messages_sent += 1;
+        for part in message.parts() {
+            match part {
+                MessagePart::RangeFingerprint(_) => self.fingerprint_parts_sent += 1,
+                MessagePart::Cancel
+                | MessagePart::Handshake(_)
+                | MessagePart::HandshakeMatch
+                | MessagePart::SessionId(_)
+                | MessagePart::Sequence(_)
+                | MessagePart::Reopen
+                | MessagePart::CompressedRangeItem(_)
+                | MessagePart::Done
+                | MessagePart::ValueRequest(_)
+                | MessagePart::More
+                | MessagePart::WantKeys { .. }
+                | MessagePart::MissingKeys { .. }
+                | MessagePart::StoreSummary(_)
+                | MessagePart::DeltaRangeItem(_)
+                | MessagePart::SendAll { .. }
+                | MessagePart::Divergent { .. }
+                | MessagePart::RangeIds(_) => {}
+                MessagePart::ValueChunk { index, total, .. } => {
+                    if index + 1 == *total {
+                        self.entries_sent += 1;
+                    }
+                }
+                MessagePart::RangeItem(item) => {
+                    self.item_parts_sent += 1;
+                    self.entries_sent += item.values.len();
+                }
+                MessagePart::LazyRangeItem(_) => self.item_parts_sent += 1,
+                MessagePart::ValueResponse(values) => self.entries_sent += values.len(),
+            }
+        }
+    }
+
+    fn record_received<E: RangeEntry>(&mut self, message: &Message<E>) {
+        self.messages_received += 1;
+        for part in message.parts() {
+            match part {
+                MessagePart::RangeFingerprint(_) => self.fingerprint_parts_received += 1,
+                MessagePart::Cancel
+                | MessagePart::Handshake(_)
+                | MessagePart::HandshakeMatch
+                | MessagePart::SessionId(_)
+                | MessagePart::Sequence(_)
+                | MessagePart::Reopen
+                | MessagePart::CompressedRangeItem(_)
+                | MessagePart::Done
+                | MessagePart::ValueRequest(_)
+                | MessagePart::More
+                | MessagePart::WantKeys { .. }
+                | MessagePart::MissingKeys { .. }
+                | MessagePart::StoreSummary(_)
+                | MessagePart::DeltaRangeItem(_)
+                | MessagePart::SendAll { .. }
+                | MessagePart::Divergent { .. }
+                | MessagePart::ValueChunk { .. }
+                | MessagePart::RangeIds(_) => {}
+                MessagePart::RangeItem(item) => {
+                    self.item_parts_received += 1;
+                    self.entries_received += item.values.len();
+                }
+                MessagePart::LazyRangeItem(_) => self.item_parts_received += 1,
+                MessagePart::ValueResponse(values) => self.entries_received += values.len(),
+            }
+        }
+    }
+}
+
+/// How far a [`SyncSession`] got, see [`SyncSession::progress`].
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct SyncProgress {
+    /// Number of ranges that were settled so far.
+    ///
+    /// A range is settled once its fingerprints match, or its entries were exchanged.
+    pub settled_ranges: usize,
+    /// Number of ranges sent in the last message, for which a response is expected.
+    pub outstanding_ranges: usize,
+    /// Number of entries sent and received, since the statistics were taken, see
+    /// [`SyncSession::take_stats`].
+    pub entries_transferred: u64,
+    /// Estimate of the settled fraction of the set, between 0.0 and 1.0.
+    ///
+    /// Outstanding ranges are weighted by the number of local entries in them. The estimate
+    /// never decreases during a session, and is 1.0 once no more responses are expected. It is
+    /// `None` before the first message was sent or received.
+    pub estimate: Option<f32>,
+}
+
+/// How much of a message [`SyncSession::process_message_with_budget`] processes in one call.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Budget {
+    /// Process at most this many parts.
+    Parts(usize),
+    /// Stop once the deadline passed.
+    Deadline(Instant),
+}
+
+impl Budget {
+    fn is_exhausted(&self, processed: usize) -> bool {
+        match self {
+            Budget::Parts(max) => processed >= *max,
+            Budget::Deadline(deadline) => Instant::now() >= *deadline,
+        }
+    }
+}
+
+/// The parts of a message that are left to process, see
+/// [`SyncSession::process_message_with_budget`].
+#[derive(Debug)]
+pub struct Continuation<E: RangeEntry> {
+    parts: VecDeque<MessagePart<E>>,
+    received: Received<E::Key>,
+    /// Whether no part was processed yet.
+    first: bool,
+}
+
+impl<E: RangeEntry> Continuation<E> {
+    /// Get the number of parts left to process.
+    pub fn remaining_parts(&self) -> usize {
+        self.parts.len()
+    }
+}
+
+/// The progress of a message processed with [`SyncSession::step`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StepProgress {
+    /// Number of parts of the message processed so far.
+    pub processed: usize,
+    /// Number of parts of the message left to process.
+    pub remaining: usize,
+}
+
+/// How an entry received in a [`SyncSession`] was written to the store.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InsertKind {
+    /// There was no entry with the same key in the store.
+    New,
+    /// The entry replaced an older entry with the same key.
+    Updated,
+}
+
+/// The default number of events buffered for the subscribers of a [`SyncSession`], see
+/// [`SyncSession::with_event_capacity`].
+pub const DEFAULT_EVENT_CAPACITY: usize = 1024;
+
+/// An event of a [`SyncSession`], see [`SyncSession::subscribe`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum SyncEvent<E: RangeEntry> {
+    /// A received entry was written to the store.
+    EntryInserted(E),
+    /// A received entry was not written to the store.
+    EntryRejected {
+        /// The key of the entry.
+        key: E::Key,
+        /// Why the entry was not written.
+        reason: RejectReason,
+    },
+    /// A range was settled, see [`SyncProgress::settled_ranges`].
+    RangeSettled(Range<E::Key>),
+    /// The session is done or was cancelled, with its statistics at that point.
+    SessionFinished(SyncStats),
+}
+
+/// Why a received entry was not written to the store, see [`SyncEvent::EntryRejected`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RejectReason {
+    /// The validate callback rejected the entry.
+    Validation,
+    /// The entry was expired, see [`SyncSession::with_expiry_policy`].
+    Expired,
+    /// The download of the entry was deferred, see [`SyncSession::with_download_policy`].
+    Deferred,
+    /// The key of the entry does not match the filter, see [`SyncSession::with_filter`].
+    Filtered,
+    /// A limit of [`SessionLimits`] was exceeded, see [`SyncSession::truncated`].
+    Limit(Limit),
+}
+
+/// What to do with a received entry, see [`SyncSession::with_download_policy`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DownloadDecision {
+    /// Write the entry to the store.
+    Inline,
+    /// Do not write the entry, but record its key and fingerprint, to download it later.
+    Defer,
+    /// Do not write the entry, as if the validate callback rejected it.
+    Skip,
+}
+
+/// Receives the events of a [`SyncSession`], see [`SyncSession::subscribe`].
+pub type EventReceiver<E> = broadcast::Receiver<SyncEvent<E>>;
+
+/// The result of [`SyncSession::apply_external`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExternalApplyReport<K> {
+    /// How the entries that passed validation were written to the store.
+    pub import: ImportReport,
+    /// Number of entries rejected by the validator of the session.
+    pub rejected: usize,
+    /// The ranges that became dirty, because they contain a key that was written or removed.
+    ///
+    /// Only ranges that were not dirty before are listed.
+    pub dirty_ranges: Vec<Range<K>>,
+}
+
+/// The result of [`SyncSession::epoch_report`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct EpochReport<K> {
+    /// The epoch of the store, see [`Store::epoch`].
+    pub epoch: u64,
+    /// The ranges that became dirty, because they were settled before the store was written to
+    /// outside of the session.
+    ///
+    /// Only ranges that were not dirty before are listed.
+    pub dirty_ranges: Vec<Range<K>>,
+}
+
+/// The persistent state of a [`SyncSession`], see [`SyncSession::suspend`].
+///
+/// All ranges that are not outstanding are settled: no more entries are exchanged for them in
+/// the session.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SessionSnapshot<K> {
+    /// The configuration of the session.
+    pub config: SyncConfig,
+    /// The limits of the session.
+    pub limits: ProtocolLimits,
+    /// The quotas of the session.
+    pub session_limits: SessionLimits,
+    /// The ranges of the last message sent, which the remote has not answered yet, and the
+    /// dirty ranges, see [`SyncSession::apply_external`], with their recursion depth.
+    pub outstanding: Vec<(Range<K>, usize)>,
+    /// See [`SyncSession::rounds`].
+    pub rounds: usize,
+    /// See [`SyncSession::fingerprint_parts`].
+    pub fingerprint_parts: usize,
+    /// See [`SyncSession::ranges`].
+    #[serde(default)]
+    pub ranges: usize,
+    /// See [`SyncSession::depth`].
+    pub depth: usize,
+    /// See [`SyncSession::entries_received`].
+    pub entries_received: usize,
+    /// See [`SyncSession::bytes_received`].
+    pub bytes_received: usize,
+    /// See [`SyncSession::truncated`].
+    pub truncated: Option<Limit>,
+}
+
+/// The state of a single sync session with a remote.
+///
+/// A session wraps [`Store::initial_message`] and [`Store::process_message`], and enforces
+/// [`ProtocolLimits`] across all messages of the session. The limits are checked before the
+/// message is processed, so a message that exceeds a limit does not change the store.
+///
+/// A session only holds the state of the sync with one remote, and is passed the store with
+/// each call. So one store can serve several sessions at the same time, with the messages of
+/// the sessions processed in any order. Entries received in one session are visible to all
+/// other sessions, and are sent on to their remotes if the ranges they are in are not settled
+/// yet.
+///
+/// Use a new session for each sync. A session that is interrupted, e.g. because the connection
+/// dropped, can be continued with [`SyncSession::suspend`] and [`SyncSession::resume`].
+pub struct SyncSession<E: RangeEntry> {
+    config: SyncConfig,
+    limits: ProtocolLimits,
+    session_limits: SessionLimits,
+    message_limits: MessageLimits,
+    size_of: SizeFn<E>,
+    rounds: usize,
+    fingerprint_parts: usize,
+    ranges: usize,
+    depth: usize,
+    entries_received: usize,
+    bytes_received: usize,
+    /// The session limit that was exceeded, after which no more entries are stored.
+    truncated: Option<Limit>,
+    /// Whether the session was cancelled by either side.
+    cancelled: bool,
+    /// The state of the sync that refers to the contents of the store, see
+    /// [`SyncSession::replace_store`].
+    state: SessionState<E>,
+    session_id: Option<SessionId>,
+    /// The sequence number of the next message we send, if messages are numbered.
+    next_sequence: Option<u64>,
+    /// The highest sequence number received.
+    last_sequence_received: Option<u64>,
+    /// The highest version of the protocol the session supports.
+    max_version: u32,
+    /// The negotiated version of the protocol, see [`SyncSession::decode_message`].
+    version: Option<u32>,
+    /// The size above which the values of range items are compressed, see
+    /// [`SyncSession::with_compression`].
+    #[cfg(feature = "zstd")]
+    compression_threshold: Option<usize>,
+    /// The functions that delta encode and decode the keys of range items, see
+    /// [`SyncSession::with_delta_keys`].
+    delta_keys: Option<(DeltaEncodeFn<E>, DeltaDecodeFn<E>)>,
+    /// The messages of the last response, if messages are numbered.
+    last_response: Vec<Message<E>>,
+    /// The maximum encoded size of a message, and a function to compute the encoded size of a
+    /// part.
+    max_message_bytes: Option<(usize, PartSizeFn<E>)>,
+    /// The maximum number of parts of a message we send, see [`SyncSession::with_page_size`].
+    page_size: Option<usize>,
+    /// Whether updates of the remote are applied, see [`SyncSession::with_live_updates`].
+    live_updates: bool,
+    /// The local entries to send with the next update, see [`SyncSession::local_put`].
+    updates: Vec<E>,
+    /// Whether an update of the remote was lost or not applied, see
+    /// [`SyncSession::has_drifted`].
+    drifted: bool,
+    stats: SyncStats,
+    /// When the first message of the session, or since the stats were taken, was handled.
+    started: Option<Instant>,
+    on_insert: Option<InsertFn<E>>,
+    transform: Option<TransformFn<E>>,
+    validator: Option<ValidateFn<E>>,
+    content_status: Option<ContentStatusFn<E>>,
+    send_threshold: Option<SendThresholdFn<E>>,
+    /// The callback for entries whose value is missing, if values are transferred lazily, see
+    /// [`SyncSession::with_lazy_values`].
+    on_missing_value: Option<MissingValueFn<E>>,
+    /// The keys the remote has no entry for, see [`SyncSession::missing_keys`].
+    missing_keys: Vec<E::Key>,
+    /// Whether we advertise a summary of the store, see [`SyncSession::with_store_summary`].
+    advertise_summary: bool,
+    /// The summary of the store we sent.
+    local_summary: Option<StoreSummary<E::Key>>,
+    /// The summary of the store of the remote.
+    remote_summary: Option<StoreSummary<E::Key>>,
+    /// The maximum size of a message the remote accepts, see [`MessagePart::SendAll`].
+    remote_max_message_bytes: Option<usize>,
+    /// The sender of the events, once there is a subscriber, see [`SyncSession::subscribe`].
+    events: Option<broadcast::Sender<SyncEvent<E>>>,
+    /// The number of events buffered for the subscribers.
+    event_capacity: usize,
+    /// The policy that rejects expired entries, see [`SyncSession::with_expiry_policy`].
+    expiry: Option<ExpiryPolicy<E>>,
+    /// The filter that rejects entries with other keys, see [`SyncSession::with_filter`].
+    filter: Option<SessionFilter<E>>,
+    /// The policy that decides which received entries are written, and the callback for the
+    /// deferred ones, see [`SyncSession::with_download_policy`].
+    download_policy: Option<(DownloadPolicyFn<E>, MissingValueFn<E>)>,
+    /// The keys and fingerprints of the deferred entries, see
+    /// [`SyncSession::deferred_downloads`].
+    deferred_downloads: BTreeMap<E::Key, Fingerprint>,
+    /// The ranges reconciled before the rest of the set, disjoint and ordered by their start,
+    /// see [`SyncSession::with_priority_ranges`].
+    priority_ranges: Vec<Range<E::Key>>,
+    /// The ranges in which the stores differ, see [`SyncSession::verification_report`].
+    divergent: Vec<Range<E::Key>>,
+    /// The function that encodes a part to split it into [`MessagePart::ValueChunk`]s, see
+    /// [`SyncSession::with_max_message_bytes`].
+    encode_part: Option<EncodePartFn<E>>,
+    /// The chunks received for each key whose entry is not complete yet, see
+    /// [`SyncSession::partial_values`].
+    partial_values: BTreeMap<E::Key, PartialValue>,
+    /// The size of the chunks in `partial_values`, as counted against
+    /// [`ProtocolLimits::max_reassembly_bytes`].
+    reassembly_bytes: usize,
+    /// Whether messages carry range ids, see [`SyncSession::with_range_ids`].
+    range_ids: bool,
+    /// The id of the next part we send that expects a reply.
+    next_range_id: u64,
+    /// The ids of the parts of the remote we responded to.
+    answered: BTreeSet<u64>,
+    /// The ids of the parts of the message we respond to, and the index of the next message of
+    /// the response.
+    responding_to: (Vec<u64>, u32),
+    /// Number of times the store was written to outside of the session, see
+    /// [`SyncSession::epoch_report`].
+    epoch_moves: u64,
+    /// The value of `epoch_moves` when the ranges of `sent` were sent.
+    sent_moves: u64,
+}
+
+/// The state of a [`SyncSession`] that refers to the contents of the store, which is dropped
+/// when the store is replaced, see [`SyncSession::replace_store`].
+struct SessionState<E: RangeEntry> {
+    /// See [`SyncSession::role`].
+    role: Option<Role>,
+    /// Messages of split responses that were not returned yet.
+    pending: VecDeque<Message<E>>,
+    /// The parts of the received messages of the current round that have more messages
+    /// following, see [`Message::has_more`].
+    incoming: Vec<MessagePart<E>>,
+    /// The messages to process with [`SyncSession::step`].
+    queued: VecDeque<Message<E>>,
+    /// The message that is processed with [`SyncSession::step`].
+    stepping: Option<Stepping<E>>,
+    /// The ranges of the parts sent in the last message, with their depth, ordered by the start
+    /// of the range.
+    sent: DepthRanges<E::Key>,
+    /// The ranges of the parts sent in the last message, which expect a response, ordered by
+    /// the start of the range.
+    awaiting: Vec<Range<E::Key>>,
+    /// The ranges of `awaiting` that were sent as fingerprints, whose response may continue the
+    /// reconciliation of the range with the entries of the store, ordered by the start of the
+    /// range. A range sent as an item with all of our entries is answered, if at all, with the
+    /// entries we lack.
+    reconciling: Vec<Range<E::Key>>,
+    /// The received ranges that were settled, with their depth, ordered by the start of the
+    /// range.
+    settled: DepthRanges<E::Key>,
+    /// The sent ranges that the remote did not respond to, because it settled them.
+    unanswered: Vec<Range<E::Key>>,
+    /// The received fingerprints that were answered, to detect a sync that makes no progress.
+    responded: Responded<E::Key>,
+    /// The ranges whose entries changed after they were reconciled, ordered by the start of the
+    /// range, see [`SyncSession::apply_external`].
+    dirty: Vec<Range<E::Key>>,
+    /// The parts we sent that expect a reply, with their range if they have one and whether the
+    /// response may continue the reconciliation of the range, by id, until all messages of the
+    /// response arrived.
+    outstanding: OutstandingParts<E::Key>,
+    /// The messages received of incomplete responses, by the smallest id they respond to: the
+    /// indices of the messages, and the index of the last message, once it arrived.
+    responses: BTreeMap<u64, (BTreeSet<u32>, Option<u32>)>,
+    /// The keys of the entries reassembled last, and the same keys, least recent first, see
+    /// [`ProtocolLimits::max_completed_values`].
+    completed_values: (BTreeSet<E::Key>, VecDeque<E::Key>),
+    /// The epoch of the store after the last write of the session, see [`Store::epoch`].
+    epoch: Option<u64>,
+    /// The settled ranges and the ranges the remote settled, with the value of
+    /// [`SyncSession`]'s `epoch_moves` when they were reconciled.
+    settled_epochs: Vec<(Range<E::Key>, u64)>,
+    /// The highest progress estimate returned so far.
+    estimate: Option<f32>,
+    /// Whether we sent a [`MessagePart::Done`].
+    done_sent: bool,
+    /// Whether the remote sent a [`MessagePart::Done`].
+    done_received: bool,
+    /// Whether [`SyncEvent::SessionFinished`] was emitted.
+    finished: bool,
+}
+
+impl<E: RangeEntry> Default for SessionState<E> {
+    fn default() -> Self {
+        Self {
+            role: None,
+            pending: VecDeque::new(),
+            incoming: Vec::new(),
+            queued: VecDeque::new(),
+            stepping: None,
+            sent: Vec::new(),
+            awaiting: Vec::new(),
+            reconciling: Vec::new(),
+            settled: Vec::new(),
+            unanswered: Vec::new(),
+            responded: Responded::new(),
+            dirty: Vec::new(),
+            outstanding: BTreeMap::new(),
+            responses: BTreeMap::new(),
+            completed_values: Default::default(),
+            epoch: None,
+            settled_epochs: Vec::new(),
+            estimate: None,
+            done_sent: false,
+            done_received: false,
+            finished: false,
+        }
+    }
+}
+
+/// The chunks of an entry received so far, see [`MessagePart::ValueChunk`].
+#[derive(Debug, Clone)]
+struct PartialValue {
+    total: u32,
+    chunks: BTreeMap<u32, Vec<u8>>,
+}
+
+impl PartialValue {
+    /// The size of the entry, as counted against [`ProtocolLimits::max_reassembly_bytes`].
+    fn size(&self) -> usize {
+        let chunks: usize = self.chunks.values().map(Vec::len).sum();
+        VALUE_CHUNK_OVERHEAD * (1 + self.chunks.len()) + chunks
+    }
+}
+
+type SizeFn<E> = Box<dyn Fn(&E) -> usize + Send + Sync>;
+type PartSizeFn<E> = fn(&MessagePart<E>) -> usize;
+type EncodePartFn<E> = fn(&MessagePart<E>) -> Result<Vec<u8>, postcard::Error>;
+type InsertFn<E> = Box<dyn FnMut(&E, InsertKind) + Send>;
+type TransformFn<E> = Box<dyn Fn(E) -> Option<E> + Send + Sync>;
+pub(super) type ValidateFn<E> = Box<dyn Fn(&E, ContentStatus) -> bool + Send + Sync>;
+type ContentStatusFn<E> = Box<dyn Fn(&E) -> ContentStatus + Send + Sync>;
+type MissingValueFn<E> = Box<dyn FnMut(&<E as RangeEntry>::Key, Fingerprint) + Send>;
+type DownloadPolicyFn<E> = Box<dyn Fn(&E) -> DownloadDecision + Send + Sync>;
+type SendThresholdFn<E> =
+    Box<dyn Fn(&Range<<E as RangeEntry>::Key>) -> SendThreshold + Send + Sync>;
+type DeltaEncodeFn<E> =
+    fn(&RangeItem<E>) -> Result<Option<DeltaRangeItem<<E as RangeEntry>::Key>>, EnvelopeError>;
+type DeltaDecodeFn<E> =
+    fn(&DeltaRangeItem<<E as RangeEntry>::Key>) -> Result<RangeItem<E>, EnvelopeError>;
+/// A response, and the continuation of a message, see
+/// [`SyncSession::process_message_with_budget`].
+type Budgeted<E> = (Option<Message<E>>, Option<Continuation<E>>);
+/// The response to a message, or the progress of processing it, see [`SyncSession::step`].
+type Step<E> = ControlFlow<Option<Message<E>>, StepProgress>;
+/// A received message without the parts for lazy value transfer, and the parts to respond to
+/// them with, see [`SyncSession::with_lazy_values`].
+type Lazy<E> = (Message<E>, Vec<MessagePart<E>>);
+
+/// A message whose parts are processed with the store, see [`SyncSession::apply`].
+#[derive(Debug)]
+struct Applying<E: RangeEntry> {
+    /// The parts left to process, items first.
+    parts: VecDeque<MessagePart<E>>,
+    /// The reply to the processed parts.
+    reply: Vec<MessagePart<E>>,
+    /// The parts added to the response after the reply.
+    lazy_response: Vec<MessagePart<E>>,
+    /// The ranges of the values that were not applied, see [`SyncSession::defer_values`].
+    deferred: Vec<Range<E::Key>>,
+}
+
+/// A message that is processed with [`SyncSession::step`].
+#[derive(Debug)]
+struct Stepping<E: RangeEntry> {
+    received: Received<E::Key>,
+    applying: Applying<E>,
+    processed: usize,
+}
+/// Ranges of message parts, with their recursion depth.
+type DepthRanges<K> = Vec<(Range<K>, usize)>;
+/// Parts that expect a reply by id, with their range if they have one, and whether the response
+/// may continue the reconciliation of the range.
+type OutstandingParts<K> = BTreeMap<u64, (Option<Range<K>>, bool)>;
+
+/// The results of a validate callback, see [`SyncSession::process_validated`].
+///
+/// The results are looked up by the key and fingerprint of the entry and its content status, not
+/// by position, as [`Store::process_message`] sorts the values of a part and drops duplicates
+/// before it validates them.
+struct Verdicts<E: RangeEntry>(BTreeMap<EntryKey<E::Key>, Vec<(ContentStatus, bool)>>);
+
+/// The key of an entry, and its fingerprint.
+type EntryKey<K> = (K, [u8; 32]);
+
+impl<E: RangeEntry> Verdicts<E> {
+    fn new() -> Self {
+        Self(BTreeMap::new())
+    }
+
+    fn get(&self, entry: &E, content_status: ContentStatus) -> Option<bool> {
+        let key = (entry.key().clone(), entry.as_fingerprint().0);
+        let verdicts = self.0.get(&key)?;
+        verdicts
+            .iter()
+            .find(|(status, _)| *status == content_status)
+            .map(|(_, valid)| *valid)
+    }
+
+    fn insert(&mut self, entry: &E, content_status: ContentStatus, valid: bool) {
+        let key = (entry.key().clone(), entry.as_fingerprint().0);
+        self.0.entry(key).or_default().push((content_status, valid));
+    }
+}
+
+/// The ranges of a received message.
+#[derive(Debug)]
+struct Received<K> {
+    /// The ranges of all parts.
+    parts: DepthRanges<K>,
+    /// The ranges of the fingerprint parts.
+    fingerprints: DepthRanges<K>,
+}
+
+/// A range, with all ranges of the whole set mapped to `None`, and a fingerprint of it.
+type ResponseKey<K> = (Option<(K, K)>, [u8; 32]);
+
+/// The received fingerprints that did not match, with the local fingerprint of their range when
+/// they were answered, see [`ProtocolError::NoProgress`].
+///
+/// Only the most recently received fingerprints are kept, so that a remote cannot grow it
+/// without bound.
+#[derive(Debug)]
+struct Responded<K> {
+    /// The local fingerprint, and the time the fingerprint was last received.
+    entries: BTreeMap<ResponseKey<K>, (Fingerprint, u64)>,
+    /// The keys of `entries`, by the time they were last received.
+    recency: BTreeMap<u64, ResponseKey<K>>,
+    time: u64,
+}
+
+impl<K: Ord + Clone> Responded<K> {
+    fn new() -> Self {
+        Self {
+            entries: BTreeMap::new(),
+            recency: BTreeMap::new(),
+            time: 0,
+        }
+    }
+
+    /// Records that `fingerprint` was received for `range` and answered with the `local`
+    /// fingerprint, keeping at most `max` fingerprints.
+    ///
+    /// Returns `true` if the same fingerprint was answered for the range before, with the same
+    /// local fingerprint.
+    fn insert(
+        &mut self,
+        range: &Range<K>,
+        fingerprint: Fingerprint,
+        local: Fingerprint,
+        max: usize,
+    ) -> bool {
+        if max == 0 {
+            return false;
+        }
+        let range = match range.is_all() {
+            true => None,
+            false => Some((range.x().clone(), range.y().clone())),
+        };
+        let key = (range, fingerprint.0);
+        self.time += 1;
+        let repeated = match self.entries.insert(key.clone(), (local, self.time)) {
+            Some((previous, time)) => {
+                self.recency.remove(&time);
+                previous == local
+            }
+            None => false,
+        };
+        self.recency.insert(self.time, key);
+        while self.entries.len() > max {
+            let Some((_, key)) = self.recency.pop_first() else {
+                break;
+            };
+            self.entries.remove(&key);
+        }
+        repeated
+    }
+
+    /// Forgets the fingerprints of the ranges that overlap `range`, because the remote is asked
+    /// to send them again.
+    fn forget(&mut self, range: &Range<K>) {
+        let mut removed = Vec::new();
+        self.entries.retain(|(other, _), (_, time)| {
+            let overlaps = match other {
+                None => true,
+                Some((x, y)) => range.overlaps(&Range::new(x.clone(), y.clone())),
+            };
+            if overlaps {
+                removed.push(*time);
+            }
+            !overlaps
+        });
+        for time in removed {
+            self.recency.remove(&time);
+        }
+    }
+}
+
+impl<E: RangeEntry> std::fmt::Debug for SyncSession<E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SyncSession")
+            .field("config", &self.config)
+            .field("limits", &self.limits)
+            .field("session_limits", &self.session_limits)
+            .field("rounds", &self.rounds)
+            .field("fingerprint_parts", &self.fingerprint_parts)
+            .field("ranges", &self.ranges)
+            .field("depth", &self.depth)
+            .field("entries_received", &self.entries_received)
+            .field("bytes_received", &self.bytes_received)
+            .field("truncated", &self.truncated)
+            .field("cancelled", &self.cancelled)
+            .field("role", &self.state.role)
+            .field("session_id", &self.session_id)
+            .field("version", &self.version)
+            .field("lazy_values", &self.on_missing_value.is_some())
+            .field("expiry", &self.expiry)
+            .field("download_policy", &self.download_policy.is_some())
+            .field("deferred_downloads", &self.deferred_downloads.len())
+            .field("delta_keys", &self.delta_keys.is_some())
+            .field("missing_keys", &self.missing_keys.len())
+            .field("local_summary", &self.local_summary)
+            .field("remote_summary", &self.remote_summary)
+            .field(
+                "max_message_bytes",
+                &self.max_message_bytes.map(|(max, _)| max),
+            )
+            .field("pending", &self.state.pending.len())
+            .field("dirty", &self.state.dirty.len())
+            .field("stats", &self.stats)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<E: RangeEntry> Default for SyncSession<E> {
+    fn default() -> Self {
+        Self::new(SyncConfig::default())
+    }
+}
+
+impl<E: RangeEntry> SyncSession<E> {
+    /// Create a new session, with the default limits.
+    pub fn new(config: SyncConfig) -> Self {
+        Self {
+            config,
+            limits: ProtocolLimits::default(),
+            session_limits: SessionLimits::default(),
+            message_limits: MessageLimits::default(),
+            size_of: Box::new(|_| 0),
+            rounds: 0,
+            fingerprint_parts: 0,
+            ranges: 0,
+            depth: 0,
+            entries_received: 0,
+            bytes_received: 0,
+            truncated: None,
+            cancelled: false,
+            state: SessionState::default(),
+            session_id: None,
+            next_sequence: None,
+            last_sequence_received: None,
+            max_version: PROTOCOL_VERSION,
+            version: None,
+            #[cfg(feature = "zstd")]
+            compression_threshold: None,
+            delta_keys: None,
+            last_response: Vec::new(),
+            max_message_bytes: None,
+            page_size: None,
+            live_updates: false,
+            updates: Vec::new(),
+            drifted: false,
+            stats: SyncStats::default(),
+            started: None,
+            on_insert: None,
+            transform: None,
+            validator: None,
+            content_status: None,
+            send_threshold: None,
+            on_missing_value: None,
+            missing_keys: Vec::new(),
+            advertise_summary: false,
+            local_summary: None,
+            remote_summary: None,
+            remote_max_message_bytes: None,
+            events: None,
+            event_capacity: DEFAULT_EVENT_CAPACITY,
+            expiry: None,
+            filter: None,
+            download_policy: None,
+            deferred_downloads: BTreeMap::new(),
+            priority_ranges: Vec::new(),
+            divergent: Vec::new(),
+            encode_part: None,
+            partial_values: BTreeMap::new(),
+            reassembly_bytes: 0,
+            range_ids: false,
+            next_range_id: 0,
+            answered: BTreeSet::new(),
+            responding_to: (Vec::new(), 0),
+            epoch_moves: 0,
+            sent_moves: 0,
+        }
+    }
+
+    /// Set the limits of the session.
+    pub fn with_limits(mut self, limits: ProtocolLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Set the limits of a single received message, see [`Message::validate`].
+    pub fn with_message_limits(mut self, message_limits: MessageLimits) -> Self {
+        self.message_limits = message_limits;
+        self
+    }
+
+    /// Set the id of the session, which is sent with every message of the session.
+    ///
+    /// The initiator should use a new id for each session, e.g. [`SessionId::random`]. A
+    /// responder without an id takes the id of the first message it receives, and sends it back
+    /// from then on. All other messages are rejected with [`ProtocolError::SessionMismatch`]
+    /// unless they carry the id of the session, or no id if the session has none. So messages
+    /// of concurrent sessions over the same connection cannot be processed in the wrong one.
+    pub fn with_session_id(mut self, session_id: SessionId) -> Self {
+        self.session_id = Some(session_id);
+        self
+    }
+
+    /// Number the messages sent in this session, see [`MessagePart::Sequence`].
+    ///
+    /// A session drops messages whose sequence number is not higher than that of a message it
+    /// processed before, e.g. a message that the transport delivered twice, and returns no
+    /// response for them. Dropped messages are counted in [`SyncStats::messages_dropped`]. This
+    /// only needs to be enabled on the side that sends the messages, and a session that numbers
+    /// its messages keeps its last response for retransmission, see
+    /// [`SyncSession::last_response`].
+    pub fn with_sequence_numbers(mut self) -> Self {
+        self.next_sequence = Some(0);
+        self
+    }
+
+    /// Tag the messages of the session with [`RangeIds`], so that the session tolerates a
+    /// transport that delivers messages more than once, or out of order, such as datagrams.
+    ///
+    /// Each part that expects a reply gets an id, and each response carries the ids of the
+    /// parts it responds to. The ids stay outstanding until all messages of their response
+    /// arrived, see [`SyncSession::outstanding_range_ids`]. Messages that only respond to ids
+    /// that are no longer outstanding, or that ask again for parts the session responded to
+    /// before, are dropped without a response, and counted in [`SyncStats::messages_dropped`].
+    /// Every message with ids is answered, with an empty response if there is nothing to send,
+    /// so that the remote learns that its parts were answered.
+    ///
+    /// A session that receives a message with range ids sends them as well. Sequence numbers,
+    /// see [`SyncSession::with_sequence_numbers`], do not drop messages that arrive out of
+    /// order in such a session. Paginated messages, see [`SyncSession::with_page_size`], must
+    /// arrive in order.
+    ///
+    /// Range ids are only sent if the negotiated version of the protocol supports them, see
+    /// [`PROTOCOL_VERSION`].
+    pub fn with_range_ids(mut self) -> Self {
+        self.range_ids = true;
+        self
+    }
+
+    /// Get the ids of the parts we sent that expect a reply, and whose response did not arrive
+    /// completely yet, see [`SyncSession::with_range_ids`].
+    pub fn outstanding_range_ids(&self) -> impl Iterator<Item = u64> + '_ {
+        self.state.outstanding.keys().copied()
+    }
+
+    /// Set the highest version of the protocol the session supports, which defaults to
+    /// [`PROTOCOL_VERSION`].
+    ///
+    /// The version is negotiated with the first message of the session, see
+    /// [`SyncSession::decode_message`], and only matters for sessions whose messages are encoded
+    /// with [`SyncSession::encode_message`].
+    pub fn with_max_version(mut self, max_version: u32) -> Self {
+        self.max_version = max_version.clamp(1, PROTOCOL_VERSION);
+        self
+    }
+
+    /// Compress the values of range items whose encoding exceeds `threshold` bytes, if the
+    /// negotiated version of the protocol supports it.
+    ///
+    /// Only applies to messages encoded with [`SyncSession::encode_message`], which sends the
+    /// values as a [`MessagePart::CompressedRangeItem`] if they get smaller.
+    /// [`SyncSession::decode_message`] decompresses them regardless of this setting.
+    #[cfg(feature = "zstd")]
+    pub fn with_compression(mut self, threshold: usize) -> Self {
+        self.compression_threshold = Some(threshold);
+        self
+    }
+
+    /// Delta encode the keys of range items, if the negotiated version of the protocol supports
+    /// it.
+    ///
+    /// Each key is sent as the length of the prefix it shares with the key of the previous value
+    /// of the item, followed by its remaining bytes, which shrinks messages with keys such as
+    /// paths that share long prefixes. Only applies to messages encoded with
+    /// [`SyncSession::encode_message`], which sends the values as a
+    /// [`MessagePart::DeltaRangeItem`] if they get smaller. The keys of items that are delta
+    /// encoded are not compressed, see [`SyncSession::with_compression`].
+    ///
+    /// Decoding the keys needs the same capability, so both sessions have to enable it.
+    /// [`SyncSession::decode_message`] fails with [`EnvelopeError::DeltaKeysDisabled`] for delta
+    /// encoded keys otherwise.
+    pub fn with_delta_keys(mut self) -> Self
+    where
+        E: SplitKey + Serialize,
+        E::Key: AsKeyBytes,
+        E::Rest: Serialize + DeserializeOwned,
+    {
+        self.delta_keys = Some((super::delta::encode::<E>, super::delta::decode::<E>));
+        self
+    }
+
+    /// Set quotas on the entries received in this session, where `size_of` returns the size of
+    /// an entry.
+    pub fn with_session_limits(
+        mut self,
+        session_limits: SessionLimits,
+        size_of: impl Fn(&E) -> usize + Send + Sync + 'static,
+    ) -> Self {
+        self.session_limits = session_limits;
+        self.size_of = Box::new(size_of);
+        self
+    }
+
+    /// Split responses into messages of at most `max_message_bytes` bytes, when encoded with
+    /// postcard.
+    ///
+    /// A part that exceeds the budget on its own, and cannot be split as described below, is
+    /// sent in a message of its own. Only the first message of a response is returned from
+    /// [`SyncSession::process_message`], the others have to be taken with
+    /// [`SyncSession::poll_pending_message`] and sent in order.
+    ///
+    /// If the store is empty when the initial message of the remote arrives, the session asks
+    /// for all entries with a [`MessagePart::SendAll`], if the negotiated version of the
+    /// protocol supports it. The remote then sends its entries in messages of at most
+    /// `max_message_bytes` bytes, if it splits its own messages as well, instead of in a single
+    /// item.
+    ///
+    /// If the negotiated version of the protocol supports it, an item whose entries exceed the
+    /// budget is split into items of fewer entries, and an entry that exceeds the budget on its
+    /// own is split into [`MessagePart::ValueChunk`]s, which the remote reassembles in
+    /// [`SyncSession::decode_message`].
+    pub fn with_max_message_bytes(mut self, max_message_bytes: usize) -> Self
+    where
+        MessagePart<E>: Serialize,
+    {
+        self.max_message_bytes = Some((max_message_bytes, MessagePart::encoded_size));
+        self.encode_part = Some(postcard::to_stdvec);
+        self
+    }
+
+    /// Split responses into messages of at most `page_size` parts, see [`Message::paginate`].
+    ///
+    /// All messages of a response but the last have [`Message::has_more`] set, so the remote
+    /// processes them together, and responds once to all of them. Like split messages, see
+    /// [`SyncSession::with_max_message_bytes`], only the first message is returned from
+    /// [`SyncSession::process_message`], and the others have to be taken with
+    /// [`SyncSession::poll_pending_message`].
+    ///
+    /// Responses are only split if the remote supports version 5 of the protocol, see
+    /// [`PROTOCOL_VERSION`].
+    pub fn with_page_size(mut self, page_size: usize) -> Self {
+        self.page_size = Some(page_size);
+        self
+    }
+
+    /// Set a hook that is invoked for each received entry that is written to the store.
+    ///
+    /// The hook is invoked once per entry, after the write succeeded, and before the
+    /// `on_insert_cb` passed to [`SyncSession::process_message`]. It is not invoked for entries
+    /// that are rejected by validation, or not written because a newer entry exists.
+    pub fn with_on_insert(
+        mut self,
+        on_insert: impl FnMut(&E, InsertKind) + Send + 'static,
+    ) -> Self {
+        self.on_insert = Some(Box::new(on_insert));
+        self
+    }
+
+    /// Set a hook that transforms each received entry before it is written to the store, e.g. to
+    /// attach a local receive timestamp to the value.
+    ///
+    /// The hook is invoked for each entry that passed validation, and returns the entry to write
+    /// instead, or `None` to drop it, see [`ProcessOptions::with_transform`]. The
+    /// transformed entry must have the same key as the received entry. Otherwise
+    /// [`SyncError::Transform`] is returned, and the remaining entries of the message are dropped.
+    /// Entries of the message that were written before are kept.
+    pub fn with_transform(
+        mut self,
+        transform: impl Fn(E) -> Option<E> + Send + Sync + 'static,
+    ) -> Self {
+        self.transform = Some(Box::new(transform));
+        self
+    }
+
+    /// Set the validate callback used by [`SyncSession::process`].
+    ///
+    /// Without a validator, all entries are accepted. Validators that need to read from the store
+    /// have to be passed to [`SyncSession::process_message`] instead, which ignores the stored
+    /// validator.
+    pub fn with_validator(
+        mut self,
+        validator: impl Fn(&E, ContentStatus) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.validator = Some(Box::new(validator));
+        self
+    }
+
+    pub(super) fn set_validator(&mut self, validator: Option<ValidateFn<E>>) {
+        self.validator = validator;
+    }
+
+    /// Reject received entries that are expired according to `expiry`, before they are passed
+    /// to the validate callback.
+    ///
+    /// The session does not withhold expired local entries, so the store should be wrapped with
+    /// [`ExpiryPolicy::store`] as well.
+    pub fn with_expiry_policy(mut self, expiry: ExpiryPolicy<E>) -> Self {
+        self.expiry = Some(expiry);
+        self
+    }
+
+    /// Only sync the keys selected by `filter`.
+    ///
+    /// Received entries with other keys are rejected before they are passed to the validate
+    /// callback, and requests for them are answered as if the store did not have them. If the
+    /// filter is strict, see [`SessionFilter::strict`], messages with such entries or requests
+    /// are rejected with [`ProtocolError::KeyOutsideFilter`] before the store is accessed.
+    ///
+    /// The session does not withhold local entries with other keys, so the store should be
+    /// wrapped with [`SessionFilter::store`] as well.
+    pub fn with_filter(mut self, filter: SessionFilter<E>) -> Self {
+        self.filter = Some(filter);
+        self
+    }
+
+    /// Returns `true` if `key` is synced, see [`SyncSession::with_filter`].
+    fn is_selected(&self, key: &E::Key) -> bool {
+        self.filter
+            .as_ref()
+            .map_or(true, |filter| filter.matches(key))
+    }
+
+    /// Returns [`ProtocolError::KeyOutsideFilter`] if the filter of the session is strict, and
+    /// `message` has an entry or a request for a key that does not match it.
+    fn check_filter(&self, message: &Message<E>) -> Result<(), ProtocolError> {
+        let Some(filter) = self.filter.as_ref().filter(|filter| filter.is_strict()) else {
+            return Ok(());
+        };
+        for part in message.parts() {
+            let mut keys: Box<dyn Iterator<Item = &E::Key>> = match part {
+                MessagePart::ValueRequest(keys) | MessagePart::WantKeys { keys } => {
+                    Box::new(keys.iter())
+                }
+                MessagePart::LazyRangeItem(item) => {
+                    Box::new(item.values.iter().map(|(key, _)| key))
+                }
+                part => Box::new(
+                    part.values()
+                        .unwrap_or_default()
+                        .iter()
+                        .map(|(e, _)| e.key()),
+                ),
+            };
+            if let Some(key) = keys.find(|key| !filter.matches(key)) {
+                return Err(ProtocolError::KeyOutsideFilter {
+                    key: format!("{:?}", key),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Decide for each received entry whether it is written to the store, e.g. to skip the
+    /// values above a size threshold.
+    ///
+    /// The policy is called for the entries that passed the validate callback. Entries with
+    /// [`DownloadDecision::Inline`] are written as usual, and entries with
+    /// [`DownloadDecision::Skip`] are rejected as if the validate callback rejected them. Entries
+    /// with [`DownloadDecision::Defer`] are not written, and their key and fingerprint are added
+    /// to [`SyncSession::deferred_downloads`], and passed to `on_defer` unless they were deferred
+    /// before. With lazy values, see [`SyncSession::with_lazy_values`], deferred entries are not
+    /// requested again. Keys are removed from the deferred downloads once an entry for them is
+    /// written.
+    pub fn with_download_policy(
+        mut self,
+        policy: impl Fn(&E) -> DownloadDecision + Send + Sync + 'static,
+        on_defer: impl FnMut(&E::Key, Fingerprint) + Send + 'static,
+    ) -> Self {
+        self.download_policy = Some((Box::new(policy), Box::new(on_defer)));
+        self
+    }
+
+    /// Start with the deferred downloads of an earlier session, see
+    /// [`SyncSession::deferred_downloads`], so that they are not deferred again.
+    pub fn with_deferred_downloads(
+        mut self,
+        deferred_downloads: BTreeMap<E::Key, Fingerprint>,
+    ) -> Self {
+        self.deferred_downloads = deferred_downloads;
+        self
+    }
+
+    /// Get the keys and fingerprints of the received entries that were deferred, see
+    /// [`SyncSession::with_download_policy`].
+    pub fn deferred_downloads(&self) -> &BTreeMap<E::Key, Fingerprint> {
+        &self.deferred_downloads
+    }
+
+    /// Reconcile the entries in `ranges` before the rest of the set, e.g. the entries shown to
+    /// the user.
+    ///
+    /// The initial message has a fingerprint for each priority range, followed by the
+    /// fingerprints of the ranges between them. In each response, the parts whose range
+    /// overlaps a priority range come before the other parts, so with split responses, see
+    /// [`SyncSession::with_max_message_bytes`], their entries are sent first. Only the order of
+    /// the parts changes, so the sync converges just the same.
+    ///
+    /// # Panics
+    ///
+    /// Panics if one of the ranges wraps around or covers the whole set, see [`Range`].
+    pub fn with_priority_ranges(mut self, ranges: Vec<Range<E::Key>>) -> Self {
+        assert!(
+            ranges.iter().all(|range| range.x() < range.y()),
+            "priority ranges must not wrap around"
+        );
+        let mut ranges = ranges;
+        ranges.sort_by(|a, b| a.x().cmp(b.x()));
+        let mut merged: Vec<Range<E::Key>> = Vec::with_capacity(ranges.len());
+        for range in ranges {
+            match merged.last_mut() {
+                Some(last) if range.x() <= last.y() => {
+                    if range.y() > last.y() {
+                        *last = Range::new(last.x().clone(), range.y().clone());
+                    }
+                }
+                _ => merged.push(range),
+            }
+        }
+        self.priority_ranges = merged;
+        self
+    }
+
+    /// Set the content status callback used by [`SyncSession::process`].
+    ///
+    /// Without a callback, all entries are sent as [`ContentStatus::Complete`].
+    pub fn with_content_status(
+        mut self,
+        content_status: impl Fn(&E) -> ContentStatus + Send + Sync + 'static,
+    ) -> Self {
+        self.content_status = Some(Box::new(content_status));
+        self
+    }
+
+    /// Set a callback that overrides the [`SyncConfig::send_threshold`] per range, e.g. to always
+    /// send the entries under a prefix of small entries.
+    ///
+    /// The callback is used for every message the session processes, see
+    /// [`ProcessOptions::with_send_threshold`].
+    pub fn with_send_threshold_fn(
+        mut self,
+        send_threshold: impl Fn(&Range<E::Key>) -> SendThreshold + Send + Sync + 'static,
+    ) -> Self {
+        self.send_threshold = Some(Box::new(send_threshold));
+        self
+    }
+
+    /// Transfer values lazily: range items carry the key and fingerprint of each entry instead
+    /// of the entry, see [`MessagePart::LazyRangeItem`], and `on_missing_value` is called with
+    /// each key whose entry is missing from the store, or differs from the remote's.
+    ///
+    /// The values are not fetched by the session. They can be pulled from the remote with
+    /// [`SyncSession::value_request_message`], or with a separate protocol, and are written to
+    /// the store when the response is processed. Sessions without lazy values answer lazy range
+    /// items as well, and request the missing values right away.
+    ///
+    /// Lazy range items are only sent if the remote supports version 4 of the protocol, see
+    /// [`PROTOCOL_VERSION`].
+    pub fn with_lazy_values(
+        mut self,
+        on_missing_value: impl FnMut(&E::Key, Fingerprint) + Send + 'static,
+    ) -> Self {
+        self.on_missing_value = Some(Box::new(on_missing_value));
+        self
+    }
+
+    /// Advertise a summary of the store, see [`StoreSummary`], in the initial message, or in
+    /// the first response if the remote starts the sync.
+    ///
+    /// A responder sends its summary in the first response whenever the initial message has
+    /// one, so only the initiator has to enable it. The summaries can be read with
+    /// [`SyncSession::local_summary`] and [`SyncSession::remote_summary`].
+    ///
+    /// Summaries are only sent if the remote supports version 7 of the protocol, see
+    /// [`PROTOCOL_VERSION`]. As the version is not negotiated before the initial message, only
+    /// enable it on an initiator if the remote supports that version.
+    pub fn with_store_summary(mut self) -> Self {
+        self.advertise_summary = true;
+        self
+    }
+
+    /// Set the number of events buffered for each subscriber, see [`SyncSession::subscribe`],
+    /// which defaults to [`DEFAULT_EVENT_CAPACITY`].
+    ///
+    /// Only applies to subscriptions made afterwards, if there was no subscriber before.
+    pub fn with_event_capacity(mut self, capacity: usize) -> Self {
+        self.event_capacity = capacity.max(1);
+        self
+    }
+
+    /// Subscribe to the events of the session.
+    ///
+    /// Entries are reported once they were written to the store, or rejected, and ranges once
+    /// they were settled, in the order the session processed them. [`SyncEvent::SessionFinished`]
+    /// is emitted once, when the session is done, see [`SyncSession::is_done`], or cancelled.
+    /// Sessions that end without [`MessagePart::Done`] do not emit it.
+    ///
+    /// Events are only recorded while there are subscribers, and never block the session. Each
+    /// subscriber buffers up to [`SyncSession::with_event_capacity`] events. A subscriber that
+    /// falls further behind loses the oldest events, and its next receive returns
+    /// [`broadcast::error::RecvError::Lagged`] with the number of lost events.
+    pub fn subscribe(&mut self) -> EventReceiver<E> {
+        match &self.events {
+            Some(events) => events.subscribe(),
+            None => {
+                let (events, receiver) = broadcast::channel(self.event_capacity);
+                self.events = Some(events);
+                receiver
+            }
+        }
+    }
+
+    /// Get the configuration of the session.
+    pub fn config(&self) -> &SyncConfig {
+        &self.config
+    }
+
+    /// Get the limits of the session.
+    pub fn limits(&self) -> &ProtocolLimits {
+        &self.limits
+    }
+
+    /// Get the number of messages processed so far.
+    pub fn rounds(&self) -> usize {
+        self.rounds
+    }
+
+    /// Get the number of fingerprint parts processed so far.
+    pub fn fingerprint_parts(&self) -> usize {
+        self.fingerprint_parts
+    }
+
+    /// Get the number of ranges of fingerprint and item parts processed so far.
+    pub fn ranges(&self) -> usize {
+        self.ranges
+    }
+
+    /// Get the deepest recursion depth reached so far.
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// Get the number of entries received so far, see [`SessionLimits`].
+    pub fn entries_received(&self) -> usize {
+        self.entries_received
+    }
+
+    /// Get the total size of the entries received so far, see [`SessionLimits`].
+    pub fn bytes_received(&self) -> usize {
+        self.bytes_received
+    }
+
+    /// Returns the session limit that was exceeded, if any.
+    ///
+    /// Once a session limit is exceeded, all further entries received in the session are
+    /// dropped, while the session continues so that the remote still receives our entries. The
+    /// dropped entries are received again in a later session.
+    pub fn truncated(&self) -> Option<Limit> {
+        self.truncated
+    }
+
+    /// Returns `true` if the session was cancelled, see [`SyncSession::cancel_message`].
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled
+    }
+
+    /// Returns `true` if both sides sent a [`MessagePart::Done`], so no more messages are
+    /// exchanged in this session.
+    ///
+    /// Sessions only send `Done` if the negotiated version supports it, see
+    /// [`SyncSession::decode_message`]. The side that sends the second `Done` is done once it
+    /// sent it, the other side once it received it, so both can close the connection without
+    /// waiting for the remote.
+    pub fn is_done(&self) -> bool {
+        self.state.done_sent && self.state.done_received
+    }
+
+    /// Get the role of the session, or `None` if no message was sent or received yet.
+    ///
+    /// The role is set by the first call to [`SyncSession::initial_message`],
+    /// [`SyncSession::handshake_message`] or [`SyncSession::process_message`]. A resumed session
+    /// is the initiator, see [`SyncSession::resume`].
+    pub fn role(&self) -> Option<Role> {
+        self.state.role
+    }
+
+    /// Get the id of the session, see [`SyncSession::with_session_id`].
+    pub fn session_id(&self) -> Option<SessionId> {
+        self.session_id
+    }
+
+    /// Get the negotiated version of the protocol, or `None` if no message was decoded yet, see
+    /// [`SyncSession::decode_message`].
+    pub fn version(&self) -> Option<u32> {
+        self.version
+    }
+
+    /// Encodes a message of this session for the wire, wrapped in an [`Envelope`].
+    ///
+    /// Before a version was negotiated, the envelope carries the highest version the session
+    /// supports, see [`SyncSession::with_max_version`], and the negotiated version afterwards.
+    /// Returns [`ProtocolError::UnsupportedVersion`] if the message needs a newer version, see
+    /// [`Message::min_version`].
+    pub fn encode_message(&self, message: &Message<E>) -> Result<Vec<u8>, EnvelopeError>
+    where
+        Message<E>: Serialize,
+        E: Serialize,
+    {
+        let version = self.version.unwrap_or(self.max_version);
+        let needed = message.min_version();
+        if needed > version {
+            return Err(ProtocolError::UnsupportedVersion {
+                version: needed,
+                supported: version,
+            }
+            .into());
+        }
+        let delta_keys = self.delta_keys.filter(|_| version >= DELTA_VERSION);
+        #[cfg(feature = "zstd")]
+        let threshold = self
+            .compression_threshold
+            .filter(|_| version >= super::envelope::COMPRESSION_VERSION);
+        #[cfg(not(feature = "zstd"))]
+        let threshold: Option<usize> = None;
+        if delta_keys.is_some() || threshold.is_some() {
+            let mut encoded = message.clone();
+            for part in encoded.parts.iter_mut() {
+                let MessagePart::RangeItem(item) = part else {
+                    continue;
+                };
+                if let Some((encode, _)) = delta_keys {
+                    if let Some(item) = encode(item)? {
+                        *part = MessagePart::DeltaRangeItem(item);
+                        continue;
+                    }
+                }
+                #[cfg(feature = "zstd")]
+                if let Some(threshold) = threshold {
+                    if let Some(item) = super::envelope::compress(item, threshold)? {
+                        *part = MessagePart::CompressedRangeItem(item);
+                    }
+                }
+            }
+            let payload = encoded.encode()?;
+            return Ok(postcard::to_stdvec(&Envelope { version, payload })?);
+        }
+        let payload = message.encode()?;
+        Ok(postcard::to_stdvec(&Envelope { version, payload })?)
+    }
+
+    /// Decodes a message encoded with [`SyncSession::encode_message`] by the remote.
+    ///
+    /// The first message decoded by the session negotiates the version: a responder picks the
+    /// lower of the version of the message and its own highest version, and an initiator
+    /// accepts the version of the first response if it supports it. All later messages must
+    /// carry the negotiated version, and must not contain parts that need a newer version.
+    /// Otherwise [`ProtocolError::UnsupportedVersion`] is returned.
+    ///
+    /// Compressed range items are decompressed, and the keys of delta encoded range items are
+    /// reassembled, so the returned message never contains a
+    /// [`MessagePart::CompressedRangeItem`] or a [`MessagePart::DeltaRangeItem`].
+    ///
+    /// [`MessagePart::ValueChunk`]s are buffered until all chunks of their entry arrived, and
+    /// the last chunk is replaced by the reassembled item. While entries are incomplete, the
+    /// returned message has [`Message::has_more`] set, so the session waits for the remaining
+    /// chunks before it responds. Chunks beyond [`ProtocolLimits::max_reassembly_bytes`] fail
+    /// with [`Limit::ReassemblyBytes`].
+    pub fn decode_message(&mut self, bytes: &[u8]) -> Result<Message<E>, EnvelopeError>
+    where
+        Message<E>: DeserializeOwned,
+        E: DeserializeOwned,
+    {
+        let envelope: Envelope = postcard::from_bytes(bytes)?;
+        let version = match (self.version, self.state.role) {
+            (Some(version), _) => (envelope.version == version).then_some(version),
+            // the responder picks a version that is not higher than the one we sent
+            (None, Some(Role::Initiator)) => {
+                (envelope.version <= self.max_version).then_some(envelope.version)
+            }
+            (None, _) => Some(envelope.version.min(self.max_version)),
+        };
+        let Some(version) = version.filter(|version| *version >= 1) else {
+            return Err(ProtocolError::UnsupportedVersion {
+                version: envelope.version,
+                supported: self.version.unwrap_or(self.max_version),
+            }
+            .into());
+        };
+        let message = Message::<E>::decode(&envelope.payload)?;
+        let needed = message.min_version();
+        if needed > version {
+            return Err(ProtocolError::UnsupportedVersion {
+                version: needed,
+                supported: version,
+            }
+            .into());
+        }
+        let cancel = message.is_cancel();
+        let range_ids = message.range_ids().is_some();
+        let mut parts = Vec::with_capacity(message.parts.len());
+        for part in message.parts {
+            let part = match part {
+                #[cfg(feature = "zstd")]
+                MessagePart::CompressedRangeItem(item) => {
+                    MessagePart::RangeItem(super::envelope::decompress(&item)?)
+                }
+                MessagePart::DeltaRangeItem(item) => match self.delta_keys {
+                    Some((_, decode)) => MessagePart::RangeItem(decode(&item)?),
+                    None => return Err(EnvelopeError::DeltaKeysDisabled),
+                },
+                MessagePart::ValueChunk {
+                    key,
+                    index,
+                    total,
+                    bytes,
+                } => match self.reassemble(key, index, total, bytes)? {
+                    Some(part) => part,
+                    None => continue,
+                },
+                part => part,
+            };
+            parts.push(part);
+        }
+        if cancel {
+            self.abandon_partial_values();
+        }
+        let message = Message {
+            parts,
+            // the session waits for the missing chunks before it responds, unless the message
+            // carries range ids, with which each message of a response is processed on its own
+            more: message.more || (!range_ids && !self.partial_values.is_empty()),
+        };
+        self.version = Some(version);
+        Ok(message)
+    }
+
+    /// Buffers a received [`MessagePart::ValueChunk`], and returns the reassembled part once
+    /// all chunks of its entry arrived.
+    ///
+    /// A chunk that was received before is ignored, so messages sent again do not break the
+    /// reassembly. So are the chunks of the entries reassembled last, see
+    /// [`ProtocolLimits::max_completed_values`].
+    fn reassemble(
+        &mut self,
+        key: E::Key,
+        index: u32,
+        total: u32,
+        bytes: Vec<u8>,
+    ) -> Result<Option<MessagePart<E>>, EnvelopeError>
+    where
+        Message<E>: DeserializeOwned,
+    {
+        // chunks are never empty, see `value_chunks`
+        if index >= total || bytes.is_empty() {
+            return Err(EnvelopeError::InvalidValueChunk);
+        }
+        if self.state.completed_values.0.contains(&key) {
+            return Ok(None);
+        }
+        let new_entry = match self.partial_values.get(&key) {
+            Some(partial) if partial.total != total => {
+                return Err(EnvelopeError::InvalidValueChunk)
+            }
+            Some(partial) => match partial.chunks.get(&index) {
+                Some(chunk) if *chunk != bytes => return Err(EnvelopeError::InvalidValueChunk),
+                Some(_) => return Ok(None),
+                None => false,
+            },
+            None => true,
+        };
+        let max = self.limits.max_reassembly_bytes;
+        // the smallest size of the whole entry, with a byte in each chunk
+        let min_size = (total as usize)
+            .saturating_mul(VALUE_CHUNK_OVERHEAD + 1)
+            .saturating_add(VALUE_CHUNK_OVERHEAD);
+        let size = VALUE_CHUNK_OVERHEAD * (1 + usize::from(new_entry)) + bytes.len();
+        if min_size > max || self.reassembly_bytes + size > max {
+            self.abandon_partial_values();
+            return Err(ProtocolError::from(Limit::ReassemblyBytes(max)).into());
+        }
+        self.reassembly_bytes += size;
+        let partial = self
+            .partial_values
+            .entry(key.clone())
+            .or_insert_with(|| PartialValue {
+                total,
+                chunks: BTreeMap::new(),
+            });
+        partial.chunks.insert(index, bytes);
+        if partial.chunks.len() < total as usize {
+            return Ok(None);
+        }
+        let partial = self
+            .partial_values
+            .remove(&key)
+            .expect("partial value exists");
+        self.reassembly_bytes -= partial.size();
+        let max = self.limits.max_completed_values;
+        if max > 0 {
+            let (keys, order) = &mut self.state.completed_values;
+            keys.insert(key.clone());
+            order.push_back(key.clone());
+            while order.len() > max {
+                if let Some(key) = order.pop_front() {
+                    keys.remove(&key);
+                }
+            }
+        }
+        let bytes: Vec<u8> = partial.chunks.into_values().flatten().collect();
+        let mut message = Message::<E>::decode(&bytes)?;
+        let part = match message.parts.pop() {
+            Some(part) if message.parts.is_empty() && !message.more => part,
+            _ => return Err(EnvelopeError::InvalidValueChunk),
+        };
+        match &part {
+            MessagePart::RangeItem(RangeItem { values, .. })
+            | MessagePart::ValueResponse(values)
+                if matches!(values.as_slice(), [(entry, _)] if *entry.key() == key) =>
+            {
+                Ok(Some(part))
+            }
+            _ => Err(EnvelopeError::InvalidValueChunk),
+        }
+    }
+
+    /// Get the keys of the entries of which some, but not all [`MessagePart::ValueChunk`]s were
+    /// received.
+    pub fn partial_values(&self) -> impl Iterator<Item = &E::Key> {
+        self.partial_values.keys()
+    }
+
+    /// Drops the chunks of the entries that are not complete, and returns their keys.
+    ///
+    /// The chunks are dropped when the session is done or cancelled, so this only needs to be
+    /// called if the remote stops sending before, e.g. after a timeout. The entries are counted
+    /// in [`SyncStats::entries_abandoned`], and are not written to the store.
+    pub fn abandon_partial_values(&mut self) -> Vec<E::Key> {
+        let keys: Vec<_> = std::mem::take(&mut self.partial_values)
+            .into_keys()
+            .collect();
+        self.reassembly_bytes = 0;
+        self.stats.entries_abandoned += keys.len();
+        keys
+    }
+
+    /// Encodes a message of this session with [`SyncSession::encode_message`] and seals it with
+    /// `key`, see [`SealedMessage`].
+    #[cfg(feature = "seal")]
+    pub fn seal_message(
+        &self,
+        message: &Message<E>,
+        key: &SealingKey,
+    ) -> Result<SealedMessage, EnvelopeError>
+    where
+        Message<E>: Serialize,
+        E: Serialize,
+    {
+        let bytes = self.encode_message(message)?;
+        Ok(SealedMessage::seal(key, &bytes)?)
+    }
+
+    /// Opens a message sealed with [`SyncSession::seal_message`] by the remote, and decodes it
+    /// with [`SyncSession::decode_message`].
+    ///
+    /// Returns [`EnvelopeError::Seal`] if the message was modified on the way, or sealed with a
+    /// different key. The session is unchanged in that case.
+    #[cfg(feature = "seal")]
+    pub fn open_message(
+        &mut self,
+        sealed: &SealedMessage,
+        key: &SealingKey,
+    ) -> Result<Message<E>, EnvelopeError>
+    where
+        Message<E>: DeserializeOwned,
+        E: DeserializeOwned,
+    {
+        let bytes = sealed.open(key)?;
+        self.decode_message(&bytes)
+    }
+
+    /// Get the messages of the last response, including pending messages, if messages are
+    /// numbered, see [`SyncSession::with_sequence_numbers`].
+    ///
+    /// The messages keep their sequence numbers, so they can be sent again if the remote may
+    /// not have received them, e.g. because it sent its last message again, without the risk
+    /// of being processed twice.
+    pub fn last_response(&self) -> &[Message<E>] {
+        &self.last_response
+    }
+
+    /// Get the statistics of the session.
+    pub fn stats(&self) -> &SyncStats {
+        &self.stats
+    }
+
+    /// Get the progress of the session.
+    ///
+    /// This counts the local entries in the outstanding ranges, so it reads from the store.
+    ///
+    /// The remote does not respond to a message in which all ranges were settled, so the session
+    /// that sent the last message keeps the ranges in it outstanding, and only the session that
+    /// received the last message reaches an estimate of 1.0.
+    pub fn progress<S: Store<E>>(&mut self, store: &mut S) -> Result<SyncProgress, S::Error> {
+        if self.rounds > 0 || !self.state.awaiting.is_empty() {
+            let estimate = match self.state.awaiting.len() {
+                0 => 1.0,
+                awaiting => {
+                    // each range counts as an entry itself, so that empty ranges are weighed
+                    let mut outstanding = awaiting;
+                    for range in &self.state.awaiting {
+                        outstanding += store.get_range_len(range.clone())?;
+                    }
+                    let total = store.len()? + awaiting;
+                    1.0 - outstanding as f32 / total as f32
+                }
+            };
+            self.state.estimate = Some(self.state.estimate.map_or(estimate, |e| e.max(estimate)));
+        }
+        Ok(SyncProgress {
+            settled_ranges: self.state.settled.len(),
+            outstanding_ranges: self.state.awaiting.len(),
+            entries_transferred: (self.stats.entries_sent + self.stats.entries_received) as u64,
+            estimate: self.state.estimate,
+        })
+    }
+
+    /// Take the statistics of the session, and reset them.
+    pub fn take_stats(&mut self) -> SyncStats {
+        self.started = None;
+        std::mem::take(&mut self.stats)
+    }
+
+    /// Cancels the session, and returns the message that tells the remote to cancel its session
+    /// as well.
+    ///
+    /// Pending messages are dropped, and all messages received afterwards are rejected with
+    /// [`ProtocolError::Cancelled`]. The remote ends its session when it processes the message,
+    /// without sending a response.
+    pub fn cancel_message(&mut self) -> Message<E> {
+        self.cancel();
+        let mut message = Message::cancel();
+        self.stamp(&mut message);
+        self.stats.record_sent(&message);
+        self.emit_finished();
+        message
+    }
+
+    /// Returns the message that requests the entries with `keys` from the remote, e.g. those
+    /// passed to the callback set with [`SyncSession::with_lazy_values`].
+    ///
+    /// The remote responds with a [`MessagePart::ValueResponse`] with the entries it has, which
+    /// are validated and written to the store like the entries of range items.
+    pub fn value_request_message(&mut self, keys: Vec<E::Key>) -> Message<E> {
+        let mut message = Message {
+            parts: vec![MessagePart::ValueRequest(keys)],
+            more: false,
+        };
+        self.stamp(&mut message);
+        self.stats.record_sent(&message);
+        message
+    }
+
+    /// Returns the message that requests the entries with exactly `keys` from the remote,
+    /// outside of a reconciliation, e.g. after an interrupted session left a known set of keys
+    /// missing.
+    ///
+    /// The remote responds with [`MessagePart::ValueResponse`] parts with the entries it has,
+    /// which are validated and written to the store like the entries of range items, and a
+    /// [`MessagePart::MissingKeys`] with the keys it has no entry for, which are collected in
+    /// [`SyncSession::missing_keys`].
+    ///
+    /// The request needs version 6 of the protocol, see [`PROTOCOL_VERSION`]. Remotes that do
+    /// not support it reject the message.
+    pub fn request_keys(&mut self, keys: Vec<E::Key>) -> Message<E> {
+        let mut message = Message {
+            parts: vec![MessagePart::WantKeys { keys }],
+            more: false,
+        };
+        self.stamp(&mut message);
+        self.stats.record_sent(&message);
+        message
+    }
+
+    /// Get the keys the remote reported to have no entry for, in response to
+    /// [`SyncSession::request_keys`].
+    pub fn missing_keys(&self) -> &[E::Key] {
+        &self.missing_keys
+    }
+
+    /// Get the summary of the store we sent, see [`SyncSession::with_store_summary`].
+    ///
+    /// The summary is taken when it is sent, before any entries are exchanged.
+    pub fn local_summary(&self) -> Option<&StoreSummary<E::Key>> {
+        self.local_summary.as_ref()
+    }
+
+    /// Get the summary of the remote's store, if it sent one, see
+    /// [`SyncSession::with_store_summary`].
+    ///
+    /// The summary is taken by the remote before any entries are exchanged. It is advisory, and
+    /// not checked against the entries the remote sends.
+    pub fn remote_summary(&self) -> Option<&StoreSummary<E::Key>> {
+        self.remote_summary.as_ref()
+    }
+
+    /// Get the ranges in which the stores differ, if the session compares the stores with
+    /// [`SyncMode::VerifyOnly`], or `None` otherwise.
+    ///
+    /// The report is complete once neither side has a message to send.
+    pub fn verification_report(&self) -> Option<VerificationReport<E::Key>> {
+        if self.config.mode() != SyncMode::VerifyOnly {
+            return None;
+        }
+        Some(VerificationReport {
+            identical: self.divergent.is_empty(),
+            divergent_ranges: self.divergent.clone(),
+            depth_reached: self.depth,
+        })
+    }
+
+    fn cancel(&mut self) {
+        self.cancelled = true;
+        self.state.pending.clear();
+        self.state.incoming.clear();
+        self.state.queued.clear();
+        self.state.stepping = None;
+        self.state.sent.clear();
+        self.state.awaiting.clear();
+        self.state.reconciling.clear();
+    }
+
+    /// Replaces `store` with `new_store`, and returns the old store, e.g. to swap in a compacted
+    /// copy that was built offline.
+    ///
+    /// The configuration, limits and callbacks of the session are kept, while all state that
+    /// refers to the contents of the old store is dropped: the outstanding, settled and dirty
+    /// ranges, the pending messages and the role. So the next sync of the session starts from
+    /// scratch, with an initial message from either side.
+    ///
+    /// Returns [`ProtocolError::InFlight`] and leaves the store untouched while the sync is in
+    /// flight, unless `force` is set: if the session has messages to send or to receive in full,
+    /// or sent fingerprints the remote may respond to with parts that need the entries of the
+    /// store. Ranges sent as items are answered, if at all, with the entries we lack, so the
+    /// store can be replaced once the last message of a sync only had items. The remote does not
+    /// respond to fingerprints that match its own, e.g. of equal stores, so the session that sent
+    /// them needs `force` unless the remote sent `Done`, see [`SyncSession::is_done`].
+    ///
+    /// Only the state of this session is reset. Other sessions that sync the same store are not
+    /// told about the replacement: their settled ranges still refer to the contents of the old
+    /// store, and they continue with the new store on their next call. Replace the store once
+    /// they finished, or start them again with an initial message.
+    pub fn replace_store<S: Store<E>>(
+        &mut self,
+        store: &mut S,
+        new_store: S,
+        force: bool,
+    ) -> Result<S, ProtocolError> {
+        if !force && self.in_flight() {
+            return Err(ProtocolError::InFlight);
+        }
+        self.state = SessionState::default();
+        Ok(std::mem::replace(store, new_store))
+    }
+
+    /// Returns `true` if the session has messages to send or to receive in full, or a response
+    /// of the remote may continue the reconciliation, see [`SyncSession::replace_store`].
+    fn in_flight(&self) -> bool {
+        !self.state.pending.is_empty()
+            || !self.state.incoming.is_empty()
+            || !self.state.queued.is_empty()
+            || self.state.stepping.is_some()
+            || !self.state.reconciling.is_empty()
+            || self
+                .state
+                .outstanding
+                .values()
+                .any(|(_, reconciling)| *reconciling)
+    }
+
+    /// Ends the session, and returns its state, so that it can be continued with
+    /// [`SyncSession::resume`], e.g. after reconnecting to the remote.
+    ///
+    /// The outstanding ranges are the ranges of the last response, including the messages that
+    /// are still pending. If responses are split into several messages, by either side, the
+    /// ranges of earlier messages of a response are not recorded, so such sessions should be
+    /// restarted instead.
+    pub fn suspend(self) -> SessionSnapshot<E::Key> {
+        let mut outstanding: DepthRanges<_> = self
+            .state
+            .sent
+            .into_iter()
+            .filter(|(range, _)| !self.state.dirty.iter().any(|d| contains_range(d, range)))
+            .collect();
+        outstanding.extend(self.state.dirty.into_iter().map(|range| (range, 0)));
+        outstanding.sort_by(|(a, _), (b, _)| a.x().cmp(b.x()));
+        SessionSnapshot {
+            config: self.config,
+            limits: self.limits,
+            session_limits: self.session_limits,
+            outstanding,
+            rounds: self.rounds,
+            fingerprint_parts: self.fingerprint_parts,
+            ranges: self.ranges,
+            depth: self.depth,
+            entries_received: self.entries_received,
+            bytes_received: self.bytes_received,
+            truncated: self.truncated,
+        }
+    }
+
+    /// Continues a session from a [`SessionSnapshot`].
+    ///
+    /// Returns the session, and a message with the fingerprints of the outstanding ranges, which
+    /// re-anchors the sync at these ranges. The message has to be processed by the remote with a
+    /// new session. Returns `None` instead of a message if nothing is outstanding.
+    ///
+    /// The callbacks set with [`SyncSession::with_session_limits`],
+    /// [`SyncSession::with_max_message_bytes`], [`SyncSession::with_on_insert`] and
+    /// [`SyncSession::with_expiry_policy`] are not part of the snapshot, and have to be set
+    /// again. The statistics start from zero. The resumed session has no session id, as the
+    /// remote processes the message with a new session.
+    pub fn resume<S: Store<E>>(
+        store: &mut S,
+        snapshot: SessionSnapshot<E::Key>,
+    ) -> Result<(Self, Option<Message<E>>), S::Error> {
+        let mut session = Self::new(snapshot.config).with_limits(snapshot.limits);
+        session.session_limits = snapshot.session_limits;
+        session.rounds = snapshot.rounds;
+        session.fingerprint_parts = snapshot.fingerprint_parts;
+        session.ranges = snapshot.ranges;
+        session.depth = snapshot.depth;
+        session.entries_received = snapshot.entries_received;
+        session.bytes_received = snapshot.bytes_received;
+        session.truncated = snapshot.truncated;
+        session.state.role = Some(Role::Initiator);
+        if snapshot.outstanding.is_empty() {
+            return Ok((session, None));
+        }
+
+        let started = *session.started.get_or_insert_with(Instant::now);
+        let mut parts = Vec::with_capacity(snapshot.outstanding.len());
+        for (range, _) in &snapshot.outstanding {
+            let fingerprint = store.get_fingerprint(range)?;
+            parts.push(MessagePart::RangeFingerprint(RangeFingerprint {
+                range: range.clone(),
+                fingerprint,
+            }));
+        }
+        let message = Message { parts, more: false };
+        session.state.sent = snapshot.outstanding;
+        session.state.awaiting = awaiting_ranges(&message);
+        session.state.reconciling = reconciling_ranges(&message);
+        session.stats.record_sent(&message);
+        session.stats.duration = started.elapsed();
+        Ok((session, Some(message)))
+    }
+
+    /// Generates the initial message, see [`Store::initial_message`].
+    ///
+    /// Returns [`ProtocolError::NotInitiator`] if the session received the initial message
+    /// from the remote.
+    pub fn initial_message<S: Store<E>>(
+        &mut self,
+        store: &mut S,
+    ) -> Result<Message<E>, SyncError<S::Error>> {
+        self.start_as_initiator()?;
+        self.observe_epoch(store);
+        let started = *self.started.get_or_insert_with(Instant::now);
+        let mut message = match self.priority_ranges.is_empty() {
+            true => store.initial_message(),
+            false => priority_message(store, &self.priority_ranges),
+        }
+        .map_err(SyncError::Store)?;
+        if self.advertise_summary && self.max_version >= SUMMARY_VERSION {
+            let summary = store.store_summary().map_err(SyncError::Store)?;
+            self.local_summary = Some(summary.clone());
+            message.parts.push(MessagePart::StoreSummary(summary));
+        }
+        self.stamp(&mut message);
+        self.state.sent = part_ranges(&message, &[]);
+        self.sent_moves = self.epoch_moves;
+        self.state.awaiting = awaiting_ranges(&message);
+        self.state.reconciling = reconciling_ranges(&message);
+        self.stats.record_sent(&message);
+        self.record_duration(started);
+        Ok(message)
+    }
+
+    /// Generates the initial message for reconciling only the entries in `range`, see
+    /// [`Store::initial_message_for_range`].
+    ///
+    /// The priority ranges, see [`SyncSession::with_priority_ranges`], and the store summary,
+    /// see [`SyncSession::with_store_summary`], are not used for a partial sync.
+    /// Returns [`ProtocolError::NotInitiator`] if the session received the initial message
+    /// from the remote.
+    pub fn initial_message_for_range<S: Store<E>>(
+        &mut self,
+        store: &mut S,
+        range: Range<E::Key>,
+    ) -> Result<Message<E>, SyncError<S::Error>> {
+        self.start_as_initiator()?;
+        self.observe_epoch(store);
+        let started = *self.started.get_or_insert_with(Instant::now);
+        let mut message = store
+            .initial_message_for_range(range)
+            .map_err(SyncError::Store)?;
+        self.stamp(&mut message);
+        self.state.sent = part_ranges(&message, &[]);
+        self.sent_moves = self.epoch_moves;
+        self.state.awaiting = awaiting_ranges(&message);
+        self.state.reconciling = reconciling_ranges(&message);
+        self.stats.record_sent(&message);
+        self.record_duration(started);
+        Ok(message)
+    }
+
+    /// Generates a handshake message, see [`Store::handshake_message`].
+    ///
+    /// Returns [`ProtocolError::NotInitiator`] if the session received the initial message
+    /// from the remote.
+    pub fn handshake_message<S: Store<E>>(
+        &mut self,
+        store: &mut S,
+    ) -> Result<Message<E>, SyncError<S::Error>> {
+        self.start_as_initiator()?;
+        self.observe_epoch(store);
+        let started = *self.started.get_or_insert_with(Instant::now);
+        let mut message = store.handshake_message().map_err(SyncError::Store)?;
+        self.stamp(&mut message);
+        // unless the stores match, the remote responds for the whole set
+        let x = store.get_first().map_err(SyncError::Store)?;
+        let range = Range::new(x.clone(), x);
+        self.state.sent = vec![(range.clone(), 0)];
+        self.sent_moves = self.epoch_moves;
+        self.state.awaiting = vec![range.clone()];
+        self.state.reconciling = vec![range];
+        self.stats.record_sent(&message);
+        self.record_duration(started);
+        Ok(message)
+    }
+
+    /// Writes entries that were received outside of the session, e.g. from a gossip broadcast,
+    /// to the store.
+    ///
+    /// Entries are validated with the callbacks set with [`SyncSession::with_validator`] and
+    /// [`SyncSession::with_content_status`], and written with [`Store::put`]. They do not count
+    /// toward the [`SessionLimits`], and are not passed to the hook set with
+    /// [`SyncSession::with_on_insert`].
+    ///
+    /// Writing to a range that was reconciled already would leave the remote behind, without
+    /// either side noticing. So the settled and sent ranges that contain a written key, or a key
+    /// removed by the write, become dirty, and are reconciled again with the message from
+    /// [`SyncSession::reopen_message`].
+    pub fn apply_external<S: Store<E>>(
+        &mut self,
+        store: &mut S,
+        entries: Vec<E>,
+    ) -> Result<ExternalApplyReport<E::Key>, S::Error> {
+        self.observe_epoch(store);
+        let mut report = ExternalApplyReport {
+            import: ImportReport::default(),
+            rejected: 0,
+            dirty_ranges: Vec::new(),
+        };
+        for entry in entries {
+            if !self.is_selected(entry.key()) {
+                report.rejected += 1;
+                continue;
+            }
+            let valid = match &self.validator {
+                Some(validator) => {
+                    let content_status = self
+                        .content_status
+                        .as_ref()
+                        .map_or(ContentStatus::Complete, |f| f(&entry));
+                    validator(&entry, content_status)
+                }
+                None => true,
+            };
+            if !valid {
+                report.rejected += 1;
+                continue;
+            }
+            // the entries whose key starts with the key of the entry are removed if it is written
+            let mut keys = vec![entry.key().clone()];
+            for prefixed in store.prefixed_by(entry.key())? {
+                keys.push(prefixed?.key().clone());
+            }
+            let import = store.put_many([entry])?;
+            report.import.inserted += import.inserted;
+            report.import.replaced += import.replaced;
+            report.import.ignored += import.ignored;
+            report.import.removed += import.removed;
+            if import.ignored > 0 || self.state.role.is_none() {
+                continue;
+            }
+            for key in &keys {
+                report.dirty_ranges.extend(self.mark_dirty(key));
+            }
+        }
+        report.dirty_ranges.sort_by(|a, b| a.x().cmp(b.x()));
+        // the written ranges are dirty already
+        self.state.epoch = Some(store.epoch());
+        Ok(report)
+    }
+
+    /// Checks whether the store was written to outside of the session after ranges were
+    /// settled, and marks these ranges as dirty, like [`SyncSession::apply_external`].
+    ///
+    /// Writes outside of the session, e.g. by the application or by other sessions with the
+    /// same store, are detected by comparing the epoch of the store, see [`Store::epoch`],
+    /// between the calls of the session that write to the store. As the epoch does not tell
+    /// which keys were written, all ranges that were settled before such a write become dirty,
+    /// as do the ranges whose fingerprints we sent before it, which the remote may have settled.
+    /// Ranges that were settled after the last write are not affected.
+    ///
+    /// This should be called once the exchange of the session ended, i.e. when neither side has
+    /// a message to send. The dirty ranges can then be reconciled again with the message from
+    /// [`SyncSession::reopen_message`], or with a new session for each of them, see
+    /// [`SyncSession::initial_message_for_range`].
+    pub fn epoch_report<S: Store<E>>(&mut self, store: &S) -> EpochReport<E::Key> {
+        self.observe_epoch(store);
+        let moves = self.epoch_moves;
+        // the remote settles the sent ranges that match without a response
+        let sent = match self.sent_moves < moves {
+            true => &self.state.sent[..],
+            false => &[][..],
+        };
+        let stale: Vec<_> = self
+            .state
+            .settled_epochs
+            .iter()
+            .filter(|(_, settled_at)| *settled_at < moves)
+            .map(|(range, _)| range)
+            .chain(sent.iter().map(|(range, _)| range))
+            .cloned()
+            .collect();
+        let mut dirty_ranges = self.mark_dirty_ranges(stale);
+        dirty_ranges.sort_by(|a, b| a.x().cmp(b.x()));
+        EpochReport {
+            epoch: store.epoch(),
+            dirty_ranges,
+        }
+    }
+
+    /// Counts a write outside of the session if the epoch of the store moved since the last
+    /// write of the session, see [`SyncSession::epoch_report`].
+    fn observe_epoch<S: Store<E>>(&mut self, store: &S) {
+        let epoch = store.epoch();
+        if self.state.epoch.is_some_and(|last| last != epoch) {
+            self.epoch_moves += 1;
+        }
+        self.state.epoch = Some(epoch);
+    }
+
+    /// Marks the reconciled ranges that contain `key` as dirty, and returns those that were not
+    /// dirty before.
+    fn mark_dirty(&mut self, key: &E::Key) -> Vec<Range<E::Key>> {
+        if self.state.dirty.iter().any(|range| range.contains(key)) {
+            return Vec::new();
+        }
+        let candidates: Vec<_> = self
+            .state
+            .settled
+            .iter()
+            .map(|(range, _)| range)
+            .chain(self.state.sent.iter().map(|(range, _)| range))
+            .chain(&self.state.unanswered)
+            .filter(|range| range.contains(key))
+            .cloned()
+            .collect();
+        self.mark_dirty_ranges(candidates)
+    }
+
+    /// Marks the outermost of `ranges` that are not in a dirty range as dirty, and returns them.
+    fn mark_dirty_ranges(&mut self, ranges: Vec<Range<E::Key>>) -> Vec<Range<E::Key>> {
+        let mut marked: Vec<Range<E::Key>> = Vec::new();
+        for range in ranges {
+            // only the outermost ranges are kept, the others are reconciled with them
+            if marked
+                .iter()
+                .chain(&self.state.dirty)
+                .any(|outer| contains_range(outer, &range))
+            {
+                continue;
+            }
+            marked.retain(|inner| !contains_range(&range, inner));
+            marked.push(range);
+        }
+        for range in &marked {
+            self.state
+                .dirty
+                .retain(|inner| !contains_range(range, inner));
+        }
+        self.state.dirty.extend(marked.iter().cloned());
+        self.state.dirty.sort_by(|a, b| a.x().cmp(b.x()));
+        marked
+    }
+
+    /// Returns `true` if ranges became dirty, see [`SyncSession::apply_external`].
+    pub fn has_dirty_ranges(&self) -> bool {
+        !self.state.dirty.is_empty()
+    }
+
+    /// Generates a message with the fingerprints of the dirty ranges, see
+    /// [`SyncSession::apply_external`], which the remote processes with its session, even if it
+    /// settled these ranges before. Returns `None` if no range is dirty.
+    ///
+    /// The message starts a new exchange, so it should only be sent once the exchange of the
+    /// session ended, i.e. when neither side has a message to send.
+    /// Returns [`ProtocolError::UnsupportedVersion`] if the negotiated version of the protocol
+    /// predates [`MessagePart::Reopen`].
+    pub fn reopen_message<S: Store<E>>(
+        &mut self,
+        store: &mut S,
+    ) -> Result<Option<Message<E>>, SyncError<S::Error>> {
+        if self.cancelled {
+            return Err(ProtocolError::Cancelled.into());
+        }
+        if self.state.dirty.is_empty() {
+            return Ok(None);
+        }
+        let reopen_version = MessagePart::<E>::Reopen.min_version();
+        if let Some(version) = self.version.filter(|version| *version < reopen_version) {
+            return Err(ProtocolError::UnsupportedVersion {
+                version: reopen_version,
+                supported: version,
+            }
+            .into());
+        }
+        self.observe_epoch(store);
+        let started = *self.started.get_or_insert_with(Instant::now);
+        let mut parts = vec![MessagePart::Reopen];
+        for range in &self.state.dirty {
+            let fingerprint = store.get_fingerprint(range).map_err(SyncError::Store)?;
+            parts.push(MessagePart::RangeFingerprint(RangeFingerprint {
+                range: range.clone(),
+                fingerprint,
+            }));
+        }
+        let dirty = std::mem::take(&mut self.state.dirty);
+        for range in &dirty {
+            self.unsettle(range);
+        }
+        let mut message = Message { parts, more: false };
+        self.stamp(&mut message);
+        self.state.sent = dirty.into_iter().map(|range| (range, 0)).collect();
+        self.sent_moves = self.epoch_moves;
+        self.state.awaiting = awaiting_ranges(&message);
+        self.state.reconciling = reconciling_ranges(&message);
+        if self.next_sequence.is_some() {
+            self.last_response = vec![message.clone()];
+        }
+        self.stats.record_sent(&message);
+        self.record_duration(started);
+        Ok(Some(message))
+    }
+
+    /// Keep the session open after the sync, to exchange the entries either side writes
+    /// afterwards, see [`SyncSession::local_put`].
+    ///
+    /// Received update messages, see [`SyncSession::take_update_message`], are processed with
+    /// [`SyncSession::process_message`] like range items, with the same validation, but are not
+    /// answered, and do not count toward the [`ProtocolLimits`]. Both sides have to enable
+    /// live updates.
+    pub fn with_live_updates(mut self) -> Self {
+        self.live_updates = true;
+        self
+    }
+
+    /// Queues an entry that was written to the store after the sync, to send it to the remote
+    /// with the next update message, see [`SyncSession::take_update_message`].
+    ///
+    /// Only the last entry queued for a key is sent, and entries with keys that do not match
+    /// the filter of the session, see [`SyncSession::with_filter`], are not sent at all.
+    pub fn local_put(&mut self, entry: E) {
+        if !self.is_selected(entry.key()) {
+            return;
+        }
+        self.updates.retain(|queued| queued.key() != entry.key());
+        self.updates.push(entry);
+    }
+
+    /// Returns the message with the entries queued with [`SyncSession::local_put`], or `None`
+    /// if no entry is queued.
+    ///
+    /// The entries are sent in a [`MessagePart::RangeItem`] for the whole set with
+    /// `have_local` set, so the remote writes them to its store without responding, and does
+    /// not send them back. Update messages should only be sent once the exchange of the session
+    /// ended, i.e. when neither side has a message to send.
+    pub fn take_update_message(&mut self) -> Option<Message<E>> {
+        if self.cancelled || self.updates.is_empty() {
+            return None;
+        }
+        let values: Vec<_> = std::mem::take(&mut self.updates)
+            .into_iter()
+            .map(|entry| {
+                let content_status = self
+                    .content_status
+                    .as_ref()
+                    .map_or(ContentStatus::Complete, |f| f(&entry));
+                (entry, content_status)
+            })
+            .collect();
+        let key = values[0].0.key().clone();
+        let mut message = Message {
+            parts: vec![MessagePart::RangeItem(RangeItem {
+                range: Range::new(key.clone(), key),
+                values,
+                have_local: true,
+            })],
+            more: false,
+        };
+        self.stamp(&mut message);
+        self.stats.record_sent(&message);
+        Some(message)
+    }
+
+    /// Returns `true` if the stores may have drifted apart since the sync, because an update of
+    /// the remote was lost, which is detected with numbered messages, see
+    /// [`SyncSession::with_sequence_numbers`], or its entries were not all written.
+    ///
+    /// The stores are reconciled again with the message from [`SyncSession::resync_message`].
+    pub fn has_drifted(&self) -> bool {
+        self.drifted
+    }
+
+    /// Generates a message that reconciles the whole set again, like
+    /// [`SyncSession::reopen_message`], e.g. after the stores drifted apart, see
+    /// [`SyncSession::has_drifted`].
+    ///
+    /// A session that lost its connection is not resumed this way, but replaced with a new
+    /// session that starts with [`SyncSession::initial_message`].
+    pub fn resync_message<S: Store<E>>(
+        &mut self,
+        store: &mut S,
+    ) -> Result<Option<Message<E>>, SyncError<S::Error>> {
+        let x = store.get_first().map_err(SyncError::Store)?;
+        self.state.dirty = vec![Range::new(x.clone(), x)];
+        self.drifted = false;
+        self.reopen_message(store)
+    }
+
+    /// Returns `true` if `message` is an update of the remote, see
+    /// [`SyncSession::take_update_message`], which is applied without a response.
+    fn is_update(&self, message: &Message<E>) -> bool {
+        // during the sync, items for the whole set with local values only answer an item of
+        // ours for the whole set
+        self.live_updates
+            && self.state.role.is_some()
+            && !self.state.awaiting.iter().any(Range::is_all)
+            && message.parts().iter().any(|part| part.is_range_item())
+            && message.parts().iter().all(|part| match part {
+                MessagePart::RangeItem(item) => item.have_local && item.range.is_all(),
+                MessagePart::SessionId(_) | MessagePart::Sequence(_) => true,
+                _ => false,
+            })
+    }
+
+    /// Writes the entries of an update of the remote to the store, see
+    /// [`SyncSession::is_update`].
+    fn apply_update<S, F, F2, F3>(
+        &mut self,
+        store: &mut S,
+        message: Message<E>,
+        validate_cb: F,
+        on_insert_cb: F2,
+        content_status_cb: F3,
+    ) -> Result<(), SyncError<S::Error>>
+    where
+        S: Store<E>,
+        F: Fn(&S, &E, ContentStatus) -> bool,
+        F2: FnMut(&S, E, ContentStatus),
+        F3: Fn(&S, &E) -> ContentStatus,
+    {
+        if self.cancelled {
+            return Err(ProtocolError::Cancelled.into());
+        }
+        if message.session_id() != self.session_id {
+            return Err(ProtocolError::SessionMismatch {
+                expected: self.session_id,
+                received: message.session_id(),
+            }
+            .into());
+        }
+        if self.drop_duplicate(&message) {
+            return Ok(());
+        }
+        message.validate(&self.message_limits)?;
+        self.check_filter(&message)?;
+        if let Some(seq) = message.sequence() {
+            if self
+                .last_sequence_received
+                .is_some_and(|last| seq > last + 1)
+            {
+                self.drifted = true;
+            }
+            self.last_sequence_received = Some(seq);
+        }
+        let started = *self.started.get_or_insert_with(Instant::now);
+        self.stats.record_received(&message);
+        let (rejected, truncated) = (self.stats.entries_rejected, self.truncated);
+        let response = self.apply(store, message, validate_cb, on_insert_cb, content_status_cb)?;
+        // entries that were not written are only exchanged again by a new reconciliation
+        if response.is_some()
+            || self.stats.entries_rejected > rejected
+            || self.truncated != truncated
+        {
+            self.drifted = true;
+        }
+        self.record_duration(started);
+        Ok(())
+    }
+
+    /// Forgets that the ranges inside or around `range` were reconciled, so that they are
+    /// reconciled again.
+    fn unsettle(&mut self, range: &Range<E::Key>) {
+        let overlaps =
+            |other: &Range<E::Key>| contains_range(range, other) || contains_range(other, range);
+        self.state.settled.retain(|(settled, _)| !overlaps(settled));
+        self.state
+            .unanswered
+            .retain(|unanswered| !overlaps(unanswered));
+        self.state
+            .settled_epochs
+            .retain(|(settled, _)| !overlaps(settled));
+        self.state.responded.forget(range);
+    }
+
+    /// Processes an incoming message and produces a response, see [`Store::process_message`].
+    ///
+    /// If responses are split, see [`SyncSession::with_max_message_bytes`], this returns the
+    /// next pending message, and the rest of the response is appended to the pending messages.
+    ///
+    /// Messages with [`Message::has_more`] set are not processed right away. Their parts are
+    /// kept, `None` is returned, and all parts are processed together with the next message
+    /// without the flag, which is answered with a single response. Update messages of the
+    /// remote are not answered either, see [`SyncSession::with_live_updates`].
+    ///
+    /// Returns [`ProtocolError::LimitExceeded`] if the message exceeds the [`ProtocolLimits`]
+    /// of the session. Exceeding the [`SessionLimits`] is not an error, see
+    /// [`SyncSession::truncated`]. Malformed messages are rejected with
+    /// [`ProtocolError::InvalidMessage`] before the store is accessed, see
+    /// [`Message::validate`].
+    ///
+    /// If the message cancels the sync, see [`SyncSession::cancel_message`], the session is
+    /// cancelled and `None` is returned. Returns [`ProtocolError::Cancelled`] if the session was
+    /// cancelled before.
+    ///
+    /// A session that did not send a message before becomes the responder, see
+    /// [`SyncSession::role`]. Returns [`ProtocolError::UnexpectedInitialMessage`] if the
+    /// initiator receives an initial message, and [`ProtocolError::SettledRange`] if the message
+    /// contains a part for a range that was settled before, as processing such messages would
+    /// transfer entries again.
+    pub fn process_message<S, F, F2, F3>(
+        &mut self,
+        store: &mut S,
+        message: Message<E>,
+        validate_cb: F,
+        on_insert_cb: F2,
+        content_status_cb: F3,
+    ) -> Result<Option<Message<E>>, SyncError<S::Error>>
+    where
+        S: Store<E>,
+        F: Fn(&S, &E, ContentStatus) -> bool,
+        F2: FnMut(&S, E, ContentStatus),
+        F3: Fn(&S, &E) -> ContentStatus,
+    {
+        let Some(message) = self.accumulate(message)? else {
+            return Ok(None);
+        };
+        if self.is_update(&message) {
+            self.apply_update(store, message, validate_cb, on_insert_cb, content_status_cb)?;
+            return Ok(None);
+        }
+        let received = self.receive(&message)?;
+        let started = *self.started.get_or_insert_with(Instant::now);
+        let response = match received {
+            Some(received) => {
+                let response =
+                    self.apply(store, message, validate_cb, on_insert_cb, content_status_cb)?;
+                self.respond(&received, response, true, true)
+            }
+            None => None,
+        };
+        self.record_duration(started);
+        Ok(response)
+    }
+
+    /// Processes an incoming message like [`SyncSession::process_message`], with the callbacks
+    /// set with [`SyncSession::with_validator`] and [`SyncSession::with_content_status`].
+    ///
+    /// Inserted entries are passed to the hook set with [`SyncSession::with_on_insert`].
+    pub fn process<S: Store<E>>(
+        &mut self,
+        store: &mut S,
+        message: Message<E>,
+    ) -> Result<Option<Message<E>>, SyncError<S::Error>> {
+        let validator = self.validator.take();
+        let content_status = self.content_status.take();
+        let res = self.process_message(
+            store,
+            message,
+            |_, entry, status| match &validator {
+                Some(validator) => validator(entry, status),
+                None => true,
+            },
+            |_, _, _| (),
+            |_, entry| {
+                content_status
+                    .as_ref()
+                    .map_or(ContentStatus::Complete, |f| f(entry))
+            },
+        );
+        self.validator = validator;
+        self.content_status = content_status;
+        res
+    }
+
+    /// Processes an incoming message like [`SyncSession::process_message`], but returns after
+    /// the parts of the message that fit into `budget`.
+    ///
+    /// Returns the response to the processed parts, and a [`Continuation`] if parts are left,
+    /// which has to be passed to [`SyncSession::continue_message`] until no continuation is
+    /// returned. At least one part is processed in each call. The responses of all calls
+    /// together contain the same parts as the response of [`SyncSession::process_message`],
+    /// in the same order, and each response should be sent to the remote right away.
+    pub fn process_message_with_budget<S, F, F2, F3>(
+        &mut self,
+        store: &mut S,
+        message: Message<E>,
+        budget: Budget,
+        validate_cb: F,
+        on_insert_cb: F2,
+        content_status_cb: F3,
+    ) -> Result<Budgeted<E>, SyncError<S::Error>>
+    where
+        S: Store<E>,
+        F: Fn(&S, &E, ContentStatus) -> bool,
+        F2: FnMut(&S, E, ContentStatus),
+        F3: Fn(&S, &E) -> ContentStatus,
+    {
+        let Some(message) = self.accumulate(message)? else {
+            return Ok((None, None));
+        };
+        let Some(received) = self.receive(&message)? else {
+            let started = *self.started.get_or_insert_with(Instant::now);
+            self.record_duration(started);
+            return Ok((None, None));
+        };
+        // items are processed before all other parts, as in `Store::process_message`
+        let (mut parts, rest): (VecDeque<_>, VecDeque<_>) = message
+            .parts
+            .into_iter()
+            .partition(MessagePart::is_range_item);
+        parts.extend(rest);
+        let continuation = Continuation {
+            parts,
+            received,
+            first: true,
+        };
+        self.continue_message(
+            store,
+            continuation,
+            budget,
+            validate_cb,
+            on_insert_cb,
+            content_status_cb,
+        )
+    }
+
+    /// Continues processing a message, see [`SyncSession::process_message_with_budget`].
+    pub fn continue_message<S, F, F2, F3>(
+        &mut self,
+        store: &mut S,
+        mut continuation: Continuation<E>,
+        budget: Budget,
+        validate_cb: F,
+        mut on_insert_cb: F2,
+        content_status_cb: F3,
+    ) -> Result<Budgeted<E>, SyncError<S::Error>>
+    where
+        S: Store<E>,
+        F: Fn(&S, &E, ContentStatus) -> bool,
+        F2: FnMut(&S, E, ContentStatus),
+        F3: Fn(&S, &E) -> ContentStatus,
+    {
+        if self.cancelled {
+            return Err(ProtocolError::Cancelled.into());
+        }
+        let started = *self.started.get_or_insert_with(Instant::now);
+        let mut out = Vec::new();
+        let mut processed = 0;
+        while let Some(part) = continuation.parts.pop_front() {
+            let message = Message {
+                parts: vec![part],
+                more: false,
+            };
+            let response = self.apply(
+                store,
+                message,
+                &validate_cb,
+                &mut on_insert_cb,
+                &content_status_cb,
+            )?;
+            if let Some(response) = response {
+                out.extend(response.parts);
+            }
+            processed += 1;
+            if budget.is_exhausted(processed) {
+                break;
+            }
+        }
+        let last = continuation.parts.is_empty();
+        let response = (!out.is_empty()).then_some(Message {
+            parts: out,
+            more: false,
+        });
+        let response = self.respond(&continuation.received, response, continuation.first, last);
+        continuation.first = false;
+        self.record_duration(started);
+        Ok((response, (!last).then_some(continuation)))
+    }
+
+    /// Queues an incoming message to be processed with [`SyncSession::step`].
+    ///
+    /// Messages are processed in the order they were queued. Errors of the message are returned
+    /// from the step that processes it.
+    pub fn enqueue(&mut self, message: Message<E>) {
+        self.state.queued.push_back(message);
+    }
+
+    /// Processes one part of the queued messages, see [`SyncSession::enqueue`], with the
+    /// callbacks set with [`SyncSession::with_validator`] and
+    /// [`SyncSession::with_content_status`].
+    ///
+    /// This allows a scheduler to interleave the work on several sessions, or to yield to other
+    /// tasks between parts. Returns [`ControlFlow::Continue`] with the progress while parts of
+    /// the current message are left, and [`ControlFlow::Break`] with the response, as returned
+    /// from [`SyncSession::process`], once the message is processed. Also breaks with `None` if
+    /// no message is queued, or if the message needs no processing, e.g. if more messages of
+    /// its round are expected.
+    ///
+    /// Stepping through a message results in the same response and store as processing it with
+    /// [`SyncSession::process`]. A step processes the part and the parts it is split into, so
+    /// the number of steps of a message does not depend on the contents of the store.
+    pub fn step<S: Store<E>>(&mut self, store: &mut S) -> Result<Step<E>, SyncError<S::Error>> {
+        let validator = self.validator.take();
+        let content_status = self.content_status.take();
+        self.observe_epoch(store);
+        let res = self.step_with(
+            store,
+            |_, entry, status| match &validator {
+                Some(validator) => validator(entry, status),
+                None => true,
+            },
+            |_, entry| {
+                content_status
+                    .as_ref()
+                    .map_or(ContentStatus::Complete, |f| f(entry))
+            },
+        );
+        self.validator = validator;
+        self.content_status = content_status;
+        self.state.epoch = Some(store.epoch());
+        if res.is_err() {
+            self.state.stepping = None;
+        }
+        res
+    }
+
+    /// Processes one part of the queued messages, see [`SyncSession::step`].
+    fn step_with<S, F, F3>(
+        &mut self,
+        store: &mut S,
+        validate_cb: F,
+        content_status_cb: F3,
+    ) -> Result<Step<E>, SyncError<S::Error>>
+    where
+        S: Store<E>,
+        F: Fn(&S, &E, ContentStatus) -> bool,
+        F3: Fn(&S, &E) -> ContentStatus,
+    {
+        let mut stepping = match self.state.stepping.take() {
+            Some(stepping) => stepping,
+            None => {
+                let Some(message) = self.state.queued.pop_front() else {
+                    return Ok(ControlFlow::Break(None));
+                };
+                let Some(message) = self.accumulate(message)? else {
+                    return Ok(ControlFlow::Break(None));
+                };
+                let received = self.receive(&message)?;
+                let started = *self.started.get_or_insert_with(Instant::now);
+                let Some(received) = received else {
+                    self.record_duration(started);
+                    return Ok(ControlFlow::Break(None));
+                };
+                let applying = self
+                    .prepare(store, message, &content_status_cb)
+                    .map_err(SyncError::Store)?;
+                Stepping {
+                    received,
+                    applying,
+                    processed: 0,
+                }
+            }
+        };
+        let started = *self.started.get_or_insert_with(Instant::now);
+        if let Some(part) = stepping.applying.parts.pop_front() {
+            let message = Message {
+                parts: vec![part],
+                more: false,
+            };
+            self.apply_parts(
+                store,
+                &mut stepping.applying,
+                message,
+                validate_cb,
+                |_, _, _| (),
+                content_status_cb,
+            )?;
+            stepping.processed += 1;
+        }
+        let remaining = stepping.applying.parts.len();
+        if remaining > 0 {
+            let progress = StepProgress {
+                processed: stepping.processed,
+                remaining,
+            };
+            self.state.stepping = Some(stepping);
+            self.record_duration(started);
+            return Ok(ControlFlow::Continue(progress));
+        }
+        let response = self.finish(store, stepping.applying)?;
+        let response = self.respond(&stepping.received, response, true, true);
+        self.record_duration(started);
+        Ok(ControlFlow::Break(response))
+    }
+
+    /// Collects the parts of messages that have more messages following, see
+    /// [`Message::has_more`], and returns them together with the parts of the next message
+    /// without the flag.
+    ///
+    /// The session id and sequence number of the last message of a round apply to the whole
+    /// round, and those of the other messages are dropped. Returns `None` while more messages
+    /// are expected.
+    fn accumulate(&mut self, message: Message<E>) -> Result<Option<Message<E>>, ProtocolError> {
+        if !message.more && self.state.incoming.is_empty() {
+            return Ok(Some(message));
+        }
+        if self.cancelled {
+            return Err(ProtocolError::Cancelled);
+        }
+        if !message.more {
+            let mut parts = std::mem::take(&mut self.state.incoming);
+            parts.extend(message.parts);
+            return Ok(Some(Message { parts, more: false }));
+        }
+        if self.drop_duplicate(&message) {
+            return Ok(None);
+        }
+        let parts = self.state.incoming.len() + message.parts.len();
+        let max = self.message_limits.max_parts;
+        if parts > max {
+            self.state.incoming.clear();
+            return Err(MessageValidationError::TooManyParts { parts, max }.into());
+        }
+        if let Some(seq) = message.sequence() {
+            self.last_sequence_received = Some(seq);
+        }
+        let parts = message
+            .parts
+            .into_iter()
+            .filter(|part| !matches!(part, MessagePart::SessionId(_) | MessagePart::Sequence(_)));
+        self.state.incoming.extend(parts);
+        Ok(None)
+    }
+
+    /// Checks `message`, see [`SyncSession::check_limits`], and records that it was received.
+    ///
+    /// Returns the ranges of the message, or `None` if the message cancels the session.
+    fn receive(&mut self, message: &Message<E>) -> Result<Option<Received<E::Key>>, ProtocolError> {
+        if self.drop_stale(message) || self.drop_duplicate(message) {
+            return Ok(None);
+        }
+        let fingerprints = self.check_limits(message)?;
+        self.check_filter(message)?;
+        if let Some(seq) = message.sequence() {
+            self.last_sequence_received = Some(seq);
+        }
+        let depth = fingerprints
+            .iter()
+            .map(|(_, depth)| *depth)
+            .max()
+            .unwrap_or(0);
+        if self.state.role.is_none() && self.session_id.is_none() {
+            self.session_id = message.session_id();
+        }
+        self.state.role.get_or_insert(Role::Responder);
+        self.rounds += 1;
+        self.fingerprint_parts += fingerprints.len();
+        self.ranges += message.parts().iter().filter_map(part_range).count();
+        self.depth = self.depth.max(depth);
+        self.stats.record_received(message);
+        if message.is_cancel() {
+            self.cancel();
+            return Ok(None);
+        }
+        if message.is_done() {
+            self.state.done_received = true;
+        }
+        let summary = message.parts().iter().find_map(|part| match part {
+            MessagePart::StoreSummary(summary) => Some(summary),
+            _ => None,
+        });
+        if let Some(summary) = summary {
+            self.remote_summary = Some(summary.clone());
+        }
+        if message.is_reopen() {
+            for (range, _) in &fingerprints {
+                self.unsettle(range);
+            }
+        }
+        let parts = part_ranges(message, &self.state.sent);
+        Ok(Some(Received {
+            parts,
+            fingerprints,
+        }))
+    }
+
+    /// Processes `message` with the store, and enforces the [`SessionLimits`].
+    fn apply<S, F, F2, F3>(
+        &mut self,
+        store: &mut S,
+        message: Message<E>,
+        validate_cb: F,
+        on_insert_cb: F2,
+        content_status_cb: F3,
+    ) -> Result<Option<Message<E>>, SyncError<S::Error>>
+    where
+        S: Store<E>,
+        F: Fn(&S, &E, ContentStatus) -> bool,
+        F2: FnMut(&S, E, ContentStatus),
+        F3: Fn(&S, &E) -> ContentStatus,
+    {
+        self.observe_epoch(store);
+        let mut applying = self
+            .prepare(store, message, &content_status_cb)
+            .map_err(SyncError::Store)?;
+        let message = Message {
+            parts: applying.parts.drain(..).collect(),
+            more: false,
+        };
+        self.apply_parts(
+            store,
+            &mut applying,
+            message,
+            validate_cb,
+            on_insert_cb,
+            content_status_cb,
+        )?;
+        let response = self.finish(store, applying);
+        self.state.epoch = Some(store.epoch());
+        response
+    }
+
+    /// Handles the parts of `message` that are not processed with the store, and returns the
+    /// others, see [`SyncSession::apply`].
+    fn prepare<S, F3>(
+        &mut self,
+        store: &mut S,
+        message: Message<E>,
+        content_status_cb: F3,
+    ) -> Result<Applying<E>, S::Error>
+    where
+        S: Store<E>,
+        F3: Fn(&S, &E) -> ContentStatus,
+    {
+        // the summary is taken before the entries of the message are written
+        let summary = match self.responds_with_summary() {
+            true => Some(store.store_summary()?),
+            false => None,
+        };
+        let (message, mut lazy_response) = self.apply_lazy(store, message, content_status_cb)?;
+        if let Some(summary) = summary {
+            self.local_summary = Some(summary.clone());
+            lazy_response.push(MessagePart::StoreSummary(summary));
+        }
+        let (message, deferred) = self.defer_values(message);
+        // items are processed before all other parts, as in `Store::process_message`
+        let (mut parts, rest): (VecDeque<_>, VecDeque<_>) = message
+            .parts
+            .into_iter()
+            .partition(MessagePart::is_range_item);
+        parts.extend(rest);
+        Ok(Applying {
+            parts,
+            reply: Vec::new(),
+            lazy_response,
+            deferred,
+        })
+    }
+
+    /// Processes `message`, which holds parts of `applying`, with the store, and adds the reply
+    /// to `applying`.
+    fn apply_parts<S, F, F2, F3>(
+        &mut self,
+        store: &mut S,
+        applying: &mut Applying<E>,
+        message: Message<E>,
+        validate_cb: F,
+        mut on_insert_cb: F2,
+        content_status_cb: F3,
+    ) -> Result<(), SyncError<S::Error>>
+    where
+        S: Store<E>,
+        F: Fn(&S, &E, ContentStatus) -> bool,
+        F2: FnMut(&S, E, ContentStatus),
+        F3: Fn(&S, &E) -> ContentStatus,
+    {
+        // the keys of received entries that are in the store, to tell apart new entries from
+        // overwritten ones
+        let mut existing = BTreeSet::new();
+        for (entry, _) in message.values() {
+            if store.get(entry.key()).map_err(SyncError::Store)?.is_some() {
+                existing.insert(entry.key().clone());
+            }
+        }
+        let mut inserted = 0;
+        let mut overwritten = 0;
+        let on_insert = &mut self.on_insert;
+        let events = &self.events;
+        let deferred_downloads = &mut self.deferred_downloads;
+        let on_insert_cb = |store: &S, entry: E, content_status| {
+            let kind = match existing.contains(entry.key()) {
+                true => {
+                    overwritten += 1;
+                    InsertKind::Updated
+                }
+                false => {
+                    inserted += 1;
+                    InsertKind::New
+                }
+            };
+            deferred_downloads.remove(entry.key());
+            if let Some(on_insert) = on_insert {
+                on_insert(&entry, kind);
+            }
+            emit(events, || SyncEvent::EntryInserted(entry.clone()));
+            on_insert_cb(store, entry, content_status)
+        };
+
+        let entries_received = Cell::new(self.entries_received);
+        let bytes_received = Cell::new(self.bytes_received);
+        let truncated = Cell::new(self.truncated);
+        let rejected = Cell::new(0);
+        let session_limits = self.session_limits;
+        let size_of = &self.size_of;
+        let reject = |entry: &E, reason| {
+            emit(events, || SyncEvent::EntryRejected {
+                key: entry.key().clone(),
+                reason,
+            })
+        };
+        let expiry = self.expiry.as_ref().map(|expiry| (expiry, expiry.now()));
+        let filter = self.filter.as_ref();
+        let download_policy = self.download_policy.as_ref().map(|(policy, _)| policy);
+        let deferred = RefCell::new(Vec::new());
+        let validate_cb = |store: &S, entry: &E, content_status| {
+            if let Some(limit) = truncated.get() {
+                reject(entry, RejectReason::Limit(limit));
+                return false;
+            }
+            if filter.is_some_and(|filter| !filter.matches(entry.key())) {
+                rejected.set(rejected.get() + 1);
+                reject(entry, RejectReason::Filtered);
+                return false;
+            }
+            if let Some((expiry, now)) = expiry {
+                if expiry.is_expired_at(entry, now) {
+                    rejected.set(rejected.get() + 1);
+                    reject(entry, RejectReason::Expired);
+                    return false;
+                }
+            }
+            if !validate_cb(store, entry, content_status) {
+                rejected.set(rejected.get() + 1);
+                reject(entry, RejectReason::Validation);
+                return false;
+            }
+            match download_policy.map(|policy| policy(entry)) {
+                None | Some(DownloadDecision::Inline) => {}
+                Some(DownloadDecision::Defer) => {
+                    let fingerprint = entry.as_fingerprint();
+                    deferred
+                        .borrow_mut()
+                        .push((entry.key().clone(), fingerprint));
+                    reject(entry, RejectReason::Deferred);
+                    return false;
+                }
+                Some(DownloadDecision::Skip) => {
+                    rejected.set(rejected.get() + 1);
+                    reject(entry, RejectReason::Validation);
+                    return false;
+                }
+            }
+            let entries = entries_received.get() + 1;
+            let bytes = bytes_received.get() + size_of(entry);
+            let exceeded = match session_limits {
+                SessionLimits {
+                    max_entries_received: Some(max),
+                    ..
+                } if entries > max => Some(Limit::EntriesReceived(max)),
+                SessionLimits {
+                    max_bytes_received: Some(max),
+                    ..
+                } if bytes > max => Some(Limit::BytesReceived(max)),
+                _ => None,
+            };
+            if let Some(limit) = exceeded {
+                truncated.set(exceeded);
+                reject(entry, RejectReason::Limit(limit));
+                return false;
+            }
+            entries_received.set(entries);
+            bytes_received.set(bytes);
+            true
+        };
+        let transform = &self.transform;
+        let key_changed = RefCell::new(None);
+        let transform_cb = |entry: E| {
+            let Some(transform) = transform else {
+                return Some(entry);
+            };
+            if key_changed.borrow().is_some() {
+                return None;
+            }
+            let key = entry.key().clone();
+            let transformed = transform(entry)?;
+            if transformed.key() != &key {
+                *key_changed.borrow_mut() = Some((key, transformed.key().clone()));
+                return None;
+            }
+            Some(transformed)
+        };
+        let mut options = ProcessOptions::default().with_transform(&transform_cb);
+        if let Some(send_threshold) = &self.send_threshold {
+            options = options.with_send_threshold(send_threshold);
+        }
+        let response = store.process_message_with_options(
+            &self.config,
+            message,
+            options,
+            validate_cb,
+            on_insert_cb,
+            content_status_cb,
+        );
+        self.entries_received = entries_received.get();
+        self.bytes_received = bytes_received.get();
+        self.truncated = truncated.get();
+        self.stats.entries_rejected += rejected.get();
+        self.stats.entries_inserted += inserted;
+        self.stats.entries_overwritten += overwritten;
+        for (key, fingerprint) in deferred.into_inner() {
+            if self.deferred_downloads.insert(key.clone(), fingerprint) == Some(fingerprint) {
+                continue;
+            }
+            if let Some((_, on_defer)) = self.download_policy.as_mut() {
+                on_defer(&key, fingerprint);
+            }
+        }
+        let outcome = response?;
+        self.stats.degenerate_ranges += outcome.degenerate.len();
+        let max = self.limits.max_responded_fingerprints;
+        for (RangeFingerprint { range, fingerprint }, local) in &outcome.mismatched {
+            if self
+                .state
+                .responded
+                .insert(range, *fingerprint, *local, max)
+            {
+                return Err(ProtocolError::NoProgress {
+                    range: format!("{:?}", range),
+                }
+                .into());
+            }
+        }
+        if let Some((key, transformed_key)) = key_changed.into_inner() {
+            return Err(SyncError::Transform(TransformError {
+                key: format!("{:?}", key),
+                transformed_key: format!("{:?}", transformed_key),
+            }));
+        }
+        if !outcome.divergent.is_empty() {
+            self.divergent.extend(outcome.divergent);
+            self.divergent.sort_by(|a, b| a.x().cmp(b.x()));
+        }
+        applying
+            .reply
+            .extend(outcome.reply.into_iter().flat_map(Message::into_parts));
+        Ok(())
+    }
+
+    /// Returns the response to a message once all its parts were processed, see
+    /// [`SyncSession::apply`].
+    fn finish<S: Store<E>>(
+        &mut self,
+        store: &mut S,
+        applying: Applying<E>,
+    ) -> Result<Option<Message<E>>, SyncError<S::Error>> {
+        let Applying {
+            parts: _,
+            mut reply,
+            mut lazy_response,
+            deferred,
+        } = applying;
+        // the replies of parts processed one by one are ordered like a single reply
+        reply.sort_by(|a, b| reply_order(a).cmp(&reply_order(b)));
+        let mut response = (!reply.is_empty()).then_some(Message {
+            parts: reply,
+            more: false,
+        });
+        if !deferred.is_empty() {
+            let reopen_version = MessagePart::<E>::Reopen.min_version();
+            if self
+                .version
+                .map_or(true, |version| version >= reopen_version)
+            {
+                // the remote answers with the deferred values, even if it settled their ranges
+                lazy_response.push(MessagePart::Reopen);
+                for range in deferred {
+                    self.state.responded.forget(&range);
+                    let fingerprint = store.get_fingerprint(&range).map_err(SyncError::Store)?;
+                    lazy_response.push(MessagePart::RangeFingerprint(RangeFingerprint {
+                        range,
+                        fingerprint,
+                    }));
+                }
+            } else if let Some(max) = self.session_limits.max_pending_value_bytes {
+                self.truncated.get_or_insert(Limit::PendingValueBytes(max));
+            }
+        }
+        if self.sends_lazy_values() {
+            response = response.map(|response| Message {
+                parts: response.parts.into_iter().map(into_lazy).collect(),
+                more: response.more,
+            });
+        }
+        if !lazy_response.is_empty() {
+            response
+                .get_or_insert_with(|| Message {
+                    parts: Vec::new(),
+                    more: false,
+                })
+                .parts
+                .extend(lazy_response);
+        }
+        Ok(response)
+    }
+
+    /// Removes the values of `message` beyond [`SessionLimits::max_pending_value_bytes`], and
+    /// returns the message to process with the store, together with the ranges of the removed
+    /// values.
+    ///
+    /// Values are taken in the order of the parts, and within a part in the order of its range.
+    /// An item whose values are only partly taken is cut down to the range of these values.
+    fn defer_values(&mut self, message: Message<E>) -> (Message<E>, Vec<Range<E::Key>>) {
+        let Some(max) = self.session_limits.max_pending_value_bytes else {
+            return (message, Vec::new());
+        };
+        if message.is_cancel() {
+            return (message, Vec::new());
+        }
+        let mut pending = 0;
+        let mut taken = false;
+        let mut full = false;
+        let mut deferred = Vec::new();
+        let mut parts = Vec::with_capacity(message.parts.len());
+        for part in message.parts {
+            let MessagePart::RangeItem(mut item) = part else {
+                parts.push(part);
+                continue;
+            };
+            // the keys from the start of the range up to the end of the key space come first
+            let x = item.range.x().clone();
+            item.values
+                .sort_by(|(a, _), (b, _)| (a.key() < &x, a.key()).cmp(&(b.key() < &x, b.key())));
+            let mut split = item.values.len();
+            for (i, (entry, _)) in item.values.iter().enumerate() {
+                let size = (self.size_of)(entry);
+                if full || (taken && pending + size > max) {
+                    full = true;
+                    split = i;
+                    break;
+                }
+                pending += size;
+                taken = true;
+            }
+            if split == item.values.len() {
+                parts.push(MessagePart::RangeItem(item));
+                continue;
+            }
+            self.stats.entries_deferred += item.values.len() - split;
+            if split == 0 {
+                deferred.push(item.range);
+                continue;
+            }
+            let key = item.values[split].0.key().clone();
+            deferred.push(Range::new(key.clone(), item.range.y().clone()));
+            item.values.truncate(split);
+            item.range = Range::new(x, key);
+            parts.push(MessagePart::RangeItem(item));
+        }
+        let message = Message {
+            parts,
+            more: message.more,
+        };
+        (message, deferred)
+    }
+
+    /// Handles the parts of `message` for lazy value transfer, see
+    /// [`SyncSession::with_lazy_values`], and returns the message to process with the store,
+    /// together with the parts to add to the response.
+    ///
+    /// Value responses are turned into range items, so their entries are validated and written
+    /// like all other received entries. Requests for keys, see [`SyncSession::request_keys`],
+    /// are answered here as well.
+    fn apply_lazy<S, F3>(
+        &mut self,
+        store: &mut S,
+        message: Message<E>,
+        content_status_cb: F3,
+    ) -> Result<Lazy<E>, S::Error>
+    where
+        S: Store<E>,
+        F3: Fn(&S, &E) -> ContentStatus,
+    {
+        let direction = self.config.direction();
+        let mut parts = Vec::with_capacity(message.parts.len());
+        let mut response = Vec::new();
+        let mut requested = Vec::new();
+        // with an empty store, the remote is asked for all of its entries at once
+        let send_all_budget = match self.send_all_budget() {
+            Some(max) if is_initial(&message) && store.is_empty()? => Some(max),
+            _ => None,
+        };
+        for part in message.parts {
+            match part {
+                MessagePart::RangeFingerprint(fp)
+                    if fp.range.is_all() && fp.fingerprint != Fingerprint::empty() =>
+                {
+                    match send_all_budget {
+                        Some(max_bytes) => response.push(MessagePart::SendAll {
+                            range: fp.range,
+                            max_bytes: max_bytes as u64,
+                        }),
+                        None => parts.push(MessagePart::RangeFingerprint(fp)),
+                    }
+                }
+                MessagePart::SendAll { range, max_bytes } => {
+                    self.remote_max_message_bytes =
+                        Some(usize::try_from(max_bytes).unwrap_or(usize::MAX));
+                    match direction {
+                        // a receive-only session sends no entries, so it has none to offer
+                        SyncDirection::ReceiveOnly => {
+                            response.push(MessagePart::RangeItem(RangeItem {
+                                range,
+                                values: Vec::new(),
+                                have_local: true,
+                            }))
+                        }
+                        _ => response.extend(self.send_all(store, range, &content_status_cb)?),
+                    }
+                }
+                MessagePart::ValueResponse(values) => {
+                    if let Some((entry, _)) = values.first() {
+                        let key = entry.key().clone();
+                        parts.push(MessagePart::RangeItem(RangeItem {
+                            range: Range::new(key.clone(), key),
+                            values,
+                            have_local: true,
+                        }));
+                    }
+                }
+                MessagePart::ValueRequest(keys) => {
+                    if direction == SyncDirection::ReceiveOnly {
+                        continue;
+                    }
+                    let mut values = Vec::new();
+                    for key in keys.into_iter().filter(|key| self.is_selected(key)) {
+                        if let Some(entry) = store.get(&key)? {
+                            let content_status = content_status_cb(store, &entry);
+                            values.push((entry, content_status));
+                        }
+                    }
+                    response.push(MessagePart::ValueResponse(values));
+                }
+                MessagePart::WantKeys { keys } => {
+                    let mut values = Vec::new();
+                    let mut missing = Vec::new();
+                    for key in keys {
+                        // a receive-only session sends no entries, so it has none to offer
+                        let entry = match direction {
+                            SyncDirection::ReceiveOnly => None,
+                            _ if !self.is_selected(&key) => None,
+                            _ => store.get(&key)?,
+                        };
+                        match entry {
+                            Some(entry) => {
+                                let content_status = content_status_cb(store, &entry);
+                                values.push((entry, content_status));
+                            }
+                            None => missing.push(key),
+                        }
+                    }
+                    if !values.is_empty() {
+                        response.extend(self.value_responses(values));
+                    }
+                    if !missing.is_empty() {
+                        response.push(MessagePart::MissingKeys { keys: missing });
+                    }
+                }
+                MessagePart::MissingKeys { keys } => self.missing_keys.extend(keys),
+                MessagePart::LazyRangeItem(item) => {
+                    if direction != SyncDirection::SendOnly {
+                        for (key, fingerprint) in &item.values {
+                            if !item.range.contains(key) || !self.is_selected(key) {
+                                continue;
+                            }
+                            let local = store.get(key)?;
+                            if local.is_some_and(|entry| entry.as_fingerprint() == *fingerprint) {
+                                continue;
+                            }
+                            // deferred entries are downloaded by the caller
+                            if self.deferred_downloads.get(key) == Some(fingerprint) {
+                                continue;
+                            }
+                            match self.on_missing_value.as_mut() {
+                                Some(on_missing_value) => on_missing_value(key, *fingerprint),
+                                None => requested.push(key.clone()),
+                            }
+                        }
+                    }
+                    // without sending local values, the remote's values are never answered
+                    if item.have_local || direction == SyncDirection::ReceiveOnly {
+                        continue;
+                    }
+                    let mut values = Vec::new();
+                    for entry in store.get_range(item.range.clone())? {
+                        let entry = entry?;
+                        let fingerprint = entry.as_fingerprint();
+                        if !item.values.contains(&(entry.key().clone(), fingerprint)) {
+                            values.push((entry.key().clone(), fingerprint));
+                        }
+                    }
+                    if !values.is_empty() {
+                        response.push(MessagePart::LazyRangeItem(LazyRangeItem {
+                            range: item.range,
+                            values,
+                            have_local: true,
+                        }));
+                    }
+                }
+                part => parts.push(part),
+            }
+        }
+        if !requested.is_empty() {
+            response.push(MessagePart::ValueRequest(requested));
+        }
+        Ok((Message { parts, more: false }, response))
+    }
+
+    /// Records `response` as sent, and returns the next message to send, see
+    /// [`SyncSession::poll_pending_message`].
+    ///
+    /// A response to a message can be the concatenation of several responses, see
+    /// [`SyncSession::continue_message`], of which `first` replaces the ranges of the previous
+    /// response, and after the `last` the received ranges are settled.
+    fn respond(
+        &mut self,
+        received: &Received<E::Key>,
+        response: Option<Message<E>>,
+        first: bool,
+        last: bool,
+    ) -> Option<Message<E>> {
+        let sent = match &response {
+            Some(response) => part_ranges(response, &received.fingerprints),
+            None => Vec::new(),
+        };
+        let awaiting = response.as_ref().map(awaiting_ranges).unwrap_or_default();
+        let reconciling = response
+            .as_ref()
+            .map(reconciling_ranges)
+            .unwrap_or_default();
+        if first {
+            // the remote settled the ranges of our last message that it did not respond to
+            let answered: BTreeSet<_> = received
+                .parts
+                .iter()
+                .flat_map(|(range, _)| parent_indices(&self.state.sent, range))
+                .collect();
+            let unanswered: Vec<_> = std::mem::take(&mut self.state.sent)
+                .into_iter()
+                .enumerate()
+                .filter(|(i, _)| !answered.contains(i))
+                .map(|(_, (range, _))| range)
+                .collect();
+            // the remote compared them with the fingerprints we sent
+            let sent_moves = self.sent_moves;
+            self.state
+                .settled_epochs
+                .extend(unanswered.iter().map(|range| (range.clone(), sent_moves)));
+            self.state.unanswered.extend(unanswered);
+            self.state.sent = sent;
+            self.sent_moves = self.epoch_moves;
+            self.state.awaiting = awaiting;
+            self.state.reconciling = reconciling;
+        } else {
+            self.state.sent.extend(sent);
+            self.state.sent.sort_by(|(a, _), (b, _)| a.x().cmp(b.x()));
+            self.state.awaiting.extend(awaiting);
+            self.state.awaiting.sort_by(|a, b| a.x().cmp(b.x()));
+            self.state.reconciling.extend(reconciling);
+            self.state.reconciling.sort_by(|a, b| a.x().cmp(b.x()));
+        }
+        if self.range_ids {
+            // responses to earlier messages may still arrive
+            self.state.awaiting.extend(
+                self.state
+                    .outstanding
+                    .values()
+                    .filter_map(|(range, _)| range.clone()),
+            );
+            self.state
+                .awaiting
+                .sort_by(|a, b| a.x().cmp(b.x()).then_with(|| a.y().cmp(b.y())));
+            self.state.awaiting.dedup();
+        }
+        if last {
+            // the received ranges that contain no range we expect a response for are settled
+            let open: BTreeSet<_> = self
+                .state
+                .awaiting
+                .iter()
+                .flat_map(|range| parent_indices(&received.parts, range))
+                .collect();
+            let settled: Vec<_> = received
+                .parts
+                .iter()
+                .enumerate()
+                .filter(|(i, _)| !open.contains(i))
+                .map(|(_, range)| range.clone())
+                .collect();
+            for (range, _) in &settled {
+                emit(&self.events, || SyncEvent::RangeSettled(range.clone()));
+                self.state
+                    .settled_epochs
+                    .push((range.clone(), self.epoch_moves));
+            }
+            self.state.settled.extend(settled);
+            self.state
+                .settled
+                .sort_by(|(a, _), (b, _)| a.x().cmp(b.x()));
+        }
+        // with nothing left to ask, we tell the remote that we are done, once
+        let mut response = response;
+        let requesting = response.as_ref().is_some_and(|response| {
+            response
+                .parts()
+                .iter()
+                .any(|part| matches!(part, MessagePart::ValueRequest(_)))
+        });
+        if last
+            && !self.state.done_sent
+            && self.state.awaiting.is_empty()
+            && self.state.outstanding.is_empty()
+            && !requesting
+            && self.supports_done()
+        {
+            response
+                .get_or_insert_with(|| Message {
+                    parts: Vec::new(),
+                    more: false,
+                })
+                .parts
+                .push(MessagePart::Done);
+            self.state.done_sent = true;
+        }
+        if let Some(response) = response.as_mut() {
+            prioritize(&mut response.parts, &self.priority_ranges);
+        }
+        let messages = match (response, self.message_budget()) {
+            (Some(response), Some((max, part_size))) => {
+                let response = self.split_oversized(response, max, part_size);
+                split_message(response, max, part_size)
+            }
+            (Some(response), None) => vec![response],
+            (None, _) => Vec::new(),
+        };
+        let min_version = MessagePart::<E>::More.min_version();
+        let messages = match self.page_size {
+            Some(page_size) if self.version.map_or(true, |v| v >= min_version) => messages
+                .into_iter()
+                .flat_map(|message| message.paginate(page_size))
+                .collect(),
+            _ => messages,
+        };
+        let mut messages = messages;
+        if last && messages.is_empty() && self.sends_range_ids() && !self.responding_to.0.is_empty()
+        {
+            // the remote keeps its parts outstanding until the last message of the response
+            messages.push(Message {
+                parts: Vec::new(),
+                more: false,
+            });
+        }
+        if first {
+            self.last_response.clear();
+        }
+        let count = messages.len();
+        for (i, mut message) in messages.into_iter().enumerate() {
+            self.stamp_piece(&mut message, Some(last && i + 1 == count));
+            if self.next_sequence.is_some() {
+                self.last_response.push(message.clone());
+            }
+            self.state.pending.push_back(message);
+        }
+        self.poll_pending_message()
+    }
+
+    /// Processes an incoming message like [`SyncSession::process_message`], with an async
+    /// validate callback.
+    ///
+    /// The entries of the message are validated first, one after the other in message order,
+    /// each distinct entry once, and the message is then processed with the results. So the
+    /// entries sent back to the remote are computed against the store before any entry of the
+    /// message is inserted, just as with a sync validate callback.
+    ///
+    /// The future returned from `validate_cb` cannot borrow from the arguments of the callback.
+    pub async fn process_message_with_async_validator<S, F, Fut, F2, F3>(
+        &mut self,
+        store: &mut S,
+        message: Message<E>,
+        validate_cb: F,
+        on_insert_cb: F2,
+        content_status_cb: F3,
+    ) -> Result<Option<Message<E>>, SyncError<S::Error>>
+    where
+        S: Store<E>,
+        F: Fn(&S, &E, ContentStatus) -> Fut,
+        Fut: Future<Output = bool>,
+        F2: FnMut(&S, E, ContentStatus),
+        F3: Fn(&S, &E) -> ContentStatus,
+    {
+        let Some(message) = self.accumulate(message)? else {
+            return Ok(None);
+        };
+        // don't validate messages that are rejected or dropped anyways
+        if self.drop_duplicate(&message) {
+            return Ok(None);
+        }
+        self.check_limits(&message)?;
+        let mut verdicts = Verdicts::new();
+        for (entry, content_status) in entries_to_validate(&self.config, &message) {
+            if verdicts.get(entry, content_status).is_none() {
+                let valid = validate_cb(store, entry, content_status).await;
+                verdicts.insert(entry, content_status, valid);
+            }
+        }
+        self.process_validated(store, message, verdicts, on_insert_cb, content_status_cb)
+    }
+
+    /// Processes an incoming message like [`SyncSession::process_message`], with a fallible
+    /// validate callback.
+    ///
+    /// The entries of the message are validated first, in message order, each distinct entry
+    /// once. If the callback fails for an entry, [`SyncError::Validation`] is returned for it,
+    /// and the store is not changed.
+    pub fn try_process_message<S, F, F2, F3>(
+        &mut self,
+        store: &mut S,
+        message: Message<E>,
+        validate_cb: F,
+        on_insert_cb: F2,
+        content_status_cb: F3,
+    ) -> Result<Option<Message<E>>, SyncError<S::Error>>
+    where
+        S: Store<E>,
+        F: Fn(&S, &E, ContentStatus) -> anyhow::Result<bool>,
+        F2: FnMut(&S, E, ContentStatus),
+        F3: Fn(&S, &E) -> ContentStatus,
+    {
+        let Some(message) = self.accumulate(message)? else {
+            return Ok(None);
+        };
+        if self.drop_duplicate(&message) {
+            return Ok(None);
+        }
+        self.check_limits(&message)?;
+        let mut verdicts = Verdicts::new();
+        for (entry, content_status) in entries_to_validate(&self.config, &message) {
+            if verdicts.get(entry, content_status).is_some() {
+                continue;
+            }
+            match validate_cb(store, entry, content_status) {
+                Ok(valid) => verdicts.insert(entry, content_status, valid),
+                Err(source) => {
+                    return Err(SyncError::Validation(ValidationError {
+                        key: format!("{:?}", entry.key()),
+                        source,
+                    }))
+                }
+            }
+        }
+        self.process_validated(store, message, verdicts, on_insert_cb, content_status_cb)
+    }
+
+    /// Processes a message whose entries were already validated, see
+    /// [`entries_to_validate`].
+    fn process_validated<S, F2, F3>(
+        &mut self,
+        store: &mut S,
+        message: Message<E>,
+        verdicts: Verdicts<E>,
+        on_insert_cb: F2,
+        content_status_cb: F3,
+    ) -> Result<Option<Message<E>>, SyncError<S::Error>>
+    where
+        S: Store<E>,
+        F2: FnMut(&S, E, ContentStatus),
+        F3: Fn(&S, &E) -> ContentStatus,
+    {
+        let validate_cb =
+            |_: &S, entry: &E, content_status| verdicts.get(entry, content_status).unwrap_or(false);
+        self.process_message(store, message, validate_cb, on_insert_cb, content_status_cb)
+    }
+
+    /// Checks that the session was not cancelled, that `message` is well-formed, and that
+    /// processing it stays within the [`ProtocolLimits`], and returns the
+    /// ranges of its fingerprint parts, with their depth.
+    fn check_limits(&self, message: &Message<E>) -> Result<DepthRanges<E::Key>, ProtocolError> {
+        if self.cancelled {
+            return Err(ProtocolError::Cancelled);
+        }
+        // a responder without an id takes the id of the first message
+        let adopt_id = self.state.role.is_none() && self.session_id.is_none();
+        if !adopt_id && message.session_id() != self.session_id {
+            return Err(ProtocolError::SessionMismatch {
+                expected: self.session_id,
+                received: message.session_id(),
+            });
+        }
+        let limits = self.limits;
+        if self.rounds >= limits.max_rounds {
+            return Err(ProtocolError::LimitExceeded(Limit::Rounds(
+                limits.max_rounds,
+            )));
+        }
+        message.validate(&self.message_limits)?;
+        let ranges = message.parts().iter().filter_map(part_range).count();
+        if self.ranges + ranges > limits.max_ranges {
+            return Err(ProtocolError::LimitExceeded(Limit::Ranges(
+                limits.max_ranges,
+            )));
+        }
+        // re-advertised ranges can be settled, and can be the whole set
+        let reopen = message.is_reopen();
+        if self.state.role == Some(Role::Initiator) && !reopen && is_initial(message) {
+            return Err(ProtocolError::UnexpectedInitialMessage);
+        }
+        let settled = message
+            .parts()
+            .iter()
+            .filter(|part| !(reopen && part.is_range_fingerprint()))
+            .filter_map(part_range)
+            .any(|range| parent_indices(&self.state.settled, range).next().is_some());
+        if settled {
+            return Err(ProtocolError::SettledRange);
+        }
+        // re-advertised ranges start a new exchange, so their depth starts over
+        let parents = match reopen {
+            true => &[][..],
+            false => &self.state.sent[..],
+        };
+        let received = fingerprint_ranges(message, parents);
+        if self.fingerprint_parts + received.len() > limits.max_fingerprint_parts {
+            let limit = Limit::FingerprintParts(limits.max_fingerprint_parts);
+            return Err(ProtocolError::LimitExceeded(limit));
+        }
+        let depth = received.iter().map(|(_, depth)| *depth).max().unwrap_or(0);
+        if depth > limits.max_depth {
+            return Err(ProtocolError::LimitExceeded(Limit::Depth(limits.max_depth)));
+        }
+        Ok(received)
+    }
+
+    /// Adds the session id, the next sequence number and the range ids, if enabled, to a
+    /// message we send.
+    fn stamp(&mut self, message: &mut Message<E>) {
+        self.stamp_piece(message, None);
+    }
+
+    /// Stamps `message`, see [`SyncSession::stamp`], which is a message of the response to the
+    /// received message if `last` is set, and the last one of the response if it is `true`.
+    fn stamp_piece(&mut self, message: &mut Message<E>, last: Option<bool>) {
+        if self.sends_range_ids() {
+            let mut ids = Vec::new();
+            for part in message.parts.iter().filter(|part| part.expects_reply()) {
+                let id = self.next_range_id;
+                self.next_range_id += 1;
+                let reconciling = continues_reconciliation(part);
+                self.state
+                    .outstanding
+                    .insert(id, (part_range(part).cloned(), reconciling));
+                ids.push(id);
+            }
+            let (responds_to, piece) = match last {
+                Some(_) => {
+                    let (responds_to, next_piece) = &mut self.responding_to;
+                    let piece = *next_piece;
+                    *next_piece += 1;
+                    (responds_to.clone(), piece)
+                }
+                None => (Vec::new(), 0),
+            };
+            let ids = RangeIds {
+                ids,
+                responds_to,
+                piece,
+                last: last.unwrap_or(true),
+            };
+            message.parts.insert(0, MessagePart::RangeIds(ids));
+        }
+        if let Some(seq) = self.next_sequence.as_mut() {
+            message.parts.insert(0, MessagePart::Sequence(*seq));
+            *seq += 1;
+        }
+        if let Some(id) = self.session_id {
+            message.parts.insert(0, MessagePart::SessionId(id));
+        }
+    }
+
+    /// Returns `true` and records the message as dropped if it was received before, see
+    /// [`SyncSession::with_sequence_numbers`].
+    fn drop_duplicate(&mut self, message: &Message<E>) -> bool {
+        let duplicate = match (message.sequence(), self.last_sequence_received) {
+            // with range ids, messages may arrive out of order
+            (Some(seq), Some(last)) => !self.range_ids && seq <= last,
+            _ => false,
+        };
+        if duplicate {
+            warn!(
+                seq = message.sequence(),
+                last = self.last_sequence_received,
+                "dropping duplicate message"
+            );
+            self.stats.messages_dropped += 1;
+        }
+        duplicate
+    }
+
+    /// Returns `true` and records the message as dropped if it only responds to parts that are
+    /// no longer outstanding, or asks again for parts we responded to, see
+    /// [`SyncSession::with_range_ids`].
+    ///
+    /// Otherwise the range ids of the message are recorded, and a response to it is complete
+    /// once all of its messages arrived.
+    fn drop_stale(&mut self, message: &Message<E>) -> bool {
+        let Some(range_ids) = message.range_ids() else {
+            self.responding_to = (Vec::new(), 0);
+            return false;
+        };
+        self.range_ids = true;
+        let RangeIds {
+            ids,
+            responds_to,
+            piece,
+            last,
+        } = range_ids;
+        let stale = match responds_to.iter().min() {
+            _ if ids.iter().any(|id| self.answered.contains(id)) => true,
+            None => false,
+            Some(_)
+                if !responds_to
+                    .iter()
+                    .any(|id| self.state.outstanding.contains_key(id)) =>
+            {
+                true
+            }
+            Some(first) => {
+                let (pieces, last_piece) = self.state.responses.entry(*first).or_default();
+                let duplicate = !pieces.insert(*piece);
+                if *last {
+                    *last_piece = Some(*piece);
+                }
+                let complete = last_piece.is_some_and(|last| pieces.len() as u64 > u64::from(last));
+                if complete {
+                    self.state.responses.remove(first);
+                    for id in responds_to {
+                        self.state.outstanding.remove(id);
+                    }
+                }
+                duplicate
+            }
+        };
+        if stale {
+            warn!(?range_ids, "dropping late or duplicate message");
+            self.stats.messages_dropped += 1;
+            return true;
+        }
+        self.answered.extend(ids.iter().copied());
+        self.responding_to = (ids.clone(), 0);
+        false
+    }
+
+    /// Returns `true` if we send range ids, see [`SyncSession::with_range_ids`].
+    fn sends_range_ids(&self) -> bool {
+        self.range_ids && self.version.map_or(true, |v| v >= RANGE_IDS_VERSION)
+    }
+
+    /// Returns `true` if the negotiated version supports [`MessagePart::Done`].
+    fn supports_done(&self) -> bool {
+        self.version
+            .is_some_and(|version| version >= MessagePart::<E>::Done.min_version())
+    }
+
+    /// Returns `true` if we send the summary of the store in the response to the message we
+    /// received, see [`SyncSession::with_store_summary`].
+    fn responds_with_summary(&self) -> bool {
+        let first_response = self.state.role == Some(Role::Responder) && self.rounds == 1;
+        first_response
+            && (self.advertise_summary || self.remote_summary.is_some())
+            && self.version.map_or(true, |v| v >= SUMMARY_VERSION)
+    }
+
+    /// Returns `true` if values are transferred lazily, and the negotiated version supports
+    /// [`MessagePart::LazyRangeItem`].
+    fn sends_lazy_values(&self) -> bool {
+        let min_version = MessagePart::<E>::ValueRequest(Vec::new()).min_version();
+        self.on_missing_value.is_some() && self.version.map_or(true, |v| v >= min_version)
+    }
+
+    /// Returns the space for parts in a message, and the function to measure them with, if
+    /// responses are split, see [`SyncSession::with_max_message_bytes`].
+    ///
+    /// Messages also fit into the budget the remote asked for with a
+    /// [`MessagePart::SendAll`].
+    fn message_budget(&self) -> Option<(usize, PartSizeFn<E>)> {
+        let (max, part_size) = self.max_message_bytes?;
+        let max = max.min(self.remote_max_message_bytes.unwrap_or(usize::MAX));
+        // every message carries the session id and a sequence number
+        let id_size = self
+            .session_id
+            .map_or(0, |id| part_size(&MessagePart::SessionId(id)));
+        let seq_size = self
+            .next_sequence
+            .map_or(0, |_| part_size(&MessagePart::Sequence(u64::MAX)));
+        Some((max.saturating_sub(id_size + seq_size), part_size))
+    }
+
+    /// Returns the budget to ask the remote for all of its entries with, if the local store is
+    /// empty, see [`MessagePart::SendAll`].
+    fn send_all_budget(&self) -> Option<usize> {
+        let (max, _) = self.max_message_bytes?;
+        let supported = self.version.map_or(true, |v| v >= SEND_ALL_VERSION);
+        (supported && self.config.direction() != SyncDirection::SendOnly).then_some(max)
+    }
+
+    /// Returns the entries of `range` as range items that cover the range, each of which fits
+    /// into a message, see [`MessagePart::SendAll`].
+    fn send_all<S, F3>(
+        &self,
+        store: &mut S,
+        range: Range<E::Key>,
+        content_status_cb: F3,
+    ) -> Result<Vec<MessagePart<E>>, S::Error>
+    where
+        S: Store<E>,
+        F3: Fn(&S, &E) -> ContentStatus,
+    {
+        let with_status = |store: &S, entries: Vec<E>| {
+            entries
+                .into_iter()
+                .map(|entry| {
+                    let content_status = content_status_cb(store, &entry);
+                    (entry, content_status)
+                })
+                .collect()
+        };
+        let Some((max, part_size)) = self.message_budget() else {
+            // without splitting messages, all entries are sent in a single item
+            let entries = store.get_range(range.clone())?.collect::<Result<_, _>>()?;
+            return Ok(vec![MessagePart::RangeItem(RangeItem {
+                range,
+                values: with_status(store, entries),
+                have_local: true,
+            })]);
+        };
+        // each chunk holds a single entry, and covers the range up to the next entry
+        let chunks: Vec<_> = store
+            .get_range_chunked(range, 1)?
+            .collect::<Result<_, _>>()?;
+        let items = chunks
+            .into_iter()
+            .map(|Chunk { range, entries }| RangeItem {
+                range,
+                values: with_status(store, entries),
+                have_local: true,
+            });
+        Ok(merge_items(items, max, part_size))
+    }
+
+    /// Splits the parts of `message` with values that exceed `max` bytes, if the remote
+    /// supports [`MessagePart::ValueChunk`]s.
+    ///
+    /// Items and value responses are first split into parts of fewer entries, and the entries
+    /// that exceed the budget on their own are then split into chunks. The items cover the
+    /// same range as before, so the response is the same for the remote.
+    fn split_oversized(
+        &self,
+        message: Message<E>,
+        max: usize,
+        part_size: PartSizeFn<E>,
+    ) -> Message<E> {
+        let supported = self.version.is_some_and(|v| v >= CHUNK_VERSION);
+        let Some(encode_part) = self.encode_part.filter(|_| supported) else {
+            return message;
+        };
+        let mut parts = Vec::with_capacity(message.parts.len());
+        for part in message.parts {
+            if part_size(&part) <= max {
+                parts.push(part);
+                continue;
+            }
+            let split = match part {
+                MessagePart::RangeItem(item) if item.values.len() > 1 => {
+                    merge_items(split_item(item), max, part_size)
+                }
+                MessagePart::ValueResponse(values) if values.len() > 1 => {
+                    chunk_values(values, max, part_size)
+                }
+                part => vec![part],
+            };
+            for part in split {
+                match part_size(&part) > max {
+                    true => parts.extend(value_chunks(part, max, part_size, encode_part)),
+                    false => parts.push(part),
+                }
+            }
+        }
+        Message {
+            parts,
+            more: message.more,
+        }
+    }
+
+    /// Returns `values` as value responses, split so that each fits into a message, see
+    /// [`SyncSession::with_max_message_bytes`].
+    fn value_responses(&self, values: Vec<(E, ContentStatus)>) -> Vec<MessagePart<E>> {
+        match self.max_message_bytes {
+            Some((max, part_size)) => chunk_values(values, max, part_size),
+            None => vec![MessagePart::ValueResponse(values)],
+        }
+    }
+
+    fn start_as_initiator(&mut self) -> Result<(), ProtocolError> {
+        match self.state.role.get_or_insert(Role::Initiator) {
+            Role::Initiator => Ok(()),
+            Role::Responder => Err(ProtocolError::NotInitiator),
+        }
+    }
+
+    /// Records the time the session took so far, see [`SyncStats::duration`].
+    fn record_duration(&mut self, started: Instant) {
+        self.stats.duration = started.elapsed();
+        self.emit_finished();
+    }
+
+    /// Emits [`SyncEvent::SessionFinished`], once the session is done or cancelled.
+    fn emit_finished(&mut self) {
+        if self.state.finished || !(self.is_done() || self.cancelled) {
+            return;
+        }
+        self.state.finished = true;
+        self.abandon_partial_values();
+        emit(&self.events, || {
+            SyncEvent::SessionFinished(self.stats.clone())
+        });
+    }
+
+    /// Takes the next pending message of a split response.
+    ///
+    /// After each call to [`SyncSession::process_message`], this should be called until it
+    /// returns `None`, and all returned messages sent to the remote in order.
+    pub fn poll_pending_message(&mut self) -> Option<Message<E>> {
+        let message = self.state.pending.pop_front()?;
+        self.stats.record_sent(&message);
+        Some(message)
+    }
+}
+
+/// Sends the event returned from `event` to the subscribers, if there are any.
+fn emit<E: RangeEntry>(
+    events: &Option<broadcast::Sender<SyncEvent<E>>>,
+    event: impl FnOnce() -> SyncEvent<E>,
+) {
+    if let Some(events) = events.as_ref().filter(|events| events.receiver_count() > 0) {
+        // the only error is that all subscribers were dropped in the meantime
+        events.send(event()).ok();
+    }
+}
+
+/// Returns the entries of `message` that [`Store::process_message`] may pass to the validate
+/// callback, in message order.
+///
+/// The store passes them in a different order, and without duplicates, so the results are
+/// looked up by entry, see [`Verdicts`].
+fn entries_to_validate<'a, E: RangeEntry>(
+    config: &SyncConfig,
+    message: &'a Message<E>,
+) -> impl Iterator<Item = (&'a E, ContentStatus)> {
+    // messages that cancel the sync are not processed
+    let receive = config.direction() != SyncDirection::SendOnly && !message.is_cancel();
+    message
+        .parts()
+        .iter()
+        .filter_map(move |part| match part {
+            MessagePart::RangeItem(item) if receive => Some((Some(&item.range), &item.values)),
+            // value responses are processed as items of the whole set
+            MessagePart::ValueResponse(values) if receive => Some((None, values)),
+            _ => None,
+        })
+        .flat_map(|(range, values)| {
+            values
+                .iter()
+                .filter(move |(entry, _)| range.map_or(true, |range| range.contains(entry.key())))
+                .map(|(entry, content_status)| (entry, *content_status))
+        })
+}
+
+/// Builds the initial message for a session with priority ranges, with the fingerprints of
+/// `priority` first, followed by those of the ranges between them.
+fn priority_message<E: RangeEntry, S: Store<E>>(
+    store: &mut S,
+    priority: &[Range<E::Key>],
+) -> Result<Message<E>, S::Error> {
+    let (Some(first), Some(last)) = (priority.first(), priority.last()) else {
+        return store.initial_message();
+    };
+    let gaps = priority
+        .windows(2)
+        .filter(|w| w[0].y() < w[1].x())
+        .map(|w| Range::new(w[0].y().clone(), w[1].x().clone()))
+        // the rest of the set wraps around from the end of the last range
+        .chain([Range::new(last.y().clone(), first.x().clone())]);
+    let mut parts = Vec::new();
+    for range in priority.iter().cloned().chain(gaps) {
+        let fingerprint = store.get_fingerprint(&range)?;
+        parts.push(MessagePart::RangeFingerprint(RangeFingerprint {
+            range,
+            fingerprint,
+        }));
+    }
+    Ok(Message { parts, more: false })
+}
+
+/// Moves the parts whose range overlaps one of the `priority` ranges before the other parts
+/// with a range, keeping the order within both groups, and the positions of the parts without
+/// a range.
+fn prioritize<E: RangeEntry>(parts: &mut Vec<MessagePart<E>>, priority: &[Range<E::Key>]) {
+    if priority.is_empty() {
+        return;
+    }
+    let is_priority = |part: &MessagePart<E>| {
+        part_range(part).is_some_and(|range| priority.iter().any(|p| p.overlaps(range)))
+    };
+    let (mut slots, mut ranged) = (Vec::new(), Vec::new());
+    for part in std::mem::take(parts) {
+        match part_range(&part) {
+            Some(_) => {
+                slots.push(None);
+                ranged.push(part);
+            }
+            None => slots.push(Some(part)),
+        }
+    }
+    // a stable sort, so the parts of each group stay ordered by the start of their range
+    ranged.sort_by_key(|part| !is_priority(part));
+    let mut ranged = ranged.into_iter();
+    parts.extend(
+        slots
+            .into_iter()
+            .filter_map(|slot| slot.or_else(|| ranged.next())),
+    );
+}
+
+/// Turns a range item into a [`MessagePart::LazyRangeItem`], see
+/// [`SyncSession::with_lazy_values`], and returns all other parts as they are.
+fn into_lazy<E: RangeEntry>(part: MessagePart<E>) -> MessagePart<E> {
+    match part {
+        MessagePart::RangeItem(RangeItem {
+            range,
+            values,
+            have_local,
+        }) => MessagePart::LazyRangeItem(LazyRangeItem {
+            range,
+            values: values
+                .iter()
+                .map(|(entry, _)| (entry.key().clone(), entry.as_fingerprint()))
+                .collect(),
+            have_local,
+        }),
+        part => part,
+    }
+}
+
+/// Splits `values` into value responses of at most `max` bytes each, where `part_size` is the
+/// size of a part, see [`SyncSession::with_max_message_bytes`].
+///
+/// The size of each value is taken as the size of a response with only that value, which
+/// overestimates the size of a response by a few bytes per value.
+fn chunk_values<E: RangeEntry>(
+    values: Vec<(E, ContentStatus)>,
+    max: usize,
+    part_size: PartSizeFn<E>,
+) -> Vec<MessagePart<E>> {
+    let mut parts = Vec::new();
+    let mut chunk = Vec::new();
+    let mut size = 0;
+    for value in values {
+        let value_size = part_size(&MessagePart::ValueResponse(vec![value.clone()]));
+        if !chunk.is_empty() && size + value_size > max {
+            parts.push(MessagePart::ValueResponse(std::mem::take(&mut chunk)));
+            size = 0;
+        }
+        size += value_size;
+        chunk.push(value);
+    }
+    if !chunk.is_empty() {
+        parts.push(MessagePart::ValueResponse(chunk));
+    }
+    parts
+}
+
+/// Merges adjacent `items` into items of at most `max` bytes.
+///
+/// The range of each item must start where the range of the previous one ends.
+fn merge_items<E: RangeEntry>(
+    items: impl IntoIterator<Item = RangeItem<E>>,
+    max: usize,
+    part_size: PartSizeFn<E>,
+) -> Vec<MessagePart<E>> {
+    let mut parts = Vec::new();
+    let mut item: Option<RangeItem<E>> = None;
+    let mut size = 0;
+    for chunk in items {
+        let chunk = MessagePart::RangeItem(chunk);
+        let chunk_size = part_size(&chunk);
+        let MessagePart::RangeItem(chunk) = chunk else {
+            unreachable!("chunk is a range item");
+        };
+        match &mut item {
+            Some(item) if size + chunk_size <= max => {
+                item.range = Range::new(item.range.x().clone(), chunk.range.y().clone());
+                item.values.extend(chunk.values);
+                size += chunk_size;
+            }
+            _ => {
+                parts.extend(item.replace(chunk).map(MessagePart::RangeItem));
+                size = chunk_size;
+            }
+        }
+    }
+    parts.extend(item.map(MessagePart::RangeItem));
+    parts
+}
+
+/// Splits `item` into items of a single entry each, which cover the range of `item` in order.
+fn split_item<E: RangeEntry>(item: RangeItem<E>) -> Vec<RangeItem<E>> {
+    let RangeItem {
+        range,
+        mut values,
+        have_local,
+    } = item;
+    // the range wraps around if its end is not after its start, so the keys before the start
+    // come last
+    values.sort_by(|(a, _), (b, _)| {
+        (a.key() < range.x(), a.key()).cmp(&(b.key() < range.x(), b.key()))
+    });
+    let mut items = Vec::with_capacity(values.len());
+    let mut x = range.x().clone();
+    let mut values = values.into_iter().peekable();
+    while let Some(value) = values.next() {
+        let y = match values.peek() {
+            Some((next, _)) => next.key().clone(),
+            None => range.y().clone(),
+        };
+        items.push(RangeItem {
+            range: Range::new(std::mem::replace(&mut x, y.clone()), y),
+            values: vec![value],
+            have_local,
+        });
+    }
+    items
+}
+
+/// Splits `part`, an item or a value response with a single entry, into
+/// [`MessagePart::ValueChunk`]s that fit into messages of at most `max` bytes.
+///
+/// Other parts, and parts that cannot be encoded, are returned as they are.
+fn value_chunks<E: RangeEntry>(
+    part: MessagePart<E>,
+    max: usize,
+    part_size: PartSizeFn<E>,
+    encode_part: EncodePartFn<E>,
+) -> Vec<MessagePart<E>> {
+    let key = match part.values() {
+        Some([(entry, _)]) => entry.key().clone(),
+        _ => return vec![part],
+    };
+    let Ok(encoded) = encode_part(&part) else {
+        return vec![part];
+    };
+    // the chunks hold the encoding of a message with the part as its only part, which is the
+    // number of parts, one, followed by the part
+    let mut bytes = Vec::with_capacity(1 + encoded.len());
+    bytes.push(1);
+    bytes.extend(encoded);
+    // the size of a chunk without its bytes, with the largest index, and the largest length
+    // prefix of the bytes, in a message of a single part
+    let empty = MessagePart::ValueChunk {
+        key: key.clone(),
+        index: u32::MAX,
+        total: u32::MAX,
+        bytes: Vec::new(),
+    };
+    let overhead = 1 + part_size(&empty) + 4;
+    let chunk_size = max.saturating_sub(overhead).max(1);
+    let Ok(total) = u32::try_from(bytes.len().div_ceil(chunk_size)) else {
+        return vec![part];
+    };
+    bytes
+        .chunks(chunk_size)
+        .zip(0..)
+        .map(|(chunk, index)| MessagePart::ValueChunk {
+            key: key.clone(),
+            index,
+            total,
+            bytes: chunk.to_vec(),
+        })
+        .collect()
+}
+
+/// Splits `message` into messages of at most `max` bytes, see
+/// [`SyncSession::with_max_message_bytes`].
+fn split_message<E: RangeEntry>(
+    message: Message<E>,
+    max: usize,
+    part_size: PartSizeFn<E>,
+) -> Vec<Message<E>> {
+    let mut messages = Vec::new();
+    let mut parts = Vec::new();
+    let mut size = 0;
+    for part in message.parts {
+        let part_size = part_size(&part);
+        // a message is encoded as the varint encoded number of parts, followed by the parts
+        let len = parts.len() + 1;
+        let len_size = (usize::BITS - len.leading_zeros()).div_ceil(7) as usize;
+        if !parts.is_empty() && len_size + size + part_size > max {
+            messages.push(Message {
+                parts: std::mem::take(&mut parts),
+                more: false,
+            });
+            size = 0;
+        }
+        size += part_size;
+        parts.push(part);
+    }
+    if !parts.is_empty() {
+        messages.push(Message { parts, more: false });
+    }
+    messages
+}
+
+/// Collects the ranges of the fingerprint parts of `message`, with their depth, ordered by the
+/// start of the range.
+///
+/// `parents` are the part ranges of the previous message, ordered by the start of the range.
+fn fingerprint_ranges<E: RangeEntry>(
+    message: &Message<E>,
+    parents: &[(Range<E::Key>, usize)],
+) -> DepthRanges<E::Key> {
+    let ranges = message.parts().iter().filter_map(|part| match part {
+        MessagePart::RangeFingerprint(fp) => Some(&fp.range),
+        MessagePart::SendAll { range, .. } => Some(range),
+        // a divergent range the sender did not settle is split like a fingerprint
+        MessagePart::Divergent { range, settled } => (!settled).then_some(range),
+        MessagePart::RangeItem(_)
+        | MessagePart::Cancel
+        | MessagePart::Handshake(_)
+        | MessagePart::HandshakeMatch
+        | MessagePart::SessionId(_)
+        | MessagePart::Sequence(_)
+        | MessagePart::Reopen
+        | MessagePart::CompressedRangeItem(_)
+        | MessagePart::DeltaRangeItem(_)
+        | MessagePart::Done
+        | MessagePart::LazyRangeItem(_)
+        | MessagePart::ValueRequest(_)
+        | MessagePart::ValueResponse(_)
+        | MessagePart::More
+        | MessagePart::WantKeys { .. }
+        | MessagePart::MissingKeys { .. }
+        | MessagePart::StoreSummary(_)
+        | MessagePart::ValueChunk { .. }
+        | MessagePart::RangeIds(_) => None,
+    });
+    ranges_with_depth(ranges, parents)
+}
+
+/// Collects the ranges of all parts of `message`, with their depth, ordered by the start of the
+/// range.
+fn part_ranges<E: RangeEntry>(
+    message: &Message<E>,
+    parents: &[(Range<E::Key>, usize)],
+) -> DepthRanges<E::Key> {
+    let ranges = message.parts().iter().filter_map(part_range);
+    ranges_with_depth(ranges, parents)
+}
+
+/// Returns the range of `part`, if it has one.
+fn part_range<E: RangeEntry>(part: &MessagePart<E>) -> Option<&Range<E::Key>> {
+    match part {
+        MessagePart::RangeFingerprint(fp) => Some(&fp.range),
+        MessagePart::RangeItem(item) => Some(&item.range),
+        MessagePart::CompressedRangeItem(item) => Some(&item.range),
+        MessagePart::DeltaRangeItem(item) => Some(&item.range),
+        MessagePart::LazyRangeItem(item) => Some(&item.range),
+        MessagePart::SendAll { range, .. } => Some(range),
+        MessagePart::Divergent { range, .. } => Some(range),
+        MessagePart::Cancel
+        | MessagePart::Handshake(_)
+        | MessagePart::HandshakeMatch
+        | MessagePart::SessionId(_)
+        | MessagePart::Sequence(_)
+        | MessagePart::Reopen
+        | MessagePart::Done
+        | MessagePart::ValueRequest(_)
+        | MessagePart::ValueResponse(_)
+        | MessagePart::More
+        | MessagePart::WantKeys { .. }
+        | MessagePart::MissingKeys { .. }
+        | MessagePart::StoreSummary(_)
+        | MessagePart::ValueChunk { .. }
+        | MessagePart::RangeIds(_) => None,
+    }
+}
+
+/// Returns `true` if `message` starts a sync, i.e. it is a handshake or contains the
+/// fingerprint of the whole set.
+///
+/// Responses never contain a fingerprint of the whole set, because the responder splits the
+/// ranges it receives.
+fn is_initial<E: RangeEntry>(message: &Message<E>) -> bool {
+    message.parts().iter().any(|part| match part {
+        MessagePart::Handshake(_) => true,
+        MessagePart::RangeFingerprint(fp) => fp.range.is_all(),
+        _ => false,
+    })
+}
+
+/// Returns `true` if the remote may respond to `part` with parts that need the entries of our
+/// store, see [`SyncSession::replace_store`].
+fn continues_reconciliation<E: RangeEntry>(part: &MessagePart<E>) -> bool {
+    matches!(
+        part,
+        MessagePart::RangeFingerprint(_)
+            | MessagePart::SendAll { .. }
+            | MessagePart::Divergent { settled: false, .. }
+    )
+}
+
+/// Collects the ranges of the parts of `message` that continue the reconciliation, see
+/// [`continues_reconciliation`], ordered by the start of the range.
+fn reconciling_ranges<E: RangeEntry>(message: &Message<E>) -> Vec<Range<E::Key>> {
+    let mut ranges: Vec<_> = message
+        .parts()
+        .iter()
+        .filter(|part| continues_reconciliation(part))
+        .filter_map(part_range)
+        .cloned()
+        .collect();
+    ranges.sort_by(|a, b| a.x().cmp(b.x()));
+    ranges
+}
+
+/// Collects the ranges of the parts of `message` that expect a response, ordered by the start
+/// of the range.
+fn awaiting_ranges<E: RangeEntry>(message: &Message<E>) -> Vec<Range<E::Key>> {
+    let mut ranges: Vec<_> = message
+        .parts()
+        .iter()
+        .filter_map(|part| match part {
+            MessagePart::RangeFingerprint(fp) => Some(fp.range.clone()),
+            MessagePart::SendAll { range, .. } => Some(range.clone()),
+            MessagePart::Divergent { range, settled } if !settled => Some(range.clone()),
+            MessagePart::RangeItem(item) if !item.have_local => Some(item.range.clone()),
+            MessagePart::CompressedRangeItem(item) if !item.have_local => Some(item.range.clone()),
+            MessagePart::DeltaRangeItem(item) if !item.have_local => Some(item.range.clone()),
+            MessagePart::LazyRangeItem(item) if !item.have_local => Some(item.range.clone()),
+            MessagePart::RangeItem(_)
+            | MessagePart::CompressedRangeItem(_)
+            | MessagePart::DeltaRangeItem(_)
+            | MessagePart::LazyRangeItem(_)
+            | MessagePart::Divergent { .. }
+            | MessagePart::ValueRequest(_)
+            | MessagePart::ValueResponse(_)
+            | MessagePart::More
+            | MessagePart::WantKeys { .. }
+            | MessagePart::MissingKeys { .. }
+            | MessagePart::StoreSummary(_)
+            | MessagePart::Cancel
+            | MessagePart::Handshake(_)
+            | MessagePart::HandshakeMatch
+            | MessagePart::SessionId(_)
+            | MessagePart::Sequence(_)
+            | MessagePart::Reopen
+            | MessagePart::Done
+            | MessagePart::ValueChunk { .. }
+            | MessagePart::RangeIds(_) => None,
+        })
+        .collect();
+    ranges.sort_by(|a, b| a.x().cmp(b.x()));
+    ranges
+}
+
+fn ranges_with_depth<'a, K: Ord + Clone + 'a>(
+    ranges: impl Iterator<Item = &'a Range<K>>,
+    parents: &[(Range<K>, usize)],
+) -> DepthRanges<K> {
+    let mut ranges: Vec<_> = ranges
+        .map(|range| {
+            let depth = parent_depth(parents, range).map_or(0, |depth| depth + 1);
+            (range.clone(), depth)
+        })
+        .collect();
+    ranges.sort_by(|(a, _), (b, _)| a.x().cmp(b.x()));
+    ranges
+}
+
+/// Returns the depth of the range in `parents` that contains `range`.
+///
+/// The ranges in `parents` are disjoint and ordered by their start, so only the range that
+/// starts last before `range`, and the last range, which might wrap around, can contain it.
+fn parent_depth<K: Ord>(parents: &[(Range<K>, usize)], range: &Range<K>) -> Option<usize> {
+    parent_indices(parents, range).map(|i| parents[i].1).max()
+}
+
+/// Returns the indices of the ranges in `parents` that contain `range`, see [`parent_depth`].
+fn parent_indices<'a, K: Ord>(
+    parents: &'a [(Range<K>, usize)],
+    range: &'a Range<K>,
+) -> impl Iterator<Item = usize> + 'a {
+    let i = parents.partition_point(|(parent, _)| parent.x() <= range.x());
+    let candidates = i
+        .checked_sub(1)
+        .into_iter()
+        .chain(parents.len().checked_sub(1));
+    candidates.filter(move |&i| contains_range(&parents[i].0, range))
+}
+
+/// Returns `true` if `inner` is contained in `outer`.
+fn contains_range<K: Ord>(outer: &Range<K>, inner: &Range<K>) -> bool {
+    if outer.is_all() {
+        return true;
+    }
+    if inner.is_all() {
+        return false;
+    }
+    match (outer.x() < outer.y(), inner.x() < inner.y()) {
+        (true, true) => outer.x() <= inner.x() && inner.y() <= outer.y(),
+        // a wrapping range is never contained in a regular range
+        (true, false) => false,
+        // a regular range is contained in either segment of the wrapping range
+        (false, true) => outer.x() <= inner.x() || inner.y() <= outer.y(),
+        (false, false) => outer.x() <= inner.x() && inner.y() <= outer.y(),
+    }
+}
@@ -0,0 +1,241 @@
+//! Entries and stores for reconciling plain sets of keys.
+
+use std::{
+    collections::{btree_set, BTreeSet},
+    convert::Infallible,
+    iter::{Chain, Flatten},
+    ops::Bound,
+};
+
+use serde::{Deserialize, Serialize};
+
+use super::{ChunkIterator, Fingerprint, Range, RangeChunks, RangeEntry, RangeKey, Store};
+
+/// An entry that consists of a key only, for reconciling sets of keys such as blob hashes.
+///
+/// The fingerprint of an entry is the hash of the key bytes, and the entry is encoded as the bare
+/// key, so messages do not carry any value data.
+///
+/// The value of every entry is `()`. Prefix deletion still applies: an entry is not inserted if
+/// an entry whose key is a prefix of its key is present. Keys that should not shadow each other
+/// should implement [`RangeKey::is_prefix_of`] as equality.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct SetEntry<K>(pub K);
+
+impl<K: RangeKey + AsRef<[u8]>> RangeEntry for SetEntry<K> {
+    type Key = K;
+    type Value = ();
+
+    fn key(&self) -> &K {
+        &self.0
+    }
+
+    fn value(&self) -> &() {
+        &()
+    }
+
+    fn as_fingerprint(&self) -> Fingerprint {
+        Fingerprint(*blake3::hash(self.0.as_ref()).as_bytes())
+    }
+}
+
+/// A [`Store`] of [`SetEntry`]s that keeps the keys in a [`BTreeSet`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MemorySetStore<K> {
+    keys: BTreeSet<K>,
+}
+
+impl<K> Default for MemorySetStore<K> {
+    fn default() -> Self {
+        Self {
+            keys: Default::default(),
+        }
+    }
+}
+
+impl<K> MemorySetStore<K> {
+    /// Create a new, empty store.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Get the keys in the store.
+    pub fn as_set(&self) -> &BTreeSet<K> {
+        &self.keys
+    }
+
+    /// Consume the store and return its keys.
+    pub fn into_set(self) -> BTreeSet<K> {
+        self.keys
+    }
+}
+
+impl<K> From<BTreeSet<K>> for MemorySetStore<K> {
+    fn from(keys: BTreeSet<K>) -> Self {
+        Self { keys }
+    }
+}
+
+impl<K: Ord> FromIterator<K> for MemorySetStore<K> {
+    fn from_iter<T: IntoIterator<Item = K>>(iter: T) -> Self {
+        Self {
+            keys: iter.into_iter().collect(),
+        }
+    }
+}
+
+impl<K> Store<SetEntry<K>> for MemorySetStore<K>
+where
+    K: RangeKey + AsRef<[u8]> + Default,
+{
+    type Error = Infallible;
+    type RangeIterator<'a> = MemorySetRangeIterator<'a, K>
+    where K: 'a;
+    type ParentIterator<'a> = std::vec::IntoIter<Result<SetEntry<K>, Infallible>>
+    where K: 'a;
+
+    fn get_first(&mut self) -> Result<K, Self::Error> {
+        Ok(self.keys.first().cloned().unwrap_or_default())
+    }
+
+    fn first_and_last(&mut self) -> Result<Option<(K, K)>, Self::Error> {
+        let first = self.keys.first().cloned();
+        let last = self.keys.last().cloned();
+        Ok(first.zip(last))
+    }
+
+    fn get(&mut self, key: &K) -> Result<Option<SetEntry<K>>, Self::Error> {
+        Ok(self.keys.get(key).cloned().map(SetEntry))
+    }
+
+    fn len(&mut self) -> Result<usize, Self::Error> {
+        Ok(self.keys.len())
+    }
+
+    fn is_empty(&mut self) -> Result<bool, Self::Error> {
+        Ok(self.keys.is_empty())
+    }
+
+    fn get_fingerprint(&mut self, range: &Range<K>) -> Result<Fingerprint, Self::Error> {
+        let mut fp = Fingerprint::empty();
+        for entry in self.get_range(range.clone())? {
+            fp ^= entry?.as_fingerprint();
+        }
+        Ok(fp)
+    }
+
+    fn entry_put(&mut self, entry: SetEntry<K>) -> Result<(), Self::Error> {
+        self.keys.insert(entry.0);
+        Ok(())
+    }
+
+    fn get_range(&mut self, range: Range<K>) -> Result<Self::RangeIterator<'_>, Self::Error> {
+        let (x, y) = (Bound::Included(range.x()), Bound::Excluded(range.y()));
+        let (first, second) = match range.x().cmp(range.y()) {
+            std::cmp::Ordering::Less => (self.keys.range::<K, _>((x, y)), None),
+            // the range wraps around (or is the full range), so we need two segments: from the
+            // start to y, and from x to the end.
+            _ => (
+                self.keys.range::<K, _>((Bound::Unbounded, y)),
+                Some(self.keys.range::<K, _>((x, Bound::Unbounded))),
+            ),
+        };
+        Ok(MemorySetRangeIterator::new(first, second, None))
+    }
+
+    fn get_range_chunked<'a>(
+        &'a mut self,
+        range: Range<K>,
+        chunk_size: usize,
+    ) -> Result<ChunkIterator<'a, SetEntry<K>, Self::Error>, Self::Error>
+    where
+        SetEntry<K>: 'a,
+    {
+        let (x, y) = (Bound::Included(range.x()), Bound::Excluded(range.y()));
+        // chunks are yielded in range order, so for wrap-around ranges we start at x.
+        let (first, second) = match range.x().cmp(range.y()) {
+            std::cmp::Ordering::Less => (self.keys.range::<K, _>((x, y)), None),
+            _ => (
+                self.keys.range::<K, _>((x, Bound::Unbounded)),
+                Some(self.keys.range::<K, _>((Bound::Unbounded, y))),
+            ),
+        };
+        let iter = MemorySetRangeIterator::new(first, second, None);
+        Ok(Box::new(RangeChunks::new(range, chunk_size, iter)))
+    }
+
+    fn prefixed_by(&mut self, prefix: &K) -> Result<Self::RangeIterator<'_>, Self::Error> {
+        let iter = self.keys.range::<K, _>(..);
+        Ok(MemorySetRangeIterator::new(
+            iter,
+            None,
+            Some(prefix.clone()),
+        ))
+    }
+
+    fn prefixes_of(&mut self, key: &K) -> Result<Self::ParentIterator<'_>, Self::Error> {
+        let res: Vec<_> = self
+            .keys
+            .iter()
+            .filter(|k| k.is_prefix_of(key))
+            .map(|k| Ok(SetEntry(k.clone())))
+            .collect();
+        Ok(res.into_iter())
+    }
+
+    fn all(&mut self) -> Result<Self::RangeIterator<'_>, Self::Error> {
+        let iter = self.keys.range::<K, _>(..);
+        Ok(MemorySetRangeIterator::new(iter, None, None))
+    }
+
+    fn entry_remove(&mut self, key: &K) -> Result<Option<SetEntry<K>>, Self::Error> {
+        Ok(self.keys.take(key).map(SetEntry))
+    }
+
+    fn remove_prefix_filtered(
+        &mut self,
+        prefix: &K,
+        predicate: impl Fn(&()) -> bool,
+    ) -> Result<usize, Self::Error> {
+        let old_len = self.keys.len();
+        self.keys.retain(|key| {
+            let remove = prefix.is_prefix_of(key) && predicate(&());
+            !remove
+        });
+        Ok(old_len - self.keys.len())
+    }
+}
+
+/// Iterator over the entries of a [`MemorySetStore`].
+#[derive(Debug)]
+pub struct MemorySetRangeIterator<'a, K> {
+    iter: Chain<btree_set::Range<'a, K>, Flatten<std::option::IntoIter<btree_set::Range<'a, K>>>>,
+    /// Only yield entries whose key starts with this prefix.
+    prefix: Option<K>,
+}
+
+impl<'a, K> MemorySetRangeIterator<'a, K> {
+    fn new(
+        first: btree_set::Range<'a, K>,
+        second: Option<btree_set::Range<'a, K>>,
+        prefix: Option<K>,
+    ) -> Self {
+        Self {
+            iter: first.chain(second.into_iter().flatten()),
+            prefix,
+        }
+    }
+}
+
+impl<'a, K: RangeKey> Iterator for MemorySetRangeIterator<'a, K> {
+    type Item = Result<SetEntry<K>, Infallible>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let key = match &self.prefix {
+            None => self.iter.next()?,
+            Some(prefix) => self.iter.find(|key| prefix.is_prefix_of(key))?,
+        };
+        Some(Ok(SetEntry(key.clone())))
+    }
+}
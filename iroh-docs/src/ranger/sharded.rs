@@ -0,0 +1,324 @@
+//! A store that partitions the keyspace across multiple inner stores.
+
+use std::cmp::Ordering;
+
+use super::{
+    overlay::cmp_keys, validators::EntryLookup, ChunkEntries, ChunkIterator, Fingerprint, Range,
+    RangeChunks, RangeEntry, Store,
+};
+
+/// A [`Store`] that splits the keyspace into shards, each backed by its own inner store.
+///
+/// The shards are separated by a sorted list of boundary keys: shard `i` holds the keys from
+/// boundary `i - 1` (inclusive) up to boundary `i` (exclusive), with the first and last shard
+/// being unbounded below and above. Single entries are routed to the shard owning their key,
+/// while ranges are answered by merging the results of all shards the range touches. Because
+/// the shards are disjoint, the fingerprint of a range is the XOR of the per-shard fingerprints.
+///
+/// All inner stores must yield the entries of [`Store::get_range`], [`Store::prefixed_by`],
+/// [`Store::prefixes_of`] and [`Store::all`] ordered by key, which holds for all stores in this
+/// crate.
+#[derive(Debug)]
+pub struct ShardedStore<E: RangeEntry, S> {
+    shards: Vec<S>,
+    /// Sorted, there is one less boundary than there are shards.
+    boundaries: Vec<E::Key>,
+}
+
+impl<E: RangeEntry, S> ShardedStore<E, S> {
+    /// Create a sharded store from the boundaries between the shards, and the shards.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the boundaries are not strictly ascending, or if there is not exactly one more
+    /// shard than there are boundaries.
+    pub fn new(boundaries: Vec<E::Key>, shards: Vec<S>) -> Self {
+        assert!(
+            boundaries.windows(2).all(|w| w[0] < w[1]),
+            "shard boundaries must be strictly ascending"
+        );
+        assert_eq!(
+            shards.len(),
+            boundaries.len() + 1,
+            "there must be one more shard than boundaries"
+        );
+        Self { shards, boundaries }
+    }
+
+    /// Get the boundaries between the shards.
+    pub fn boundaries(&self) -> &[E::Key] {
+        &self.boundaries
+    }
+
+    /// Get the shards, ordered by key.
+    pub fn shards(&self) -> &[S] {
+        &self.shards
+    }
+
+    /// Consume the store and return the shards, ordered by key.
+    pub fn into_shards(self) -> Vec<S> {
+        self.shards
+    }
+
+    fn shard_index(&self, key: &E::Key) -> usize {
+        self.boundaries.partition_point(|boundary| boundary <= key)
+    }
+
+    fn shard(&mut self, key: &E::Key) -> &mut S {
+        let index = self.shard_index(key);
+        &mut self.shards[index]
+    }
+
+    /// The shards that may contain entries in `range`, ordered by key.
+    fn touched(&mut self, range: &Range<E::Key>) -> impl Iterator<Item = &mut S> {
+        let boundaries = &self.boundaries;
+        let range = range.clone();
+        self.shards
+            .iter_mut()
+            .enumerate()
+            .filter(move |(i, _)| {
+                let lo = i.checked_sub(1).map(|i| &boundaries[i]);
+                let hi = boundaries.get(*i);
+                // whether the shard has keys not smaller than x, and keys smaller than y
+                let after_x = !matches!(hi, Some(hi) if hi <= range.x());
+                let before_y = !matches!(lo, Some(lo) if range.y() <= lo);
+                match range.x().cmp(range.y()) {
+                    Ordering::Less => after_x && before_y,
+                    Ordering::Equal => true,
+                    Ordering::Greater => after_x || before_y,
+                }
+            })
+            .map(|(_, shard)| shard)
+    }
+}
+
+impl<E: RangeEntry, S: EntryLookup<E>> EntryLookup<E> for ShardedStore<E, S> {
+    fn lookup(&self, key: &E::Key) -> Option<&E> {
+        self.shards[self.shard_index(key)].lookup(key)
+    }
+}
+
+impl<E: RangeEntry, S: Store<E>> Store<E> for ShardedStore<E, S> {
+    type Error = S::Error;
+    type RangeIterator<'a> = ShardedIterator<E, S::RangeIterator<'a>>
+    where S: 'a, E: 'a;
+    type ParentIterator<'a> = ShardedIterator<E, S::ParentIterator<'a>>
+    where S: 'a, E: 'a;
+
+    fn epoch(&self) -> u64 {
+        // each write moves the epoch of exactly one shard, and so the sum
+        self.shards
+            .iter()
+            .fold(0, |epoch, shard| epoch.wrapping_add(shard.epoch()))
+    }
+
+    fn get_first(&mut self) -> Result<E::Key, Self::Error> {
+        for shard in &mut self.shards {
+            if !shard.is_empty()? {
+                return shard.get_first();
+            }
+        }
+        self.shards[0].get_first()
+    }
+
+    fn first_and_last(&mut self) -> Result<Option<(E::Key, E::Key)>, Self::Error> {
+        let mut res: Option<(E::Key, E::Key)> = None;
+        for shard in &mut self.shards {
+            if let Some((first, last)) = shard.first_and_last()? {
+                let first = res.map_or(first, |(first, _)| first);
+                res = Some((first, last));
+            }
+        }
+        Ok(res)
+    }
+
+    fn get(&mut self, key: &E::Key) -> Result<Option<E>, Self::Error> {
+        self.shard(key).get(key)
+    }
+
+    fn entry_fingerprint(&mut self, key: &E::Key) -> Result<Option<Fingerprint>, Self::Error> {
+        self.shard(key).entry_fingerprint(key)
+    }
+
+    fn len(&mut self) -> Result<usize, Self::Error> {
+        let mut len = 0;
+        for shard in &mut self.shards {
+            len += shard.len()?;
+        }
+        Ok(len)
+    }
+
+    fn is_empty(&mut self) -> Result<bool, Self::Error> {
+        for shard in &mut self.shards {
+            if !shard.is_empty()? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    fn get_fingerprint(&mut self, range: &Range<E::Key>) -> Result<Fingerprint, Self::Error> {
+        let mut fingerprint = Fingerprint::empty();
+        for shard in self.touched(range) {
+            // every shard fingerprint includes the fingerprint of the empty set
+            fingerprint ^= shard.get_fingerprint(range)?;
+            fingerprint ^= Fingerprint::empty();
+        }
+        Ok(fingerprint)
+    }
+
+    fn entry_put(&mut self, entry: E) -> Result<(), Self::Error> {
+        self.shard(entry.key()).entry_put(entry)
+    }
+
+    fn get_range(&mut self, range: Range<E::Key>) -> Result<Self::RangeIterator<'_>, Self::Error> {
+        let iters = self
+            .touched(&range)
+            .map(|shard| shard.get_range(range.clone()))
+            .collect::<Result<_, _>>()?;
+        Ok(ShardedIterator::new(iters, None))
+    }
+
+    fn get_range_chunked<'a>(
+        &'a mut self,
+        range: Range<E::Key>,
+        chunk_size: usize,
+    ) -> Result<ChunkIterator<'a, E, Self::Error>, Self::Error>
+    where
+        E: 'a,
+    {
+        let iters = self
+            .touched(&range)
+            .map(|shard| {
+                let chunks = shard.get_range_chunked(range.clone(), chunk_size)?;
+                Ok(ChunkEntries::new(chunks))
+            })
+            .collect::<Result<_, _>>()?;
+        // The chunk iterators yield in range order, so merge accordingly.
+        let iter = ShardedIterator::new(iters, Some(range.x().clone()));
+        Ok(Box::new(RangeChunks::new(range, chunk_size, iter)))
+    }
+
+    fn get_range_len(&mut self, range: Range<E::Key>) -> Result<usize, Self::Error> {
+        let mut len = 0;
+        for shard in self.touched(&range) {
+            len += shard.get_range_len(range.clone())?;
+        }
+        Ok(len)
+    }
+
+    fn prefixed_by(&mut self, prefix: &E::Key) -> Result<Self::RangeIterator<'_>, Self::Error> {
+        let iters = self
+            .shards
+            .iter_mut()
+            .map(|shard| shard.prefixed_by(prefix))
+            .collect::<Result<_, _>>()?;
+        Ok(ShardedIterator::new(iters, None))
+    }
+
+    fn prefixes_of(&mut self, key: &E::Key) -> Result<Self::ParentIterator<'_>, Self::Error> {
+        let iters = self
+            .shards
+            .iter_mut()
+            .map(|shard| shard.prefixes_of(key))
+            .collect::<Result<_, _>>()?;
+        Ok(ShardedIterator::new(iters, None))
+    }
+
+    fn all(&mut self) -> Result<Self::RangeIterator<'_>, Self::Error> {
+        let iters = self
+            .shards
+            .iter_mut()
+            .map(|shard| shard.all())
+            .collect::<Result<_, _>>()?;
+        Ok(ShardedIterator::new(iters, None))
+    }
+
+    fn entry_remove(&mut self, key: &E::Key) -> Result<Option<E>, Self::Error> {
+        self.shard(key).entry_remove(key)
+    }
+
+    fn remove_prefix_filtered(
+        &mut self,
+        prefix: &E::Key,
+        predicate: impl Fn(&E::Value) -> bool,
+    ) -> Result<usize, Self::Error> {
+        let mut removed = 0;
+        for shard in &mut self.shards {
+            removed += shard.remove_prefix_filtered(prefix, &predicate)?;
+        }
+        Ok(removed)
+    }
+}
+
+/// Iterator over the entries of a [`ShardedStore`].
+///
+/// Merges the sorted entries of the shards.
+pub struct ShardedIterator<E: RangeEntry, I: Iterator> {
+    iters: Vec<I>,
+    /// The next item of each iterator.
+    heads: Vec<Option<I::Item>>,
+    /// If set, keys smaller than this key sort after all other keys, to merge in range order.
+    wrap_at: Option<E::Key>,
+}
+
+impl<E: RangeEntry, I: Iterator> std::fmt::Debug for ShardedIterator<E, I> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ShardedIterator")
+            .field("shards", &self.iters.len())
+            .field("wrap_at", &self.wrap_at)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<E: RangeEntry, I: Iterator> ShardedIterator<E, I> {
+    fn new(mut iters: Vec<I>, wrap_at: Option<E::Key>) -> Self {
+        let heads = iters.iter_mut().map(Iterator::next).collect();
+        Self {
+            iters,
+            heads,
+            wrap_at,
+        }
+    }
+}
+
+impl<E, I, Err> Iterator for ShardedIterator<E, I>
+where
+    E: RangeEntry,
+    I: Iterator<Item = Result<E, Err>>,
+{
+    type Item = Result<E, Err>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut next: Option<usize> = None;
+        for (i, head) in self.heads.iter().enumerate() {
+            match (head, next.and_then(|j| self.heads[j].as_ref())) {
+                (None, _) => {}
+                // Errors are yielded as soon as they are seen.
+                (Some(Err(_)), _) => {
+                    next = Some(i);
+                    break;
+                }
+                (Some(Ok(entry)), Some(Ok(current))) => {
+                    if cmp_keys(self.wrap_at.as_ref(), entry.key(), current.key()).is_lt() {
+                        next = Some(i);
+                    }
+                }
+                (Some(Ok(_)), _) => next = Some(i),
+            }
+        }
+        self.advance(next?)
+    }
+}
+
+impl<E, I, Err> ShardedIterator<E, I>
+where
+    E: RangeEntry,
+    I: Iterator<Item = Result<E, Err>>,
+{
+    /// Take the head of shard `i`, and replace it with the next item of the shard.
+    fn advance(&mut self, i: usize) -> Option<Result<E, Err>> {
+        let next = self.iters[i].next();
+        std::mem::replace(&mut self.heads[i], next)
+    }
+}
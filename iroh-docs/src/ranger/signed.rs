@@ -0,0 +1,128 @@
+//! Entries together with a signature, see [`SignedEntry`].
+
+use std::fmt::Debug;
+
+use serde::{Deserialize, Serialize};
+
+use super::{Fingerprint, RangeEntry};
+
+/// An entry together with a signature over it, e.g. by the author of the entry.
+///
+/// The key and the value are those of the wrapped entry. The fingerprint covers the signature
+/// too, so stores that hold the same entry with different signatures do not match. Signatures
+/// are checked with a [`Verifier`] when entries are received, see
+/// [`validators::verify_signed`].
+///
+/// [`validators::verify_signed`]: super::validators::verify_signed
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SignedEntry<E, Sig> {
+    /// The signed entry.
+    pub entry: E,
+    /// The signature over the entry.
+    pub signature: Sig,
+}
+
+impl<E, Sig> SignedEntry<E, Sig> {
+    /// Create a signed entry from an entry and its signature.
+    pub fn new(entry: E, signature: Sig) -> Self {
+        Self { entry, signature }
+    }
+
+    /// Returns `true` if the signature is valid for the entry, see [`Verifier::verify`].
+    pub fn verify<V: Verifier<E, Signature = Sig>>(&self) -> bool {
+        V::verify(&self.entry, &self.signature)
+    }
+}
+
+impl<E, Sig> RangeEntry for SignedEntry<E, Sig>
+where
+    E: RangeEntry,
+    Sig: Debug + Clone + Serialize,
+{
+    type Key = E::Key;
+    type Value = E::Value;
+
+    fn key(&self) -> &E::Key {
+        self.entry.key()
+    }
+
+    fn value(&self) -> &E::Value {
+        self.entry.value()
+    }
+
+    fn as_fingerprint(&self) -> Fingerprint {
+        let signature = postcard::to_stdvec(&self.signature).expect("signature can be encoded");
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(&self.entry.as_fingerprint().0);
+        hasher.update(&signature);
+        Fingerprint(*hasher.finalize().as_bytes())
+    }
+
+    fn encoded_size_hint(&self) -> u64 {
+        self.entry.encoded_size_hint() + std::mem::size_of_val(&self.signature) as u64
+    }
+
+    fn deletes_prefixed(&self) -> bool {
+        self.entry.deletes_prefixed()
+    }
+}
+
+/// Checks the signatures of [`SignedEntry`]s.
+///
+/// Verifiers are stateless, so that they can be named in a validate callback, see
+/// [`validators::verify_signed`]. Everything needed to check a signature, such as the public
+/// key of the author, has to be part of the entry or the signature.
+///
+/// [`validators::verify_signed`]: super::validators::verify_signed
+pub trait Verifier<E> {
+    /// The type of the signatures.
+    type Signature;
+
+    /// Returns `true` if `signature` is a valid signature of `entry`.
+    fn verify(entry: &E, signature: &Self::Signature) -> bool;
+}
+
+/// A [`Verifier`] for ed25519 signatures over the postcard encoding of an entry.
+///
+/// The first 32 bytes of the key of an entry are the public key of its author, who signs the
+/// entry. Entries with shorter keys, or keys that do not start with a valid public key, are
+/// never valid.
+#[cfg(feature = "ed25519")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Ed25519Verifier;
+
+#[cfg(feature = "ed25519")]
+impl Ed25519Verifier {
+    /// Signs `entry` with the secret key of its author.
+    pub fn sign<E: Serialize>(
+        entry: E,
+        key: &ed25519_dalek::SigningKey,
+    ) -> Result<SignedEntry<E, ed25519_dalek::Signature>, postcard::Error> {
+        use ed25519_dalek::Signer;
+
+        let signature = key.sign(&postcard::to_stdvec(&entry)?);
+        Ok(SignedEntry::new(entry, signature))
+    }
+}
+
+#[cfg(feature = "ed25519")]
+impl<E> Verifier<E> for Ed25519Verifier
+where
+    E: RangeEntry + Serialize,
+    E::Key: AsRef<[u8]>,
+{
+    type Signature = ed25519_dalek::Signature;
+
+    fn verify(entry: &E, signature: &Self::Signature) -> bool {
+        let Some(author) = entry.key().as_ref().get(..32) else {
+            return false;
+        };
+        let Ok(author) = ed25519_dalek::VerifyingKey::try_from(author) else {
+            return false;
+        };
+        let Ok(message) = postcard::to_stdvec(entry) else {
+            return false;
+        };
+        author.verify_strict(&message, signature).is_ok()
+    }
+}
@@ -0,0 +1,199 @@
+//! Snapshot serialization for stores, see [`Store::export`] and [`Store::import`].
+//!
+//! A snapshot consists of a header followed by the entries of the store:
+//!
+//! * the magic bytes `b"rngr"`
+//! * the format version as a single byte, currently [`SNAPSHOT_VERSION`]
+//! * the fingerprint of the full range of the store (32 bytes)
+//! * the number of entries as `u64` little-endian
+//! * for each entry, its length as `u32` little-endian, followed by the postcard encoded entry.
+//!
+//! The entries are ordered by strictly ascending keys, and nothing follows the last entry.
+
+use std::io::{self, Read};
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use super::{Fingerprint, Range, RangeEntry, Store};
+
+/// Magic bytes at the start of a snapshot.
+const SNAPSHOT_MAGIC: &[u8; 4] = b"rngr";
+
+/// The current snapshot format version.
+pub const SNAPSHOT_VERSION: u8 = 1;
+
+/// Error returned from [`Store::export`].
+#[derive(Debug, thiserror::Error)]
+pub enum ExportError {
+    /// Failed to read from the store.
+    #[error("store error: {0}")]
+    Store(anyhow::Error),
+    /// Failed to write the snapshot.
+    #[error("io error: {0}")]
+    Io(#[from] io::Error),
+    /// Failed to encode an entry.
+    #[error("failed to encode entry: {0}")]
+    Encode(#[from] postcard::Error),
+    /// An encoded entry exceeds the maximum entry size of `u32::MAX` bytes.
+    #[error("entry too large: {0} bytes")]
+    EntryTooLarge(usize),
+}
+
+/// Error returned from [`Store::import`].
+#[derive(Debug, thiserror::Error)]
+pub enum ImportError {
+    /// Failed to read the snapshot.
+    #[error("io error: {0}")]
+    Io(#[from] io::Error),
+    /// The snapshot does not start with the expected magic bytes.
+    #[error("not a snapshot")]
+    InvalidMagic,
+    /// The snapshot was written with an unsupported format version.
+    #[error("unsupported snapshot version: {0}")]
+    UnsupportedVersion(u8),
+    /// The snapshot ended before all entries were read.
+    #[error("snapshot is truncated")]
+    Truncated,
+    /// Failed to decode an entry.
+    #[error("failed to decode entry: {0}")]
+    Decode(#[from] postcard::Error),
+    /// The keys of the entries are not strictly ascending, e.g. because an entry is repeated.
+    #[error("snapshot entries are not ordered by key")]
+    UnorderedKeys,
+    /// The snapshot continues after the number of entries given in the header.
+    #[error("snapshot has trailing data after the last entry")]
+    TrailingData,
+    /// The fingerprint of the entries does not match the fingerprint in the header.
+    #[error("fingerprint mismatch: expected {expected:?}, got {actual:?}")]
+    FingerprintMismatch {
+        /// The fingerprint from the snapshot header.
+        expected: Fingerprint,
+        /// The fingerprint of the entries in the snapshot.
+        actual: Fingerprint,
+    },
+    /// Failed to write to the store.
+    #[error("store error: {0}")]
+    Store(anyhow::Error),
+}
+
+pub(super) fn export<E, S, W>(store: &mut S, mut w: W) -> Result<(), ExportError>
+where
+    E: RangeEntry + Serialize,
+    S: Store<E>,
+    W: io::Write,
+{
+    let first = store.get_first().map_err(store_err)?;
+    let fingerprint = store
+        .get_fingerprint(&Range::new(first.clone(), first))
+        .map_err(store_err)?;
+    let len = store.len().map_err(store_err)?;
+
+    w.write_all(SNAPSHOT_MAGIC)?;
+    w.write_all(&[SNAPSHOT_VERSION])?;
+    w.write_all(&fingerprint.0)?;
+    w.write_all(&(len as u64).to_le_bytes())?;
+
+    let mut count = 0;
+    let mut last: Option<E::Key> = None;
+    for entry in store.all().map_err(store_err)? {
+        let entry = entry.map_err(store_err)?;
+        // The snapshot could not be imported again.
+        if last.as_ref().is_some_and(|last| entry.key() <= last) {
+            return Err(ExportError::Store(anyhow::anyhow!(
+                "store returned entries out of key order"
+            )));
+        }
+        last = Some(entry.key().clone());
+        let buf = postcard::to_allocvec(&entry)?;
+        let entry_len =
+            u32::try_from(buf.len()).map_err(|_| ExportError::EntryTooLarge(buf.len()))?;
+        w.write_all(&entry_len.to_le_bytes())?;
+        w.write_all(&buf)?;
+        count += 1;
+    }
+    // The store changed while we were iterating, so the header would be wrong.
+    if count != len {
+        return Err(ExportError::Store(anyhow::anyhow!(
+            "store returned {count} entries, expected {len}"
+        )));
+    }
+    w.flush()?;
+    Ok(())
+}
+
+pub(super) fn import<E, S, R>(mut r: R) -> Result<S, ImportError>
+where
+    E: RangeEntry + DeserializeOwned,
+    S: Store<E> + Default,
+    R: io::Read,
+{
+    let mut magic = [0u8; 4];
+    read_exact(&mut r, &mut magic)?;
+    if &magic != SNAPSHOT_MAGIC {
+        return Err(ImportError::InvalidMagic);
+    }
+    let mut version = [0u8; 1];
+    read_exact(&mut r, &mut version)?;
+    if version[0] != SNAPSHOT_VERSION {
+        return Err(ImportError::UnsupportedVersion(version[0]));
+    }
+    let mut expected = [0u8; 32];
+    read_exact(&mut r, &mut expected)?;
+    let expected = Fingerprint(expected);
+    let mut len = [0u8; 8];
+    read_exact(&mut r, &mut len)?;
+    let len = u64::from_le_bytes(len);
+
+    // Decode and verify all entries before touching the store, so that a corrupt snapshot never
+    // results in a partially filled store.
+    let mut entries = Vec::new();
+    let mut actual = Fingerprint::empty();
+    let mut buf = Vec::new();
+    for _ in 0..len {
+        let mut entry_len = [0u8; 4];
+        read_exact(&mut r, &mut entry_len)?;
+        let entry_len = u32::from_le_bytes(entry_len) as u64;
+        buf.clear();
+        // Read through `take` so that a corrupt length can not make us allocate a huge buffer.
+        (&mut r).take(entry_len).read_to_end(&mut buf)?;
+        if (buf.len() as u64) < entry_len {
+            return Err(ImportError::Truncated);
+        }
+        let entry: E = postcard::from_bytes(&buf)?;
+        // Equal entries cancel out in the fingerprint, so repeated keys must be rejected here.
+        if entries
+            .last()
+            .is_some_and(|last: &E| entry.key() <= last.key())
+        {
+            return Err(ImportError::UnorderedKeys);
+        }
+        actual ^= entry.as_fingerprint();
+        entries.push(entry);
+    }
+    // A header with too few entries must not silently drop the rest of the snapshot.
+    if r.read(&mut [0u8; 1])? != 0 {
+        return Err(ImportError::TrailingData);
+    }
+    if actual != expected {
+        return Err(ImportError::FingerprintMismatch { expected, actual });
+    }
+
+    let mut store = S::default();
+    for entry in entries {
+        store
+            .entry_put(entry)
+            .map_err(|err| ImportError::Store(err.into()))?;
+    }
+    Ok(store)
+}
+
+fn read_exact(r: &mut impl io::Read, buf: &mut [u8]) -> Result<(), ImportError> {
+    r.read_exact(buf).map_err(|err| match err.kind() {
+        io::ErrorKind::UnexpectedEof => ImportError::Truncated,
+        _ => ImportError::Io(err),
+    })
+}
+
+fn store_err(err: impl Into<anyhow::Error>) -> ExportError {
+    ExportError::Store(err.into())
+}
@@ -0,0 +1,407 @@
+//! Persistent store backed by [`rusqlite`].
+
+use std::{collections::VecDeque, marker::PhantomData, ops::Bound, path::Path};
+
+use rusqlite::{params, params_from_iter, Connection, OptionalExtension};
+use serde::{de::DeserializeOwned, Serialize};
+
+use super::{ChunkIterator, Fingerprint, Range, RangeChunks, RangeEntry, RangeKey, Store};
+
+/// Name of the table holding the entries.
+const TABLE: &str = "ranger_entries";
+
+/// Number of rows fetched per query when iterating over entries.
+const PAGE_SIZE: usize = 1024;
+
+/// Error returned from a [`SqliteStore`].
+#[derive(Debug, thiserror::Error)]
+pub enum SqliteStoreError {
+    /// Failed to access the database.
+    #[error("database error: {0}")]
+    Sqlite(#[from] rusqlite::Error),
+    /// Failed to encode or decode an entry.
+    #[error("encoding error: {0}")]
+    Encoding(#[from] postcard::Error),
+}
+
+/// A persistent [`Store`] backed by a SQLite database.
+///
+/// Entries are kept in a `ranger_entries (key BLOB PRIMARY KEY, value BLOB, fingerprint BLOB)`
+/// table, where `value` is the postcard encoded entry. SQLite compares blobs bytewise, so the
+/// byte order of [`RangeEntry::Key`] must match its [`Ord`] implementation. Fingerprints of
+/// ranges are computed by streaming the fingerprints of the rows in the range.
+///
+/// The store can be created on a [`Connection`] that is also used for other data, the table is
+/// created if it does not exist yet. Each write is committed on its own, unless the connection
+/// is in a transaction.
+pub struct SqliteStore<E> {
+    conn: Connection,
+    _entry: PhantomData<fn() -> E>,
+}
+
+impl<E> std::fmt::Debug for SqliteStore<E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SqliteStore").finish_non_exhaustive()
+    }
+}
+
+impl<E> SqliteStore<E> {
+    /// Create or open a store at the given path.
+    pub fn persistent(path: impl AsRef<Path>) -> Result<Self, SqliteStoreError> {
+        Self::new(Connection::open(path)?)
+    }
+
+    /// Create a new in-memory store.
+    pub fn memory() -> Result<Self, SqliteStoreError> {
+        Self::new(Connection::open_in_memory()?)
+    }
+
+    /// Create a store on an existing connection, creating the entries table if needed.
+    pub fn new(conn: Connection) -> Result<Self, SqliteStoreError> {
+        conn.execute(
+            &format!(
+                "CREATE TABLE IF NOT EXISTS {TABLE} \
+                 (key BLOB PRIMARY KEY, value BLOB NOT NULL, fingerprint BLOB NOT NULL)"
+            ),
+            (),
+        )?;
+        Ok(Self {
+            conn,
+            _entry: PhantomData,
+        })
+    }
+
+    /// Get a reference to the underlying connection.
+    pub fn connection(&self) -> &Connection {
+        &self.conn
+    }
+
+    /// Unwrap the underlying connection.
+    pub fn into_connection(self) -> Connection {
+        self.conn
+    }
+}
+
+/// A scan over the table in key order, from a lower bound to an exclusive upper bound.
+#[derive(Debug, Clone)]
+struct Scan {
+    from: Bound<Vec<u8>>,
+    to: Option<Vec<u8>>,
+}
+
+impl Scan {
+    fn new(from: Option<&[u8]>, to: Option<&[u8]>) -> Self {
+        Self {
+            from: from.map_or(Bound::Unbounded, |from| Bound::Included(from.to_vec())),
+            to: to.map(<[u8]>::to_vec),
+        }
+    }
+
+    /// Build a query selecting `columns` from the rows of the scan, followed by `suffix`.
+    fn query(&self, columns: &str, suffix: &str) -> (String, Vec<&[u8]>) {
+        let mut conditions = vec![];
+        let mut params = vec![];
+        match &self.from {
+            Bound::Included(from) => {
+                conditions.push("key >= ?");
+                params.push(&from[..]);
+            }
+            Bound::Excluded(from) => {
+                conditions.push("key > ?");
+                params.push(&from[..]);
+            }
+            Bound::Unbounded => {}
+        }
+        if let Some(to) = &self.to {
+            conditions.push("key < ?");
+            params.push(&to[..]);
+        }
+        let mut sql = format!("SELECT {columns} FROM {TABLE}");
+        if !conditions.is_empty() {
+            sql.push_str(" WHERE ");
+            sql.push_str(&conditions.join(" AND "));
+        }
+        sql.push(' ');
+        sql.push_str(suffix);
+        (sql, params)
+    }
+}
+
+/// Translate a range into at most two scans, in key order.
+fn scans<K: AsRef<[u8]> + Ord>(range: &Range<K>) -> Vec<Scan> {
+    let (x, y) = (range.x().as_ref(), range.y().as_ref());
+    if range.x() < range.y() {
+        vec![Scan::new(Some(x), Some(y))]
+    } else {
+        // the range wraps around (or is the full range), so we need two scans: from the start
+        // to y, and from x to the end.
+        vec![Scan::new(None, Some(y)), Scan::new(Some(x), None)]
+    }
+}
+
+impl<E> SqliteStore<E>
+where
+    E: RangeEntry + Serialize + DeserializeOwned,
+    E::Key: AsRef<[u8]>,
+{
+    /// Get the entry with the smallest or the largest key.
+    fn get_edge(&self, order: &str) -> Result<Option<E>, SqliteStoreError> {
+        let value = self
+            .conn
+            .prepare_cached(&format!(
+                "SELECT value FROM {TABLE} ORDER BY key {order} LIMIT 1"
+            ))?
+            .query_row((), |row| row.get::<_, Vec<u8>>(0))
+            .optional()?;
+        value.map(|value| decode(&value)).transpose()
+    }
+
+    fn remove_key(&self, key: &[u8]) -> Result<Option<E>, SqliteStoreError> {
+        let value = self
+            .conn
+            .prepare_cached(&format!(
+                "DELETE FROM {TABLE} WHERE key = ? RETURNING value"
+            ))?
+            .query_row([key], |row| row.get::<_, Vec<u8>>(0))
+            .optional()?;
+        value.map(|value| decode(&value)).transpose()
+    }
+}
+
+impl<E> Store<E> for SqliteStore<E>
+where
+    E: RangeEntry + Serialize + DeserializeOwned,
+    E::Key: AsRef<[u8]> + Default,
+{
+    type Error = SqliteStoreError;
+    type RangeIterator<'a> = SqliteIterator<'a, E>
+    where E: 'a;
+    type ParentIterator<'a> = std::vec::IntoIter<Result<E, SqliteStoreError>>
+    where E: 'a;
+
+    fn get_first(&mut self) -> Result<E::Key, Self::Error> {
+        let first = self.get_edge("ASC")?;
+        Ok(first.map(|e| e.key().clone()).unwrap_or_default())
+    }
+
+    fn first_and_last(&mut self) -> Result<Option<(E::Key, E::Key)>, Self::Error> {
+        let (Some(first), Some(last)) = (self.get_edge("ASC")?, self.get_edge("DESC")?) else {
+            return Ok(None);
+        };
+        Ok(Some((first.key().clone(), last.key().clone())))
+    }
+
+    fn get(&mut self, key: &E::Key) -> Result<Option<E>, Self::Error> {
+        let value = self
+            .conn
+            .prepare_cached(&format!("SELECT value FROM {TABLE} WHERE key = ?"))?
+            .query_row([key.as_ref()], |row| row.get::<_, Vec<u8>>(0))
+            .optional()?;
+        value.map(|value| decode(&value)).transpose()
+    }
+
+    fn len(&mut self) -> Result<usize, Self::Error> {
+        let len = self
+            .conn
+            .prepare_cached(&format!("SELECT COUNT(*) FROM {TABLE}"))?
+            .query_row((), |row| row.get::<_, usize>(0))?;
+        Ok(len)
+    }
+
+    fn is_empty(&mut self) -> Result<bool, Self::Error> {
+        Ok(self.get_edge("ASC")?.is_none())
+    }
+
+    fn get_fingerprint(&mut self, range: &Range<E::Key>) -> Result<Fingerprint, Self::Error> {
+        let mut fingerprint = Fingerprint::empty();
+        for scan in scans(range) {
+            let (sql, params) = scan.query("fingerprint", "");
+            let mut stmt = self.conn.prepare_cached(&sql)?;
+            let mut rows = stmt.query(params_from_iter(params))?;
+            while let Some(row) = rows.next()? {
+                fingerprint ^= Fingerprint(row.get(0)?);
+            }
+        }
+        Ok(fingerprint)
+    }
+
+    fn entry_put(&mut self, entry: E) -> Result<(), Self::Error> {
+        let value = postcard::to_stdvec(&entry)?;
+        self.conn
+            .prepare_cached(&format!(
+                "INSERT OR REPLACE INTO {TABLE} (key, value, fingerprint) VALUES (?, ?, ?)"
+            ))?
+            .execute(params![
+                entry.key().as_ref(),
+                value,
+                &entry.as_fingerprint().0[..]
+            ])?;
+        Ok(())
+    }
+
+    fn get_range(&mut self, range: Range<E::Key>) -> Result<Self::RangeIterator<'_>, Self::Error> {
+        Ok(SqliteIterator::new(&self.conn, scans(&range), None))
+    }
+
+    fn get_range_chunked<'a>(
+        &'a mut self,
+        range: Range<E::Key>,
+        chunk_size: usize,
+    ) -> Result<ChunkIterator<'a, E, Self::Error>, Self::Error>
+    where
+        E: 'a,
+    {
+        // chunks are yielded in range order, so for wrap-around ranges we start at x.
+        let mut scans = scans(&range);
+        scans.reverse();
+        let iter = SqliteIterator::new(&self.conn, scans, None);
+        Ok(Box::new(RangeChunks::new(range, chunk_size, iter)))
+    }
+
+    fn get_range_len(&mut self, range: Range<E::Key>) -> Result<usize, Self::Error> {
+        let mut len = 0;
+        for scan in scans(&range) {
+            let (sql, params) = scan.query("COUNT(*)", "");
+            len += self
+                .conn
+                .prepare_cached(&sql)?
+                .query_row(params_from_iter(params), |row| row.get::<_, usize>(0))?;
+        }
+        Ok(len)
+    }
+
+    fn prefixed_by(&mut self, prefix: &E::Key) -> Result<Self::RangeIterator<'_>, Self::Error> {
+        let scan = Scan::new(None, None);
+        Ok(SqliteIterator::new(
+            &self.conn,
+            vec![scan],
+            Some(prefix.clone()),
+        ))
+    }
+
+    fn prefixes_of(&mut self, key: &E::Key) -> Result<Self::ParentIterator<'_>, Self::Error> {
+        let res: Vec<_> = self
+            .all()?
+            .filter(|entry| match entry {
+                Ok(entry) => entry.key().is_prefix_of(key),
+                Err(_) => true,
+            })
+            .collect();
+        Ok(res.into_iter())
+    }
+
+    fn all(&mut self) -> Result<Self::RangeIterator<'_>, Self::Error> {
+        let scan = Scan::new(None, None);
+        Ok(SqliteIterator::new(&self.conn, vec![scan], None))
+    }
+
+    fn entry_remove(&mut self, key: &E::Key) -> Result<Option<E>, Self::Error> {
+        self.remove_key(key.as_ref())
+    }
+
+    fn remove_prefix_filtered(
+        &mut self,
+        prefix: &E::Key,
+        predicate: impl Fn(&E::Value) -> bool,
+    ) -> Result<usize, Self::Error> {
+        let keys = self
+            .prefixed_by(prefix)?
+            .filter_map(|entry| match entry {
+                Ok(entry) => predicate(entry.value()).then(|| Ok(entry.key().clone())),
+                Err(err) => Some(Err(err)),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let tx = self.conn.transaction()?;
+        {
+            let mut stmt = tx.prepare_cached(&format!("DELETE FROM {TABLE} WHERE key = ?"))?;
+            for key in &keys {
+                stmt.execute([key.as_ref()])?;
+            }
+        }
+        tx.commit()?;
+        Ok(keys.len())
+    }
+}
+
+fn decode<E: DeserializeOwned>(bytes: &[u8]) -> Result<E, SqliteStoreError> {
+    Ok(postcard::from_bytes(bytes)?)
+}
+
+/// Iterator over the entries of a [`SqliteStore`].
+///
+/// Fetches the rows in pages, so that no statement is kept open between calls to `next`.
+pub struct SqliteIterator<'a, E: RangeEntry> {
+    conn: &'a Connection,
+    /// The remaining scans, the first one is in progress.
+    scans: VecDeque<Scan>,
+    /// The rows of the current page.
+    page: std::vec::IntoIter<(Vec<u8>, Vec<u8>)>,
+    /// Only yield entries whose key starts with this prefix.
+    prefix: Option<E::Key>,
+}
+
+impl<'a, E: RangeEntry> std::fmt::Debug for SqliteIterator<'a, E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SqliteIterator")
+            .field("scans", &self.scans)
+            .field("prefix", &self.prefix)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<'a, E: RangeEntry> SqliteIterator<'a, E> {
+    fn new(conn: &'a Connection, scans: Vec<Scan>, prefix: Option<E::Key>) -> Self {
+        Self {
+            conn,
+            scans: scans.into(),
+            page: Vec::new().into_iter(),
+            prefix,
+        }
+    }
+
+    /// Fetch the next page of the current scan, moving on to the next scan when it is done.
+    fn fetch_page(&mut self) -> Result<(), SqliteStoreError> {
+        let Some(scan) = self.scans.front_mut() else {
+            return Ok(());
+        };
+        let (sql, params) = scan.query("key, value", &format!("ORDER BY key LIMIT {PAGE_SIZE}"));
+        let rows = self
+            .conn
+            .prepare_cached(&sql)?
+            .query_map(params_from_iter(params), |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })?
+            .collect::<Result<Vec<(Vec<u8>, Vec<u8>)>, _>>()?;
+        match rows.last() {
+            Some((key, _)) if rows.len() == PAGE_SIZE => scan.from = Bound::Excluded(key.clone()),
+            _ => {
+                self.scans.pop_front();
+            }
+        }
+        self.page = rows.into_iter();
+        Ok(())
+    }
+}
+
+impl<'a, E: RangeEntry + DeserializeOwned> Iterator for SqliteIterator<'a, E> {
+    type Item = Result<E, SqliteStoreError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let Some((_, value)) = self.page.next() else {
+                if self.scans.is_empty() {
+                    return None;
+                }
+                if let Err(err) = self.fetch_page() {
+                    self.scans.clear();
+                    return Some(Err(err));
+                }
+                continue;
+            };
+            match (decode::<E>(&value), &self.prefix) {
+                (Ok(entry), Some(prefix)) if !prefix.is_prefix_of(entry.key()) => continue,
+                (entry, _) => return Some(entry),
+            }
+        }
+    }
+}
@@ -0,0 +1,724 @@
+//! Conformance tests for [`Store`] implementations.
+//!
+//! This module is available with the `test-utils` feature. Store implementations can run the
+//! shared test suite from their own tests, either for stores that implement [`Default`]:
+//!
+//! ```
+//! use iroh_docs::ranger::{test_utils, MemoryStore};
+//!
+//! test_utils::conformance::<MemoryStore<test_utils::TestEntry>, _>();
+//! ```
+//!
+//! or with a function that creates empty stores, with [`conformance_with`].
+//!
+//! The suite creates [`TestEntry`]s, and converts them into the entry type of the store with
+//! [`From`], so stores that only support their own entry type can be tested as well.
+//!
+//! With the `futures` feature, [`duplex`] creates an in-memory transport for
+//! [`run_sync`](super::run_sync).
+
+use std::collections::BTreeMap;
+#[cfg(feature = "futures")]
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+
+#[cfg(feature = "futures")]
+use futures_util::{Sink, Stream};
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "futures")]
+use tokio::{
+    sync::mpsc,
+    time::{Instant, Sleep},
+};
+
+use super::{
+    CompressedRangeItem, DeltaRangeItem, Fingerprint, FullFingerprint, InsertOutcome,
+    LazyRangeItem, MemoryStore, Message, MessagePart, Range, RangeEntry, RangeFingerprint,
+    RangeItem, RangeKey, Store, SyncSession, SyncStats,
+};
+use crate::ContentStatus;
+
+/// Key of a [`TestEntry`].
+///
+/// A key is a prefix of another key if it is a string prefix of it.
+#[derive(Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct TestKey(pub String);
+
+impl RangeKey for TestKey {
+    fn is_prefix_of(&self, other: &Self) -> bool {
+        other.0.starts_with(&self.0)
+    }
+}
+
+impl AsRef<[u8]> for TestKey {
+    fn as_ref(&self) -> &[u8] {
+        self.0.as_bytes()
+    }
+}
+
+/// Entry used by the conformance tests.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TestEntry {
+    /// The key of the entry.
+    pub key: TestKey,
+    /// The value of the entry, higher values are newer.
+    pub value: u64,
+}
+
+impl TestEntry {
+    /// Create a new entry.
+    pub fn new(key: &str, value: u64) -> Self {
+        Self {
+            key: TestKey(key.to_string()),
+            value,
+        }
+    }
+}
+
+impl RangeEntry for TestEntry {
+    type Key = TestKey;
+    type Value = u64;
+
+    fn key(&self) -> &TestKey {
+        &self.key
+    }
+
+    fn value(&self) -> &u64 {
+        &self.value
+    }
+
+    fn as_fingerprint(&self) -> Fingerprint {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(self.key.0.as_bytes());
+        hasher.update(&self.value.to_le_bytes());
+        Fingerprint(hasher.finalize().into())
+    }
+}
+
+/// The result of a sync between two stores.
+#[derive(Debug)]
+pub struct SyncResult<S, E: RangeEntry> {
+    /// The store of the initiating side after the sync.
+    pub alice: S,
+    /// The store of the accepting side after the sync.
+    pub bob: S,
+    /// The messages sent from alice to bob.
+    pub alice_to_bob: Vec<Message<E>>,
+    /// The messages sent from bob to alice.
+    pub bob_to_alice: Vec<Message<E>>,
+    /// The statistics of alice's session.
+    pub alice_stats: SyncStats,
+    /// The statistics of bob's session.
+    pub bob_stats: SyncStats,
+    /// Whether alice's session was cancelled.
+    pub alice_cancelled: bool,
+    /// Whether bob's session was cancelled.
+    pub bob_cancelled: bool,
+}
+
+impl<S: Store<E>, E: RangeEntry + PartialEq> SyncResult<S, E> {
+    /// Print all messages, in the order they were sent.
+    pub fn print_messages(&self) {
+        let len = std::cmp::max(self.alice_to_bob.len(), self.bob_to_alice.len());
+        for i in 0..len {
+            if let Some(msg) = self.alice_to_bob.get(i) {
+                println!("A -> B:");
+                print_message(msg);
+            }
+            if let Some(msg) = self.bob_to_alice.get(i) {
+                println!("B -> A:");
+                print_message(msg);
+            }
+        }
+    }
+
+    /// Assert that alice's store contains exactly the `expected` entries.
+    pub fn assert_alice_set(&mut self, ctx: &str, expected: &[E]) {
+        assert_set(&mut self.alice, &format!("{ctx}: (alice)"), expected);
+    }
+
+    /// Assert that bob's store contains exactly the `expected` entries.
+    pub fn assert_bob_set(&mut self, ctx: &str, expected: &[E]) {
+        assert_set(&mut self.bob, &format!("{ctx}: (bob)"), expected);
+    }
+
+    /// Assert that neither side sent an entry more than once.
+    pub fn assert_sent_once(&self) {
+        for (side, messages) in [("alice", &self.alice_to_bob), ("bob", &self.bob_to_alice)] {
+            assert_sent_once(side, messages);
+        }
+    }
+}
+
+/// Assert that no entry is sent more than once in `messages`.
+pub fn assert_sent_once<E: RangeEntry>(side: &str, messages: &[Message<E>]) {
+    let mut sent = BTreeMap::new();
+    for (e, _) in messages.iter().flat_map(Message::values) {
+        assert!(sent.insert(e.key(), e).is_none(), "{side}: duplicate {e:?}");
+    }
+}
+
+fn assert_set<S: Store<E>, E: RangeEntry + PartialEq>(store: &mut S, ctx: &str, expected: &[E]) {
+    for e in expected {
+        assert_eq!(
+            store.get(e.key()).unwrap().as_ref(),
+            Some(e),
+            "{ctx} missing key {:?}",
+            e.key()
+        );
+    }
+    assert_eq!(expected.len(), store.len().unwrap(), "{ctx}");
+}
+
+/// Print the parts of a message.
+pub fn print_message<E: RangeEntry>(msg: &Message<E>) {
+    for part in msg.parts() {
+        match part {
+            MessagePart::RangeFingerprint(RangeFingerprint { range, fingerprint }) => {
+                println!(
+                    "  RangeFingerprint({:?}, {:?}, {:?})",
+                    range.x(),
+                    range.y(),
+                    fingerprint
+                );
+            }
+            MessagePart::RangeItem(RangeItem {
+                range,
+                values,
+                have_local,
+            }) => {
+                println!(
+                    "  RangeItem({:?} | {:?}) (local?: {})\n  {:?}",
+                    range.x(),
+                    range.y(),
+                    have_local,
+                    values,
+                );
+            }
+            MessagePart::Cancel => println!("  Cancel"),
+            MessagePart::Handshake(FullFingerprint { fingerprint, count }) => {
+                println!("  Handshake({:?}, {})", fingerprint, count);
+            }
+            MessagePart::HandshakeMatch => println!("  HandshakeMatch"),
+            MessagePart::SessionId(id) => println!("  {:?}", id),
+            MessagePart::Sequence(seq) => println!("  Sequence({})", seq),
+            MessagePart::Reopen => println!("  Reopen"),
+            MessagePart::Done => println!("  Done"),
+            MessagePart::LazyRangeItem(LazyRangeItem {
+                range,
+                values,
+                have_local,
+            }) => {
+                println!(
+                    "  LazyRangeItem({:?} | {:?}) (local?: {})\n  {:?}",
+                    range.x(),
+                    range.y(),
+                    have_local,
+                    values,
+                );
+            }
+            MessagePart::ValueRequest(keys) => println!("  ValueRequest({:?})", keys),
+            MessagePart::ValueResponse(values) => println!("  ValueResponse({:?})", values),
+            MessagePart::More => println!("  More"),
+            MessagePart::WantKeys { keys } => println!("  WantKeys({:?})", keys),
+            MessagePart::MissingKeys { keys } => println!("  MissingKeys({:?})", keys),
+            MessagePart::StoreSummary(summary) => println!("  {:?}", summary),
+            MessagePart::RangeIds(ids) => println!("  {:?}", ids),
+            MessagePart::ValueChunk {
+                key,
+                index,
+                total,
+                bytes,
+            } => {
+                println!(
+                    "  ValueChunk({:?}) ({}/{}, {} bytes)",
+                    key,
+                    index + 1,
+                    total,
+                    bytes.len()
+                );
+            }
+            MessagePart::Divergent { range, settled } => {
+                println!(
+                    "  Divergent({:?} | {:?}) (settled: {})",
+                    range.x(),
+                    range.y(),
+                    settled
+                );
+            }
+            MessagePart::SendAll { range, max_bytes } => {
+                println!(
+                    "  SendAll({:?} | {:?}) ({} bytes)",
+                    range.x(),
+                    range.y(),
+                    max_bytes
+                );
+            }
+            MessagePart::DeltaRangeItem(DeltaRangeItem {
+                range,
+                values,
+                have_local,
+            }) => {
+                println!(
+                    "  DeltaRangeItem({:?} | {:?}) (local?: {})\n  {} bytes",
+                    range.x(),
+                    range.y(),
+                    have_local,
+                    values.len(),
+                );
+            }
+            MessagePart::CompressedRangeItem(CompressedRangeItem {
+                range,
+                compressed,
+                codec,
+                have_local,
+            }) => {
+                println!(
+                    "  CompressedRangeItem({:?} | {:?}) (local?: {})\n  {:?}, {} bytes",
+                    range.x(),
+                    range.y(),
+                    have_local,
+                    codec,
+                    compressed.len(),
+                );
+            }
+        }
+    }
+}
+
+/// Run a sync between `alice` and `bob` with the given validate callbacks.
+///
+/// # Panics
+///
+/// Panics if a store returns an error, or if the sync takes more than `max_rounds` rounds.
+pub fn sync_exchange_messages<S, E, F1, F2>(
+    alice: S,
+    bob: S,
+    alice_validate_cb: F1,
+    bob_validate_cb: F2,
+    max_rounds: usize,
+) -> SyncResult<S, E>
+where
+    S: Store<E>,
+    E: RangeEntry,
+    F1: Fn(&S, &E, ContentStatus) -> bool,
+    F2: Fn(&S, &E, ContentStatus) -> bool,
+{
+    exchange_messages(
+        alice,
+        bob,
+        alice_validate_cb,
+        bob_validate_cb,
+        max_rounds,
+        None,
+    )
+}
+
+/// Run a sync between `alice` and `bob`, where alice cancels the sync instead of sending her
+/// next message once she sent `rounds` messages.
+///
+/// # Panics
+///
+/// Panics if a store returns an error, or if the sync takes more than 100 rounds.
+pub fn sync_cancel_after<S, E>(alice: S, bob: S, rounds: usize) -> SyncResult<S, E>
+where
+    S: Store<E>,
+    E: RangeEntry,
+{
+    let validate_cb = |_: &S, _: &E, _| true;
+    exchange_messages(alice, bob, validate_cb, validate_cb, 100, Some(rounds))
+}
+
+fn exchange_messages<S, E, F1, F2>(
+    mut alice: S,
+    mut bob: S,
+    alice_validate_cb: F1,
+    bob_validate_cb: F2,
+    max_rounds: usize,
+    cancel_after: Option<usize>,
+) -> SyncResult<S, E>
+where
+    S: Store<E>,
+    E: RangeEntry,
+    F1: Fn(&S, &E, ContentStatus) -> bool,
+    F2: Fn(&S, &E, ContentStatus) -> bool,
+{
+    let mut alice_to_bob = Vec::new();
+    let mut bob_to_alice = Vec::new();
+    let mut alice_session = SyncSession::default();
+    let mut bob_session = SyncSession::default();
+    let initial_message = alice_session.initial_message(&mut alice).unwrap();
+
+    let mut next_to_bob = Some(initial_message);
+    let mut rounds = 0;
+    while let Some(mut msg) = next_to_bob.take() {
+        assert!(rounds < max_rounds, "too many rounds");
+        if cancel_after == Some(rounds) {
+            msg = alice_session.cancel_message();
+        }
+        rounds += 1;
+        alice_to_bob.push(msg.clone());
+
+        if let Some(msg) = bob_session
+            .process_message(
+                &mut bob,
+                msg,
+                &bob_validate_cb,
+                |_, _, _| (),
+                |_, _| ContentStatus::Complete,
+            )
+            .unwrap()
+        {
+            bob_to_alice.push(msg.clone());
+            next_to_bob = alice_session
+                .process_message(
+                    &mut alice,
+                    msg,
+                    &alice_validate_cb,
+                    |_, _, _| (),
+                    |_, _| ContentStatus::Complete,
+                )
+                .unwrap();
+        }
+    }
+    SyncResult {
+        alice,
+        bob,
+        alice_to_bob,
+        bob_to_alice,
+        alice_stats: alice_session.take_stats(),
+        bob_stats: bob_session.take_stats(),
+        alice_cancelled: alice_session.is_cancelled(),
+        bob_cancelled: bob_session.is_cancelled(),
+    }
+}
+
+/// Fill two new stores with the given entries, sync them, and assert that both end up with the
+/// union of the entries, after prefix deletion, and that no entry was sent twice.
+pub fn sync<S, E>(
+    mut new_store: impl FnMut() -> S,
+    alice_set: &[E],
+    bob_set: &[E],
+) -> SyncResult<S, E>
+where
+    S: Store<E>,
+    E: RangeEntry + PartialEq,
+    E::Key: Default,
+{
+    let (mut alice, mut bob) = (new_store(), new_store());
+    let mut expected = MemoryStore::default();
+    for e in alice_set {
+        alice.put(e.clone()).unwrap();
+        expected.put(e.clone()).unwrap();
+    }
+    for e in bob_set {
+        bob.put(e.clone()).unwrap();
+        expected.put(e.clone()).unwrap();
+    }
+    let expected: Vec<_> = expected.into_entries().collect();
+
+    let mut res = sync_exchange_messages(alice, bob, |_, _, _| true, |_, _, _| true, 100);
+    res.assert_alice_set("sync", &expected);
+    res.assert_bob_set("sync", &expected);
+    res.assert_sent_once();
+    res
+}
+
+/// Run the conformance tests against stores that are created with [`Default`].
+pub fn conformance<S, E>()
+where
+    S: Store<E> + Default,
+    E: RangeEntry + From<TestEntry> + PartialEq,
+    E::Key: Default,
+{
+    conformance_with(S::default)
+}
+
+/// Run the conformance tests against stores created with `new_store`.
+///
+/// `new_store` must return an empty store on every call.
+pub fn conformance_with<S, E>(mut new_store: impl FnMut() -> S)
+where
+    S: Store<E>,
+    E: RangeEntry + From<TestEntry> + PartialEq,
+    E::Key: Default,
+{
+    check_get_range(&mut new_store);
+    check_fingerprints(&mut new_store);
+    check_remove(&mut new_store);
+    check_sync(&mut new_store);
+}
+
+fn entries<E: From<TestEntry>>(keys: &[&str]) -> Vec<E> {
+    keys.iter().map(|k| TestEntry::new(k, 1).into()).collect()
+}
+
+fn key<E: RangeEntry + From<TestEntry>>(key: &str) -> E::Key {
+    E::from(TestEntry::new(key, 0)).key().clone()
+}
+
+fn range<E: RangeEntry + From<TestEntry>>(x: &str, y: &str) -> Range<E::Key> {
+    Range::new(key::<E>(x), key::<E>(y))
+}
+
+fn collect<S: Store<E>, E: RangeEntry>(iter: impl Iterator<Item = Result<E, S::Error>>) -> Vec<E> {
+    iter.collect::<Result<_, _>>().unwrap()
+}
+
+/// The entries of the paper sets, with `bee` to `hog` in one store.
+const SET: [&str; 6] = ["bee", "cat", "doe", "eel", "fox", "hog"];
+
+/// Check [`Store::get_range`], [`Store::get_range_len`] and [`Store::get_range_chunked`],
+/// including wrap-around ranges.
+pub fn check_get_range<S, E>(mut new_store: impl FnMut() -> S)
+where
+    S: Store<E>,
+    E: RangeEntry + From<TestEntry> + PartialEq,
+{
+    let mut store = new_store();
+    let set: Vec<E> = entries(&SET);
+    for e in &set {
+        store.entry_put(e.clone()).unwrap();
+    }
+
+    let cases: [(&str, &str, Vec<E>); 6] = [
+        ("", "", set.clone()),
+        ("bee", "eel", set[..3].to_vec()),
+        // empty start
+        ("", "eel", set[..3].to_vec()),
+        ("cat", "hog", set[1..5].to_vec()),
+        ("fox", "bee", set[4..].to_vec()),
+        ("fox", "doe", [&set[..2], &set[4..]].concat()),
+    ];
+    for (x, y, expected) in cases {
+        let range = range::<E>(x, y);
+        let actual = collect::<S, E>(store.get_range(range.clone()).unwrap());
+        assert_eq!(actual, expected, "get_range {range:?}");
+        let len = store.get_range_len(range.clone()).unwrap();
+        assert_eq!(len, expected.len(), "get_range_len {range:?}");
+
+        // chunks are in range order, starting at x
+        let mut chunked = vec![];
+        for chunk in store.get_range_chunked(range.clone(), 2).unwrap() {
+            let chunk = chunk.unwrap();
+            assert!(chunk.entries.len() <= 2, "chunk size {range:?}");
+            chunked.extend(chunk.entries);
+        }
+        let mut in_range_order = expected.clone();
+        in_range_order.sort_by_key(|e| e.key() < range.x());
+        assert_eq!(chunked, in_range_order, "get_range_chunked {range:?}");
+    }
+}
+
+/// Check that [`Store::get_fingerprint`] is the XOR of the fingerprints of the entries in the
+/// range, and that the fingerprint of an empty range is [`Fingerprint::empty`].
+pub fn check_fingerprints<S, E>(mut new_store: impl FnMut() -> S)
+where
+    S: Store<E>,
+    E: RangeEntry + From<TestEntry> + PartialEq,
+{
+    let mut store = new_store();
+    let all = range::<E>("", "");
+    assert_eq!(store.get_fingerprint(&all).unwrap(), Fingerprint::empty());
+
+    let set: Vec<E> = entries(&SET);
+    for e in &set {
+        store.entry_put(e.clone()).unwrap();
+    }
+    for x in SET.iter().chain([&"", &"zzz"]) {
+        for y in SET.iter().chain([&"", &"zzz"]) {
+            let range = range::<E>(x, y);
+            let mut expected = Fingerprint::empty();
+            for e in set.iter().filter(|e| range.contains(e.key())) {
+                expected ^= e.as_fingerprint();
+            }
+            let actual = store.get_fingerprint(&range).unwrap();
+            assert_eq!(actual, expected, "get_fingerprint {range:?}");
+        }
+    }
+
+    // replacing an entry replaces its fingerprint
+    let replaced: E = TestEntry::new("cat", 2).into();
+    store.entry_put(replaced.clone()).unwrap();
+    let mut expected = Fingerprint::empty();
+    for e in set.iter().filter(|e| e.key() != replaced.key()) {
+        expected ^= e.as_fingerprint();
+    }
+    expected ^= replaced.as_fingerprint();
+    assert_eq!(store.get_fingerprint(&all).unwrap(), expected);
+}
+
+/// Check [`Store::entry_remove`], [`Store::remove_prefix_filtered`] and prefix deletion in
+/// [`Store::put`].
+pub fn check_remove<S, E>(mut new_store: impl FnMut() -> S)
+where
+    S: Store<E>,
+    E: RangeEntry + From<TestEntry> + PartialEq,
+{
+    let mut store = new_store();
+    let set: Vec<E> = entries(&["/foo", "/foo/bar", "/foo/baz", "/qux"]);
+    for e in &set {
+        store.entry_put(e.clone()).unwrap();
+    }
+
+    let removed = store.entry_remove(&key::<E>("/qux")).unwrap();
+    assert_eq!(removed.as_ref(), Some(&set[3]));
+    assert_eq!(store.entry_remove(&key::<E>("/qux")).unwrap(), None);
+    assert_eq!(store.get(&key::<E>("/qux")).unwrap(), None);
+    assert_eq!(store.len().unwrap(), 3);
+
+    // only entries with the prefix for which the predicate holds are removed
+    let removed = store
+        .remove_prefix_filtered(&key::<E>("/foo/"), |_| true)
+        .unwrap();
+    assert_eq!(removed, 2);
+    assert_eq!(collect::<S, E>(store.all().unwrap()), &set[..1]);
+    let removed = store
+        .remove_prefix_filtered(&key::<E>("/foo"), |_| false)
+        .unwrap();
+    assert_eq!(removed, 0);
+
+    // an entry is not inserted if an entry with a prefix of its key is newer or equal
+    let outcome = store.put(TestEntry::new("/foo/bar", 1).into()).unwrap();
+    assert!(matches!(outcome, InsertOutcome::NotInserted), "{outcome:?}");
+    // a newer entry removes the older entries it is a prefix of
+    store
+        .entry_put(TestEntry::new("/foo/bar", 1).into())
+        .unwrap();
+    let newer: E = TestEntry::new("/foo", 2).into();
+    let outcome = store.put(newer.clone()).unwrap();
+    assert!(
+        matches!(outcome, InsertOutcome::Inserted { removed: 2 }),
+        "{outcome:?}"
+    );
+    assert_eq!(collect::<S, E>(store.all().unwrap()), [newer]);
+}
+
+/// Check that syncing two stores converges, for the sets of the paper and sets with prefixes.
+pub fn check_sync<S, E>(mut new_store: impl FnMut() -> S)
+where
+    S: Store<E>,
+    E: RangeEntry + From<TestEntry> + PartialEq,
+    E::Key: Default,
+{
+    let sets: [(&[&str], &[&str]); 6] = [
+        (&["ape", "eel", "fox", "gnu"], &SET),
+        (
+            &["ape", "bee", "cat", "doe", "eel", "fox", "gnu", "hog"],
+            &["ape", "bee", "cat", "doe", "eel", "gnu", "hog"],
+        ),
+        (
+            &["ape", "bee", "cat", "doe", "eel", "fox", "gnu", "hog"],
+            &["ape", "cat", "eel", "gnu"],
+        ),
+        (
+            &["/foo/bar", "/foo/baz", "/foo/cat"],
+            &["/foo/bar", "/alice/bar", "/alice/baz"],
+        ),
+        (&[], &["/foo/bar", "/alice/bar", "/alice/baz"]),
+        (&["/foo/bar", "/foo/baz", "/foo/cat"], &[]),
+    ];
+    for (alice_set, bob_set) in sets {
+        sync(&mut new_store, &entries(alice_set), &entries(bob_set));
+    }
+
+    // a newer entry on one side replaces the older entries it is a prefix of on the other side
+    let alice_set: Vec<E> = vec![TestEntry::new("/foo", 2).into()];
+    let bob_set: Vec<E> = entries(&["/foo/bar", "/foo/baz", "/qux"]);
+    let mut res = sync(&mut new_store, &alice_set, &bob_set);
+    let expected = [alice_set[0].clone(), bob_set[2].clone()];
+    res.assert_alice_set("prefix", &expected);
+}
+
+/// Error returned from a [`MemoryTransport`] when sending after the transport was closed, or
+/// after the other end was dropped.
+#[cfg(feature = "futures")]
+#[derive(Debug, thiserror::Error)]
+#[error("memory transport closed")]
+pub struct TransportClosed;
+
+/// One end of an in-memory transport for [`run_sync`](super::run_sync), see [`duplex`].
+///
+/// Messages are delivered in order, each once the latency of the transport has passed since it
+/// was sent. Closing the transport ends the stream of the other end after all messages sent
+/// before were delivered.
+#[cfg(feature = "futures")]
+#[derive(Debug)]
+pub struct MemoryTransport<E: RangeEntry> {
+    latency: Duration,
+    tx: Option<mpsc::UnboundedSender<(Instant, Message<E>)>>,
+    rx: mpsc::UnboundedReceiver<(Instant, Message<E>)>,
+    next: Option<(Pin<Box<Sleep>>, Message<E>)>,
+}
+
+// messages are never pinned
+#[cfg(feature = "futures")]
+impl<E: RangeEntry> Unpin for MemoryTransport<E> {}
+
+/// Creates the two ends of an in-memory transport, which delays each message by `latency`.
+#[cfg(feature = "futures")]
+pub fn duplex<E: RangeEntry>(latency: Duration) -> (MemoryTransport<E>, MemoryTransport<E>) {
+    let (a_tx, b_rx) = mpsc::unbounded_channel();
+    let (b_tx, a_rx) = mpsc::unbounded_channel();
+    let end = |tx, rx| MemoryTransport {
+        latency,
+        tx: Some(tx),
+        rx,
+        next: None,
+    };
+    (end(a_tx, a_rx), end(b_tx, b_rx))
+}
+
+#[cfg(feature = "futures")]
+impl<E: RangeEntry> Sink<Message<E>> for MemoryTransport<E> {
+    type Error = TransportClosed;
+
+    fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        match &self.tx {
+            Some(tx) if !tx.is_closed() => Poll::Ready(Ok(())),
+            _ => Poll::Ready(Err(TransportClosed)),
+        }
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Message<E>) -> Result<(), Self::Error> {
+        let tx = self.tx.as_ref().ok_or(TransportClosed)?;
+        tx.send((Instant::now() + self.latency, item))
+            .map_err(|_| TransportClosed)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+    ) -> Poll<Result<(), Self::Error>> {
+        self.tx = None;
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(feature = "futures")]
+impl<E: RangeEntry> Stream for MemoryTransport<E> {
+    type Item = Result<Message<E>, TransportClosed>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.next.is_none() {
+            let Some((deliver_at, message)) = ready!(self.rx.poll_recv(cx)) else {
+                return Poll::Ready(None);
+            };
+            self.next = Some((Box::pin(tokio::time::sleep_until(deliver_at)), message));
+        }
+        if let Some((sleep, _)) = &mut self.next {
+            ready!(sleep.as_mut().poll(cx));
+        }
+        let (_, message) = self.next.take().expect("checked above");
+        Poll::Ready(Some(Ok(message)))
+    }
+}
@@ -0,0 +1,116 @@
+//! Entries with a timestamp, for newer-wins conflict resolution, see [`TimestampedEntry`].
+
+use serde::{Deserialize, Serialize};
+
+use super::{Fingerprint, RangeEntry, RangeValue};
+
+/// An entry together with the time it was written at.
+///
+/// The key is that of the wrapped entry, and the value is ordered by the timestamp first, so
+/// that the newer of two entries with the same key replaces the older one with [`Store::put`],
+/// regardless of the order of the wrapped values. The fingerprint covers the timestamp too.
+///
+/// Received entries that are older than the stored ones are rejected with
+/// [`validators::newer_wins`], and the newer local entries are sent back to the remote, so that
+/// both sides converge in the same session.
+///
+/// [`Store::put`]: super::Store::put
+/// [`validators::newer_wins`]: super::validators::newer_wins
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(
+    from = "TimestampedRepr<E>",
+    into = "TimestampedRepr<E>",
+    bound(serialize = "E: Serialize", deserialize = "E: Deserialize<'de>")
+)]
+pub struct TimestampedEntry<E: RangeEntry> {
+    entry: E,
+    value: TimestampedValue<E::Value>,
+}
+
+/// The value of a [`TimestampedEntry`], ordered by the timestamp and then by the value of the
+/// wrapped entry.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct TimestampedValue<V> {
+    /// The time the entry was written at.
+    pub timestamp: u64,
+    /// The value of the wrapped entry.
+    pub value: V,
+}
+
+impl<V: RangeValue> RangeValue for TimestampedValue<V> {}
+
+/// The encoding of a [`TimestampedEntry`], which does not repeat the value of the entry.
+#[derive(Serialize, Deserialize)]
+struct TimestampedRepr<E> {
+    entry: E,
+    timestamp: u64,
+}
+
+impl<E: RangeEntry> From<TimestampedRepr<E>> for TimestampedEntry<E> {
+    fn from(repr: TimestampedRepr<E>) -> Self {
+        Self::new(repr.entry, repr.timestamp)
+    }
+}
+
+impl<E: RangeEntry> From<TimestampedEntry<E>> for TimestampedRepr<E> {
+    fn from(entry: TimestampedEntry<E>) -> Self {
+        TimestampedRepr {
+            entry: entry.entry,
+            timestamp: entry.value.timestamp,
+        }
+    }
+}
+
+impl<E: RangeEntry> TimestampedEntry<E> {
+    /// Wrap an entry that was written at `timestamp`.
+    pub fn new(entry: E, timestamp: u64) -> Self {
+        let value = TimestampedValue {
+            timestamp,
+            value: entry.value().clone(),
+        };
+        Self { entry, value }
+    }
+
+    /// Get the wrapped entry.
+    pub fn entry(&self) -> &E {
+        &self.entry
+    }
+
+    /// Get the time the entry was written at.
+    pub fn timestamp(&self) -> u64 {
+        self.value.timestamp
+    }
+
+    /// Unwrap the entry.
+    pub fn into_entry(self) -> E {
+        self.entry
+    }
+}
+
+impl<E: RangeEntry> RangeEntry for TimestampedEntry<E> {
+    type Key = E::Key;
+    type Value = TimestampedValue<E::Value>;
+
+    fn key(&self) -> &E::Key {
+        self.entry.key()
+    }
+
+    fn value(&self) -> &TimestampedValue<E::Value> {
+        &self.value
+    }
+
+    fn as_fingerprint(&self) -> Fingerprint {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(&self.entry.as_fingerprint().0);
+        hasher.update(&self.value.timestamp.to_le_bytes());
+        Fingerprint(*hasher.finalize().as_bytes())
+    }
+
+    fn encoded_size_hint(&self) -> u64 {
+        self.entry.encoded_size_hint() + std::mem::size_of::<u64>() as u64
+    }
+
+    fn deletes_prefixed(&self) -> bool {
+        self.entry.deletes_prefixed()
+    }
+}
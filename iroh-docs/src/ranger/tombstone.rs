@@ -0,0 +1,285 @@
+//! Entries that can be deleted, see [`Tombstoned`].
+
+use serde::{Deserialize, Serialize};
+
+use super::{Fingerprint, InsertOutcome, RangeEntry, RangeValue, Store};
+
+/// An entry, or a tombstone that marks the deletion of a key or of all keys with a prefix.
+///
+/// Removing an entry from a store does not stop the next sync from inserting it again, if the
+/// remote still has it. A tombstone is an entry of its own, which is synced like any other and
+/// replaces the entry of its key, so that deletions propagate to the remote.
+///
+/// Entries and tombstones are ordered by their [`TombstoneValue`]: the newer one replaces the
+/// older one with [`Store::put`], and a tombstone replaces an entry with the same value. An entry
+/// resurrects a deleted key if its value is newer than the tombstone.
+///
+/// A tombstone only deletes its own key. A prefix tombstone deletes its key and all keys it is a
+/// prefix of, see [`RangeKey::is_prefix_of`]: it replaces the entries with such keys that are not
+/// newer than it, and keeps older ones from being inserted, while newer ones are inserted next to
+/// it. Entries of the wrapped type delete the keys they are a prefix of if the wrapped entry does,
+/// see [`RangeEntry::deletes_prefixed`].
+///
+/// Tombstones are written with [`TombstoneStore::delete`] and
+/// [`TombstoneStore::delete_prefix`], and removed for good with
+/// [`TombstoneStore::purge_tombstones`].
+///
+/// [`RangeKey::is_prefix_of`]: super::RangeKey::is_prefix_of
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(
+    from = "TombstonedRepr<E>",
+    into = "TombstonedRepr<E>",
+    bound(
+        serialize = "E: Serialize, E::Key: Serialize, E::Value: Serialize",
+        deserialize = "E: Deserialize<'de>, E::Key: Deserialize<'de>, E::Value: Deserialize<'de>"
+    )
+)]
+pub struct Tombstoned<E: RangeEntry> {
+    key: E::Key,
+    value: TombstoneValue<E::Value>,
+    /// The entry, or `None` for a tombstone.
+    entry: Option<E>,
+}
+
+/// The value of a [`Tombstoned`] entry.
+///
+/// Values are ordered by the value of the entry, or the value the key was deleted at, and then
+/// by their [`TombstoneKind`], so that a tombstone is newer than an entry with the same value.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct TombstoneValue<V> {
+    /// The value of the entry, or the value the key was deleted at.
+    pub value: V,
+    /// Whether this is the value of an entry or of a tombstone.
+    pub kind: TombstoneKind,
+}
+
+impl<V: RangeValue> RangeValue for TombstoneValue<V> {}
+
+/// The kind of a [`Tombstoned`] entry, in ascending order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum TombstoneKind {
+    /// A live entry.
+    Entry,
+    /// A tombstone that deletes its key.
+    Tombstone,
+    /// A tombstone that deletes its key and all keys it is a prefix of.
+    PrefixTombstone,
+}
+
+/// The encoding of a [`Tombstoned`] entry, which does not repeat the key and value of an entry.
+#[derive(Serialize, Deserialize)]
+#[serde(bound(
+    serialize = "E: Serialize, E::Key: Serialize, E::Value: Serialize",
+    deserialize = "E: Deserialize<'de>, E::Key: Deserialize<'de>, E::Value: Deserialize<'de>"
+))]
+enum TombstonedRepr<E: RangeEntry> {
+    Entry(E),
+    Tombstone { key: E::Key, value: E::Value },
+    PrefixTombstone { prefix: E::Key, value: E::Value },
+}
+
+impl<E: RangeEntry> From<TombstonedRepr<E>> for Tombstoned<E> {
+    fn from(repr: TombstonedRepr<E>) -> Self {
+        match repr {
+            TombstonedRepr::Entry(entry) => Self::entry(entry),
+            TombstonedRepr::Tombstone { key, value } => Self::tombstone(key, value),
+            TombstonedRepr::PrefixTombstone { prefix, value } => {
+                Self::prefix_tombstone(prefix, value)
+            }
+        }
+    }
+}
+
+impl<E: RangeEntry> From<Tombstoned<E>> for TombstonedRepr<E> {
+    fn from(entry: Tombstoned<E>) -> Self {
+        match (entry.entry, entry.value.kind) {
+            (Some(entry), _) => TombstonedRepr::Entry(entry),
+            (None, TombstoneKind::PrefixTombstone) => TombstonedRepr::PrefixTombstone {
+                prefix: entry.key,
+                value: entry.value.value,
+            },
+            (None, _) => TombstonedRepr::Tombstone {
+                key: entry.key,
+                value: entry.value.value,
+            },
+        }
+    }
+}
+
+impl<E: RangeEntry> Tombstoned<E> {
+    /// Wrap a live entry.
+    pub fn entry(entry: E) -> Self {
+        Self {
+            key: entry.key().clone(),
+            value: TombstoneValue {
+                value: entry.value().clone(),
+                kind: TombstoneKind::Entry,
+            },
+            entry: Some(entry),
+        }
+    }
+
+    /// Create a tombstone that deletes `key` at `value`.
+    ///
+    /// The tombstone replaces the entries of `key` whose values are not newer than `value`.
+    pub fn tombstone(key: E::Key, value: E::Value) -> Self {
+        Self {
+            key,
+            value: TombstoneValue {
+                value,
+                kind: TombstoneKind::Tombstone,
+            },
+            entry: None,
+        }
+    }
+
+    /// Create a tombstone that deletes `prefix` and all keys it is a prefix of at `value`.
+    ///
+    /// The tombstone replaces the entries of these keys whose values are not newer than `value`.
+    pub fn prefix_tombstone(prefix: E::Key, value: E::Value) -> Self {
+        Self {
+            key: prefix,
+            value: TombstoneValue {
+                value,
+                kind: TombstoneKind::PrefixTombstone,
+            },
+            entry: None,
+        }
+    }
+
+    /// Returns `true` if this is a tombstone, of a key or of a prefix.
+    pub fn is_tombstone(&self) -> bool {
+        self.entry.is_none()
+    }
+
+    /// Get the kind of this entry.
+    pub fn kind(&self) -> TombstoneKind {
+        self.value.kind
+    }
+
+    /// Get the live entry, or `None` for a tombstone.
+    pub fn as_entry(&self) -> Option<&E> {
+        self.entry.as_ref()
+    }
+
+    /// Unwrap the live entry, or `None` for a tombstone.
+    pub fn into_entry(self) -> Option<E> {
+        self.entry
+    }
+}
+
+impl<E> RangeEntry for Tombstoned<E>
+where
+    E: RangeEntry,
+    E::Key: Serialize,
+    E::Value: Serialize,
+{
+    type Key = E::Key;
+    type Value = TombstoneValue<E::Value>;
+
+    fn key(&self) -> &E::Key {
+        &self.key
+    }
+
+    fn value(&self) -> &TombstoneValue<E::Value> {
+        &self.value
+    }
+
+    fn as_fingerprint(&self) -> Fingerprint {
+        match &self.entry {
+            Some(entry) => entry.as_fingerprint(),
+            None => {
+                let key = postcard::to_stdvec(&self.key).expect("key can be encoded");
+                let value = postcard::to_stdvec(&self.value.value).expect("value can be encoded");
+                let mut hasher = blake3::Hasher::new();
+                match self.value.kind {
+                    TombstoneKind::PrefixTombstone => hasher.update(b"prefix tombstone"),
+                    _ => hasher.update(b"tombstone"),
+                };
+                hasher.update(&key);
+                hasher.update(&value);
+                Fingerprint(*hasher.finalize().as_bytes())
+            }
+        }
+    }
+
+    fn encoded_size_hint(&self) -> u64 {
+        match &self.entry {
+            Some(entry) => 1 + entry.encoded_size_hint(),
+            None => {
+                1 + std::mem::size_of_val(&self.key) as u64 + std::mem::size_of::<E::Value>() as u64
+            }
+        }
+    }
+
+    fn deletes_prefixed(&self) -> bool {
+        match &self.entry {
+            Some(entry) => entry.deletes_prefixed(),
+            None => self.value.kind == TombstoneKind::PrefixTombstone,
+        }
+    }
+}
+
+/// Helpers for stores of [`Tombstoned`] entries.
+///
+/// This is implemented for all such stores.
+pub trait TombstoneStore<E>: Store<Tombstoned<E>>
+where
+    E: RangeEntry,
+    E::Key: Serialize,
+    E::Value: Serialize,
+{
+    /// Delete `key` at `value`, by inserting a tombstone with [`Store::put`].
+    ///
+    /// The tombstone is not inserted if the store has a newer entry of `key`, or a newer entry
+    /// that deletes a prefix of `key`.
+    fn delete(&mut self, key: E::Key, value: E::Value) -> Result<InsertOutcome, Self::Error> {
+        self.put(Tombstoned::tombstone(key, value))
+    }
+
+    /// Delete `prefix` and all keys it is a prefix of at `value`, by inserting a prefix
+    /// tombstone with [`Store::put`].
+    ///
+    /// This removes the entries of these keys that are not newer than `value` from the store,
+    /// and from the remotes the tombstone is synced to.
+    fn delete_prefix(
+        &mut self,
+        prefix: E::Key,
+        value: E::Value,
+    ) -> Result<InsertOutcome, Self::Error> {
+        self.put(Tombstoned::prefix_tombstone(prefix, value))
+    }
+
+    /// Get the live entry of `key`, or `None` if there is none or the key is deleted.
+    fn get_live(&mut self, key: &E::Key) -> Result<Option<E>, Self::Error> {
+        Ok(self.get(key)?.and_then(Tombstoned::into_entry))
+    }
+
+    /// Remove the tombstones and prefix tombstones whose values are older than `older_than`, and
+    /// return how many were removed.
+    ///
+    /// A remote that still has an entry of a purged key inserts it again on the next sync, so
+    /// tombstones should only be purged once all remotes have received them.
+    fn purge_tombstones(&mut self, older_than: &E::Value) -> Result<usize, Self::Error> {
+        let mut purged = Vec::new();
+        for entry in self.all()? {
+            let entry = entry?;
+            if entry.is_tombstone() && entry.value.value < *older_than {
+                purged.push(entry.key);
+            }
+        }
+        for key in &purged {
+            self.entry_remove(key)?;
+        }
+        Ok(purged.len())
+    }
+}
+
+impl<E, S> TombstoneStore<E> for S
+where
+    E: RangeEntry,
+    E::Key: Serialize,
+    E::Value: Serialize,
+    S: Store<Tombstoned<E>>,
+{
+}
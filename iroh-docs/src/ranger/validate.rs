@@ -0,0 +1,161 @@
+//! Structural validation of received messages, see [`Message::validate`].
+
+use std::collections::BTreeSet;
+
+use serde::{Deserialize, Serialize};
+
+use super::{Message, MessagePart, RangeEntry, SyncConfig};
+
+/// The recursion depth the default limits are derived from.
+///
+/// With the default split factor, a sync this deep reconciles sets of about a million entries.
+const TYPICAL_DEPTH: u32 = 20;
+
+/// Limits on the size of a single message, see [`Message::validate`].
+///
+/// The defaults are derived from the default split factor and a typical recursion depth of 20: a message
+/// has at most one part per range of the deepest level, and a range holds only a few values
+/// before it is split. This is far beyond what a sync of two well-behaved stores needs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MessageLimits {
+    /// Maximum number of parts of a message.
+    pub max_parts: usize,
+    /// Maximum number of values of a message, summed over all parts.
+    pub max_values: usize,
+}
+
+impl Default for MessageLimits {
+    fn default() -> Self {
+        let max_parts = SyncConfig::default().split_factor.pow(TYPICAL_DEPTH);
+        Self {
+            max_parts,
+            max_values: max_parts << 4,
+        }
+    }
+}
+
+/// A message is malformed, see [`Message::validate`].
+///
+/// Keys and ranges are given as their debug representation, so they can be logged together
+/// with the remote that sent the message.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum MessageValidationError {
+    /// The message has more parts than allowed.
+    #[error("message has {parts} parts, more than the maximum of {max}")]
+    TooManyParts {
+        /// The number of parts of the message.
+        parts: usize,
+        /// See [`MessageLimits::max_parts`].
+        max: usize,
+    },
+    /// The message has more values than allowed.
+    #[error("message has more than the maximum of {max} values")]
+    TooManyValues {
+        /// See [`MessageLimits::max_values`].
+        max: usize,
+    },
+    /// A value of a part is not contained in the range of the part.
+    #[error("part {part} has a value with key {key} outside of its range {range}")]
+    KeyOutOfRange {
+        /// The index of the part in the message.
+        part: usize,
+        /// The debug representation of the key of the value.
+        key: String,
+        /// The debug representation of the range of the part.
+        range: String,
+    },
+    /// A part has more than one value with the same key.
+    #[error("part {part} has more than one value with key {key}")]
+    DuplicateKey {
+        /// The index of the part in the message.
+        part: usize,
+        /// The debug representation of the key.
+        key: String,
+    },
+    /// A part is in a compact encoding that has to be expanded before the message is applied to
+    /// a store, e.g. with [`SyncSession::decode_message`](super::SyncSession::decode_message).
+    #[error("part {part} has to be expanded before the message is applied")]
+    UnexpandedPart {
+        /// The index of the part in the message.
+        part: usize,
+    },
+}
+
+impl<E: RangeEntry> Message<E> {
+    /// Checks that the message is well-formed, before it is applied to a store.
+    ///
+    /// The values of each [`MessagePart::RangeItem`] and [`MessagePart::LazyRangeItem`] must lie
+    /// within the range of the part, the keys of each part must be distinct, and the message
+    /// must stay within `limits`, where the keys of requests count as values. Otherwise a
+    /// remote could e.g. send values for ranges that were already settled.
+    pub fn validate(&self, limits: &MessageLimits) -> Result<(), MessageValidationError> {
+        if self.parts.len() > limits.max_parts {
+            return Err(MessageValidationError::TooManyParts {
+                parts: self.parts.len(),
+                max: limits.max_parts,
+            });
+        }
+        let mut values = 0;
+        for (part, message_part) in self.parts.iter().enumerate() {
+            // the keys of the part, and the range they must lie in
+            let (range, keys): (_, Vec<&E::Key>) = match message_part {
+                MessagePart::RangeItem(item) => (
+                    Some(&item.range),
+                    item.values.iter().map(|(entry, _)| entry.key()).collect(),
+                ),
+                MessagePart::LazyRangeItem(item) => (
+                    Some(&item.range),
+                    item.values.iter().map(|(key, _)| key).collect(),
+                ),
+                MessagePart::ValueResponse(values) => {
+                    (None, values.iter().map(|(entry, _)| entry.key()).collect())
+                }
+                MessagePart::ValueRequest(keys)
+                | MessagePart::WantKeys { keys }
+                | MessagePart::MissingKeys { keys } => (None, keys.iter().collect()),
+                _ => continue,
+            };
+            values += keys.len();
+            if values > limits.max_values {
+                return Err(MessageValidationError::TooManyValues {
+                    max: limits.max_values,
+                });
+            }
+            let mut seen = BTreeSet::new();
+            for key in keys {
+                if let Some(range) = range.filter(|range| !range.contains(key)) {
+                    return Err(MessageValidationError::KeyOutOfRange {
+                        part,
+                        key: format!("{:?}", key),
+                        range: format!("{:?}", range),
+                    });
+                }
+                if !seen.insert(key) {
+                    return Err(MessageValidationError::DuplicateKey {
+                        part,
+                        key: format!("{:?}", key),
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that the message has no parts that have to be expanded before it is applied to a
+    /// store, see [`MessageValidationError::UnexpandedPart`].
+    pub(super) fn check_expanded(&self) -> Result<(), MessageValidationError> {
+        let part = self.parts.iter().position(|part| {
+            matches!(
+                part,
+                MessagePart::CompressedRangeItem(_)
+                    | MessagePart::DeltaRangeItem(_)
+                    | MessagePart::LazyRangeItem(_)
+                    | MessagePart::ValueChunk { .. }
+            )
+        });
+        match part {
+            Some(part) => Err(MessageValidationError::UnexpandedPart { part }),
+            None => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,75 @@
+//! Validate callbacks for common checks of received entries.
+//!
+//! The callbacks are passed to [`SyncSession::with_validator`], so entries that fail the check
+//! are rejected before they reach the store. To use them with
+//! [`SyncSession::process_message`], wrap them in a closure that ignores the store. Callbacks
+//! that compare received entries with the stored ones, such as [`newer_wins`], are passed to
+//! [`SyncSession::process_message`] directly.
+//!
+//! [`SyncSession::with_validator`]: super::SyncSession::with_validator
+//! [`SyncSession::process_message`]: super::SyncSession::process_message
+
+use crate::ContentStatus;
+
+use super::{RangeEntry, SignedEntry, TimestampedEntry, Verifier};
+
+/// Stores whose entries can be read through a shared reference, which is all that validate
+/// callbacks get.
+///
+/// This is implemented for [`MemoryStore`] and [`VecStore`]. The store wrappers in this crate
+/// implement it if the stores they wrap do, except for [`BoxedStore`], which does not know the
+/// type of its store.
+///
+/// [`MemoryStore`]: super::MemoryStore
+/// [`VecStore`]: super::VecStore
+/// [`BoxedStore`]: super::BoxedStore
+pub trait EntryLookup<E: RangeEntry> {
+    /// Get the entry of `key`, if any.
+    fn lookup(&self, key: &E::Key) -> Option<&E>;
+}
+
+impl<E: RangeEntry, S: EntryLookup<E>> EntryLookup<E> for &mut S {
+    fn lookup(&self, key: &E::Key) -> Option<&E> {
+        (**self).lookup(key)
+    }
+}
+
+/// Returns a validate callback that accepts the [`SignedEntry`]s whose signature is valid, see
+/// [`Verifier::verify`].
+///
+/// Entries with an invalid signature are counted as rejected, see
+/// [`SyncStats::entries_rejected`].
+///
+/// [`SyncStats::entries_rejected`]: super::SyncStats::entries_rejected
+pub fn verify_signed<V, E>(
+) -> impl Fn(&SignedEntry<E, V::Signature>, ContentStatus) -> bool + Clone + Send + Sync + 'static
+where
+    V: Verifier<E> + 'static,
+    E: 'static,
+{
+    |entry, _| entry.verify::<V>()
+}
+
+/// Returns a validate callback that rejects the [`TimestampedEntry`]s that are older than the
+/// stored entries of their keys.
+///
+/// Rejected entries are counted as rejected, see [`SyncStats::entries_rejected`], and the newer
+/// stored entries are sent back to the remote in reply to the rejected ones, regardless of the
+/// [`EchoPolicy`], so that the remote receives them in the same session. Entries with the same
+/// timestamp are accepted, and [`Store::put`] keeps the one with the greater value.
+///
+/// [`SyncStats::entries_rejected`]: super::SyncStats::entries_rejected
+/// [`EchoPolicy`]: super::EchoPolicy
+/// [`Store::put`]: super::Store::put
+pub fn newer_wins<S, E>(
+) -> impl Fn(&S, &TimestampedEntry<E>, ContentStatus) -> bool + Clone + Send + Sync + 'static
+where
+    S: EntryLookup<TimestampedEntry<E>>,
+    E: RangeEntry,
+{
+    |store, entry, _| {
+        store
+            .lookup(entry.key())
+            .map_or(true, |stored| entry.timestamp() >= stored.timestamp())
+    }
+}
@@ -0,0 +1,250 @@
+//! In-memory store backed by a sorted [`Vec`].
+
+use std::{convert::Infallible, iter::Chain, slice};
+
+use super::{
+    validators::EntryLookup, ChunkIterator, Fingerprint, Range, RangeChunks, RangeEntry, RangeKey,
+    Store,
+};
+
+/// A [`Store`] that keeps all entries in a [`Vec`], sorted by key.
+///
+/// Lookups use binary search and ranges are contiguous slices, which makes this store compact
+/// and cache friendly. Inserting or removing a single entry is O(n) though, so this store is
+/// meant for sets that are built once, e.g. with [`VecStore::from_sorted_iter`], and then
+/// reconciled many times.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VecStore<E> {
+    /// Sorted by key, without duplicate keys.
+    entries: Vec<E>,
+}
+
+impl<E> Default for VecStore<E> {
+    fn default() -> Self {
+        Self {
+            entries: Default::default(),
+        }
+    }
+}
+
+impl<E: RangeEntry> VecStore<E> {
+    /// Create a new, empty store.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a store from entries that are sorted by key.
+    ///
+    /// This is much faster than inserting the entries one by one.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the entries are not strictly ascending by key.
+    pub fn from_sorted_iter(iter: impl IntoIterator<Item = E>) -> Self {
+        let entries: Vec<E> = iter.into_iter().collect();
+        assert!(
+            entries.windows(2).all(|w| w[0].key() < w[1].key()),
+            "entries must be sorted by key without duplicates"
+        );
+        Self { entries }
+    }
+
+    /// Get the entries, ordered by key.
+    pub fn as_slice(&self) -> &[E] {
+        &self.entries
+    }
+
+    /// Consume the store and return its entries, ordered by key.
+    pub fn into_entries(self) -> Vec<E> {
+        self.entries
+    }
+
+    fn find(&self, key: &E::Key) -> Result<usize, usize> {
+        self.entries.binary_search_by(|entry| entry.key().cmp(key))
+    }
+
+    /// Index of the first entry with a key not smaller than `key`.
+    fn lower_bound(&self, key: &E::Key) -> usize {
+        self.entries.partition_point(|entry| entry.key() < key)
+    }
+
+    /// The entries in `range`, as two slices in key order.
+    fn slices(&self, range: &Range<E::Key>) -> (&[E], &[E]) {
+        let (x, y) = (self.lower_bound(range.x()), self.lower_bound(range.y()));
+        if range.x() < range.y() {
+            (&self.entries[x..y], &[])
+        } else {
+            // the range wraps around (or is the full range), so we need two segments: from the
+            // start to y, and from x to the end.
+            (&self.entries[..y], &self.entries[x..])
+        }
+    }
+}
+
+impl<E: RangeEntry> EntryLookup<E> for VecStore<E> {
+    fn lookup(&self, key: &E::Key) -> Option<&E> {
+        self.find(key).ok().map(|i| &self.entries[i])
+    }
+}
+
+impl<E: RangeEntry> FromIterator<E> for VecStore<E> {
+    /// Create a store from entries in any order.
+    ///
+    /// Entries are inserted with [`Store::entry_put`] semantics, so later entries replace
+    /// earlier entries with the same key.
+    fn from_iter<T: IntoIterator<Item = E>>(iter: T) -> Self {
+        let mut entries: Vec<E> = iter.into_iter().collect();
+        // the sort is stable, so of entries with equal keys the last one is inserted last
+        entries.sort_by(|a, b| a.key().cmp(b.key()));
+        let mut deduped: Vec<E> = Vec::with_capacity(entries.len());
+        for entry in entries {
+            match deduped.last_mut() {
+                Some(last) if last.key() == entry.key() => *last = entry,
+                _ => deduped.push(entry),
+            }
+        }
+        Self { entries: deduped }
+    }
+}
+
+impl<E: RangeEntry> Store<E> for VecStore<E>
+where
+    E::Key: Default,
+{
+    type Error = Infallible;
+    type RangeIterator<'a> = VecRangeIterator<'a, E>
+    where E: 'a;
+    type ParentIterator<'a> = std::vec::IntoIter<Result<E, Infallible>>
+    where E: 'a;
+
+    fn get_first(&mut self) -> Result<E::Key, Self::Error> {
+        match self.entries.first() {
+            Some(entry) => Ok(entry.key().clone()),
+            None => Ok(Default::default()),
+        }
+    }
+
+    fn first_and_last(&mut self) -> Result<Option<(E::Key, E::Key)>, Self::Error> {
+        let first = self.entries.first().map(|e| e.key().clone());
+        let last = self.entries.last().map(|e| e.key().clone());
+        Ok(first.zip(last))
+    }
+
+    fn get(&mut self, key: &E::Key) -> Result<Option<E>, Self::Error> {
+        Ok(self.find(key).ok().map(|i| self.entries[i].clone()))
+    }
+
+    fn len(&mut self) -> Result<usize, Self::Error> {
+        Ok(self.entries.len())
+    }
+
+    fn is_empty(&mut self) -> Result<bool, Self::Error> {
+        Ok(self.entries.is_empty())
+    }
+
+    fn get_fingerprint(&mut self, range: &Range<E::Key>) -> Result<Fingerprint, Self::Error> {
+        let (first, second) = self.slices(range);
+        let mut fp = Fingerprint::empty();
+        for entry in first.iter().chain(second) {
+            fp ^= entry.as_fingerprint();
+        }
+        Ok(fp)
+    }
+
+    fn entry_put(&mut self, entry: E) -> Result<(), Self::Error> {
+        match self.find(entry.key()) {
+            Ok(i) => self.entries[i] = entry,
+            Err(i) => self.entries.insert(i, entry),
+        }
+        Ok(())
+    }
+
+    fn get_range(&mut self, range: Range<E::Key>) -> Result<Self::RangeIterator<'_>, Self::Error> {
+        let (first, second) = self.slices(&range);
+        Ok(VecRangeIterator::new(first, second, None))
+    }
+
+    fn get_range_chunked<'a>(
+        &'a mut self,
+        range: Range<E::Key>,
+        chunk_size: usize,
+    ) -> Result<ChunkIterator<'a, E, Self::Error>, Self::Error>
+    where
+        E: 'a,
+    {
+        // chunks are yielded in range order, so for wrap-around ranges we start at x.
+        let (first, second) = self.slices(&range);
+        let iter = VecRangeIterator::new(second, first, None);
+        Ok(Box::new(RangeChunks::new(range, chunk_size, iter)))
+    }
+
+    fn get_range_len(&mut self, range: Range<E::Key>) -> Result<usize, Self::Error> {
+        let (first, second) = self.slices(&range);
+        Ok(first.len() + second.len())
+    }
+
+    fn prefixed_by(&mut self, prefix: &E::Key) -> Result<Self::RangeIterator<'_>, Self::Error> {
+        let prefix = Some(prefix.clone());
+        Ok(VecRangeIterator::new(&self.entries, &[], prefix))
+    }
+
+    fn prefixes_of(&mut self, key: &E::Key) -> Result<Self::ParentIterator<'_>, Self::Error> {
+        let res: Vec<_> = self
+            .entries
+            .iter()
+            .filter(|entry| entry.key().is_prefix_of(key))
+            .map(|entry| Ok(entry.clone()))
+            .collect();
+        Ok(res.into_iter())
+    }
+
+    fn all(&mut self) -> Result<Self::RangeIterator<'_>, Self::Error> {
+        Ok(VecRangeIterator::new(&self.entries, &[], None))
+    }
+
+    fn entry_remove(&mut self, key: &E::Key) -> Result<Option<E>, Self::Error> {
+        Ok(self.find(key).ok().map(|i| self.entries.remove(i)))
+    }
+
+    fn remove_prefix_filtered(
+        &mut self,
+        prefix: &E::Key,
+        predicate: impl Fn(&E::Value) -> bool,
+    ) -> Result<usize, Self::Error> {
+        let old_len = self.entries.len();
+        self.entries.retain(|entry| {
+            let remove = prefix.is_prefix_of(entry.key()) && predicate(entry.value());
+            !remove
+        });
+        Ok(old_len - self.entries.len())
+    }
+}
+
+/// Iterator over the entries of a [`VecStore`].
+#[derive(Debug)]
+pub struct VecRangeIterator<'a, E: RangeEntry> {
+    iter: Chain<slice::Iter<'a, E>, slice::Iter<'a, E>>,
+    /// Only yield entries whose key starts with this prefix.
+    prefix: Option<E::Key>,
+}
+
+impl<'a, E: RangeEntry> VecRangeIterator<'a, E> {
+    fn new(first: &'a [E], second: &'a [E], prefix: Option<E::Key>) -> Self {
+        Self {
+            iter: first.iter().chain(second),
+            prefix,
+        }
+    }
+}
+
+impl<'a, E: RangeEntry> Iterator for VecRangeIterator<'a, E> {
+    type Item = Result<E, Infallible>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let entry = match &self.prefix {
+            None => self.iter.next()?,
+            Some(prefix) => self.iter.find(|entry| prefix.is_prefix_of(entry.key()))?,
+        };
+        Some(Ok(entry.clone()))
+    }
+}
@@ -0,0 +1,339 @@
+//! The wire encoding of messages, see [`Message::encode`].
+
+use std::{io, marker::PhantomData};
+
+use serde::{
+    de::DeserializeOwned,
+    ser::{SerializeSeq, SerializeStruct},
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+
+use super::{Message, MessagePart, MessageValidationError, ProcessError, RangeEntry};
+
+/// Size of the length prefix of a frame.
+const PREFIX_LEN: usize = 4;
+
+/// Error returned from [`Message::decode_framed`] and [`MessagePartReader`].
+#[derive(Debug, thiserror::Error)]
+pub enum DecodeError {
+    /// The buffer does not contain a whole frame yet.
+    #[error("incomplete frame, {needed} bytes needed")]
+    Incomplete {
+        /// Number of bytes the buffer needs at least, including the length prefix. Once the
+        /// length prefix is complete, this is the size of the whole frame.
+        needed: usize,
+    },
+    /// The frame exceeds the maximum size, so its body is not read.
+    #[error("frame of {len} bytes exceeds the maximum of {max} bytes")]
+    TooLarge {
+        /// The size of the frame body, as announced by the length prefix.
+        len: usize,
+        /// The maximum size of a frame body.
+        max: usize,
+    },
+    /// The frame body is not a valid message, or not a valid part.
+    #[error("failed to decode message: {0}")]
+    Encoding(#[from] postcard::Error),
+    /// Reading a part failed, e.g. because the stream ended before the end of the message.
+    #[error("failed to read part: {0}")]
+    Io(#[from] io::Error),
+}
+
+/// Error returned from [`Store::process_parts`].
+///
+/// [`Store::process_parts`]: super::Store::process_parts
+#[derive(Debug, thiserror::Error)]
+pub enum PartsError<S> {
+    /// A part could not be read or decoded.
+    #[error(transparent)]
+    Decode(DecodeError),
+    /// The store returned an error.
+    #[error("store error: {0:?}")]
+    Store(S),
+    /// A part cannot be applied to a store, see [`ProcessError::InvalidMessage`].
+    #[error(transparent)]
+    InvalidMessage(MessageValidationError),
+}
+
+impl<S> From<ProcessError<S>> for PartsError<S> {
+    fn from(err: ProcessError<S>) -> Self {
+        match err {
+            ProcessError::Store(err) => Self::Store(err),
+            ProcessError::InvalidMessage(err) => Self::InvalidMessage(err),
+        }
+    }
+}
+
+impl<E: RangeEntry> Message<E> {
+    /// Encodes the message for the wire.
+    ///
+    /// Messages are encoded with [postcard], so integers other than `u8` are varints,
+    /// sequences are prefixed with their length as a varint, and enum variants with their index
+    /// as a varint. Fields are encoded in the order listed here, without names or padding:
+    ///
+    /// * [`Message`]: the number of parts, followed by the parts. If [`Message::has_more`] is
+    ///   set, a `More` part is appended, and counted in the number of parts.
+    /// * [`MessagePart`]: the index of the variant, followed by its fields:
+    ///   * 0 `RangeFingerprint`: range, fingerprint
+    ///   * 1 `RangeItem`: range, values, `have_local` as a byte of 0 or 1
+    ///   * 2 `Cancel`
+    ///   * 3 `Handshake`: fingerprint, count
+    ///   * 4 `HandshakeMatch`
+    ///   * 5 `SessionId`: 16 bytes
+    ///   * 6 `Sequence`: the sequence number
+    ///   * 7 `Reopen`
+    ///   * 8 `CompressedRangeItem`: range, compressed values as a byte sequence, the index of
+    ///     the [`Codec`], `have_local`
+    ///   * 9 `Done`
+    ///   * 10 `LazyRangeItem`: range, the number of keys, followed by each key, encoded as the
+    ///     key type, and its fingerprint, `have_local`
+    ///   * 11 `ValueRequest`: the number of keys, followed by the keys
+    ///   * 12 `ValueResponse`: values
+    ///   * 13 `More`
+    ///   * 14 `WantKeys`: the number of keys, followed by the keys
+    ///   * 15 `MissingKeys`: the number of keys, followed by the keys
+    ///   * 16 `StoreSummary`: the number of entries, the first and the last key, each as a
+    ///     byte of 0 if the store is empty, or 1 followed by the key, the fingerprint of all
+    ///     entries
+    ///   * 17 `DeltaRangeItem`: range, the encoded values as a byte sequence, `have_local`. The
+    ///     values are encoded as their number, followed by each value: the length of the prefix
+    ///     its key shares with the key of the previous value, the remaining bytes of the key as a
+    ///     byte sequence, the entry without its key, see [`SplitKey`], and its [`ContentStatus`]
+    ///   * 18 `SendAll`: range, `max_bytes`
+    ///   * 19 `Divergent`: range, `settled` as a byte of 0 or 1
+    ///   * 20 `ValueChunk`: key, index, total, bytes as a byte sequence
+    ///   * 21 `RangeIds`: the number of ids, followed by the ids, the number of ids responded
+    ///     to, followed by these ids, the index of the message in its response, `last` as a
+    ///     byte of 0 or 1
+    /// * [`Range`]: `x`, then `y`, each encoded as the key type.
+    /// * [`Fingerprint`]: 32 bytes.
+    /// * values: the number of values, followed by each entry, encoded as the entry type, and
+    ///   its [`ContentStatus`] as a variant index: 0 complete, 1 incomplete, 2 missing.
+    ///
+    /// The fixtures in `tests/fixtures/wire` pin this encoding for a set of messages. New parts
+    /// are only ever appended, see [`PROTOCOL_VERSION`].
+    ///
+    /// Frames, see [`Message::encode_framed`], prefix the encoded message with its length as a
+    /// little-endian `u32`. Streams of parts, see [`Message::encode_parts`], instead frame each
+    /// part on its own, and end the message with an empty frame.
+    ///
+    /// [`PROTOCOL_VERSION`]: super::PROTOCOL_VERSION
+    /// [`Codec`]: super::Codec
+    /// [`SplitKey`]: super::SplitKey
+    /// [`Range`]: super::Range
+    /// [`Fingerprint`]: super::Fingerprint
+    /// [`ContentStatus`]: crate::ContentStatus
+    pub fn encode(&self) -> Result<Vec<u8>, postcard::Error>
+    where
+        Self: Serialize,
+    {
+        postcard::to_stdvec(self)
+    }
+
+    /// Decodes a message encoded with [`Message::encode`].
+    ///
+    /// Bytes after the message are ignored.
+    pub fn decode(bytes: &[u8]) -> Result<Self, postcard::Error>
+    where
+        Self: DeserializeOwned,
+    {
+        postcard::from_bytes(bytes)
+    }
+
+    /// Appends the message to `buf` as a frame: the length of the body as a little-endian
+    /// `u32`, followed by the message, see [`Message::encode`].
+    ///
+    /// Fails if the message cannot be encoded, or its encoding does not fit into a `u32`.
+    pub fn encode_framed(&self, buf: &mut Vec<u8>) -> Result<(), postcard::Error>
+    where
+        Self: Serialize,
+    {
+        let body = self.encode()?;
+        let len = u32::try_from(body.len()).map_err(|_| postcard::Error::SerializeBufferFull)?;
+        buf.reserve(PREFIX_LEN + body.len());
+        buf.extend_from_slice(&len.to_le_bytes());
+        buf.extend_from_slice(&body);
+        Ok(())
+    }
+
+    /// Appends the message to `buf` as a stream of parts, which can be read one part at a time
+    /// with a [`MessagePartReader`].
+    ///
+    /// Each part is framed like a message, see [`Message::encode_framed`], followed by a
+    /// `More` part if [`Message::has_more`] is set, and an empty frame that ends the message.
+    pub fn encode_parts(&self, buf: &mut Vec<u8>) -> Result<(), postcard::Error>
+    where
+        MessagePart<E>: Serialize,
+    {
+        let more = self.more.then_some(MessagePart::More);
+        for part in self.parts.iter().chain(more.as_ref()) {
+            let body = postcard::to_stdvec(part)?;
+            let len =
+                u32::try_from(body.len()).map_err(|_| postcard::Error::SerializeBufferFull)?;
+            buf.reserve(PREFIX_LEN + body.len());
+            buf.extend_from_slice(&len.to_le_bytes());
+            buf.extend_from_slice(&body);
+        }
+        buf.extend_from_slice(&0u32.to_le_bytes());
+        Ok(())
+    }
+
+    /// Decodes the frame at the start of `buf`, see [`Message::encode_framed`], and returns
+    /// the message together with the size of the frame, so the caller can advance past it.
+    ///
+    /// Frames with a body larger than `max_size` are rejected with [`DecodeError::TooLarge`]
+    /// before the body is read. If `buf` does not contain the whole frame yet,
+    /// [`DecodeError::Incomplete`] is returned, and the call can be repeated once more bytes
+    /// were received.
+    pub fn decode_framed(buf: &[u8], max_size: usize) -> Result<(Self, usize), DecodeError>
+    where
+        Self: DeserializeOwned,
+    {
+        let Some(prefix) = buf.get(..PREFIX_LEN) else {
+            return Err(DecodeError::Incomplete { needed: PREFIX_LEN });
+        };
+        let len = u32::from_le_bytes(prefix.try_into().expect("prefix length")) as usize;
+        if len > max_size {
+            return Err(DecodeError::TooLarge { len, max: max_size });
+        }
+        let end = PREFIX_LEN.saturating_add(len);
+        let Some(body) = buf.get(PREFIX_LEN..end) else {
+            return Err(DecodeError::Incomplete { needed: end });
+        };
+        let message = Self::decode(body)?;
+        Ok((message, end))
+    }
+}
+
+/// Reads the parts of a message one at a time, as written by [`Message::encode_parts`].
+///
+/// This allows to process the parts of a large message while later parts are still being
+/// received, see [`Store::process_parts`]. The iterator ends after the empty frame that ends
+/// the message, and the next message can be read from the reader returned by
+/// [`MessagePartReader::into_inner`]. A stream that ends before that fails with
+/// [`DecodeError::Io`]. Parts larger than the maximum size fail with [`DecodeError::TooLarge`]
+/// before they are read. The iterator ends after the first error.
+///
+/// [`Store::process_parts`]: super::Store::process_parts
+#[derive(Debug)]
+pub struct MessagePartReader<R, E> {
+    reader: R,
+    max_size: usize,
+    buf: Vec<u8>,
+    done: bool,
+    _entry: PhantomData<fn() -> E>,
+}
+
+impl<R: io::Read, E: RangeEntry> MessagePartReader<R, E> {
+    /// Create a reader for the parts of the next message in `reader`, each of at most
+    /// `max_size` bytes.
+    pub fn new(reader: R, max_size: usize) -> Self {
+        Self {
+            reader,
+            max_size,
+            buf: Vec::new(),
+            done: false,
+            _entry: PhantomData,
+        }
+    }
+
+    /// Returns `true` once the message was read to its end, or reading it failed.
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    /// Get back the reader, e.g. to read the next message.
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+
+    fn read_part(&mut self) -> Result<Option<MessagePart<E>>, DecodeError>
+    where
+        MessagePart<E>: DeserializeOwned,
+    {
+        let mut prefix = [0u8; PREFIX_LEN];
+        self.reader.read_exact(&mut prefix)?;
+        let len = u32::from_le_bytes(prefix) as usize;
+        if len == 0 {
+            return Ok(None);
+        }
+        if len > self.max_size {
+            return Err(DecodeError::TooLarge {
+                len,
+                max: self.max_size,
+            });
+        }
+        self.buf.resize(len, 0);
+        self.reader.read_exact(&mut self.buf)?;
+        Ok(Some(postcard::from_bytes(&self.buf)?))
+    }
+}
+
+impl<R: io::Read, E: RangeEntry> Iterator for MessagePartReader<R, E>
+where
+    MessagePart<E>: DeserializeOwned,
+{
+    type Item = Result<MessagePart<E>, DecodeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let part = self.read_part().transpose();
+        self.done = !matches!(part, Some(Ok(_)));
+        part
+    }
+}
+
+impl<E: RangeEntry> Serialize for Message<E>
+where
+    MessagePart<E>: Serialize,
+{
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut message = serializer.serialize_struct("Message", 1)?;
+        message.serialize_field("parts", &WireParts(self))?;
+        message.end()
+    }
+}
+
+impl<'de, E: RangeEntry> Deserialize<'de> for Message<E>
+where
+    MessagePart<E>: Deserialize<'de>,
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let WireMessage { mut parts } = WireMessage::deserialize(deserializer)?;
+        let len = parts.len();
+        parts.retain(|part| !matches!(part, MessagePart::More));
+        let more = parts.len() < len;
+        Ok(Message { parts, more })
+    }
+}
+
+/// The parts of a message as encoded, with a trailing [`MessagePart::More`] if more messages
+/// follow.
+struct WireParts<'a, E: RangeEntry>(&'a Message<E>);
+
+impl<E: RangeEntry> Serialize for WireParts<'_, E>
+where
+    MessagePart<E>: Serialize,
+{
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let Message { parts, more } = self.0;
+        let mut seq = serializer.serialize_seq(Some(parts.len() + usize::from(*more)))?;
+        for part in parts {
+            seq.serialize_element(part)?;
+        }
+        if *more {
+            seq.serialize_element(&MessagePart::<E>::More)?;
+        }
+        seq.end()
+    }
+}
+
+/// A message as decoded, before a [`MessagePart::More`] is turned into the flag.
+#[derive(Deserialize)]
+#[serde(rename = "Message")]
+struct WireMessage<E: RangeEntry> {
+    #[serde(bound(deserialize = "MessagePart<E>: Deserialize<'de>"))]
+    parts: Vec<MessagePart<E>>,
+}
@@ -18,7 +18,7 @@ use redb::{Database, DatabaseError, ReadableMultimapTable, ReadableTable, Readab
 
 use crate::{
     keys::Author,
-    ranger::{Fingerprint, Range, RangeEntry},
+    ranger::{ChunkIterator, Fingerprint, Range, RangeChunks, RangeEntry},
     sync::{Entry, EntrySignature, Record, RecordIdentifier, Replica, SignedEntry},
     AuthorHeads, AuthorId, Capability, CapabilityKind, NamespaceId, NamespaceSecret, PeerIdBytes,
     ReplicaInfo,
@@ -763,6 +763,48 @@ impl<'a> crate::ranger::Store<SignedEntry> for StoreInstance<'a> {
         Ok(iter)
     }
 
+    fn get_range_chunked<'x>(
+        &'x mut self,
+        range: Range<RecordIdentifier>,
+        chunk_size: usize,
+    ) -> Result<ChunkIterator<'x, SignedEntry, anyhow::Error>>
+    where
+        SignedEntry: 'x,
+    {
+        let tables = self.store.as_mut().tables()?;
+        // chunks need the entries in range order, so for wrap-around ranges we first iterate
+        // from range.x to the end, and then from the start to range.y.
+        let iter = match range.x().cmp(range.y()) {
+            // identity range outside of our namespace (happens for empty replicas): iter1 = all
+            Ordering::Equal if range.x().namespace() != self.namespace => {
+                let bounds = RecordsBounds::namespace(self.namespace);
+                let iter = RecordsRange::with_bounds(&tables.records, bounds)?;
+                chain_none(iter)
+            }
+            // regular range: iter1 = x <= t < y, iter2 = none
+            Ordering::Less => {
+                let start = Bound::Included(range.x().to_byte_tuple());
+                let end = Bound::Excluded(range.y().to_byte_tuple());
+                let bounds = RecordsBounds::new(start, end);
+                let iter = RecordsRange::with_bounds(&tables.records, bounds)?;
+                chain_none(iter)
+            }
+            // identity or split range: iter1 = x <= t <= end, iter2 = start <= t < y
+            Ordering::Equal | Ordering::Greater => {
+                let start = Bound::Included(range.x().to_byte_tuple());
+                let bounds = RecordsBounds::to_end(&self.namespace, start);
+                let iter = RecordsRange::with_bounds(&tables.records, bounds)?;
+
+                let end = Bound::Excluded(range.y().to_byte_tuple());
+                let bounds = RecordsBounds::from_start(&self.namespace, end);
+                let iter2 = RecordsRange::with_bounds(&tables.records, bounds)?;
+
+                iter.chain(Some(iter2).into_iter().flatten())
+            }
+        };
+        Ok(Box::new(RangeChunks::new(range, chunk_size, iter)))
+    }
+
     fn entry_remove(&mut self, id: &RecordIdentifier) -> Result<Option<SignedEntry>> {
         self.store.as_mut().modify(|tables| {
             let entry = {
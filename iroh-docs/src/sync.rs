@@ -526,7 +526,7 @@ where
             .store
             .get_download_policy(&my_namespace)
             .unwrap_or_default();
-        let reply = self.store.process_message(
+        let reply = self.store.process_message_reply(
             &Default::default(),
             message,
             // validate callback: validate incoming entries, and send to on_insert channel
@@ -973,7 +973,6 @@ impl Debug for RecordIdentifier {
 }
 
 impl RangeKey for RecordIdentifier {
-    #[cfg(test)]
     fn is_prefix_of(&self, other: &Self) -> bool {
         other.as_ref().starts_with(self.as_ref())
     }
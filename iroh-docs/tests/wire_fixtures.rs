@@ -0,0 +1,287 @@
+//! Pins the wire encoding of messages, see the docs of `iroh_docs::ranger::Message::encode`.
+//!
+//! Each message is compared against the hex encoding in `tests/fixtures/wire/<name>.hex`. If
+//! the encoding changed on purpose, the fixtures can be rewritten by running the test with
+//! `UPDATE_WIRE_FIXTURES=1`.
+
+use std::path::PathBuf;
+
+use iroh_docs::{
+    ranger::{
+        Codec, CompressedRangeItem, DeltaRangeItem, Fingerprint, FullFingerprint, LazyRangeItem,
+        MemoryStore, Message, MessageBuilder, MessagePart, Range, RangeEntry, RangeIds, RangeKey,
+        RangeValue, SessionId, Store, StoreSummary,
+    },
+    ContentStatus,
+};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+struct Key(Vec<u8>);
+
+impl RangeKey for Key {
+    fn is_prefix_of(&self, other: &Self) -> bool {
+        other.0.starts_with(&self.0)
+    }
+}
+
+impl From<&str> for Key {
+    fn from(key: &str) -> Self {
+        Key(key.as_bytes().to_vec())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+struct Value(u64);
+
+impl RangeValue for Value {}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Entry(Key, Value);
+
+impl RangeEntry for Entry {
+    type Key = Key;
+    type Value = Value;
+
+    fn key(&self) -> &Key {
+        &self.0
+    }
+
+    fn value(&self) -> &Value {
+        &self.1
+    }
+
+    fn as_fingerprint(&self) -> Fingerprint {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(&self.0 .0);
+        hasher.update(&self.1 .0.to_le_bytes());
+        Fingerprint(hasher.finalize().into())
+    }
+}
+
+fn entry(key: impl Into<Key>, value: u64) -> Entry {
+    Entry(key.into(), Value(value))
+}
+
+fn range(x: &str, y: &str) -> Range<Key> {
+    Range::new(x.into(), y.into())
+}
+
+/// The messages whose encoding is pinned, by name.
+fn messages() -> Vec<(&'static str, Message<Entry>)> {
+    let empty_store_init = MemoryStore::<Entry>::new().initial_message().unwrap();
+
+    let mut store: MemoryStore<_> = [entry("ape", 1), entry("bee", 2), entry("cat", 3)]
+        .into_iter()
+        .collect();
+    let mut fingerprint = |x, y| store.get_fingerprint(&range(x, y)).unwrap();
+    let fingerprint_only = MessageBuilder::new()
+        .add_fingerprint(range("ape", "bee"), fingerprint("ape", "bee"))
+        .add_fingerprint(range("bee", "cat"), fingerprint("bee", "cat"))
+        .add_fingerprint(range("cat", "ape"), fingerprint("cat", "ape"))
+        .build();
+
+    let mixed_parts = MessageBuilder::new()
+        .add_part(MessagePart::SessionId(SessionId([7; 16])))
+        .add_part(MessagePart::Sequence(300))
+        .add_part(MessagePart::Reopen)
+        .add_fingerprint(range("a", "m"), Fingerprint([0xab; 32]))
+        .add_items(
+            range("m", "t"),
+            vec![
+                (entry("moth", 1), ContentStatus::Complete),
+                (entry("newt", u64::MAX), ContentStatus::Incomplete),
+                (entry("owl", 128), ContentStatus::Missing),
+            ],
+            true,
+        )
+        .add_items(range("t", "a"), Vec::new(), false)
+        .add_part(MessagePart::Handshake(FullFingerprint {
+            fingerprint: Fingerprint::empty(),
+            count: 3,
+        }))
+        .add_part(MessagePart::HandshakeMatch)
+        .add_part(MessagePart::CompressedRangeItem(CompressedRangeItem {
+            range: range("x", "y"),
+            compressed: vec![0x28, 0xb5, 0x2f, 0xfd],
+            codec: Codec::Zstd,
+            have_local: false,
+        }))
+        .add_part(MessagePart::Done)
+        .add_part(MessagePart::Cancel)
+        .build();
+
+    // ranges with x > y wrap around the end of the set
+    let wrap_around = MessageBuilder::new()
+        .add_items(
+            range("yak", "bee"),
+            vec![
+                (entry("yak", 1), ContentStatus::Complete),
+                (entry("zebra", 2), ContentStatus::Complete),
+                (entry("ape", 3), ContentStatus::Complete),
+            ],
+            false,
+        )
+        .add_fingerprint(range("bee", "yak"), Fingerprint([0x01; 32]))
+        .add_fingerprint(range("", ""), Fingerprint::empty())
+        .build();
+
+    let unicode_binary_keys = MessageBuilder::new()
+        .add_items(
+            Range::new(Key(vec![0x00]), Key(vec![0xff, 0xff])),
+            vec![
+                (
+                    entry(Key(vec![0x00, 0xff, 0x80]), 1),
+                    ContentStatus::Complete,
+                ),
+                (entry("ключ", 2), ContentStatus::Complete),
+                (entry("🦀", 3), ContentStatus::Complete),
+                (entry(Key(Vec::new()), 4), ContentStatus::Complete),
+            ],
+            false,
+        )
+        .build();
+
+    let lazy_values = MessageBuilder::new()
+        .add_part(MessagePart::LazyRangeItem(LazyRangeItem {
+            range: range("ape", "cat"),
+            values: vec![
+                ("ape".into(), entry("ape", 1).as_fingerprint()),
+                ("bee".into(), entry("bee", 2).as_fingerprint()),
+            ],
+            have_local: false,
+        }))
+        .add_part(MessagePart::ValueRequest(vec!["bee".into(), "cat".into()]))
+        .add_part(MessagePart::ValueResponse(vec![(
+            entry("cat", 3),
+            ContentStatus::Incomplete,
+        )]))
+        .build();
+
+    // the flag is encoded as a trailing part
+    let more = MessageBuilder::new()
+        .add_fingerprint(range("ape", "bee"), Fingerprint([0x02; 32]))
+        .build()
+        .with_more(true);
+
+    let want_keys = MessageBuilder::new()
+        .add_part(MessagePart::WantKeys {
+            keys: vec!["ape".into(), "eel".into()],
+        })
+        .add_part(MessagePart::MissingKeys {
+            keys: vec!["eel".into()],
+        })
+        .build();
+
+    let store_summary = MessageBuilder::new()
+        .add_part(MessagePart::StoreSummary(StoreSummary {
+            entry_count: 2,
+            first_key: Some("ape".into()),
+            last_key: Some("bee".into()),
+            full_fingerprint: Fingerprint([0x03; 32]),
+        }))
+        .build();
+
+    // "apple" and "apricot" delta encoded, each with value 1 and complete content
+    let delta_keys = MessageBuilder::new()
+        .add_part(MessagePart::DeltaRangeItem(DeltaRangeItem {
+            range: range("a", "b"),
+            values: vec![
+                0x02, 0x00, 0x05, b'a', b'p', b'p', b'l', b'e', 0x01, 0x00, 0x02, 0x05, b'r', b'i',
+                b'c', b'o', b't', 0x01, 0x00,
+            ],
+            have_local: true,
+        }))
+        .build();
+
+    let send_all = MessageBuilder::new()
+        .add_part(MessagePart::SendAll {
+            range: range("ape", "ape"),
+            max_bytes: 16 * 1024,
+        })
+        .build();
+
+    let divergent = MessageBuilder::new()
+        .add_part(MessagePart::Divergent {
+            range: range("ape", "bee"),
+            settled: false,
+        })
+        .add_part(MessagePart::Divergent {
+            range: range("bee", "ape"),
+            settled: true,
+        })
+        .build();
+
+    let value_chunk = MessageBuilder::new()
+        .add_part(MessagePart::ValueChunk {
+            key: "ape".into(),
+            index: 1,
+            total: 3,
+            bytes: vec![0, 1, 2, 0xff],
+        })
+        .build();
+
+    let range_ids = MessageBuilder::new()
+        .add_part(MessagePart::RangeIds(RangeIds {
+            ids: vec![3, 4],
+            responds_to: vec![1],
+            piece: 2,
+            last: true,
+        }))
+        .add_fingerprint(range("ape", "bee"), fingerprint("ape", "bee"))
+        .build();
+
+    vec![
+        ("empty_store_init", empty_store_init),
+        ("fingerprint_only", fingerprint_only),
+        ("mixed_parts", mixed_parts),
+        ("wrap_around", wrap_around),
+        ("unicode_binary_keys", unicode_binary_keys),
+        ("lazy_values", lazy_values),
+        ("more", more),
+        ("want_keys", want_keys),
+        ("store_summary", store_summary),
+        ("delta_keys", delta_keys),
+        ("send_all", send_all),
+        ("divergent", divergent),
+        ("value_chunk", value_chunk),
+        ("range_ids", range_ids),
+    ]
+}
+
+fn fixture_path(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures/wire")
+        .join(format!("{name}.hex"))
+}
+
+#[test]
+fn wire_fixtures() {
+    let update = std::env::var_os("UPDATE_WIRE_FIXTURES").is_some();
+    let mut drifted = Vec::new();
+    for (name, message) in messages() {
+        let encoded = hex::encode(message.encode().unwrap());
+        let path = fixture_path(name);
+        if update {
+            std::fs::write(&path, format!("{encoded}\n")).unwrap();
+            continue;
+        }
+        let expected = std::fs::read_to_string(&path)
+            .unwrap_or_else(|err| panic!("failed to read fixture {}: {err}", path.display()));
+        let expected = expected.trim();
+        if encoded != expected {
+            drifted.push(format!(
+                "{name}:\n  expected {expected}\n  actual   {encoded}"
+            ));
+            continue;
+        }
+        // the fixture decodes to the same message
+        let decoded = Message::<Entry>::decode(&hex::decode(expected).unwrap()).unwrap();
+        assert_eq!(decoded, message, "{name}");
+    }
+    assert!(
+        drifted.is_empty(),
+        "the wire encoding changed, which breaks compatibility with other peers:\n{}",
+        drifted.join("\n")
+    );
+}